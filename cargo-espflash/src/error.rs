@@ -1,5 +1,5 @@
-use espflash::Chip;
-use miette::{Diagnostic, LabeledSpan, SourceCode, SourceOffset};
+use espflash::{Chip, ErrorCategory};
+use miette::{Diagnostic, LabeledSpan, Report, SourceCode, SourceOffset};
 use std::fmt::{Display, Formatter};
 use std::iter::once;
 use thiserror::Error;
@@ -7,13 +7,6 @@ use thiserror::Error;
 #[derive(Error, Debug, Diagnostic)]
 #[non_exhaustive]
 pub enum Error {
-    #[error("No executable artifact found")]
-    #[diagnostic(
-        code(cargo_espflash::no_artifact),
-        help("If you're trying to run an example you need to specify it using the `--example` argument\n\
-              or if you're in a cargo workspace, specify the binary package with `--package`.")
-    )]
-    NoArtifact,
     #[error("'build-std' not configured")]
     #[diagnostic(
         code(cargo_espflash::build_std),
@@ -24,12 +17,6 @@ pub enum Error {
         )
     )]
     NoBuildStd,
-    #[error("Multiple build artifacts found")]
-    #[diagnostic(
-        code(cargo_espflash::multiple_artifacts),
-        help("Please specify which artifact to flash using --bin")
-    )]
-    MultipleArtifacts,
     #[error("Specified partition table is not a csv file")]
     #[diagnostic(code(cargo_espflash::partition_table_path))]
     InvalidPartitionTablePath,
@@ -54,12 +41,16 @@ pub enum Error {
         help(
             "The following targets are recognized:
     - ESP32: {}
+    - ESP32-C2: {}
     - ESP32-C3: {}
+    - ESP32-H2: {}
     - ESP32-S2: {}
     - ESP32-S3: {}
     - ESP8266: {}",
             Chip::Esp32.supported_targets().join(", "),
+            Chip::Esp32c2.supported_targets().join(", "),
             Chip::Esp32c3.supported_targets().join(", "),
+            Chip::Esp32h2.supported_targets().join(", "),
             Chip::Esp32s2.supported_targets().join(", "),
             Chip::Esp32s3.supported_targets().join(", "),
             Chip::Esp8266.supported_targets().join(", "),
@@ -68,6 +59,36 @@ pub enum Error {
     UnknownTarget(String),
 }
 
+impl Error {
+    pub fn category(&self) -> ErrorCategory {
+        match self {
+            Error::InvalidPartitionTablePath | Error::InvalidBootloaderPath | Error::NoProject => {
+                ErrorCategory::Input
+            }
+            _ => ErrorCategory::Image,
+        }
+    }
+}
+
+/// Category of `report`, including the errors of building the project
+pub fn category(report: &Report) -> ErrorCategory {
+    ErrorCategory::of(report)
+        .or_else(|| {
+            report.chain().find_map(|error| {
+                if let Some(error) = error.downcast_ref::<Error>() {
+                    Some(error.category())
+                } else if error.is::<NoTargetError>() || error.is::<UnsupportedTargetError>() {
+                    Some(ErrorCategory::Image)
+                } else if error.is::<TomlError>() {
+                    Some(ErrorCategory::Input)
+                } else {
+                    None
+                }
+            })
+        })
+        .unwrap_or(ErrorCategory::Other)
+}
+
 #[derive(Debug)]
 pub struct TomlError {
     err: MaybeTomlError,
@@ -1,18 +1,15 @@
-use std::{
-    fs,
-    path::PathBuf,
-    process::{exit, Command, ExitStatus, Stdio},
-    str::FromStr,
-};
+use std::{fs, path::PathBuf, str::FromStr, time::Instant};
 
-use cargo_metadata::Message;
 use clap::{AppSettings, Parser};
 use espflash::{
     cli::{
-        board_info, connect, flash_elf_image, monitor::monitor, save_elf_as_image, ConnectOpts,
-        FlashOpts,
+        board_info, build::cargo_build, connect, deadline::deadline, debug::debug,
+        dry_run::dry_run, exit_with_error, flash_elf_image, flash_monitor, load_signing_key,
+        logging::initialize_logger, print_flash_report, progress::EspflashProgress,
+        save_elf_as_image, sector_cache, watch::watch_and_flash, BoardInfoOpts, ConnectOpts,
+        FlashConfigOpts, FlashOpts, LogOpts,
     },
-    Chip, Config, ImageFormatId,
+    Chip, Config, FileFormat, Flasher, ImageFormatId,
 };
 use miette::{IntoDiagnostic, Result, WrapErr};
 
@@ -40,6 +37,7 @@ enum CargoSubCommand {
 }
 
 #[derive(Parser)]
+#[clap(about)]
 struct EspFlashOpts {
     #[clap(flatten)]
     flash_opts: FlashOpts,
@@ -47,6 +45,14 @@ struct EspFlashOpts {
     build_opts: BuildOpts,
     #[clap(flatten)]
     connect_opts: ConnectOpts,
+    #[clap(flatten)]
+    log_opts: LogOpts,
+    /// Flash the image again whenever it is rebuilt, restarting the monitor if enabled
+    #[clap(long, conflicts_with = "debug")]
+    watch: bool,
+    /// Board preset setting the chip, flash settings, reset behavior and monitor speed, eg `wemos-d1-mini`
+    #[clap(long, global = true)]
+    board: Option<String>,
     #[clap(subcommand)]
     subcommand: Option<SubCommand>,
 }
@@ -54,7 +60,7 @@ struct EspFlashOpts {
 #[derive(Parser)]
 pub enum SubCommand {
     /// Display information about the connected board and exit without flashing
-    BoardInfo(ConnectOpts),
+    BoardInfo(BoardInfoOpts),
     /// Save the image to disk instead of flashing to device
     SaveImage(SaveImageOpts),
 }
@@ -67,6 +73,9 @@ pub struct BuildOpts {
     /// Example to build and flash
     #[clap(long)]
     pub example: Option<String>,
+    /// Binary of the package to build and flash
+    #[clap(long)]
+    pub bin: Option<String>,
     /// Specify a (binary) package within a workspace to be built
     #[clap(long)]
     pub package: Option<String>,
@@ -88,25 +97,67 @@ pub struct BuildOpts {
 pub struct SaveImageOpts {
     #[clap(flatten)]
     pub build_args: BuildOpts,
+    #[clap(flatten)]
+    pub flash_config_opts: FlashConfigOpts,
+    /// File format to save the image in, defaults to the one matching the file extension
+    #[clap(long, possible_values = &["bin", "uf2", "hex"])]
+    pub file_format: Option<FileFormat>,
+    /// Sign the image for Secure Boot v2 with this RSA-3072 or ECDSA private key
+    #[clap(long)]
+    pub signing_key: Option<PathBuf>,
     /// File name to save the generated image to
     pub file: PathBuf,
 }
 
-fn main() -> Result<()> {
+fn main() {
     miette::set_panic_hook();
 
     let CargoSubCommand::Espflash(opts) = Opts::parse().subcommand;
+    initialize_logger(&opts.log_opts);
 
-    let config = Config::load()?;
-    let metadata = CargoEspFlashMeta::load("Cargo.toml")?;
+    let output_format = opts.log_opts.output_format;
+    if let Err(report) = run(opts) {
+        let category = error::category(&report);
+        exit_with_error(report, category, output_format);
+    }
+}
+
+fn run(mut opts: EspFlashOpts) -> Result<()> {
+    let mut config = Config::load()?;
+    if let Some(board) = &opts.board {
+        config.select_board(board)?;
+    }
+    opts.flash_opts.flash_config_opts.apply_config(&config);
+    opts.flash_opts.monitor_opts.apply_config(&config);
+
+    // The package metadata takes precedence over the config files
+    let mut metadata = CargoEspFlashMeta::load("Cargo.toml")?;
+    metadata.bootloader = metadata
+        .bootloader
+        .or_else(|| config.flash.bootloader.clone());
+    if metadata.bootloader.is_none() && opts.flash_opts.bootloader_version.is_none() {
+        opts.flash_opts.bootloader_version = config.flash.bootloader_version.clone();
+    }
+    // A bootloader release given on the command line replaces the bootloader file
+    if opts.flash_opts.bootloader_version.is_some() {
+        metadata.bootloader = None;
+    }
+    metadata.partition_table = metadata
+        .partition_table
+        .or_else(|| config.flash.partition_table.clone());
+    metadata.format = metadata.format.or(config.flash.format);
     let cargo_config = parse_cargo_config(".")?;
 
+    let output_format = opts.log_opts.output_format;
     if let Some(subcommand) = opts.subcommand {
         use SubCommand::*;
 
         match subcommand {
-            BoardInfo(opts) => board_info(opts, config),
-            SaveImage(opts) => save_image(opts, metadata, cargo_config),
+            BoardInfo(opts) => board_info(opts, config, output_format),
+            SaveImage(mut opts) => {
+                opts.flash_config_opts.apply_config(&config);
+                save_image(opts, metadata, cargo_config)
+            }
         }
     } else {
         flash(opts, config, metadata, cargo_config)
@@ -119,27 +170,142 @@ fn flash(
     metadata: CargoEspFlashMeta,
     cargo_config: CargoConfig,
 ) -> Result<()> {
+    if opts.flash_opts.dry_run {
+        return flash_dry_run(opts, metadata, cargo_config);
+    }
+
+    // The deadline covers communicating with the device, building the project may
+    // legitimately take much longer.
+    let connect_deadline = deadline(opts.connect_opts.timeout);
     let mut flasher = connect(&opts.connect_opts, &config)?;
+    drop(connect_deadline);
 
     let artifact_path = build(&opts.build_opts, &cargo_config, Some(flasher.chip()))
         .wrap_err("Failed to build project")?;
 
+    if opts.watch {
+        // The serial port is opened again for watching
+        drop(flasher);
+        return watch_and_flash(
+            &opts.connect_opts,
+            &config,
+            &artifact_path,
+            opts.flash_opts
+                .monitor
+                .then_some(&opts.flash_opts.monitor_opts),
+            |flasher, elf_data| load_image(flasher, &opts, &metadata, elf_data),
+        );
+    }
+
+    let flash_deadline = deadline(opts.connect_opts.timeout);
+
     // Print the board information once the project has successfully built. We do
     // here rather than upon connection to show the Cargo output prior to the board
     // information, rather than breaking up cargo-espflash's output.
     flasher.board_info()?;
 
     // Read the ELF data from the build path and load it to the target.
-    let elf_data = fs::read(artifact_path).into_diagnostic()?;
+    let elf_data = fs::read(&artifact_path).into_diagnostic()?;
+
+    let start = Instant::now();
+    load_image(&mut flasher, &opts, &metadata, &elf_data)?;
+    print_flash_report(&mut flasher, start.elapsed(), opts.log_opts.output_format)?;
+
+    drop(flash_deadline);
+
+    if opts.flash_opts.debug_opts.debug {
+        return debug(
+            flasher,
+            &artifact_path,
+            &elf_data,
+            &opts.flash_opts.debug_opts,
+        );
+    }
+    if opts.flash_opts.monitor {
+        flash_monitor(
+            flasher,
+            &opts.connect_opts,
+            &config,
+            &opts.flash_opts.monitor_opts,
+            &elf_data,
+            |flasher, elf_data| load_image(flasher, &opts, &metadata, elf_data),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn flash_dry_run(
+    opts: EspFlashOpts,
+    metadata: CargoEspFlashMeta,
+    cargo_config: CargoConfig,
+) -> Result<()> {
+    let target = opts
+        .build_opts
+        .target
+        .as_deref()
+        .or_else(|| cargo_config.target())
+        .ok_or_else(|| NoTargetError::new(None))
+        .into_diagnostic()?;
+    let chip = Chip::from_target(target).ok_or_else(|| Error::UnknownTarget(target.into()))?;
+
+    let path = build(&opts.build_opts, &cargo_config, Some(chip))?;
+    let elf_data = fs::read(path).into_diagnostic()?;
+
+    let bootloader = opts
+        .flash_opts
+        .bootloader(chip)?
+        .or_else(|| metadata.bootloader.clone());
+    let partition_table = opts
+        .flash_opts
+        .partition_table
+        .as_deref()
+        .or(metadata.partition_table.as_deref());
+    let image_format = opts
+        .build_opts
+        .format
+        .as_deref()
+        .map(ImageFormatId::from_str)
+        .transpose()?
+        .or(metadata.format);
+
+    dry_run(
+        chip,
+        &elf_data,
+        &opts.flash_opts,
+        bootloader.as_deref(),
+        partition_table,
+        image_format,
+        opts.log_opts.output_format,
+    )
+}
+
+fn load_image(
+    flasher: &mut Flasher,
+    opts: &EspFlashOpts,
+    metadata: &CargoEspFlashMeta,
+    elf_data: &[u8],
+) -> Result<()> {
+    flasher.set_verify(opts.flash_opts.verify);
+    flasher.set_compression(!opts.flash_opts.no_compress);
+    flasher.set_image_settings(opts.flash_opts.image_settings()?);
+    flasher.set_encrypt(opts.flash_opts.encrypt);
+    if opts.flash_opts.force {
+        sector_cache::clear(flasher);
+    }
+    flasher.set_skip_identical(!opts.flash_opts.no_skip && !opts.flash_opts.force);
 
     if opts.flash_opts.ram {
-        flasher.load_elf_to_ram(&elf_data)?;
+        flasher.load_elf_to_ram_with_entry(
+            elf_data,
+            opts.flash_opts.entry_point,
+            Some(&mut EspflashProgress::default()),
+        )?;
     } else {
         let bootloader = opts
             .flash_opts
-            .bootloader
-            .as_deref()
-            .or(metadata.bootloader.as_deref());
+            .bootloader(flasher.chip())?
+            .or_else(|| metadata.bootloader.clone());
 
         let partition_table = opts
             .flash_opts
@@ -155,29 +321,21 @@ fn flash(
             .transpose()?
             .or(metadata.format);
 
+        opts.flash_opts
+            .provision_opts
+            .provision(flasher, partition_table)?;
+
         flash_elf_image(
-            &mut flasher,
-            &elf_data,
-            bootloader,
+            flasher,
+            elf_data,
+            bootloader.as_deref(),
             partition_table,
             image_format,
         )?;
+        opts.flash_opts.provision_opts.flashed(flasher)?;
     }
 
-    if opts.flash_opts.monitor {
-        #[cfg(target_os = "linux")]
-        {
-            let (dtr, rts) = espflash::cli::create_dtr_rts_gpios_from_args(
-                &opts.connect_opts.gpio_dtr,
-                &opts.connect_opts.gpio_rts,
-            )?;
-            monitor(flasher.into_serial(), dtr, rts).into_diagnostic()?;
-        }
-        #[cfg(not(target_os = "linux"))]
-        monitor(flasher.into_serial(), None, None).into_diagnostic()?;
-    }
-
-    Ok(())
+    sector_cache::save(flasher)
 }
 
 fn build(
@@ -227,6 +385,11 @@ fn build(
         args.push(example);
     }
 
+    if let Some(bin) = build_options.bin.as_deref() {
+        args.push("--bin");
+        args.push(bin);
+    }
+
     if let Some(package) = build_options.package.as_deref() {
         args.push("--package");
         args.push(package);
@@ -244,58 +407,7 @@ fn build(
         }
     }
 
-    // Invoke the 'cargo build' command, passing our list of arguments.
-    let output = Command::new("cargo")
-        .arg("build")
-        .args(args)
-        .args(&["--message-format", "json-diagnostic-rendered-ansi"])
-        .stdout(Stdio::piped())
-        .stderr(Stdio::inherit())
-        .spawn()
-        .into_diagnostic()?
-        .wait_with_output()
-        .into_diagnostic()?;
-
-    // Parse build output.
-    let messages = Message::parse_stream(&output.stdout[..]);
-
-    // Find artifacts.
-    let mut target_artifact = None;
-
-    for message in messages {
-        match message.into_diagnostic()? {
-            Message::CompilerArtifact(artifact) => {
-                if artifact.executable.is_some() {
-                    if target_artifact.is_some() {
-                        return Err(Error::MultipleArtifacts.into());
-                    } else {
-                        target_artifact = Some(artifact);
-                    }
-                }
-            }
-            Message::CompilerMessage(message) => {
-                if let Some(rendered) = message.message.rendered {
-                    print!("{}", rendered);
-                }
-            }
-            // Ignore all other messages.
-            _ => (),
-        }
-    }
-
-    // Check if the command succeeded, otherwise return an error. Any error messages
-    // occurring during the build are shown above, when the compiler messages are
-    // rendered.
-    if !output.status.success() {
-        exit_with_process_status(output.status);
-    }
-
-    // If no target artifact was found, we don't have a path to return.
-    let target_artifact = target_artifact.ok_or(Error::NoArtifact)?;
-
-    let artifact_path = target_artifact.executable.unwrap().into();
-
-    Ok(artifact_path)
+    cargo_build(&args)
 }
 
 fn save_image(
@@ -324,22 +436,15 @@ fn save_image(
         .transpose()?
         .or(metadata.format);
 
-    save_elf_as_image(chip, &elf_data, opts.file, image_format)?;
+    save_elf_as_image(
+        chip,
+        &elf_data,
+        opts.file,
+        image_format,
+        &opts.flash_config_opts,
+        opts.file_format,
+        load_signing_key(opts.signing_key.as_deref())?.as_ref(),
+    )?;
 
     Ok(())
 }
-
-#[cfg(unix)]
-fn exit_with_process_status(status: ExitStatus) -> ! {
-    use std::os::unix::process::ExitStatusExt;
-    let code = status.code().or_else(|| status.signal()).unwrap_or(1);
-
-    exit(code)
-}
-
-#[cfg(not(unix))]
-fn exit_with_process_status(status: ExitStatus) -> ! {
-    let code = status.code().unwrap_or(1);
-
-    exit(code)
-}
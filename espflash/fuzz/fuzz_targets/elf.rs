@@ -0,0 +1,22 @@
+#![no_main]
+
+use espflash::{Chip, FirmwareImage, ImageFormatId};
+use libfuzzer_sys::fuzz_target;
+use strum::IntoEnumIterator;
+
+fuzz_target!(|data: &[u8]| {
+    let image = match FirmwareImage::from_data(data) {
+        Ok(image) => image,
+        Err(_) => return,
+    };
+
+    for chip in Chip::iter() {
+        for format in [
+            None,
+            Some(ImageFormatId::Bootloader),
+            Some(ImageFormatId::DirectBoot),
+        ] {
+            let _ = chip.get_flash_image(&image, None, None, format, None);
+        }
+    }
+});
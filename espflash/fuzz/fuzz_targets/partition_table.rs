@@ -0,0 +1,10 @@
+#![no_main]
+
+use espflash::PartitionTable;
+use libfuzzer_sys::fuzz_target;
+
+fuzz_target!(|data: &[u8]| {
+    if let Ok(table) = PartitionTable::try_from_csv(data) {
+        table.to_bytes();
+    }
+});
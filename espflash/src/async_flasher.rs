@@ -0,0 +1,832 @@
+//! Flashing from async code on tokio, without blocking the threads of the
+//! runtime
+
+use std::{borrow::Cow, io, time::Duration};
+
+use log::{debug, info, warn};
+use serialport::{ClearBuffer, SerialPort, SerialPortType};
+use tokio::{
+    io::{AsyncRead, AsyncReadExt, AsyncWrite, AsyncWriteExt},
+    time::{sleep, timeout},
+};
+use tokio_serial::SerialStream;
+
+use crate::{
+    chip::Chip,
+    command::{Command, CommandType},
+    connection::ConnectSettings,
+    elf::RomSegment,
+    error::{ConnectionError, Error, ResultExt},
+    flasher::{
+        check_flash_region, detected_flash_size, erase_region_step, FlashSize, ImageSettings,
+        ProgressCallbacks, SpiAttachParams, CHIP_DETECT_MAGIC_REG_ADDR, FLASH_SECTOR_SIZE,
+        SYNC_ATTEMPTS, TRY_SPI_PARAMS,
+    },
+    protocol::{encode_command, parse_response, FrameDecoder, Operation, SpiCommand, Step, Steps},
+    reset::{is_usb_serial_jtag, ResetAfterOperation, ResetBeforeOperation, ResetStep},
+    stubs::{FlashStub, STUB_GREETING},
+    PartitionTable,
+};
+
+/// Progress callbacks which can be passed along with the futures of the
+/// [`AsyncFlasher`] between the threads of the runtime
+pub type AsyncProgressCallbacks<'a> = &'a mut (dyn ProgressCallbacks + Send);
+
+/// The byte stream of an [`AsyncFlasher`], with the control lines used for
+/// resetting the device
+///
+/// Like [`Transport`](crate::transport::Transport) for the blocking flasher,
+/// except that reads and writes are async. The control lines and the baud rate
+/// are set right away.
+pub trait AsyncTransport: AsyncRead + AsyncWrite + Unpin + Send {
+    fn baud_rate(&self) -> Result<u32, Error>;
+
+    fn set_baud_rate(&mut self, speed: u32) -> Result<(), Error>;
+
+    /// Drop the data received but not read yet
+    fn clear_input(&mut self) -> Result<(), Error>;
+
+    /// Set the DTR line, transports without one ignore it
+    fn set_dtr(&mut self, level: bool) -> Result<(), Error>;
+
+    /// Set the RTS line, transports without one ignore it
+    fn set_rts(&mut self, level: bool) -> Result<(), Error>;
+}
+
+impl AsyncTransport for SerialStream {
+    fn baud_rate(&self) -> Result<u32, Error> {
+        Ok(SerialPort::baud_rate(self)?)
+    }
+
+    fn set_baud_rate(&mut self, speed: u32) -> Result<(), Error> {
+        Ok(SerialPort::set_baud_rate(self, speed)?)
+    }
+
+    fn clear_input(&mut self) -> Result<(), Error> {
+        Ok(self.clear(ClearBuffer::Input)?)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> Result<(), Error> {
+        Ok(self.write_data_terminal_ready(level)?)
+    }
+
+    fn set_rts(&mut self, level: bool) -> Result<(), Error> {
+        Ok(self.write_request_to_send(level)?)
+    }
+}
+
+/// The bootloader protocol over an [`AsyncTransport`], the async counterpart
+/// of [`Connection`](crate::connection::Connection)
+struct AsyncConnection<T> {
+    transport: T,
+    port_type: SerialPortType,
+    decoder: FrameDecoder,
+    /// Bytes received but not decoded yet
+    received: Vec<u8>,
+    before: ResetBeforeOperation,
+    after: ResetAfterOperation,
+    timeout_scale: f64,
+}
+
+impl<T: AsyncTransport> AsyncConnection<T> {
+    /// Whether the device can be reset through the DTR and RTS lines, which
+    /// Bluetooth serial ports don't have
+    fn can_reset(&self) -> bool {
+        self.port_type != SerialPortType::BluetoothPort
+    }
+
+    async fn run_reset(&mut self, steps: Vec<ResetStep>) -> Result<(), Error> {
+        // Without the control lines the device has to be reset manually
+        if !self.can_reset() {
+            return Ok(());
+        }
+
+        for step in steps {
+            match step {
+                ResetStep::Dtr(level) => self.transport.set_dtr(level)?,
+                ResetStep::Rts(level) => self.transport.set_rts(level)?,
+                ResetStep::Wait(duration) => sleep(duration).await,
+            }
+        }
+        Ok(())
+    }
+
+    async fn reset_to_flash(&mut self, extra_delay: bool) -> Result<(), Error> {
+        let usb_serial_jtag = is_usb_serial_jtag(&self.port_type);
+        let steps = self.before.steps(usb_serial_jtag, extra_delay);
+        self.run_reset(steps).await
+    }
+
+    async fn reset(&mut self) -> Result<(), Error> {
+        let usb_serial_jtag = is_usb_serial_jtag(&self.port_type);
+        let steps = self.after.steps(self.before, usb_serial_jtag);
+        self.run_reset(steps).await
+    }
+
+    async fn write_command(&mut self, command: Command<'_>) -> Result<(), Error> {
+        let frame = encode_command(command)?;
+        self.transport.clear_input()?;
+        self.received.clear();
+        self.decoder = FrameDecoder::default();
+        self.transport.write_all(&frame).await?;
+        self.transport.flush().await?;
+        Ok(())
+    }
+
+    /// Read the next frame, failing with a timeout if no data arrives for
+    /// `wait`
+    async fn read_frame(&mut self, wait: Duration) -> Result<Vec<u8>, Error> {
+        let wait = wait.mul_f64(self.timeout_scale);
+        loop {
+            let mut data = &self.received[..];
+            let frame = self.decoder.decode(&mut data);
+            let consumed = self.received.len() - data.len();
+            self.received.drain(..consumed);
+            if let Some(frame) = frame? {
+                return Ok(frame);
+            }
+
+            let mut buffer = [0; 1024];
+            let len = match timeout(wait, self.transport.read(&mut buffer)).await {
+                Ok(len) => len?,
+                Err(_) => return Err(io::Error::from(io::ErrorKind::TimedOut).into()),
+            };
+            if len == 0 {
+                return Err(io::Error::from(io::ErrorKind::UnexpectedEof).into());
+            }
+            self.received.extend_from_slice(&buffer[..len]);
+        }
+    }
+
+    async fn command(&mut self, command: Command<'_>, wait: Duration) -> Result<u32, Error> {
+        let ty = command.command_type();
+        debug!("Sending {} command", ty);
+        self.write_command(command).await.for_command(ty)?;
+
+        for _ in 0..100 {
+            let frame = self.read_frame(wait).await.for_command(ty)?;
+            if let Some(value) = parse_response(&frame, ty)? {
+                return Ok(value);
+            }
+        }
+        Err(Error::Connection(ConnectionError::ConnectionFailed))
+    }
+
+    async fn read_reg(&mut self, address: u32) -> Result<u32, Error> {
+        self.command(Command::ReadReg { address }, CommandType::ReadReg.timeout())
+            .await
+    }
+
+    async fn write_reg(
+        &mut self,
+        address: u32,
+        value: u32,
+        mask: Option<u32>,
+    ) -> Result<(), Error> {
+        self.command(
+            Command::WriteReg {
+                address,
+                value,
+                mask,
+            },
+            CommandType::WriteReg.timeout(),
+        )
+        .await?;
+        Ok(())
+    }
+
+    /// Carry out the steps of `operation` like
+    /// [`Connection::run`](crate::connection::Connection::run), and return its
+    /// output
+    async fn run<O: Operation>(
+        &mut self,
+        mut operation: O,
+        progress: &mut Option<AsyncProgressCallbacks<'_>>,
+    ) -> Result<O::Output, Error> {
+        let mut value = 0;
+        loop {
+            let step = {
+                let mut progress = progress
+                    .as_mut()
+                    .map(|cb| &mut **cb as &mut dyn ProgressCallbacks);
+                operation.step(value, &mut progress)?
+            };
+            let step = match step {
+                Some(step) => step,
+                None => return Ok(operation.output()),
+            };
+
+            value = 0;
+            match step {
+                Step::Command(command, wait) => value = self.command(command, wait).await?,
+                Step::Send(command) => self.write_command(command).await?,
+                Step::Sleep(duration) => sleep(duration).await,
+                Step::Reset => self.reset().await?,
+            }
+        }
+    }
+
+    /// Wait for the greeting sent by the flasher stub once it's started
+    async fn wait_for_stub(&mut self) -> Result<(), Error> {
+        // The response to the command that started the stub may come first
+        for _ in 0..10 {
+            match self.read_frame(CommandType::MemEnd.timeout()).await {
+                Ok(frame) if frame == STUB_GREETING => return Ok(()),
+                Ok(_) => continue,
+                Err(Error::Connection(ConnectionError::Timeout(_))) => break,
+                Err(err) => return Err(err),
+            }
+        }
+        Err(Error::StubNotRunning)
+    }
+
+    /// Switch both the device and the host to `speed`, like
+    /// [`Connection::change_baud`](crate::connection::Connection::change_baud)
+    /// does
+    async fn change_baud(&mut self, speed: u32, stub: bool) -> Result<(), Error> {
+        let old_speed = self.transport.baud_rate()?;
+        let prior_speed = if stub { old_speed } else { 0 };
+
+        self.command(
+            Command::ChangeBaud { speed, prior_speed },
+            CommandType::ChangeBaud.timeout(),
+        )
+        .await?;
+        self.transport.set_baud_rate(speed)?;
+        sleep(Duration::from_millis(50)).await;
+
+        if self.check_connection().await {
+            return Ok(());
+        }
+
+        debug!("No response at {} baud, going back to {}", speed, old_speed);
+        self.transport.set_baud_rate(old_speed)?;
+        sleep(Duration::from_millis(50)).await;
+        if self.check_connection().await {
+            Err(Error::Connection(ConnectionError::BaudChangeFailed(speed)))
+        } else {
+            Err(Error::Connection(ConnectionError::ConnectionFailed))
+        }
+    }
+
+    async fn check_connection(&mut self) -> bool {
+        for _ in 0..3 {
+            if self.read_reg(CHIP_DETECT_MAGIC_REG_ADDR).await.is_ok() {
+                return true;
+            }
+        }
+        false
+    }
+}
+
+/// Flashes a device through an [`AsyncTransport`], by default a serial port of
+/// [`tokio_serial`]
+///
+/// This lets a single runtime flash many devices at once. The same commands are
+/// sent as by the blocking [`Flasher`](crate::Flasher), which shares the code
+/// building them, but only writing images and raw data to flash is covered,
+/// through the ROM bootloader or the flasher stub. The data written isn't
+/// encrypted, verified or cached.
+///
+/// ```no_run
+/// # async fn flash(elf_data: &[u8]) -> Result<(), espflash::Error> {
+/// use espflash::{AsyncFlasher, ConnectSettings};
+/// use serialport::SerialPortType;
+/// use tokio_serial::SerialPortBuilderExt;
+///
+/// let serial = tokio_serial::new("/dev/ttyUSB0", 115_200).open_native_async()?;
+/// let mut flasher = AsyncFlasher::connect(
+///     serial,
+///     SerialPortType::Unknown,
+///     Some(921_600),
+///     ConnectSettings::default(),
+/// )
+/// .await?;
+/// flasher.load_elf_to_flash(elf_data, None, None, None).await?;
+/// # Ok(())
+/// # }
+/// ```
+pub struct AsyncFlasher<T = SerialStream> {
+    connection: AsyncConnection<T>,
+    chip: Chip,
+    flash_size: FlashSize,
+    flash_id: u32,
+    spi_params: SpiAttachParams,
+    use_compression: bool,
+    stub: bool,
+    image_settings: ImageSettings,
+}
+
+impl<T: AsyncTransport> AsyncFlasher<T> {
+    /// Reset the device into the bootloader, connect to it and detect the chip
+    /// and its flash
+    ///
+    /// Like with [`Flasher::connect`](crate::Flasher::connect), the baud rate
+    /// is switched to `speed` afterwards if the device responds at that
+    /// speed, otherwise it stays at 115200 baud.
+    pub async fn connect(
+        transport: T,
+        port_type: SerialPortType,
+        speed: Option<u32>,
+        settings: ConnectSettings,
+    ) -> Result<Self, Error> {
+        let connection = AsyncConnection {
+            transport,
+            port_type,
+            decoder: FrameDecoder::default(),
+            received: Vec::new(),
+            before: settings.before,
+            after: settings.after,
+            timeout_scale: settings.timeout_scale,
+        };
+        let mut flasher = AsyncFlasher {
+            connection,
+            chip: Chip::Esp8266, // dummy, set properly later
+            flash_size: FlashSize::Flash4Mb,
+            flash_id: 0,
+            spi_params: SpiAttachParams::default(),
+            use_compression: true,
+            stub: false,
+            image_settings: ImageSettings::default(),
+        };
+        if !flasher.connection.can_reset() {
+            warn!(
+                "The device can not be reset through this port, make sure it is in download mode"
+            );
+        }
+        flasher.start_connection(settings.attempts).await?;
+        let magic = flasher
+            .connection
+            .read_reg(CHIP_DETECT_MAGIC_REG_ADDR)
+            .await?;
+        flasher.chip = Chip::from_magic(magic)?;
+        flasher.spi_autodetect().await?;
+
+        flasher.switch_speed(speed, settings.attempts).await?;
+
+        Ok(flasher)
+    }
+
+    /// Reset the device into the bootloader and sync with it, alternating
+    /// between the default and the extra reset delay on every attempt
+    async fn start_connection(&mut self, attempts: usize) -> Result<(), Error> {
+        let mut extra_delay = false;
+        let mut attempt = 1;
+        loop {
+            if self.connect_attempt(extra_delay).await.is_ok() {
+                return Ok(());
+            }
+            if attempts != 0 && attempt >= attempts {
+                break;
+            }
+            attempt += 1;
+            extra_delay = !extra_delay;
+            debug!("Unable to connect, retrying (attempt {})...", attempt);
+        }
+
+        Err(Error::Connection(ConnectionError::ConnectionFailed))
+    }
+
+    async fn connect_attempt(&mut self, extra_delay: bool) -> Result<(), Error> {
+        self.connection.reset_to_flash(extra_delay).await?;
+
+        // The ROM responds to a sync command several times, the responses left
+        // over are dropped along with the input by the next command
+        for _ in 0..SYNC_ATTEMPTS {
+            if self
+                .connection
+                .command(Command::Sync, CommandType::Sync.timeout())
+                .await
+                .is_ok()
+            {
+                return Ok(());
+            }
+        }
+
+        Err(Error::Connection(ConnectionError::ConnectionFailed))
+    }
+
+    /// Switch to the baud rate `speed` if it's faster than the default one,
+    /// reconnecting at the default one if the device is lost on the way
+    async fn switch_speed(&mut self, speed: Option<u32>, attempts: usize) -> Result<(), Error> {
+        let speed = match speed {
+            Some(speed) if speed > 115_200 => speed,
+            _ => return Ok(()),
+        };
+        // Not available
+        if self.chip == Chip::Esp8266 {
+            return Ok(());
+        }
+
+        match self.change_baud(speed).await {
+            Err(Error::Connection(ConnectionError::BaudChangeFailed(_))) => {
+                warn!(
+                    "Failed to switch to {} baud, continuing at {} baud",
+                    speed,
+                    self.connection.transport.baud_rate()?
+                );
+                Ok(())
+            }
+            Err(Error::Connection(ConnectionError::ConnectionFailed)) => {
+                warn!(
+                    "Lost the connection switching to {} baud, reconnecting at 115200 baud",
+                    speed
+                );
+                self.connection.transport.set_baud_rate(115_200)?;
+                self.stub = false;
+                self.start_connection(attempts).await?;
+                self.enable_flash(self.spi_params).await
+            }
+            result => result,
+        }
+    }
+
+    async fn spi_autodetect(&mut self) -> Result<(), Error> {
+        for spi_params in TRY_SPI_PARAMS.iter().copied() {
+            self.enable_flash(spi_params).await?;
+            let command =
+                SpiCommand::new(self.chip.spi_registers(), CommandType::FlashDetect, &[], 24);
+            self.flash_id = self.connection.run(command, &mut None).await?;
+            self.flash_size = detected_flash_size(self.flash_id);
+            if self.flash_size != FlashSize::FlashRetry {
+                self.spi_params = spi_params;
+                return Ok(());
+            }
+        }
+
+        Err(Error::FlashConnect)
+    }
+
+    async fn enable_flash(&mut self, spi_params: SpiAttachParams) -> Result<(), Error> {
+        let steps = self
+            .chip
+            .flash_target(spi_params, self.use_compression, None)
+            .begin();
+        self.connection.run(steps, &mut None).await
+    }
+
+    /// Read the 32 bit register at `addr`
+    pub async fn read_reg(&mut self, addr: u32) -> Result<u32, Error> {
+        self.connection.read_reg(addr).await
+    }
+
+    /// Write `value` to the 32 bit register at `addr`, only changing the bits
+    /// in `mask` if given
+    pub async fn write_reg(
+        &mut self,
+        addr: u32,
+        value: u32,
+        mask: Option<u32>,
+    ) -> Result<(), Error> {
+        self.connection.write_reg(addr, value, mask).await
+    }
+
+    /// The chip type that the flasher is connected to
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    /// The size of the flash, detected when connecting
+    pub fn flash_size(&self) -> FlashSize {
+        self.flash_size
+    }
+
+    /// The JEDEC ID of the flash, detected when connecting
+    pub fn flash_id(&self) -> u32 {
+        self.flash_id
+    }
+
+    /// Set how ELF images are turned into the data written to flash, see
+    /// [`ImageSettings`]
+    pub fn set_image_settings(&mut self, settings: ImageSettings) {
+        self.image_settings = settings;
+    }
+
+    /// Compress the data written to flash, which the ROM of the ESP8266 doesn't
+    /// support
+    pub fn set_compression(&mut self, use_compression: bool) {
+        self.use_compression = use_compression;
+    }
+
+    /// Switch to a different baud rate, the device has to respond at it
+    pub async fn change_baud(&mut self, speed: u32) -> Result<(), Error> {
+        self.connection.change_baud(speed, self.stub).await
+    }
+
+    /// Load the flasher stub into RAM and run it, like
+    /// [`Flasher::load_stub`](crate::Flasher::load_stub)
+    ///
+    /// The stub replaces the ROM bootloader until the device is reset.
+    pub async fn load_stub(&mut self, stub: &FlashStub) -> Result<(), Error> {
+        if self.stub {
+            return Ok(());
+        }
+
+        if self.connection.after == ResetAfterOperation::SoftReset {
+            warn!(
+                "The flasher stub can't start the application without a reset, using a hard reset"
+            );
+            self.connection.after = ResetAfterOperation::HardReset;
+        }
+
+        info!("Loading the flasher stub");
+        let mut target = self.chip.ram_target(Some(stub.entry()));
+        self.connection
+            .run(target.begin(), &mut None)
+            .await
+            .flashing()?;
+        for segment in stub.segments() {
+            let write = target.write_segment(segment)?;
+            self.connection.run(write, &mut None).await.flashing()?;
+        }
+        self.connection
+            .run(target.finish(true), &mut None)
+            .await
+            .flashing()?;
+
+        self.connection.wait_for_stub().await?;
+        self.stub = true;
+
+        Ok(())
+    }
+
+    /// Whether the flasher stub is running
+    pub fn is_stub(&self) -> bool {
+        self.stub
+    }
+
+    /// Build the flash image of the ELF image in `elf_data` like
+    /// [`Flasher::load_elf_to_flash`](crate::Flasher::load_elf_to_flash),
+    /// write it and reset the device
+    ///
+    /// The revision of the chip isn't detected, so it can't be checked against
+    /// the minimum revision of the image.
+    pub async fn load_elf_to_flash(
+        &mut self,
+        elf_data: &[u8],
+        bootloader: Option<Vec<u8>>,
+        partition_table: Option<PartitionTable>,
+        mut progress: Option<AsyncProgressCallbacks<'_>>,
+    ) -> Result<(), Error> {
+        let plan = self.image_settings.plan(
+            self.chip,
+            self.flash_size,
+            None,
+            elf_data,
+            bootloader,
+            partition_table,
+            None,
+        )?;
+
+        for (name, offset, size) in &plan.erase {
+            info!("Erasing partition {}", name);
+            self.erase_region(*offset, *size).await?;
+        }
+
+        let segments: Vec<_> = plan
+            .segments
+            .iter()
+            .map(|(addr, data)| (*addr, &data[..]))
+            .collect();
+        self.write_segments(&segments, &mut progress).await
+    }
+
+    /// Write raw data to flash at `addr` and reset the device
+    pub async fn write_bin_to_flash(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+        mut progress: Option<AsyncProgressCallbacks<'_>>,
+    ) -> Result<(), Error> {
+        check_flash_region(addr, data.len() as u32, self.flash_size)?;
+
+        self.write_segments(&[(addr, data)], &mut progress).await
+    }
+
+    /// Erase `size` bytes of flash starting at `offset`, both have to be aligned
+    /// to flash sectors
+    ///
+    /// Like with [`Flasher::erase_region`](crate::Flasher::erase_region), the
+    /// ROM loader starts a flash write covering the region instead of erasing it
+    /// with the command of the flasher stub.
+    pub async fn erase_region(&mut self, offset: u32, size: u32) -> Result<(), Error> {
+        if offset % FLASH_SECTOR_SIZE as u32 != 0 || size % FLASH_SECTOR_SIZE as u32 != 0 {
+            return Err(Error::UnalignedEraseRegion(offset, size));
+        }
+        check_flash_region(offset, size, self.flash_size)?;
+
+        let step = erase_region_step(self.chip, self.stub, offset, size);
+        self.connection
+            .run(Steps::from(Some(step)), &mut None)
+            .await
+    }
+
+    /// Write the segments through the flash target of the chip, then reset the
+    /// device
+    async fn write_segments(
+        &mut self,
+        segments: &[(u32, &[u8])],
+        progress: &mut Option<AsyncProgressCallbacks<'_>>,
+    ) -> Result<(), Error> {
+        let mut target = self
+            .chip
+            .flash_target(self.spi_params, self.use_compression, None);
+        self.connection
+            .run(target.begin(), &mut None)
+            .await
+            .flashing()?;
+        for (addr, data) in segments {
+            let write = target.write_segment(RomSegment {
+                addr: *addr,
+                data: Cow::Borrowed(data),
+            })?;
+            self.connection.run(write, progress).await.flashing()?;
+        }
+        self.connection
+            .run(target.finish(true), &mut None)
+            .await
+            .flashing()
+    }
+
+    /// Reset the device following the [`ResetAfterOperation`] it was connected
+    /// with
+    pub async fn reset(&mut self) -> Result<(), Error> {
+        self.connection.reset().await
+    }
+
+    pub fn into_transport(self) -> T {
+        self.connection.transport
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        io::{Read, Write},
+        pin::Pin,
+        task::{Context, Poll},
+    };
+
+    use tokio::io::ReadBuf;
+
+    use super::*;
+    use crate::transport::{
+        mock::{MockDevice, FLASH_ID},
+        Transport,
+    };
+
+    struct AsyncMock(MockDevice);
+
+    impl AsyncRead for AsyncMock {
+        fn poll_read(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &mut ReadBuf<'_>,
+        ) -> Poll<io::Result<()>> {
+            match self.get_mut().0.read(buf.initialize_unfilled()) {
+                Ok(len) => {
+                    buf.advance(len);
+                    Poll::Ready(Ok(()))
+                }
+                // The device responds to the commands right away, nothing
+                // arrives later
+                Err(err) if err.kind() == io::ErrorKind::TimedOut => Poll::Pending,
+                Err(err) => Poll::Ready(Err(err)),
+            }
+        }
+    }
+
+    impl AsyncWrite for AsyncMock {
+        fn poll_write(
+            self: Pin<&mut Self>,
+            _cx: &mut Context<'_>,
+            buf: &[u8],
+        ) -> Poll<io::Result<usize>> {
+            Poll::Ready(self.get_mut().0.write(buf))
+        }
+
+        fn poll_flush(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+
+        fn poll_shutdown(self: Pin<&mut Self>, _cx: &mut Context<'_>) -> Poll<io::Result<()>> {
+            Poll::Ready(Ok(()))
+        }
+    }
+
+    impl AsyncTransport for AsyncMock {
+        fn baud_rate(&self) -> Result<u32, Error> {
+            Transport::baud_rate(&self.0)
+        }
+
+        fn set_baud_rate(&mut self, speed: u32) -> Result<(), Error> {
+            Transport::set_baud_rate(&mut self.0, speed)
+        }
+
+        fn clear_input(&mut self) -> Result<(), Error> {
+            Transport::clear_input(&mut self.0)
+        }
+
+        fn set_dtr(&mut self, level: bool) -> Result<(), Error> {
+            Transport::set_dtr(&mut self.0, level)
+        }
+
+        fn set_rts(&mut self, level: bool) -> Result<(), Error> {
+            Transport::set_rts(&mut self.0, level)
+        }
+    }
+
+    async fn connect_mock(
+        device: &MockDevice,
+        speed: Option<u32>,
+    ) -> Result<AsyncFlasher<AsyncMock>, Error> {
+        let settings = ConnectSettings {
+            before: ResetBeforeOperation::NoReset,
+            after: ResetAfterOperation::HardReset,
+            ..ConnectSettings::default()
+        };
+        AsyncFlasher::connect(
+            AsyncMock(device.clone()),
+            SerialPortType::Unknown,
+            speed,
+            settings,
+        )
+        .await
+    }
+
+    // Reads wait for data until the timeout, which paused time skips
+    #[tokio::test(start_paused = true)]
+    async fn connects_to_the_rom() {
+        let device = MockDevice::new();
+        let flasher = connect_mock(&device, None).await.unwrap();
+        assert_eq!(flasher.chip(), Chip::Esp32c3);
+        assert_eq!(flasher.flash_id(), FLASH_ID);
+        assert_eq!(flasher.flash_size(), FlashSize::Flash4Mb);
+        assert_eq!(device.commands()[0], CommandType::Sync as u8);
+
+        // The baud rate is only kept if the device responds at it
+        let flasher = connect_mock(&device, Some(921_600)).await.unwrap();
+        assert_eq!(flasher.into_transport().baud_rate().unwrap(), 921_600);
+        let device = MockDevice::new();
+        device.refuse_baud_change();
+        let flasher = connect_mock(&device, Some(921_600)).await.unwrap();
+        assert_eq!(flasher.into_transport().baud_rate().unwrap(), 115_200);
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn flashes_several_devices_concurrently() {
+        let data: Vec<u8> = (0..0x1800).map(|i| (i % 251) as u8).collect();
+        let flash = |compression| {
+            let data = &data;
+            async move {
+                let device = MockDevice::new();
+                let mut flasher = connect_mock(&device, Some(921_600)).await.unwrap();
+                flasher.set_compression(compression);
+                flasher
+                    .write_bin_to_flash(0x10000, data, None)
+                    .await
+                    .unwrap();
+                device
+            }
+        };
+
+        let (compressed, uncompressed) = tokio::join!(flash(true), flash(false));
+        for (device, begin) in [
+            (compressed, CommandType::FlashDeflateBegin),
+            (uncompressed, CommandType::FlashBegin),
+        ] {
+            assert_eq!(device.flash(0x10000, data.len()), data);
+            assert!(device.commands().contains(&(begin as u8)));
+            assert!(device.is_running_app());
+        }
+    }
+
+    #[tokio::test(start_paused = true)]
+    async fn loads_the_stub_on_another_task() {
+        let device = MockDevice::new();
+        let stub = FlashStub::bundled(Chip::Esp32c3);
+
+        // Spawning needs the futures to be Send
+        let mock = device.clone();
+        let flasher = tokio::spawn(async move {
+            let mut flasher = connect_mock(&mock, Some(921_600)).await.unwrap();
+            flasher.load_stub(&stub).await.unwrap();
+            flasher.erase_region(0x10000, 0x1000).await.unwrap();
+            flasher
+                .write_bin_to_flash(0x11000, &[1, 2, 3, 4], None)
+                .await
+                .unwrap();
+            flasher
+        })
+        .await
+        .unwrap();
+
+        assert!(flasher.is_stub());
+        assert!(device
+            .commands()
+            .contains(&(CommandType::EraseRegion as u8)));
+        assert_eq!(device.flash(0x10000, 4), [0xff; 4]);
+        assert_eq!(device.flash(0x11000, 4), [1, 2, 3, 4]);
+        assert!(device.is_running_app());
+    }
+}
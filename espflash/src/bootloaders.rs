@@ -0,0 +1,195 @@
+//! Second stage bootloaders
+//!
+//! espflash bundles the bootloaders of several ESP-IDF releases. The header of
+//! each bootloader holds the range of chip revisions it runs on, so when no
+//! release is selected the newest bootloader supporting the revision of the
+//! connected chip is flashed.
+
+use std::ops::RangeInclusive;
+
+use crate::{error::Error, Chip};
+
+/// A second stage bootloader bundled with espflash
+#[derive(Debug)]
+pub struct Bootloader {
+    chip: Chip,
+    release: &'static str,
+    data: &'static [u8],
+}
+
+/// The bundled bootloaders, oldest release first
+const BOOTLOADERS: &[Bootloader] = &[
+    Bootloader {
+        chip: Chip::Esp32,
+        release: "v4.4",
+        data: include_bytes!("../bootloader/v4.4/esp32-bootloader.bin"),
+    },
+    Bootloader {
+        chip: Chip::Esp32c3,
+        release: "v4.4",
+        data: include_bytes!("../bootloader/v4.4/esp32c3-bootloader.bin"),
+    },
+    Bootloader {
+        chip: Chip::Esp32s2,
+        release: "v4.4",
+        data: include_bytes!("../bootloader/v4.4/esp32s2-bootloader.bin"),
+    },
+    Bootloader {
+        chip: Chip::Esp32s3,
+        release: "v4.4",
+        data: include_bytes!("../bootloader/v4.4/esp32s3-bootloader.bin"),
+    },
+    Bootloader {
+        chip: Chip::Esp32,
+        release: "v5.1",
+        data: include_bytes!("../bootloader/v5.1/esp32-bootloader.bin"),
+    },
+    Bootloader {
+        chip: Chip::Esp32c2,
+        release: "v5.1",
+        data: include_bytes!("../bootloader/v5.1/esp32c2-bootloader.bin"),
+    },
+    Bootloader {
+        chip: Chip::Esp32c3,
+        release: "v5.1",
+        data: include_bytes!("../bootloader/v5.1/esp32c3-bootloader.bin"),
+    },
+    Bootloader {
+        chip: Chip::Esp32h2,
+        release: "v5.1",
+        data: include_bytes!("../bootloader/v5.1/esp32h2-bootloader.bin"),
+    },
+    Bootloader {
+        chip: Chip::Esp32s2,
+        release: "v5.1",
+        data: include_bytes!("../bootloader/v5.1/esp32s2-bootloader.bin"),
+    },
+    Bootloader {
+        chip: Chip::Esp32s3,
+        release: "v5.1",
+        data: include_bytes!("../bootloader/v5.1/esp32s3-bootloader.bin"),
+    },
+];
+
+impl Bootloader {
+    /// The bundled bootloaders for `chip`, oldest release first
+    pub fn bundled(chip: Chip) -> impl Iterator<Item = &'static Bootloader> {
+        BOOTLOADERS
+            .iter()
+            .filter(move |bootloader| bootloader.chip == chip)
+    }
+
+    /// The bundled bootloader of the ESP-IDF `release` for `chip`
+    pub fn find(chip: Chip, release: &str) -> Result<&'static Bootloader, Error> {
+        Self::bundled(chip)
+            .find(|bootloader| bootloader.release == release)
+            .ok_or_else(|| {
+                let releases: Vec<_> = Self::bundled(chip)
+                    .map(|bootloader| bootloader.release)
+                    .collect();
+                Error::BootloaderNotFound(release.into(), chip, releases.join(", "))
+            })
+    }
+
+    /// The newest bundled bootloader for `chip` which runs on `revision`, as
+    /// detected when connecting, or the newest one if the revision is unknown
+    pub fn select(chip: Chip, revision: Option<u32>) -> Option<&'static Bootloader> {
+        let revision = revision.map(|revision| full_revision(chip, revision));
+        Self::bundled(chip)
+            .filter(|bootloader| match revision {
+                Some(revision) => bootloader.revisions().contains(&revision),
+                None => true,
+            })
+            .last()
+    }
+
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
+
+    /// The ESP-IDF release the bootloader was built with, e.g. `v5.1`
+    pub fn release(&self) -> &'static str {
+        self.release
+    }
+
+    pub fn data(&self) -> &'static [u8] {
+        self.data
+    }
+
+    /// The chip revisions the bootloader runs on, as `major * 100 + minor`
+    ///
+    /// Bootloaders older than ESP-IDF v5.0 leave the maximum revision at 0,
+    /// they aren't limited to any revision.
+    pub fn revisions(&self) -> RangeInclusive<u32> {
+        let min = u16::from_le_bytes([self.data[15], self.data[16]]) as u32;
+        let max = match u16::from_le_bytes([self.data[17], self.data[18]]) {
+            0 => u16::MAX,
+            max => max,
+        };
+
+        min..=max as u32
+    }
+}
+
+/// `revision` of `chip` as detected when connecting, as `major * 100 + minor`
+///
+/// The revision of the ESP32 is its major version, that of the ESP32-C3 its
+/// minor version.
+fn full_revision(chip: Chip, revision: u32) -> u32 {
+    match chip {
+        Chip::Esp32 => revision * 100,
+        _ => revision,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use strum::IntoEnumIterator;
+
+    use super::*;
+
+    #[test]
+    fn bootloaders_are_selected_by_release_and_revision() {
+        for chip in Chip::iter().filter(|chip| *chip != Chip::Esp8266) {
+            let bootloader = Bootloader::select(chip, None).unwrap();
+            assert_eq!(bootloader.chip(), chip);
+            assert_eq!(bootloader.release(), "v5.1");
+        }
+        assert!(Bootloader::select(Chip::Esp8266, None).is_none());
+
+        // The ESP-IDF v5.1 bootloader of the ESP32-C3 needs revision 0.3
+        assert_eq!(
+            Bootloader::select(Chip::Esp32c3, Some(2))
+                .unwrap()
+                .release(),
+            "v4.4"
+        );
+        assert_eq!(
+            Bootloader::select(Chip::Esp32c3, Some(4))
+                .unwrap()
+                .release(),
+            "v5.1"
+        );
+        assert_eq!(
+            Bootloader::select(Chip::Esp32, Some(3))
+                .unwrap()
+                .revisions(),
+            0..=399
+        );
+
+        let bootloader = Bootloader::find(Chip::Esp32s3, "v4.4").unwrap();
+        assert_eq!(bootloader.revisions(), 0..=u16::MAX as u32);
+        assert_eq!(
+            bootloader.data(),
+            include_bytes!("../bootloader/v4.4/esp32s3-bootloader.bin")
+        );
+        match Bootloader::find(Chip::Esp32s3, "v5.0") {
+            Err(Error::BootloaderNotFound(release, chip, releases)) => {
+                assert_eq!((release.as_str(), chip), ("v5.0", Chip::Esp32s3));
+                assert_eq!(releases, "v4.4, v5.1");
+            }
+            result => panic!("unexpected result {:?}", result),
+        }
+        assert!(Bootloader::find(Chip::Esp32h2, "v4.4").is_err());
+    }
+}
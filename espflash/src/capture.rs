@@ -0,0 +1,202 @@
+//! Recording of serial traffic to capture files
+//!
+//! A capture file starts with the magic `ESPCAP` followed by a two byte format
+//! version, after which records are appended until the port is closed. Every
+//! record has the following layout, all integers being little endian:
+//!
+//! | Size | Field                                                    |
+//! | :--- | :------------------------------------------------------- |
+//! | 1    | [`RecordKind`]                                           |
+//! | 8    | Microseconds since the start of the capture              |
+//! | 4    | Length of the payload                                    |
+//! | n    | Payload, raw data for reads and writes, otherwise a value |
+//!
+//! The level of a control line is stored as a single byte, a baud rate as a
+//! 32 bit integer.
+
+use std::{
+    convert::TryInto,
+    fs::File,
+    io::{self, Read, Write},
+    path::Path,
+    time::{Duration, Instant},
+};
+
+use log::warn;
+
+use crate::{error::Error, transport::Transport};
+
+const MAGIC: &[u8] = b"ESPCAP";
+const VERSION: u16 = 1;
+const RECORD_HEADER_SIZE: usize = 13;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+#[repr(u8)]
+pub enum RecordKind {
+    /// Data sent from the host to the device
+    Write = 0,
+    /// Data received by the host
+    Read = 1,
+    /// Level of the DTR line changed
+    Dtr = 2,
+    /// Level of the RTS line changed
+    Rts = 3,
+    /// Baud rate of the port changed
+    BaudRate = 4,
+}
+
+impl RecordKind {
+    fn from_u8(kind: u8) -> Option<Self> {
+        match kind {
+            0 => Some(RecordKind::Write),
+            1 => Some(RecordKind::Read),
+            2 => Some(RecordKind::Dtr),
+            3 => Some(RecordKind::Rts),
+            4 => Some(RecordKind::BaudRate),
+            _ => None,
+        }
+    }
+}
+
+#[derive(Clone, Debug)]
+pub struct Record<'a> {
+    pub kind: RecordKind,
+    pub timestamp: Duration,
+    pub data: &'a [u8],
+}
+
+/// Parse the records from the contents of a capture file
+pub fn parse_capture(mut data: &[u8]) -> Result<Vec<Record<'_>>, Error> {
+    if !data.starts_with(MAGIC) || data.len() < MAGIC.len() + 2 {
+        return Err(Error::InvalidCapture("not a capture file"));
+    }
+    let version = u16::from_le_bytes([data[MAGIC.len()], data[MAGIC.len() + 1]]);
+    if version != VERSION {
+        return Err(Error::InvalidCapture("unsupported format version"));
+    }
+    data = &data[MAGIC.len() + 2..];
+
+    let mut records = Vec::new();
+    while !data.is_empty() {
+        if data.len() < RECORD_HEADER_SIZE {
+            return Err(Error::InvalidCapture("truncated record header"));
+        }
+        let kind =
+            RecordKind::from_u8(data[0]).ok_or(Error::InvalidCapture("unknown record kind"))?;
+        let timestamp = u64::from_le_bytes(data[1..9].try_into().unwrap());
+        let len = u32::from_le_bytes(data[9..13].try_into().unwrap()) as usize;
+
+        let payload = data[RECORD_HEADER_SIZE..]
+            .get(..len)
+            .ok_or(Error::InvalidCapture("truncated record"))?;
+        records.push(Record {
+            kind,
+            timestamp: Duration::from_micros(timestamp),
+            data: payload,
+        });
+        data = &data[RECORD_HEADER_SIZE + len..];
+    }
+
+    Ok(records)
+}
+
+/// Transport wrapper which records all traffic to a capture file
+pub struct CaptureTransport {
+    inner: Box<dyn Transport>,
+    file: Option<File>,
+    start: Instant,
+}
+
+impl CaptureTransport {
+    pub fn new(inner: Box<dyn Transport>, path: &Path) -> io::Result<Self> {
+        let mut file = File::create(path)?;
+        file.write_all(MAGIC)?;
+        file.write_all(&VERSION.to_le_bytes())?;
+
+        Ok(CaptureTransport {
+            inner,
+            file: Some(file),
+            start: Instant::now(),
+        })
+    }
+
+    fn record(&mut self, kind: RecordKind, data: &[u8]) {
+        let file = match &mut self.file {
+            Some(file) => file,
+            None => return,
+        };
+
+        let mut record = Vec::with_capacity(RECORD_HEADER_SIZE + data.len());
+        record.push(kind as u8);
+        record.extend_from_slice(&(self.start.elapsed().as_micros() as u64).to_le_bytes());
+        record.extend_from_slice(&(data.len() as u32).to_le_bytes());
+        record.extend_from_slice(data);
+
+        // Records are written unbuffered so the capture is complete even if the process is
+        // terminated, a failing capture should never break the operation being captured.
+        if let Err(e) = file.write_all(&record) {
+            warn!("Failed to write capture file, stopping capture: {}", e);
+            self.file = None;
+        }
+    }
+}
+
+impl Read for CaptureTransport {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let count = self.inner.read(buf)?;
+        if count > 0 {
+            self.record(RecordKind::Read, &buf[..count]);
+        }
+        Ok(count)
+    }
+}
+
+impl Write for CaptureTransport {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let count = self.inner.write(buf)?;
+        if count > 0 {
+            self.record(RecordKind::Write, &buf[..count]);
+        }
+        Ok(count)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.inner.flush()
+    }
+}
+
+impl Transport for CaptureTransport {
+    fn timeout(&self) -> Duration {
+        self.inner.timeout()
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.inner.set_timeout(timeout)
+    }
+
+    fn baud_rate(&self) -> Result<u32, Error> {
+        self.inner.baud_rate()
+    }
+
+    fn set_baud_rate(&mut self, speed: u32) -> Result<(), Error> {
+        self.inner.set_baud_rate(speed)?;
+        self.record(RecordKind::BaudRate, &speed.to_le_bytes());
+        Ok(())
+    }
+
+    fn clear_input(&mut self) -> Result<(), Error> {
+        self.inner.clear_input()
+    }
+
+    fn set_dtr(&mut self, level: bool) -> Result<(), Error> {
+        self.inner.set_dtr(level)?;
+        self.record(RecordKind::Dtr, &[level as u8]);
+        Ok(())
+    }
+
+    fn set_rts(&mut self, level: bool) -> Result<(), Error> {
+        self.inner.set_rts(level)?;
+        self.record(RecordKind::Rts, &[level as u8]);
+        Ok(())
+    }
+}
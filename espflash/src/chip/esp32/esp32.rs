@@ -5,7 +5,7 @@ use crate::error::UnsupportedImageFormatError;
 use crate::{
     chip::{bytes_to_mac_addr, Chip, ChipType, ReadEFuse, SpiRegisters},
     connection::Connection,
-    elf::FirmwareImage,
+    elf::{FirmwareImage, FlashLayout},
     image_format::{Esp32BootloaderFormat, ImageFormat, ImageFormatId},
     Error, PartitionTable,
 };
@@ -18,6 +18,12 @@ const IROM_MAP_END: u32 = 0x40400000;
 const DROM_MAP_START: u32 = 0x3F400000;
 const DROM_MAP_END: u32 = 0x3F800000;
 
+const IRAM_START: u32 = 0x40070000;
+const IRAM_END: u32 = 0x400a0000;
+
+const DRAM_START: u32 = 0x3ffae000;
+const DRAM_END: u32 = 0x40000000;
+
 pub const PARAMS: Esp32Params = Esp32Params {
     boot_addr: 0x1000,
     partition_addr: 0x8000,
@@ -28,7 +34,6 @@ pub const PARAMS: Esp32Params = Esp32Params {
     app_addr: 0x10000,
     app_size: 0x3f0000,
     chip_id: 0,
-    default_bootloader: include_bytes!("../../../bootloader/esp32-bootloader.bin"),
 };
 
 impl ChipType for Esp32 {
@@ -48,6 +53,8 @@ impl ChipType for Esp32 {
 
     const FLASH_RANGES: &'static [Range<u32>] =
         &[IROM_MAP_START..IROM_MAP_END, DROM_MAP_START..DROM_MAP_END];
+    const IRAM_RANGE: Range<u32> = IRAM_START..IRAM_END;
+    const DRAM_RANGE: Range<u32> = DRAM_START..DRAM_END;
 
     const DEFAULT_IMAGE_FORMAT: ImageFormatId = ImageFormatId::Bootloader;
     const SUPPORTED_IMAGE_FORMATS: &'static [ImageFormatId] = &[ImageFormatId::Bootloader];
@@ -113,12 +120,16 @@ impl ChipType for Esp32 {
         Ok(features)
     }
 
+    fn default_partition_table(&self, layout: FlashLayout) -> Option<PartitionTable> {
+        Some(PARAMS.with_layout(layout).default_partition_table())
+    }
+
     fn get_flash_segments<'a>(
         image: &'a FirmwareImage,
         bootloader: Option<Vec<u8>>,
         partition_table: Option<PartitionTable>,
         image_format: ImageFormatId,
-        _chip_revision: Option<u32>,
+        chip_revision: Option<u32>,
     ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         match image_format {
             ImageFormatId::Bootloader => Ok(Box::new(Esp32BootloaderFormat::new(
@@ -127,6 +138,7 @@ impl ChipType for Esp32 {
                 PARAMS,
                 partition_table,
                 bootloader,
+                chip_revision,
             )?)),
             _ => Err(UnsupportedImageFormatError::new(image_format, Chip::Esp32, None).into()),
         }
@@ -187,7 +199,8 @@ fn test_esp32_rom() {
     let expected_bin = read("./tests/data/esp32.bin").unwrap();
 
     let image = FirmwareImage::from_data(&input_bytes).unwrap();
-    let flash_image = Esp32BootloaderFormat::new(&image, Chip::Esp32, PARAMS, None, None).unwrap();
+    let flash_image =
+        Esp32BootloaderFormat::new(&image, Chip::Esp32, PARAMS, None, None, None).unwrap();
 
     let segments = flash_image.flash_segments().collect::<Vec<_>>();
 
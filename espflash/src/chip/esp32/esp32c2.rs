@@ -0,0 +1,99 @@
+use std::ops::Range;
+
+use super::Esp32Params;
+use crate::{
+    chip::{ChipType, ReadEFuse, SpiRegisters},
+    connection::Connection,
+    elf::{FirmwareImage, FlashLayout},
+    error::UnsupportedImageFormatError,
+    image_format::{Esp32BootloaderFormat, ImageFormat, ImageFormatId},
+    Chip, Error, PartitionTable,
+};
+
+pub struct Esp32c2;
+
+const IROM_MAP_START: u32 = 0x42000000;
+const IROM_MAP_END: u32 = 0x42400000;
+
+const DROM_MAP_START: u32 = 0x3c000000;
+const DROM_MAP_END: u32 = 0x3c400000;
+
+const IRAM_START: u32 = 0x4037c000;
+const IRAM_END: u32 = 0x403c0000;
+
+const DRAM_START: u32 = 0x3fca0000;
+const DRAM_END: u32 = 0x3fce0000;
+
+pub const PARAMS: Esp32Params = Esp32Params {
+    boot_addr: 0x0,
+    partition_addr: 0x8000,
+    nvs_addr: 0x9000,
+    nvs_size: 0x6000,
+    phy_init_data_addr: 0xf000,
+    phy_init_data_size: 0x1000,
+    app_addr: 0x10000,
+    app_size: 0x1f0000,
+    chip_id: 12,
+};
+
+impl ChipType for Esp32c2 {
+    const CHIP_DETECT_MAGIC_VALUE: u32 = 0x6f51306f;
+    const CHIP_DETECT_MAGIC_VALUE2: u32 = 0x7c41a06f;
+
+    const UART_CLKDIV_REG: u32 = 0x60000014;
+
+    const SPI_REGISTERS: SpiRegisters = SpiRegisters {
+        base: 0x60002000,
+        usr_offset: 0x18,
+        usr1_offset: 0x1C,
+        usr2_offset: 0x20,
+        w0_offset: 0x58,
+        mosi_length_offset: Some(0x24),
+        miso_length_offset: Some(0x28),
+    };
+
+    const FLASH_RANGES: &'static [Range<u32>] =
+        &[IROM_MAP_START..IROM_MAP_END, DROM_MAP_START..DROM_MAP_END];
+    const IRAM_RANGE: Range<u32> = IRAM_START..IRAM_END;
+    const DRAM_RANGE: Range<u32> = DRAM_START..DRAM_END;
+
+    const DEFAULT_IMAGE_FORMAT: ImageFormatId = ImageFormatId::Bootloader;
+    const SUPPORTED_IMAGE_FORMATS: &'static [ImageFormatId] = &[ImageFormatId::Bootloader];
+
+    const SUPPORTED_TARGETS: &'static [&'static str] =
+        &["riscv32imc-unknown-none-elf", "riscv32imc-esp-espidf"];
+
+    fn chip_features(&self, _connection: &mut Connection) -> Result<Vec<&str>, Error> {
+        Ok(vec!["WiFi", "BLE"])
+    }
+
+    fn default_partition_table(&self, layout: FlashLayout) -> Option<PartitionTable> {
+        Some(PARAMS.with_layout(layout).default_partition_table())
+    }
+
+    fn get_flash_segments<'a>(
+        image: &'a FirmwareImage,
+        bootloader: Option<Vec<u8>>,
+        partition_table: Option<PartitionTable>,
+        image_format: ImageFormatId,
+        chip_revision: Option<u32>,
+    ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        match image_format {
+            ImageFormatId::Bootloader => Ok(Box::new(Esp32BootloaderFormat::new(
+                image,
+                Chip::Esp32c2,
+                PARAMS,
+                partition_table,
+                bootloader,
+                chip_revision,
+            )?)),
+            _ => Err(
+                UnsupportedImageFormatError::new(image_format, Chip::Esp32c2, chip_revision).into(),
+            ),
+        }
+    }
+}
+
+impl ReadEFuse for Esp32c2 {
+    const EFUSE_REG_BASE: u32 = 0x6000882c;
+}
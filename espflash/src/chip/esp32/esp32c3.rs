@@ -4,7 +4,7 @@ use super::Esp32Params;
 use crate::{
     chip::{ChipType, ReadEFuse, SpiRegisters},
     connection::Connection,
-    elf::FirmwareImage,
+    elf::{FirmwareImage, FlashLayout},
     error::UnsupportedImageFormatError,
     image_format::{Esp32BootloaderFormat, Esp32DirectBootFormat, ImageFormat, ImageFormatId},
     Chip, Error, PartitionTable,
@@ -18,6 +18,12 @@ const IROM_MAP_END: u32 = 0x42800000;
 const DROM_MAP_START: u32 = 0x3c000000;
 const DROM_MAP_END: u32 = 0x3c800000;
 
+const IRAM_START: u32 = 0x4037c000;
+const IRAM_END: u32 = 0x403e0000;
+
+const DRAM_START: u32 = 0x3fc80000;
+const DRAM_END: u32 = 0x3fce0000;
+
 pub const PARAMS: Esp32Params = Esp32Params {
     boot_addr: 0x0,
     partition_addr: 0x8000,
@@ -28,7 +34,6 @@ pub const PARAMS: Esp32Params = Esp32Params {
     app_addr: 0x10000,
     app_size: 0x3f0000,
     chip_id: 5,
-    default_bootloader: include_bytes!("../../../bootloader/esp32c3-bootloader.bin"),
 };
 
 impl ChipType for Esp32c3 {
@@ -49,6 +54,8 @@ impl ChipType for Esp32c3 {
 
     const FLASH_RANGES: &'static [Range<u32>] =
         &[IROM_MAP_START..IROM_MAP_END, DROM_MAP_START..DROM_MAP_END];
+    const IRAM_RANGE: Range<u32> = IRAM_START..IRAM_END;
+    const DRAM_RANGE: Range<u32> = DRAM_START..DRAM_END;
 
     const DEFAULT_IMAGE_FORMAT: ImageFormatId = ImageFormatId::Bootloader;
     const SUPPORTED_IMAGE_FORMATS: &'static [ImageFormatId] =
@@ -66,6 +73,10 @@ impl ChipType for Esp32c3 {
         Ok(40)
     }
 
+    fn default_partition_table(&self, layout: FlashLayout) -> Option<PartitionTable> {
+        Some(PARAMS.with_layout(layout).default_partition_table())
+    }
+
     fn get_flash_segments<'a>(
         image: &'a FirmwareImage,
         bootloader: Option<Vec<u8>>,
@@ -80,6 +91,7 @@ impl ChipType for Esp32c3 {
                 PARAMS,
                 partition_table,
                 bootloader,
+                chip_revision,
             )?)),
             (ImageFormatId::DirectBoot, None | Some(3..)) => {
                 Ok(Box::new(Esp32DirectBootFormat::new(image)?))
@@ -4,7 +4,7 @@ use super::Esp32Params;
 use crate::{
     chip::{ChipType, ReadEFuse, SpiRegisters},
     connection::Connection,
-    elf::FirmwareImage,
+    elf::{FirmwareImage, FlashLayout},
     error::UnsupportedImageFormatError,
     image_format::{Esp32BootloaderFormat, ImageFormat, ImageFormatId},
     Chip, Error, PartitionTable,
@@ -18,6 +18,12 @@ const IROM_MAP_END: u32 = 0x40b80000;
 const DROM_MAP_START: u32 = 0x3F000000;
 const DROM_MAP_END: u32 = 0x3F3F0000;
 
+const IRAM_START: u32 = 0x40020000;
+const IRAM_END: u32 = 0x40070000;
+
+const DRAM_START: u32 = 0x3ffb0000;
+const DRAM_END: u32 = 0x40000000;
+
 pub const PARAMS: Esp32Params = Esp32Params {
     boot_addr: 0x1000,
     partition_addr: 0x8000,
@@ -28,7 +34,6 @@ pub const PARAMS: Esp32Params = Esp32Params {
     app_addr: 0x10000,
     app_size: 0x100000,
     chip_id: 2,
-    default_bootloader: include_bytes!("../../../bootloader/esp32s2-bootloader.bin"),
 };
 
 impl ChipType for Esp32s2 {
@@ -48,6 +53,8 @@ impl ChipType for Esp32s2 {
 
     const FLASH_RANGES: &'static [Range<u32>] =
         &[IROM_MAP_START..IROM_MAP_END, DROM_MAP_START..DROM_MAP_END];
+    const IRAM_RANGE: Range<u32> = IRAM_START..IRAM_END;
+    const DRAM_RANGE: Range<u32> = DRAM_START..DRAM_END;
 
     const DEFAULT_IMAGE_FORMAT: ImageFormatId = ImageFormatId::Bootloader;
     const SUPPORTED_IMAGE_FORMATS: &'static [ImageFormatId] = &[ImageFormatId::Bootloader];
@@ -90,12 +97,16 @@ impl ChipType for Esp32s2 {
         Ok(40)
     }
 
+    fn default_partition_table(&self, layout: FlashLayout) -> Option<PartitionTable> {
+        Some(PARAMS.with_layout(layout).default_partition_table())
+    }
+
     fn get_flash_segments<'a>(
         image: &'a FirmwareImage,
         bootloader: Option<Vec<u8>>,
         partition_table: Option<PartitionTable>,
         image_format: ImageFormatId,
-        _chip_revision: Option<u32>,
+        chip_revision: Option<u32>,
     ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
         match image_format {
             ImageFormatId::Bootloader => Ok(Box::new(Esp32BootloaderFormat::new(
@@ -104,6 +115,7 @@ impl ChipType for Esp32s2 {
                 PARAMS,
                 partition_table,
                 bootloader,
+                chip_revision,
             )?)),
             _ => Err(UnsupportedImageFormatError::new(image_format, Chip::Esp32s2, None).into()),
         }
@@ -1,13 +1,17 @@
-use crate::PartitionTable;
+use crate::{elf::FlashLayout, PartitionTable};
 
 #[allow(clippy::module_inception)]
 mod esp32;
+mod esp32c2;
 mod esp32c3;
+mod esp32h2;
 mod esp32s2;
 mod esp32s3;
 
 pub use esp32::Esp32;
+pub use esp32c2::Esp32c2;
 pub use esp32c3::Esp32c3;
+pub use esp32h2::Esp32h2;
 pub use esp32s2::Esp32s2;
 pub use esp32s3::Esp32s3;
 
@@ -22,10 +26,39 @@ pub struct Esp32Params {
     pub app_addr: u32,
     pub app_size: u32,
     pub chip_id: u16,
-    pub default_bootloader: &'static [u8],
 }
 
 impl Esp32Params {
+    /// The parameters with the offsets of `layout` instead of the defaults
+    ///
+    /// Like in ESP-IDF the data partitions of the default partition table follow
+    /// the partition table, and the application is moved to the first 64 KiB
+    /// boundary after them unless its offset is given. The application partition
+    /// keeps its end, it grows or shrinks with the application offset.
+    pub fn with_layout(mut self, layout: FlashLayout) -> Self {
+        if let Some(offset) = layout.bootloader_offset {
+            self.boot_addr = offset;
+        }
+        if let Some(offset) = layout.partition_table_offset {
+            self.nvs_addr = offset.saturating_add(self.nvs_addr - self.partition_addr);
+            self.phy_init_data_addr =
+                offset.saturating_add(self.phy_init_data_addr - self.partition_addr);
+            self.partition_addr = offset;
+        }
+        let data_end = self
+            .phy_init_data_addr
+            .saturating_add(self.phy_init_data_size + 0xffff);
+        let app_offset = layout
+            .app_offset
+            .unwrap_or_else(|| self.app_addr.max(data_end & !0xffff));
+        if app_offset != self.app_addr {
+            let end = self.app_addr + self.app_size;
+            self.app_addr = app_offset;
+            self.app_size = end.saturating_sub(app_offset);
+        }
+        self
+    }
+
     pub fn default_partition_table(&self) -> PartitionTable {
         PartitionTable::basic(
             self.nvs_addr,
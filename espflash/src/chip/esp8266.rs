@@ -13,6 +13,12 @@ use crate::{
 const IROM_MAP_START: u32 = 0x40200000;
 const IROM_MAP_END: u32 = 0x40300000;
 
+const IRAM_START: u32 = 0x40100000;
+const IRAM_END: u32 = 0x40110000;
+
+const DRAM_START: u32 = 0x3ffe8000;
+const DRAM_END: u32 = 0x40000000;
+
 pub struct Esp8266;
 
 impl ChipType for Esp8266 {
@@ -32,6 +38,8 @@ impl ChipType for Esp8266 {
     };
 
     const FLASH_RANGES: &'static [Range<u32>] = &[IROM_MAP_START..IROM_MAP_END];
+    const IRAM_RANGE: Range<u32> = IRAM_START..IRAM_END;
+    const DRAM_RANGE: Range<u32> = DRAM_START..DRAM_END;
 
     const DEFAULT_IMAGE_FORMAT: ImageFormatId = ImageFormatId::Bootloader;
     const SUPPORTED_IMAGE_FORMATS: &'static [ImageFormatId] = &[ImageFormatId::Bootloader];
@@ -1,12 +1,13 @@
 use std::ops::Range;
 
-use strum_macros::Display;
+use log::warn;
+use strum_macros::{Display, EnumIter};
 
 use crate::{
     connection::Connection,
-    elf::FirmwareImage,
+    elf::{FirmwareImage, FlashLayout},
     error::ChipDetectError,
-    flash_target::{Esp32Target, Esp8266Target, FlashTarget, RamTarget},
+    flash_target::{Encryption, Esp32Target, Esp8266Target, FlashTarget, RamTarget},
     flasher::SpiAttachParams,
     image_format::{ImageFormat, ImageFormatId},
     Error, PartitionTable,
@@ -15,7 +16,7 @@ use crate::{
 mod esp32;
 mod esp8266;
 
-pub use esp32::{Esp32, Esp32Params, Esp32c3, Esp32s2, Esp32s3};
+pub use esp32::{Esp32, Esp32Params, Esp32c2, Esp32c3, Esp32h2, Esp32s2, Esp32s3};
 pub use esp8266::Esp8266;
 use std::str::FromStr;
 
@@ -29,6 +30,10 @@ pub trait ChipType: ReadEFuse {
 
     const SPI_REGISTERS: SpiRegisters;
     const FLASH_RANGES: &'static [Range<u32>];
+    /// Internal RAM the CPU can execute code from
+    const IRAM_RANGE: Range<u32>;
+    /// Internal RAM the CPU can access data in
+    const DRAM_RANGE: Range<u32>;
 
     const DEFAULT_IMAGE_FORMAT: ImageFormatId;
     const SUPPORTED_IMAGE_FORMATS: &'static [ImageFormatId];
@@ -43,10 +48,21 @@ pub trait ChipType: ReadEFuse {
         let uart_div = connection.read_reg(Self::UART_CLKDIV_REG)? & Self::UART_CLKDIV_MASK;
         let est_xtal = (connection.get_baud()? * uart_div) / 1_000_000 / Self::XTAL_CLK_DIVIDER;
         let norm_xtal = if est_xtal > 33 { 40 } else { 26 };
+        if (est_xtal as i64 - norm_xtal as i64).abs() > 1 {
+            warn!(
+                "The crystal frequency of {}MHz differs from the usual {}MHz, the crystal may not be supported",
+                est_xtal, norm_xtal
+            );
+        }
 
         Ok(norm_xtal)
     }
 
+    /// The partition table used when none is given, if the chip uses one.
+    fn default_partition_table(&self, _layout: FlashLayout) -> Option<PartitionTable> {
+        None
+    }
+
     /// Get the firmware segments for writing an image to flash.
     fn get_flash_segments<'a>(
         image: &'a FirmwareImage,
@@ -123,12 +139,16 @@ impl SpiRegisters {
     }
 }
 
-#[derive(Debug, Copy, Clone, Eq, PartialEq, Display)]
+#[derive(Debug, Copy, Clone, Eq, PartialEq, Display, EnumIter)]
 pub enum Chip {
     #[strum(serialize = "ESP32")]
     Esp32,
+    #[strum(serialize = "ESP32-C2")]
+    Esp32c2,
     #[strum(serialize = "ESP32-C3")]
     Esp32c3,
+    #[strum(serialize = "ESP32-H2")]
+    Esp32h2,
     #[strum(serialize = "ESP32-S2")]
     Esp32s2,
     #[strum(serialize = "ESP32-S3")]
@@ -137,15 +157,20 @@ pub enum Chip {
     Esp8266,
 }
 
+serde_plain::derive_deserialize_from_fromstr!(Chip, "a chip like `esp32c3`");
+serde_plain::derive_serialize_from_display!(Chip);
+
 impl FromStr for Chip {
     type Err = Error;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         match s.to_ascii_lowercase().as_str() {
             "esp32" => Ok(Chip::Esp32),
-            "esp32-c3" => Ok(Chip::Esp32c3),
-            "esp32-s2" => Ok(Chip::Esp32s2),
-            "esp32-s3" => Ok(Chip::Esp32s3),
+            "esp32-c2" | "esp32c2" => Ok(Chip::Esp32c2),
+            "esp32-c3" | "esp32c3" => Ok(Chip::Esp32c3),
+            "esp32-h2" | "esp32h2" => Ok(Chip::Esp32h2),
+            "esp32-s2" | "esp32s2" => Ok(Chip::Esp32s2),
+            "esp32-s3" | "esp32s3" => Ok(Chip::Esp32s3),
             "esp8266" => Ok(Chip::Esp8266),
             _ => Err(Error::UnrecognizedChipName),
         }
@@ -156,9 +181,13 @@ impl Chip {
     pub fn from_magic(magic: u32) -> Result<Self, ChipDetectError> {
         match magic {
             Esp32::CHIP_DETECT_MAGIC_VALUE => Ok(Chip::Esp32),
+            Esp32c2::CHIP_DETECT_MAGIC_VALUE | Esp32c2::CHIP_DETECT_MAGIC_VALUE2 => {
+                Ok(Chip::Esp32c2)
+            }
             Esp32c3::CHIP_DETECT_MAGIC_VALUE | Esp32c3::CHIP_DETECT_MAGIC_VALUE2 => {
                 Ok(Chip::Esp32c3)
             }
+            Esp32h2::CHIP_DETECT_MAGIC_VALUE => Ok(Chip::Esp32h2),
             Esp32s2::CHIP_DETECT_MAGIC_VALUE => Ok(Chip::Esp32s2),
             Esp32s3::CHIP_DETECT_MAGIC_VALUE => Ok(Chip::Esp32s3),
             Esp8266::CHIP_DETECT_MAGIC_VALUE => Ok(Chip::Esp8266),
@@ -171,6 +200,8 @@ impl Chip {
             return Some(Chip::Esp32);
         } else if Esp32c3::supports_target(target) {
             return Some(Chip::Esp32c3);
+        } else if Esp32h2::supports_target(target) {
+            return Some(Chip::Esp32h2);
         } else if Esp32s2::supports_target(target) {
             return Some(Chip::Esp32s2);
         } else if Esp32s3::supports_target(target) {
@@ -190,6 +221,7 @@ impl Chip {
         image_format: Option<ImageFormatId>,
         chip_revision: Option<u32>,
     ) -> Result<Box<dyn ImageFormat<'a> + 'a>, Error> {
+        image.check_chip(*self)?;
         let image_format = image_format.unwrap_or_else(|| self.default_image_format());
 
         match self {
@@ -200,6 +232,13 @@ impl Chip {
                 image_format,
                 chip_revision,
             ),
+            Chip::Esp32c2 => Esp32c2::get_flash_segments(
+                image,
+                bootloader,
+                partition_table,
+                image_format,
+                chip_revision,
+            ),
             Chip::Esp32c3 => Esp32c3::get_flash_segments(
                 image,
                 bootloader,
@@ -207,6 +246,13 @@ impl Chip {
                 image_format,
                 chip_revision,
             ),
+            Chip::Esp32h2 => Esp32h2::get_flash_segments(
+                image,
+                bootloader,
+                partition_table,
+                image_format,
+                chip_revision,
+            ),
             Chip::Esp32s2 => Esp32s2::get_flash_segments(
                 image,
                 bootloader,
@@ -227,10 +273,26 @@ impl Chip {
         }
     }
 
+    /// The partition table used when none is given, with the offsets of
+    /// `layout` instead of the defaults
+    pub fn default_partition_table(&self, layout: FlashLayout) -> Option<PartitionTable> {
+        match self {
+            Chip::Esp32 => Esp32.default_partition_table(layout),
+            Chip::Esp32c2 => Esp32c2.default_partition_table(layout),
+            Chip::Esp32c3 => Esp32c3.default_partition_table(layout),
+            Chip::Esp32h2 => Esp32h2.default_partition_table(layout),
+            Chip::Esp32s2 => Esp32s2.default_partition_table(layout),
+            Chip::Esp32s3 => Esp32s3.default_partition_table(layout),
+            Chip::Esp8266 => Esp8266.default_partition_table(layout),
+        }
+    }
+
     pub fn addr_is_flash(&self, addr: u32) -> bool {
         let flash_ranges = match self {
             Chip::Esp32 => Esp32::FLASH_RANGES,
+            Chip::Esp32c2 => Esp32c2::FLASH_RANGES,
             Chip::Esp32c3 => Esp32c3::FLASH_RANGES,
+            Chip::Esp32h2 => Esp32h2::FLASH_RANGES,
             Chip::Esp32s2 => Esp32s2::FLASH_RANGES,
             Chip::Esp32s3 => Esp32s3::FLASH_RANGES,
             Chip::Esp8266 => Esp8266::FLASH_RANGES,
@@ -239,31 +301,82 @@ impl Chip {
         flash_ranges.iter().any(|range| range.contains(&addr))
     }
 
+    /// Whether `addr` can be the entry point of an application, which is
+    /// either in the internal RAM or in the flash mapped for code
+    pub fn addr_is_entry_point(&self, addr: u32) -> bool {
+        self.iram_range().contains(&addr) || self.addr_is_flash(addr)
+    }
+
+    /// Internal RAM the CPU can execute code from
+    pub fn iram_range(&self) -> Range<u32> {
+        match self {
+            Chip::Esp32 => Esp32::IRAM_RANGE,
+            Chip::Esp32c2 => Esp32c2::IRAM_RANGE,
+            Chip::Esp32c3 => Esp32c3::IRAM_RANGE,
+            Chip::Esp32h2 => Esp32h2::IRAM_RANGE,
+            Chip::Esp32s2 => Esp32s2::IRAM_RANGE,
+            Chip::Esp32s3 => Esp32s3::IRAM_RANGE,
+            Chip::Esp8266 => Esp8266::IRAM_RANGE,
+        }
+    }
+
+    /// Internal RAM the CPU can access data in, the same as the IRAM on chips
+    /// with unified memory
+    pub fn dram_range(&self) -> Range<u32> {
+        match self {
+            Chip::Esp32 => Esp32::DRAM_RANGE,
+            Chip::Esp32c2 => Esp32c2::DRAM_RANGE,
+            Chip::Esp32c3 => Esp32c3::DRAM_RANGE,
+            Chip::Esp32h2 => Esp32h2::DRAM_RANGE,
+            Chip::Esp32s2 => Esp32s2::DRAM_RANGE,
+            Chip::Esp32s3 => Esp32s3::DRAM_RANGE,
+            Chip::Esp8266 => Esp8266::DRAM_RANGE,
+        }
+    }
+
+    pub fn is_riscv(&self) -> bool {
+        matches!(self, Chip::Esp32c2 | Chip::Esp32c3 | Chip::Esp32h2)
+    }
+
     pub fn spi_registers(&self) -> SpiRegisters {
         match self {
             Chip::Esp32 => Esp32::SPI_REGISTERS,
+            Chip::Esp32c2 => Esp32c2::SPI_REGISTERS,
             Chip::Esp32c3 => Esp32c3::SPI_REGISTERS,
+            Chip::Esp32h2 => Esp32h2::SPI_REGISTERS,
             Chip::Esp32s2 => Esp32s2::SPI_REGISTERS,
             Chip::Esp32s3 => Esp32s3::SPI_REGISTERS,
             Chip::Esp8266 => Esp8266::SPI_REGISTERS,
         }
     }
 
-    pub fn ram_target(&self) -> Box<dyn FlashTarget> {
-        Box::new(RamTarget::new())
+    pub fn ram_target(&self, entry: Option<u32>) -> Box<dyn FlashTarget> {
+        Box::new(RamTarget::new(entry))
     }
 
-    pub fn flash_target(&self, spi_params: SpiAttachParams) -> Box<dyn FlashTarget> {
+    pub fn flash_target(
+        &self,
+        spi_params: SpiAttachParams,
+        use_compression: bool,
+        encryption: Option<Encryption>,
+    ) -> Box<dyn FlashTarget> {
         match self {
             Chip::Esp8266 => Box::new(Esp8266Target::new()),
-            _ => Box::new(Esp32Target::new(*self, spi_params)),
+            _ => Box::new(Esp32Target::new(
+                *self,
+                spi_params,
+                use_compression,
+                encryption,
+            )),
         }
     }
 
     fn default_image_format(&self) -> ImageFormatId {
         match self {
             Chip::Esp32 => Esp32::DEFAULT_IMAGE_FORMAT,
+            Chip::Esp32c2 => Esp32c2::DEFAULT_IMAGE_FORMAT,
             Chip::Esp32c3 => Esp32c3::DEFAULT_IMAGE_FORMAT,
+            Chip::Esp32h2 => Esp32h2::DEFAULT_IMAGE_FORMAT,
             Chip::Esp32s2 => Esp32s2::DEFAULT_IMAGE_FORMAT,
             Chip::Esp32s3 => Esp32s3::DEFAULT_IMAGE_FORMAT,
             Chip::Esp8266 => Esp8266::DEFAULT_IMAGE_FORMAT,
@@ -273,17 +386,34 @@ impl Chip {
     pub fn supported_image_formats(&self) -> &[ImageFormatId] {
         match self {
             Chip::Esp32 => Esp32::SUPPORTED_IMAGE_FORMATS,
+            Chip::Esp32c2 => Esp32c2::SUPPORTED_IMAGE_FORMATS,
             Chip::Esp32c3 => Esp32c3::SUPPORTED_IMAGE_FORMATS,
+            Chip::Esp32h2 => Esp32h2::SUPPORTED_IMAGE_FORMATS,
             Chip::Esp32s2 => Esp32s2::SUPPORTED_IMAGE_FORMATS,
             Chip::Esp32s3 => Esp32s3::SUPPORTED_IMAGE_FORMATS,
             Chip::Esp8266 => Esp8266::SUPPORTED_IMAGE_FORMATS,
         }
     }
 
+    /// The family ID identifying the chip in UF2 files
+    pub fn uf2_family_id(&self) -> u32 {
+        match self {
+            Chip::Esp32 => 0x1c5f21b0,
+            Chip::Esp32c2 => 0x2b88d29c,
+            Chip::Esp32c3 => 0xd42ba06c,
+            Chip::Esp32h2 => 0x332726f6,
+            Chip::Esp32s2 => 0xbfdd4eee,
+            Chip::Esp32s3 => 0xc47e5767,
+            Chip::Esp8266 => 0x7eab61ed,
+        }
+    }
+
     pub fn supports_target(&self, target: &str) -> bool {
         match self {
             Chip::Esp32 => Esp32::supports_target(target),
+            Chip::Esp32c2 => Esp32c2::supports_target(target),
             Chip::Esp32c3 => Esp32c3::supports_target(target),
+            Chip::Esp32h2 => Esp32h2::supports_target(target),
             Chip::Esp32s2 => Esp32s2::supports_target(target),
             Chip::Esp32s3 => Esp32s3::supports_target(target),
             Chip::Esp8266 => Esp8266::supports_target(target),
@@ -293,7 +423,9 @@ impl Chip {
     pub fn supported_targets(&self) -> &[&str] {
         match self {
             Chip::Esp32 => Esp32::SUPPORTED_TARGETS,
+            Chip::Esp32c2 => Esp32c2::SUPPORTED_TARGETS,
             Chip::Esp32c3 => Esp32c3::SUPPORTED_TARGETS,
+            Chip::Esp32h2 => Esp32h2::SUPPORTED_TARGETS,
             Chip::Esp32s2 => Esp32s2::SUPPORTED_TARGETS,
             Chip::Esp32s3 => Esp32s3::SUPPORTED_TARGETS,
             Chip::Esp8266 => Esp8266::SUPPORTED_TARGETS,
@@ -303,7 +435,9 @@ impl Chip {
     pub fn crystal_freq(&self, connection: &mut Connection) -> Result<u32, Error> {
         match self {
             Chip::Esp32 => Esp32.crystal_freq(connection),
+            Chip::Esp32c2 => Esp32c2.crystal_freq(connection),
             Chip::Esp32c3 => Esp32c3.crystal_freq(connection),
+            Chip::Esp32h2 => Esp32h2.crystal_freq(connection),
             Chip::Esp32s2 => Esp32s2.crystal_freq(connection),
             Chip::Esp32s3 => Esp32s3.crystal_freq(connection),
             Chip::Esp8266 => Esp8266.crystal_freq(connection),
@@ -323,7 +457,9 @@ impl Chip {
     pub fn chip_features(&self, connection: &mut Connection) -> Result<Vec<&str>, Error> {
         match self {
             Chip::Esp32 => Esp32.chip_features(connection),
+            Chip::Esp32c2 => Esp32c2.chip_features(connection),
             Chip::Esp32c3 => Esp32c3.chip_features(connection),
+            Chip::Esp32h2 => Esp32h2.chip_features(connection),
             Chip::Esp32s2 => Esp32s2.chip_features(connection),
             Chip::Esp32s3 => Esp32s3.chip_features(connection),
             Chip::Esp8266 => Esp8266.chip_features(connection),
@@ -333,7 +469,9 @@ impl Chip {
     pub fn mac_address(&self, connection: &mut Connection) -> Result<String, Error> {
         match self {
             Chip::Esp32 => Esp32.mac_address(connection),
+            Chip::Esp32c2 => Esp32c2.mac_address(connection),
             Chip::Esp32c3 => Esp32c3.mac_address(connection),
+            Chip::Esp32h2 => Esp32h2.mac_address(connection),
             Chip::Esp32s2 => Esp32s2.mac_address(connection),
             Chip::Esp32s3 => Esp32s3.mac_address(connection),
             Chip::Esp8266 => Esp8266.mac_address(connection),
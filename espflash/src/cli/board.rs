@@ -0,0 +1,117 @@
+//! Presets for common development boards, selected with `--board`
+//!
+//! A preset sets the chip, the flash settings, the reset behavior and the
+//! monitor speed of a board at once. The built-in presets are listed in
+//! `boards.toml`, further ones can be added to the `[boards]` table of the
+//! configuration file, which take precedence over the built-in ones:
+//!
+//! ```toml
+//! [boards.my-board]
+//! chip = "esp32c3"
+//! flash_size = "4MB"
+//! before = "usb-reset"
+//! ```
+
+use std::collections::BTreeMap;
+
+use serde::{Deserialize, Serialize};
+
+use super::config::Config;
+use crate::{
+    error::Error, Chip, FlashFrequency, FlashMode, FlashSize, ResetAfterOperation,
+    ResetBeforeOperation,
+};
+
+const BOARDS: &str = include_str!("boards.toml");
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone, PartialEq)]
+pub struct Board {
+    pub chip: Option<Chip>,
+    pub flash_mode: Option<FlashMode>,
+    pub flash_size: Option<FlashSize>,
+    pub flash_frequency: Option<FlashFrequency>,
+    pub before: Option<ResetBeforeOperation>,
+    pub after: Option<ResetAfterOperation>,
+    pub monitor_speed: Option<u32>,
+}
+
+/// The built-in board presets
+pub fn builtin_boards() -> BTreeMap<String, Board> {
+    toml::from_str(BOARDS).unwrap()
+}
+
+impl Config {
+    /// Use the settings of the board `name` instead of the configured ones,
+    /// options given on the command line still take precedence
+    pub fn select_board(&mut self, name: &str) -> Result<(), Error> {
+        let mut boards = builtin_boards();
+        boards.extend(self.boards.clone());
+        let board = boards.remove(name).ok_or_else(|| {
+            let names: Vec<_> = boards.keys().map(String::as_str).collect();
+            Error::UnknownBoard(name.into(), names.join(", "))
+        })?;
+
+        self.connection.before = board.before.or(self.connection.before);
+        self.connection.after = board.after.or(self.connection.after);
+        self.flash.mode = board.flash_mode.or(self.flash.mode);
+        self.flash.size = board.flash_size.or(self.flash.size);
+        self.flash.frequency = board.flash_frequency.or(self.flash.frequency);
+        self.monitor.speed = board.monitor_speed.or(self.monitor.speed);
+        self.board = Some((name.into(), board));
+
+        Ok(())
+    }
+
+    /// Check that the connected `chip` is the one of the selected board
+    pub fn check_board_chip(&self, chip: Chip) -> Result<(), Error> {
+        match &self.board {
+            Some((
+                name,
+                Board {
+                    chip: Some(expected),
+                    ..
+                },
+            )) if *expected != chip => Err(Error::BoardChipMismatch(name.clone(), *expected, chip)),
+            _ => Ok(()),
+        }
+    }
+
+    /// The chip of the selected board, if it names one
+    pub fn board_chip(&self) -> Option<Chip> {
+        self.board.as_ref().and_then(|(_, board)| board.chip)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn boards_are_selected() {
+        assert!(builtin_boards().values().all(|board| board.chip.is_some()));
+
+        let mut config: Config = toml::from_str(
+            r#"
+            [flash]
+            size = "16MB"
+            mode = "qio"
+
+            [boards.custom]
+            chip = "esp32"
+            flash_size = "8MB"
+            "#,
+        )
+        .unwrap();
+        config.clone().select_board("missing").unwrap_err();
+
+        config.select_board("custom").unwrap();
+        assert_eq!(config.flash.size, Some(FlashSize::Flash8Mb));
+        assert_eq!(config.flash.mode, Some(FlashMode::Qio));
+        config.check_board_chip(Chip::Esp32).unwrap();
+        config.check_board_chip(Chip::Esp32c3).unwrap_err();
+
+        config.select_board("wemos-d1-mini").unwrap();
+        assert_eq!(config.board_chip(), Some(Chip::Esp8266));
+        assert_eq!(config.flash.size, Some(FlashSize::Flash4Mb));
+    }
+}
@@ -0,0 +1,187 @@
+//! Building the application with cargo before flashing it
+
+use std::{
+    path::PathBuf,
+    process::{Command, Stdio},
+};
+
+use cargo_metadata::Message;
+use clap::Parser;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use crate::error::Error;
+
+#[derive(Parser)]
+pub struct BuildOpts {
+    /// Build the application with cargo in debug mode and flash it, instead of
+    /// flashing an ELF image
+    #[clap(long, conflicts_with_all = &["image", "manifest"])]
+    pub cargo: bool,
+    /// Build the application with cargo in release mode and flash it, instead
+    /// of flashing an ELF image
+    #[clap(long, conflicts_with_all = &["image", "manifest"])]
+    pub release: bool,
+    /// Build and flash the given example
+    #[clap(long, conflicts_with_all = &["image", "manifest"])]
+    pub example: Option<String>,
+    /// Build and flash the given binary of the package
+    #[clap(long, conflicts_with_all = &["image", "manifest"])]
+    pub bin: Option<String>,
+    /// Build and flash the binary of the given package in a workspace
+    #[clap(long, conflicts_with_all = &["image", "manifest"])]
+    pub package: Option<String>,
+}
+
+impl BuildOpts {
+    /// Whether the application is built with cargo instead of given as an ELF
+    /// image
+    pub fn enabled(&self) -> bool {
+        self.cargo
+            || self.release
+            || self.example.is_some()
+            || self.bin.is_some()
+            || self.package.is_some()
+    }
+
+    /// Arguments of `cargo build` selecting the profile and the executable
+    fn args(&self) -> Vec<&str> {
+        let mut args = Vec::new();
+        if self.release {
+            args.push("--release");
+        }
+        if let Some(example) = self.example.as_deref() {
+            args.push("--example");
+            args.push(example);
+        }
+        if let Some(bin) = self.bin.as_deref() {
+            args.push("--bin");
+            args.push(bin);
+        }
+        if let Some(package) = self.package.as_deref() {
+            args.push("--package");
+            args.push(package);
+        }
+
+        args
+    }
+}
+
+/// Build the application in the current directory and return the path of the
+/// executable
+///
+/// The target is the one configured for the project, eg with `build.target` in
+/// `.cargo/config.toml`.
+pub fn build(opts: &BuildOpts) -> Result<PathBuf> {
+    cargo_build(&opts.args())
+}
+
+/// Run `cargo build` with the given arguments and return the path of the
+/// executable it built
+///
+/// The errors and warnings of the compiler are printed as cargo would.
+pub fn cargo_build(args: &[&str]) -> Result<PathBuf> {
+    let output = Command::new("cargo")
+        .arg("build")
+        .args(args)
+        .args(["--message-format", "json-diagnostic-rendered-ansi"])
+        .stdout(Stdio::piped())
+        .stderr(Stdio::inherit())
+        .spawn()
+        .into_diagnostic()
+        .wrap_err("Failed to run cargo")?
+        .wait_with_output()
+        .into_diagnostic()?;
+
+    let executable = find_executable(&output.stdout)?;
+    if !output.status.success() {
+        return Err(Error::BuildFailed.into());
+    }
+
+    Ok(executable.ok_or(Error::NoArtifact)?)
+}
+
+/// Find the executable in the JSON messages of cargo, printing the compiler
+/// messages along the way
+fn find_executable(messages: &[u8]) -> Result<Option<PathBuf>> {
+    let mut executable = None;
+    for message in Message::parse_stream(messages) {
+        match message.into_diagnostic()? {
+            Message::CompilerArtifact(artifact) => {
+                if let Some(path) = artifact.executable {
+                    if executable.is_some() {
+                        return Err(Error::MultipleArtifacts.into());
+                    }
+                    executable = Some(PathBuf::from(path));
+                }
+            }
+            Message::CompilerMessage(message) => {
+                if let Some(rendered) = message.message.rendered {
+                    eprint!("{}", rendered);
+                }
+            }
+            _ => {}
+        }
+    }
+
+    Ok(executable)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn artifact(name: &str, executable: Option<&str>) -> String {
+        let executable = match executable {
+            Some(path) => format!("\"{}\"", path),
+            None => "null".to_string(),
+        };
+        format!(
+            r#"{{"reason":"compiler-artifact","package_id":"{name} 0.1.0 (path+file:///{name})","manifest_path":"/{name}/Cargo.toml","target":{{"kind":["bin"],"crate_types":["bin"],"name":"{name}","src_path":"/{name}/src/main.rs","edition":"2018","doc":true,"doctest":false,"test":true}},"profile":{{"opt_level":"0","debuginfo":2,"debug_assertions":true,"overflow_checks":true,"test":false}},"features":[],"filenames":[],"executable":{executable},"fresh":false}}"#,
+            name = name,
+            executable = executable
+        )
+    }
+
+    #[test]
+    fn the_executable_is_selected() {
+        let mut opts = BuildOpts {
+            cargo: true,
+            release: false,
+            example: None,
+            bin: None,
+            package: None,
+        };
+        assert!(opts.enabled());
+        assert!(opts.args().is_empty());
+
+        opts.cargo = false;
+        assert!(!opts.enabled());
+        opts.release = true;
+        opts.bin = Some("app".into());
+        assert_eq!(opts.args(), ["--release", "--bin", "app"]);
+    }
+
+    #[test]
+    fn the_executable_is_found() {
+        let messages = format!(
+            "{}\n{}\n{{\"reason\":\"build-finished\",\"success\":true}}\n",
+            artifact("lib", None),
+            artifact("app", Some("/app/target/debug/app"))
+        );
+        assert_eq!(
+            find_executable(messages.as_bytes()).unwrap(),
+            Some(PathBuf::from("/app/target/debug/app"))
+        );
+
+        let messages = format!(
+            "{}\n{}\n",
+            artifact("app", Some("/app/target/debug/app")),
+            artifact("tool", Some("/app/target/debug/tool"))
+        );
+        let error = find_executable(messages.as_bytes()).unwrap_err();
+        assert!(matches!(
+            error.downcast_ref::<Error>(),
+            Some(Error::MultipleArtifacts)
+        ));
+    }
+}
@@ -0,0 +1,384 @@
+//! Pretty printing of capture files recorded with `--capture`, and replaying
+//! them against the mock device
+
+use std::{
+    fs,
+    io::{Read, Write},
+    path::{Path, PathBuf},
+    time::Duration,
+};
+
+use clap::Parser;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use crate::{
+    capture::{parse_capture, Record, RecordKind},
+    command::CommandType,
+    encoder::{SlipEncoder, END, ESC, ESC_END, ESC_ESC},
+    error::Error,
+    transport::{mock::MockDevice, Transport},
+};
+
+#[derive(Parser)]
+pub struct PrintCaptureOpts {
+    /// Capture file to print
+    file: PathBuf,
+}
+
+#[derive(Parser)]
+pub struct ReplayCaptureOpts {
+    /// Capture file recorded with `--capture`
+    file: PathBuf,
+}
+
+enum Chunk {
+    /// A complete SLIP frame
+    Frame(Vec<u8>),
+    /// Data received outside of a frame, usually text printed by the ROM
+    Text(Vec<u8>),
+}
+
+/// Splits one direction of the traffic into SLIP frames
+///
+/// Frames can be spread over multiple reads, so the decoder state is kept
+/// across records.
+#[derive(Default)]
+struct SlipStream {
+    in_frame: bool,
+    escape: bool,
+    frame: Vec<u8>,
+}
+
+impl SlipStream {
+    fn feed(&mut self, data: &[u8]) -> Vec<Chunk> {
+        let mut chunks = Vec::new();
+        let mut text = Vec::new();
+
+        for &byte in data {
+            match (self.in_frame, byte) {
+                (false, END) => {
+                    if !text.is_empty() {
+                        chunks.push(Chunk::Text(std::mem::take(&mut text)));
+                    }
+                    self.in_frame = true;
+                }
+                (false, _) => text.push(byte),
+                // A frame delimiter directly following another one starts a new frame
+                (true, END) if self.frame.is_empty() => {}
+                (true, END) => {
+                    chunks.push(Chunk::Frame(std::mem::take(&mut self.frame)));
+                    self.in_frame = false;
+                    self.escape = false;
+                }
+                (true, ESC) => self.escape = true,
+                (true, _) => {
+                    let byte = match (self.escape, byte) {
+                        (true, ESC_END) => END,
+                        (true, ESC_ESC) => ESC,
+                        (_, byte) => byte,
+                    };
+                    self.escape = false;
+                    self.frame.push(byte);
+                }
+            }
+        }
+
+        if !text.is_empty() {
+            chunks.push(Chunk::Text(text));
+        }
+
+        chunks
+    }
+}
+
+fn describe_command(frame: &[u8]) -> String {
+    if frame.len() < 8 || frame[0] != 0 {
+        return format!("{} byte frame {:02x?}", frame.len(), frame);
+    }
+
+    let op = frame[1];
+    let size = u16::from_le_bytes([frame[2], frame[3]]);
+    format!(
+        "{} ({:#04x}) with {} bytes of data",
+        CommandType::from_u8(op),
+        op,
+        size
+    )
+}
+
+fn describe_response(frame: &[u8]) -> String {
+    if frame.len() < 10 || frame[0] != 1 {
+        return format!("{} byte frame {:02x?}", frame.len(), frame);
+    }
+
+    let op = frame[1];
+    let value = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]);
+    let status = match frame[8] {
+        0 => "ok".to_string(),
+        _ => format!("error {:#04x}", frame[9]),
+    };
+    format!(
+        "{} ({:#04x}) response, value {:#010x}, {}",
+        CommandType::from_u8(op),
+        op,
+        value,
+        status
+    )
+}
+
+fn print_line(timestamp: Duration, direction: &str, message: &str) {
+    println!(
+        "[{:>11.6}] {} {}",
+        timestamp.as_secs_f64(),
+        direction,
+        message
+    );
+}
+
+/// Level of a control line, stored in its records
+fn is_high(data: &[u8]) -> bool {
+    data.first() != Some(&0)
+}
+
+/// Baud rate stored in its records
+fn baud_rate(data: &[u8]) -> u32 {
+    let mut baud = [0; 4];
+    let len = data.len().min(4);
+    baud[..len].copy_from_slice(&data[..len]);
+    u32::from_le_bytes(baud)
+}
+
+fn read_capture(file: &Path) -> Result<Vec<u8>> {
+    fs::read(file)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open capture {}", file.display()))
+}
+
+pub fn print_capture(opts: PrintCaptureOpts) -> Result<()> {
+    let data = read_capture(&opts.file)?;
+    let records = parse_capture(&data)?;
+
+    let mut host = SlipStream::default();
+    let mut device = SlipStream::default();
+
+    for record in records {
+        let level = |data: &[u8]| if is_high(data) { "high" } else { "low" };
+
+        match record.kind {
+            RecordKind::Write => {
+                for chunk in host.feed(record.data) {
+                    let message = match chunk {
+                        Chunk::Frame(frame) => describe_command(&frame),
+                        Chunk::Text(text) => format!("{:?}", String::from_utf8_lossy(&text)),
+                    };
+                    print_line(record.timestamp, "->", &message);
+                }
+            }
+            RecordKind::Read => {
+                for chunk in device.feed(record.data) {
+                    let message = match chunk {
+                        Chunk::Frame(frame) => describe_response(&frame),
+                        Chunk::Text(text) => format!("{:?}", String::from_utf8_lossy(&text)),
+                    };
+                    print_line(record.timestamp, "<-", &message);
+                }
+            }
+            RecordKind::Dtr => print_line(
+                record.timestamp,
+                "  ",
+                &format!("DTR {}", level(record.data)),
+            ),
+            RecordKind::Rts => print_line(
+                record.timestamp,
+                "  ",
+                &format!("RTS {}", level(record.data)),
+            ),
+            RecordKind::BaudRate => print_line(
+                record.timestamp,
+                "  ",
+                &format!("baud rate {}", baud_rate(record.data)),
+            ),
+        }
+    }
+
+    Ok(())
+}
+
+/// A command sent to the mock device, with the first response to it in the
+/// capture
+struct Replayed {
+    timestamp: Duration,
+    command: CommandType,
+    response: Option<Vec<u8>>,
+    recorded: Option<Vec<u8>>,
+}
+
+/// Send `frame` to the mock device and return the first frame it responds with
+fn replay_frame(device: &mut MockDevice, frame: &[u8]) -> Result<Option<Vec<u8>>, Error> {
+    device.clear_input()?;
+    let mut encoder = SlipEncoder::new(device)?;
+    encoder.write_all(frame)?;
+    encoder.finish()?;
+
+    let mut output = Vec::new();
+    let mut buf = [0; 256];
+    while let Ok(count) = device.read(&mut buf) {
+        output.extend_from_slice(&buf[..count]);
+    }
+    let response = SlipStream::default()
+        .feed(&output)
+        .into_iter()
+        .find_map(|chunk| match chunk {
+            Chunk::Frame(frame) => Some(frame),
+            Chunk::Text(_) => None,
+        });
+
+    Ok(response)
+}
+
+/// Send the commands of a capture to the mock device, along with the changes of
+/// the control lines and baud rate
+fn replay(records: &[Record<'_>]) -> Result<Vec<Replayed>, Error> {
+    let mut device = MockDevice::new();
+    let mut host = SlipStream::default();
+    let mut received = SlipStream::default();
+    let mut replayed: Vec<Replayed> = Vec::new();
+
+    for record in records {
+        match record.kind {
+            RecordKind::Write => {
+                for chunk in host.feed(record.data) {
+                    let frame = match chunk {
+                        Chunk::Frame(frame) => frame,
+                        Chunk::Text(_) => continue,
+                    };
+                    let command = match frame.get(..2) {
+                        Some([0, op]) => CommandType::from_u8(*op),
+                        _ => continue,
+                    };
+                    replayed.push(Replayed {
+                        timestamp: record.timestamp,
+                        command,
+                        response: replay_frame(&mut device, &frame)?,
+                        recorded: None,
+                    });
+                }
+            }
+            RecordKind::Read => {
+                for chunk in received.feed(record.data) {
+                    if let (Chunk::Frame(frame), Some(command)) = (chunk, replayed.last_mut()) {
+                        command.recorded.get_or_insert(frame);
+                    }
+                }
+            }
+            RecordKind::Dtr => device.set_dtr(is_high(record.data))?,
+            RecordKind::Rts => device.set_rts(is_high(record.data))?,
+            RecordKind::BaudRate => device.set_baud_rate(baud_rate(record.data))?,
+        }
+    }
+
+    Ok(replayed)
+}
+
+/// Send the commands of a capture to the mock device, and print the responses
+/// which differ from the ones in the capture
+///
+/// The mock device is an ESP32-C3, captures recorded with other chips differ
+/// where chip specific registers are read.
+pub fn replay_capture(opts: ReplayCaptureOpts) -> Result<()> {
+    let data = read_capture(&opts.file)?;
+    let replayed = replay(&parse_capture(&data)?)?;
+
+    // Commands the host didn't wait for a response to can't be compared
+    let compared: Vec<_> = replayed
+        .iter()
+        .filter(|command| command.recorded.is_some())
+        .collect();
+    let mut differences = 0;
+    for command in &compared {
+        if command.response == command.recorded {
+            continue;
+        }
+        differences += 1;
+
+        let describe = |frame: &Option<Vec<u8>>| match frame {
+            Some(frame) => describe_response(frame),
+            None => "no response".to_string(),
+        };
+        print_line(
+            command.timestamp,
+            "  ",
+            &format!(
+                "{}: the device sent {}, the mock {}",
+                command.command,
+                describe(&command.recorded),
+                describe(&command.response)
+            ),
+        );
+    }
+
+    println!(
+        "{} of {} responses matched the mock device",
+        compared.len() - differences,
+        compared.len()
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serialport::SerialPortType;
+
+    use super::*;
+    use crate::{
+        capture::CaptureTransport, ConnectSettings, Flasher, ResetAfterOperation,
+        ResetBeforeOperation,
+    };
+
+    #[test]
+    fn frames_are_reassembled_across_reads() {
+        let mut stream = SlipStream::default();
+
+        let chunks = stream.feed(b"ets Jun  8 2016\r\n\xc0\x01\x08\x02\x00");
+        assert!(matches!(&chunks[..], [Chunk::Text(text)] if text == b"ets Jun  8 2016\r\n"));
+
+        let chunks = stream.feed(b"\xdb\xdc\x00\x00\x00\x00\x00\xc0\xc0\x01");
+        assert!(matches!(
+            &chunks[..],
+            [Chunk::Frame(frame)] if frame == b"\x01\x08\x02\x00\xc0\x00\x00\x00\x00\x00"
+        ));
+    }
+
+    #[test]
+    fn captured_sessions_are_replayed() {
+        let path = std::env::temp_dir().join(format!("espflash-{}.capture", std::process::id()));
+        let transport = CaptureTransport::new(Box::new(MockDevice::new()), &path).unwrap();
+        let settings = ConnectSettings {
+            before: ResetBeforeOperation::NoReset,
+            after: ResetAfterOperation::NoReset,
+            ..ConnectSettings::default()
+        };
+        let flasher = Flasher::connect_transport(
+            Box::new(transport),
+            SerialPortType::Unknown,
+            Some(921_600),
+            None,
+            None,
+            settings,
+        )
+        .unwrap();
+        drop(flasher);
+
+        let data = fs::read(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        let replayed = replay(&parse_capture(&data).unwrap()).unwrap();
+        assert!(matches!(replayed[0].command, CommandType::Sync));
+        assert!(replayed
+            .iter()
+            .any(|command| matches!(command.command, CommandType::ChangeBaud)));
+        assert!(replayed
+            .iter()
+            .all(|command| command.recorded.is_none() || command.response == command.recorded));
+    }
+}
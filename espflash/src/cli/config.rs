@@ -3,8 +3,18 @@ use miette::{IntoDiagnostic, Result, WrapErr};
 use serde::{Deserialize, Serialize};
 use serde_hex::{Compact, SerHex};
 use serialport::UsbPortInfo;
+use std::collections::BTreeMap;
+use std::env::current_dir;
 use std::fs::{create_dir_all, read, write};
-use std::path::PathBuf;
+use std::path::{Path, PathBuf};
+
+use super::board::Board;
+use crate::{
+    FlashFrequency, FlashMode, FlashSize, ImageFormatId, ResetAfterOperation, ResetBeforeOperation,
+};
+
+/// Name of the config file, both globally and in projects
+const CONFIG_FILE: &str = "espflash.toml";
 
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct Config {
@@ -12,6 +22,16 @@ pub struct Config {
     pub connection: Connection,
     #[serde(default)]
     pub usb_device: Vec<UsbDevice>,
+    #[serde(default)]
+    pub flash: Flash,
+    #[serde(default)]
+    pub monitor: Monitor,
+    /// Board presets in addition to the built-in ones
+    #[serde(default, skip_serializing_if = "BTreeMap::is_empty")]
+    pub boards: BTreeMap<String, Board>,
+    /// The board selected with `--board`
+    #[serde(skip)]
+    pub board: Option<(String, Board)>,
     #[serde(skip)]
     save_path: PathBuf,
 }
@@ -19,6 +39,39 @@ pub struct Config {
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
 pub struct Connection {
     pub serial: Option<String>,
+    /// Only detect ports of the configured and the known USB devices
+    #[serde(default)]
+    pub only_known_devices: bool,
+    /// Baud rate at which to flash
+    pub speed: Option<u32>,
+    /// How to reset the device before connecting and after flashing, for boards
+    /// with the control lines wired differently
+    pub before: Option<ResetBeforeOperation>,
+    pub after: Option<ResetAfterOperation>,
+    /// Factor the timeouts of the device commands are multiplied with
+    pub timeout_scale: Option<f64>,
+}
+
+/// Defaults for the flash options
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct Flash {
+    pub bootloader: Option<PathBuf>,
+    /// ESP-IDF release of the bundled bootloader to flash
+    pub bootloader_version: Option<String>,
+    pub partition_table: Option<PathBuf>,
+    pub format: Option<ImageFormatId>,
+    pub mode: Option<FlashMode>,
+    pub size: Option<FlashSize>,
+    pub frequency: Option<FlashFrequency>,
+    pub bootloader_offset: Option<u32>,
+    pub partition_table_offset: Option<u32>,
+    pub app_offset: Option<u32>,
+}
+
+#[derive(Debug, Deserialize, Serialize, Default, Clone)]
+pub struct Monitor {
+    /// Baud rate of the serial monitor
+    pub speed: Option<u32>,
 }
 
 #[derive(Debug, Deserialize, Serialize, Default, Clone)]
@@ -27,34 +80,136 @@ pub struct UsbDevice {
     pub vid: u16,
     #[serde(with = "SerHex::<Compact>")]
     pub pid: u16,
+    /// Serial number of the adapter, to recognize it regardless of the name of
+    /// its port
+    #[serde(default, skip_serializing_if = "Option::is_none")]
+    pub serial_number: Option<String>,
 }
 
 impl UsbDevice {
     pub fn matches(&self, port: &UsbPortInfo) -> bool {
-        self.vid == port.vid && self.pid == port.pid
+        self.vid == port.vid
+            && self.pid == port.pid
+            && (self.serial_number.is_none() || self.serial_number == port.serial_number)
     }
 }
 
 impl Config {
-    /// Load the config from config file
+    /// Load the config from the global config file, and from the project config
+    /// file in the current directory or any of its parents
+    ///
+    /// Settings in the project config file take precedence, changes are always
+    /// saved to the global config file.
     pub fn load() -> Result<Self> {
         let dirs = ProjectDirs::from("rs", "esp", "espflash").unwrap();
-        let file = dirs.config_dir().join("espflash.toml");
+        let file = dirs.config_dir().join(CONFIG_FILE);
 
-        let mut config = if let Ok(data) = read(&file) {
-            toml::from_slice(&data).into_diagnostic()?
-        } else {
-            Self::default()
-        };
+        let mut config = Self::read(&file)?.unwrap_or_default();
+        if let Some(project) = find_project_config() {
+            if project != file {
+                if let Some(project_config) = Self::read(&project)? {
+                    config.merge(project_config);
+                }
+            }
+        }
         config.save_path = file;
         Ok(config)
     }
 
+    fn read(path: &Path) -> Result<Option<Self>> {
+        let mut config = match Self::read_unresolved(path)? {
+            Some(config) => config,
+            None => return Ok(None),
+        };
+
+        // Paths are relative to the config file
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let flash = &mut config.flash;
+        for path in flash
+            .bootloader
+            .iter_mut()
+            .chain(flash.partition_table.iter_mut())
+        {
+            *path = dir.join(&path);
+        }
+
+        Ok(Some(config))
+    }
+
+    /// Read the config file at `path` as it is, with the paths relative to it
+    fn read_unresolved(path: &Path) -> Result<Option<Self>> {
+        let data = match read(path) {
+            Ok(data) => data,
+            Err(_) => return Ok(None),
+        };
+        toml::from_slice(&data)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to parse config {}", path.display()))
+            .map(Some)
+    }
+
+    /// Use the settings given in `project` instead of the current ones
+    fn merge(&mut self, project: Config) {
+        let Config {
+            connection,
+            usb_device,
+            flash,
+            monitor,
+            boards,
+            board: _,
+            save_path: _,
+        } = project;
+
+        self.connection.serial = connection.serial.or_else(|| self.connection.serial.take());
+        self.connection.only_known_devices |= connection.only_known_devices;
+        self.connection.speed = connection.speed.or(self.connection.speed);
+        self.connection.before = connection.before.or(self.connection.before);
+        self.connection.after = connection.after.or(self.connection.after);
+        self.connection.timeout_scale = connection.timeout_scale.or(self.connection.timeout_scale);
+        self.usb_device.extend(usb_device);
+        self.flash.bootloader = flash.bootloader.or_else(|| self.flash.bootloader.take());
+        self.flash.bootloader_version = flash
+            .bootloader_version
+            .or_else(|| self.flash.bootloader_version.take());
+        self.flash.partition_table = flash
+            .partition_table
+            .or_else(|| self.flash.partition_table.take());
+        self.flash.format = flash.format.or(self.flash.format);
+        self.flash.mode = flash.mode.or(self.flash.mode);
+        self.flash.size = flash.size.or(self.flash.size);
+        self.flash.frequency = flash.frequency.or(self.flash.frequency);
+        self.flash.bootloader_offset = flash.bootloader_offset.or(self.flash.bootloader_offset);
+        self.flash.partition_table_offset = flash
+            .partition_table_offset
+            .or(self.flash.partition_table_offset);
+        self.flash.app_offset = flash.app_offset.or(self.flash.app_offset);
+        self.monitor.speed = monitor.speed.or(self.monitor.speed);
+        self.boards.extend(boards);
+    }
+
+    /// Directory containing flasher stubs used with `--use-stub` instead of the
+    /// bundled ones
+    pub fn stub_dir() -> PathBuf {
+        let dirs = ProjectDirs::from("rs", "esp", "espflash").unwrap();
+        dirs.config_dir().join("stubs")
+    }
+
+    /// Directory containing the sector caches of the flashed devices
+    pub fn sector_cache_dir() -> PathBuf {
+        let dirs = ProjectDirs::from("rs", "esp", "espflash").unwrap();
+        dirs.cache_dir().join("sectors")
+    }
+
+    /// Change the global config file with `modify_fn`
+    ///
+    /// Only the settings of the global config file are passed to `modify_fn`
+    /// and saved, those of the project config file and the selected board are
+    /// left out.
     pub fn save_with<F: Fn(&mut Self)>(&self, modify_fn: F) -> Result<()> {
-        let mut copy = self.clone();
-        modify_fn(&mut copy);
+        let mut global = Self::read_unresolved(&self.save_path)?.unwrap_or_default();
+        modify_fn(&mut global);
 
-        let serialized = toml::to_string(&copy)
+        let serialized = toml::to_string(&global)
             .into_diagnostic()
             .wrap_err("Failed to serialize config")?;
         create_dir_all(self.save_path.parent().unwrap())
@@ -65,3 +220,97 @@ impl Config {
             .wrap_err_with(|| format!("Failed to write config to {}", self.save_path.display()))
     }
 }
+
+/// Find the project config file in the current directory or its parents
+fn find_project_config() -> Option<PathBuf> {
+    current_dir()
+        .ok()?
+        .ancestors()
+        .map(|dir| dir.join(CONFIG_FILE))
+        .find(|path| path.is_file())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn project_settings_take_precedence() {
+        let mut config: Config = toml::from_str(
+            r#"
+            [connection]
+            serial = "/dev/ttyUSB0"
+
+            [flash]
+            mode = "qio"
+            size = "4MB"
+            "#,
+        )
+        .unwrap();
+        let project: Config = toml::from_str(
+            r#"
+            [connection]
+            before = "no-reset"
+
+            [flash]
+            format = "direct-boot"
+            size = "16mb"
+            frequency = "80m"
+            "#,
+        )
+        .unwrap();
+        config.merge(project);
+
+        assert_eq!(config.connection.serial.as_deref(), Some("/dev/ttyUSB0"));
+        assert_eq!(
+            config.connection.before,
+            Some(ResetBeforeOperation::NoReset)
+        );
+        assert_eq!(config.flash.format, Some(ImageFormatId::DirectBoot));
+        assert_eq!(config.flash.mode, Some(FlashMode::Qio));
+        assert_eq!(config.flash.size, Some(FlashSize::Flash16Mb));
+        assert_eq!(config.flash.frequency, Some(FlashFrequency::Flash80M));
+    }
+
+    #[test]
+    fn changes_are_saved_to_the_global_config_only() {
+        let dir = std::env::temp_dir().join(format!("espflash-config-{}", std::process::id()));
+        let file = dir.join(CONFIG_FILE);
+        create_dir_all(&dir).unwrap();
+        write(&file, "[flash]\nbootloader = \"bootloader.bin\"\n").unwrap();
+
+        let mut config = Config::read(&file).unwrap().unwrap();
+        config.merge(
+            toml::from_str(
+                r#"
+                [connection]
+                serial = "/dev/ttyUSB1"
+
+                [flash]
+                partition_table = "partitions.csv"
+                "#,
+            )
+            .unwrap(),
+        );
+        config.save_path = file.clone();
+        config
+            .save_with(|config| {
+                config.usb_device.push(UsbDevice {
+                    vid: 0x303a,
+                    pid: 0x1001,
+                    serial_number: None,
+                })
+            })
+            .unwrap();
+
+        let saved = Config::read_unresolved(&file).unwrap().unwrap();
+        std::fs::remove_dir_all(&dir).unwrap();
+        assert_eq!(saved.usb_device.len(), 1);
+        assert_eq!(saved.connection.serial, None);
+        assert_eq!(saved.flash.partition_table, None);
+        assert_eq!(
+            saved.flash.bootloader,
+            Some(PathBuf::from("bootloader.bin"))
+        );
+    }
+}
@@ -0,0 +1,147 @@
+//! Reading core dumps from the device and printing the backtraces of its tasks
+
+use std::{fs, path::PathBuf};
+
+use clap::Parser;
+use log::{info, warn};
+use miette::{IntoDiagnostic, Result, WrapErr};
+use sha2::{Digest, Sha256};
+
+use super::{
+    config::Config, connect, deadline::deadline, device_partition_table, input::read_input,
+    parse_u32, symbols::Symbols, ConnectOpts,
+};
+use crate::{
+    coredump::{self, Coredump, Task, HEADER_SIZE},
+    error::Error,
+    partition_table::{DataType, SubType, Type},
+    FlashLayout,
+};
+
+#[derive(Parser)]
+pub struct CoredumpOpts {
+    /// ELF image of the application which crashed, to resolve the backtraces
+    pub image: PathBuf,
+    /// Read the core dump from a file containing the coredump partition,
+    /// instead of from the device
+    #[clap(long)]
+    pub file: Option<PathBuf>,
+    /// Partition table to look the coredump partition up in, read from the
+    /// device if not given
+    #[clap(long)]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub partition_table_offset: Option<u32>,
+    /// Save the ELF core file to this path, eg to load it into GDB
+    #[clap(long)]
+    pub output: Option<PathBuf>,
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+}
+
+pub fn coredump(opts: CoredumpOpts, config: Config) -> Result<()> {
+    let elf = read_input(&opts.image)?;
+    let dump = match &opts.file {
+        Some(path) => read_input(path)?,
+        None => read_dump(&opts, &config)?,
+    };
+
+    let core = coredump::extract_elf(&dump)?;
+    if let Some(path) = &opts.output {
+        fs::write(path, core)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+        info!("Saved the ELF core file to {}", path.display());
+    }
+    let coredump = Coredump::parse(core)?;
+
+    if let Some(sha) = &coredump.app_elf_sha256 {
+        let digest: String = Sha256::digest(&elf)
+            .iter()
+            .map(|byte| format!("{:02x}", byte))
+            .collect();
+        if sha.is_empty() || !digest.starts_with(sha.as_str()) {
+            warn!(
+                "The core dump was saved by another application, with the ELF SHA-256 {}",
+                sha
+            );
+        }
+    }
+
+    let symbols = Symbols::try_from(&elf);
+    if let Some(details) = &coredump.panic_details {
+        println!("Panic: {}", details);
+    }
+
+    // The crashed task first, followed by the other ones
+    let mut tasks = coredump.tasks.clone();
+    tasks.sort_by_key(|task| Some(task.tcb) != coredump.crashed_task);
+    for (index, task) in tasks.iter().enumerate() {
+        if index > 0 {
+            println!();
+        }
+        if Some(task.tcb) == coredump.crashed_task {
+            println!("Crashed task {:#010x}:", task.tcb);
+            for (name, value) in &coredump.exception_registers {
+                match coredump::exception_cause(*value).filter(|_| name == "EXCCAUSE") {
+                    Some(cause) => println!("  {:<9} {:#010x} ({})", name, value, cause),
+                    None => println!("  {:<9} {:#010x}", name, value),
+                }
+            }
+        } else {
+            println!("Task {:#010x}:", task.tcb);
+        }
+        print_backtrace(&coredump, task, symbols.as_ref());
+    }
+
+    Ok(())
+}
+
+/// Read the core dump from the coredump partition of the device
+fn read_dump(opts: &CoredumpOpts, config: &Config) -> Result<Vec<u8>> {
+    let _deadline = deadline(opts.connect_opts.timeout);
+    let mut flasher = connect(&opts.connect_opts, config)?;
+    flasher.set_flash_layout(FlashLayout {
+        partition_table_offset: opts
+            .partition_table_offset
+            .or(config.flash.partition_table_offset),
+        ..FlashLayout::default()
+    });
+
+    let table = device_partition_table(&mut flasher, opts.partition_table.as_deref())?;
+    let partition = table
+        .find_by_subtype(Type::Data, SubType::Data(DataType::CoreDump))
+        .ok_or_else(|| Error::MissingPartition("coredump".into()))?;
+
+    // Only read as much of the partition as the dump takes up
+    let mut dump = Vec::new();
+    flasher.read_flash(partition.offset(), HEADER_SIZE as u32, &mut dump)?;
+    let len = coredump::dump_len(&dump)?;
+    if len > partition.size() {
+        return Err(Error::InvalidCoredump(format!(
+            "the dump of {:#x} bytes exceeds the partition of {:#x} bytes",
+            len,
+            partition.size()
+        ))
+        .into());
+    }
+
+    info!("Reading the core dump of {:#x} bytes", len);
+    dump.clear();
+    flasher.read_flash(partition.offset(), len, &mut dump)?;
+
+    Ok(dump)
+}
+
+fn print_backtrace(coredump: &Coredump, task: &Task, symbols: Option<&Symbols>) {
+    for (index, frame) in coredump.backtrace(task).iter().enumerate() {
+        let location = symbols
+            .and_then(|symbols| symbols.resolve(frame.pc as u64))
+            .unwrap_or_else(|| "??".into());
+        println!(
+            "  #{:<2} {:#010x} (sp {:#010x}) {}",
+            index, frame.pc, frame.stack_pointer, location
+        );
+    }
+}
@@ -0,0 +1,54 @@
+use std::{
+    process::exit,
+    sync::mpsc::{channel, RecvTimeoutError, Sender},
+    thread,
+    time::Duration,
+};
+
+use crossterm::terminal::disable_raw_mode;
+
+use crate::error::{Error, ErrorCategory};
+
+/// Exit code used when an operation is aborted because its deadline expired
+pub const TIMEOUT_EXIT_CODE: i32 = ErrorCategory::Timeout as i32;
+
+/// Watchdog enforcing an overall deadline on an operation
+///
+/// Reads from a hung device or a dead adapter can block indefinitely, so rather
+/// than trying to interrupt them the watchdog terminates the whole process once
+/// the deadline expires. Dropping the guard disarms the watchdog.
+pub struct Deadline {
+    _disarm: Sender<()>,
+}
+
+impl Deadline {
+    pub fn start(timeout: Duration) -> Self {
+        let (disarm, armed) = channel::<()>();
+
+        thread::spawn(move || {
+            // The sender is only ever dropped, so anything other than a timeout means the
+            // guard has been disarmed.
+            if let Err(RecvTimeoutError::Timeout) = armed.recv_timeout(timeout) {
+                abort(timeout);
+            }
+        });
+
+        Deadline { _disarm: disarm }
+    }
+}
+
+/// Start a watchdog if a timeout was given, see [`Deadline`]
+pub fn deadline(timeout: Option<u64>) -> Option<Deadline> {
+    timeout.map(|secs| Deadline::start(Duration::from_secs(secs)))
+}
+
+fn abort(timeout: Duration) -> ! {
+    // The monitor may have put the terminal in raw mode, make sure the user gets a
+    // usable shell back.
+    disable_raw_mode().ok();
+
+    let err = Error::OperationTimeout(timeout.as_secs());
+    eprintln!("\n{:?}", miette::Report::new(err));
+
+    exit(TIMEOUT_EXIT_CODE)
+}
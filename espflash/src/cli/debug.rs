@@ -0,0 +1,327 @@
+//! Handing the device over to a debugger after flashing
+//!
+//! The chip is reset and halted at the entry point of the application through
+//! OpenOCD, which GDB then connects to. An OpenOCD instance which is already
+//! running is used through its Tcl port, otherwise one is started for the chip
+//! and runs until it's interrupted. probe-rs can be used instead, it halts the
+//! chip at its reset vector and GDB then runs it to the entry point.
+
+use std::{
+    io::{BufRead, BufReader, Write},
+    net::{SocketAddr, TcpStream},
+    path::{Path, PathBuf},
+    process::Command,
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::Parser;
+use log::debug;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use crate::{error::Error, Chip, FirmwareImage, Flasher};
+
+/// Tcl port of OpenOCD
+const TCL_PORT: u16 = 6666;
+/// Terminates the commands and responses on the Tcl port
+const TCL_TERMINATOR: u8 = 0x1a;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Debugger {
+    OpenOcd,
+    ProbeRs,
+}
+
+impl FromStr for Debugger {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "openocd" => Ok(Debugger::OpenOcd),
+            "probe-rs" => Ok(Debugger::ProbeRs),
+            _ => Err(format!("unknown debugger `{}`", s)),
+        }
+    }
+}
+
+#[derive(Parser, Debug, Clone)]
+pub struct DebugOpts {
+    /// Halt the chip at the entry point of the application after flashing and
+    /// wait for GDB to connect through OpenOCD or probe-rs
+    #[clap(long, conflicts_with_all = &["monitor", "ram"])]
+    pub debug: bool,
+    /// Debugger GDB connects to with `--debug`
+    #[clap(long, default_value = "openocd", possible_values = &["openocd", "probe-rs"])]
+    pub debugger: Debugger,
+    /// OpenOCD executable to start for `--debug`
+    #[clap(long, default_value = "openocd")]
+    pub openocd: PathBuf,
+    /// OpenOCD configuration file for the debug adapter and the chip, defaults
+    /// to the board file of the chip, eg `board/esp32c3-builtin.cfg`
+    #[clap(long)]
+    pub openocd_config: Vec<String>,
+    /// probe-rs executable to start for `--debug --debugger probe-rs`
+    #[clap(long, default_value = "probe-rs")]
+    pub probe_rs: PathBuf,
+    /// Port the debugger listens for GDB on
+    #[clap(long, default_value_t = 3333)]
+    pub gdb_port: u16,
+}
+
+/// Halt the flashed application at its entry point for GDB
+pub fn debug(flasher: Flasher, elf: &Path, elf_data: &[u8], opts: &DebugOpts) -> Result<()> {
+    let chip = flasher.chip();
+    // The serial port isn't used by the debugger, but releasing it allows the
+    // monitor of another program to open it
+    drop(flasher);
+
+    let entry = FirmwareImage::from_data(elf_data)?.entry();
+    match opts.debugger {
+        Debugger::OpenOcd => debug_openocd(chip, elf, entry, opts),
+        Debugger::ProbeRs => debug_probe_rs(chip, elf, entry, opts),
+    }
+}
+
+fn debug_openocd(chip: Chip, elf: &Path, entry: u32, opts: &DebugOpts) -> Result<()> {
+    let configs = if opts.openocd_config.is_empty() {
+        board_configs(chip)?
+    } else {
+        opts.openocd_config.iter().map(String::as_str).collect()
+    };
+    let commands = tcl_commands(entry);
+    let gdb_command = gdb_command(chip, opts.gdb_port, elf, &[]);
+
+    let tcl_addr = SocketAddr::from(([127, 0, 0, 1], TCL_PORT));
+    if let Ok(stream) = TcpStream::connect_timeout(&tcl_addr, Duration::from_millis(200)) {
+        println!("Using the OpenOCD instance running on port {}", TCL_PORT);
+        run_tcl_commands(stream, &commands)?;
+        println!("Halted at the entry point {:#010x}, connect with:", entry);
+        println!("  {}", gdb_command);
+        return Ok(());
+    }
+
+    println!(
+        "Starting OpenOCD, the application halts at its entry point {:#010x}. Connect with:",
+        entry
+    );
+    println!("  {}", gdb_command);
+
+    run_debugger(
+        &opts.openocd,
+        &openocd_args(&configs, opts.gdb_port, &commands),
+    )
+}
+
+fn debug_probe_rs(chip: Chip, elf: &Path, entry: u32, opts: &DebugOpts) -> Result<()> {
+    let args = probe_rs_args(chip, opts.gdb_port)?;
+    // probe-rs only halts at the reset vector, GDB runs to the entry point
+    let gdb_command = gdb_command(
+        chip,
+        opts.gdb_port,
+        elf,
+        &[format!("hbreak *{:#x}", entry), "continue".to_string()],
+    );
+
+    println!(
+        "Starting probe-rs, the application halts at its entry point {:#010x} once GDB is connected with:",
+        entry
+    );
+    println!("  {}", gdb_command);
+
+    run_debugger(&opts.probe_rs, &args)
+}
+
+/// Run the debugger until it's interrupted
+fn run_debugger(program: &Path, args: &[String]) -> Result<()> {
+    let status = Command::new(program)
+        .args(args)
+        .status()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to start {}", program.display()))?;
+    if !status.success() {
+        return Err(miette::miette!(
+            "{} exited with {}",
+            program.display(),
+            status
+        ));
+    }
+
+    Ok(())
+}
+
+/// Commands resetting the chip and halting it at `entry`, with a temporary
+/// hardware breakpoint
+fn tcl_commands(entry: u32) -> Vec<String> {
+    vec![
+        "reset halt".to_string(),
+        format!("bp {:#x} 2 hw", entry),
+        "resume".to_string(),
+        "wait_halt 10000".to_string(),
+        format!("rbp {:#x}", entry),
+    ]
+}
+
+/// Arguments of OpenOCD running `commands` once it's initialized
+fn openocd_args(configs: &[&str], gdb_port: u16, commands: &[String]) -> Vec<String> {
+    let mut args = Vec::new();
+    for config in configs {
+        args.push("-f".to_string());
+        args.push(config.to_string());
+    }
+    args.push("-c".to_string());
+    args.push(format!("gdb_port {}", gdb_port));
+    args.push("-c".to_string());
+    args.push("init".to_string());
+    for command in commands {
+        args.push("-c".to_string());
+        args.push(command.clone());
+    }
+
+    args
+}
+
+/// Arguments of the GDB server of probe-rs, halting the chip at its reset
+/// vector
+fn probe_rs_args(chip: Chip, gdb_port: u16) -> Result<Vec<String>, Error> {
+    let target = match chip {
+        Chip::Esp32 => "esp32",
+        Chip::Esp32c2 => "esp32c2",
+        Chip::Esp32c3 => "esp32c3",
+        Chip::Esp32h2 => "esp32h2",
+        Chip::Esp32s2 => "esp32s2",
+        Chip::Esp32s3 => "esp32s3",
+        Chip::Esp8266 => return Err(Error::DebugNotSupported(chip)),
+    };
+
+    Ok(vec![
+        "gdb".to_string(),
+        "--chip".to_string(),
+        target.to_string(),
+        "--gdb-connection-string".to_string(),
+        format!("127.0.0.1:{}", gdb_port),
+        "--reset-halt".to_string(),
+    ])
+}
+
+/// The GDB command connecting to the debugger and running `commands`
+fn gdb_command(chip: Chip, gdb_port: u16, elf: &Path, commands: &[String]) -> String {
+    let mut command = format!("{} -ex 'target extended-remote :{}'", gdb(chip), gdb_port);
+    for extra in commands {
+        command.push_str(&format!(" -ex '{}'", extra));
+    }
+    command.push_str(&format!(" {}", elf.display()));
+
+    command
+}
+
+fn run_tcl_commands(stream: TcpStream, commands: &[String]) -> Result<()> {
+    let mut reader = BufReader::new(stream.try_clone().into_diagnostic()?);
+    let mut writer = stream;
+
+    for command in commands {
+        writer.write_all(command.as_bytes()).into_diagnostic()?;
+        writer.write_all(&[TCL_TERMINATOR]).into_diagnostic()?;
+
+        let mut response = Vec::new();
+        reader
+            .read_until(TCL_TERMINATOR, &mut response)
+            .into_diagnostic()
+            .wrap_err("Failed to communicate with OpenOCD")?;
+        let response = String::from_utf8_lossy(&response);
+        let response = response.trim_end_matches(TCL_TERMINATOR as char).trim();
+        if !response.is_empty() {
+            debug!("{}: {}", command, response);
+        }
+    }
+
+    Ok(())
+}
+
+/// The OpenOCD board files of the development boards of `chip`, using its
+/// built-in USB JTAG interface if it has one
+fn board_configs(chip: Chip) -> Result<Vec<&'static str>, Error> {
+    let config = match chip {
+        Chip::Esp32 => "board/esp32-wrover-kit-3.3v.cfg",
+        Chip::Esp32c2 => "board/esp32c2-ftdi.cfg",
+        Chip::Esp32c3 => "board/esp32c3-builtin.cfg",
+        Chip::Esp32h2 => "board/esp32h2-builtin.cfg",
+        Chip::Esp32s2 => "board/esp32s2-kaluga-1.cfg",
+        Chip::Esp32s3 => "board/esp32s3-builtin.cfg",
+        Chip::Esp8266 => return Err(Error::DebugNotSupported(chip)),
+    };
+
+    Ok(vec![config])
+}
+
+fn gdb(chip: Chip) -> &'static str {
+    match chip {
+        Chip::Esp32 | Chip::Esp8266 => "xtensa-esp32-elf-gdb",
+        Chip::Esp32s2 => "xtensa-esp32s2-elf-gdb",
+        Chip::Esp32s3 => "xtensa-esp32s3-elf-gdb",
+        Chip::Esp32c2 | Chip::Esp32c3 | Chip::Esp32h2 => "riscv32-esp-elf-gdb",
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{io::Read, net::TcpListener, thread};
+
+    use super::*;
+
+    #[test]
+    fn debugger_commands_are_built() {
+        let commands = tcl_commands(0x4038_0000);
+        assert_eq!(commands[1], "bp 0x40380000 2 hw");
+        assert_eq!(commands[4], "rbp 0x40380000");
+        assert_eq!(
+            openocd_args(&["board/esp32c3-builtin.cfg"], 3334, &commands[..1]),
+            [
+                "-f",
+                "board/esp32c3-builtin.cfg",
+                "-c",
+                "gdb_port 3334",
+                "-c",
+                "init",
+                "-c",
+                "reset halt"
+            ]
+        );
+
+        assert_eq!(
+            probe_rs_args(Chip::Esp32c3, 3333).unwrap()[..3],
+            ["gdb", "--chip", "esp32c3"]
+        );
+        assert!(probe_rs_args(Chip::Esp8266, 3333).is_err());
+        assert_eq!(
+            gdb_command(Chip::Esp32s3, 3333, Path::new("app"), &["continue".into()]),
+            "xtensa-esp32s3-elf-gdb -ex 'target extended-remote :3333' -ex 'continue' app"
+        );
+    }
+
+    #[test]
+    fn tcl_commands_are_terminated() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let addr = listener.local_addr().unwrap();
+        let openocd = thread::spawn(move || {
+            let (mut stream, _) = listener.accept().unwrap();
+            let mut received = Vec::new();
+            let mut buf = [0; 64];
+            // Respond to every command, the last one is `rbp`
+            while !received.ends_with(b"rbp 0x42000000\x1a") {
+                let count = stream.read(&mut buf).unwrap();
+                received.extend_from_slice(&buf[..count]);
+                for _ in buf[..count].iter().filter(|&&byte| byte == TCL_TERMINATOR) {
+                    stream.write_all(b"\x1a").unwrap();
+                }
+            }
+            received
+        });
+
+        let stream = TcpStream::connect(addr).unwrap();
+        run_tcl_commands(stream, &tcl_commands(0x4200_0000)).unwrap();
+        assert_eq!(
+            openocd.join().unwrap(),
+            b"reset halt\x1abp 0x42000000 2 hw\x1aresume\x1await_halt 10000\x1arbp 0x42000000\x1a"
+        );
+    }
+}
@@ -0,0 +1,470 @@
+//! Decoding defmt log frames
+//!
+//! With [defmt] the device doesn't send the log messages as text, only the
+//! index of the interned format string followed by the binary encoded
+//! arguments. The format strings are kept in the `.defmt` section of the ELF
+//! image, which is why decoding requires the image running on the device.
+//!
+//! Every frame is rzCOBS encoded and, as done by `esp-println`, starts with
+//! `0xFF 0x00` and ends with `0x00`. Anything received outside of a frame is
+//! passed through as text, so the output of the ROM bootloader is still shown.
+//!
+//! The defmt 0.3 wire format is decoded, with the exception of bitfields and
+//! slices of values implementing `Format`. Frames using these are shown as
+//! undecodable.
+//!
+//! [defmt]: https://defmt.ferrous-systems.com
+
+use std::{collections::HashMap, convert::TryInto};
+
+use object::{Object, ObjectSection, ObjectSymbol, SymbolSection};
+use serde::Deserialize;
+
+use crate::error::Error;
+
+/// Largest frame which is buffered, longer frames are assumed to be corrupted
+const MAX_FRAME_LEN: usize = 0x10000;
+
+const LEVELS: &[&str] = &["trace", "debug", "info", "warn", "error"];
+
+/// The JSON encoded names of the symbols in the `.defmt` section
+#[derive(Deserialize)]
+struct SymbolName {
+    tag: String,
+    data: String,
+}
+
+struct Entry {
+    tag: String,
+    format: String,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum State {
+    Text,
+    /// The first byte of the start of a frame has been received
+    FrameStart,
+    Frame,
+}
+
+pub(crate) struct DefmtDecoder {
+    table: HashMap<u16, Entry>,
+    timestamp: Option<String>,
+    state: State,
+    frame: Vec<u8>,
+}
+
+impl DefmtDecoder {
+    /// Read the format strings from the `.defmt` section of `elf`
+    pub fn new(elf: &[u8]) -> Result<Self, Error> {
+        let object = object::File::parse(elf).map_err(|_| Error::NoDefmtData)?;
+        let section = object.section_by_name(".defmt").ok_or(Error::NoDefmtData)?;
+
+        let mut table = HashMap::new();
+        let mut timestamp = None;
+        for symbol in object.symbols() {
+            if symbol.section() != SymbolSection::Section(section.index()) {
+                continue;
+            }
+            let name = match symbol.name().map(serde_json::from_str::<SymbolName>) {
+                Ok(Ok(name)) => name,
+                _ => continue,
+            };
+
+            if name.tag == "defmt_timestamp" {
+                timestamp = Some(name.data);
+            } else {
+                let index = (symbol.address() - section.address()) as u16;
+                table.insert(
+                    index,
+                    Entry {
+                        tag: name.tag,
+                        format: name.data,
+                    },
+                );
+            }
+        }
+
+        Ok(Self::from_table(table, timestamp))
+    }
+
+    fn from_table(table: HashMap<u16, Entry>, timestamp: Option<String>) -> Self {
+        DefmtDecoder {
+            table,
+            timestamp,
+            state: State::Text,
+            frame: Vec::new(),
+        }
+    }
+
+    /// Process received data, returning the text to display
+    ///
+    /// Every decoded frame results in a line. A frame may be split over multiple
+    /// calls, it's decoded once the end of the frame has been received.
+    pub fn feed(&mut self, data: &[u8]) -> String {
+        let mut output = String::new();
+        let mut text = Vec::new();
+
+        for &byte in data {
+            match (self.state, byte) {
+                (State::Text, 0xff) => self.state = State::FrameStart,
+                (State::Text, byte) => text.push(byte),
+                (State::FrameStart, 0x00) => {
+                    self.state = State::Frame;
+                    self.frame.clear();
+                }
+                (State::FrameStart, 0xff) => text.push(0xff),
+                (State::FrameStart, byte) => {
+                    text.extend_from_slice(&[0xff, byte]);
+                    self.state = State::Text;
+                }
+                (State::Frame, 0x00) => {
+                    output.push_str(&String::from_utf8_lossy(&text));
+                    text.clear();
+
+                    let line = self
+                        .decode_frame(&self.frame)
+                        .unwrap_or_else(|| "<invalid defmt frame>".to_string());
+                    output.push_str(&line);
+                    output.push('\n');
+                    self.state = State::Text;
+                }
+                (State::Frame, byte) => {
+                    self.frame.push(byte);
+                    if self.frame.len() > MAX_FRAME_LEN {
+                        self.state = State::Text;
+                    }
+                }
+            }
+        }
+
+        output.push_str(&String::from_utf8_lossy(&text));
+        output
+    }
+
+    fn decode_frame(&self, frame: &[u8]) -> Option<String> {
+        let data = rzcobs_decode(frame)?;
+        let mut reader = Reader(&data);
+
+        let entry = self.table.get(&reader.u16()?)?;
+        let level = entry
+            .tag
+            .strip_prefix("defmt_")
+            .filter(|level| LEVELS.contains(level));
+
+        let mut line = String::new();
+        if let Some(level) = level {
+            if let Some(timestamp) = &self.timestamp {
+                line.push_str(&self.format(timestamp, &mut reader)?);
+                line.push(' ');
+            }
+            line.push_str(&format!("{:<5} ", level.to_uppercase()));
+        }
+        line.push_str(&self.format(&entry.format, &mut reader)?);
+
+        Some(line)
+    }
+
+    /// Format the arguments read from `reader` according to `format`
+    fn format(&self, format: &str, reader: &mut Reader) -> Option<String> {
+        let mut output = String::new();
+        let mut chars = format.chars().peekable();
+
+        while let Some(c) = chars.next() {
+            match c {
+                '{' if chars.peek() == Some(&'{') => {
+                    chars.next();
+                    output.push('{');
+                }
+                '}' if chars.peek() == Some(&'}') => {
+                    chars.next();
+                    output.push('}');
+                }
+                '{' => {
+                    let param: String = chars.by_ref().take_while(|&c| c != '}').collect();
+                    output.push_str(&self.param(&param, reader)?);
+                }
+                c => output.push(c),
+            }
+        }
+
+        Some(output)
+    }
+
+    /// Format a single parameter like `=u8:x`, positional parameters are taken
+    /// in order
+    fn param(&self, param: &str, reader: &mut Reader) -> Option<String> {
+        let (spec, hint) = match param.find(':') {
+            Some(colon) => (&param[..colon], &param[colon + 1..]),
+            None => (param, ""),
+        };
+        let ty = match spec.trim_start_matches(|c: char| c.is_ascii_digit()) {
+            "" => "?",
+            ty => ty.strip_prefix('=')?,
+        };
+
+        let value = match ty {
+            "u8" => format_int(reader.uint(1)? as i128, 8, hint),
+            "u16" => format_int(reader.uint(2)? as i128, 16, hint),
+            "u32" => format_int(reader.uint(4)? as i128, 32, hint),
+            "u64" => format_int(reader.uint(8)? as i128, 64, hint),
+            "u128" => format_int(reader.uint(16)? as i128, 128, hint),
+            "i8" => format_int(reader.uint(1)? as u8 as i8 as i128, 8, hint),
+            "i16" => format_int(reader.uint(2)? as u16 as i16 as i128, 16, hint),
+            "i32" => format_int(reader.uint(4)? as u32 as i32 as i128, 32, hint),
+            "i64" => format_int(reader.uint(8)? as u64 as i64 as i128, 64, hint),
+            "i128" => format_int(reader.uint(16)? as i128, 128, hint),
+            "usize" => format_int(reader.leb128()? as i128, 32, hint),
+            "isize" => {
+                let zigzag = reader.leb128()?;
+                format_int((zigzag >> 1) as i128 ^ -((zigzag & 1) as i128), 32, hint)
+            }
+            "f32" => f32::from_bits(reader.uint(4)? as u32).to_string(),
+            "f64" => f64::from_bits(reader.uint(8)? as u64).to_string(),
+            "bool" => (reader.uint(1)? != 0).to_string(),
+            "char" => char::from_u32(reader.uint(4)? as u32)?.to_string(),
+            "str" => reader.str()?,
+            "istr" => self.table.get(&reader.u16()?)?.format.clone(),
+            "[u8]" => {
+                let len = reader.leb128()? as usize;
+                format_bytes(reader.take(len)?, hint)
+            }
+            "?" => self.nested(reader)?,
+            ty if ty.starts_with("__internal_") => reader.str()?,
+            ty => {
+                // Byte arrays like `[u8; 4]`
+                let len = ty.strip_prefix("[u8;")?.strip_suffix(']')?.trim();
+                format_bytes(reader.take(len.parse().ok()?)?, hint)
+            }
+        };
+
+        Some(value)
+    }
+
+    /// Format a value implementing `Format`, which starts with the index of its
+    /// own format string
+    fn nested(&self, reader: &mut Reader) -> Option<String> {
+        let entry = self.table.get(&reader.u16()?)?;
+
+        // The format string of enums lists the variants, separated by `|`, which
+        // are selected by the discriminant
+        let variants = split_variants(&entry.format);
+        if entry.tag == "defmt_derived" && variants.len() > 1 {
+            let discriminant = match variants.len() {
+                0..=0xff => reader.uint(1)?,
+                0x100..=0xffff => reader.uint(2)?,
+                _ => reader.uint(4)?,
+            };
+            self.format(variants.get(discriminant as usize)?, reader)
+        } else {
+            self.format(&entry.format, reader)
+        }
+    }
+}
+
+/// Split the format string of an enum into its variants, ignoring any `|`
+/// within parameters
+fn split_variants(format: &str) -> Vec<&str> {
+    let mut variants = Vec::new();
+    let mut depth = 0;
+    let mut start = 0;
+
+    for (i, c) in format.char_indices() {
+        match c {
+            '{' => depth += 1,
+            '}' => depth -= 1,
+            '|' if depth == 0 => {
+                variants.push(&format[start..i]);
+                start = i + 1;
+            }
+            _ => {}
+        }
+    }
+    variants.push(&format[start..]);
+
+    variants
+}
+
+/// Format an integer of `bits` bits, negative values are shown in two's
+/// complement when using a radix other than 10
+fn format_int(value: i128, bits: u32, hint: &str) -> String {
+    let unsigned = match bits {
+        128 => value as u128,
+        bits => value as u128 & ((1 << bits) - 1),
+    };
+
+    match hint {
+        "x" => format!("{:x}", unsigned),
+        "#x" => format!("{:#x}", unsigned),
+        "X" => format!("{:X}", unsigned),
+        "#X" => format!("{:#X}", unsigned),
+        "b" => format!("{:b}", unsigned),
+        "#b" => format!("{:#b}", unsigned),
+        "o" => format!("{:o}", unsigned),
+        "#o" => format!("{:#o}", unsigned),
+        // Timestamps are shown in seconds
+        "us" => format!("{}.{:06}", value / 1_000_000, value % 1_000_000),
+        "ms" => format!("{}.{:03}", value / 1_000, value % 1_000),
+        _ => value.to_string(),
+    }
+}
+
+fn format_bytes(bytes: &[u8], hint: &str) -> String {
+    if hint == "a" {
+        let ascii: String = bytes
+            .iter()
+            .flat_map(|&byte| std::ascii::escape_default(byte))
+            .map(char::from)
+            .collect();
+        return format!("b\"{}\"", ascii);
+    }
+
+    let bytes: Vec<_> = bytes
+        .iter()
+        .map(|&byte| format_int(byte as i128, 8, hint))
+        .collect();
+    format!("[{}]", bytes.join(", "))
+}
+
+struct Reader<'a>(&'a [u8]);
+
+impl<'a> Reader<'a> {
+    fn take(&mut self, len: usize) -> Option<&'a [u8]> {
+        if len > self.0.len() {
+            return None;
+        }
+        let (taken, rest) = self.0.split_at(len);
+        self.0 = rest;
+        Some(taken)
+    }
+
+    /// Read a little endian unsigned integer of `len` bytes
+    fn uint(&mut self, len: usize) -> Option<u128> {
+        let mut bytes = [0; 16];
+        bytes[..len].copy_from_slice(self.take(len)?);
+        Some(u128::from_le_bytes(bytes))
+    }
+
+    fn u16(&mut self) -> Option<u16> {
+        Some(u16::from_le_bytes(self.take(2)?.try_into().ok()?))
+    }
+
+    fn leb128(&mut self) -> Option<u64> {
+        let mut value = 0;
+        for shift in (0..64).step_by(7) {
+            let byte = self.take(1)?[0];
+            value |= ((byte & 0x7f) as u64) << shift;
+            if byte & 0x80 == 0 {
+                return Some(value);
+            }
+        }
+        None
+    }
+
+    fn str(&mut self) -> Option<String> {
+        let len = self.leb128()? as usize;
+        Some(String::from_utf8_lossy(self.take(len)?).into_owned())
+    }
+}
+
+/// Decode an rzCOBS encoded frame, without the terminating zero
+///
+/// The frame is decoded from the end, each group is either a bitmap of the
+/// zeros among the following 7 bytes, or the length of a run of non-zero
+/// bytes following a zero.
+fn rzcobs_decode(frame: &[u8]) -> Option<Vec<u8>> {
+    let mut decoded = Vec::new();
+    let mut data = frame.iter().rev().copied();
+
+    while let Some(group) = data.next() {
+        match group {
+            0x00 => return None,
+            0x01..=0x7f => {
+                for i in 0..7 {
+                    if group & (1 << (6 - i)) == 0 {
+                        decoded.push(data.next()?);
+                    } else {
+                        decoded.push(0);
+                    }
+                }
+            }
+            0x80..=0xfe => {
+                decoded.push(0);
+                for _ in 0..(group & 0x7f) + 7 {
+                    decoded.push(data.next()?);
+                }
+            }
+            0xff => {
+                for _ in 0..134 {
+                    decoded.push(data.next()?);
+                }
+            }
+        }
+    }
+
+    decoded.reverse();
+    Some(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn decoder() -> DefmtDecoder {
+        let entries = [
+            (1, "defmt_info", "value: {=u8}, {=str}"),
+            (2, "defmt_derived", "None|Some({=i16:#x})"),
+            (3, "defmt_warn", "state {}"),
+        ];
+        let table = entries
+            .iter()
+            .map(|&(index, tag, format)| {
+                (
+                    index,
+                    Entry {
+                        tag: tag.into(),
+                        format: format.into(),
+                    },
+                )
+            })
+            .collect();
+
+        DefmtDecoder::from_table(table, None)
+    }
+
+    #[test]
+    fn decodes_frames() {
+        let mut decoder = decoder();
+
+        // index 1, 42, "hi" and the zeros of the last group
+        let frame = [0x01, 0x2a, 0x02, 0x68, 0x69, 0x42];
+        let mut data = b"boot\n\xff\x00".to_vec();
+        data.extend_from_slice(&frame);
+        data.extend_from_slice(b"\x00done");
+
+        let (first, second) = data.split_at(9);
+        assert_eq!(decoder.feed(first), "boot\n");
+        assert_eq!(decoder.feed(second), "INFO  value: 42, hi\ndone");
+    }
+
+    #[test]
+    fn formats_arguments() {
+        let decoder = decoder();
+
+        // index 3, index 2, variant 1, -2
+        let data = [0x03, 0x00, 0x02, 0x00, 0x01, 0xfe, 0xff];
+        let mut reader = Reader(&data);
+        let entry = &decoder.table[&reader.u16().unwrap()];
+        assert_eq!(
+            decoder.format(&entry.format, &mut reader),
+            Some("state Some(0xfffe)".to_string())
+        );
+
+        assert_eq!(format_int(1234, 32, "ms"), "1.234");
+        assert_eq!(split_variants("A|B({=u8})|C"), vec!["A", "B({=u8})", "C"]);
+        assert_eq!(
+            rzcobs_decode(&[0x01, 0x02, 0x03, 0x04, 0x05, 0x06, 0x07, 0x80]),
+            Some(vec![1, 2, 3, 4, 5, 6, 7, 0])
+        );
+    }
+}
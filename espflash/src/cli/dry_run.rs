@@ -0,0 +1,110 @@
+//! Checking images and printing what flashing them would do, without a device
+
+use std::path::Path;
+
+use log::{info, warn};
+use miette::Result;
+use serde::Serialize;
+
+use super::{load_flash_files, log_segments, print_json, FlashOpts, OutputFormat};
+use crate::{
+    file_format::ihex,
+    flasher::{check_flash_region, FlashPlan},
+    Chip, FlashSize, ImageFormatId, WrittenSegment,
+};
+
+#[derive(Serialize)]
+struct ErasedPartition<'a> {
+    name: &'a str,
+    offset: u32,
+    size: u32,
+}
+
+#[derive(Serialize)]
+struct DryRunReport<'a> {
+    chip: String,
+    erase: Vec<ErasedPartition<'a>>,
+    segments: Vec<WrittenSegment>,
+}
+
+/// Build the image for `chip` like flashing it would, and print the
+/// partitions which would be erased and the data which would be written
+///
+/// Nothing is read from the device, so the flash size given in the options is
+/// used for checking the regions, or 4MB if there is none.
+pub fn dry_run(
+    chip: Chip,
+    elf_data: &[u8],
+    flash_opts: &FlashOpts,
+    bootloader: Option<&Path>,
+    partition_table: Option<&Path>,
+    image_format: Option<ImageFormatId>,
+    output_format: OutputFormat,
+) -> Result<()> {
+    let flash_size = flash_opts.flash_config_opts.flash_size.unwrap_or_else(|| {
+        warn!("No flash size given, checking the image against 4MB of flash");
+        FlashSize::Flash4Mb
+    });
+    if flash_opts.provision_opts.nvs.is_some() {
+        warn!("The NVS partition is generated for the connected device and is not included");
+    }
+
+    let plan = if ihex::is_hex(elf_data) {
+        let segments = ihex::decode(elf_data)?;
+        for (addr, data) in &segments {
+            check_flash_region(*addr, data.len() as u32, flash_size)?;
+        }
+        FlashPlan {
+            erase: Vec::new(),
+            segments,
+            partition_table: None,
+        }
+    } else {
+        let (bootloader, partition_table) = load_flash_files(
+            bootloader,
+            partition_table,
+            flash_opts.flash_config_opts.partition_table_offset,
+        )?;
+        flash_opts.image_settings()?.plan(
+            chip,
+            flash_size,
+            None,
+            elf_data,
+            bootloader,
+            partition_table,
+            image_format,
+        )?
+    };
+
+    info!("Dry run for {}, nothing is written", chip);
+    for (name, offset, size) in &plan.erase {
+        info!(
+            "Would erase the partition `{}` of {:#x} bytes at {:#x}",
+            name, size, offset
+        );
+    }
+    let segments: Vec<_> = plan
+        .segments
+        .iter()
+        .map(|(addr, data)| WrittenSegment::new(*addr, data))
+        .collect();
+    log_segments(&segments);
+
+    if output_format == OutputFormat::Json {
+        print_json(&DryRunReport {
+            chip: chip.to_string(),
+            erase: plan
+                .erase
+                .iter()
+                .map(|(name, offset, size)| ErasedPartition {
+                    name,
+                    offset: *offset,
+                    size: *size,
+                })
+                .collect(),
+            segments,
+        })?;
+    }
+
+    Ok(())
+}
@@ -0,0 +1,48 @@
+//! Dumping the efuses of the connected chip
+
+use clap::Parser;
+use miette::Result;
+
+use super::{config::Config, connect, deadline::deadline, ConnectOpts};
+use crate::{efuse, flasher::flash_manufacturer};
+
+#[derive(Parser)]
+pub struct ReadEfuseOpts {
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+}
+
+pub fn read_efuse(opts: ReadEfuseOpts, config: Config) -> Result<()> {
+    let _deadline = deadline(opts.connect_opts.timeout);
+    let mut flasher = connect(&opts.connect_opts, &config)?;
+    let chip = flasher.chip();
+
+    let contents = flasher.read_efuses()?;
+    for (block, words) in efuse::blocks(chip).iter().zip(&contents) {
+        let words: Vec<_> = words.iter().map(|word| format!("{:08x}", word)).collect();
+        println!(
+            "{:<10} ({:#010x}): {}",
+            block.name,
+            block.addr,
+            words.join(" ")
+        );
+    }
+    println!();
+
+    if let Some(revision) = chip.chip_revision(flasher.connection())? {
+        println!("{:<17} {}", "Chip revision:", revision);
+    }
+    let manufacturer = (flasher.flash_id() & 0xff) as u8;
+    println!(
+        "{:<17} {}",
+        "Flash vendor:",
+        flash_manufacturer(manufacturer)
+            .map(str::to_string)
+            .unwrap_or_else(|| format!("{:#04x}", manufacturer))
+    );
+    for (name, value) in efuse::decode(chip, &contents) {
+        println!("{:<17} {}", format!("{}:", name), value);
+    }
+
+    Ok(())
+}
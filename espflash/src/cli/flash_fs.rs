@@ -0,0 +1,159 @@
+//! Building filesystem images and flashing them to data partitions
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use clap::Parser;
+use log::{info, warn};
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use super::{
+    config::Config, connect, deadline::deadline, device_partition_table, input::read_input,
+    parse_u32, print_flash_report, progress::EspflashProgress, ConnectOpts, OutputFormat,
+};
+use crate::{
+    error::Error,
+    fs::{FsFile, LittleFsConfig, SpiffsConfig},
+    partition_table::{DataType, SubType, Type},
+    FlashLayout,
+};
+
+#[derive(Parser)]
+pub struct FlashFsOpts {
+    /// Directory to build a filesystem image from, or a prebuilt filesystem
+    /// image to flash as is
+    ///
+    /// A LittleFS image is built for `littlefs` partitions, a SPIFFS image for
+    /// all others.
+    pub path: PathBuf,
+    /// Name of the data partition to flash the filesystem to
+    #[clap(long)]
+    pub partition: String,
+    /// Partition table to look the partition up in, read from the device if
+    /// not given
+    #[clap(long)]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub partition_table_offset: Option<u32>,
+    /// Page size of the SPIFFS filesystem, has to match the firmware
+    #[clap(long, default_value_t = 256)]
+    pub page_size: usize,
+    /// Block size of the filesystem, has to match the firmware
+    #[clap(long, default_value_t = 4096)]
+    pub block_size: usize,
+    /// Verify the flash contents after writing them
+    #[clap(long)]
+    pub verify: bool,
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+}
+
+pub fn flash_fs(opts: FlashFsOpts, config: Config, output_format: OutputFormat) -> Result<()> {
+    let _deadline = deadline(opts.connect_opts.timeout);
+    let mut flasher = connect(&opts.connect_opts, &config)?;
+    flasher.board_info()?;
+    flasher.set_flash_layout(FlashLayout {
+        partition_table_offset: opts
+            .partition_table_offset
+            .or(config.flash.partition_table_offset),
+        ..FlashLayout::default()
+    });
+    flasher.set_verify(opts.verify);
+
+    let table = device_partition_table(&mut flasher, opts.partition_table.as_deref())?;
+    let partition = table
+        .find(&opts.partition)
+        .ok_or_else(|| Error::MissingPartition(opts.partition.clone()))?;
+    if partition.ty() != Type::Data {
+        warn!("The partition `{}` is not a data partition", opts.partition);
+    }
+
+    let image = if opts.path.is_dir() {
+        let files = collect_files(&opts.path)?;
+        let littlefs = partition.sub_type() == SubType::Data(DataType::LittleFs);
+        info!(
+            "Building a {} image of {} files from {}",
+            if littlefs { "LittleFS" } else { "SPIFFS" },
+            files.len(),
+            opts.path.display()
+        );
+
+        if littlefs {
+            let config = LittleFsConfig {
+                block_size: opts.block_size,
+                ..LittleFsConfig::default()
+            };
+            config.build(&files, partition.size())?
+        } else {
+            let config = SpiffsConfig {
+                page_size: opts.page_size,
+                block_size: opts.block_size,
+                ..SpiffsConfig::default()
+            };
+            config.build(&files, partition.size())?
+        }
+    } else {
+        let image = read_input(&opts.path)?;
+        if image.len() > partition.size() as usize {
+            return Err(miette::miette!(
+                "The image of {:#x} bytes doesn't fit into the partition `{}` of {:#x} bytes",
+                image.len(),
+                opts.partition,
+                partition.size()
+            ));
+        }
+        image
+    };
+
+    let start = Instant::now();
+    flasher.write_bin_to_flash(
+        partition.offset(),
+        &image,
+        Some(&mut EspflashProgress::default()),
+    )?;
+    info!("Flashing has completed");
+    print_flash_report(&mut flasher, start.elapsed(), output_format)?;
+
+    Ok(())
+}
+
+/// Collect the files below `dir`, named by their path relative to it
+fn collect_files(dir: &Path) -> Result<Vec<FsFile>> {
+    let mut files = Vec::new();
+    let mut dirs = vec![(dir.to_path_buf(), String::new())];
+
+    while let Some((dir, prefix)) = dirs.pop() {
+        let entries = fs::read_dir(&dir)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {}", dir.display()))?;
+
+        for entry in entries {
+            let entry = entry.into_diagnostic()?;
+            let path = entry.path();
+            let name = entry
+                .file_name()
+                .into_string()
+                .map_err(|name| miette::miette!("Invalid file name {:?}", name))?;
+            let name = format!("{}/{}", prefix, name);
+
+            if path.is_dir() {
+                dirs.push((path, name));
+            } else {
+                // Compressed files are kept as they are, web servers often serve them
+                let data = fs::read(&path)
+                    .into_diagnostic()
+                    .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+                files.push(FsFile::new(name, data));
+            }
+        }
+    }
+
+    // The order of directory entries depends on the host
+    files.sort_by(|a, b| a.path.cmp(&b.path));
+
+    Ok(files)
+}
@@ -0,0 +1,81 @@
+//! Showing the received bytes as a hex dump, in the format of `hexdump -C`
+
+/// Bytes shown per row
+const ROW_LEN: usize = 16;
+
+#[derive(Default)]
+pub struct HexDump {
+    /// Offset of the first pending byte in the received data
+    offset: usize,
+    pending: Vec<u8>,
+}
+
+impl HexDump {
+    pub fn push(&mut self, data: &[u8]) {
+        self.pending.extend_from_slice(data);
+    }
+
+    /// The complete rows received so far, and the incomplete one if the data
+    /// is `quiet`, each ending with `newline`
+    pub fn rows(&mut self, quiet: bool, newline: &str) -> String {
+        let mut rows = String::new();
+        let complete = self.pending.len() - self.pending.len() % ROW_LEN;
+        let end = if quiet { self.pending.len() } else { complete };
+
+        for row in self.pending[..end].chunks(ROW_LEN) {
+            rows.push_str(&format_row(self.offset, row));
+            rows.push_str(newline);
+            self.offset += row.len();
+        }
+        self.pending.drain(..end);
+
+        rows
+    }
+}
+
+fn format_row(offset: usize, row: &[u8]) -> String {
+    let mut formatted = format!("{:08x} ", offset);
+    for index in 0..ROW_LEN {
+        if index % 8 == 0 {
+            formatted.push(' ');
+        }
+        match row.get(index) {
+            Some(byte) => formatted.push_str(&format!("{:02x} ", byte)),
+            None => formatted.push_str("   "),
+        }
+    }
+
+    let ascii: String = row
+        .iter()
+        .map(|&byte| {
+            if byte.is_ascii_graphic() || byte == b' ' {
+                byte as char
+            } else {
+                '.'
+            }
+        })
+        .collect();
+    formatted.push_str(&format!(" |{}|", ascii));
+
+    formatted
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn bytes_are_dumped() {
+        let mut dump = HexDump::default();
+        dump.push(b"Hello, world!\n\x00\x01\x02");
+        assert_eq!(
+            dump.rows(false, "\n"),
+            "00000000  48 65 6c 6c 6f 2c 20 77  6f 72 6c 64 21 0a 00 01  |Hello, world!...|\n"
+        );
+        assert_eq!(
+            dump.rows(true, "\n"),
+            "00000010  02                                                |.|\n"
+        );
+        assert_eq!(dump.rows(true, "\n"), "");
+    }
+}
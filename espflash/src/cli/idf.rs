@@ -0,0 +1,223 @@
+//! Flashing the binaries of an ESP-IDF build directory
+//!
+//! ESP-IDF describes the binaries of a build and their offsets in
+//! `flasher_args.json`, and for older versions in the `flash_args` file which
+//! is passed to esptool.py. Either of them, or the build directory holding them,
+//! can be flashed.
+
+use std::{
+    collections::BTreeMap,
+    fs,
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Instant,
+};
+
+use clap::Parser;
+use log::info;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Deserialize;
+
+use super::{
+    config::Config, connect, deadline::deadline, input::read_input, parse_u32, print_flash_report,
+    progress::EspflashProgress, ConnectOpts, OutputFormat,
+};
+use crate::{error::Error, Chip};
+
+#[derive(Parser)]
+pub struct FlashIdfOpts {
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+    /// ESP-IDF build directory, or its `flasher_args.json` or `flash_args` file
+    #[clap(long)]
+    pub build: PathBuf,
+    /// Verify the flash contents after writing them
+    #[clap(long)]
+    pub verify: bool,
+}
+
+#[derive(Deserialize)]
+struct FlasherArgs {
+    flash_files: BTreeMap<String, String>,
+    #[serde(default)]
+    extra_esptool_args: ExtraEsptoolArgs,
+}
+
+#[derive(Deserialize, Default)]
+struct ExtraEsptoolArgs {
+    chip: Option<String>,
+}
+
+/// Binaries listed by an ESP-IDF build, and the chip it's for if known
+struct IdfBuild {
+    chip: Option<Chip>,
+    bins: Vec<(u32, PathBuf)>,
+}
+
+impl IdfBuild {
+    fn load(path: &Path) -> Result<Self> {
+        let path = if path.is_dir() {
+            let json = path.join("flasher_args.json");
+            if json.exists() {
+                json
+            } else {
+                path.join("flash_args")
+            }
+        } else {
+            path.to_path_buf()
+        };
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        let contents = fs::read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+
+        let build = if path.extension().map_or(false, |ext| ext == "json") {
+            IdfBuild::from_json(&contents, dir)
+        } else {
+            IdfBuild::from_flash_args(&contents, dir)
+        };
+        build.wrap_err_with(|| format!("Failed to parse {}", path.display()))
+    }
+
+    fn from_json(json: &str, dir: &Path) -> Result<Self, Error> {
+        let args: FlasherArgs =
+            serde_json::from_str(json).map_err(|err| Error::InvalidIdfBuild(err.to_string()))?;
+
+        let mut bins = Vec::new();
+        for (offset, file) in args.flash_files {
+            bins.push((parse_offset(&offset)?, dir.join(file)));
+        }
+        let chip = args
+            .extra_esptool_args
+            .chip
+            .as_deref()
+            .map(parse_chip)
+            .transpose()?;
+
+        Ok(IdfBuild { chip, bins })
+    }
+
+    /// The `flash_args` file lists the esptool.py options followed by pairs of
+    /// offsets and files
+    fn from_flash_args(flash_args: &str, dir: &Path) -> Result<Self, Error> {
+        let mut words = flash_args.split_whitespace();
+        let mut bins = Vec::new();
+        while let Some(word) = words.next() {
+            if let Some(option) = word.strip_prefix("--") {
+                // Options take a value, eg `--flash_mode dio`, unless they're flags
+                let option = option.replace('_', "-");
+                if !option.contains('=') && !ESPTOOL_FLAGS.contains(&option.as_str()) {
+                    words.next();
+                }
+                continue;
+            }
+            let file = words
+                .next()
+                .ok_or_else(|| Error::InvalidIdfBuild(format!("no file given for {}", word)))?;
+            bins.push((parse_offset(word)?, dir.join(file)));
+        }
+
+        Ok(IdfBuild { chip: None, bins })
+    }
+}
+
+/// The options of `esptool.py write_flash` which don't take a value, with
+/// dashes instead of underscores
+const ESPTOOL_FLAGS: &[&str] = &[
+    "compress",
+    "encrypt",
+    "erase-all",
+    "force",
+    "ignore-flash-encryption-efuse-setting",
+    "no-compress",
+    "no-progress",
+    "no-stub",
+    "verify",
+];
+
+fn parse_offset(offset: &str) -> Result<u32, Error> {
+    parse_u32(offset).map_err(|_| Error::InvalidIdfBuild(format!("invalid offset `{}`", offset)))
+}
+
+fn parse_chip(chip: &str) -> Result<Chip, Error> {
+    Chip::from_str(chip).map_err(|_| Error::InvalidIdfBuild(format!("unknown chip `{}`", chip)))
+}
+
+pub fn flash_idf(opts: FlashIdfOpts, config: Config, output_format: OutputFormat) -> Result<()> {
+    let build = IdfBuild::load(&opts.build)?;
+    if build.bins.is_empty() {
+        return Err(Error::InvalidIdfBuild("no binaries are listed".into()).into());
+    }
+
+    let mut bins = Vec::new();
+    for (offset, path) in &build.bins {
+        bins.push((*offset, read_input(path)?));
+    }
+
+    let _deadline = deadline(opts.connect_opts.timeout);
+    let mut flasher = connect(&opts.connect_opts, &config)?;
+    flasher.board_info()?;
+    if let Some(chip) = build.chip {
+        if chip != flasher.chip() {
+            return Err(Error::IdfChipMismatch(chip, flasher.chip()).into());
+        }
+    }
+    flasher.set_verify(opts.verify);
+
+    let start = Instant::now();
+    flasher.write_bins_to_flash(
+        bins.iter().map(|(offset, data)| (*offset, data.as_slice())),
+        Some(&mut EspflashProgress::default()),
+    )?;
+    info!("Flashing has completed");
+    print_flash_report(&mut flasher, start.elapsed(), output_format)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn builds_are_parsed() {
+        let dir = Path::new("build");
+        let json = r#"{
+            "write_flash_args" : [ "--flash_mode", "dio" ],
+            "flash_files" : {
+                "0x1000" : "bootloader/bootloader.bin",
+                "0x10000" : "app.bin",
+                "0x8000" : "partition_table/partition-table.bin"
+            },
+            "extra_esptool_args" : { "chip" : "esp32c3", "stub" : true }
+        }"#;
+        let build = IdfBuild::from_json(json, dir).unwrap();
+        assert_eq!(build.chip, Some(Chip::Esp32c3));
+        assert_eq!(
+            build.bins,
+            [
+                (0x1000, dir.join("bootloader/bootloader.bin")),
+                (0x10000, dir.join("app.bin")),
+                (0x8000, dir.join("partition_table/partition-table.bin")),
+            ]
+        );
+
+        let flash_args = "--flash_mode dio --flash_freq 40m --flash_size 2MB\n\
+                          0x1000 bootloader/bootloader.bin\n\
+                          0x10000 app.bin\n";
+        let build = IdfBuild::from_flash_args(flash_args, dir).unwrap();
+        assert_eq!(build.chip, None);
+        assert_eq!(
+            build.bins,
+            [
+                (0x1000, dir.join("bootloader/bootloader.bin")),
+                (0x10000, dir.join("app.bin")),
+            ]
+        );
+
+        let flash_args = "--flash_mode dio --compress --flash_size=2MB --no-stub\n\
+                          0x1000 bootloader.bin\n";
+        let build = IdfBuild::from_flash_args(flash_args, dir).unwrap();
+        assert_eq!(build.bins, [(0x1000, dir.join("bootloader.bin"))]);
+    }
+}
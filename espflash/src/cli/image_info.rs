@@ -0,0 +1,140 @@
+//! Printing the contents of application images and ELF files
+
+use std::path::PathBuf;
+
+use clap::Parser;
+use miette::Result;
+
+use super::input::read_input;
+use crate::{
+    image_info::{AppDescriptor, ImageInfo},
+    secure_boot, Chip, FirmwareImage,
+};
+
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+
+#[derive(Parser)]
+pub struct ImageInfoOpts {
+    /// Binary image or ELF file to inspect
+    file: PathBuf,
+}
+
+pub fn image_info(opts: ImageInfoOpts) -> Result<()> {
+    let data = read_input(&opts.file)?;
+
+    if data.starts_with(ELF_MAGIC) {
+        let image = FirmwareImage::from_data(&data)?;
+        println!("{:<17} {:#010x}", "Entry point:", image.entry());
+        if let Some(descriptor) = AppDescriptor::from_elf(&image) {
+            print_descriptor(&descriptor);
+        }
+
+        println!();
+        println!("Segments:");
+        for (index, segment) in image.segments().enumerate() {
+            println!(
+                "  {:<2} {:#010x} {:>8} bytes",
+                index,
+                segment.addr,
+                segment.size()
+            );
+        }
+
+        return Ok(());
+    }
+
+    let info = ImageInfo::parse(&data)?;
+    println!("{:<17} {}", "Chip:", info.chip);
+    println!("{:<17} {}", "Flash mode:", optional(info.flash_mode));
+    println!("{:<17} {}", "Flash size:", optional(info.flash_size));
+    println!(
+        "{:<17} {}",
+        "Flash frequency:",
+        optional(info.flash_frequency)
+    );
+    println!("{:<17} {:#010x}", "Entry point:", info.entry);
+    println!(
+        "{:<17} {:#04x} ({})",
+        "Checksum:",
+        info.checksum.value,
+        validity(info.checksum.valid)
+    );
+    if let Some(digest) = info.digest {
+        println!(
+            "{:<17} {} ({})",
+            "SHA-256 digest:",
+            hex(&digest.value),
+            validity(digest.valid)
+        );
+    }
+    if secure_boot::is_signed(&data) {
+        // A bad signature is reported like the other invalid fields
+        match secure_boot::verify(&data) {
+            Ok(signatures) => {
+                for signature in signatures {
+                    println!(
+                        "{:<17} {} with the key digest {}",
+                        "Signed:",
+                        signature.scheme,
+                        hex(&signature.key_digest)
+                    );
+                }
+            }
+            Err(err) => println!("{:<17} {}", "Signed:", err),
+        }
+    }
+    if let Some(descriptor) = &info.app_descriptor {
+        print_descriptor(descriptor);
+    }
+
+    println!();
+    println!("Segments:");
+    for (index, segment) in info.segments.iter().enumerate() {
+        let memory = if info.chip.addr_is_flash(segment.addr) {
+            "flash"
+        } else {
+            "RAM"
+        };
+        println!(
+            "  {:<2} {:#010x} {:>8} bytes at offset {:#08x} ({})",
+            index, segment.addr, segment.len, segment.offset, memory
+        );
+    }
+    if info.chip == Chip::Esp8266 {
+        println!();
+        println!("No extended header found, assuming an ESP8266 image");
+    }
+
+    Ok(())
+}
+
+fn print_descriptor(descriptor: &AppDescriptor) {
+    println!();
+    println!("{:<17} {}", "Project name:", descriptor.project_name);
+    println!("{:<17} {}", "Version:", descriptor.version);
+    println!("{:<17} {}", "IDF version:", descriptor.idf_version);
+    println!(
+        "{:<17} {} {}",
+        "Build time:", descriptor.date, descriptor.time
+    );
+    println!("{:<17} {}", "Secure version:", descriptor.secure_version);
+    println!("{:<17} {}", "ELF SHA-256:", hex(&descriptor.elf_sha256));
+}
+
+fn optional<T: ToString>(value: Option<T>) -> String {
+    value
+        .map(|value| value.to_string())
+        .unwrap_or_else(|| "unknown".into())
+}
+
+fn validity(valid: bool) -> &'static str {
+    if valid {
+        "valid"
+    } else {
+        "invalid"
+    }
+}
+
+fn hex(bytes: &[u8]) -> String {
+    bytes.iter().map(|byte| format!("{:02x}", byte)).collect()
+}
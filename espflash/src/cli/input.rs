@@ -0,0 +1,66 @@
+//! Reading of input files, which may be compressed
+
+use std::{
+    fs,
+    io::{self, Read},
+    path::Path,
+};
+
+use flate2::read::GzDecoder;
+use miette::{IntoDiagnostic, Result, WrapErr};
+use ruzstd::StreamingDecoder;
+
+const GZIP_MAGIC: &[u8] = &[0x1f, 0x8b];
+const ZSTD_MAGIC: &[u8] = &[0x28, 0xb5, 0x2f, 0xfd];
+
+/// Read an input file, transparently decompressing gzip and zstd files
+///
+/// Compressed files are recognized by their contents rather than their
+/// extension, none of the files we accept can start with either magic.
+pub fn read_input(path: &Path) -> Result<Vec<u8>> {
+    let data = fs::read(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+
+    decompress(data)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to decompress {}", path.display()))
+}
+
+fn decompress(data: Vec<u8>) -> io::Result<Vec<u8>> {
+    let mut decompressed = Vec::new();
+
+    if data.starts_with(GZIP_MAGIC) {
+        GzDecoder::new(data.as_slice()).read_to_end(&mut decompressed)?;
+    } else if data.starts_with(ZSTD_MAGIC) {
+        let mut source = data.as_slice();
+        StreamingDecoder::new(&mut source)
+            .map_err(|e| io::Error::new(io::ErrorKind::InvalidData, e))?
+            .read_to_end(&mut decompressed)?;
+    } else {
+        return Ok(data);
+    }
+
+    Ok(decompressed)
+}
+
+#[cfg(test)]
+mod tests {
+    use std::io::Write;
+
+    use flate2::{write::GzEncoder, Compression};
+
+    use super::*;
+
+    #[test]
+    fn gzip_input_is_decompressed() {
+        let data = b"\x7fELF not really an elf file".to_vec();
+
+        let mut encoder = GzEncoder::new(Vec::new(), Compression::default());
+        encoder.write_all(&data).unwrap();
+        let compressed = encoder.finish().unwrap();
+
+        assert_eq!(decompress(compressed).unwrap(), data);
+        assert_eq!(decompress(data.clone()).unwrap(), data);
+    }
+}
@@ -0,0 +1,212 @@
+//! Editing the input of the monitor locally, and sending it a line at a time
+//!
+//! The line being edited is drawn after the output of the device, which is
+//! written above it as it arrives.
+
+use std::str::FromStr;
+
+use crossterm::event::{KeyCode, KeyEvent, KeyModifiers};
+
+/// Most lines kept in the history
+const MAX_HISTORY: usize = 100;
+
+/// Line ending sent after every line
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Default)]
+pub enum LineEnding {
+    #[default]
+    Cr,
+    Lf,
+    CrLf,
+}
+
+impl FromStr for LineEnding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "cr" => Ok(LineEnding::Cr),
+            "lf" => Ok(LineEnding::Lf),
+            "crlf" => Ok(LineEnding::CrLf),
+            _ => Err(format!("unknown line ending `{}`", s)),
+        }
+    }
+}
+
+impl LineEnding {
+    pub fn as_bytes(self) -> &'static [u8] {
+        match self {
+            LineEnding::Cr => b"\r",
+            LineEnding::Lf => b"\n",
+            LineEnding::CrLf => b"\r\n",
+        }
+    }
+}
+
+/// What a key did to the line being edited
+#[derive(Debug, PartialEq, Eq)]
+pub enum LineEdit {
+    /// The line has to be drawn again
+    Changed,
+    /// The line was completed with Enter and has to be sent
+    Submitted(String),
+    /// The key isn't used for editing
+    Ignored,
+}
+
+#[derive(Default)]
+pub struct LineEditor {
+    line: Vec<char>,
+    /// Position of the cursor in `line`
+    cursor: usize,
+    history: Vec<String>,
+    /// Entry of the history being shown, `None` when editing a new line
+    history_index: Option<usize>,
+    /// The new line, kept while going through the history
+    draft: Vec<char>,
+    /// The incomplete last line of the output, which the line is drawn after
+    prompt: String,
+}
+
+impl LineEditor {
+    pub fn handle_key(&mut self, key: KeyEvent) -> LineEdit {
+        if key.modifiers.contains(KeyModifiers::CONTROL) {
+            return LineEdit::Ignored;
+        }
+
+        match key.code {
+            KeyCode::Char(c) => {
+                self.line.insert(self.cursor, c);
+                self.cursor += 1;
+            }
+            KeyCode::Backspace if self.cursor > 0 => {
+                self.cursor -= 1;
+                self.line.remove(self.cursor);
+            }
+            KeyCode::Delete if self.cursor < self.line.len() => {
+                self.line.remove(self.cursor);
+            }
+            KeyCode::Left if self.cursor > 0 => self.cursor -= 1,
+            KeyCode::Right if self.cursor < self.line.len() => self.cursor += 1,
+            KeyCode::Home => self.cursor = 0,
+            KeyCode::End => self.cursor = self.line.len(),
+            KeyCode::Up => {
+                let index = match self.history_index {
+                    Some(0) => return LineEdit::Ignored,
+                    Some(index) => index - 1,
+                    None if self.history.is_empty() => return LineEdit::Ignored,
+                    None => {
+                        self.draft = std::mem::take(&mut self.line);
+                        self.history.len() - 1
+                    }
+                };
+                self.history_index = Some(index);
+                self.set_line(self.history[index].chars().collect());
+            }
+            KeyCode::Down => {
+                let index = match self.history_index {
+                    Some(index) => index + 1,
+                    None => return LineEdit::Ignored,
+                };
+                if index < self.history.len() {
+                    self.history_index = Some(index);
+                    self.set_line(self.history[index].chars().collect());
+                } else {
+                    self.history_index = None;
+                    let draft = std::mem::take(&mut self.draft);
+                    self.set_line(draft);
+                }
+            }
+            KeyCode::Enter => {
+                let line: String = std::mem::take(&mut self.line).into_iter().collect();
+                self.cursor = 0;
+                self.history_index = None;
+                self.draft.clear();
+                self.prompt.clear();
+                if !line.is_empty() && self.history.last() != Some(&line) {
+                    if self.history.len() == MAX_HISTORY {
+                        self.history.remove(0);
+                    }
+                    self.history.push(line.clone());
+                }
+                return LineEdit::Submitted(line);
+            }
+            _ => return LineEdit::Ignored,
+        }
+
+        LineEdit::Changed
+    }
+
+    fn set_line(&mut self, line: Vec<char>) {
+        self.cursor = line.len();
+        self.line = line;
+    }
+
+    /// The line being edited, with the cursor moved to its position
+    fn draw(&self) -> String {
+        let mut drawn: String = self.line.iter().collect();
+        let behind = self.line.len() - self.cursor;
+        if behind > 0 {
+            drawn.push_str(&format!("\x1b[{}D", behind));
+        }
+        drawn
+    }
+
+    /// Draw the line again, after the output in front of it
+    pub fn redraw(&self) -> String {
+        format!("\r\x1b[K{}{}", self.prompt, self.draw())
+    }
+
+    /// Put `output` of the device in front of the line being edited
+    pub fn wrap_output(&mut self, output: &str) -> String {
+        // The output continues where it left off before the line was drawn
+        let wrapped = format!("\r\x1b[K{}{}", self.prompt, output);
+        match output.rfind('\n') {
+            Some(end) => self.prompt = output[end + 1..].to_string(),
+            None => self.prompt.push_str(output),
+        }
+        wrapped + &self.draw()
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn type_keys(editor: &mut LineEditor, codes: &[KeyCode]) -> Vec<LineEdit> {
+        codes
+            .iter()
+            .map(|&code| editor.handle_key(KeyEvent::new(code, KeyModifiers::NONE)))
+            .collect()
+    }
+
+    #[test]
+    fn lines_are_edited() {
+        let mut editor = LineEditor::default();
+        type_keys(
+            &mut editor,
+            &[
+                KeyCode::Char('A'),
+                KeyCode::Char('T'),
+                KeyCode::Char('x'),
+                KeyCode::Backspace,
+                KeyCode::Home,
+                KeyCode::Char('+'),
+                KeyCode::Delete,
+            ],
+        );
+        assert_eq!(editor.draw(), "+T\x1b[1D");
+        assert_eq!(
+            type_keys(&mut editor, &[KeyCode::Enter]),
+            [LineEdit::Submitted("+T".into())]
+        );
+
+        type_keys(&mut editor, &[KeyCode::Char('A'), KeyCode::Enter]);
+        type_keys(&mut editor, &[KeyCode::Char('B'), KeyCode::Up, KeyCode::Up]);
+        assert_eq!(editor.draw(), "+T");
+        type_keys(&mut editor, &[KeyCode::Down, KeyCode::Down]);
+        assert_eq!(editor.draw(), "B");
+
+        assert_eq!(editor.wrap_output("OK\r\n> "), "\r\x1b[KOK\r\n> B");
+        assert_eq!(editor.redraw(), "\r\x1b[K> B");
+    }
+}
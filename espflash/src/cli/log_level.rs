@@ -0,0 +1,130 @@
+//! Log levels of the lines printed by ESP-IDF and esp-println
+//!
+//! ESP-IDF starts its log lines with the first letter of the level followed by
+//! the time, eg `E (1234) wifi: ...`, esp-println with the name of the level,
+//! eg `ERROR - ...`. Either may be preceded by the color the firmware applied.
+
+use std::str::FromStr;
+
+use crossterm::style::Stylize;
+
+/// Log levels, from the most to the least severe
+#[derive(Copy, Clone, Debug, PartialEq, Eq, PartialOrd, Ord)]
+pub enum LogLevel {
+    Error,
+    Warn,
+    Info,
+    Debug,
+    Verbose,
+}
+
+impl FromStr for LogLevel {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "error" => Ok(LogLevel::Error),
+            "warn" => Ok(LogLevel::Warn),
+            "info" => Ok(LogLevel::Info),
+            "debug" => Ok(LogLevel::Debug),
+            "verbose" | "trace" => Ok(LogLevel::Verbose),
+            _ => Err(format!("unknown log level `{}`", s)),
+        }
+    }
+}
+
+impl LogLevel {
+    /// The level of a log line, `None` if it isn't one
+    pub fn of(line: &str) -> Option<Self> {
+        let line = strip_colors(line);
+        let line = line.trim_start();
+
+        let mut chars = line.chars();
+        let letter = chars.next()?;
+        if chars.as_str().starts_with(" (") {
+            return match letter {
+                'E' => Some(LogLevel::Error),
+                'W' => Some(LogLevel::Warn),
+                'I' => Some(LogLevel::Info),
+                'D' => Some(LogLevel::Debug),
+                'V' => Some(LogLevel::Verbose),
+                _ => None,
+            };
+        }
+
+        let name = match line.strip_prefix('[') {
+            Some(rest) => rest.split(']').next()?.trim_end(),
+            None => line.split(" - ").next()?.trim_end(),
+        };
+        match name {
+            "ERROR" => Some(LogLevel::Error),
+            "WARN" => Some(LogLevel::Warn),
+            "INFO" => Some(LogLevel::Info),
+            "DEBUG" => Some(LogLevel::Debug),
+            "TRACE" => Some(LogLevel::Verbose),
+            _ => None,
+        }
+    }
+
+    /// Color `line` by its level, keeping the line break uncolored
+    pub fn colorize(self, line: &str) -> String {
+        let text = strip_colors(line);
+        let content = text.trim_end_matches(&['\r', '\n'][..]);
+        let line_break = &text[content.len()..];
+        let styled = match self {
+            LogLevel::Error => content.red().to_string(),
+            LogLevel::Warn => content.yellow().to_string(),
+            LogLevel::Info => content.green().to_string(),
+            LogLevel::Debug | LogLevel::Verbose => content.to_string(),
+        };
+        styled + line_break
+    }
+}
+
+/// Remove the colors and text attributes set through escape sequences
+pub fn strip_colors(line: &str) -> String {
+    let mut stripped = String::with_capacity(line.len());
+    let mut rest = line;
+    while let Some(start) = rest.find("\x1b[") {
+        stripped.push_str(&rest[..start]);
+        let params = &rest[start + 2..];
+        let len = params
+            .find(|c: char| !c.is_ascii_digit() && c != ';')
+            .unwrap_or(params.len());
+        if params[len..].starts_with('m') {
+            rest = &params[len + 1..];
+        } else {
+            stripped.push_str(&rest[start..start + 2]);
+            rest = params;
+        }
+    }
+    stripped.push_str(rest);
+    stripped
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn levels_are_parsed() {
+        assert_eq!(
+            LogLevel::of("E (1234) wifi: failed\r\n"),
+            Some(LogLevel::Error)
+        );
+        assert_eq!(
+            LogLevel::of("\x1b[0;33mW (56) boot: flash\x1b[0m\n"),
+            Some(LogLevel::Warn)
+        );
+        assert_eq!(LogLevel::of("INFO - connected\n"), Some(LogLevel::Info));
+        assert_eq!(LogLevel::of("[TRACE] poll\n"), Some(LogLevel::Verbose));
+        assert_eq!(LogLevel::of("Hello world\n"), None);
+        assert_eq!(LogLevel::of("I2C ready\n"), None);
+        assert!(LogLevel::Warn < LogLevel::Info);
+
+        assert_eq!(
+            LogLevel::Debug.colorize("\x1b[0;32mD (1) x: y\x1b[0m\r\n"),
+            "D (1) x: y\r\n"
+        );
+    }
+}
@@ -0,0 +1,95 @@
+use std::{
+    io::{stdout, Write},
+    time::Instant,
+};
+
+use log::{Level, LevelFilter, Log, Metadata, Record};
+
+use super::{LogOpts, OutputFormat};
+
+/// Simple logger which prints user facing messages the same way they used to
+/// be printed before the logging layer existed, and adds a level prefix and
+/// optionally a timestamp for the more detailed levels
+struct Logger {
+    level: LevelFilter,
+    timing: bool,
+    /// Print all messages to stderr, keeping stdout free for the results
+    stderr_only: bool,
+    start: Instant,
+}
+
+impl Logger {
+    /// Whether a record originates from our own crates, dependencies are only
+    /// allowed to print warnings and errors
+    fn is_own(metadata: &Metadata) -> bool {
+        let target = metadata.target();
+        target.starts_with("espflash") || target.starts_with("cargo_espflash")
+    }
+}
+
+impl Log for Logger {
+    fn enabled(&self, metadata: &Metadata) -> bool {
+        metadata.level() <= self.level
+            && (metadata.level() <= Level::Warn || Self::is_own(metadata))
+    }
+
+    fn log(&self, record: &Record) {
+        if !self.enabled(record.metadata()) {
+            return;
+        }
+
+        let prefix = match record.level() {
+            Level::Error => "Error: ",
+            Level::Warn => "Warning: ",
+            Level::Info => "",
+            Level::Debug => "[DEBUG] ",
+            Level::Trace => "[TRACE] ",
+        };
+        let timestamp = if self.timing {
+            format!("[{:>9.3}s] ", self.start.elapsed().as_secs_f64())
+        } else {
+            String::new()
+        };
+
+        match record.level() {
+            _ if self.stderr_only => eprintln!("{}{}{}", timestamp, prefix, record.args()),
+            Level::Error | Level::Warn => eprintln!("{}{}{}", timestamp, prefix, record.args()),
+            _ => println!("{}{}{}", timestamp, prefix, record.args()),
+        }
+    }
+
+    fn flush(&self) {
+        stdout().flush().ok();
+    }
+}
+
+/// Install the logger, using the verbosity requested on the command line
+///
+/// - `-q` only shows errors
+/// - the default shows status messages and progress
+/// - `-v` adds command level protocol information
+/// - `-vv` adds everything, and prefixes every line with a timestamp
+///
+/// With JSON output all messages are printed to stderr.
+pub fn initialize_logger(opts: &LogOpts) {
+    let level = if opts.quiet {
+        LevelFilter::Error
+    } else {
+        match opts.verbose {
+            0 => LevelFilter::Info,
+            1 => LevelFilter::Debug,
+            _ => LevelFilter::Trace,
+        }
+    };
+
+    let logger = Logger {
+        level,
+        timing: level == LevelFilter::Trace,
+        stderr_only: opts.output_format == OutputFormat::Json,
+        start: Instant::now(),
+    };
+
+    if log::set_boxed_logger(Box::new(logger)).is_ok() {
+        log::set_max_level(level);
+    }
+}
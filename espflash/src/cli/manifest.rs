@@ -0,0 +1,227 @@
+//! Flashing the binaries listed in a manifest file
+//!
+//! A manifest describes everything written to a device in production in one
+//! file, the binaries with their offsets or the partitions they belong into,
+//! and whether to encrypt and verify them:
+//!
+//! ```toml
+//! chip = "esp32c3"
+//! partition_table = "partitions.csv"
+//! verify = true
+//!
+//! [[bin]]
+//! path = "bootloader.bin"
+//! offset = 0x0
+//!
+//! [[bin]]
+//! path = "app.bin"
+//! partition = "factory"
+//! encrypt = true
+//! ```
+//!
+//! Paths are relative to the manifest.
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    time::Instant,
+};
+
+use log::{info, warn};
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::Deserialize;
+
+use super::{
+    device_partition_table, input::read_input, print_flash_report, progress::EspflashProgress,
+    OutputFormat,
+};
+use crate::{error::Error, flasher::FLASH_SECTOR_SIZE, BinOptions, Chip, Flasher, PartitionTable};
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct Manifest {
+    /// Chip the binaries are built for, checked against the device
+    pub chip: Option<Chip>,
+    /// Partition table to look up the partitions of the binaries in, the one
+    /// of the device is used if not given
+    pub partition_table: Option<PathBuf>,
+    /// Whether to encrypt the binaries by default, like `--encrypt`
+    pub encrypt: Option<bool>,
+    /// Whether to verify the binaries by default, like `--verify`
+    pub verify: Option<bool>,
+    #[serde(rename = "bin")]
+    pub bins: Vec<ManifestBin>,
+}
+
+#[derive(Debug, Deserialize, PartialEq)]
+#[serde(deny_unknown_fields)]
+pub struct ManifestBin {
+    pub path: PathBuf,
+    /// Offset to write the binary at
+    pub offset: Option<u32>,
+    /// Partition to write the binary to, instead of an offset
+    pub partition: Option<String>,
+    pub encrypt: Option<bool>,
+    pub verify: Option<bool>,
+}
+
+impl Manifest {
+    pub fn load(path: &Path) -> Result<Self> {
+        let contents = fs::read_to_string(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        let mut manifest = Manifest::from_toml(&contents)
+            .wrap_err_with(|| format!("Failed to parse {}", path.display()))?;
+
+        let dir = path.parent().unwrap_or_else(|| Path::new("."));
+        if let Some(table) = &mut manifest.partition_table {
+            *table = dir.join(&table);
+        }
+        for bin in &mut manifest.bins {
+            bin.path = dir.join(&bin.path);
+        }
+
+        Ok(manifest)
+    }
+
+    fn from_toml(toml: &str) -> Result<Self, Error> {
+        let manifest: Manifest =
+            toml::from_str(toml).map_err(|err| Error::InvalidManifest(err.to_string()))?;
+        if manifest.bins.is_empty() {
+            return Err(Error::InvalidManifest("no binaries are listed".into()));
+        }
+        for bin in &manifest.bins {
+            if bin.offset.is_some() == bin.partition.is_some() {
+                return Err(Error::InvalidManifest(format!(
+                    "`{}` needs either an offset or a partition",
+                    bin.path.display()
+                )));
+            }
+        }
+
+        Ok(manifest)
+    }
+
+    fn needs_partition_table(&self) -> bool {
+        self.bins.iter().any(|bin| bin.partition.is_some())
+    }
+}
+
+/// The offset to write `bin` of `len` bytes at
+fn resolve_offset(bin: &ManifestBin, len: usize, table: Option<&PartitionTable>) -> Result<u32> {
+    let name = match (&bin.partition, bin.offset) {
+        (Some(name), _) => name,
+        (None, Some(offset)) => return Ok(offset),
+        (None, None) => unreachable!("checked when parsing the manifest"),
+    };
+    let partition = table
+        .and_then(|table| table.find(name))
+        .ok_or_else(|| Error::MissingPartition(name.clone()))?;
+    if len > partition.size() as usize {
+        return Err(Error::PartitionTooSmall(name.clone(), len, partition.size()).into());
+    }
+
+    Ok(partition.offset())
+}
+
+/// Flash the binaries of the manifest at `path`
+///
+/// The `--encrypt` and `--verify` options apply to the binaries for which the
+/// manifest doesn't set them.
+pub fn flash_manifest(
+    flasher: &mut Flasher,
+    path: &Path,
+    encrypt: bool,
+    verify: bool,
+    output_format: OutputFormat,
+) -> Result<()> {
+    let manifest = Manifest::load(path)?;
+    if let Some(chip) = manifest.chip {
+        if chip != flasher.chip() {
+            return Err(Error::ChipMismatch(chip, flasher.chip()).into());
+        }
+    }
+
+    let table = match &manifest.partition_table {
+        Some(path) => Some(device_partition_table(flasher, Some(path))?),
+        None if manifest.needs_partition_table() => Some(device_partition_table(flasher, None)?),
+        None => None,
+    };
+
+    let mut bins = Vec::new();
+    for bin in &manifest.bins {
+        let data = read_input(&bin.path)?;
+        let offset = resolve_offset(bin, data.len(), table.as_ref())?;
+        if offset as usize % FLASH_SECTOR_SIZE != 0 {
+            warn!(
+                "{:#x} is not aligned to a flash sector, the start of the sector will be erased",
+                offset
+            );
+        }
+        let options = BinOptions {
+            encrypt: bin.encrypt,
+            verify: bin.verify,
+        };
+        bins.push((offset, data, options));
+    }
+
+    flasher.set_encrypt(manifest.encrypt.unwrap_or(encrypt));
+    flasher.set_verify(manifest.verify.unwrap_or(verify));
+
+    let start = Instant::now();
+    flasher.write_bins_to_flash_with_options(
+        bins.iter()
+            .map(|(offset, data, options)| (*offset, data.as_slice(), *options)),
+        Some(&mut EspflashProgress::default()),
+    )?;
+    info!("Flashing has completed");
+    print_flash_report(flasher, start.elapsed(), output_format)?;
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn manifests_are_parsed() {
+        let manifest = Manifest::from_toml(
+            r#"
+            chip = "esp32"
+            verify = true
+
+            [[bin]]
+            path = "bootloader.bin"
+            offset = 0x1000
+
+            [[bin]]
+            path = "app.bin"
+            partition = "factory"
+            encrypt = false
+            "#,
+        )
+        .unwrap();
+        assert_eq!(manifest.chip, Some(Chip::Esp32));
+        assert_eq!(manifest.verify, Some(true));
+        assert_eq!(manifest.bins.len(), 2);
+        assert!(manifest.needs_partition_table());
+
+        let table = PartitionTable::try_from_str(
+            "nvs,data,nvs,0x9000,0x6000,\nfactory,app,factory,0x10000,0x100000,\n",
+        )
+        .unwrap();
+        assert_eq!(
+            resolve_offset(&manifest.bins[0], 0x7000, None).unwrap(),
+            0x1000
+        );
+        assert_eq!(
+            resolve_offset(&manifest.bins[1], 0x80000, Some(&table)).unwrap(),
+            0x10000
+        );
+        assert!(resolve_offset(&manifest.bins[1], 0x200000, Some(&table)).is_err());
+
+        let both = "[[bin]]\npath = \"app.bin\"\noffset = 0x10000\npartition = \"factory\"\n";
+        assert!(Manifest::from_toml(both).is_err());
+    }
+}
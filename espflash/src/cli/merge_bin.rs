@@ -0,0 +1,200 @@
+//! Combining the bootloader, partition table, application and any extra
+//! binaries into a single flash image
+//!
+//! The result can be written with external programmers, or used as the flash
+//! contents when emulating the chip.
+
+use std::{fs, path::PathBuf};
+
+use clap::Parser;
+use log::info;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use super::{
+    bootloader_release, config::Config, input::read_input, load_signing_key, parse_u32,
+    FlashConfigOpts,
+};
+use crate::{
+    elf::RomSegment, error::Error, partition_table::PARTITION_TABLE_OFFSET, Chip, FileFormat,
+    FirmwareImage, FlashSize, ImageFormatId, PartitionTable,
+};
+
+#[derive(Parser)]
+pub struct MergeBinOpts {
+    /// Image format to use for the application
+    #[clap(long)]
+    format: Option<ImageFormatId>,
+    /// Path to a binary (.bin) bootloader file
+    #[clap(long)]
+    bootloader: Option<PathBuf>,
+    /// ESP-IDF release of the bundled bootloader to flash, eg `v4.4`, instead
+    /// of the newest one supporting the chip revision, which `bundled` selects
+    #[clap(long, conflicts_with = "bootloader")]
+    bootloader_version: Option<String>,
+    /// Path to a CSV or binary file containing partition table
+    #[clap(long)]
+    partition_table: Option<PathBuf>,
+    #[clap(flatten)]
+    flash_config_opts: FlashConfigOpts,
+    /// Offset and path of an additional binary, eg `--bin 0x310000 storage.bin`,
+    /// can be given multiple times
+    #[clap(
+        long = "bin",
+        number_of_values = 2,
+        multiple_occurrences = true,
+        value_names = &["OFFSET", "PATH"]
+    )]
+    bins: Vec<String>,
+    /// Pad the image with erased flash up to this size, eg `4MB`
+    #[clap(long)]
+    fill_flash_size: Option<FlashSize>,
+    /// Sign the bootloader and the application for Secure Boot v2 with this
+    /// RSA-3072 or ECDSA private key
+    #[clap(long)]
+    signing_key: Option<PathBuf>,
+    /// File format of the merged image, defaults to the one matching the file extension
+    #[clap(long, possible_values = &["bin", "uf2", "hex"])]
+    file_format: Option<FileFormat>,
+    /// The chip to create an image for
+    chip: Chip,
+    /// ELF image to include
+    image: PathBuf,
+    /// File name to save the merged image to
+    file: PathBuf,
+}
+
+pub fn merge_bin(mut opts: MergeBinOpts, config: Config) -> Result<()> {
+    opts.flash_config_opts.apply_config(&config);
+    let elf_data = read_input(&opts.image)?;
+    let mut image = FirmwareImage::from_data(&elf_data)?;
+    image.apply_flash_settings(opts.flash_config_opts.flash_settings());
+    image.app_descriptor = opts.flash_config_opts.app_descriptor();
+    image.flash_layout = opts.flash_config_opts.flash_layout();
+
+    let bootloader = match (&opts.bootloader, &opts.bootloader_version) {
+        (Some(path), _) => Some(path.clone()),
+        (None, Some(version)) => {
+            image.bootloader_release = bootloader_release(version, opts.chip)?;
+            None
+        }
+        (None, None) => match &config.flash.bootloader_version {
+            Some(version) if config.flash.bootloader.is_none() => {
+                image.bootloader_release = bootloader_release(version, opts.chip)?;
+                None
+            }
+            _ => config.flash.bootloader.clone(),
+        },
+    };
+    let bootloader = bootloader.map(|path| read_input(&path)).transpose()?;
+    let partition_table = match opts
+        .partition_table
+        .as_ref()
+        .or(config.flash.partition_table.as_ref())
+    {
+        Some(path) => Some(
+            PartitionTable::try_from_bytes_at(
+                &read_input(path)?,
+                image
+                    .flash_layout
+                    .partition_table_offset
+                    .unwrap_or(PARTITION_TABLE_OFFSET),
+            )
+            .wrap_err("Failed to parse partition table")?,
+        ),
+        None => None,
+    };
+    let format = opts.format.or(config.flash.format);
+
+    let mut flash_image =
+        opts.chip
+            .get_flash_image(&image, bootloader, partition_table, format, None)?;
+    if let Some(key) = load_signing_key(opts.signing_key.as_deref())? {
+        flash_image.sign(&key)?;
+    }
+    let mut segments: Vec<RomSegment> = flash_image.flash_segments().collect();
+    for pair in opts.bins.chunks(2) {
+        let addr = parse_u32(&pair[0]).map_err(|e| miette::miette!("Invalid offset: {}", e))?;
+        let data = read_input(&PathBuf::from(&pair[1]))?;
+        segments.push(RomSegment {
+            addr,
+            data: data.into(),
+        });
+    }
+
+    let file_format = opts
+        .file_format
+        .unwrap_or_else(|| FileFormat::from_path(&opts.file));
+    let merged = merge_segments(segments, opts.fill_flash_size)?;
+    let merged = file_format.encode(
+        opts.chip,
+        &[RomSegment {
+            addr: 0,
+            data: merged.into(),
+        }],
+    );
+    fs::write(&opts.file, &merged)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", opts.file.display()))?;
+    info!(
+        "Merged image of {:#x} bytes written to {}",
+        merged.len(),
+        opts.file.display()
+    );
+
+    Ok(())
+}
+
+/// Lay out the segments at their flash addresses, starting at address 0
+///
+/// Gaps between segments are filled with `0xFF`, the contents of erased flash.
+pub fn merge_segments(
+    mut segments: Vec<RomSegment>,
+    fill_size: Option<FlashSize>,
+) -> Result<Vec<u8>, Error> {
+    segments.sort_by_key(|segment| segment.addr);
+
+    let mut merged = Vec::new();
+    for segment in &segments {
+        let addr = segment.addr as usize;
+        if addr < merged.len() {
+            return Err(Error::OverlappingSegments(
+                segment.addr,
+                merged.len() as u32,
+            ));
+        }
+        merged.resize(addr, 0xFF);
+        merged.extend_from_slice(&segment.data);
+    }
+
+    if let Some(size) = fill_size {
+        if merged.len() > size.size() as usize {
+            return Err(Error::FlashRegionOutOfRange(0, merged.len() as u32, size));
+        }
+        merged.resize(size.size() as usize, 0xFF);
+    }
+
+    Ok(merged)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn segment(addr: u32, data: &[u8]) -> RomSegment<'static> {
+        RomSegment {
+            addr,
+            data: data.to_vec().into(),
+        }
+    }
+
+    #[test]
+    fn gaps_are_filled_with_erased_flash() {
+        let merged = merge_segments(vec![segment(4, &[2, 2]), segment(1, &[1])], None).unwrap();
+        assert_eq!(merged, [0xFF, 1, 0xFF, 0xFF, 2, 2]);
+
+        assert!(matches!(
+            merge_segments(vec![segment(0, &[1, 1]), segment(1, &[2])], None),
+            Err(Error::OverlappingSegments(1, 2))
+        ));
+    }
+}
@@ -3,68 +3,216 @@
 //! No stability guaranties apply
 
 use std::{
-    fs,
+    fmt, fs,
     path::{Path, PathBuf},
+    process::exit,
+    str::FromStr,
+    time::Duration,
 };
 
 use clap::Parser;
 use config::Config;
-use miette::{IntoDiagnostic, Result, WrapErr};
-use serialport::{FlowControl, SerialPortType};
+use deadline::deadline;
+use indicatif::ProgressBar;
+use input::read_input;
+use log::{info, log_enabled, warn, Level};
+use miette::{miette, IntoDiagnostic, Report, Result, WrapErr};
+use monitor::{monitor_until, MonitorExit, MonitorOpts};
+use progress::EspflashProgress;
+use serde::Serialize;
+use serialport::{FlowControl, SerialPort, SerialPortInfo, SerialPortType};
 
 use crate::{
-    cli::serial::get_serial_port_info, error::Error, Chip, FirmwareImage, Flasher, ImageFormatId,
-    PartitionTable,
+    bootloaders::Bootloader,
+    capture::CaptureTransport,
+    cli::serial::{get_serial_port_info, open_error},
+    error::{Error, ErrorCategory},
+    file_format::ihex,
+    image_info::AppDescriptorSettings,
+    partition_table::PARTITION_TABLE_OFFSET,
+    remote::{self, TcpSerial},
+    secure_boot::{self, SigningKey},
+    stubs::FlashStub,
+    transport::Transport,
+    Chip, ConnectSettings, DeviceInfo, FileFormat, FirmwareImage, FlashFrequency, FlashLayout,
+    FlashMode, FlashSettings, FlashSize, Flasher, ImageFormatId, ImageSettings, PartitionTable,
+    ResetAfterOperation, ResetBeforeOperation, WrittenSegment, DEFAULT_CONNECT_ATTEMPTS,
 };
 
+pub use crate::partition_table::parse_u32;
+
+pub mod board;
+pub mod build;
+pub mod capture;
 pub mod config;
+pub mod coredump;
+pub mod deadline;
+pub mod debug;
+pub mod dry_run;
+pub mod efuse;
+pub mod flash_fs;
+pub mod idf;
+pub mod image_info;
+pub mod input;
+pub mod logging;
+pub mod manifest;
+pub mod merge_bin;
 pub mod monitor;
+pub mod multi;
+pub mod nvs;
+pub mod partition_table;
+pub mod progress;
+pub mod read_flash;
+pub mod register;
+pub mod sector_cache;
+pub mod serve;
+pub mod size;
+pub mod test_runner;
+#[cfg(target_os = "linux")]
+pub mod udev;
+pub mod watch;
+pub mod write_bin;
 
+mod defmt;
+mod hex_dump;
+mod line_editor;
 mod line_endings;
+mod log_level;
 mod serial;
+mod symbols;
 
-#[cfg(target_os = "linux")]
-pub struct GpioCdev {
-    chip: String,
-    line: u32,
+/// A pin replacing the DTR or RTS line of the serial port
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum ResetGpio {
+    /// A line of a GPIO character device, eg `/dev/gpiochip0:10`
+    Cdev { chip: String, line: u32 },
+    /// A CBUS pin of an FTDI adapter, eg `ftdi:A50285BI:2`, or `ftdi::2` for
+    /// the only connected adapter
+    FtdiCbus {
+        serial_number: Option<String>,
+        pin: u8,
+    },
 }
-#[cfg(target_os = "linux")]
-impl std::str::FromStr for GpioCdev {
+
+impl FromStr for ResetGpio {
     type Err = String;
 
     fn from_str(s: &str) -> Result<Self, Self::Err> {
         let tokens = s.split(':').collect::<Vec<_>>();
-        if tokens.len() == 2 {
-            let line = match tokens[1].parse::<u32>() {
-                Ok(line) => line,
-                Err(_) => return Err(format!("`{:}` is not a valid gpio line number", tokens[1])),
-            };
-            Ok(Self {
-                chip: tokens[0].into(),
-                line,
-            })
-        } else {
-            Err(format!(
-                "`{:}` is not valid gpio cdev, define it as like `/dev/gpiochip0:10`",
+        match tokens.as_slice() {
+            ["ftdi", serial_number, pin] => {
+                let pin = match pin.parse::<u8>() {
+                    Ok(pin) if pin <= 3 => pin,
+                    _ => return Err(format!("`{:}` is not a valid CBUS pin, 0 to 3", pin)),
+                };
+                Ok(ResetGpio::FtdiCbus {
+                    serial_number: Some(serial_number.to_string()).filter(|s| !s.is_empty()),
+                    pin,
+                })
+            }
+            [chip, line] => {
+                let line = match line.parse::<u32>() {
+                    Ok(line) => line,
+                    Err(_) => return Err(format!("`{:}` is not a valid gpio line number", line)),
+                };
+                Ok(ResetGpio::Cdev {
+                    chip: chip.to_string(),
+                    line,
+                })
+            }
+            _ => Err(format!(
+                "`{:}` is not valid gpio, define it as like `/dev/gpiochip0:10` or `ftdi:<serial number>:<CBUS pin>`",
                 s
-            ))
+            )),
         }
     }
 }
 
-#[derive(Parser)]
+impl fmt::Display for ResetGpio {
+    fn fmt(&self, f: &mut fmt::Formatter<'_>) -> fmt::Result {
+        match self {
+            ResetGpio::Cdev { chip, line } => write!(f, "{}:{}", chip, line),
+            ResetGpio::FtdiCbus { serial_number, pin } => write!(
+                f,
+                "ftdi:{}:{}",
+                serial_number.as_deref().unwrap_or_default(),
+                pin
+            ),
+        }
+    }
+}
+
+#[derive(Clone, Parser)]
 pub struct ConnectOpts {
     /// Serial port connected to target device
     pub serial: Option<String>,
-    #[cfg(target_os = "linux")]
-    /// For flashing use GPIO pin instead of serial DTR line, eg `/dev/gpiochip0:10`
-    pub gpio_dtr: Option<GpioCdev>,
-    /// For flashing use GPIO pin instead of serial RTS line, eg `/dev/gpiochip0:11`
-    #[cfg(target_os = "linux")]
-    pub gpio_rts: Option<GpioCdev>,
+    /// For flashing use GPIO pin instead of serial DTR line, eg `/dev/gpiochip0:10` (Linux only) or the CBUS pin of an FTDI adapter, eg `ftdi:A50285BI:0` (with the `ftdi` feature)
+    pub gpio_dtr: Option<ResetGpio>,
+    /// For flashing use GPIO pin instead of serial RTS line, eg `/dev/gpiochip0:11` (Linux only) or the CBUS pin of an FTDI adapter, eg `ftdi:A50285BI:1` (with the `ftdi` feature)
+    pub gpio_rts: Option<ResetGpio>,
     /// Baud rate at which to flash target device
     #[clap(long)]
     pub speed: Option<u32>,
+    /// Abort with an error if the operation takes longer than this many seconds
+    #[clap(long)]
+    pub timeout: Option<u64>,
+    /// Record all serial traffic to a capture file, see the `print-capture` and `replay-capture` subcommands
+    #[clap(long)]
+    pub capture: Option<PathBuf>,
+    /// Load the esptool.py flasher stub into RAM and use it instead of the ROM bootloader
+    #[clap(long)]
+    pub use_stub: bool,
+    /// Always ask before using an automatically detected serial port
+    #[clap(long)]
+    pub confirm_port: bool,
+    /// Never prompt when detecting the serial port, fail unless a single known device is found
+    #[clap(long, conflicts_with = "confirm-port")]
+    pub no_interactive: bool,
+    /// How to put the device into the bootloader before connecting, `default-reset` unless configured
+    #[clap(long, possible_values = &["default-reset", "usb-reset", "no-reset"])]
+    pub before: Option<ResetBeforeOperation>,
+    /// What to do with the device once flashing has completed, `hard-reset` unless configured
+    #[clap(long, possible_values = &["hard-reset", "soft-reset", "no-reset", "stay-in-bootloader"])]
+    pub after: Option<ResetAfterOperation>,
+    /// Number of times to reset the device and try to connect before giving up, 0 retries forever
+    #[clap(long, default_value_t = DEFAULT_CONNECT_ATTEMPTS)]
+    pub connect_attempts: usize,
+    /// Multiply the timeouts of the device commands by this factor, eg `2.5`
+    /// for slow flash chips or serial adapters
+    #[clap(long, parse(try_from_str = parse_timeout_scale))]
+    pub timeout_scale: Option<f64>,
+}
+
+#[derive(Parser)]
+pub struct LogOpts {
+    /// Print more detailed output, pass twice to also show timing information
+    #[clap(short, long, global = true, parse(from_occurrences))]
+    pub verbose: u8,
+    /// Only print errors and the final result
+    #[clap(short, long, global = true, conflicts_with = "verbose")]
+    pub quiet: bool,
+    /// Format of the results printed to stdout, with `json` all other messages
+    /// are printed to stderr
+    #[clap(long, global = true, default_value = "text", possible_values = &["text", "json"])]
+    pub output_format: OutputFormat,
+}
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum OutputFormat {
+    Text,
+    Json,
+}
+
+impl FromStr for OutputFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "text" => Ok(OutputFormat::Text),
+            "json" => Ok(OutputFormat::Json),
+            _ => Err(format!("unknown output format `{}`", s)),
+        }
+    }
 }
 
 #[derive(Parser)]
@@ -72,97 +220,699 @@ pub struct FlashOpts {
     /// Load the application to RAM instead of Flash
     #[clap(long)]
     pub ram: bool,
+    /// Start the application loaded to RAM at this address instead of its
+    /// entry point
+    #[clap(long, requires = "ram", parse(try_from_str = parse_u32))]
+    pub entry_point: Option<u32>,
     /// Path to a binary (.bin) bootloader file
     #[clap(long)]
     pub bootloader: Option<PathBuf>,
-    /// Path to a CSV file containing partition table
+    /// ESP-IDF release of the bundled bootloader to flash, eg `v4.4`, instead
+    /// of the newest one supporting the chip revision, which `bundled` selects
+    #[clap(long, conflicts_with = "bootloader")]
+    pub bootloader_version: Option<String>,
+    /// Path to a CSV or binary file containing partition table
     #[clap(long)]
     pub partition_table: Option<PathBuf>,
     /// Open a serial monitor after flashing
     #[clap(long)]
     pub monitor: bool,
+    /// Verify the flash contents after writing them
+    #[clap(long)]
+    pub verify: bool,
+    /// Write all sectors, including the ones unchanged since the last flash,
+    /// implies `--no-skip`
+    #[clap(long)]
+    pub force: bool,
+    /// Write the segments the flash already contains, instead of comparing
+    /// their MD5 digest with the flash first
+    #[clap(long)]
+    pub no_skip: bool,
+    /// Name of the app partition to write the application to, eg `ota_1`
+    #[clap(long)]
+    pub target_app_partition: Option<String>,
+    /// Comma delimited list of partitions to erase before flashing, eg `nvs,otadata`
+    #[clap(long, use_delimiter = true)]
+    pub erase_parts: Option<Vec<String>>,
+    /// Rewrite the otadata partition so the bootloader starts the flashed application
+    #[clap(long)]
+    pub update_otadata: bool,
+    /// Write the data to flash uncompressed, for chips or stubs that don't support compression
+    #[clap(long)]
+    pub no_compress: bool,
+    /// Sign the bootloader and the application for Secure Boot v2 with this
+    /// RSA-3072 or ECDSA private key
+    #[clap(long)]
+    pub signing_key: Option<PathBuf>,
+    /// Write the bootloader, partition table, app partitions and partitions
+    /// marked as encrypted through the flash encryption of the device
+    #[clap(long)]
+    pub encrypt: bool,
+    /// Build and check the image and print the regions which would be erased
+    /// and written, without connecting to a device
+    #[clap(long, conflicts_with_all = &["ram", "monitor", "debug"])]
+    pub dry_run: bool,
+    #[clap(flatten)]
+    pub flash_config_opts: FlashConfigOpts,
+    #[clap(flatten)]
+    pub monitor_opts: MonitorOpts,
+    #[clap(flatten)]
+    pub provision_opts: nvs::ProvisionOpts,
+    #[clap(flatten)]
+    pub debug_opts: debug::DebugOpts,
+}
+
+impl FlashOpts {
+    /// Use the settings from the config file for the options which weren't given
+    pub fn apply_config(&mut self, config: &Config) {
+        if self.bootloader.is_none() && self.bootloader_version.is_none() {
+            self.bootloader = config.flash.bootloader.clone();
+            self.bootloader_version = config.flash.bootloader_version.clone();
+        }
+        if self.partition_table.is_none() {
+            self.partition_table = config.flash.partition_table.clone();
+        }
+        self.flash_config_opts.apply_config(config);
+        self.monitor_opts.apply_config(config);
+    }
+
+    /// The bootloader file to flash to `chip`, if a bundled one isn't used
+    ///
+    /// The bootloader release is checked here already, so an unknown one is
+    /// reported before connecting.
+    pub fn bootloader(&self, chip: Chip) -> Result<Option<PathBuf>> {
+        if let Some(version) = &self.bootloader_version {
+            bootloader_release(version, chip)?;
+        }
+
+        Ok(self.bootloader.clone())
+    }
+
+    /// The settings for flashing ELF images given by the options
+    pub fn image_settings(&self) -> Result<ImageSettings> {
+        Ok(ImageSettings {
+            app_partition: self.target_app_partition.clone(),
+            update_otadata: self.update_otadata,
+            erase_parts: self.erase_parts.clone().unwrap_or_default(),
+            partition_data: Vec::new(),
+            signing_key: load_signing_key(self.signing_key.as_deref())?,
+            flash_settings: self.flash_config_opts.flash_settings(),
+            app_descriptor: self.flash_config_opts.app_descriptor(),
+            flash_layout: self.flash_config_opts.flash_layout(),
+            bootloader_release: self
+                .bootloader_version
+                .clone()
+                .filter(|version| version != BUNDLED_BOOTLOADER),
+        })
+    }
+}
+
+/// Flash settings written to the image headers, overriding the ones of the image
+#[derive(Parser)]
+pub struct FlashConfigOpts {
+    /// Flash mode to write to the image headers
+    #[clap(long, possible_values = &["qio", "qout", "dio", "dout"], ignore_case = true)]
+    pub flash_mode: Option<FlashMode>,
+    /// Flash frequency to write to the image headers
+    #[clap(long, possible_values = &["20m", "26m", "40m", "80m"], ignore_case = true)]
+    pub flash_freq: Option<FlashFrequency>,
+    /// Flash size to write to the image headers, defaults to the detected size, eg `8MB`
+    #[clap(long)]
+    pub flash_size: Option<FlashSize>,
+    /// Lowest revision of the chip the image runs on, written to the image
+    /// header and checked against the device before flashing
+    #[clap(long)]
+    pub min_chip_rev: Option<u8>,
+    /// Version to write to the application description of ESP-IDF images
+    #[clap(long)]
+    pub app_version: Option<String>,
+    /// Project name to write to the application description of ESP-IDF images
+    #[clap(long)]
+    pub project_name: Option<String>,
+    /// Offset of the second stage bootloader, instead of the default of the chip
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub bootloader_offset: Option<u32>,
+    /// Offset of the partition table, like `CONFIG_PARTITION_TABLE_OFFSET` of
+    /// ESP-IDF, requires `--bootloader` when flashing
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub partition_table_offset: Option<u32>,
+    /// Offset of the application, which has to match the app partition of the
+    /// partition table
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub app_offset: Option<u32>,
+}
+
+impl FlashConfigOpts {
+    /// Use the settings from the config file for the options which weren't given
+    pub fn apply_config(&mut self, config: &Config) {
+        self.flash_mode = self.flash_mode.or(config.flash.mode);
+        self.flash_freq = self.flash_freq.or(config.flash.frequency);
+        self.flash_size = self.flash_size.or(config.flash.size);
+        self.bootloader_offset = self.bootloader_offset.or(config.flash.bootloader_offset);
+        self.partition_table_offset = self
+            .partition_table_offset
+            .or(config.flash.partition_table_offset);
+        self.app_offset = self.app_offset.or(config.flash.app_offset);
+    }
+
+    pub fn flash_settings(&self) -> FlashSettings {
+        FlashSettings {
+            mode: self.flash_mode,
+            frequency: self.flash_freq,
+            size: self.flash_size,
+            min_chip_rev: self.min_chip_rev,
+        }
+    }
+
+    pub fn app_descriptor(&self) -> AppDescriptorSettings {
+        AppDescriptorSettings {
+            version: self.app_version.clone(),
+            project_name: self.project_name.clone(),
+        }
+    }
+
+    pub fn flash_layout(&self) -> FlashLayout {
+        FlashLayout {
+            bootloader_offset: self.bootloader_offset,
+            partition_table_offset: self.partition_table_offset,
+            app_offset: self.app_offset,
+        }
+    }
+}
+
+fn parse_timeout_scale(input: &str) -> Result<f64, String> {
+    match input.parse::<f64>() {
+        Ok(scale) if scale > 0.0 && scale.is_finite() => Ok(scale),
+        _ => Err(format!("`{}` is not a positive number", input)),
+    }
 }
 
 pub fn connect(opts: &ConnectOpts, config: &Config) -> Result<Flasher> {
-    let port_info = get_serial_port_info(opts, config)?;
+    let (serial, port_info) = open_serial(opts, config)?;
+    connect_serial(serial, port_info, opts, config)
+}
+
+/// Select and open the serial port to use
+pub fn open_serial(
+    opts: &ConnectOpts,
+    config: &Config,
+) -> Result<(Box<dyn Transport>, SerialPortType)> {
+    let name = opts.serial.as_ref().or(config.connection.serial.as_ref());
+    let port_info = match name {
+        Some(url) if remote::is_remote(url) => SerialPortInfo {
+            port_name: url.clone(),
+            port_type: SerialPortType::Unknown,
+        },
+        _ => get_serial_port_info(opts, config)?,
+    };
 
     // Attempt to open the serial port and set its initial baud rate.
-    println!("Serial port: {}", port_info.port_name);
-    println!("Connecting...\n");
-    let serial = serialport::new(&port_info.port_name, 115_200)
-        .flow_control(FlowControl::None)
-        .open()
-        .map_err(Error::from)
-        .wrap_err_with(|| format!("Failed to open serial port {}", port_info.port_name))?;
-
-    // NOTE: since `get_serial_port_info` filters out all non-USB serial ports, we
-    //       can just pretend the remaining types don't exist here.
-    let port_info = match port_info.port_type {
-        SerialPortType::UsbPort(info) => info,
-        _ => unreachable!(),
+    info!("Serial port: {}", port_info.port_name);
+    info!("Connecting...\n");
+    let serial: Box<dyn SerialPort> = if remote::is_remote(&port_info.port_name) {
+        let serial = TcpSerial::open(&port_info.port_name, 115_200)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to connect to {}", port_info.port_name))?;
+        Box::new(serial)
+    } else {
+        serialport::new(&port_info.port_name, 115_200)
+            .flow_control(FlowControl::None)
+            .open()
+            .map_err(|err| open_error(err, &port_info.port_name))
+            .wrap_err_with(|| format!("Failed to open serial port {}", port_info.port_name))?
+    };
+    let serial: Box<dyn Transport> = match &opts.capture {
+        Some(path) => Box::new(
+            CaptureTransport::new(Box::new(serial), path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to create capture file {}", path.display()))?,
+        ),
+        None => Box::new(serial),
     };
 
-    #[cfg(target_os = "linux")]
-    {
-        let (dtr, rts) = create_dtr_rts_gpios_from_args(&opts.gpio_dtr, &opts.gpio_rts)?;
-        Ok(Flasher::connect(serial, port_info, opts.speed, dtr, rts)?)
+    Ok((serial, port_info.port_type))
+}
+
+/// Connect to the device through an already opened serial port or transport
+pub fn connect_serial(
+    serial: Box<dyn Transport>,
+    port_type: SerialPortType,
+    opts: &ConnectOpts,
+    config: &Config,
+) -> Result<Flasher> {
+    let (dtr, rts) = create_dtr_rts_gpios_from_args(&opts.gpio_dtr, &opts.gpio_rts)?;
+
+    let speed = opts.speed.or(config.connection.speed);
+    let settings = ConnectSettings {
+        before: opts.before.or(config.connection.before).unwrap_or_default(),
+        after: opts.after.or(config.connection.after).unwrap_or_default(),
+        attempts: opts.connect_attempts,
+        timeout_scale: opts
+            .timeout_scale
+            .or(config.connection.timeout_scale)
+            .unwrap_or(1.0),
+    };
+
+    // Retrying can take a while, so show that something is still happening
+    let spinner = if log_enabled!(Level::Info) {
+        ProgressBar::new_spinner()
+    } else {
+        ProgressBar::hidden()
+    };
+    spinner.set_message("Waiting for the device to enter download mode...");
+    spinner.enable_steady_tick(100);
+    let flasher = Flasher::connect_transport(serial, port_type, speed, dtr, rts, settings);
+    spinner.finish_and_clear();
+    let mut flasher = flasher?;
+    config.check_board_chip(flasher.chip())?;
+
+    load_stub(&mut flasher, opts)?;
+    sector_cache::attach(&mut flasher)?;
+
+    Ok(flasher)
+}
+
+/// Load the flasher stub if `--use-stub` was given, the bundled one unless the
+/// stub directory holds one for the chip
+pub fn load_stub(flasher: &mut Flasher, opts: &ConnectOpts) -> Result<()> {
+    if !opts.use_stub {
+        return Ok(());
+    }
+
+    let path = Config::stub_dir().join(FlashStub::file_name(flasher.chip()));
+    let stub = if path.is_file() {
+        info!("Using the flasher stub {}", path.display());
+        let json = fs::read_to_string(&path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        FlashStub::from_json(&json)?
+    } else {
+        FlashStub::bundled(flasher.chip())
+    };
+    flasher.load_stub(&stub)?;
+
+    Ok(())
+}
+
+/// Version selecting the newest bundled bootloader supporting the chip revision
+pub const BUNDLED_BOOTLOADER: &str = "bundled";
+
+/// The ESP-IDF release of the bundled bootloader selected by `version` for
+/// `chip`, `None` for the newest one supporting the chip revision
+pub fn bootloader_release(version: &str, chip: Chip) -> Result<Option<String>, Error> {
+    if version == BUNDLED_BOOTLOADER {
+        return Ok(None);
     }
-    #[cfg(not(target_os = "linux"))]
-    Ok(Flasher::connect(serial, port_info, opts.speed, None, None)?)
+
+    Bootloader::find(chip, version)?;
+    Ok(Some(version.into()))
 }
 
-#[cfg(target_os = "linux")]
-// On Linux platforms it is possible to use GPIO pins for DTR and RTS.
+/// Request the GPIO pins replacing the DTR and RTS lines, if any were given
 pub fn create_dtr_rts_gpios_from_args(
-    gpio_dtr: &Option<GpioCdev>,
-    gpio_rts: &Option<GpioCdev>,
+    gpio_dtr: &Option<ResetGpio>,
+    gpio_rts: &Option<ResetGpio>,
 ) -> Result<(
     Option<crate::connection::GpioLine>,
     Option<crate::connection::GpioLine>,
 )> {
-    let dtr = if let Some(gpio_dtr) = gpio_dtr {
-        let mut chip = gpio_cdev::Chip::new(gpio_dtr.chip.clone()).map_err(Error::from)?;
-        let output = chip.get_line(gpio_dtr.line).map_err(Error::from)?;
-        let handle = output
-            .request(gpio_cdev::LineRequestFlags::OUTPUT, 0, "gpio-dtr")
-            .map_err(Error::from)?;
-        Some(crate::connection::GpioLine(handle))
-    } else {
-        None
-    };
-    let rts = if let Some(gpio_rts) = gpio_rts {
-        let mut chip = gpio_cdev::Chip::new(gpio_rts.chip.clone()).map_err(Error::from)?;
-        let output = chip.get_line(gpio_rts.line).map_err(Error::from)?;
-        let handle = output
-            .request(gpio_cdev::LineRequestFlags::OUTPUT, 0, "gpio-rts")
-            .map_err(Error::from)?;
-        Some(crate::connection::GpioLine(handle))
-    } else {
-        None
-    };
+    #[allow(clippy::let_unit_value)]
+    let mut adapters = FtdiAdapters::default();
+    let dtr = gpio_dtr
+        .as_ref()
+        .map(|gpio| request_gpio(gpio, "gpio-dtr", &mut adapters))
+        .transpose()?;
+    let rts = gpio_rts
+        .as_ref()
+        .map(|gpio| request_gpio(gpio, "gpio-rts", &mut adapters))
+        .transpose()?;
 
     Ok((dtr, rts))
 }
 
-pub fn board_info(opts: ConnectOpts, config: Config) -> Result<()> {
+/// FTDI adapters opened for the reset pins, DTR and RTS can be pins of the same
+/// adapter
+#[cfg(feature = "ftdi")]
+type FtdiAdapters = Vec<(Option<String>, crate::reset::FtdiCbus)>;
+#[cfg(not(feature = "ftdi"))]
+type FtdiAdapters = ();
+
+// On Linux platforms it is possible to use GPIO pins for DTR and RTS, on all
+// of them the CBUS pins of FTDI adapters with the `ftdi` feature.
+#[cfg_attr(
+    not(all(target_os = "linux", feature = "ftdi")),
+    allow(unused_variables)
+)]
+fn request_gpio(
+    gpio: &ResetGpio,
+    consumer: &str,
+    adapters: &mut FtdiAdapters,
+) -> Result<crate::connection::GpioLine> {
+    match gpio {
+        #[cfg(target_os = "linux")]
+        ResetGpio::Cdev { chip, line } => {
+            let mut chip = gpio_cdev::Chip::new(chip).map_err(Error::from)?;
+            let output = chip.get_line(*line).map_err(Error::from)?;
+            let handle = output
+                .request(gpio_cdev::LineRequestFlags::OUTPUT, 0, consumer)
+                .map_err(Error::from)?;
+
+            Ok(Box::new(handle))
+        }
+        #[cfg(feature = "ftdi")]
+        ResetGpio::FtdiCbus { serial_number, pin } => {
+            let serial_number = serial_number.as_deref();
+            let cbus = match adapters
+                .iter()
+                .find(|(serial, _)| serial.as_deref() == serial_number)
+            {
+                Some((_, cbus)) => cbus.clone(),
+                None => {
+                    let cbus = crate::reset::FtdiCbus::open(serial_number)?;
+                    adapters.push((serial_number.map(Into::into), cbus.clone()));
+                    cbus
+                }
+            };
+
+            Ok(Box::new(cbus.pin(*pin)?))
+        }
+        #[allow(unreachable_patterns)]
+        _ => Err(Error::GpioUnsupported(gpio.to_string()).into()),
+    }
+}
+
+#[derive(Parser)]
+pub struct SerialMonitorOpts {
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+    #[clap(flatten)]
+    pub monitor_opts: MonitorOpts,
+    /// ELF image running on the device, used to resolve the code addresses it prints
+    #[clap(long)]
+    pub elf: Option<PathBuf>,
+}
+
+/// Run the monitor after flashing `elf_data`
+///
+/// When the user asks for it from within the monitor, the image is flashed
+/// again using `load`, after which the monitor is resumed.
+pub fn flash_monitor(
+    mut flasher: Flasher,
+    connect_opts: &ConnectOpts,
+    config: &Config,
+    monitor_opts: &MonitorOpts,
+    elf_data: &[u8],
+    mut load: impl FnMut(&mut Flasher, &[u8]) -> Result<()>,
+) -> Result<()> {
+    loop {
+        let port_type = flasher.connection().port_type().clone();
+        let mut serial = flasher.into_transport();
+
+        // The GPIO lines are released before connecting again, which requests them
+        // for itself
+        {
+            let (dtr, rts) =
+                create_dtr_rts_gpios_from_args(&connect_opts.gpio_dtr, &connect_opts.gpio_rts)?;
+
+            let exit = monitor_until(
+                &mut *serial,
+                &dtr,
+                &rts,
+                monitor_opts,
+                Some(elf_data),
+                true,
+                || false,
+            )
+            .into_diagnostic()?;
+            if exit != MonitorExit::Reflash {
+                return Ok(());
+            }
+        }
+        println!();
+
+        // Connecting assumes that the port is at the default baud rate
+        serial.set_baud_rate(115_200).into_diagnostic()?;
+
+        let deadline = deadline(connect_opts.timeout);
+        flasher = connect_serial(serial, port_type, connect_opts, config)?;
+        load(&mut flasher, elf_data)?;
+        drop(deadline);
+    }
+}
+
+/// Open the serial port and run the monitor, without connecting to the bootloader
+pub fn serial_monitor(mut opts: SerialMonitorOpts, config: Config) -> Result<()> {
+    opts.monitor_opts.apply_config(&config);
+    let connect_opts = &opts.connect_opts;
+    let elf = opts.elf.as_deref().map(read_input).transpose()?;
+    let (serial, _) = open_serial(connect_opts, &config)?;
+
+    let (dtr, rts) =
+        create_dtr_rts_gpios_from_args(&connect_opts.gpio_dtr, &connect_opts.gpio_rts)?;
+
+    monitor::monitor(serial, dtr, rts, &opts.monitor_opts, elf.as_deref()).into_diagnostic()
+}
+
+#[derive(Parser)]
+pub struct BoardInfoOpts {
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+    /// Only print the MAC address of the board to stdout
+    #[clap(long)]
+    pub list_mac: bool,
+}
+
+pub fn board_info(opts: BoardInfoOpts, config: Config, output_format: OutputFormat) -> Result<()> {
+    let _deadline = deadline(opts.connect_opts.timeout);
+    let mut flasher = connect(&opts.connect_opts, &config)?;
+
+    if opts.list_mac {
+        println!("{}", flasher.mac_address()?);
+    } else if output_format == OutputFormat::Json {
+        print_json(&flasher.device_info()?)?;
+    } else {
+        flasher.board_info()?;
+    }
+
+    Ok(())
+}
+
+#[derive(Serialize)]
+struct FlashReport<'a> {
+    device: DeviceInfo,
+    segments: &'a [WrittenSegment],
+    duration_ms: u128,
+}
+
+/// Log the segments written by the flashing operation which just finished,
+/// and print its details as JSON with the JSON output format
+///
+/// The device may have been reset already, the details are the ones read
+/// by [`Flasher::board_info`] before flashing.
+pub fn print_flash_report(
+    flasher: &mut Flasher,
+    duration: Duration,
+    output_format: OutputFormat,
+) -> Result<()> {
+    log_segments(flasher.written_segments());
+
+    if output_format == OutputFormat::Json {
+        let device = flasher.device_info()?;
+        print_json(&FlashReport {
+            device,
+            segments: flasher.written_segments(),
+            duration_ms: duration.as_millis(),
+        })?;
+    }
+
+    Ok(())
+}
+
+fn log_segments(segments: &[WrittenSegment]) {
+    info!("{:<10} {:>10}  {:<32}  SHA-256", "Address", "Size", "MD5");
+    for segment in segments {
+        info!(
+            "{:#010x} {:>10}  {}  {}",
+            segment.addr, segment.size, segment.md5, segment.sha256
+        );
+    }
+}
+
+#[derive(Serialize)]
+struct ErrorOutput {
+    error: ErrorReport,
+}
+
+#[derive(Serialize)]
+struct ErrorReport {
+    category: ErrorCategory,
+    exit_code: i32,
+    code: Option<String>,
+    message: String,
+    causes: Vec<String>,
+    help: Option<String>,
+}
+
+/// Print `report` and exit with the code of its category
+///
+/// The error is always printed to stderr, with the JSON output format it's
+/// additionally printed to stdout as an object with the `error` key.
+pub fn exit_with_error(report: Report, category: ErrorCategory, output_format: OutputFormat) -> ! {
+    if output_format == OutputFormat::Json {
+        let error = ErrorReport {
+            category,
+            exit_code: category.exit_code(),
+            code: report.code().map(|code| code.to_string()),
+            message: report.to_string(),
+            causes: report
+                .chain()
+                .skip(1)
+                .map(|cause| cause.to_string())
+                .collect(),
+            help: report.help().map(|help| help.to_string()),
+        };
+        print_json(&ErrorOutput { error }).ok();
+    }
+    eprintln!("Error: {:?}", report);
+
+    exit(category.exit_code())
+}
+
+fn print_json(value: &impl Serialize) -> Result<()> {
+    let json = serde_json::to_string_pretty(value).into_diagnostic()?;
+    println!("{}", json);
+    Ok(())
+}
+
+pub fn erase_flash(opts: ConnectOpts, config: Config) -> Result<()> {
+    let _deadline = deadline(opts.timeout);
     let mut flasher = connect(&opts, &config)?;
     flasher.board_info()?;
 
+    info!("Erasing flash, this can take a while...");
+    flasher.erase_flash()?;
+    info!("Flash has been erased");
+
+    Ok(())
+}
+
+#[derive(Parser)]
+pub struct EraseRegionOpts {
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+    /// Offset of the region to erase, aligned to 4096 bytes, eg `0x110000`
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub offset: u32,
+    /// Size of the region to erase, a multiple of 4096 bytes, eg `64k`
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub size: u32,
+}
+
+pub fn erase_region(opts: EraseRegionOpts, config: Config) -> Result<()> {
+    let _deadline = deadline(opts.connect_opts.timeout);
+    let mut flasher = connect(&opts.connect_opts, &config)?;
+    flasher.board_info()?;
+
+    info!(
+        "Erasing {:#x} bytes at {:#x}, this can take a while...",
+        opts.size, opts.offset
+    );
+    flasher.erase_region(opts.offset, opts.size)?;
+    info!("Region has been erased");
+
     Ok(())
 }
 
+/// Load the partition table at `path`, or read the one of the device if no
+/// path is given
+pub fn device_partition_table(
+    flasher: &mut Flasher,
+    path: Option<&Path>,
+) -> Result<PartitionTable> {
+    // The maximum size of the partition table
+    const SIZE: u32 = 0xC00;
+
+    if let Some(path) = path {
+        return PartitionTable::try_from_bytes_at(
+            &read_input(path)?,
+            flasher.partition_table_offset(),
+        )
+        .wrap_err_with(|| format!("Failed to parse {}", path.display()));
+    }
+
+    let mut data = Vec::new();
+    flasher
+        .read_flash(flasher.partition_table_offset(), SIZE, &mut data)
+        .wrap_err("Failed to read the partition table, pass it with `--partition-table`")?;
+
+    PartitionTable::try_from_bytes_at(&data, flasher.partition_table_offset())
+        .wrap_err("Failed to parse the partition table of the device")
+}
+
+/// Load the private key at `path` to sign images with
+pub fn load_signing_key(path: Option<&Path>) -> Result<Option<SigningKey>> {
+    path.map(|path| {
+        let pem = fs::read_to_string(path)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to read {}", path.display()))?;
+        SigningKey::from_pem(&pem)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to load {}", path.display()))
+    })
+    .transpose()
+}
+
 pub fn save_elf_as_image(
     chip: Chip,
     elf_data: &[u8],
     path: PathBuf,
     image_format: Option<ImageFormatId>,
+    flash_config: &FlashConfigOpts,
+    file_format: Option<FileFormat>,
+    signing_key: Option<&SigningKey>,
 ) -> Result<()> {
-    let image = FirmwareImage::from_data(elf_data)?;
+    let mut image = FirmwareImage::from_data(elf_data)?;
+    image.apply_flash_settings(flash_config.flash_settings());
+    // The ESP8266 SDK keeps its settings at the end of the flash given in the
+    // header, which has to match the module
+    if chip == Chip::Esp8266
+        && image.metadata.flash_size.is_none()
+        && flash_config.flash_size.is_none()
+    {
+        warn!(
+            "No flash size given, building the image for {} of flash, pass `--flash-size` for other modules",
+            image.flash_size
+        );
+    }
+    image.app_descriptor = flash_config.app_descriptor();
+    image.flash_layout = flash_config.flash_layout();
 
-    let flash_image = chip.get_flash_image(&image, None, None, image_format, None)?;
+    let mut flash_image = chip.get_flash_image(&image, None, None, image_format, None)?;
+    if let Some(key) = signing_key {
+        flash_image.sign(key)?;
+    }
     let parts: Vec<_> = flash_image.ota_segments().collect();
+    for part in &parts {
+        if secure_boot::is_signed(&part.data) {
+            for signature in secure_boot::verify(&part.data)? {
+                let digest: String = signature
+                    .key_digest
+                    .iter()
+                    .map(|byte| format!("{:02x}", byte))
+                    .collect();
+                info!(
+                    "Signed with the {} key with the digest {}",
+                    signature.scheme, digest
+                );
+            }
+        }
+    }
+    let file_format = file_format.unwrap_or_else(|| FileFormat::from_path(&path));
 
     match parts.as_slice() {
+        _ if file_format != FileFormat::Binary => {
+            fs::write(path, file_format.encode(chip, &parts)).into_diagnostic()?
+        }
         [single] => fs::write(path, &single.data).into_diagnostic()?,
         parts => {
             for part in parts {
@@ -182,11 +932,68 @@ pub fn flash_elf_image(
     partition_table: Option<&Path>,
     image_format: Option<ImageFormatId>,
 ) -> Result<()> {
+    // Intel HEX files already contain the complete image, including the bootloader
+    // and partition table if needed
+    if ihex::is_hex(elf_data) {
+        if bootloader.is_some() || partition_table.is_some() || image_format.is_some() {
+            warn!(
+                "The bootloader, partition table and image format are ignored for Intel HEX files"
+            );
+        }
+        let segments = ihex::decode(elf_data)?;
+        flasher.write_bins_to_flash(
+            segments.iter().map(|(addr, data)| (*addr, data.as_slice())),
+            Some(&mut EspflashProgress::default()),
+        )?;
+        info!("\nFlashing has completed!");
+
+        return Ok(());
+    }
+
+    let (bootloader, partition_table) = load_flash_files(
+        bootloader,
+        partition_table,
+        flasher.flash_layout().partition_table_offset,
+    )?;
+
+    // Load the ELF data, optionally using the provider bootloader/partition
+    // table/image format, to the device's flash memory.
+    flasher.load_elf_to_flash_with_format(
+        elf_data,
+        bootloader,
+        partition_table,
+        image_format,
+        Some(&mut EspflashProgress::default()),
+    )?;
+    info!("\nFlashing has completed!");
+
+    Ok(())
+}
+
+/// The chip given on the command line, or the one named in the metadata of
+/// the ELF image in `elf_data`
+pub fn image_chip(chip: Option<Chip>, elf_data: &[u8]) -> Result<Chip> {
+    match chip {
+        Some(chip) => Ok(chip),
+        None => FirmwareImage::from_data(elf_data)
+            .ok()
+            .and_then(|image| image.metadata.chip)
+            .ok_or_else(|| miette!("The image doesn't name its chip, pass it with `--chip`")),
+    }
+}
+
+/// Load the bootloader and the partition table given on the command line, the
+/// table is flashed to `table_offset` or the default offset
+pub(crate) fn load_flash_files(
+    bootloader: Option<&Path>,
+    partition_table: Option<&Path>,
+    table_offset: Option<u32>,
+) -> Result<(Option<Vec<u8>>, Option<PartitionTable>)> {
     // If the '--bootloader' option is provided, load the binary file at the
     // specified path.
     let bootloader = if let Some(path) = bootloader {
         let path = fs::canonicalize(path).into_diagnostic()?;
-        let data = fs::read(path).into_diagnostic()?;
+        let data = read_input(&path)?;
 
         Some(data)
     } else {
@@ -194,25 +1001,95 @@ pub fn flash_elf_image(
     };
 
     // If the '--partition-table' option is provided, load the partition table from
-    // the CSV at the specified path.
+    // the CSV or binary file at the specified path.
     let partition_table = if let Some(path) = partition_table {
         let path = fs::canonicalize(path).into_diagnostic()?;
-        let data = fs::read_to_string(path)
-            .into_diagnostic()
-            .wrap_err("Failed to open partition table")?;
+        let data = read_input(&path)?;
 
-        let table =
-            PartitionTable::try_from_str(data).wrap_err("Failed to parse partition table")?;
+        let table = PartitionTable::try_from_bytes_at(
+            &data,
+            table_offset.unwrap_or(PARTITION_TABLE_OFFSET),
+        )
+        .wrap_err("Failed to parse partition table")?;
 
         Some(table)
     } else {
         None
     };
 
-    // Load the ELF data, optionally using the provider bootloader/partition
-    // table/image format, to the device's flash memory.
-    flasher.load_elf_to_flash_with_format(elf_data, bootloader, partition_table, image_format)?;
-    println!("\nFlashing has completed!");
+    Ok((bootloader, partition_table))
+}
 
-    Ok(())
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn numbers_are_parsed() {
+        assert_eq!(parse_u32("4096"), Ok(4096));
+        assert_eq!(parse_u32("0x10000"), Ok(0x10000));
+        assert_eq!(parse_u32("64k"), Ok(0x10000));
+        assert_eq!(parse_u32("4M"), Ok(0x400000));
+        assert!(parse_u32("0x").is_err());
+        assert!(parse_u32("8192M").is_err());
+
+        assert_eq!(parse_timeout_scale("2.5"), Ok(2.5));
+        assert!(parse_timeout_scale("0").is_err());
+        assert!(parse_timeout_scale("inf").is_err());
+    }
+
+    #[test]
+    fn reset_gpios_are_parsed() {
+        for (text, gpio) in &[
+            (
+                "/dev/gpiochip0:10",
+                ResetGpio::Cdev {
+                    chip: "/dev/gpiochip0".into(),
+                    line: 10,
+                },
+            ),
+            (
+                "ftdi:A50285BI:2",
+                ResetGpio::FtdiCbus {
+                    serial_number: Some("A50285BI".into()),
+                    pin: 2,
+                },
+            ),
+            (
+                "ftdi::0",
+                ResetGpio::FtdiCbus {
+                    serial_number: None,
+                    pin: 0,
+                },
+            ),
+        ] {
+            assert_eq!(text.parse::<ResetGpio>().as_ref(), Ok(gpio));
+            assert_eq!(gpio.to_string(), *text);
+        }
+
+        for text in &[
+            "/dev/gpiochip0",
+            "/dev/gpiochip0:x",
+            "ftdi:A50285BI:4",
+            "ftdi::",
+        ] {
+            assert!(text.parse::<ResetGpio>().is_err());
+        }
+    }
+
+    #[test]
+    fn bootloader_versions_select_a_release() {
+        assert_eq!(
+            bootloader_release("v4.4", Chip::Esp32c3).unwrap(),
+            Some("v4.4".into())
+        );
+        assert_eq!(
+            bootloader_release(BUNDLED_BOOTLOADER, Chip::Esp32h2).unwrap(),
+            None
+        );
+        assert!(matches!(
+            bootloader_release("v5.0", Chip::Esp32c3),
+            Err(Error::BootloaderNotFound(..))
+        ));
+    }
 }
@@ -1,12 +1,147 @@
+use super::config::Config;
+use super::defmt::DefmtDecoder;
+use super::hex_dump::HexDump;
+use super::line_editor::{LineEdit, LineEditor, LineEnding};
 use super::line_endings::normalized;
+use super::log_level::{strip_colors, LogLevel};
+use super::symbols::Symbols;
 use crate::connection::GpioLine;
+use crate::error::Error;
+use crate::reset::{ClassicReset, HardReset, ResetLines, ResetStrategy};
+use crate::transport::Transport;
+use clap::Parser;
 use crossterm::event::{poll, read, Event, KeyCode, KeyEvent, KeyModifiers};
+use crossterm::style::Stylize;
 use crossterm::terminal::{disable_raw_mode, enable_raw_mode};
 use miette::{IntoDiagnostic, Result};
-use serialport::SerialPort;
-use std::io::{stdout, ErrorKind, Read, Write};
-use std::thread::sleep;
-use std::time::Duration;
+use regex::Regex;
+use std::borrow::Cow;
+use std::fs::{File, OpenOptions};
+use std::io::{stdout, ErrorKind, Write};
+use std::path::{Path, PathBuf};
+use std::str::FromStr;
+use std::time::{Duration, Instant, SystemTime, UNIX_EPOCH};
+
+#[derive(Parser, Default)]
+pub struct MonitorOpts {
+    /// Append everything received by the monitor to this file, with a timestamp
+    /// in front of every line
+    #[clap(long)]
+    pub log_file: Option<PathBuf>,
+    /// Show the time at which each line was received, can be toggled with CTRL+T
+    #[clap(long)]
+    pub timestamps: bool,
+    /// Exit with an error when the device panics or is reset by a watchdog or a
+    /// brownout
+    #[clap(long)]
+    pub fail_on_panic: bool,
+    /// Baud rate of the serial monitor, defaults to 115200
+    #[clap(long)]
+    pub monitor_speed: Option<u32>,
+    /// Decode the defmt log frames sent by the device, using the format strings
+    /// from the ELF image
+    #[clap(long)]
+    pub defmt: bool,
+    /// Only show the output, without using the raw terminal mode or handling
+    /// any keys, for CI logs and piping the output to other programs
+    #[clap(long)]
+    pub non_interactive: bool,
+    /// Only show the lines matching this regular expression, eg `wifi|sensor`,
+    /// filtering can be toggled with CTRL+G
+    #[clap(long)]
+    pub filter: Option<Regex>,
+    /// Hide the lines matching `--filter` instead of showing only them
+    #[clap(long, requires = "filter")]
+    pub filter_invert: bool,
+    /// Hide the log lines less severe than this level, lines which aren't
+    /// logged through ESP-IDF or esp-println are always shown
+    #[clap(long, possible_values = &["error", "warn", "info", "debug", "verbose"])]
+    pub log_level: Option<LogLevel>,
+    /// Don't color the log lines by their level, and remove the colors set by
+    /// the device
+    #[clap(long)]
+    pub no_color: bool,
+    /// Edit the input locally and send it a line at a time on Enter, with the
+    /// previous lines available through the up and down keys
+    #[clap(long, conflicts_with = "non-interactive")]
+    pub line_mode: bool,
+    /// Line ending sent after every line in `--line-mode`, and after the text
+    /// of `send:` actions
+    #[clap(long, default_value = "cr", possible_values = &["cr", "lf", "crlf"])]
+    pub line_ending: LineEnding,
+    /// Show the received bytes as a hex dump instead of as text, can be
+    /// toggled with CTRL+X
+    ///
+    /// The lines are still matched by `--expect` and checked for crashes, only
+    /// their text isn't shown.
+    #[clap(long)]
+    pub hex: bool,
+    /// Regular expression to wait for in the output, the action given by the
+    /// `--then` in the same position runs on every line matching it
+    #[clap(long)]
+    pub expect: Vec<Regex>,
+    /// What to do when the output matches the `--expect` pattern: `exit`,
+    /// `reset` the device or `send:TEXT`, which sends the text followed by the
+    /// `--line-ending`. Defaults to `exit`
+    #[clap(long, requires = "expect")]
+    pub then: Vec<TriggerAction>,
+}
+
+impl MonitorOpts {
+    /// Use the settings from the config file for the options which weren't given
+    pub fn apply_config(&mut self, config: &Config) {
+        if self.monitor_speed.is_none() {
+            self.monitor_speed = config.monitor.speed;
+        }
+    }
+}
+
+pub(crate) const DEFAULT_MONITOR_SPEED: u32 = 115_200;
+
+/// Most output kept while the output is paused, older output is dropped
+const MAX_PAUSED_OUTPUT: usize = 0x100000;
+
+/// Messages printed by ESP-IDF and the Rust panic handlers when crashing
+const CRASH_MESSAGES: &[&str] = &[
+    "Guru Meditation Error",
+    "abort() was called",
+    "panicked at",
+    "Stack smashing protect failure",
+    "CORRUPT HEAP",
+    "assert failed:",
+];
+
+/// How long the output has to be quiet after a crash before the monitor exits,
+/// so the backtrace following the crash message is still shown
+const CRASH_EXIT_DELAY: Duration = Duration::from_secs(1);
+
+/// How long the output has to be quiet before an incomplete line is shown, eg
+/// a prompt waiting for input
+const PARTIAL_LINE_DELAY: Duration = Duration::from_millis(30);
+
+/// Longest incomplete line which is buffered, longer ones are shown right away
+const MAX_LINE_LENGTH: usize = 1024;
+
+/// Check whether `line` reports a crash, returning a description of the crash
+///
+/// Besides the messages printed by the panic handlers, the reset reason printed
+/// by the ROM bootloader is checked for resets by a watchdog or a brownout.
+fn detect_crash(line: &str) -> Option<String> {
+    let line = line.trim();
+    if CRASH_MESSAGES.iter().any(|message| line.contains(message)) {
+        return Some(line.to_string());
+    }
+
+    // eg `rst:0x7 (TG0WDT_SYS_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)`
+    let reason = line.strip_prefix("rst:")?;
+    let (_, name) = reason.split_once('(')?;
+    let (name, _) = name.split_once(')')?;
+    if name.contains("WDT") || name.contains("BROWN") {
+        Some(format!("Reset by {}", name))
+    } else {
+        None
+    }
+}
 
 /// Converts key events from crossterm into appropriate character/escape sequences which are then
 /// sent over the serial connection.
@@ -78,68 +213,526 @@ impl Drop for RawModeGuard {
     }
 }
 
+/// Why [`monitor_until`] returned
+#[derive(Debug, Copy, Clone, PartialEq, Eq)]
+pub enum MonitorExit {
+    /// The user exited the monitor
+    User,
+    /// The stop condition was met
+    Stopped,
+    /// The user asked for the image to be flashed again
+    Reflash,
+    /// The output matched an `--expect` pattern with the `exit` action
+    Expected,
+}
+
 pub fn monitor(
-    mut serial: Box<dyn SerialPort>,
+    mut serial: Box<dyn Transport>,
     gpio_dtr: Option<GpioLine>,
     gpio_rts: Option<GpioLine>,
+    opts: &MonitorOpts,
+    elf: Option<&[u8]>,
+) -> Result<(), crate::error::Error> {
+    monitor_until(&mut *serial, &gpio_dtr, &gpio_rts, opts, elf, false, || {
+        false
+    })?;
+    Ok(())
+}
+
+/// Format `time` as a UTC date and time with milliseconds
+fn format_timestamp(time: SystemTime) -> String {
+    let since_epoch = time.duration_since(UNIX_EPOCH).unwrap_or_default();
+    let secs = since_epoch.as_secs();
+    let (days, secs_of_day) = ((secs / 86400) as i64, secs % 86400);
+
+    // Convert the days since the epoch to a civil date, see
+    // http://howardhinnant.github.io/date_algorithms.html#civil_from_days
+    let z = days + 719_468;
+    let era = z.div_euclid(146_097);
+    let doe = z.rem_euclid(146_097);
+    let yoe = (doe - doe / 1460 + doe / 36524 - doe / 146_096) / 365;
+    let doy = doe - (365 * yoe + yoe / 4 - yoe / 100);
+    let mp = (5 * doy + 2) / 153;
+    let day = doy - (153 * mp + 2) / 5 + 1;
+    let month = if mp < 10 { mp + 3 } else { mp - 9 };
+    let year = yoe + era * 400 + if month <= 2 { 1 } else { 0 };
+
+    format!(
+        "{:04}-{:02}-{:02} {:02}:{:02}:{:02}.{:03}",
+        year,
+        month,
+        day,
+        secs_of_day / 3600,
+        secs_of_day / 60 % 60,
+        secs_of_day % 60,
+        since_epoch.subsec_millis()
+    )
+}
+
+/// Puts a timestamp in front of every line of the data passing through
+#[derive(Default)]
+struct Timestamper {
+    in_line: bool,
+}
+
+impl Timestamper {
+    fn stamp(&mut self, data: &str, timestamp: &str) -> String {
+        let mut stamped = String::with_capacity(data.len());
+        for c in data.chars() {
+            if !self.in_line {
+                stamped.push('[');
+                stamped.push_str(timestamp);
+                stamped.push_str("] ");
+                self.in_line = true;
+            }
+            stamped.push(c);
+            if c == '\n' {
+                self.in_line = false;
+            }
+        }
+        stamped
+    }
+}
+
+/// Splits the received text into lines, which are processed one at a time
+#[derive(Default)]
+struct LineBuffer {
+    pending: String,
+}
+
+impl LineBuffer {
+    fn push(&mut self, text: &str) {
+        self.pending.push_str(text);
+    }
+
+    /// The next complete line, including its line break
+    fn next_line(&mut self) -> Option<String> {
+        let end = self.pending.find('\n')?;
+        Some(self.pending.drain(..=end).collect())
+    }
+
+    /// The incomplete line received so far, if it's `quiet` or too long to
+    /// keep buffering
+    fn take_partial(&mut self, quiet: bool) -> Option<String> {
+        if self.pending.is_empty() || !(quiet || self.pending.len() > MAX_LINE_LENGTH) {
+            return None;
+        }
+        Some(std::mem::take(&mut self.pending))
+    }
+}
+
+/// Selects the lines shown by the monitor
+struct LineFilter {
+    regex: Regex,
+    invert: bool,
+    enabled: bool,
+}
+
+impl LineFilter {
+    fn new(regex: Regex, invert: bool) -> Self {
+        LineFilter {
+            regex,
+            invert,
+            enabled: true,
+        }
+    }
+
+    fn shows(&self, line: &str) -> bool {
+        !self.enabled || self.regex.is_match(line) != self.invert
+    }
+}
+
+/// What the monitor does when the output matches an `--expect` pattern
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub enum TriggerAction {
+    Exit,
+    Reset,
+    Send(String),
+}
+
+impl FromStr for TriggerAction {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "exit" => Ok(TriggerAction::Exit),
+            "reset" => Ok(TriggerAction::Reset),
+            _ => match s.strip_prefix("send:") {
+                Some(text) => Ok(TriggerAction::Send(text.into())),
+                None => Err(format!(
+                    "unknown action `{}`, expected `exit`, `reset` or `send:TEXT`",
+                    s
+                )),
+            },
+        }
+    }
+}
+
+/// Pair the `--expect` patterns with their actions
+fn triggers(opts: &MonitorOpts) -> Result<Vec<(Regex, TriggerAction)>, Error> {
+    if opts.then.len() > opts.expect.len() {
+        return Err(Error::UnmatchedTriggerAction(
+            opts.then.len(),
+            opts.expect.len(),
+        ));
+    }
+
+    let actions = opts
+        .then
+        .iter()
+        .cloned()
+        .chain(std::iter::repeat(TriggerAction::Exit));
+    Ok(opts.expect.iter().cloned().zip(actions).collect())
+}
+
+/// Log file the received data is appended to
+struct MonitorLog {
+    path: PathBuf,
+    file: File,
+    timestamper: Timestamper,
+}
+
+impl MonitorLog {
+    fn open(path: &Path) -> Result<Self, Error> {
+        let file = OpenOptions::new()
+            .create(true)
+            .append(true)
+            .open(path)
+            .map_err(|err| Error::LogFile(path.into(), err))?;
+
+        Ok(MonitorLog {
+            path: path.into(),
+            file,
+            timestamper: Timestamper::default(),
+        })
+    }
+
+    fn write(&mut self, data: &str) -> Result<(), Error> {
+        let timestamp = format_timestamp(SystemTime::now());
+        let stamped = self.timestamper.stamp(data, &timestamp);
+        self.file
+            .write_all(stamped.as_bytes())
+            .map_err(|err| Error::LogFile(self.path.clone(), err))
+    }
+}
+
+fn reset(
+    serial: &mut dyn Transport,
+    gpio_dtr: &Option<GpioLine>,
+    gpio_rts: &Option<GpioLine>,
+) -> Result<(), crate::error::Error> {
+    HardReset {
+        usb_serial_jtag: false,
+    }
+    .reset(&mut ResetLines::new(serial, gpio_dtr, gpio_rts))
+}
+
+/// Reset the device into the serial bootloader, by holding GPIO0 low while
+/// releasing the reset
+fn reset_to_bootloader(
+    serial: &mut dyn Transport,
+    gpio_dtr: &Option<GpioLine>,
+    gpio_rts: &Option<GpioLine>,
 ) -> Result<(), crate::error::Error> {
-    println!("Commands:");
-    println!("    CTRL+R    Reset chip");
-    println!("    CTRL+C    Exit");
-    println!();
+    ClassicReset { extra_delay: false }.reset(&mut ResetLines::new(serial, gpio_dtr, gpio_rts))
+}
+
+/// Run the monitor until either the user exits it or `stop` returns true
+///
+/// The serial port is borrowed so it can be reused afterwards. If the ELF image
+/// running on the device is given, the code addresses printed by the device are
+/// resolved to the functions and source lines they belong to, it's also
+/// required for decoding defmt frames. With `reflash` the user can ask for
+/// the image to be flashed again, which ends the monitor with
+/// [`MonitorExit::Reflash`].
+pub fn monitor_until(
+    serial: &mut dyn Transport,
+    gpio_dtr: &Option<GpioLine>,
+    gpio_rts: &Option<GpioLine>,
+    opts: &MonitorOpts,
+    elf: Option<&[u8]>,
+    reflash: bool,
+    mut stop: impl FnMut() -> bool,
+) -> Result<MonitorExit, crate::error::Error> {
+    let mut log = opts.log_file.as_deref().map(MonitorLog::open).transpose()?;
+    let mut timestamps = opts.timestamps;
+    let mut timestamper = Timestamper::default();
+    let symbols = elf.and_then(Symbols::try_from);
+    let mut defmt = if opts.defmt {
+        Some(DefmtDecoder::new(elf.ok_or(Error::NoDefmtData)?)?)
+    } else {
+        None
+    };
+    let triggers = triggers(opts)?;
+    let mut lines = LineBuffer::default();
+    let mut hex = opts.hex;
+    let mut hex_dump = HexDump::default();
+    let mut filter = opts
+        .filter
+        .clone()
+        .map(|regex| LineFilter::new(regex, opts.filter_invert));
+    let mut crash: Option<String> = None;
+    let mut last_read = Instant::now();
+    let mut paused = false;
+    let mut paused_output = String::new();
+    let newline = if opts.non_interactive { "\n" } else { "\r\n" };
+    let mut editor = if opts.line_mode {
+        Some(LineEditor::default())
+    } else {
+        None
+    };
+
+    if !opts.non_interactive {
+        println!("Commands:");
+        println!("    CTRL+R    Reset chip");
+        println!("    CTRL+B    Reset chip into the bootloader");
+        if reflash {
+            println!("    CTRL+F    Flash the image again");
+        }
+        println!("    CTRL+T    Toggle timestamps");
+        println!("    CTRL+X    Toggle the hex dump");
+        if filter.is_some() {
+            println!("    CTRL+G    Toggle the line filter");
+        }
+        println!("    CTRL+P    Pause and resume the output");
+        println!("    CTRL+C    Exit");
+        println!();
+    }
 
     let mut buff = [0; 128];
-    serial.set_baud_rate(115_200)?;
+    serial.set_baud_rate(opts.monitor_speed.unwrap_or(DEFAULT_MONITOR_SPEED))?;
     serial.set_timeout(Duration::from_millis(5))?;
 
-    let _raw_mode = RawModeGuard::new();
+    let _raw_mode = if opts.non_interactive {
+        None
+    } else {
+        Some(RawModeGuard::new())
+    };
     let stdout = stdout();
     let mut stdout = stdout.lock();
     loop {
+        if stop() {
+            return Ok(MonitorExit::Stopped);
+        }
+        if let Some(crash) = &crash {
+            if last_read.elapsed() > CRASH_EXIT_DELAY {
+                return Err(Error::DeviceCrashed(crash.clone()));
+            }
+        }
+
         let read_count = match serial.read(&mut buff) {
             Ok(count) => Ok(count),
             Err(e) if e.kind() == ErrorKind::TimedOut => Ok(0),
             err => err,
         }?;
         if read_count > 0 {
-            let data: Vec<u8> = normalized(buff[0..read_count].iter().copied()).collect();
-            let data = String::from_utf8_lossy(&data);
-            stdout.write_all(data.as_bytes()).ok();
-            stdout.flush()?;
+            last_read = Instant::now();
+            let text = match &mut defmt {
+                Some(decoder) => Cow::Owned(decoder.feed(&buff[0..read_count])),
+                None => String::from_utf8_lossy(&buff[0..read_count]),
+            };
+            if let Some(log) = &mut log {
+                log.write(&text)?;
+            }
+            if hex {
+                hex_dump.push(&buff[0..read_count]);
+            }
+            // The lines are split in hex mode as well, the patterns and the crash
+            // detection depend on them
+            lines.push(&text);
+        }
+
+        // Lines are processed once they are complete, as they may be split over
+        // multiple reads
+        let mut received = Vec::new();
+        while let Some(line) = lines.next_line() {
+            received.push(line);
+        }
+        received.extend(lines.take_partial(last_read.elapsed() > PARTIAL_LINE_DELAY));
+
+        let mut output = hex_dump.rows(last_read.elapsed() > PARTIAL_LINE_DELAY, newline);
+        let mut actions = Vec::new();
+        for line in received {
+            let text = strip_colors(&line);
+            actions.extend(
+                triggers
+                    .iter()
+                    .filter(|(pattern, _)| pattern.is_match(&text))
+                    .map(|(_, action)| action.clone()),
+            );
+
+            // Crashes are shown even if the filter hides the line
+            let crashed = detect_crash(&line);
+            let level = LogLevel::of(&line);
+            let shown = !hex
+                && (crashed.is_some()
+                    || (filter.as_ref().map_or(true, |f| f.shows(&line))
+                        && level.zip(opts.log_level).map_or(true, |(l, max)| l <= max)));
+            if shown {
+                let data = match level {
+                    _ if opts.no_color => strip_colors(&line),
+                    Some(level) if !opts.non_interactive => level.colorize(&line),
+                    _ => line.clone(),
+                };
+                // Raw mode needs carriage returns to start new lines at the left edge
+                let data = if opts.non_interactive {
+                    data
+                } else {
+                    let data: Vec<u8> = normalized(data.bytes()).collect();
+                    String::from_utf8_lossy(&data).into_owned()
+                };
+                if timestamps {
+                    let timestamp = format_timestamp(SystemTime::now());
+                    // The date is left out, it's rarely needed when watching the output
+                    output.push_str(&timestamper.stamp(&data, &timestamp[11..]));
+                } else {
+                    output.push_str(&data);
+                }
+                if let Some(symbols) = &symbols {
+                    for (addr, description) in symbols.resolve_line(&line) {
+                        output.push_str(&format!("{:#010x} - {}{}", addr, description, newline));
+                    }
+                }
+            }
+            if let Some(description) = crashed {
+                let notice = format!("Crash detected: {}", description);
+                if opts.non_interactive || opts.no_color {
+                    output.push_str(&notice);
+                } else {
+                    output.push_str(&notice.red().bold().to_string());
+                }
+                output.push_str(newline);
+                if opts.fail_on_panic && crash.is_none() {
+                    crash = Some(description);
+                }
+            }
+        }
+
+        if !output.is_empty() {
+            if paused {
+                paused_output.push_str(&output);
+                if paused_output.len() > MAX_PAUSED_OUTPUT {
+                    let excess = paused_output.len() - MAX_PAUSED_OUTPUT;
+                    let start = (excess..paused_output.len())
+                        .find(|&i| paused_output.is_char_boundary(i))
+                        .unwrap_or(excess);
+                    paused_output.drain(..start);
+                }
+            } else {
+                if let Some(editor) = &mut editor {
+                    output = editor.wrap_output(&output);
+                }
+                stdout.write_all(output.as_bytes()).ok();
+                stdout.flush()?;
+            }
+        }
+        for action in actions {
+            match action {
+                TriggerAction::Exit => {
+                    stdout.write_all(paused_output.as_bytes()).ok();
+                    stdout.flush()?;
+                    return Ok(MonitorExit::Expected);
+                }
+                TriggerAction::Reset => reset(serial, gpio_dtr, gpio_rts)?,
+                TriggerAction::Send(text) => {
+                    serial.write_all(text.as_bytes())?;
+                    serial.write_all(opts.line_ending.as_bytes())?;
+                    serial.flush()?;
+                }
+            }
+        }
+        if opts.non_interactive {
+            continue;
         }
         if poll(Duration::from_secs(0))? {
             if let Event::Key(key) = read()? {
                 if key.modifiers.contains(KeyModifiers::CONTROL) {
                     match key.code {
-                        KeyCode::Char('c') => break,
+                        KeyCode::Char('c') => return Ok(MonitorExit::User),
                         KeyCode::Char('r') => {
-                            // set DTR to 0
-                            if let Some(dtr) = &gpio_dtr {
-                                dtr.0.set_value(0)?;
-                            } else {
-                                serial.write_data_terminal_ready(false)?;
+                            // Ports without control lines, like Bluetooth serial ports,
+                            // can't reset the device, which shouldn't end the monitor.
+                            if reset(serial, gpio_dtr, gpio_rts).is_err() {
+                                stdout
+                                    .write_all(
+                                        b"\r\nThe device can not be reset through this port\r\n",
+                                    )
+                                    .ok();
+                                stdout.flush()?;
                             }
-
-                            // set RTS to 1
-                            if let Some(rts) = &gpio_rts {
-                                rts.0.set_value(1)?;
-                            } else {
-                                serial.write_request_to_send(true)?;
+                            continue;
+                        }
+                        KeyCode::Char('b') => {
+                            if reset_to_bootloader(serial, gpio_dtr, gpio_rts).is_err() {
+                                stdout
+                                    .write_all(
+                                        b"\r\nThe device can not be reset through this port\r\n",
+                                    )
+                                    .ok();
+                                stdout.flush()?;
                             }
-
-                            sleep(Duration::from_millis(100));
-
-                            // set RTS to 0
-                            if let Some(rts) = &gpio_rts {
-                                rts.0.set_value(0)?;
+                            continue;
+                        }
+                        KeyCode::Char('f') if reflash => return Ok(MonitorExit::Reflash),
+                        KeyCode::Char('t') => {
+                            timestamps = !timestamps;
+                            continue;
+                        }
+                        KeyCode::Char('x') => {
+                            hex = !hex;
+                            continue;
+                        }
+                        KeyCode::Char('g') if filter.is_some() => {
+                            if let Some(filter) = &mut filter {
+                                filter.enabled = !filter.enabled;
+                                let state = if filter.enabled {
+                                    "enabled"
+                                } else {
+                                    "disabled"
+                                };
+                                stdout
+                                    .write_all(format!("\r\nLine filter {}\r\n", state).as_bytes())
+                                    .ok();
+                                stdout.flush()?;
+                            }
+                            continue;
+                        }
+                        KeyCode::Char('p') => {
+                            paused = !paused;
+                            if paused {
+                                stdout
+                                    .write_all(b"\r\nOutput paused, press CTRL+P to resume\r\n")
+                                    .ok();
                             } else {
-                                serial.write_request_to_send(false)?;
+                                if let Some(editor) = &mut editor {
+                                    paused_output = editor.wrap_output(&paused_output);
+                                }
+                                stdout.write_all(paused_output.as_bytes()).ok();
+                                paused_output.clear();
                             }
+                            stdout.flush()?;
                             continue;
                         }
                         _ => {}
                     }
                 }
+                if let Some(editor) = &mut editor {
+                    match editor.handle_key(key) {
+                        LineEdit::Changed => {
+                            stdout.write_all(editor.redraw().as_bytes()).ok();
+                            stdout.flush()?;
+                        }
+                        LineEdit::Submitted(line) => {
+                            stdout.write_all(b"\r\n").ok();
+                            stdout.flush()?;
+                            serial.write_all(line.as_bytes())?;
+                            serial.write_all(opts.line_ending.as_bytes())?;
+                            serial.flush()?;
+                        }
+                        LineEdit::Ignored => {}
+                    }
+                    continue;
+                }
                 if let Some(bytes) = handle_key_event(key) {
                     serial.write_all(&bytes)?;
                     serial.flush()?;
@@ -147,5 +740,84 @@ pub fn monitor(
             }
         }
     }
-    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn detects_crashes() {
+        assert_eq!(
+            detect_crash("Guru Meditation Error: Core  0 panic'ed (LoadProhibited).\r\n"),
+            Some("Guru Meditation Error: Core  0 panic'ed (LoadProhibited).".to_string())
+        );
+        assert_eq!(
+            detect_crash("rst:0x7 (TG0WDT_SYS_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)"),
+            Some("Reset by TG0WDT_SYS_RESET".to_string())
+        );
+        assert_eq!(
+            detect_crash("rst:0x1 (POWERON_RESET),boot:0x13 (SPI_FAST_FLASH_BOOT)"),
+            None
+        );
+        assert_eq!(detect_crash("I (312) cpu_start: Starting scheduler."), None);
+        assert_eq!(detect_crash("rst:0x7 ) WDT ("), None);
+    }
+
+    #[test]
+    fn lines_are_buffered_and_filtered() {
+        let mut lines = LineBuffer::default();
+        lines.push("I (10) wifi: connected\nI (11) sen");
+        assert_eq!(
+            lines.next_line().as_deref(),
+            Some("I (10) wifi: connected\n")
+        );
+        assert_eq!(lines.next_line(), None);
+        assert_eq!(lines.take_partial(false), None);
+        lines.push("sor: 21.5\n> ");
+        assert_eq!(lines.next_line().as_deref(), Some("I (11) sensor: 21.5\n"));
+        assert_eq!(lines.take_partial(true).as_deref(), Some("> "));
+
+        let mut filter = LineFilter::new(Regex::new("wifi|sensor").unwrap(), false);
+        assert!(filter.shows("I (10) wifi: connected"));
+        assert!(!filter.shows("I (12) main: idle"));
+        filter.invert = true;
+        assert!(!filter.shows("I (10) wifi: connected"));
+        filter.enabled = false;
+        assert!(filter.shows("I (10) wifi: connected"));
+    }
+
+    #[test]
+    fn triggers_are_paired_with_actions() {
+        let mut opts = MonitorOpts {
+            expect: vec![Regex::new("login:").unwrap(), Regex::new("ready").unwrap()],
+            then: vec!["send:root".parse().unwrap()],
+            ..MonitorOpts::default()
+        };
+        let actions: Vec<_> = triggers(&opts)
+            .unwrap()
+            .into_iter()
+            .map(|(_, action)| action)
+            .collect();
+        assert_eq!(
+            actions,
+            [TriggerAction::Send("root".into()), TriggerAction::Exit]
+        );
+        assert!("restart".parse::<TriggerAction>().is_err());
+
+        opts.expect.pop();
+        opts.then.push(TriggerAction::Reset);
+        assert!(triggers(&opts).is_err());
+    }
+
+    #[test]
+    fn timestamps_lines() {
+        let time = UNIX_EPOCH + Duration::from_millis(1_709_251_199_250);
+        assert_eq!(format_timestamp(time), "2024-02-29 23:59:59.250");
+
+        let mut timestamper = Timestamper::default();
+        assert_eq!(timestamper.stamp("boot\nHel", "t0"), "[t0] boot\n[t0] Hel");
+        assert_eq!(timestamper.stamp("lo\n\n", "t1"), "lo\n[t1] \n");
+        assert_eq!(timestamper.stamp("", "t2"), "");
+    }
 }
@@ -0,0 +1,202 @@
+//! Flash the same image to several devices at once
+//!
+//! Every device is flashed from its own thread, showing a single progress bar
+//! per device. A summary of all devices is printed once they are done.
+
+use std::{
+    sync::Arc,
+    thread,
+    time::{Duration, Instant},
+};
+
+use indicatif::{MultiProgress, ProgressBar, ProgressDrawTarget, ProgressStyle};
+use log::{log_enabled, Level};
+use miette::Result;
+
+use super::{
+    config::Config, connect, progress::use_device_bar, serial::known_device_ports, ConnectOpts,
+};
+use crate::{error::Error, Chip, Flasher};
+
+/// Serial ports to flash when flashing several devices at once
+///
+/// These are either all ports of known devices, or a comma separated list of
+/// ports given as the serial port. Returns `None` when a single device is
+/// flashed.
+pub fn device_ports(
+    serial: Option<&str>,
+    all_ports: bool,
+    config: &Config,
+) -> Result<Option<Vec<String>>> {
+    if all_ports {
+        let ports = known_device_ports(config);
+        if ports.is_empty() {
+            return Err(Error::NoSerial.into());
+        }
+        return Ok(Some(ports));
+    }
+
+    match serial {
+        Some(serial) if serial.contains(',') => Ok(Some(
+            serial
+                .split(',')
+                .map(str::trim)
+                .filter(|port| !port.is_empty())
+                .map(String::from)
+                .collect(),
+        )),
+        _ => Ok(None),
+    }
+}
+
+struct DeviceResult {
+    port: String,
+    result: Result<(Chip, Duration)>,
+}
+
+/// Connect to every port in `ports` and flash `elf_data` to it using `load`
+pub fn flash_devices<F>(
+    ports: Vec<String>,
+    opts: &ConnectOpts,
+    config: &Config,
+    elf_data: Vec<u8>,
+    load: F,
+) -> Result<()>
+where
+    F: Fn(&mut Flasher, &[u8]) -> Result<()> + Send + Sync + 'static,
+{
+    let multi = MultiProgress::new();
+    if !log_enabled!(Level::Info) {
+        multi.set_draw_target(ProgressDrawTarget::hidden());
+    }
+    let width = ports
+        .iter()
+        .map(|port| port.len())
+        .max()
+        .unwrap_or_default()
+        .max("Port".len());
+    let style = ProgressStyle::default_bar()
+        .template("{prefix} [{elapsed_precise}] {bar:40.cyan/blue} {percent:>3}% {msg}")
+        .progress_chars("#>-");
+
+    // The status messages of the devices would get mixed up, only the progress
+    // bars and warnings are shown until all of them are done
+    let level = log::max_level();
+    log::set_max_level(level.min(log::LevelFilter::Warn));
+
+    let elf_data = Arc::new(elf_data);
+    let load = Arc::new(load);
+    let handles = ports
+        .into_iter()
+        .map(|port| {
+            let pb = multi.add(ProgressBar::new(0));
+            pb.set_style(style.clone());
+            pb.set_prefix(format!("{:width$}", port, width = width));
+            pb.set_message("connecting");
+
+            let mut opts = opts.clone();
+            opts.serial = Some(port.clone());
+            opts.no_interactive = true;
+            let config = config.clone();
+            let elf_data = elf_data.clone();
+            let load = load.clone();
+
+            let handle = thread::spawn(move || {
+                use_device_bar(pb.clone());
+                let result = flash_device(&opts, &config, &elf_data, &*load);
+                match &result {
+                    Ok(_) => pb.finish_with_message("done"),
+                    Err(_) => pb.abandon_with_message("failed"),
+                }
+                result
+            });
+            (port, handle)
+        })
+        .collect::<Vec<_>>();
+
+    multi.join().ok();
+    let results = handles
+        .into_iter()
+        .map(|(port, handle)| DeviceResult {
+            result: handle
+                .join()
+                .unwrap_or_else(|_| Err(miette::miette!("flashing thread panicked"))),
+            port,
+        })
+        .collect::<Vec<_>>();
+
+    log::set_max_level(level);
+    print_summary(&results, width);
+
+    let failed = results
+        .iter()
+        .filter(|device| device.result.is_err())
+        .count();
+    if failed > 0 {
+        return Err(Error::DevicesFailed(failed, results.len()).into());
+    }
+
+    Ok(())
+}
+
+fn flash_device(
+    opts: &ConnectOpts,
+    config: &Config,
+    elf_data: &[u8],
+    load: &dyn Fn(&mut Flasher, &[u8]) -> Result<()>,
+) -> Result<(Chip, Duration)> {
+    let mut flasher = connect(opts, config)?;
+    let start = Instant::now();
+    load(&mut flasher, elf_data)?;
+
+    Ok((flasher.chip(), start.elapsed()))
+}
+
+fn print_summary(results: &[DeviceResult], width: usize) {
+    println!();
+    println!(
+        "{:width$}  {:8}  {:>7}  Result",
+        "Port",
+        "Chip",
+        "Time",
+        width = width
+    );
+    for device in results {
+        match &device.result {
+            Ok((chip, duration)) => println!(
+                "{:width$}  {:8}  {:>6.1}s  ok",
+                device.port,
+                chip.to_string(),
+                duration.as_secs_f32(),
+                width = width
+            ),
+            Err(err) => println!(
+                "{:width$}  {:8}  {:>7}  {}",
+                device.port,
+                "-",
+                "-",
+                err,
+                width = width
+            ),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn comma_separated_ports_are_split() {
+        let config = Config::default();
+
+        assert_eq!(
+            device_ports(Some("/dev/ttyUSB0, /dev/ttyUSB1,"), false, &config).unwrap(),
+            Some(vec!["/dev/ttyUSB0".to_string(), "/dev/ttyUSB1".to_string()])
+        );
+        assert_eq!(
+            device_ports(Some("/dev/ttyUSB0"), false, &config).unwrap(),
+            None
+        );
+    }
+}
@@ -0,0 +1,524 @@
+//! Generating NVS partitions and flashing them
+
+use std::{
+    convert::TryFrom,
+    fs,
+    path::{Path, PathBuf},
+    sync::Mutex,
+    time::Instant,
+};
+
+use clap::Parser;
+use log::{info, warn};
+use miette::{miette, IntoDiagnostic, Result, WrapErr};
+use once_cell::sync::Lazy;
+use regex::{Captures, Regex};
+
+use super::{
+    config::Config, connect, deadline::deadline, device_partition_table, input::read_input,
+    parse_u32, print_flash_report, progress::EspflashProgress, ConnectOpts, OutputFormat,
+};
+use crate::{
+    error::Error,
+    nvs::{NvsPartition, NvsValue},
+    FlashLayout, Flasher, PartitionTable,
+};
+
+/// Size of the NVS partition in the default partition tables of ESP-IDF
+const DEFAULT_SIZE: u32 = 0x6000;
+
+#[derive(Parser)]
+pub struct NvsOpts {
+    /// Values to store, as a CSV file in the format of `nvs_partition_gen.py`
+    /// or a TOML file with a table for each namespace
+    pub input: PathBuf,
+    /// Size of the partition, defaults to the size of the partition flashed to
+    /// or 0x6000
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub size: Option<u32>,
+    /// File to save the generated partition to
+    #[clap(short, long, required_unless_present = "flash")]
+    pub output: Option<PathBuf>,
+    /// Flash the generated partition to the connected device
+    #[clap(long)]
+    pub flash: bool,
+    /// Name of the partition to flash to
+    #[clap(long, default_value = "nvs")]
+    pub partition: String,
+    /// Partition table to look the partition up in, read from the device if
+    /// not given
+    #[clap(long)]
+    pub partition_table: Option<PathBuf>,
+    /// Offset of the partition table on the device
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub partition_table_offset: Option<u32>,
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+}
+
+pub fn nvs(opts: NvsOpts, config: Config, output_format: OutputFormat) -> Result<()> {
+    let partition = load_nvs(&opts.input)?;
+
+    if !opts.flash {
+        let image = partition.to_bytes(opts.size.unwrap_or(DEFAULT_SIZE))?;
+        return save(opts.output.as_deref(), &image);
+    }
+
+    let _deadline = deadline(opts.connect_opts.timeout);
+    let mut flasher = connect(&opts.connect_opts, &config)?;
+    flasher.board_info()?;
+    flasher.set_flash_layout(FlashLayout {
+        partition_table_offset: opts
+            .partition_table_offset
+            .or(config.flash.partition_table_offset),
+        ..FlashLayout::default()
+    });
+
+    let table = device_partition_table(&mut flasher, opts.partition_table.as_deref())?;
+    let target = table
+        .find(&opts.partition)
+        .ok_or_else(|| Error::MissingPartition(opts.partition.clone()))?;
+    let size = opts.size.unwrap_or_else(|| target.size());
+    if size > target.size() {
+        return Err(miette!(
+            "The size {:#x} is larger than the partition `{}` of {:#x} bytes",
+            size,
+            opts.partition,
+            target.size()
+        ));
+    }
+
+    let image = partition.to_bytes(size)?;
+    save(opts.output.as_deref(), &image)?;
+
+    let start = Instant::now();
+    flasher.write_bin_to_flash(
+        target.offset(),
+        &image,
+        Some(&mut EspflashProgress::default()),
+    )?;
+    info!("Flashing has completed");
+    print_flash_report(&mut flasher, start.elapsed(), output_format)?;
+
+    Ok(())
+}
+
+/// Options generating the NVS partition of every flashed device
+#[derive(Parser)]
+pub struct ProvisionOpts {
+    /// NVS values to flash along with the application, in the format of the
+    /// `nvs-partition` subcommand
+    ///
+    /// Placeholders like `{mac}` are replaced by the MAC address of the device,
+    /// `{mac_hex}` by the same without colons, and the names of the columns
+    /// of the manifest by the values of the device.
+    #[clap(long)]
+    pub nvs: Option<PathBuf>,
+    /// CSV file with a row of values for every device, the row with the MAC
+    /// address of the device in its `mac` column is used, or otherwise the
+    /// first row without one is assigned to it
+    #[clap(long, requires = "nvs")]
+    pub nvs_manifest: Option<PathBuf>,
+    /// Name of the partition to write the NVS values to
+    #[clap(long, default_value = "nvs")]
+    pub nvs_partition: String,
+    // The rows assigned to devices which are still being flashed, by their
+    // MAC address. Devices flashed at the same time must not be assigned the
+    // same row, but the manifest is only changed once flashing succeeded.
+    #[clap(skip)]
+    pending_rows: Mutex<Vec<(String, usize)>>,
+}
+
+impl ProvisionOpts {
+    /// Generate the NVS partition of the connected device, which is written
+    /// once the image has been flashed
+    ///
+    /// The partition is looked up in the partition table at `partition_table`,
+    /// or the default one of the chip. A row of the manifest newly assigned to
+    /// the device is saved by [`ProvisionOpts::flashed`].
+    pub fn provision(&self, flasher: &mut Flasher, partition_table: Option<&Path>) -> Result<()> {
+        let nvs = match &self.nvs {
+            Some(nvs) => nvs,
+            None => return Ok(()),
+        };
+
+        let mac = flasher.mac_address()?;
+        let mut values = vec![
+            ("mac".to_string(), mac.clone()),
+            ("mac_hex".to_string(), mac.replace(':', "")),
+        ];
+        if let Some(manifest) = &self.nvs_manifest {
+            let mut pending = self.pending_rows.lock().unwrap();
+            values.extend(manifest_row(manifest, &mac, &mut pending)?);
+        }
+
+        let partition = parse_nvs(nvs, &read_input(nvs)?, &|value| {
+            fill_placeholders(value, &values)
+        })?;
+
+        let table = match partition_table {
+            Some(path) => PartitionTable::try_from_bytes_at(
+                &read_input(path)?,
+                flasher.partition_table_offset(),
+            )
+            .wrap_err_with(|| format!("Failed to parse {}", path.display()))?,
+            None => flasher
+                .chip()
+                .default_partition_table(flasher.flash_layout())
+                .ok_or_else(|| Error::NoPartitionTable(flasher.chip()))?,
+        };
+        let size = table
+            .find(&self.nvs_partition)
+            .ok_or_else(|| Error::MissingPartition(self.nvs_partition.clone()))?
+            .size();
+
+        info!("Generated the NVS partition of {}", mac);
+        flasher.set_partition_data(vec![(
+            self.nvs_partition.clone(),
+            partition.to_bytes(size)?,
+        )]);
+
+        Ok(())
+    }
+
+    /// Save the row of the manifest assigned to the connected device, once its
+    /// image and NVS partition have been flashed
+    pub fn flashed(&self, flasher: &mut Flasher) -> Result<()> {
+        let manifest = match &self.nvs_manifest {
+            Some(manifest) => manifest,
+            None => return Ok(()),
+        };
+
+        let mac = flasher.mac_address()?;
+        let mut pending = self.pending_rows.lock().unwrap();
+        if let Some(i) = pending.iter().position(|(device, _)| *device == mac) {
+            save_row(manifest, &mac, pending[i].1)?;
+            pending.swap_remove(i);
+        }
+
+        Ok(())
+    }
+}
+
+/// Find the row of the manifest assigned to the device with the MAC address
+/// `mac`, or assign it the first free one
+///
+/// Rows which aren't saved yet are kept in `pending`. Returns the values of the
+/// row by the names of their columns.
+fn manifest_row(
+    path: &Path,
+    mac: &str,
+    pending: &mut Vec<(String, usize)>,
+) -> Result<Vec<(String, String)>> {
+    let (columns, mut rows, mac_column) = read_manifest(path)?;
+
+    let saved = rows
+        .iter()
+        .position(|row| row[mac_column].eq_ignore_ascii_case(mac));
+    let index = match saved.or_else(|| {
+        pending
+            .iter()
+            .find(|(device, _)| device == mac)
+            .map(|(_, index)| *index)
+    }) {
+        Some(index) => index,
+        None => {
+            let index = (0..rows.len())
+                .find(|&index| {
+                    rows[index][mac_column].is_empty()
+                        && pending.iter().all(|(_, pending)| *pending != index)
+                })
+                .ok_or_else(|| {
+                    miette!(
+                        "All rows of {} are assigned to other devices",
+                        path.display()
+                    )
+                })?;
+            pending.push((mac.into(), index));
+
+            index
+        }
+    };
+
+    rows[index][mac_column] = mac.into();
+    Ok(columns.into_iter().zip(rows.swap_remove(index)).collect())
+}
+
+/// Save the assignment of the row `index` of the manifest to the device, so
+/// flashing it again uses the same row
+fn save_row(path: &Path, mac: &str, index: usize) -> Result<()> {
+    let (columns, mut rows, mac_column) = read_manifest(path)?;
+    let row = rows
+        .get_mut(index)
+        .ok_or_else(|| miette!("The row {} of {} was removed", index + 1, path.display()))?;
+    row[mac_column] = mac.into();
+
+    let mut writer = csv::Writer::from_path(path).into_diagnostic()?;
+    writer.write_record(&columns).into_diagnostic()?;
+    for row in &rows {
+        writer.write_record(row).into_diagnostic()?;
+    }
+    writer
+        .flush()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    info!(
+        "Assigned row {} of {} to {}",
+        index + 1,
+        path.display(),
+        mac
+    );
+
+    Ok(())
+}
+
+/// Read the columns and rows of the manifest, and the index of its `mac`
+/// column, which is added if it's missing
+fn read_manifest(path: &Path) -> Result<(Vec<String>, Vec<Vec<String>>, usize)> {
+    let mut reader = csv::ReaderBuilder::new()
+        .trim(csv::Trim::All)
+        .from_path(path)
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+    let mut columns: Vec<String> = reader
+        .headers()
+        .into_diagnostic()?
+        .iter()
+        .map(String::from)
+        .collect();
+    let mut rows = reader
+        .records()
+        .map(|record| record.map(|record| record.iter().map(String::from).collect::<Vec<_>>()))
+        .collect::<Result<Vec<_>, _>>()
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to parse {}", path.display()))?;
+
+    let mac_column = match columns.iter().position(|column| column == "mac") {
+        Some(column) => column,
+        None => {
+            columns.push("mac".into());
+            for row in &mut rows {
+                row.push(String::new());
+            }
+            columns.len() - 1
+        }
+    };
+
+    Ok((columns, rows, mac_column))
+}
+
+/// Replace the `{name}` placeholders in a value of the definitions by their
+/// values
+///
+/// The values are inserted as they are, placeholders within them are kept.
+fn fill_placeholders(template: &str, values: &[(String, String)]) -> String {
+    static PLACEHOLDER: Lazy<Regex> = Lazy::new(|| Regex::new(r"\{([^{}\s]+)\}").unwrap());
+
+    PLACEHOLDER
+        .replace_all(template, |captures: &Captures| {
+            match values.iter().find(|(name, _)| *name == captures[1]) {
+                Some((_, value)) => value.clone(),
+                None => {
+                    warn!("There is no value for the placeholder {}", &captures[0]);
+                    captures[0].to_string()
+                }
+            }
+        })
+        .into_owned()
+}
+
+/// Load the values of an NVS partition from a CSV or TOML file
+pub fn load_nvs(path: &Path) -> Result<NvsPartition> {
+    let data = read_input(path)?;
+    parse_nvs(path, &data, &|value| value.to_string())
+}
+
+/// Parse the values, passing every value given as a string, or the path of the
+/// file holding it, through `fill`
+fn parse_nvs(path: &Path, data: &[u8], fill: &dyn Fn(&str) -> String) -> Result<NvsPartition> {
+    // Files are looked up relative to the definitions
+    let dir = path.parent().unwrap_or_else(|| Path::new(""));
+
+    let is_toml = path.extension().map_or(false, |ext| ext == "toml");
+    let partition = if is_toml {
+        let data = std::str::from_utf8(data).into_diagnostic()?;
+        from_toml(data, dir, fill)
+    } else {
+        NvsPartition::try_from_csv_with(data, dir, fill).map_err(Into::into)
+    };
+
+    partition.wrap_err_with(|| format!("Failed to parse {}", path.display()))
+}
+
+/// Parse a table of values for each namespace
+///
+/// Strings, integers and booleans are stored as `string`, `i32` or `i64`, and
+/// `u8` values. Other types are given as a table with the `encoding` and
+/// either the `value` or the `file` holding it, like the rows of the CSV files.
+fn from_toml(data: &str, dir: &Path, fill: &dyn Fn(&str) -> String) -> Result<NvsPartition> {
+    let namespaces: toml::value::Table = toml::from_str(data).into_diagnostic()?;
+    let mut partition = NvsPartition::new();
+
+    for (namespace, values) in &namespaces {
+        let values = values
+            .as_table()
+            .ok_or_else(|| miette!("The namespace `{}` is not a table", namespace))?;
+
+        for (key, value) in values {
+            let value = match value {
+                toml::Value::String(string) => NvsValue::String(fill(string)),
+                toml::Value::Integer(int) => i32::try_from(*int)
+                    .map(NvsValue::I32)
+                    .unwrap_or(NvsValue::I64(*int)),
+                toml::Value::Boolean(bool) => NvsValue::U8(*bool as u8),
+                toml::Value::Table(table) => {
+                    let encoding = table
+                        .get("encoding")
+                        .and_then(|encoding| encoding.as_str())
+                        .ok_or_else(|| miette!("The value of `{}` has no encoding", key))?;
+
+                    match (table.get("file"), table.get("value")) {
+                        (Some(toml::Value::String(file)), _) => {
+                            let path = dir.join(fill(file));
+                            let data = fs::read(&path)
+                                .into_diagnostic()
+                                .wrap_err_with(|| format!("Failed to open {}", path.display()))?;
+                            NvsValue::from_file(encoding, data)?
+                        }
+                        (_, Some(toml::Value::String(value))) => {
+                            NvsValue::parse(encoding, &fill(value))?
+                        }
+                        (_, Some(toml::Value::Integer(value))) => {
+                            NvsValue::parse(encoding, &value.to_string())?
+                        }
+                        _ => return Err(miette!("The value of `{}` is missing", key)),
+                    }
+                }
+                _ => return Err(miette!("The value of `{}` has an unsupported type", key)),
+            };
+
+            partition.set(namespace, key, value)?;
+        }
+    }
+
+    Ok(partition)
+}
+
+fn save(path: Option<&Path>, image: &[u8]) -> Result<()> {
+    if let Some(path) = path {
+        fs::write(path, image)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {}", path.display()))?;
+    }
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn placeholders_are_filled() {
+        let values = [
+            ("mac".to_string(), "7c:df:a1:40:02:a4".to_string()),
+            ("device_id".to_string(), "42".to_string()),
+        ];
+        assert_eq!(
+            fill_placeholders("{device_id}-{mac}", &values),
+            "42-7c:df:a1:40:02:a4"
+        );
+
+        let values = [
+            ("name".to_string(), "{id}".to_string()),
+            ("id".to_string(), "42".to_string()),
+        ];
+        assert_eq!(
+            fill_placeholders("{name}/{id}/{serial}", &values),
+            "{id}/42/{serial}"
+        );
+    }
+
+    #[test]
+    fn placeholders_are_filled_in_values_only() {
+        let values = [
+            ("id".to_string(), "42".to_string()),
+            ("name".to_string(), "a,b\"\nc".to_string()),
+        ];
+        let fill = |value: &str| fill_placeholders(value, &values);
+        let expected = [NvsValue::U32(42), NvsValue::String("a,b\"\nc".into())];
+
+        let csv = "key,type,encoding,value\n\
+                   device,namespace,,\n\
+                   id,data,u32,{id}\n\
+                   name,data,string,{name}\n";
+        let partition = parse_nvs(Path::new("nvs.csv"), csv.as_bytes(), &fill).unwrap();
+        let values: Vec<_> = partition
+            .entries()
+            .iter()
+            .map(|e| e.value.clone())
+            .collect();
+        assert_eq!(values, expected);
+
+        let toml = r#"
+            [device]
+            id = { encoding = "u32", value = "{id}" }
+            name = "{name}"
+        "#;
+        let partition = from_toml(toml, Path::new(""), &fill).unwrap();
+        let values: Vec<_> = partition
+            .entries()
+            .iter()
+            .map(|e| e.value.clone())
+            .collect();
+        assert_eq!(values, expected);
+    }
+
+    #[test]
+    fn manifest_rows_are_saved_once_flashed() {
+        let path =
+            std::env::temp_dir().join(format!("espflash-manifest-{}.csv", std::process::id()));
+        fs::write(&path, "device_id\n1\n2\n").unwrap();
+        let mut pending = Vec::new();
+
+        let row = manifest_row(&path, "aa", &mut pending).unwrap();
+        assert_eq!(row[0], ("device_id".to_string(), "1".to_string()));
+        // Other devices are assigned other rows, the same device the same one
+        let row = manifest_row(&path, "bb", &mut pending).unwrap();
+        assert_eq!(row[0].1, "2");
+        let row = manifest_row(&path, "aa", &mut pending).unwrap();
+        assert_eq!(row[0].1, "1");
+        assert!(manifest_row(&path, "cc", &mut pending).is_err());
+        assert_eq!(fs::read_to_string(&path).unwrap(), "device_id\n1\n2\n");
+
+        save_row(&path, "bb", 1).unwrap();
+        let contents = fs::read_to_string(&path).unwrap();
+        fs::remove_file(&path).unwrap();
+        assert_eq!(contents, "device_id,mac\n1,\n2,bb\n");
+    }
+
+    #[test]
+    fn toml_values_are_parsed() {
+        let toml = r#"
+            [wifi]
+            ssid = "espflash"
+            retries = { encoding = "u8", value = 3 }
+            key = { encoding = "hex2bin", value = "deadbeef" }
+        "#;
+        let partition = from_toml(toml, Path::new(""), &|value| value.to_string()).unwrap();
+
+        let values: Vec<_> = partition
+            .entries()
+            .iter()
+            .map(|entry| (entry.key.as_str(), entry.value.clone()))
+            .collect();
+        assert_eq!(
+            values,
+            [
+                ("key", NvsValue::Blob(vec![0xde, 0xad, 0xbe, 0xef])),
+                ("retries", NvsValue::U8(3)),
+                ("ssid", NvsValue::String("espflash".into())),
+            ]
+        );
+    }
+}
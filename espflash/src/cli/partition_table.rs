@@ -0,0 +1,92 @@
+//! Converting partition tables between the CSV and binary formats
+
+use std::{
+    fs,
+    io::{stdout, Write},
+    path::PathBuf,
+};
+
+use clap::Parser;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use super::{config::Config, input::read_input};
+use crate::{
+    partition_table::{format_size, parse_u32, PARTITION_MAGIC, PARTITION_TABLE_OFFSET},
+    PartitionTable,
+};
+
+#[derive(Parser)]
+pub struct PartitionTableOpts {
+    /// Partition table to read, in either the CSV or the binary format
+    partition_table: PathBuf,
+    /// Offset the partition table is flashed to, partitions without an offset
+    /// follow it
+    #[clap(long, parse(try_from_str = parse_u32))]
+    partition_table_offset: Option<u32>,
+    /// Convert the partition table to the binary format
+    #[clap(long, conflicts_with = "to-csv")]
+    to_binary: bool,
+    /// Convert the partition table to the CSV format
+    #[clap(long)]
+    to_csv: bool,
+    /// File to write the converted partition table to, instead of stdout
+    #[clap(short, long)]
+    output: Option<PathBuf>,
+}
+
+/// Validate a partition table and convert it to the requested format
+///
+/// Without a target format the table is converted to the other format if an
+/// output file is given, and pretty printed otherwise.
+pub fn partition_table(opts: PartitionTableOpts, config: Config) -> Result<()> {
+    let data = read_input(&opts.partition_table)?;
+    let offset = opts
+        .partition_table_offset
+        .or(config.flash.partition_table_offset)
+        .unwrap_or(PARTITION_TABLE_OFFSET);
+    let table = PartitionTable::try_from_bytes_at(&data, offset)
+        .wrap_err_with(|| format!("Failed to parse {}", opts.partition_table.display()))?;
+    // Binary tables start with the magic of the first partition entry
+    let is_binary = data.starts_with(&PARTITION_MAGIC);
+
+    let output = if opts.to_binary {
+        table.to_bytes()
+    } else if opts.to_csv {
+        table.to_csv().into_bytes()
+    } else if opts.output.is_some() {
+        if is_binary {
+            table.to_csv().into_bytes()
+        } else {
+            table.to_bytes()
+        }
+    } else {
+        print_table(&table);
+        return Ok(());
+    };
+
+    match &opts.output {
+        Some(path) => fs::write(path, output)
+            .into_diagnostic()
+            .wrap_err_with(|| format!("Failed to write {}", path.display())),
+        None => stdout().write_all(&output).into_diagnostic(),
+    }
+}
+
+fn print_table(table: &PartitionTable) {
+    println!(
+        "{:<16} {:<5} {:<9} {:>10} {:>17} Flags",
+        "Name", "Type", "SubType", "Offset", "Size"
+    );
+    for partition in table.partitions() {
+        println!(
+            "{:<16} {:<5} {:<9} {:>#10x} {:>#10x} {:>6} {}",
+            partition.name(),
+            partition.ty().to_string(),
+            partition.sub_type().to_string(),
+            partition.offset(),
+            partition.size(),
+            format_size(partition.size()),
+            partition.flags()
+        );
+    }
+}
@@ -0,0 +1,75 @@
+//! Progress bar shown while writing to the device
+
+use std::cell::RefCell;
+
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{log_enabled, Level};
+
+use crate::flasher::ProgressCallbacks;
+
+thread_local! {
+    /// Bar reused for every segment written from this thread, see
+    /// [`use_device_bar`]
+    static DEVICE_BAR: RefCell<Option<ProgressBar>> = const { RefCell::new(None) };
+}
+
+/// Show the progress of everything written from the current thread on `pb`
+///
+/// When several devices are flashed at once each of them gets a single line,
+/// instead of one bar per segment.
+pub fn use_device_bar(pb: ProgressBar) {
+    DEVICE_BAR.with(|bar| *bar.borrow_mut() = Some(pb));
+}
+
+/// Progress bar showing the throughput and remaining time of each segment
+#[derive(Default)]
+pub struct EspflashProgress {
+    pb: Option<ProgressBar>,
+    shared: bool,
+}
+
+impl ProgressCallbacks for EspflashProgress {
+    fn init(&mut self, addr: u32, total: usize) {
+        if let Some(pb) = DEVICE_BAR.with(|bar| bar.borrow().clone()) {
+            pb.set_length(total as u64);
+            pb.set_position(0);
+            pb.set_message(format!("segment 0x{:X}", addr));
+
+            self.pb = Some(pb);
+            self.shared = true;
+            return;
+        }
+
+        // Progress is status output, so it is hidden along with it in quiet mode
+        let pb = if log_enabled!(Level::Info) {
+            ProgressBar::new(total as u64)
+        } else {
+            ProgressBar::hidden()
+        };
+        pb.set_style(
+            ProgressStyle::default_bar()
+                .template(
+                    "[{elapsed_precise}] {bar:40.cyan/blue} {percent:>3}% {bytes_per_sec:>11} ETA {eta:>3} {msg}",
+                )
+                .progress_chars("#>-"),
+        );
+        pb.set_message(format!("segment 0x{:X}", addr));
+
+        self.pb = Some(pb);
+    }
+
+    fn update(&mut self, current: usize) {
+        if let Some(pb) = &self.pb {
+            pb.set_position(current as u64);
+        }
+    }
+
+    fn finish(&mut self) {
+        if let Some(pb) = self.pb.take() {
+            // The shared bar is finished once the whole device is done
+            if !self.shared {
+                pb.finish();
+            }
+        }
+    }
+}
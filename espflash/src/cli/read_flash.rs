@@ -0,0 +1,180 @@
+//! Reading the flash contents back from the device
+//!
+//! Large dumps take a long time at the default baud rate, so the flash is read
+//! in chunks which are retried individually. Progress is recorded in a state
+//! file next to the output, which allows an interrupted dump to be resumed by
+//! running the same command again.
+
+use std::{
+    fs::{self, File, OpenOptions},
+    io::{BufWriter, Seek, SeekFrom, Write},
+    path::{Path, PathBuf},
+};
+
+use clap::Parser;
+use indicatif::{ProgressBar, ProgressStyle};
+use log::{info, log_enabled, warn, Level};
+use miette::{IntoDiagnostic, Result, WrapErr};
+use serde::{Deserialize, Serialize};
+
+use super::{config::Config, connect, deadline::deadline, parse_u32, ConnectOpts};
+use crate::error::Error;
+
+const CHUNK_SIZE: u32 = 0x1000;
+const CHUNK_ATTEMPTS: usize = 3;
+
+#[derive(Parser)]
+pub struct ReadFlashOpts {
+    /// Offset to start reading at, eg `0x10000`
+    #[clap(parse(try_from_str = parse_u32))]
+    pub offset: u32,
+    /// Number of bytes to read, eg `0x1000` or `4M`
+    #[clap(parse(try_from_str = parse_u32))]
+    pub size: u32,
+    /// File name to save the flash contents to
+    pub file: PathBuf,
+    // The arguments are taken in the same order as by esptool.py, before the
+    // optional serial port
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+}
+
+/// Progress of a dump, saved after every chunk
+#[derive(Debug, Serialize, Deserialize, PartialEq, Eq)]
+struct DumpState {
+    offset: u32,
+    size: u32,
+    completed: u32,
+}
+
+impl DumpState {
+    fn path(file: &Path) -> PathBuf {
+        let mut path = file.as_os_str().to_owned();
+        path.push(".state");
+        PathBuf::from(path)
+    }
+
+    /// Load the state of a previous dump with the same parameters, if any
+    fn load(file: &Path, offset: u32, size: u32) -> Option<Self> {
+        let state: DumpState = toml::from_str(&fs::read_to_string(Self::path(file)).ok()?).ok()?;
+        let written = fs::metadata(file).ok()?.len();
+
+        if state.offset == offset
+            && state.size == size
+            && state.completed <= size
+            && written >= state.completed as u64
+        {
+            Some(state)
+        } else {
+            None
+        }
+    }
+
+    fn save(&self, file: &Path) -> Result<()> {
+        let state = toml::to_string(self).into_diagnostic()?;
+        fs::write(Self::path(file), state)
+            .into_diagnostic()
+            .wrap_err("Failed to save the dump state")
+    }
+}
+
+pub fn read_flash(opts: ReadFlashOpts, config: Config) -> Result<()> {
+    let _deadline = deadline(opts.connect_opts.timeout);
+    let mut flasher = connect(&opts.connect_opts, &config)?;
+    flasher.board_info()?;
+    // Addresses within the region can't overflow once its end is known to fit
+    if opts.offset.checked_add(opts.size).is_none() {
+        return Err(
+            Error::FlashRegionOutOfRange(opts.offset, opts.size, flasher.flash_size()).into(),
+        );
+    }
+
+    let resumed = DumpState::load(&opts.file, opts.offset, opts.size);
+    let mut output = match &resumed {
+        Some(state) => OpenOptions::new()
+            .write(true)
+            .open(&opts.file)
+            .and_then(|file| file.set_len(state.completed as u64).map(|_| file)),
+        None => File::create(&opts.file),
+    }
+    .into_diagnostic()
+    .wrap_err_with(|| format!("Failed to open {}", opts.file.display()))?;
+    output.seek(SeekFrom::End(0)).into_diagnostic()?;
+    let mut output = BufWriter::new(output);
+
+    let mut state = match resumed {
+        Some(state) => {
+            info!(
+                "Resuming dump of {} at {:#x}",
+                opts.file.display(),
+                opts.offset + state.completed
+            );
+            state
+        }
+        None => DumpState {
+            offset: opts.offset,
+            size: opts.size,
+            completed: 0,
+        },
+    };
+
+    let pb = if log_enabled!(Level::Info) {
+        ProgressBar::new(opts.size as u64)
+    } else {
+        ProgressBar::hidden()
+    };
+    pb.set_style(
+        ProgressStyle::default_bar()
+            .template("[{elapsed_precise}] {bar:40.cyan/blue} {bytes:>9}/{total_bytes:9} {eta}")
+            .progress_chars("#>-"),
+    );
+    pb.set_position(state.completed as u64);
+
+    while state.completed < state.size {
+        let address = state.offset + state.completed;
+        let chunk_size = CHUNK_SIZE.min(state.size - state.completed);
+
+        let mut attempt = 1;
+        let data = loop {
+            let mut data = Vec::with_capacity(chunk_size as usize);
+            match flasher.read_flash(address, chunk_size, &mut data) {
+                Ok(()) => break data,
+                // Only communication errors are worth retrying
+                Err(err @ (Error::Connection(_) | Error::RomError(_)))
+                    if attempt < CHUNK_ATTEMPTS =>
+                {
+                    warn!(
+                        "Reading {:#x} failed, retrying ({}/{}): {}",
+                        address, attempt, CHUNK_ATTEMPTS, err
+                    );
+                    attempt += 1;
+                }
+                Err(err) => {
+                    pb.abandon();
+                    return Err(err).wrap_err_with(|| {
+                        format!(
+                            "Failed to read flash at {:#x}, run the same command again to resume",
+                            address
+                        )
+                    });
+                }
+            }
+        };
+
+        output.write_all(&data).into_diagnostic()?;
+        output.flush().into_diagnostic()?;
+        state.completed += chunk_size;
+        state.save(&opts.file)?;
+        pb.set_position(state.completed as u64);
+    }
+    pb.finish();
+
+    fs::remove_file(DumpState::path(&opts.file)).into_diagnostic()?;
+    info!(
+        "Saved {} bytes of flash to {}",
+        opts.size,
+        opts.file.display()
+    );
+
+    Ok(())
+}
@@ -0,0 +1,126 @@
+//! Reading and writing the registers of the chip through the bootloader
+
+use clap::Parser;
+use log::info;
+use miette::Result;
+
+use super::{config::Config, connect, deadline::deadline, parse_u32, ConnectOpts};
+use crate::{error::Error, Flasher};
+
+#[derive(Parser)]
+pub struct ReadRegOpts {
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+    /// Address of the register, eg `0x3ff5a000`
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub address: u32,
+    /// Number of consecutive 32 bit registers to read
+    #[clap(long, default_value_t = 1)]
+    pub count: u32,
+}
+
+#[derive(Parser)]
+pub struct WriteRegOpts {
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+    /// Address of the register, eg `0x3ff5a000`
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub address: u32,
+    /// Value to write
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub value: u32,
+    /// Only change the bits set in this mask, keeping the other ones
+    #[clap(long, parse(try_from_str = parse_u32))]
+    pub mask: Option<u32>,
+}
+
+pub fn read_reg(opts: ReadRegOpts, config: Config) -> Result<()> {
+    let addresses = addresses(opts.address, opts.count)?;
+    let _deadline = deadline(opts.connect_opts.timeout);
+    let mut flasher = connect(&opts.connect_opts, &config)?;
+
+    for (address, value) in read_regs(&mut flasher, addresses)? {
+        println!("{:#010x}: {:#010x}", address, value);
+    }
+
+    Ok(())
+}
+
+/// The addresses of `count` consecutive registers starting at `address`
+fn addresses(address: u32, count: u32) -> Result<Vec<u32>, Error> {
+    (0..count)
+        .map(|index| {
+            index
+                .checked_mul(4)
+                .and_then(|offset| address.checked_add(offset))
+                .ok_or(Error::RegisterRangeOutOfRange(address, count))
+        })
+        .collect()
+}
+
+/// Read the consecutive registers at `addresses`, paired with their values
+fn read_regs(flasher: &mut Flasher, addresses: Vec<u32>) -> Result<Vec<(u32, u32)>, Error> {
+    let values = match addresses.first() {
+        Some(&first) => flasher
+            .connection()
+            .read_regs(first, addresses.len() as u32)?,
+        None => Vec::new(),
+    };
+    Ok(addresses.into_iter().zip(values).collect())
+}
+
+pub fn write_reg(opts: WriteRegOpts, config: Config) -> Result<()> {
+    let _deadline = deadline(opts.connect_opts.timeout);
+    let mut flasher = connect(&opts.connect_opts, &config)?;
+
+    flasher.write_reg(opts.address, opts.value, opts.mask)?;
+    info!(
+        "Wrote {:#010x} to {:#010x}, it now reads {:#010x}",
+        opts.value,
+        opts.address,
+        flasher.read_reg(opts.address)?
+    );
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use serialport::SerialPortType;
+
+    use super::*;
+    use crate::{
+        reset::{ResetAfterOperation, ResetBeforeOperation},
+        transport::mock::MockDevice,
+        ConnectSettings,
+    };
+
+    #[test]
+    fn reads_consecutive_registers() {
+        let settings = ConnectSettings {
+            before: ResetBeforeOperation::NoReset,
+            after: ResetAfterOperation::NoReset,
+            ..ConnectSettings::default()
+        };
+        let mut flasher = Flasher::connect_transport(
+            Box::new(MockDevice::new()),
+            SerialPortType::Unknown,
+            None,
+            None,
+            None,
+            settings,
+        )
+        .unwrap();
+        flasher.write_reg(0x6000_0004, 0x1234, None).unwrap();
+
+        assert_eq!(
+            read_regs(&mut flasher, addresses(0x6000_0000, 3).unwrap()).unwrap(),
+            [(0x6000_0000, 0), (0x6000_0004, 0x1234), (0x6000_0008, 0)]
+        );
+        assert!(matches!(
+            addresses(0xffff_fff8, 3),
+            Err(Error::RegisterRangeOutOfRange(0xffff_fff8, 3))
+        ));
+        assert_eq!(addresses(0xffff_fffc, 1).unwrap(), [0xffff_fffc]);
+    }
+}
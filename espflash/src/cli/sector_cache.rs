@@ -0,0 +1,73 @@
+//! Keeping the sector cache of every device in the cache directory, by its MAC
+//! address
+
+use std::{fs, path::PathBuf};
+
+use log::{debug, warn};
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use super::config::Config;
+use crate::{Flasher, SectorCache};
+
+fn cache_file(mac: &str) -> PathBuf {
+    Config::sector_cache_dir().join(format!("{}.json", mac.replace(':', "")))
+}
+
+/// Attach the sector cache of the connected device to `flasher`
+///
+/// The cache file is removed until [`save`] writes it again, so the cache can't
+/// go out of date when the flash is changed without saving it afterwards.
+pub fn attach(flasher: &mut Flasher) -> Result<()> {
+    // The flasher keeps the MAC address, so saving the cache doesn't need the
+    // device anymore once it was reset
+    let path = match flasher.mac_address() {
+        Ok(mac) => cache_file(&mac),
+        Err(err) => {
+            debug!(
+                "Not using a sector cache, the MAC address is unknown: {}",
+                err
+            );
+            return Ok(());
+        }
+    };
+
+    let cache = match fs::read_to_string(&path) {
+        Ok(json) => {
+            fs::remove_file(&path)
+                .into_diagnostic()
+                .wrap_err_with(|| format!("Failed to remove {}", path.display()))?;
+            SectorCache::from_json(&json).unwrap_or_else(|err| {
+                warn!("{}, writing all sectors", err);
+                SectorCache::default()
+            })
+        }
+        Err(_) => SectorCache::default(),
+    };
+    flasher.set_sector_cache(Some(cache));
+
+    Ok(())
+}
+
+/// Make the next flash write all sectors
+pub fn clear(flasher: &mut Flasher) {
+    if flasher.sector_cache().is_some() {
+        flasher.set_sector_cache(Some(SectorCache::default()));
+    }
+}
+
+/// Write the sector cache of `flasher` back to the cache directory
+///
+/// The MAC address was read by [`attach`], so this works after the device was
+/// reset into the application.
+pub fn save(flasher: &mut Flasher) -> Result<()> {
+    let json = match flasher.sector_cache() {
+        Some(cache) => cache.to_json(),
+        None => return Ok(()),
+    };
+    let path = cache_file(&flasher.mac_address()?);
+
+    fs::create_dir_all(Config::sector_cache_dir())
+        .and_then(|_| fs::write(&path, json))
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to save the sector cache {}", path.display()))
+}
@@ -1,7 +1,10 @@
+use std::path::Path;
+
 use crossterm::style::Stylize;
 use dialoguer::{theme::ColorfulTheme, Confirm, Select};
+use log::warn;
 use miette::{IntoDiagnostic, Result};
-use serialport::{available_ports, SerialPortInfo, SerialPortType};
+use serialport::{available_ports, SerialPortInfo, SerialPortType, UsbPortInfo};
 
 use super::{config::Config, ConnectOpts};
 use crate::{cli::config::UsbDevice, error::Error};
@@ -19,16 +22,38 @@ pub fn get_serial_port_info(
     // select a serial port. If some VID and PID were provided then the user will
     // also be prompted to select a port, unless there is only one found and its VID
     // and PID match the configured values.
-    let ports = detect_usb_serial_ports().unwrap_or_default();
+    let mut ports = detect_usb_serial_ports().unwrap_or_default();
 
     let maybe_port = if let Some(serial) = &matches.serial {
-        find_serial_port(&ports, serial.to_owned())
+        find_serial_port(&ports, serial)?
     } else if let Some(serial) = &config.connection.serial {
-        find_serial_port(&ports, serial.to_owned())
-    } else if !ports.is_empty() {
-        let (port, matches) = select_serial_port(ports, config)?;
+        find_serial_port(&ports, serial)?
+    } else {
+        if config.connection.only_known_devices {
+            ports.retain(|port| match &port.port_type {
+                SerialPortType::UsbPort(info) => is_known_device(config, info),
+                _ => false,
+            });
+        }
+        if ports.is_empty() {
+            return Err(Error::NoSerial);
+        }
+
+        // Adapters remembered by their serial number are used without asking, even
+        // if the name of their port changed, unless the port should be confirmed
+        if !matches.confirm_port {
+            if let Some(port) = find_remembered_port(&ports, config) {
+                return Ok(port);
+            }
+        }
+
+        let (port, _) = select_serial_port(ports, config, matches)?;
         match &port.port_type {
-            SerialPortType::UsbPort(usb_info) if !matches => {
+            // Known adapters, eg CP210x or CH340, can be remembered as well, so that
+            // the same one is picked when several are connected
+            SerialPortType::UsbPort(usb_info)
+                if !is_remembered(config, usb_info) && !matches.no_interactive =>
+            {
                 if Confirm::with_theme(&ColorfulTheme::default())
                     .with_prompt("Remember this serial port for future use?")
                     .interact_opt()?
@@ -38,9 +63,10 @@ pub fn get_serial_port_info(
                         config.usb_device.push(UsbDevice {
                             vid: usb_info.vid,
                             pid: usb_info.pid,
+                            serial_number: usb_info.serial_number.clone(),
                         })
                     }) {
-                        eprintln!("Failed to save config {:#}", e);
+                        warn!("Failed to save config {:#}", e);
                     }
                 }
             }
@@ -48,8 +74,6 @@ pub fn get_serial_port_info(
         }
 
         Some(port)
-    } else {
-        None
     };
 
     if let Some(port_info) = maybe_port {
@@ -61,11 +85,109 @@ pub fn get_serial_port_info(
 
 /// Given a vector of `SerialPortInfo` structs, attempt to find and return one
 /// whose `port_name` field matches the provided `name` argument.
-fn find_serial_port(ports: &[SerialPortInfo], name: String) -> Option<SerialPortInfo> {
-    ports
+///
+/// Bluetooth serial ports and ports which aren't USB ports, like the UARTs of a
+/// Raspberry Pi, are never selected automatically, but can be used by
+/// specifying them explicitly. If no port has the given name, USB ports are also
+/// matched by their product name, eg `Silicon Labs CP210x`, as long as only a
+/// single port matches.
+fn find_serial_port(ports: &[SerialPortInfo], name: &str) -> Result<Option<SerialPortInfo>, Error> {
+    let original_name = name.trim();
+    let name = normalize_port_name(name);
+
+    let other_ports = available_ports().unwrap_or_default();
+    let bluetooth_ports = detect_bluetooth_serial_ports();
+    let by_name = ports
         .iter()
-        .find(|port| port.port_name.to_lowercase() == name.to_lowercase())
-        .map(|port| port.to_owned())
+        .chain(bluetooth_ports.iter())
+        .chain(other_ports.iter())
+        .find(|port| normalize_port_name(&port.port_name) == name);
+    if let Some(port) = by_name {
+        return Ok(Some(port.to_owned()));
+    }
+
+    // Not all ports are enumerated, eg ptys or the UARTs of some boards, these
+    // are used as is if the device exists
+    if Path::new(original_name).exists() {
+        return Ok(Some(SerialPortInfo {
+            port_name: original_name.to_string(),
+            port_type: SerialPortType::Unknown,
+        }));
+    }
+
+    let by_product = ports
+        .iter()
+        .filter(|port| match &port.port_type {
+            SerialPortType::UsbPort(info) => info
+                .product
+                .as_ref()
+                .map_or(false, |product| product.to_lowercase().contains(&name)),
+            _ => false,
+        })
+        .collect::<Vec<_>>();
+
+    match by_product.as_slice() {
+        [] => Ok(None),
+        [port] => Ok(Some((*port).clone())),
+        ports => Err(Error::AmbiguousSerialPort(
+            name,
+            ports
+                .iter()
+                .map(|port| port.port_name.as_str())
+                .collect::<Vec<_>>()
+                .join(", "),
+        )),
+    }
+}
+
+/// Normalize a port name for comparison
+///
+/// Windows port names are case insensitive, and ports from `COM10` onwards are
+/// often written with the `\\.\` device namespace prefix, which is added when
+/// opening the port anyway.
+fn normalize_port_name(name: &str) -> String {
+    let name = name.trim();
+    let name = name.strip_prefix(r"\\.\").unwrap_or(name);
+    name.to_lowercase()
+}
+
+/// Create an error for a port that could not be opened
+pub(super) fn open_error(err: serialport::Error, port_name: &str) -> Error {
+    // Windows reports ports which are opened by another program as access denied,
+    // which serialport turns into `NoDevice`. The port was enumerated, so it exists.
+    if cfg!(windows) && err.kind() == serialport::ErrorKind::NoDevice {
+        return Error::SerialPortInUse(port_name.to_string());
+    }
+
+    Error::from(err)
+}
+
+/// Bluetooth serial ports, eg rfcomm bindings on Linux or the virtual ports
+/// created for paired devices on macOS
+fn detect_bluetooth_serial_ports() -> Vec<SerialPortInfo> {
+    #[allow(unused_mut)]
+    let mut ports = available_ports()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|port_info| port_info.port_type == SerialPortType::BluetoothPort)
+        .collect::<Vec<_>>();
+
+    // rfcomm devices have no parent device, so they are not enumerated by udev
+    #[cfg(target_os = "linux")]
+    if let Ok(entries) = std::fs::read_dir("/dev") {
+        for entry in entries.flatten() {
+            let name = entry.file_name();
+            let name = name.to_string_lossy();
+            if name.starts_with("rfcomm") {
+                ports.push(SerialPortInfo {
+                    port_name: format!("/dev/{}", name),
+                    port_type: SerialPortType::BluetoothPort,
+                });
+            }
+        }
+    }
+
+    ports
 }
 
 /// serialport's autodetect doesn't provide any port information when using musl
@@ -109,13 +231,16 @@ fn detect_usb_serial_ports() -> Result<Vec<SerialPortInfo>> {
 
             let vid = read_to_string(parent_dev.join("idVendor")).ok()?;
             let pid = read_to_string(parent_dev.join("idProduct")).ok()?;
+            let serial_number = read_to_string(parent_dev.join("serial"))
+                .ok()
+                .map(|s| s.trim().to_string());
 
             Some(SerialPortInfo {
                 port_type: SerialPortType::UsbPort(UsbPortInfo {
                     vid: u16::from_str_radix(vid.trim(), 16).ok()?,
                     pid: u16::from_str_radix(pid.trim(), 16).ok()?,
                     product: interface,
-                    serial_number: None,
+                    serial_number,
                     manufacturer: None,
                 }),
                 port_name: format!("/dev/{}", path.file_name()?.to_str()?),
@@ -138,28 +263,176 @@ fn detect_usb_serial_ports() -> Result<Vec<SerialPortInfo>> {
 }
 
 /// USB UART adapters which are known to be on common dev boards
-const KNOWN_DEVICES: &[UsbDevice] = &[
-    UsbDevice {
-        vid: 0x10c4,
-        pid: 0xea60,
-    }, // Silicon Labs CP210x UART Bridge
-    UsbDevice {
-        vid: 0x1a86,
-        pid: 0x7523,
-    }, // QinHeng Electronics CH340 serial converter
+pub(super) const KNOWN_DEVICES: &[(UsbDevice, &str)] = &[
+    (
+        UsbDevice {
+            vid: 0x10c4,
+            pid: 0xea60,
+            serial_number: None,
+        },
+        "Silicon Labs CP210x UART Bridge",
+    ),
+    (
+        UsbDevice {
+            vid: 0x10c4,
+            pid: 0xea70,
+            serial_number: None,
+        },
+        "Silicon Labs CP2105 Dual UART Bridge",
+    ),
+    (
+        UsbDevice {
+            vid: 0x1a86,
+            pid: 0x7523,
+            serial_number: None,
+        },
+        "QinHeng Electronics CH340 serial converter",
+    ),
+    (
+        UsbDevice {
+            vid: 0x1a86,
+            pid: 0x55d4,
+            serial_number: None,
+        },
+        "QinHeng Electronics CH9102 serial converter",
+    ),
+    (
+        UsbDevice {
+            vid: 0x0403,
+            pid: 0x6001,
+            serial_number: None,
+        },
+        "FTDI FT232R UART",
+    ),
+    (
+        UsbDevice {
+            vid: 0x0403,
+            pid: 0x6010,
+            serial_number: None,
+        },
+        "FTDI FT2232 dual UART",
+    ),
+    (
+        UsbDevice {
+            vid: 0x0403,
+            pid: 0x6015,
+            serial_number: None,
+        },
+        "FTDI FT231X UART",
+    ),
+    (
+        UsbDevice {
+            vid: 0x303a,
+            pid: 0x1001,
+            serial_number: None,
+        },
+        "Espressif USB JTAG/serial debug unit",
+    ),
+    (
+        UsbDevice {
+            vid: 0x303a,
+            pid: 0x0002,
+            serial_number: None,
+        },
+        "Espressif ESP32-S2 native USB CDC",
+    ),
 ];
 
+/// Find the single port of an adapter which was remembered with its serial
+/// number
+fn find_remembered_port(ports: &[SerialPortInfo], config: &Config) -> Option<SerialPortInfo> {
+    let mut remembered = ports.iter().filter(|port| match &port.port_type {
+        SerialPortType::UsbPort(info) => config
+            .usb_device
+            .iter()
+            .any(|device| device.serial_number.is_some() && device.matches(info)),
+        _ => false,
+    });
+
+    match (remembered.next(), remembered.next()) {
+        (Some(port), None) => Some(port.clone()),
+        _ => None,
+    }
+}
+
+/// Names of all USB serial ports whose device is configured or known to be on
+/// common dev boards
+pub fn known_device_ports(config: &Config) -> Vec<String> {
+    detect_usb_serial_ports()
+        .unwrap_or_default()
+        .into_iter()
+        .filter(|port| match &port.port_type {
+            SerialPortType::UsbPort(info) => is_known_device(config, info),
+            _ => false,
+        })
+        .map(|port| port.port_name)
+        .collect()
+}
+
+/// Whether the USB device is configured or known to be on common dev boards
+/// Whether the device is already in the config, and shouldn't be remembered again
+fn is_remembered(config: &Config, info: &UsbPortInfo) -> bool {
+    config.usb_device.iter().any(|device| device.matches(info))
+}
+
+fn is_known_device(config: &Config, info: &UsbPortInfo) -> bool {
+    config
+        .usb_device
+        .iter()
+        .chain(KNOWN_DEVICES.iter().map(|(device, _)| device))
+        .any(|dev| dev.matches(info))
+}
+
+/// Describe a port for the selection, eg `/dev/ttyUSB0 - 10c4:ea60 - CP2102 USB to UART`
+fn describe_port(port_info: &SerialPortInfo, highlight: bool) -> String {
+    let name = if highlight {
+        port_info.port_name.as_str().bold()
+    } else {
+        port_info.port_name.as_str().reset()
+    };
+
+    match &port_info.port_type {
+        SerialPortType::UsbPort(info) => {
+            let mut description = format!("{} - {:04x}:{:04x}", name, info.vid, info.pid);
+            if let Some(product) = &info.product {
+                description.push_str(" - ");
+                description.push_str(product);
+            }
+            description
+        }
+        _ => format!("{}", name),
+    }
+}
+
 fn select_serial_port(
     ports: Vec<SerialPortInfo>,
     config: &Config,
+    opts: &ConnectOpts,
 ) -> Result<(SerialPortInfo, bool), Error> {
-    let device_matches = |info| {
-        config
-            .usb_device
+    let device_matches = |info| is_known_device(config, info);
+
+    if opts.no_interactive {
+        // Without prompting, only a single known device can be picked
+        let known = ports
             .iter()
-            .chain(KNOWN_DEVICES.iter())
-            .any(|dev| dev.matches(info))
-    };
+            .filter(|port| match &port.port_type {
+                SerialPortType::UsbPort(info) => device_matches(info),
+                _ => false,
+            })
+            .collect::<Vec<_>>();
+
+        return match known.as_slice() {
+            [port] => Ok(((*port).clone(), true)),
+            [] => Err(Error::NoSerial),
+            known => Err(Error::MultipleSerialPorts(
+                known
+                    .iter()
+                    .map(|port| port.port_name.as_str())
+                    .collect::<Vec<_>>()
+                    .join(", "),
+            )),
+        };
+    }
 
     if ports.len() > 1 {
         // Multiple serial ports detected
@@ -170,20 +443,12 @@ fn select_serial_port(
 
         let port_names = ports
             .iter()
-            .map(|port_info| match &port_info.port_type {
-                SerialPortType::UsbPort(info) => {
-                    let formatted = if device_matches(info) {
-                        port_info.port_name.as_str().bold()
-                    } else {
-                        port_info.port_name.as_str().reset()
-                    };
-                    if let Some(product) = &info.product {
-                        format!("{} - {}", formatted, product)
-                    } else {
-                        format!("{}", formatted)
-                    }
-                }
-                _ => port_info.port_name.clone(),
+            .map(|port_info| {
+                let known = match &port_info.port_type {
+                    SerialPortType::UsbPort(info) => device_matches(info),
+                    _ => false,
+                };
+                describe_port(port_info, known)
             })
             .collect::<Vec<_>>();
         let index = Select::with_theme(&ColorfulTheme::default())
@@ -194,9 +459,7 @@ fn select_serial_port(
 
         match ports.get(index) {
             Some(
-                port_info
-                @
-                SerialPortInfo {
+                port_info @ SerialPortInfo {
                     port_type: SerialPortType::UsbPort(usb_info),
                     ..
                 },
@@ -206,26 +469,18 @@ fn select_serial_port(
         }
     } else if let [port] = ports.as_slice() {
         // Single serial port detected
-        let port_name = port.port_name.clone();
-        let port_info = match &port.port_type {
-            SerialPortType::UsbPort(info) => info,
-            _ => unreachable!(),
+        let known = match &port.port_type {
+            SerialPortType::UsbPort(info) => device_matches(info),
+            _ => false,
         };
-
-        if device_matches(port_info) {
+        if known && !opts.confirm_port {
             Ok((port.clone(), true))
         } else if Confirm::with_theme(&ColorfulTheme::default())
-            .with_prompt({
-                if let Some(product) = &port_info.product {
-                    format!("Use serial port '{}' - {}?", port_name, product)
-                } else {
-                    format!("Use serial port '{}'?", port_name)
-                }
-            })
+            .with_prompt(format!("Use serial port {}?", describe_port(port, false)))
             .interact_opt()?
             .ok_or(Error::Canceled)?
         {
-            Ok((port.clone(), false))
+            Ok((port.clone(), known))
         } else {
             Err(Error::NoSerial)
         }
@@ -234,3 +489,37 @@ fn select_serial_port(
         Err(Error::NoSerial)
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn windows_port_names_are_normalized() {
+        assert_eq!(normalize_port_name(r"\\.\COM10"), "com10");
+        assert_eq!(normalize_port_name("COM3 "), "com3");
+        assert_eq!(normalize_port_name("/dev/ttyUSB0"), "/dev/ttyusb0");
+    }
+
+    #[test]
+    fn known_adapters_are_not_remembered_until_configured() {
+        let cp2102 = UsbPortInfo {
+            vid: 0x10c4,
+            pid: 0xea60,
+            serial_number: Some("0001".into()),
+            manufacturer: None,
+            product: None,
+        };
+
+        let mut config = Config::default();
+        assert!(is_known_device(&config, &cp2102));
+        assert!(!is_remembered(&config, &cp2102));
+
+        config.usb_device.push(UsbDevice {
+            vid: cp2102.vid,
+            pid: cp2102.pid,
+            serial_number: cp2102.serial_number.clone(),
+        });
+        assert!(is_remembered(&config, &cp2102));
+    }
+}
@@ -0,0 +1,436 @@
+//! Keeping the device connected and flashing it on request
+//!
+//! The server listens on a local TCP port and handles one client at a time,
+//! which sends a single request line:
+//!
+//! - `flash <elf>` flashes the ELF image, the path is relative to the
+//!   directory the server was started in
+//! - `reset` resets the device
+//! - `monitor` streams the device output to the client, and the data written
+//!   by the client to the device, until the client disconnects or the next
+//!   client connects
+//!
+//! The server answers with `ok` or `error: <message>` on a line of its own.
+//! Other clients wait until the current request is done, so they never use the
+//! serial port at the same time.
+//!
+//! The connection to the device is kept between requests, the server only
+//! syncs with the bootloader again when the device was reset since. A failed
+//! request drops the connection, the next one connects from scratch.
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::{TcpListener, TcpStream},
+    path::{Path, PathBuf},
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::Parser;
+use log::{info, warn};
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use super::{
+    config::Config, connect, input::read_input, load_stub, monitor::DEFAULT_MONITOR_SPEED,
+    ConnectOpts,
+};
+use crate::{
+    reset::{is_usb_serial_jtag, HardReset, ResetAfterOperation},
+    transport::Transport,
+    Flasher,
+};
+
+/// How long a client has to send its request after connecting
+const REQUEST_TIMEOUT: Duration = Duration::from_secs(10);
+/// How long writing the device output to a monitor client may take, before the
+/// client is taken for one which stopped reading and dropped
+const CLIENT_WRITE_TIMEOUT: Duration = Duration::from_secs(1);
+const POLL_INTERVAL: Duration = Duration::from_millis(20);
+const MAX_REQUEST_LEN: usize = 4096;
+
+#[derive(Parser)]
+pub struct ServeOpts {
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+    /// Local TCP port to accept requests on
+    #[clap(long, default_value_t = 9999)]
+    pub port: u16,
+    /// Baud rate of the device output streamed to `monitor` clients, defaults
+    /// to 115200
+    #[clap(long)]
+    pub monitor_speed: Option<u32>,
+}
+
+#[derive(Debug, PartialEq, Eq)]
+enum Request {
+    Flash(PathBuf),
+    Reset,
+    Monitor,
+}
+
+impl FromStr for Request {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let s = s.trim();
+        let (name, argument) = match s.split_once(' ') {
+            Some((name, argument)) => (name, Some(argument.trim())),
+            None => (s, None),
+        };
+
+        match (name, argument) {
+            ("flash", Some(elf)) => Ok(Request::Flash(PathBuf::from(elf))),
+            ("flash", None) => Err("`flash` takes the path of an ELF image".to_string()),
+            ("reset", None) => Ok(Request::Reset),
+            ("monitor", None) => Ok(Request::Monitor),
+            _ => Err(format!("unknown request `{}`", s)),
+        }
+    }
+}
+
+struct Server<'a> {
+    opts: &'a ServeOpts,
+    config: &'a Config,
+    listener: TcpListener,
+    /// The connected device, `None` when the connection was lost to a failed
+    /// request
+    flasher: Option<Flasher>,
+    /// Whether the device still runs the bootloader the flasher synced with
+    synced: bool,
+}
+
+impl Server<'_> {
+    /// Take the connected device, connecting to it again if the connection
+    /// was lost
+    ///
+    /// The device is put back once the request succeeded, so a failed request
+    /// drops the connection and the port.
+    fn take_flasher(&mut self) -> Result<Flasher> {
+        match self.flasher.take() {
+            Some(flasher) => Ok(flasher),
+            None => {
+                let flasher = connect(&self.opts.connect_opts, self.config)?;
+                self.synced = true;
+                Ok(flasher)
+            }
+        }
+    }
+
+    fn flash(
+        &mut self,
+        elf: &Path,
+        load: &mut impl FnMut(&mut Flasher, &[u8]) -> Result<()>,
+    ) -> Result<()> {
+        let elf_data = read_input(elf)?;
+        let mut flasher = self.take_flasher()?;
+        let connect_opts = &self.opts.connect_opts;
+        if !self.synced {
+            let speed = connect_opts.speed.or(self.config.connection.speed);
+            flasher.reconnect(speed, connect_opts.connect_attempts)?;
+            load_stub(&mut flasher, connect_opts)?;
+        }
+        flasher.board_info()?;
+        load(&mut flasher, &elf_data)?;
+
+        // Any reset leaves the bootloader the flasher synced with
+        let (_, after) = flasher.connection().reset_behavior();
+        self.synced = after == ResetAfterOperation::NoReset;
+        self.flasher = Some(flasher);
+
+        Ok(())
+    }
+
+    fn reset(&mut self) -> Result<()> {
+        let mut flasher = self.take_flasher()?;
+        let connection = flasher.connection();
+        let usb_serial_jtag = is_usb_serial_jtag(connection.port_type());
+        connection.reset_with(&HardReset { usb_serial_jtag })?;
+        self.synced = false;
+        self.flasher = Some(flasher);
+
+        Ok(())
+    }
+
+    /// Pass data between the device and the client, returns the next client if
+    /// one connected in the meantime
+    fn monitor(&mut self, client: &mut TcpStream) -> Result<Option<TcpStream>> {
+        let mut flasher = self.take_flasher()?;
+        let speed = self
+            .opts
+            .monitor_speed
+            .or(self.config.monitor.speed)
+            .unwrap_or(DEFAULT_MONITOR_SPEED);
+        let connection = flasher.connection();
+        connection.set_baud(speed)?;
+        self.synced = false;
+
+        let port = connection.transport_mut();
+        let timeout = port.timeout();
+        port.set_timeout(POLL_INTERVAL)?;
+        // Reads of the client return right away, a client which doesn't read is
+        // dropped instead of blocking the server
+        client
+            .set_read_timeout(Some(Duration::from_millis(1)))
+            .into_diagnostic()?;
+        client
+            .set_write_timeout(Some(CLIENT_WRITE_TIMEOUT))
+            .into_diagnostic()?;
+        self.listener.set_nonblocking(true).into_diagnostic()?;
+
+        let result = pass_through(port, client, &self.listener);
+
+        self.listener.set_nonblocking(false).into_diagnostic()?;
+        port.set_timeout(timeout)?;
+        self.flasher = Some(flasher);
+        result
+    }
+
+    fn handle(
+        &mut self,
+        client: &mut TcpStream,
+        load: &mut impl FnMut(&mut Flasher, &[u8]) -> Result<()>,
+    ) -> Result<Option<TcpStream>> {
+        // Accepted sockets may inherit the non-blocking mode of the listener
+        client.set_nonblocking(false).into_diagnostic()?;
+        client
+            .set_read_timeout(Some(REQUEST_TIMEOUT))
+            .into_diagnostic()?;
+        let line = read_request(client)
+            .into_diagnostic()
+            .wrap_err("Failed to read the request")?;
+        client.set_read_timeout(None).into_diagnostic()?;
+
+        let request = match Request::from_str(&line) {
+            Ok(request) => request,
+            Err(err) => {
+                writeln!(client, "error: {}", err).into_diagnostic()?;
+                return Ok(None);
+            }
+        };
+        info!("Request: {}", line.trim());
+
+        let result = match request {
+            Request::Flash(elf) => self.flash(&elf, load),
+            Request::Reset => self.reset(),
+            Request::Monitor => {
+                writeln!(client, "ok").into_diagnostic()?;
+                return self.monitor(client);
+            }
+        };
+        match result {
+            Ok(()) => writeln!(client, "ok").into_diagnostic()?,
+            Err(err) => {
+                warn!("Request failed: {:?}", err);
+                writeln!(client, "error: {}", err).into_diagnostic()?;
+            }
+        }
+
+        Ok(None)
+    }
+}
+
+/// Read the request line of a client
+///
+/// The line is read byte by byte, a buffered reader could take data following
+/// it which belongs to the device in `monitor` requests.
+fn read_request(client: &mut impl Read) -> io::Result<String> {
+    let mut line = Vec::new();
+    let mut byte = [0];
+    while line.len() < MAX_REQUEST_LEN && !line.ends_with(b"\n") {
+        match client.read(&mut byte) {
+            Ok(0) => break,
+            Ok(_) => line.push(byte[0]),
+            Err(err) if err.kind() == ErrorKind::Interrupted => {}
+            Err(err) => return Err(err),
+        }
+    }
+
+    String::from_utf8(line).map_err(|err| io::Error::new(ErrorKind::InvalidData, err))
+}
+
+/// Copy the device output to the client and the client input to the device,
+/// until the client disconnects or another one connects
+fn pass_through(
+    port: &mut dyn Transport,
+    client: &mut TcpStream,
+    listener: &TcpListener,
+) -> Result<Option<TcpStream>> {
+    let mut buf = [0; 1024];
+    loop {
+        match listener.accept() {
+            Ok((next, _)) => return Ok(Some(next)),
+            Err(err) if err.kind() == ErrorKind::WouldBlock => {}
+            Err(err) => return Err(err).into_diagnostic(),
+        }
+
+        match port.read(&mut buf) {
+            Ok(count) => {
+                if client.write_all(&buf[..count]).is_err() {
+                    return Ok(None);
+                }
+            }
+            Err(err) if err.kind() == ErrorKind::TimedOut => {}
+            Err(err) => return Err(err).into_diagnostic(),
+        }
+
+        match client.read(&mut buf) {
+            Ok(0) => return Ok(None),
+            Ok(count) => port.write_all(&buf[..count]).into_diagnostic()?,
+            Err(err) if matches!(err.kind(), ErrorKind::WouldBlock | ErrorKind::TimedOut) => {}
+            Err(_) => return Ok(None),
+        }
+    }
+}
+
+/// Keep the serial port open and handle the requests sent to the local TCP
+/// port, see the [module documentation](self)
+///
+/// `load` writes the image of flash requests to the device.
+pub fn serve(
+    opts: ServeOpts,
+    config: Config,
+    mut load: impl FnMut(&mut Flasher, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let listener = TcpListener::bind(("127.0.0.1", opts.port))
+        .into_diagnostic()
+        .wrap_err_with(|| format!("Failed to listen on port {}", opts.port))?;
+    let flasher = connect(&opts.connect_opts, &config)?;
+    let mut server = Server {
+        opts: &opts,
+        config: &config,
+        listener,
+        flasher: Some(flasher),
+        synced: true,
+    };
+    info!("Waiting for requests on 127.0.0.1:{}", opts.port);
+
+    let mut next = None;
+    loop {
+        let mut client = match next.take() {
+            Some(client) => client,
+            None => server.listener.accept().into_diagnostic()?.0,
+        };
+
+        // A failed request only ends that request, the server keeps running
+        match server.handle(&mut client, &mut load) {
+            Ok(client) => next = client,
+            Err(err) => warn!("Request failed: {:?}", err),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::{
+        fs,
+        io::{BufRead, BufReader},
+        thread,
+    };
+
+    use serialport::SerialPortType;
+
+    use super::*;
+    use crate::{command::CommandType, transport::mock::MockDevice, ConnectSettings};
+
+    /// Send `line` from a client and handle it, returns the response
+    fn request(
+        server: &mut Server,
+        load: &mut impl FnMut(&mut Flasher, &[u8]) -> Result<()>,
+        line: &str,
+    ) -> String {
+        let addr = server.listener.local_addr().unwrap();
+        let line = line.to_string();
+        let client = thread::spawn(move || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            writeln!(client, "{}", line).unwrap();
+            let mut response = String::new();
+            BufReader::new(client).read_line(&mut response).unwrap();
+            response
+        });
+        let (mut stream, _) = server.listener.accept().unwrap();
+        assert!(server.handle(&mut stream, load).unwrap().is_none());
+        drop(stream);
+        client.join().unwrap()
+    }
+
+    #[test]
+    fn handles_requests() {
+        let opts = ServeOpts::parse_from(["serve", "--port", "0"]);
+        let config = Config::default();
+        let device = MockDevice::new();
+        let flasher = Flasher::connect_transport(
+            Box::new(device.clone()),
+            SerialPortType::Unknown,
+            None,
+            None,
+            None,
+            ConnectSettings::default(),
+        )
+        .unwrap();
+        let mut server = Server {
+            opts: &opts,
+            config: &config,
+            listener: TcpListener::bind(("127.0.0.1", 0)).unwrap(),
+            flasher: Some(flasher),
+            synced: true,
+        };
+
+        let image = Path::new(env!("CARGO_MANIFEST_DIR")).join("tests/data/esp32.bin");
+        let data = fs::read(&image).unwrap();
+        let mut load = |flasher: &mut Flasher, data: &[u8]| {
+            flasher.write_bin_to_flash(0x10000, data, None)?;
+            Ok(())
+        };
+        let syncs = |device: &MockDevice| {
+            let op = CommandType::Sync as u8;
+            device.commands().iter().filter(|&&c| c == op).count()
+        };
+        let flash = format!("flash {}", image.display());
+
+        // Flashing resets the device, so the second flash has to sync again
+        assert_eq!(request(&mut server, &mut load, &flash), "ok\n");
+        assert!(device.is_running_app());
+        assert_eq!(device.flash(0x10000, data.len()), data);
+        let connected = syncs(&device);
+        device.set_flash(0x10000, &[0; 16]);
+        assert_eq!(request(&mut server, &mut load, &flash), "ok\n");
+        assert_eq!(device.flash(0x10000, data.len()), data);
+        assert_eq!(syncs(&device), connected + 1);
+
+        assert_eq!(request(&mut server, &mut load, "reset"), "ok\n");
+        assert!(device.is_running_app());
+        assert!(request(&mut server, &mut load, "erase").starts_with("error: "));
+
+        // The monitor passes the application output on until the client leaves,
+        // and the client input to the application, even if it's sent along with
+        // the request
+        device.print(b"Hello, world!\n");
+        let addr = server.listener.local_addr().unwrap();
+        let client = thread::spawn(move || {
+            let mut client = TcpStream::connect(addr).unwrap();
+            client.write_all(b"monitor\nhelp\n").unwrap();
+            let mut reader = BufReader::new(client);
+            let mut lines = vec![String::new(), String::new()];
+            for line in &mut lines {
+                reader.read_line(line).unwrap();
+            }
+            lines
+        });
+        let (mut stream, _) = server.listener.accept().unwrap();
+        assert!(server.handle(&mut stream, &mut load).unwrap().is_none());
+        assert_eq!(client.join().unwrap(), ["ok\n", "Hello, world!\n"]);
+        assert_eq!(device.input(), b"help\n");
+    }
+
+    #[test]
+    fn requests_are_parsed() {
+        assert_eq!(
+            Request::from_str("flash target/app.elf\n"),
+            Ok(Request::Flash(PathBuf::from("target/app.elf")))
+        );
+        assert_eq!(Request::from_str("reset\n"), Ok(Request::Reset));
+        assert_eq!(Request::from_str("monitor"), Ok(Request::Monitor));
+        assert!(Request::from_str("flash").is_err());
+        assert!(Request::from_str("reset now").is_err());
+    }
+}
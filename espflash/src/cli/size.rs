@@ -0,0 +1,225 @@
+//! Reporting the memory used by ELF images, like `idf.py size`
+
+use std::{ops::Range, path::PathBuf};
+
+use clap::Parser;
+use miette::Result;
+use serde::Serialize;
+
+use super::{
+    config::Config, image_chip, input::read_input, load_flash_files, print_json, FlashConfigOpts,
+    OutputFormat,
+};
+use crate::{
+    size::{section_sizes, used, Memory, SectionSize},
+    Chip, FirmwareImage, FlashSize, ImageFormatId, ImageSettings,
+};
+
+#[derive(Parser)]
+pub struct SizeOpts {
+    /// ELF image to analyze
+    image: PathBuf,
+    /// Chip the image is built for, defaults to the one given in the image
+    #[clap(long)]
+    chip: Option<Chip>,
+    /// Image format to build
+    #[clap(long)]
+    format: Option<ImageFormatId>,
+    /// Partition table to look the app partition up in, defaults to the one of
+    /// the chip
+    #[clap(long)]
+    partition_table: Option<PathBuf>,
+    /// Name of the app partition the application is written to, eg `ota_1`
+    #[clap(long)]
+    target_app_partition: Option<String>,
+    #[clap(flatten)]
+    flash_config_opts: FlashConfigOpts,
+}
+
+#[derive(Serialize)]
+struct MemoryUsage {
+    memory: Memory,
+    used: u32,
+    total: Option<u32>,
+}
+
+#[derive(Serialize)]
+struct AppPartition {
+    name: String,
+    size: u32,
+    free: u32,
+}
+
+#[derive(Serialize)]
+struct SizeReport {
+    chip: String,
+    sections: Vec<SectionSize>,
+    usage: Vec<MemoryUsage>,
+    image_size: u32,
+    app_partition: Option<AppPartition>,
+}
+
+/// Print the sections of the image and the memory they use, then build the
+/// image like for flashing to check that it fits into its app partition
+pub fn size(mut opts: SizeOpts, config: Config, output_format: OutputFormat) -> Result<()> {
+    opts.flash_config_opts.apply_config(&config);
+    let partition_table = opts
+        .partition_table
+        .clone()
+        .or_else(|| config.flash.partition_table.clone());
+    let image_format = opts.format.or(config.flash.format);
+
+    let elf_data = read_input(&opts.image)?;
+    let chip = image_chip(opts.chip, &elf_data)?;
+    let image = FirmwareImage::from_data(&elf_data)?;
+    image.check_chip(chip)?;
+
+    let sections = section_sizes(&image, chip);
+    let ram_size = |range: Range<u32>| range.end - range.start;
+    let usage = vec![
+        MemoryUsage {
+            memory: Memory::Iram,
+            used: used(&sections, Memory::Iram),
+            total: Some(ram_size(chip.iram_range())),
+        },
+        MemoryUsage {
+            memory: Memory::Dram,
+            used: used(&sections, Memory::Dram),
+            total: Some(ram_size(chip.dram_range())),
+        },
+        MemoryUsage {
+            memory: Memory::FlashCode,
+            used: used(&sections, Memory::FlashCode),
+            total: None,
+        },
+        MemoryUsage {
+            memory: Memory::FlashData,
+            used: used(&sections, Memory::FlashData),
+            total: None,
+        },
+        MemoryUsage {
+            memory: Memory::Other,
+            used: used(&sections, Memory::Other),
+            total: None,
+        },
+    ];
+
+    if output_format == OutputFormat::Text {
+        println!("{:<24} {:>10} {:>8}  Memory", "Section", "Address", "Size");
+        for section in &sections {
+            let bss = if section.bss {
+                " (zero initialized)"
+            } else {
+                ""
+            };
+            println!(
+                "{:<24} {:#010x} {:>8}  {}{}",
+                section.name, section.addr, section.size, section.memory, bss
+            );
+        }
+
+        println!();
+        println!("{:<12} {:>8} {:>8}", "Memory", "Used", "Total");
+        for usage in &usage {
+            match usage.total {
+                Some(total) => println!(
+                    "{:<12} {:>8} {:>8} ({:.1}%)",
+                    usage.memory.to_string(),
+                    usage.used,
+                    total,
+                    percent(usage.used, total)
+                ),
+                None => println!("{:<12} {:>8}", usage.memory.to_string(), usage.used),
+            }
+        }
+        println!();
+    }
+
+    // Building the image fails if it doesn't fit into the app partition or the
+    // flash
+    let (_, partition_table) = load_flash_files(
+        None,
+        partition_table.as_deref(),
+        opts.flash_config_opts.partition_table_offset,
+    )?;
+    let settings = ImageSettings {
+        app_partition: opts.target_app_partition.clone(),
+        flash_settings: opts.flash_config_opts.flash_settings(),
+        app_descriptor: opts.flash_config_opts.app_descriptor(),
+        flash_layout: opts.flash_config_opts.flash_layout(),
+        ..ImageSettings::default()
+    };
+    let flash_size = opts
+        .flash_config_opts
+        .flash_size
+        .unwrap_or(FlashSize::Flash4Mb);
+    let plan = settings.plan(
+        chip,
+        flash_size,
+        None,
+        &elf_data,
+        None,
+        partition_table,
+        image_format,
+    )?;
+
+    let name = opts
+        .target_app_partition
+        .as_deref()
+        .or(image.metadata.app_partition.as_deref());
+    let partition = plan
+        .partition_table
+        .as_ref()
+        .and_then(|table| table.find_app(name));
+    let image_size = match partition {
+        Some(partition) => plan
+            .segments
+            .iter()
+            .find(|(addr, _)| *addr == partition.offset())
+            .map(|(_, data)| data.len() as u32)
+            .unwrap_or(0),
+        None => plan
+            .segments
+            .iter()
+            .map(|(_, data)| data.len() as u32)
+            .sum(),
+    };
+    let app_partition = partition.map(|partition| AppPartition {
+        name: partition.name().to_string(),
+        size: partition.size(),
+        free: partition.size() - image_size,
+    });
+
+    if output_format == OutputFormat::Json {
+        return print_json(&SizeReport {
+            chip: chip.to_string(),
+            sections,
+            usage,
+            image_size,
+            app_partition,
+        });
+    }
+
+    match app_partition {
+        Some(partition) => println!(
+            "The image of {} bytes uses {:.1}% of the app partition `{}` of {} bytes, {} bytes are free",
+            image_size,
+            percent(image_size, partition.size),
+            partition.name,
+            partition.size,
+            partition.free
+        ),
+        None => println!(
+            "The image of {} bytes uses {:.1}% of {} of flash",
+            image_size,
+            percent(image_size, flash_size.size()),
+            flash_size
+        ),
+    }
+
+    Ok(())
+}
+
+fn percent(used: u32, total: u32) -> f64 {
+    used as f64 * 100.0 / total as f64
+}
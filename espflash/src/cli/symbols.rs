@@ -0,0 +1,95 @@
+//! Resolving code addresses printed by the device
+//!
+//! Panics and backtraces only contain raw addresses, these are looked up in
+//! the debug information of the flashed ELF image to show the function, file
+//! and line they belong to.
+
+use std::{borrow::Cow, ops::Range};
+
+use addr2line::{
+    gimli::{Dwarf, EndianSlice, RunTimeEndian},
+    Context,
+};
+use object::{Endianness, Object, ObjectSection, SectionKind, SymbolMap, SymbolMapName};
+use regex::Regex;
+
+pub(crate) struct Symbols<'a> {
+    context: Context<EndianSlice<'a, RunTimeEndian>>,
+    symbols: SymbolMap<SymbolMapName<'a>>,
+    text: Vec<Range<u64>>,
+    address_re: Regex,
+}
+
+impl<'a> Symbols<'a> {
+    pub fn try_from(elf: &'a [u8]) -> Option<Self> {
+        let object = object::File::parse(elf).ok()?;
+        let endian = match object.endianness() {
+            Endianness::Little => RunTimeEndian::Little,
+            Endianness::Big => RunTimeEndian::Big,
+        };
+        let dwarf = Dwarf::load(|id| {
+            let data = object
+                .section_by_name(id.name())
+                .and_then(|section| section.data().ok())
+                .unwrap_or(&[]);
+            Ok::<_, ()>(EndianSlice::new(data, endian))
+        })
+        .ok()?;
+        let context = Context::from_dwarf(dwarf).ok()?;
+
+        // Only addresses within the code are resolved, the stack pointers and data
+        // addresses in a backtrace would otherwise resolve to the closest symbol
+        let text = object
+            .sections()
+            .filter(|section| section.kind() == SectionKind::Text)
+            .map(|section| section.address()..section.address() + section.size())
+            .collect();
+
+        Some(Symbols {
+            context,
+            symbols: object.symbol_map(),
+            text,
+            address_re: Regex::new(r"0x[0-9a-fA-F]{8}\b").unwrap(),
+        })
+    }
+
+    /// Describe the function and location of `addr`, if it's within the code
+    pub fn resolve(&self, addr: u64) -> Option<String> {
+        if !self.text.iter().any(|range| range.contains(&addr)) {
+            return None;
+        }
+
+        let frame = self
+            .context
+            .find_frames(addr)
+            .ok()
+            .and_then(|mut frames| frames.next().ok().flatten());
+
+        let name = frame
+            .as_ref()
+            .and_then(|frame| frame.function.as_ref())
+            .and_then(|function| function.demangle().ok())
+            .or_else(|| {
+                self.symbols
+                    .get(addr)
+                    .map(|symbol| addr2line::demangle_auto(Cow::Borrowed(symbol.name()), None))
+            })?;
+
+        let location = frame.as_ref().and_then(|frame| frame.location.as_ref());
+        match location.and_then(|location| Some((location.file?, location.line?))) {
+            Some((file, line)) => Some(format!("{} at {}:{}", name, file, line)),
+            None => Some(name.into_owned()),
+        }
+    }
+
+    /// Resolve all code addresses in `line`
+    pub fn resolve_line(&self, line: &str) -> Vec<(u64, String)> {
+        self.address_re
+            .find_iter(line)
+            .filter_map(|m| {
+                let addr = u64::from_str_radix(&m.as_str()[2..], 16).ok()?;
+                Some((addr, self.resolve(addr)?))
+            })
+            .collect()
+    }
+}
@@ -0,0 +1,207 @@
+//! Hardware-in-the-loop test runner
+//!
+//! Flashes an image, lets the device boot and watches its output for a pattern
+//! marking the test run as passed or failed.
+
+use std::{
+    io::{stdout, ErrorKind, Write},
+    path::PathBuf,
+    str::FromStr,
+    time::Duration,
+};
+
+use clap::Parser;
+use log::info;
+use miette::Result;
+
+use super::{
+    config::Config, connect, deadline::deadline, flash_elf_image, input::read_input,
+    monitor::DEFAULT_MONITOR_SPEED, progress::EspflashProgress, ConnectOpts,
+};
+use crate::{error::Error, transport::Transport, ImageFormatId};
+
+#[derive(Parser)]
+pub struct TestOpts {
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+    /// ELF image containing the tests
+    #[clap(long)]
+    pub elf: PathBuf,
+    /// Device output which marks the test run as passed
+    #[clap(long)]
+    pub expect: String,
+    /// Device output which marks the test run as failed
+    #[clap(long)]
+    pub fail: Option<String>,
+    /// Load the application to RAM instead of Flash
+    #[clap(long)]
+    pub ram: bool,
+    /// Path to a binary (.bin) bootloader file
+    #[clap(long)]
+    pub bootloader: Option<PathBuf>,
+    /// Path to a CSV or binary file containing partition table
+    #[clap(long)]
+    pub partition_table: Option<PathBuf>,
+    /// Image format to flash
+    #[clap(long)]
+    pub format: Option<String>,
+    /// Baud rate the device prints its output at, defaults to 115200
+    #[clap(long)]
+    pub monitor_speed: Option<u32>,
+}
+
+/// Result of matching the device output against the test patterns
+#[derive(Debug, PartialEq, Eq)]
+pub enum TestOutcome {
+    Passed,
+    Failed,
+}
+
+/// Searches a stream of output for the pass and fail patterns
+///
+/// Output arrives from the serial port in arbitrarily sized chunks, so the tail
+/// of the previous chunk is kept around to find patterns spanning two reads.
+struct OutputMatcher<'a> {
+    expect: &'a [u8],
+    fail: Option<&'a [u8]>,
+    window: Vec<u8>,
+}
+
+impl<'a> OutputMatcher<'a> {
+    fn new(expect: &'a str, fail: Option<&'a str>) -> Self {
+        OutputMatcher {
+            expect: expect.as_bytes(),
+            fail: fail.map(str::as_bytes),
+            window: Vec::new(),
+        }
+    }
+
+    fn feed(&mut self, data: &[u8]) -> Option<TestOutcome> {
+        self.window.extend_from_slice(data);
+
+        // A failure takes precedence if both patterns show up in the same chunk.
+        let outcome = if self.fail.map_or(false, |fail| contains(&self.window, fail)) {
+            Some(TestOutcome::Failed)
+        } else if contains(&self.window, self.expect) {
+            Some(TestOutcome::Passed)
+        } else {
+            None
+        };
+
+        let keep = self
+            .expect
+            .len()
+            .max(self.fail.map_or(0, <[u8]>::len))
+            .saturating_sub(1);
+        let start = self.window.len().saturating_sub(keep);
+        self.window.drain(..start);
+
+        outcome
+    }
+}
+
+fn contains(haystack: &[u8], needle: &[u8]) -> bool {
+    needle.is_empty()
+        || haystack
+            .windows(needle.len())
+            .any(|window| window == needle)
+}
+
+/// Echo the device output until either the pass or the fail pattern is seen
+pub fn wait_for_pattern(
+    serial: &mut dyn Transport,
+    speed: u32,
+    expect: &str,
+    fail: Option<&str>,
+) -> Result<TestOutcome, Error> {
+    let mut matcher = OutputMatcher::new(expect, fail);
+    let mut buff = [0; 128];
+
+    serial.set_baud_rate(speed)?;
+    serial.set_timeout(Duration::from_millis(5))?;
+
+    let stdout = stdout();
+    let mut stdout = stdout.lock();
+    loop {
+        let read_count = match serial.read(&mut buff) {
+            Ok(count) => Ok(count),
+            Err(e) if e.kind() == ErrorKind::TimedOut => Ok(0),
+            err => err,
+        }?;
+        if read_count > 0 {
+            let data = &buff[0..read_count];
+            stdout.write_all(data).ok();
+            stdout.flush()?;
+
+            if let Some(outcome) = matcher.feed(data) {
+                return Ok(outcome);
+            }
+        }
+    }
+}
+
+/// Flash the test image and report whether the device passed the test run
+///
+/// The `--timeout` deadline covers the whole run, if it expires before either
+/// pattern is seen the process exits with
+/// [`TIMEOUT_EXIT_CODE`](super::deadline::TIMEOUT_EXIT_CODE).
+pub fn run_test(opts: TestOpts, config: Config) -> Result<()> {
+    let _deadline = deadline(opts.connect_opts.timeout);
+
+    let elf_data = read_input(&opts.elf)?;
+
+    let mut flasher = connect(&opts.connect_opts, &config)?;
+    flasher.board_info()?;
+
+    if opts.ram {
+        flasher.load_elf_to_ram(&elf_data, Some(&mut EspflashProgress::default()))?;
+    } else {
+        let image_format = opts
+            .format
+            .as_deref()
+            .map(ImageFormatId::from_str)
+            .transpose()?;
+
+        flash_elf_image(
+            &mut flasher,
+            &elf_data,
+            opts.bootloader.as_deref(),
+            opts.partition_table.as_deref(),
+            image_format,
+        )?;
+    }
+
+    info!("Waiting for `{}`...\n", opts.expect);
+    let speed = opts
+        .monitor_speed
+        .or(config.monitor.speed)
+        .unwrap_or(DEFAULT_MONITOR_SPEED);
+    let mut serial = flasher.into_transport();
+    let outcome = wait_for_pattern(&mut *serial, speed, &opts.expect, opts.fail.as_deref())?;
+    println!();
+
+    match outcome {
+        TestOutcome::Passed => {
+            println!("Test passed!");
+            Ok(())
+        }
+        TestOutcome::Failed => Err(Error::TestFailed(opts.fail.unwrap_or_default()).into()),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn matches_patterns_split_across_reads() {
+        let mut matcher = OutputMatcher::new("ALL TESTS PASSED", Some("FAILED"));
+        assert_eq!(matcher.feed(b"running 3 tests\r\nALL TE"), None);
+        assert_eq!(matcher.feed(b"STS PAS"), None);
+        assert_eq!(matcher.feed(b"SED\r\n"), Some(TestOutcome::Passed));
+
+        let mut matcher = OutputMatcher::new("ALL TESTS PASSED", Some("FAILED"));
+        assert_eq!(matcher.feed(b"test foo ... FAI"), None);
+        assert_eq!(matcher.feed(b"LED\r\n"), Some(TestOutcome::Failed));
+    }
+}
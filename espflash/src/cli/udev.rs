@@ -0,0 +1,116 @@
+//! udev rules granting non-root users access to the serial adapters found on
+//! Espressif development boards
+
+use std::{
+    fmt::Write as _,
+    io::Write,
+    process::{Command, Stdio},
+};
+
+use clap::Parser;
+use log::info;
+use miette::{IntoDiagnostic, Result, WrapErr};
+
+use super::serial::KNOWN_DEVICES;
+
+const RULES_PATH: &str = "/etc/udev/rules.d/99-espflash.rules";
+
+#[derive(Parser)]
+pub struct UdevOpts {
+    /// Install the rules using `sudo` instead of printing them
+    #[clap(long)]
+    pub install: bool,
+}
+
+/// Generate udev rules for all known USB serial adapters
+pub fn udev_rules() -> String {
+    let mut rules = String::from(
+        "# udev rules for USB serial adapters used on Espressif development boards\n\
+         # Generated by `espflash install-udev-rules`\n",
+    );
+
+    for (device, description) in KNOWN_DEVICES {
+        write!(
+            &mut rules,
+            "\n# {}\n\
+             SUBSYSTEMS==\"usb\", ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\", \
+             MODE=\"0660\", GROUP=\"plugdev\", TAG+=\"uaccess\"\n",
+            description, device.vid, device.pid
+        )
+        .unwrap();
+    }
+
+    rules
+}
+
+pub fn install_udev_rules(opts: UdevOpts) -> Result<()> {
+    let rules = udev_rules();
+
+    if !opts.install {
+        print!("{}", rules);
+        return Ok(());
+    }
+
+    info!(
+        "Installing udev rules to {}, this requires sudo",
+        RULES_PATH
+    );
+
+    let mut tee = Command::new("sudo")
+        .args(["tee", RULES_PATH])
+        .stdin(Stdio::piped())
+        .stdout(Stdio::null())
+        .spawn()
+        .into_diagnostic()
+        .wrap_err("Failed to run sudo")?;
+    tee.stdin
+        .take()
+        .unwrap()
+        .write_all(rules.as_bytes())
+        .into_diagnostic()?;
+    let status = tee.wait().into_diagnostic()?;
+    if !status.success() {
+        return Err(miette::miette!("Failed to write {}", RULES_PATH));
+    }
+
+    for args in [
+        &["udevadm", "control", "--reload-rules"][..],
+        &["udevadm", "trigger"][..],
+    ] {
+        let status = Command::new("sudo")
+            .args(args)
+            .status()
+            .into_diagnostic()
+            .wrap_err("Failed to run sudo")?;
+        if !status.success() {
+            return Err(miette::miette!("Failed to run `{}`", args.join(" ")));
+        }
+    }
+
+    println!("udev rules installed, reconnect your device for them to take effect.");
+    println!();
+    println!("Desktop sessions are granted access automatically. Otherwise add your user to");
+    println!("the `plugdev` group and log out and back in:");
+    println!();
+    println!("    sudo usermod -a -G plugdev $USER");
+
+    Ok(())
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn rules_cover_known_devices() {
+        let rules = udev_rules();
+
+        for (device, description) in KNOWN_DEVICES {
+            assert!(rules.contains(description));
+            assert!(rules.contains(&format!(
+                "ATTRS{{idVendor}}==\"{:04x}\", ATTRS{{idProduct}}==\"{:04x}\"",
+                device.vid, device.pid
+            )));
+        }
+    }
+}
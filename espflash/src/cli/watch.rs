@@ -0,0 +1,167 @@
+//! Re-flashing the device whenever the ELF image is rebuilt
+
+use std::{
+    fs,
+    path::{Path, PathBuf},
+    thread::sleep,
+    time::{Duration, Instant, SystemTime},
+};
+
+use log::info;
+use miette::{IntoDiagnostic, Result};
+
+use super::{
+    config::Config,
+    connect_serial,
+    deadline::deadline,
+    input::read_input,
+    monitor::{monitor_until, MonitorExit, MonitorOpts},
+    open_serial, ConnectOpts,
+};
+use crate::Flasher;
+
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+const SETTLE_TIME: Duration = Duration::from_millis(300);
+
+/// Detects modifications of a file by polling its metadata
+pub struct FileWatcher {
+    path: PathBuf,
+    last: Option<(SystemTime, u64)>,
+    last_check: Instant,
+}
+
+impl FileWatcher {
+    pub fn new(path: &Path) -> Self {
+        FileWatcher {
+            path: path.to_path_buf(),
+            last: file_state(path),
+            last_check: Instant::now(),
+        }
+    }
+
+    /// Whether the file changed since the last call, the file is checked at most
+    /// once per [`POLL_INTERVAL`] so this is cheap to call in a loop
+    pub fn changed(&mut self) -> bool {
+        if self.last_check.elapsed() < POLL_INTERVAL {
+            return false;
+        }
+        self.last_check = Instant::now();
+
+        let state = file_state(&self.path);
+        if state.is_none() || state == self.last {
+            return false;
+        }
+
+        // Linkers write the output in multiple steps, wait until the file is no
+        // longer being modified before reporting the change.
+        let mut state = state;
+        loop {
+            sleep(SETTLE_TIME);
+            let current = file_state(&self.path);
+            if current == state {
+                break;
+            }
+            state = current;
+        }
+        self.last = state;
+
+        true
+    }
+
+    /// Block until the file changes
+    pub fn wait(&mut self) {
+        while !self.changed() {
+            sleep(POLL_INTERVAL);
+        }
+    }
+}
+
+fn file_state(path: &Path) -> Option<(SystemTime, u64)> {
+    let metadata = fs::metadata(path).ok()?;
+    Some((metadata.modified().ok()?, metadata.len()))
+}
+
+/// Flash the image, and flash it again every time it changes
+///
+/// The serial port is kept open between iterations, if `monitor` is given the
+/// monitor runs with these options while waiting for the next change, the
+/// image can also be flashed again from within the monitor. `load` writes the
+/// image to the device. Errors while flashing are reported without ending the loop, as
+/// the next build may well fix them.
+pub fn watch_and_flash(
+    opts: &ConnectOpts,
+    config: &Config,
+    elf: &Path,
+    monitor: Option<&MonitorOpts>,
+    mut load: impl FnMut(&mut Flasher, &[u8]) -> Result<()>,
+) -> Result<()> {
+    let mut watcher = FileWatcher::new(elf);
+    let (serial, port_type) = open_serial(opts, config)?;
+    let mut serial = Some(serial);
+
+    loop {
+        // The port is lost if flashing failed, in which case it has to be reopened.
+        let port = match serial.take() {
+            Some(port) => port,
+            None => open_serial(opts, config)?.0,
+        };
+
+        let deadline = deadline(opts.timeout);
+        let result =
+            connect_serial(port, port_type.clone(), opts, config).and_then(|mut flasher| {
+                flasher.board_info()?;
+
+                let elf_data = read_input(elf)?;
+                load(&mut flasher, &elf_data)?;
+
+                Ok((flasher, elf_data))
+            });
+        drop(deadline);
+
+        match result {
+            Ok((flasher, elf_data)) => {
+                let mut port = flasher.into_transport();
+
+                if let Some(monitor_opts) = monitor {
+                    let (dtr, rts) =
+                        super::create_dtr_rts_gpios_from_args(&opts.gpio_dtr, &opts.gpio_rts)?;
+
+                    let exit = monitor_until(
+                        &mut *port,
+                        &dtr,
+                        &rts,
+                        monitor_opts,
+                        Some(&elf_data),
+                        true,
+                        || watcher.changed(),
+                    )
+                    .into_diagnostic()?;
+                    println!();
+                    match exit {
+                        MonitorExit::User | MonitorExit::Expected => return Ok(()),
+                        MonitorExit::Reflash => {
+                            port.set_baud_rate(115_200).into_diagnostic()?;
+                            serial = Some(port);
+                            continue;
+                        }
+                        MonitorExit::Stopped => {}
+                    }
+                } else {
+                    info!("\nWaiting for {} to change...", elf.display());
+                    watcher.wait();
+                }
+
+                // Connecting assumes that the port is at the default baud rate
+                port.set_baud_rate(115_200).into_diagnostic()?;
+                serial = Some(port);
+            }
+            Err(err) => {
+                eprintln!("{:?}", err);
+                info!("Waiting for {} to change...", elf.display());
+                watcher.wait();
+            }
+        }
+
+        info!("{} changed, flashing again\n", elf.display());
+    }
+}
@@ -0,0 +1,66 @@
+//! Flashing raw binaries at arbitrary offsets
+
+use std::{path::PathBuf, time::Instant};
+
+use clap::Parser;
+use log::{info, warn};
+use miette::Result;
+
+use super::{
+    config::Config, connect, deadline::deadline, input::read_input, parse_u32, print_flash_report,
+    progress::EspflashProgress, ConnectOpts, OutputFormat,
+};
+use crate::flasher::FLASH_SECTOR_SIZE;
+
+#[derive(Parser)]
+pub struct WriteBinOpts {
+    #[clap(flatten)]
+    pub connect_opts: ConnectOpts,
+    /// Offset and path of a binary to flash, eg `--bin 0x10000 app.bin`, can be
+    /// given multiple times
+    #[clap(
+        long = "bin",
+        number_of_values = 2,
+        multiple_occurrences = true,
+        required = true,
+        value_names = &["OFFSET", "PATH"]
+    )]
+    pub bins: Vec<String>,
+    /// Verify the flash contents after writing them
+    #[clap(long)]
+    pub verify: bool,
+}
+
+pub fn write_bin(opts: WriteBinOpts, config: Config, output_format: OutputFormat) -> Result<()> {
+    let mut bins = Vec::new();
+    for pair in opts.bins.chunks(2) {
+        let offset = parse_u32(&pair[0]).map_err(|e| miette::miette!("Invalid offset: {}", e))?;
+        let path = PathBuf::from(&pair[1]);
+        let data = read_input(&path)?;
+
+        // Flash is erased per sector, so anything in front of an unaligned
+        // offset in the same sector is lost
+        if offset as usize % FLASH_SECTOR_SIZE != 0 {
+            warn!(
+                "{:#x} is not aligned to a flash sector, the start of the sector will be erased",
+                offset
+            );
+        }
+        bins.push((offset, data));
+    }
+
+    let _deadline = deadline(opts.connect_opts.timeout);
+    let mut flasher = connect(&opts.connect_opts, &config)?;
+    flasher.board_info()?;
+    flasher.set_verify(opts.verify);
+
+    let start = Instant::now();
+    flasher.write_bins_to_flash(
+        bins.iter().map(|(offset, data)| (*offset, data.as_slice())),
+        Some(&mut EspflashProgress::default()),
+    )?;
+    info!("Flashing has completed");
+    print_flash_report(&mut flasher, start.elapsed(), output_format)?;
+
+    Ok(())
+}
@@ -8,6 +8,7 @@ use strum_macros::Display;
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
 const ERASE_REGION_TIMEOUT_PER_MB: Duration = Duration::from_secs(30);
 const ERASE_WRITE_TIMEOUT_PER_MB: Duration = Duration::from_secs(40);
+const MD5_TIMEOUT_PER_MB: Duration = Duration::from_secs(8);
 const MEM_END_TIMEOUT: Duration = Duration::from_millis(50);
 const SYNC_TIMEOUT: Duration = Duration::from_millis(100);
 
@@ -28,15 +29,53 @@ pub enum CommandType {
     ReadReg = 0x0a,
     SpiSetParams = 0x0B,
     SpiAttach = 0x0D,
+    ReadFlashSlow = 0x0E,
     ChangeBaud = 0x0F,
     FlashDeflateBegin = 0x10,
     FlashDeflateData = 0x11,
     FlashDeflateEnd = 0x12,
     FlashMd5 = 0x13,
     FlashDetect = 0x9f,
+    // Only supported by the flasher stub
+    EraseRegion = 0xd1,
+    ReadFlash = 0xd2,
+    FlashEncryptData = 0xd4,
 }
 
 impl CommandType {
+    /// Look up the command with the given opcode
+    pub fn from_u8(op: u8) -> Self {
+        use CommandType::*;
+
+        [
+            FlashBegin,
+            FlashData,
+            FlashEnd,
+            MemBegin,
+            MemEnd,
+            MemData,
+            Sync,
+            WriteReg,
+            ReadReg,
+            SpiSetParams,
+            SpiAttach,
+            ReadFlashSlow,
+            ChangeBaud,
+            FlashDeflateBegin,
+            FlashDeflateData,
+            FlashDeflateEnd,
+            FlashMd5,
+            FlashDetect,
+            EraseRegion,
+            ReadFlash,
+            FlashEncryptData,
+        ]
+        .iter()
+        .copied()
+        .find(|ty| *ty as u8 == op)
+        .unwrap_or(Unknown)
+    }
+
     pub fn timeout(&self) -> Duration {
         match self {
             CommandType::MemEnd => MEM_END_TIMEOUT,
@@ -57,9 +96,11 @@ impl CommandType {
             CommandType::FlashBegin | CommandType::FlashDeflateBegin => {
                 calc_timeout(ERASE_REGION_TIMEOUT_PER_MB, size)
             }
-            CommandType::FlashData | CommandType::FlashDeflateData => {
-                calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB, size)
-            }
+            CommandType::FlashData
+            | CommandType::FlashDeflateData
+            | CommandType::FlashEncryptData => calc_timeout(ERASE_WRITE_TIMEOUT_PER_MB, size),
+            CommandType::FlashMd5 => calc_timeout(MD5_TIMEOUT_PER_MB, size),
+            CommandType::EraseRegion => calc_timeout(ERASE_REGION_TIMEOUT_PER_MB, size),
             _ => self.timeout(),
         }
     }
@@ -73,6 +114,8 @@ pub enum Command<'a> {
         block_size: u32,
         offset: u32,
         supports_encryption: bool,
+        /// Whether the ROM encrypts the data written after this command
+        encrypted: bool,
     },
     FlashData {
         data: &'a [u8],
@@ -80,6 +123,13 @@ pub enum Command<'a> {
         pad_byte: u8,
         sequence: u32,
     },
+    /// Like `FlashData`, but the flasher stub encrypts the data
+    FlashEncryptData {
+        data: &'a [u8],
+        pad_to: usize,
+        pad_byte: u8,
+        sequence: u32,
+    },
     FlashEnd {
         reboot: bool,
     },
@@ -114,6 +164,28 @@ pub enum Command<'a> {
     },
     ChangeBaud {
         speed: u32,
+        /// The current speed, only used by the flasher stub, `0` for the ROM
+        prior_speed: u32,
+    },
+    ReadFlashSlow {
+        offset: u32,
+        size: u32,
+    },
+    FlashMd5 {
+        offset: u32,
+        size: u32,
+    },
+    EraseRegion {
+        offset: u32,
+        size: u32,
+    },
+    /// Stream `size` bytes of flash in blocks of `block_size`, with at most
+    /// `max_in_flight` blocks sent ahead of the acknowledgements
+    ReadFlash {
+        offset: u32,
+        size: u32,
+        block_size: u32,
+        max_in_flight: u32,
     },
     FlashDeflateBegin {
         size: u32,
@@ -139,6 +211,7 @@ impl<'a> Command<'a> {
         match self {
             Command::FlashBegin { .. } => CommandType::FlashBegin,
             Command::FlashData { .. } => CommandType::FlashData,
+            Command::FlashEncryptData { .. } => CommandType::FlashEncryptData,
             Command::FlashEnd { .. } => CommandType::FlashEnd,
             Command::MemBegin { .. } => CommandType::MemBegin,
             Command::MemData { .. } => CommandType::MemData,
@@ -148,6 +221,10 @@ impl<'a> Command<'a> {
             Command::ReadReg { .. } => CommandType::ReadReg,
             Command::SpiAttach { .. } => CommandType::SpiAttach,
             Command::ChangeBaud { .. } => CommandType::ChangeBaud,
+            Command::ReadFlashSlow { .. } => CommandType::ReadFlashSlow,
+            Command::FlashMd5 { .. } => CommandType::FlashMd5,
+            Command::EraseRegion { .. } => CommandType::EraseRegion,
+            Command::ReadFlash { .. } => CommandType::ReadFlash,
             Command::FlashDeflateBegin { .. } => CommandType::FlashDeflateBegin,
             Command::FlashDeflateData { .. } => CommandType::FlashDeflateData,
             Command::FlashDeflateEnd { .. } => CommandType::FlashDeflateEnd,
@@ -168,6 +245,7 @@ impl<'a> Command<'a> {
                 block_size,
                 offset,
                 supports_encryption,
+                encrypted,
             } => {
                 begin_command(
                     writer,
@@ -176,6 +254,7 @@ impl<'a> Command<'a> {
                     block_size,
                     offset,
                     supports_encryption,
+                    encrypted,
                 )?;
             }
             Command::FlashData {
@@ -183,6 +262,12 @@ impl<'a> Command<'a> {
                 pad_byte,
                 data,
                 sequence,
+            }
+            | Command::FlashEncryptData {
+                pad_to,
+                pad_byte,
+                data,
+                sequence,
             } => {
                 data_command(writer, data, pad_to, pad_byte, sequence)?;
             }
@@ -203,6 +288,7 @@ impl<'a> Command<'a> {
                     block_size,
                     offset,
                     supports_encryption,
+                    false,
                 )?;
             }
             Command::MemData {
@@ -267,14 +353,45 @@ impl<'a> Command<'a> {
             Command::SpiAttach { spi_params } => {
                 write_basic(writer, &spi_params.encode(), 0)?;
             }
-            Command::ChangeBaud { speed } => {
+            Command::ChangeBaud { speed, prior_speed } => {
                 // length
                 writer.write_all(&(8u16.to_le_bytes()))?;
                 // checksum
                 writer.write_all(&(0u32.to_le_bytes()))?;
                 // data
                 writer.write_all(&speed.to_le_bytes())?;
-                writer.write_all(&0u32.to_le_bytes())?;
+                writer.write_all(&prior_speed.to_le_bytes())?;
+            }
+            Command::ReadFlashSlow { offset, size } => {
+                let mut params = [0; 8];
+                params[..4].copy_from_slice(&offset.to_le_bytes());
+                params[4..].copy_from_slice(&size.to_le_bytes());
+                write_basic(writer, &params, 0)?;
+            }
+            Command::FlashMd5 { offset, size } => {
+                let mut params = [0; 16];
+                params[..4].copy_from_slice(&offset.to_le_bytes());
+                params[4..8].copy_from_slice(&size.to_le_bytes());
+                write_basic(writer, &params, 0)?;
+            }
+            Command::EraseRegion { offset, size } => {
+                let mut params = [0; 8];
+                params[..4].copy_from_slice(&offset.to_le_bytes());
+                params[4..].copy_from_slice(&size.to_le_bytes());
+                write_basic(writer, &params, 0)?;
+            }
+            Command::ReadFlash {
+                offset,
+                size,
+                block_size,
+                max_in_flight,
+            } => {
+                let mut params = [0; 16];
+                params[..4].copy_from_slice(&offset.to_le_bytes());
+                params[4..8].copy_from_slice(&size.to_le_bytes());
+                params[8..12].copy_from_slice(&block_size.to_le_bytes());
+                params[12..].copy_from_slice(&max_in_flight.to_le_bytes());
+                write_basic(writer, &params, 0)?;
             }
             Command::FlashDeflateBegin {
                 size,
@@ -290,6 +407,7 @@ impl<'a> Command<'a> {
                     block_size,
                     offset,
                     supports_encryption,
+                    false,
                 )?;
             }
             Command::FlashDeflateData {
@@ -325,6 +443,7 @@ fn begin_command<W: Write>(
     block_size: u32,
     offset: u32,
     supports_encryption: bool,
+    encrypted: bool,
 ) -> std::io::Result<()> {
     #[derive(Zeroable, Pod, Copy, Clone, Debug)]
     #[repr(C)]
@@ -340,7 +459,7 @@ fn begin_command<W: Write>(
         blocks,
         block_size,
         offset,
-        encrypted: 0,
+        encrypted: encrypted as u32,
     };
 
     let bytes = bytes_of(&params);
@@ -1,38 +1,63 @@
 use std::{
-    io::{BufWriter, Write},
+    io::Write,
     thread::sleep,
-    time::Duration,
+    time::{Duration, Instant},
 };
 
 use binread::{io::Cursor, BinRead, BinReaderExt};
 use bytemuck::{Pod, Zeroable};
-use serialport::{SerialPort, UsbPortInfo};
-use slip_codec::SlipDecoder;
+use log::{debug, trace};
+use serialport::SerialPortType;
 
 use crate::{
     command::{Command, CommandType},
-    encoder::SlipEncoder,
-    error::{ConnectionError, Error, ResultExt, RomError, RomErrorKind},
+    error::{ConnectionError, Error, ResultExt},
+    flasher::ProgressCallbacks,
+    protocol::{
+        encode_command, encode_frame, parse_data_response, parse_response, FrameDecoder, Operation,
+        Step,
+    },
+    reset::{
+        is_usb_serial_jtag, ResetAfterOperation, ResetBeforeOperation, ResetLines, ResetPin,
+        ResetStep, ResetStrategy,
+    },
+    stubs::STUB_GREETING,
+    transport::Transport,
 };
 
-const USB_SERIAL_JTAG_PID: u16 = 0x1001;
-
 // For ESP32 flashing serial DTR and RTS pins are used to reset device
 // and put it to flashing mode.
-// On Linux platforms it is also possible to use `gpio_cdev` GPIO pins for that.
-// It can be useful for embedded platforms that do not have DTR and RTS pins exposed.
-#[cfg(target_os = "linux")]
-pub struct GpioLine(pub gpio_cdev::LineHandle);
-#[cfg(not(target_os = "linux"))]
-pub struct DummyGpioLineHandle;
-#[cfg(not(target_os = "linux"))]
-impl DummyGpioLineHandle {
-    pub fn set_value(&self, _: u8) -> Result<(), Error> {
-        Ok(())
+// It is also possible to use GPIO pins for that, see `ResetPin` for the
+// supported backends. It can be useful for embedded platforms that do not have
+// DTR and RTS pins exposed.
+pub type GpioLine = Box<dyn ResetPin>;
+
+/// Connection attempts made by default before giving up
+pub const DEFAULT_CONNECT_ATTEMPTS: usize = 7;
+
+/// How the connection to the bootloader is established and left behind
+#[derive(Copy, Clone, Debug, PartialEq)]
+pub struct ConnectSettings {
+    pub before: ResetBeforeOperation,
+    pub after: ResetAfterOperation,
+    /// Number of times the device is reset and synced with before giving up,
+    /// zero keeps trying forever
+    pub attempts: usize,
+    /// Factor the timeouts of all commands are multiplied with, for slow flash
+    /// chips and serial adapters
+    pub timeout_scale: f64,
+}
+
+impl Default for ConnectSettings {
+    fn default() -> Self {
+        ConnectSettings {
+            before: ResetBeforeOperation::default(),
+            after: ResetAfterOperation::default(),
+            attempts: DEFAULT_CONNECT_ATTEMPTS,
+            timeout_scale: 1.0,
+        }
     }
 }
-#[cfg(not(target_os = "linux"))]
-pub struct GpioLine(pub DummyGpioLineHandle);
 
 #[derive(Debug, Copy, Clone, BinRead)]
 pub struct CommandResponse {
@@ -45,11 +70,16 @@ pub struct CommandResponse {
 }
 
 pub struct Connection {
-    serial: Box<dyn SerialPort>,
+    transport: Box<dyn Transport>,
     gpio_dtr: Option<GpioLine>,
     gpio_rts: Option<GpioLine>,
-    port_info: UsbPortInfo,
-    decoder: SlipDecoder,
+    port_type: SerialPortType,
+    decoder: FrameDecoder,
+    before: ResetBeforeOperation,
+    after: ResetAfterOperation,
+    timeout_scale: f64,
+    /// Number of status bytes ending the responses with data
+    status_length: usize,
 }
 
 #[derive(Zeroable, Pod, Copy, Clone, Debug)]
@@ -63,102 +93,151 @@ struct WriteRegParams {
 
 impl Connection {
     pub fn new(
-        serial: Box<dyn SerialPort>,
-        port_info: UsbPortInfo,
+        transport: Box<dyn Transport>,
+        port_type: SerialPortType,
         gpio_dtr: Option<GpioLine>,
         gpio_rts: Option<GpioLine>,
     ) -> Self {
         Connection {
-            serial,
+            transport,
             gpio_dtr,
             gpio_rts,
-            port_info,
-            decoder: SlipDecoder::new(),
+            port_type,
+            decoder: FrameDecoder::default(),
+            before: ResetBeforeOperation::default(),
+            after: ResetAfterOperation::default(),
+            timeout_scale: 1.0,
+            status_length: 4,
         }
     }
 
-    fn set_dtr(&mut self, state: bool) -> Result<(), Error> {
-        if let Some(ref dtr) = self.gpio_dtr {
-            dtr.0.set_value(state as u8)?;
-        } else {
-            self.serial.write_data_terminal_ready(state)?;
-        }
-        Ok(())
+    fn reset_lines(&mut self) -> ResetLines<'_> {
+        ResetLines::new(&mut *self.transport, &self.gpio_dtr, &self.gpio_rts)
     }
 
-    fn set_rts(&mut self, state: bool) -> Result<(), Error> {
-        if let Some(ref rts) = self.gpio_rts {
-            rts.0.set_value(state as u8)?;
-        } else {
-            self.serial.write_request_to_send(state)?;
-        }
-        Ok(())
+    /// Whether the device can be reset through the DTR and RTS lines
+    ///
+    /// Bluetooth serial ports don't have these lines, devices connected through
+    /// them have to be reset by other means.
+    pub fn can_reset(&self) -> bool {
+        self.port_type != SerialPortType::BluetoothPort
+            || self.gpio_dtr.is_some()
+            || self.gpio_rts.is_some()
     }
 
-    pub fn reset(&mut self) -> Result<(), Error> {
-        sleep(Duration::from_millis(100));
-
-        self.set_dtr(false)?;
-        self.set_rts(true)?;
-
-        sleep(Duration::from_millis(100));
-
-        self.set_rts(false)?;
-
-        Ok(())
+    /// Set how the device is reset before connecting and after flashing
+    pub fn set_reset_behavior(&mut self, before: ResetBeforeOperation, after: ResetAfterOperation) {
+        self.before = before;
+        self.after = after;
     }
 
-    pub fn reset_to_flash(&mut self, extra_delay: bool) -> Result<(), Error> {
-        if self.port_info.pid == USB_SERIAL_JTAG_PID {
-            self.set_dtr(false)?;
-            self.set_rts(false)?;
-
-            sleep(Duration::from_millis(100));
-
-            self.set_dtr(true)?;
-            self.set_rts(false)?;
-
-            sleep(Duration::from_millis(100));
+    pub fn reset_behavior(&self) -> (ResetBeforeOperation, ResetAfterOperation) {
+        (self.before, self.after)
+    }
 
-            self.set_rts(true)?;
-            self.set_dtr(false)?;
-            self.set_rts(true)?;
+    /// Set whether the responses end with two status bytes, like the ones of the
+    /// flasher stub and the ESP8266 ROM, instead of the four of the other ROMs
+    pub fn set_short_status(&mut self, short: bool) {
+        self.status_length = if short { 2 } else { 4 };
+    }
 
-            sleep(Duration::from_millis(100));
+    /// Multiply the timeouts of all following commands by `scale`
+    pub fn set_timeout_scale(&mut self, scale: f64) {
+        self.timeout_scale = scale;
+    }
 
-            self.set_dtr(false)?;
-            self.set_rts(false)?;
-        } else {
-            self.set_dtr(false)?;
-            self.set_rts(true)?;
+    /// Toggle the control lines following `strategy`
+    pub fn reset_with(&mut self, strategy: &dyn ResetStrategy) -> Result<(), Error> {
+        // Without the control lines the device has to be reset manually
+        if !self.can_reset() {
+            return Ok(());
+        }
 
-            sleep(Duration::from_millis(100));
+        strategy.reset(&mut self.reset_lines())
+    }
 
-            self.set_dtr(true)?;
-            self.set_rts(false)?;
+    fn run_reset(&mut self, steps: &[ResetStep]) -> Result<(), Error> {
+        if !self.can_reset() {
+            return Ok(());
+        }
 
-            let millis = if extra_delay { 500 } else { 50 };
-            sleep(Duration::from_millis(millis));
+        self.reset_lines().run(steps)
+    }
 
-            self.set_dtr(false)?;
-        }
+    /// Reset the device after flashing, unless this has been disabled
+    pub fn reset(&mut self) -> Result<(), Error> {
+        let usb_serial_jtag = is_usb_serial_jtag(&self.port_type);
+        self.run_reset(&self.after.steps(self.before, usb_serial_jtag))
+    }
 
-        Ok(())
+    pub fn reset_to_flash(&mut self, extra_delay: bool) -> Result<(), Error> {
+        let usb_serial_jtag = is_usb_serial_jtag(&self.port_type);
+        self.run_reset(&self.before.steps(usb_serial_jtag, extra_delay))
     }
 
     pub fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
-        self.serial.set_timeout(timeout)?;
-        Ok(())
+        self.transport
+            .set_timeout(timeout.mul_f64(self.timeout_scale))
     }
 
     pub fn set_baud(&mut self, speed: u32) -> Result<(), Error> {
-        self.serial.set_baud_rate(speed)?;
-
-        Ok(())
+        self.transport.set_baud_rate(speed)
     }
 
     pub fn get_baud(&self) -> Result<u32, Error> {
-        Ok(self.serial.baud_rate()?)
+        self.transport.baud_rate()
+    }
+
+    /// Switch both the device and the host to `speed`
+    ///
+    /// The connection is checked after switching by reading `check_reg`, if the
+    /// device doesn't respond at the new speed the host goes back to the previous
+    /// one and [`ConnectionError::BaudChangeFailed`] is returned. `stub` has to
+    /// be set when the flasher stub is running, which needs the current speed.
+    /// The device is asked for `device_speed`, which differs from `speed` when
+    /// it miscalculates the baud rate for its crystal.
+    pub fn change_baud(
+        &mut self,
+        speed: u32,
+        device_speed: u32,
+        check_reg: u32,
+        stub: bool,
+    ) -> Result<(), Error> {
+        let old_speed = self.get_baud()?;
+        let prior_speed = if stub { old_speed } else { 0 };
+
+        self.with_timeout(CommandType::ChangeBaud.timeout(), |connection| {
+            connection.command(Command::ChangeBaud {
+                speed: device_speed,
+                prior_speed,
+            })
+        })?;
+        self.set_baud(speed)?;
+        sleep(Duration::from_millis(50));
+        self.flush()?;
+
+        if self.check_connection(check_reg) {
+            return Ok(());
+        }
+
+        debug!("No response at {} baud, going back to {}", speed, old_speed);
+        self.set_baud(old_speed)?;
+        sleep(Duration::from_millis(50));
+        if self.check_connection(check_reg) {
+            Err(Error::Connection(ConnectionError::BaudChangeFailed(speed)))
+        } else {
+            Err(Error::Connection(ConnectionError::ConnectionFailed))
+        }
+    }
+
+    fn check_connection(&mut self, check_reg: u32) -> bool {
+        for _ in 0..3 {
+            let _ = self.flush();
+            if self.read_reg(check_reg).is_ok() {
+                return true;
+            }
+        }
+        false
     }
 
     pub fn with_timeout<T, F: FnMut(&mut Connection) -> Result<T, Error>>(
@@ -166,13 +245,30 @@ impl Connection {
         timeout: Duration,
         mut f: F,
     ) -> Result<T, Error> {
-        let old_timeout = self.serial.timeout();
-        self.serial.set_timeout(timeout)?;
+        let old_timeout = self.transport.timeout();
+        self.transport
+            .set_timeout(timeout.mul_f64(self.timeout_scale))?;
         let result = f(self);
-        self.serial.set_timeout(old_timeout)?;
+        self.transport.set_timeout(old_timeout)?;
         result
     }
 
+    /// Wait for the greeting sent by the flasher stub once it's started
+    pub fn wait_for_stub(&mut self) -> Result<(), Error> {
+        self.with_timeout(CommandType::MemEnd.timeout(), |connection| {
+            // The response to the command that started the stub may come first
+            for _ in 0..10 {
+                match connection.read(STUB_GREETING.len()) {
+                    Ok(Some(packet)) if packet == STUB_GREETING => return Ok(()),
+                    Ok(_) => continue,
+                    Err(Error::Connection(ConnectionError::Timeout(_))) => break,
+                    Err(err) => return Err(err),
+                }
+            }
+            Err(Error::StubNotRunning)
+        })
+    }
+
     pub fn read_response(&mut self) -> Result<Option<CommandResponse>, Error> {
         match self.read(10)? {
             None => Ok(None),
@@ -185,45 +281,109 @@ impl Connection {
     }
 
     pub fn write_command(&mut self, command: Command) -> Result<(), Error> {
-        self.serial.clear(serialport::ClearBuffer::Input)?;
-        let mut writer = BufWriter::new(&mut self.serial);
-        let mut encoder = SlipEncoder::new(&mut writer)?;
-        command.write(&mut encoder)?;
-        encoder.finish()?;
+        let frame = encode_command(command)?;
+        self.transport.clear_input()?;
+        self.transport.write_all(&frame)?;
+        Ok(())
+    }
+
+    /// Send `data` in a frame of its own, like the acknowledgements of the
+    /// data streamed by the flasher stub
+    pub fn write_frame(&mut self, data: &[u8]) -> Result<(), Error> {
+        let frame = encode_frame(data)?;
+        self.transport.write_all(&frame)?;
         Ok(())
     }
 
+    /// Read the next frame, whatever it holds
+    pub fn read_frame(&mut self) -> Result<Vec<u8>, Error> {
+        self.decoder.read(&mut self.transport)
+    }
+
     pub fn command(&mut self, command: Command) -> Result<u32, Error> {
         let ty = command.command_type();
+        debug!("Sending {} command", ty);
+        let start = Instant::now();
         self.write_command(command).for_command(ty)?;
 
         for _ in 0..100 {
-            match self.read_response().for_command(ty)? {
-                Some(response) if response.return_op == ty as u8 => {
-                    return if response.status == 1 {
-                        let _error = self.flush();
-                        Err(Error::RomError(RomError::new(
-                            command.command_type(),
-                            RomErrorKind::from(response.error),
-                        )))
-                    } else {
-                        Ok(response.value)
-                    }
+            let frame = match self.read(10).for_command(ty)? {
+                Some(frame) => frame,
+                None => continue,
+            };
+            match parse_response(&frame, ty) {
+                Ok(None) => continue,
+                Ok(Some(value)) => {
+                    trace!("{} command finished after {:?}", ty, start.elapsed());
+                    return Ok(value);
                 }
-                _ => {
-                    continue;
+                Err(err) => {
+                    let _error = self.flush();
+                    return Err(err);
                 }
             }
         }
         Err(Error::Connection(ConnectionError::ConnectionFailed))
     }
 
+    /// Send a command which responds with data, and return that data
+    ///
+    /// The status bytes ending the response are stripped, see
+    /// [`Connection::set_short_status`] for how many there are.
+    pub fn command_with_data(&mut self, command: Command) -> Result<Vec<u8>, Error> {
+        let status_length = self.status_length;
+
+        let ty = command.command_type();
+        debug!("Sending {} command", ty);
+        self.write_command(command).for_command(ty)?;
+
+        for _ in 0..100 {
+            let frame = match self.read(10).for_command(ty)? {
+                Some(frame) => frame,
+                None => continue,
+            };
+            if let Some(data) = parse_data_response(&frame, ty, status_length)? {
+                return Ok(data);
+            }
+        }
+        Err(Error::Connection(ConnectionError::ConnectionFailed))
+    }
+
+    /// Carry out the steps of `operation` and return its output
+    pub(crate) fn run<O: Operation>(
+        &mut self,
+        mut operation: O,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<O::Output, Error> {
+        let mut value = 0;
+        while let Some(step) = operation.step(value, progress)? {
+            value = 0;
+            match step {
+                Step::Command(command, timeout) => {
+                    value = self.with_timeout(timeout, |connection| connection.command(command))?
+                }
+                Step::Send(command) => self
+                    .with_timeout(command.command_type().timeout(), |connection| {
+                        connection.write_command(command)
+                    })?,
+                Step::Sleep(duration) => sleep(duration),
+                Step::Reset => self.reset()?,
+            }
+        }
+        Ok(operation.output())
+    }
+
     pub fn read_reg(&mut self, reg: u32) -> Result<u32, Error> {
         self.with_timeout(CommandType::ReadReg.timeout(), |connection| {
             connection.command(Command::ReadReg { address: reg })
         })
     }
 
+    /// Read `count` consecutive 32 bit registers starting at `addr`
+    pub fn read_regs(&mut self, addr: u32, count: u32) -> Result<Vec<u32>, Error> {
+        (0..count).map(|i| self.read_reg(addr + i * 4)).collect()
+    }
+
     pub fn write_reg(&mut self, addr: u32, value: u32, mask: Option<u32>) -> Result<(), Error> {
         self.with_timeout(CommandType::WriteReg.timeout(), |connection| {
             connection.command(Command::WriteReg {
@@ -239,7 +399,7 @@ impl Connection {
     fn read(&mut self, len: usize) -> Result<Option<Vec<u8>>, Error> {
         let mut tmp = Vec::with_capacity(1024);
         loop {
-            self.decoder.decode(&mut self.serial, &mut tmp)?;
+            tmp.extend(self.decoder.read(&mut self.transport)?);
             if tmp.len() >= len {
                 return Ok(Some(tmp));
             }
@@ -247,11 +407,19 @@ impl Connection {
     }
 
     pub fn flush(&mut self) -> Result<(), Error> {
-        self.serial.flush()?;
+        self.transport.flush()?;
         Ok(())
     }
 
-    pub fn into_serial(self) -> Box<dyn SerialPort> {
-        self.serial
+    pub fn port_type(&self) -> &SerialPortType {
+        &self.port_type
+    }
+
+    pub fn transport_mut(&mut self) -> &mut dyn Transport {
+        &mut *self.transport
+    }
+
+    pub fn into_transport(self) -> Box<dyn Transport> {
+        self.transport
     }
 }
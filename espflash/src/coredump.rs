@@ -0,0 +1,477 @@
+//! Core dumps saved to flash by ESP-IDF applications
+//!
+//! When an application crashes it can save a core dump to the `coredump`
+//! partition, see `CONFIG_ESP_COREDUMP_ENABLE_TO_FLASH`. Only the ELF format
+//! of ESP-IDF is supported, the older binary format isn't. The dump starts with
+//! a small header holding its length and version, followed by an ELF core file
+//! and a CRC32 or SHA-256 checksum.
+//!
+//! The core file contains a `PT_NOTE` segment with the registers of every task
+//! and a `PT_LOAD` segment for every task stack and control block, which are
+//! enough to unwind the stack of the tasks.
+
+use std::convert::TryInto;
+
+use sha2::{Digest, Sha256};
+use xmas_elf::{program::Type, ElfFile};
+
+use crate::{
+    elf::{check_bounds, range},
+    error::{ElfError, Error},
+};
+
+/// Length of the header in front of the ELF core file
+pub const HEADER_SIZE: usize = 20;
+const ELF_MAGIC: &[u8] = b"\x7fELF";
+const EM_XTENSA: u16 = 94;
+const EM_RISCV: u16 = 243;
+
+const NT_PRSTATUS: u32 = 1;
+const NT_CORE_DUMP_INFO: u32 = 8200;
+const NT_EXTRA_INFO: u32 = 677;
+const NT_PANIC_DETAILS: u32 = 1919;
+
+/// Offset of the process id in `elf_prstatus`, which holds the address of the
+/// task control block
+const PR_PID: usize = 24;
+/// Offset of the registers in `elf_prstatus`
+const PR_REG: usize = 72;
+
+/// Frames unwound at most, in case the stack is corrupted and loops
+const MAX_FRAMES: usize = 64;
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum Architecture {
+    Xtensa,
+    RiscV,
+}
+
+/// The registers of a task at the time of the crash
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Task {
+    /// Address of the task control block, identifying the task
+    pub tcb: u32,
+    pub pc: u32,
+    pub stack_pointer: u32,
+    /// `a0` on Xtensa and `ra` on RISC-V
+    pub return_address: u32,
+}
+
+/// A frame of a backtrace
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct Frame {
+    pub pc: u32,
+    pub stack_pointer: u32,
+}
+
+#[derive(Debug)]
+pub struct Coredump {
+    pub architecture: Architecture,
+    pub tasks: Vec<Task>,
+    /// Control block of the task which crashed
+    pub crashed_task: Option<u32>,
+    /// Exception cause and similar registers of the crash, by name
+    pub exception_registers: Vec<(String, u32)>,
+    /// Start of the hex encoded SHA-256 digest of the application ELF file
+    pub app_elf_sha256: Option<String>,
+    /// The panic message, saved by newer ESP-IDF releases
+    pub panic_details: Option<String>,
+    memory: Vec<(u32, Vec<u8>)>,
+}
+
+/// Total length of a core dump, including the header and the checksum, from
+/// its first [`HEADER_SIZE`] bytes
+pub fn dump_len(header: &[u8]) -> Result<u32, Error> {
+    let word = |index: usize| {
+        header
+            .get(index * 4..index * 4 + 4)
+            .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+            .ok_or_else(|| Error::InvalidCoredump("truncated header".into()))
+    };
+
+    let len = word(0)?;
+    if len == u32::MAX {
+        return Err(Error::NoCoredump);
+    }
+    // The major version is 1 for the ELF format and 0 for the binary one
+    if (word(1)? >> 8) & 0xff != 1 {
+        return Err(Error::UnsupportedCoredumpFormat);
+    }
+    if (len as usize) < HEADER_SIZE + ELF_MAGIC.len() {
+        return Err(Error::InvalidCoredump(format!("invalid length {:#x}", len)));
+    }
+
+    Ok(len)
+}
+
+/// The ELF core file in a core dump read from the `coredump` partition, after
+/// verifying its checksum
+pub fn extract_elf(dump: &[u8]) -> Result<&[u8], Error> {
+    let len = dump_len(dump)? as usize;
+    let dump = dump
+        .get(..len)
+        .ok_or_else(|| Error::InvalidCoredump("the dump is truncated".into()))?;
+
+    // Which checksum is used depends on the ESP-IDF configuration
+    let crc_valid = |data: &[u8], checksum: &[u8]| crc32fast::hash(data).to_le_bytes() == checksum;
+    let sha_valid = |data: &[u8], checksum: &[u8]| Sha256::digest(data).as_slice() == checksum;
+    let data = if len >= 4 && crc_valid(&dump[..len - 4], &dump[len - 4..]) {
+        &dump[..len - 4]
+    } else if len >= 32 && sha_valid(&dump[..len - 32], &dump[len - 32..]) {
+        &dump[..len - 32]
+    } else {
+        return Err(Error::InvalidCoredump("checksum mismatch".into()));
+    };
+
+    // Newer releases extend the header, the ELF file follows it directly
+    let start = data
+        .get(HEADER_SIZE..)
+        .ok_or_else(|| Error::InvalidCoredump("truncated header".into()))?
+        .windows(ELF_MAGIC.len())
+        .take(16)
+        .position(|window| window == ELF_MAGIC)
+        .ok_or_else(|| Error::InvalidCoredump("no ELF core file found".into()))?;
+
+    Ok(&data[HEADER_SIZE + start..])
+}
+
+impl Coredump {
+    /// Parse an ELF core file as written by ESP-IDF
+    pub fn parse(elf: &[u8]) -> Result<Self, Error> {
+        let invalid = |message: &str| Error::InvalidCoredump(message.into());
+
+        let file = ElfFile::new(elf).map_err(ElfError::from)?;
+        check_bounds(&file)?;
+        let architecture = match elf.get(18..20).map(|m| u16::from_le_bytes([m[0], m[1]])) {
+            Some(EM_XTENSA) => Architecture::Xtensa,
+            Some(EM_RISCV) => Architecture::RiscV,
+            _ => return Err(invalid("unsupported architecture")),
+        };
+
+        let mut coredump = Coredump {
+            architecture,
+            tasks: Vec::new(),
+            crashed_task: None,
+            exception_registers: Vec::new(),
+            app_elf_sha256: None,
+            panic_details: None,
+            memory: Vec::new(),
+        };
+
+        for header in file.program_iter() {
+            let data = range(header.offset(), header.file_size())
+                .and_then(|range| elf.get(range))
+                .ok_or_else(|| invalid("segment out of bounds"))?;
+            match header.get_type() {
+                Ok(Type::Load) => coredump
+                    .memory
+                    .push((header.virtual_addr() as u32, data.to_vec())),
+                Ok(Type::Note) => {
+                    for (name, ty, desc) in notes(data) {
+                        coredump.parse_note(name, ty, desc)?;
+                    }
+                }
+                _ => {}
+            }
+        }
+
+        Ok(coredump)
+    }
+
+    fn parse_note(&mut self, name: &[u8], ty: u32, desc: &[u8]) -> Result<(), Error> {
+        let word = |offset: usize| {
+            desc.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        };
+
+        match (name, ty) {
+            (b"CORE", NT_PRSTATUS) => {
+                // The Xtensa registers are pc, ps, lbeg, lend, lcount, sar,
+                // windowstart, windowbase, 56 reserved words and then a0 to a63,
+                // the RISC-V ones pc followed by x1 to x31
+                let (return_address, stack_pointer) = match self.architecture {
+                    Architecture::Xtensa => (PR_REG + 64 * 4, PR_REG + 65 * 4),
+                    Architecture::RiscV => (PR_REG + 4, PR_REG + 8),
+                };
+                let task = (|| {
+                    Some(Task {
+                        tcb: word(PR_PID)?,
+                        pc: word(PR_REG)?,
+                        stack_pointer: word(stack_pointer)?,
+                        return_address: word(return_address)?,
+                    })
+                })()
+                .ok_or_else(|| Error::InvalidCoredump("truncated task registers".into()))?;
+                self.tasks.push(task);
+            }
+            (b"ESP_CORE_DUMP_INFO", NT_CORE_DUMP_INFO) => {
+                let sha = desc.get(4..).unwrap_or_default();
+                let sha = &sha[..sha.iter().position(|&b| b == 0).unwrap_or(sha.len())];
+                self.app_elf_sha256 = Some(String::from_utf8_lossy(sha).into_owned());
+            }
+            (b"EXTRA_INFO", NT_EXTRA_INFO) => {
+                self.crashed_task = word(0);
+                // Only Xtensa saves further registers, as index and value pairs
+                if self.architecture == Architecture::Xtensa {
+                    let mut offset = 4;
+                    while let (Some(index), Some(value)) = (word(offset), word(offset + 4)) {
+                        if let Some(name) = xtensa_register_name(index) {
+                            self.exception_registers.push((name, value));
+                        }
+                        offset += 8;
+                    }
+                }
+            }
+            (b"ESP_PANIC_DETAILS", NT_PANIC_DETAILS) => {
+                let details = &desc[..desc.iter().position(|&b| b == 0).unwrap_or(desc.len())];
+                self.panic_details = Some(String::from_utf8_lossy(details).trim().to_string());
+            }
+            _ => {}
+        }
+
+        Ok(())
+    }
+
+    /// Read a word of the saved memory
+    pub fn read_u32(&self, addr: u32) -> Option<u32> {
+        self.memory.iter().find_map(|(start, data)| {
+            let offset = addr.checked_sub(*start)? as usize;
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+        })
+    }
+
+    /// Unwind the stack of `task`
+    ///
+    /// On Xtensa the windowed ABI saves the return address and the stack pointer
+    /// of the caller below the stack pointer, which allows following the whole
+    /// call chain. RISC-V code doesn't keep a frame pointer, so only the current
+    /// function and its caller can be determined without the unwind tables.
+    pub fn backtrace(&self, task: &Task) -> Vec<Frame> {
+        let mut frames = vec![Frame {
+            pc: task.pc,
+            stack_pointer: task.stack_pointer,
+        }];
+
+        match self.architecture {
+            Architecture::Xtensa => {
+                let mut next_pc = task.return_address;
+                let mut stack_pointer = task.stack_pointer;
+                while next_pc & 0x3fff_ffff != 0 && frames.len() < MAX_FRAMES {
+                    let (return_address, caller_sp) = match (
+                        self.read_u32(stack_pointer.wrapping_sub(16)),
+                        self.read_u32(stack_pointer.wrapping_sub(12)),
+                    ) {
+                        (Some(a0), Some(a1)) => (a0, a1),
+                        _ => break,
+                    };
+                    frames.push(Frame {
+                        pc: xtensa_call_address(next_pc),
+                        stack_pointer: caller_sp,
+                    });
+                    next_pc = return_address;
+                    stack_pointer = caller_sp;
+                }
+            }
+            Architecture::RiscV => {
+                if task.return_address != 0 {
+                    frames.push(Frame {
+                        // The call instruction before the return address
+                        pc: task.return_address.wrapping_sub(4),
+                        stack_pointer: task.stack_pointer,
+                    });
+                }
+            }
+        }
+
+        frames
+    }
+}
+
+/// The `desc` of the notes in a `PT_NOTE` segment, with their name and type
+fn notes(mut data: &[u8]) -> impl Iterator<Item = (&[u8], u32, &[u8])> {
+    let align = |len: usize| (len + 3) & !3;
+
+    std::iter::from_fn(move || {
+        let word = |offset: usize| {
+            data.get(offset..offset + 4)
+                .map(|bytes| u32::from_le_bytes(bytes.try_into().unwrap()) as usize)
+        };
+        let (name_len, desc_len, ty) = (word(0)?, word(4)?, word(8)? as u32);
+        let name_end = 12usize.checked_add(name_len)?;
+        let desc_start = 12usize.checked_add(align(name_len))?;
+        let desc_end = desc_start.checked_add(desc_len)?;
+
+        let name = data.get(12..name_end)?;
+        // The name includes the terminating null byte
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        let desc = data.get(desc_start..desc_end)?;
+        data = data.get(align(desc_end)..).unwrap_or_default();
+
+        Some((name, ty, desc))
+    })
+}
+
+/// The address of the call instruction before the return address `a0`, whose
+/// upper two bits hold the window increment instead of the address
+fn xtensa_call_address(a0: u32) -> u32 {
+    ((a0 & 0x3fff_ffff) | 0x4000_0000).wrapping_sub(3)
+}
+
+fn xtensa_register_name(index: u32) -> Option<String> {
+    match index {
+        232 => Some("EXCCAUSE".into()),
+        238 => Some("EXCVADDR".into()),
+        177..=183 => Some(format!("EPC{}", index - 176)),
+        194..=199 => Some(format!("EPS{}", index - 192)),
+        _ => None,
+    }
+}
+
+/// Name of the Xtensa exception cause `cause`
+pub fn exception_cause(cause: u32) -> Option<&'static str> {
+    let name = match cause {
+        0 => "IllegalInstruction",
+        1 => "Syscall",
+        2 => "InstructionFetchError",
+        3 => "LoadStoreError",
+        4 => "Level1Interrupt",
+        5 => "Alloca",
+        6 => "IntegerDivideByZero",
+        8 => "Privileged",
+        9 => "LoadStoreAlignment",
+        12 => "InstrPIFDataError",
+        13 => "LoadStorePIFDataError",
+        14 => "InstrPIFAddrError",
+        15 => "LoadStorePIFAddrError",
+        16 => "InstTLBMiss",
+        17 => "InstTLBMultiHit",
+        18 => "InstFetchPrivilege",
+        20 => "InstFetchProhibited",
+        24 => "LoadStoreTLBMiss",
+        25 => "LoadStoreTLBMultiHit",
+        26 => "LoadStorePrivilege",
+        28 => "LoadProhibited",
+        29 => "StoreProhibited",
+        _ => return None,
+    };
+
+    Some(name)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn note(name: &str, ty: u32, desc: &[u8]) -> Vec<u8> {
+        let mut name = name.as_bytes().to_vec();
+        name.push(0);
+        let mut note = Vec::new();
+        note.extend_from_slice(&(name.len() as u32).to_le_bytes());
+        note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        note.extend_from_slice(&ty.to_le_bytes());
+        name.resize((name.len() + 3) & !3, 0);
+        note.extend_from_slice(&name);
+        note.extend_from_slice(desc);
+        note.resize((note.len() + 3) & !3, 0);
+        note
+    }
+
+    fn words(words: &[(usize, u32)], len: usize) -> Vec<u8> {
+        let mut data = vec![0; len];
+        for (offset, word) in words {
+            data[*offset..*offset + 4].copy_from_slice(&word.to_le_bytes());
+        }
+        data
+    }
+
+    /// An Xtensa core file with one task whose stack holds two further frames
+    fn core_file() -> Vec<u8> {
+        let registers = words(
+            &[
+                (PR_PID, 0x3ffb_1000),
+                (PR_REG, 0x400d_1234),
+                (PR_REG + 64 * 4, 0x800d_2000),
+                (PR_REG + 65 * 4, 0x3ffb_2020),
+            ],
+            PR_REG + 128 * 4,
+        );
+        let mut notes = note("CORE", NT_PRSTATUS, &registers);
+        notes.extend(note(
+            "EXTRA_INFO",
+            NT_EXTRA_INFO,
+            &words(&[(0, 0x3ffb_1000), (4, 232), (8, 29)], 12),
+        ));
+        // The base save area of the first frame, then of the second one
+        let stack = words(&[(0x10, 0x800d_3000), (0x14, 0x3ffb_2040), (0x30, 0)], 0x40);
+
+        let mut elf = vec![0; 52 + 2 * 32];
+        elf[..4].copy_from_slice(ELF_MAGIC);
+        elf[4..7].copy_from_slice(&[1, 1, 1]);
+        elf[16..18].copy_from_slice(&4u16.to_le_bytes());
+        elf[18..20].copy_from_slice(&EM_XTENSA.to_le_bytes());
+        elf[20..24].copy_from_slice(&1u32.to_le_bytes());
+        elf[28..32].copy_from_slice(&52u32.to_le_bytes());
+        elf[40..42].copy_from_slice(&52u16.to_le_bytes());
+        elf[42..44].copy_from_slice(&32u16.to_le_bytes());
+        elf[44..46].copy_from_slice(&2u16.to_le_bytes());
+        let segments = [
+            (4u32, elf.len(), 0u32, notes.len()),
+            (1, elf.len() + notes.len(), 0x3ffb_2000, stack.len()),
+        ];
+        for (i, (ty, offset, addr, size)) in segments.iter().enumerate() {
+            let header = words(
+                &[
+                    (0, *ty),
+                    (4, *offset as u32),
+                    (8, *addr),
+                    (12, *addr),
+                    (16, *size as u32),
+                    (20, *size as u32),
+                ],
+                32,
+            );
+            elf[52 + i * 32..84 + i * 32].copy_from_slice(&header);
+        }
+        elf.extend(notes);
+        elf.extend(stack);
+        elf
+    }
+
+    #[test]
+    fn coredumps_are_parsed() {
+        let elf = core_file();
+        let len = HEADER_SIZE + elf.len() + 4;
+        let mut dump = words(&[(0, len as u32), (4, 0x0001_0100)], HEADER_SIZE);
+        dump.extend_from_slice(&elf);
+        dump.extend_from_slice(&crc32fast::hash(&dump).to_le_bytes());
+        dump.resize(0x1000, 0xff);
+
+        assert_eq!(dump_len(&dump).unwrap() as usize, len);
+        assert_eq!(extract_elf(&dump).unwrap(), elf.as_slice());
+        let coredump = Coredump::parse(&elf).unwrap();
+
+        assert_eq!(coredump.architecture, Architecture::Xtensa);
+        assert_eq!(coredump.crashed_task, Some(0x3ffb_1000));
+        assert_eq!(coredump.exception_registers, [("EXCCAUSE".to_string(), 29)]);
+        let frames: Vec<_> = coredump
+            .backtrace(&coredump.tasks[0])
+            .iter()
+            .map(|frame| frame.pc)
+            .collect();
+        assert_eq!(frames, [0x400d_1234, 0x400d_1ffd, 0x400d_2ffd]);
+
+        let mut corrupted = dump.clone();
+        corrupted[HEADER_SIZE + 30] ^= 1;
+        extract_elf(&corrupted).expect_err("Failed to reject a bad checksum");
+        assert!(matches!(dump_len(&[0xff; 20]), Err(Error::NoCoredump)));
+
+        // A SHA-256 digest leaving less than the header
+        let mut short = words(&[(0, 40), (4, 0x0001_0100)], 8);
+        short.extend_from_slice(&Sha256::digest(&short));
+        assert!(matches!(
+            extract_elf(&short),
+            Err(Error::InvalidCoredump(_))
+        ));
+    }
+}
@@ -0,0 +1,175 @@
+//! Reading and decoding efuses
+//!
+//! The efuses are one-time programmable bits holding the MAC address, chip
+//! revision, calibration data and the security settings of the chip. They are
+//! grouped into blocks, which are read word by word through the registers the
+//! efuse controller maps them to.
+//!
+//! Only a few well-known fields are decoded, see [`decode`]; the complete
+//! layout of every chip is documented in its technical reference manual.
+
+use crate::Chip;
+
+/// A block of efuses, mapped to consecutive registers
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct EfuseBlock {
+    pub name: &'static str,
+    /// Address of the register holding the first word of the block
+    pub addr: u32,
+    /// Number of 32 bit words in the block
+    pub words: u32,
+}
+
+const fn block(name: &'static str, addr: u32, words: u32) -> EfuseBlock {
+    EfuseBlock { name, addr, words }
+}
+
+const ESP32_BLOCKS: &[EfuseBlock] = &[
+    block("BLOCK0", 0x3ff5a000, 7),
+    block("BLOCK1", 0x3ff5a038, 8),
+    block("BLOCK2", 0x3ff5a058, 8),
+    block("BLOCK3", 0x3ff5a078, 8),
+];
+
+/// The ESP32-C2 only has four blocks, the last one holds the keys
+const ESP32C2_BLOCKS: &[EfuseBlock] = &[
+    block("BLOCK0", 0x6000882c, 2),
+    block("BLOCK1", 0x60008834, 3),
+    block("BLOCK2", 0x60008840, 8),
+    block("BLOCK_KEY0", 0x60008860, 8),
+];
+
+const ESP8266_BLOCKS: &[EfuseBlock] = &[block("EFUSE", 0x3ff00050, 4)];
+
+/// The chips derived from the ESP32-S2 share the same layout, only the base
+/// address of the efuse controller differs
+macro_rules! s2_blocks {
+    ($base:expr) => {
+        &[
+            block("BLOCK0", $base + 0x2c, 6),
+            block("BLOCK1", $base + 0x44, 6),
+            block("BLOCK2", $base + 0x5c, 8),
+            block("BLOCK3", $base + 0x7c, 8),
+            block("BLOCK_KEY0", $base + 0x9c, 8),
+            block("BLOCK_KEY1", $base + 0xbc, 8),
+            block("BLOCK_KEY2", $base + 0xdc, 8),
+            block("BLOCK_KEY3", $base + 0xfc, 8),
+            block("BLOCK_KEY4", $base + 0x11c, 8),
+            block("BLOCK_KEY5", $base + 0x13c, 8),
+            block("BLOCK10", $base + 0x15c, 8),
+        ]
+    };
+}
+
+const ESP32C3_BLOCKS: &[EfuseBlock] = s2_blocks!(0x60008800);
+const ESP32H2_BLOCKS: &[EfuseBlock] = s2_blocks!(0x600b0800);
+const ESP32S2_BLOCKS: &[EfuseBlock] = s2_blocks!(0x3f41a000);
+const ESP32S3_BLOCKS: &[EfuseBlock] = s2_blocks!(0x60007000);
+
+/// The efuse blocks of `chip`
+pub fn blocks(chip: Chip) -> &'static [EfuseBlock] {
+    match chip {
+        Chip::Esp32 => ESP32_BLOCKS,
+        Chip::Esp32c2 => ESP32C2_BLOCKS,
+        Chip::Esp32c3 => ESP32C3_BLOCKS,
+        Chip::Esp32h2 => ESP32H2_BLOCKS,
+        Chip::Esp32s2 => ESP32S2_BLOCKS,
+        Chip::Esp32s3 => ESP32S3_BLOCKS,
+        Chip::Esp8266 => ESP8266_BLOCKS,
+    }
+}
+
+/// Decode the security settings from the contents of the blocks of `chip`
+///
+/// `blocks` holds the words of each block returned by [`blocks`], in the same
+/// order. The fields are returned as name and value pairs, fields which aren't
+/// known for the chip are left out.
+pub fn decode(chip: Chip, blocks: &[Vec<u32>]) -> Vec<(&'static str, String)> {
+    let block0 = match blocks.first() {
+        Some(block0) => block0,
+        None => return Vec::new(),
+    };
+    let bits = |word: usize, shift: u32, len: u32| {
+        block0
+            .get(word)
+            .map(|word| (word >> shift) & ((1 << len) - 1))
+    };
+
+    let (crypt_cnt, secure_boot) = match chip {
+        Chip::Esp32 => {
+            let secure_boot = match (bits(6, 4, 1), bits(6, 5, 1)) {
+                (Some(1), _) => Some("enabled (v1)"),
+                (_, Some(1)) => Some("enabled (v2)"),
+                (Some(_), Some(_)) => Some("disabled"),
+                _ => None,
+            };
+            (bits(0, 20, 7), secure_boot)
+        }
+        Chip::Esp32c3 | Chip::Esp32h2 | Chip::Esp32s2 | Chip::Esp32s3 => {
+            let secure_boot = bits(3, 20, 1).map(|enabled| match enabled {
+                1 => "enabled",
+                _ => "disabled",
+            });
+            (bits(2, 18, 3), secure_boot)
+        }
+        Chip::Esp32c2 | Chip::Esp8266 => (None, None),
+    };
+
+    let mut fields = Vec::new();
+    if let Some(count) = crypt_cnt {
+        // Flash encryption is toggled by burning another bit of the counter
+        let state = if count.count_ones() % 2 == 1 {
+            "enabled"
+        } else {
+            "disabled"
+        };
+        fields.push((
+            "Flash encryption",
+            format!("{} (counter {:#x})", state, count),
+        ));
+    }
+    if let Some(state) = secure_boot {
+        fields.push(("Secure boot", state.to_string()));
+    }
+
+    fields
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn decodes_security_fields() {
+        let mut block0 = vec![0; 7];
+        block0[0] = 0b1 << 20;
+        block0[6] = 1 << 5;
+        assert_eq!(
+            decode(Chip::Esp32, &[block0]),
+            vec![
+                ("Flash encryption", "enabled (counter 0x1)".to_string()),
+                ("Secure boot", "enabled (v2)".to_string()),
+            ]
+        );
+
+        let mut block0 = vec![0; 6];
+        block0[2] = 0b11 << 18;
+        assert_eq!(
+            decode(Chip::Esp32c3, &[block0]),
+            vec![
+                ("Flash encryption", "disabled (counter 0x3)".to_string()),
+                ("Secure boot", "disabled".to_string()),
+            ]
+        );
+
+        assert!(decode(Chip::Esp8266, &[vec![0; 4]]).is_empty());
+        assert_eq!(blocks(Chip::Esp32s3)[1].addr, 0x60007044);
+
+        // The blocks of the ESP32-C2 follow each other, ending with the key block
+        let c2 = blocks(Chip::Esp32c2);
+        for pair in c2.windows(2) {
+            assert_eq!(pair[0].addr + pair[0].words * 4, pair[1].addr);
+        }
+        assert_eq!(c2.last().unwrap().name, "BLOCK_KEY0");
+    }
+}
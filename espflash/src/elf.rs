@@ -1,49 +1,155 @@
 use std::borrow::Cow;
 use std::cmp::Ordering;
+use std::convert::TryFrom;
 
 use crate::chip::Chip;
 use crate::error::{ElfError, Error};
 use crate::flasher::FlashSize;
+use crate::image_info::AppDescriptorSettings;
+use crate::metadata::FlashMetadata;
 use std::fmt::{Debug, Formatter};
 use std::mem::take;
 use std::ops::AddAssign;
+use std::str::FromStr;
+use strum::IntoEnumIterator;
+use strum_macros::Display;
+use xmas_elf::header::{Class, Machine};
 use xmas_elf::program::Type;
-use xmas_elf::sections::{SectionData, ShType};
+use xmas_elf::sections::{SectionData, ShType, SHN_LORESERVE};
 use xmas_elf::ElfFile;
 
 pub const ESP_CHECKSUM_MAGIC: u8 = 0xef;
+/// `e_machine` of Xtensa ELF files, which xmas_elf doesn't know
+const EM_XTENSA: u16 = 94;
 
-#[derive(Copy, Clone)]
-#[allow(dead_code)]
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Display)]
 pub enum FlashMode {
+    #[strum(serialize = "qio")]
     Qio,
+    #[strum(serialize = "qout")]
     Qout,
+    #[strum(serialize = "dio")]
     Dio,
+    #[strum(serialize = "dout")]
     Dout,
 }
 
-#[derive(Copy, Clone)]
+impl FromStr for FlashMode {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_lowercase().as_str() {
+            "qio" => Ok(FlashMode::Qio),
+            "qout" => Ok(FlashMode::Qout),
+            "dio" => Ok(FlashMode::Dio),
+            "dout" => Ok(FlashMode::Dout),
+            _ => Err(Error::InvalidMetadata(format!(
+                "unknown flash mode `{}`",
+                s
+            ))),
+        }
+    }
+}
+
+serde_plain::derive_deserialize_from_fromstr!(FlashMode, "a flash mode like `dio`");
+serde_plain::derive_serialize_from_display!(FlashMode);
+
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Display)]
 #[repr(u8)]
-#[allow(dead_code)]
 pub enum FlashFrequency {
+    #[strum(serialize = "40M")]
     Flash40M = 0,
+    #[strum(serialize = "26M")]
     Flash26M = 1,
+    #[strum(serialize = "20M")]
     Flash20M = 2,
+    #[strum(serialize = "80M")]
     Flash80M = 0xf,
 }
 
+impl FromStr for FlashFrequency {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s.to_ascii_uppercase().as_str() {
+            "40M" => Ok(FlashFrequency::Flash40M),
+            "26M" => Ok(FlashFrequency::Flash26M),
+            "20M" => Ok(FlashFrequency::Flash20M),
+            "80M" => Ok(FlashFrequency::Flash80M),
+            _ => Err(Error::InvalidMetadata(format!(
+                "unknown flash frequency `{}`",
+                s
+            ))),
+        }
+    }
+}
+
+serde_plain::derive_deserialize_from_fromstr!(FlashFrequency, "a flash frequency like `40m`");
+serde_plain::derive_serialize_from_display!(FlashFrequency);
+
+/// Flash settings to write to the image header, instead of the ones of the
+/// image
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlashSettings {
+    pub mode: Option<FlashMode>,
+    pub frequency: Option<FlashFrequency>,
+    pub size: Option<FlashSize>,
+    /// Lowest revision of the chip the image runs on
+    pub min_chip_rev: Option<u8>,
+}
+
+/// Offsets to use instead of the default flash layout of the chip
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlashLayout {
+    pub bootloader_offset: Option<u32>,
+    /// Like `CONFIG_PARTITION_TABLE_OFFSET` of ESP-IDF
+    pub partition_table_offset: Option<u32>,
+    pub app_offset: Option<u32>,
+}
+
 pub struct FirmwareImage<'a> {
     pub entry: u32,
     pub elf: ElfFile<'a>,
     pub flash_mode: FlashMode,
     pub flash_size: FlashSize,
     pub flash_frequency: FlashFrequency,
+    /// Lowest revision of the chip the image runs on, written to its header
+    pub min_chip_rev: u8,
+    /// Flash settings embedded in the image
+    pub metadata: FlashMetadata,
+    /// Fields to replace in the application description of ESP-IDF images
+    pub app_descriptor: AppDescriptorSettings,
+    /// Offsets of the bootloader, the partition table and the application
+    pub flash_layout: FlashLayout,
+    /// ESP-IDF release of the bundled bootloader to use, instead of the newest
+    /// one supporting the chip revision
+    pub bootloader_release: Option<String>,
 }
 
 impl<'a> FirmwareImage<'a> {
+    /// Parse an image from the raw contents of an ELF file
+    ///
+    /// `xmas_elf` trusts the offsets and sizes in the headers and panics if they
+    /// are out of bounds, so they are all verified here before the image is used.
+    /// Any malformed input results in an error instead.
     pub fn from_data(data: &'a [u8]) -> Result<Self, Error> {
         let elf = ElfFile::new(data).map_err(ElfError::from)?;
-        Ok(Self::from_elf(elf))
+        check_bounds(&elf)?;
+        let metadata = FlashMetadata::from_elf(&elf)?;
+
+        let mut image = Self::from_elf(elf);
+        if let Some(mode) = metadata.flash_mode {
+            image.flash_mode = mode;
+        }
+        if let Some(size) = metadata.flash_size {
+            image.flash_size = size;
+        }
+        if let Some(frequency) = metadata.flash_frequency {
+            image.flash_frequency = frequency;
+        }
+        image.metadata = metadata;
+
+        Ok(image)
     }
 
     pub fn from_elf(elf: ElfFile<'a>) -> Self {
@@ -53,9 +159,85 @@ impl<'a> FirmwareImage<'a> {
             flash_mode: FlashMode::Dio,
             flash_size: FlashSize::Flash4Mb,
             flash_frequency: FlashFrequency::Flash40M,
+            min_chip_rev: 0,
+            metadata: FlashMetadata::default(),
+            app_descriptor: AppDescriptorSettings::default(),
+            flash_layout: FlashLayout::default(),
+            bootloader_release: None,
         }
     }
 
+    /// Override the flash settings of the image with the ones given in `settings`
+    pub fn apply_flash_settings(&mut self, settings: FlashSettings) {
+        if let Some(mode) = settings.mode {
+            self.flash_mode = mode;
+        }
+        if let Some(frequency) = settings.frequency {
+            self.flash_frequency = frequency;
+        }
+        if let Some(size) = settings.size {
+            self.flash_size = size;
+        }
+        if let Some(revision) = settings.min_chip_rev {
+            self.min_chip_rev = revision;
+        }
+    }
+
+    /// Verify that the image can be used with `chip`
+    ///
+    /// The chip given in the image metadata is used if there is one. Otherwise
+    /// the architecture and the entry point of the ELF file have to match the
+    /// chip, which catches images built for another one before they end up in
+    /// a boot loop.
+    pub fn check_chip(&self, chip: Chip) -> Result<(), Error> {
+        if let Some(expected) = self.metadata.chip {
+            if expected != chip {
+                return Err(Error::ChipMismatch(expected, chip));
+            }
+            return Ok(());
+        }
+
+        let riscv = match self.elf.header.pt2.machine().as_machine() {
+            Machine::RISC_V => Some(true),
+            Machine::Other(EM_XTENSA) => Some(false),
+            _ => None,
+        };
+        let entry = self.entry();
+        let runs_on =
+            |chip: Chip| riscv == Some(chip.is_riscv()) && chip.addr_is_entry_point(entry);
+        if runs_on(chip) {
+            return Ok(());
+        }
+
+        // Name the chip the image is built for if that is unambiguous, the
+        // memory maps of the Xtensa chips overlap, so the chip with the most
+        // segments in its flash or internal RAM is taken
+        let mapped = |chip: Chip| {
+            self.segments()
+                .filter(|segment| chip.addr_is_entry_point(segment.addr))
+                .count()
+        };
+        let mut candidates: Vec<_> = Chip::iter()
+            .filter(|&chip| runs_on(chip))
+            .map(|chip| (mapped(chip), chip))
+            .collect();
+        candidates.sort_by_key(|&(mapped, _)| std::cmp::Reverse(mapped));
+        let built_for = match (candidates.as_slice(), riscv) {
+            ([(_, candidate)], _) => candidate.to_string(),
+            ([(first, candidate), (second, _), ..], _) if first > second => candidate.to_string(),
+            (_, Some(true)) => format!("a RISC-V chip with the entry point {:#010x}", entry),
+            (_, Some(false)) => format!("an Xtensa chip with the entry point {:#010x}", entry),
+            (_, None) => "an architecture other than Xtensa or RISC-V".into(),
+        };
+        let targets: Vec<_> = chip
+            .supported_targets()
+            .iter()
+            .map(|target| format!("`{}`", target))
+            .collect();
+
+        Err(Error::ElfChipMismatch(built_for, chip, targets.join(", ")))
+    }
+
     pub fn entry(&self) -> u32 {
         self.elf.header.pt2.entry_point() as u32
     }
@@ -87,9 +269,10 @@ impl<'a> FirmwareImage<'a> {
             })
             .flat_map(move |header| {
                 let addr = header.physical_addr() as u32;
-                let from = header.offset() as usize;
-                let to = header.offset() as usize + header.file_size() as usize;
-                let data = &self.elf.input[from..to];
+                let data = self
+                    .elf
+                    .input
+                    .get(range(header.offset(), header.file_size())?)?;
                 Some(CodeSegment::new(addr, data))
             })
     }
@@ -103,6 +286,48 @@ impl<'a> FirmwareImage<'a> {
         self.segments()
             .filter(move |segment| !chip.addr_is_flash(segment.addr))
     }
+
+    /// The RAM segments to load one at a time into the chip
+    ///
+    /// Adjacent segments are merged, and segments crossing the boundary of the
+    /// IRAM or DRAM are split, so every segment is written to a single memory
+    /// region.
+    pub fn ram_load_segments(&'a self, chip: Chip) -> Vec<CodeSegment<'a>> {
+        let (iram, dram) = (chip.iram_range(), chip.dram_range());
+        let boundaries = [iram.start, iram.end, dram.start, dram.end];
+        let region = |addr: u32| (iram.contains(&addr), dram.contains(&addr));
+
+        let mut segments: Vec<_> = self.ram_segments(chip).collect();
+        segments.sort();
+
+        let mut merged: Vec<CodeSegment<'a>> = Vec::new();
+        for segment in segments {
+            match merged.last_mut() {
+                Some(last)
+                    if last.addr + last.size() == segment.addr
+                        && region(last.addr) == region(segment.addr) =>
+                {
+                    *last += segment.data()
+                }
+                _ => merged.push(segment),
+            }
+        }
+
+        let mut split = Vec::new();
+        for mut segment in merged {
+            while let Some(boundary) = boundaries
+                .iter()
+                .copied()
+                .filter(|&b| b > segment.addr && b < segment.addr + segment.size())
+                .min()
+            {
+                split.push(segment.split_off((boundary - segment.addr) as usize));
+            }
+            split.push(segment);
+        }
+
+        split
+    }
 }
 
 #[derive(Eq, Clone, Default)]
@@ -139,12 +364,12 @@ impl<'a> CodeSegment<'a> {
                 addr: self.addr,
                 data: head,
             };
-            self.addr += count as u32;
+            self.addr = self.addr.wrapping_add(count as u32);
             self.data = tail;
             new
         } else {
             let new = self.clone();
-            self.addr += self.size();
+            self.addr = self.addr.wrapping_add(self.size());
             self.data = Cow::Borrowed(&[]);
             new
         }
@@ -158,6 +383,10 @@ impl<'a> CodeSegment<'a> {
         self.data.as_ref()
     }
 
+    pub fn data_mut(&mut self) -> &mut [u8] {
+        self.data.to_mut()
+    }
+
     pub fn pad_align(&mut self, align: usize) {
         let padding = (align - self.data.len() % align) % align;
         if padding > 0 {
@@ -242,6 +471,61 @@ impl<'a> From<CodeSegment<'a>> for RomSegment<'a> {
     }
 }
 
+/// Byte range of `size` bytes at `offset` in the input, if it doesn't overflow
+pub(crate) fn range(offset: u64, size: u64) -> Option<std::ops::Range<usize>> {
+    let start = usize::try_from(offset).ok()?;
+    let end = usize::try_from(offset.checked_add(size)?).ok()?;
+    Some(start..end)
+}
+
+/// Verify that all headers, and the data of all sections, lie within the input
+pub(crate) fn check_bounds(elf: &ElfFile) -> Result<(), ElfError> {
+    let len = elf.input.len();
+    let in_bounds = |offset: u64, size: u64| matches!(range(offset, size), Some(r) if r.end <= len);
+    let pt2 = &elf.header.pt2;
+
+    // Sizes of a section header and a program header
+    let (sh_size, ph_size) = match elf.header.pt1.class() {
+        Class::ThirtyTwo => (40, 32),
+        _ => (64, 56),
+    };
+
+    if pt2.sh_count() > 0 {
+        if pt2.sh_count() >= SHN_LORESERVE || pt2.sh_entry_size() < sh_size {
+            return Err(ElfError::from("Invalid section header table"));
+        }
+        let table_size = pt2.sh_count() as u64 * pt2.sh_entry_size() as u64;
+        if !in_bounds(pt2.sh_offset(), table_size) {
+            return Err(ElfError::from("Section header table is out of bounds"));
+        }
+        if pt2.sh_str_index() >= pt2.sh_count() {
+            return Err(ElfError::from("Invalid section name table index"));
+        }
+    }
+
+    if pt2.ph_count() > 0 && pt2.ph_offset() > 0 && pt2.ph_entry_size() > 0 {
+        if pt2.ph_entry_size() < ph_size {
+            return Err(ElfError::from("Invalid program header table"));
+        }
+        let table_size = pt2.ph_count() as u64 * pt2.ph_entry_size() as u64;
+        if !in_bounds(pt2.ph_offset(), table_size) {
+            return Err(ElfError::from("Program header table is out of bounds"));
+        }
+    }
+
+    for header in elf.section_iter() {
+        let has_data = !matches!(
+            header.get_type(),
+            Ok(ShType::Null) | Ok(ShType::NoBits) | Err(_)
+        );
+        if has_data && !in_bounds(header.offset(), header.size()) {
+            return Err(ElfError::from("Section data is out of bounds"));
+        }
+    }
+
+    Ok(())
+}
+
 pub fn update_checksum(data: &[u8], mut checksum: u8) -> u8 {
     for byte in data {
         checksum ^= *byte;
@@ -256,7 +540,7 @@ pub fn merge_adjacent_segments(mut segments: Vec<CodeSegment>) -> Vec<CodeSegmen
     let mut merged: Vec<CodeSegment> = Vec::with_capacity(segments.len());
     for segment in segments {
         match merged.last_mut() {
-            Some(last) if last.addr + last.size() == segment.addr => {
+            Some(last) if last.addr.checked_add(last.size()) == Some(segment.addr) => {
                 *last += segment.data();
             }
             _ => {
@@ -267,3 +551,44 @@ pub fn merge_adjacent_segments(mut segments: Vec<CodeSegment>) -> Vec<CodeSegmen
 
     merged
 }
+
+#[cfg(test)]
+mod tests {
+    use std::fs::read;
+
+    use super::*;
+
+    #[test]
+    fn checks_the_chip_of_images() {
+        let data = read("./tests/data/esp32").unwrap();
+        let image = FirmwareImage::from_data(&data).unwrap();
+        assert!(image.check_chip(Chip::Esp32).is_ok());
+        assert_eq!(
+            image.check_chip(Chip::Esp32c3).unwrap_err().to_string(),
+            "The elf image is built for ESP32, but the device is ESP32-C3"
+        );
+        assert!(image.check_chip(Chip::Esp32s3).is_err());
+
+        let data = read("./tests/data/esp8266").unwrap();
+        let image = FirmwareImage::from_data(&data).unwrap();
+        assert!(image.check_chip(Chip::Esp8266).is_ok());
+        assert!(image.check_chip(Chip::Esp32c2).is_err());
+    }
+
+    #[test]
+    fn ram_segments_stay_within_a_region() {
+        let data = read("./tests/data/esp32").unwrap();
+        let image = FirmwareImage::from_data(&data).unwrap();
+        let segments = image.ram_load_segments(Chip::Esp32);
+
+        let total: u32 = image.ram_segments(Chip::Esp32).map(|s| s.size()).sum();
+        assert_eq!(segments.iter().map(|s| s.size()).sum::<u32>(), total);
+        for segment in &segments {
+            let last = segment.addr + segment.size() - 1;
+            for region in [Chip::Esp32.iram_range(), Chip::Esp32.dram_range()] {
+                assert_eq!(region.contains(&segment.addr), region.contains(&last));
+            }
+        }
+        assert!(segments.windows(2).all(|pair| pair[0].addr < pair[1].addr));
+    }
+}
@@ -1,9 +1,9 @@
 use std::io::Write;
 
-const END: u8 = 0xC0;
-const ESC: u8 = 0xDB;
-const ESC_END: u8 = 0xDC;
-const ESC_ESC: u8 = 0xDD;
+pub(crate) const END: u8 = 0xC0;
+pub(crate) const ESC: u8 = 0xDB;
+pub(crate) const ESC_END: u8 = 0xDC;
+pub(crate) const ESC_ESC: u8 = 0xDD;
 
 pub struct SlipEncoder<'a, W: Write> {
     writer: &'a mut W,
@@ -1,126 +1,706 @@
+// The source code and spans of the partition table errors are only shown by
+// their diagnostics
+#![cfg_attr(not(feature = "miette"), allow(dead_code))]
+
 use std::{
     fmt::{Display, Formatter},
     io,
+    path::PathBuf,
 };
 
-use miette::{Diagnostic, SourceOffset, SourceSpan};
+#[cfg(feature = "miette")]
+use miette::{Diagnostic, Report, SourceSpan};
+use serde::Serialize;
 use slip_codec::SlipError;
+#[cfg(feature = "miette")]
 use strum::VariantNames;
 use thiserror::Error;
 
 use crate::{
     command::CommandType,
+    flasher::FlashSize,
     image_format::ImageFormatId,
-    partition_table::{SubType, Type},
+    partition_table::{SubType, Type, UNKNOWN_SUB_TYPE},
     Chip,
 };
 
-#[derive(Error, Debug, Diagnostic)]
+#[derive(Error, Debug)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
 #[non_exhaustive]
 pub enum Error {
     #[error("Error while connecting to device")]
-    #[diagnostic(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
     Connection(#[source] ConnectionError),
     #[error("Communication error while flashing device")]
-    #[diagnostic(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
     Flashing(#[source] ConnectionError),
     #[error("Supplied elf image is not valid")]
-    #[diagnostic(
-        code(espflash::invalid_elf),
-        help("Try running `cargo clean` and rebuilding the image")
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::invalid_elf),
+            help("Try running `cargo clean` and rebuilding the image")
+        )
     )]
     InvalidElf(#[from] ElfError),
     #[error("Supplied elf image can not be ran from ram as it includes segments mapped to rom addresses")]
-    #[diagnostic(
+    #[cfg_attr(feature = "miette", diagnostic(
         code(espflash::not_ram_loadable),
         help("Either build the binary to be all in ram or remove the `--ram` option to load the image to flash")
-    )]
+    ))]
     ElfNotRamLoadable,
+    #[error("The segment of {1:#x} bytes at {0:#010x} overlaps the flasher stub running in RAM")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::ram_segment_overlaps_stub),
+        help("Load the image to RAM without `--use-stub`, the ROM bootloader writes the memory by itself")
+    ))]
+    RamSegmentOverlapsStub(u32, u32),
     #[error("The bootloader returned an error")]
-    #[diagnostic(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
     RomError(#[from] RomError),
-    #[error("Chip not recognized, supported chip types are esp32, esp32-c3, esp32-s2, esp32-s3, and esp8266")]
-    #[diagnostic(code(espflash::unrecognized_chip))]
+    #[error("Chip not recognized, supported chip types are esp32, esp32-c2, esp32-c3, esp32-h2, esp32-s2, esp32-s3, and esp8266")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::unrecognized_chip)))]
     UnrecognizedChipName,
-    #[error("Chip not recognized, supported chip types are esp32, esp32-c3, esp32-s2, esp32-s3, and esp8266")]
-    #[diagnostic(
-        code(espflash::unrecognized_chip),
-        help("If your chip is supported, try hard-resetting the device and try again")
+    #[error("Chip not recognized, supported chip types are esp32, esp32-c2, esp32-c3, esp32-h2, esp32-s2, esp32-s3, and esp8266")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::unrecognized_chip),
+            help("If your chip is supported, try hard-resetting the device and try again")
+        )
     )]
     UnrecognizedChip(#[from] ChipDetectError),
     #[error("Flash chip not supported, flash sizes from 1 to 16MB are supported")]
-    #[diagnostic(code(espflash::unrecognized_flash))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::unrecognized_flash)))]
     UnsupportedFlash(#[from] FlashDetectError),
     #[error("Failed to connect to on-device flash")]
-    #[diagnostic(code(espflash::flash_connect))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::flash_connect)))]
     FlashConnect,
     #[error(transparent)]
-    #[diagnostic(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
     MalformedPartitionTable(#[from] PartitionTableError),
     #[error(transparent)]
-    #[diagnostic(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
     UnsupportedImageFormat(#[from] UnsupportedImageFormatError),
     #[error("Unrecognized image format {0}")]
-    #[diagnostic(
+    #[cfg_attr(feature = "miette", diagnostic(
         code(espflash::unknown_format),
         help("The following image formats are {}", ImageFormatId::VARIANTS.join(", "))
-    )]
+    ))]
     UnknownImageFormat(String),
     #[error("binary is not setup correct to support direct boot")]
-    #[diagnostic(
+    #[cfg_attr(feature = "miette", diagnostic(
         code(espflash::invalid_direct_boot),
         help(
             "See the following page for documentation on how to setup your binary for direct boot:
 https://github.com/espressif/esp32c3-direct-boot-example"
         )
-    )]
+    ))]
     InvalidDirectBootBinary,
     #[error("No serial port specified in arguments or config")]
-    #[diagnostic(
-        code(cargo_espflash::no_serial),
-        help("Add a command line option with the serial port to use")
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(cargo_espflash::no_serial),
+            help("Add a command line option with the serial port to use")
+        )
     )]
     NoSerial,
     #[error("Canceled by user")]
     Canceled,
+    #[error("Operation did not complete within {0} seconds")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::operation_timeout),
+        help("Ensure that the device is connected and responding, or raise the limit using `--timeout`")
+    ))]
+    OperationTimeout(u64),
+    #[error("Test failed, the device printed `{0}`")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::test_failed)))]
+    TestFailed(String),
+    #[error("Invalid capture file: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::invalid_capture)))]
+    InvalidCapture(&'static str),
+    #[error("Invalid Intel HEX file, line {0}: {1}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::invalid_hex)))]
+    InvalidHex(usize, &'static str),
+    #[error("Reading flash is not supported on {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::read_flash_unsupported)))]
+    ReadFlashUnsupported(Chip),
+    #[error("The data read from flash doesn't match what the flasher stub sent")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::read_flash_corrupted),
+            help("Try reading again, at a lower baud rate if the error persists")
+        )
+    )]
+    ReadFlashCorrupted,
+    #[error("Invalid flash metadata in elf image: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::invalid_metadata)))]
+    InvalidMetadata(String),
+    #[error("The elf image is built for {0}, but the device is {1}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::chip_mismatch)))]
+    ChipMismatch(Chip, Chip),
+    #[error("The elf image is built for {0}, but the device is {1}")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::chip_mismatch),
+            help("Build the application for the {1}, with one of the targets {2}")
+        )
+    )]
+    ElfChipMismatch(String, Chip, String),
+    #[error("The board {0} has an {1}, but the device is {2}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::chip_mismatch)))]
+    BoardChipMismatch(String, Chip, Chip),
+    #[error("Unknown board {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::unknown_board),
+        help("The known boards are: {1}, more can be added to the `[boards]` table of the configuration")
+    ))]
+    UnknownBoard(String, String),
+    #[error("The ESP-IDF build is for {0}, but the device is {1}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::chip_mismatch)))]
+    IdfChipMismatch(Chip, Chip),
+    #[error("Invalid ESP-IDF build: {0}")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::invalid_idf_build),
+            help("Pass the build directory of the project, after building it with `idf.py build`")
+        )
+    )]
+    InvalidIdfBuild(String),
+    #[error("The elf image requests the app partition `{0}`, which is not in the partition table")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::missing_app_partition),
+            help("Add the partition to the partition table or remove it from the elf image")
+        )
+    )]
+    MissingAppPartition(String),
+    #[error("{0} doesn't use a partition table")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::no_partition_table)))]
+    NoPartitionTable(Chip),
+    #[error("The partition `{0}` is not in the partition table")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::missing_partition)))]
+    MissingPartition(String),
+    #[error("The {1:#x} bytes of data don't fit into the partition `{0}` of {2:#x} bytes")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::partition_too_small)))]
+    PartitionTooSmall(String, usize, u32),
+    #[error("The application image of {1:#x} bytes doesn't fit into the app partition `{0}` of {2:#x} bytes")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::app_too_large),
+        help("Use a partition table with a larger app partition with `--partition-table`, or reduce the size of the application")
+    ))]
+    AppTooLarge(String, usize, u32),
+    #[error("The image needs {0:#x} bytes of flash, more than the {1} flash has")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::image_too_large),
+            help("Pass the size of the flash with `--flash-size` if it is larger")
+        )
+    )]
+    ImageTooLarge(u32, FlashSize),
+    #[error("The RAM segments of {0:#x} bytes overlap the irom segment at {1:#x}")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::irom_overlap),
+        help("Link the irom section to a higher address, the RAM segments are stored at the start of the flash")
+    ))]
+    IromOverlap(u32, u32),
+    #[error(
+        "The irom segment at {0:#x} is linked for neither OTA slot of a module with {1} of flash"
+    )]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::invalid_irom_address),
+        help("Link `user1.bin` for 0x40201010, and `user2.bin` for 0x40241010 with 512KB, 0x40281010 with 1MB and 0x40201010 with more flash, or pass the size of the flash with `--flash-size`")
+    ))]
+    InvalidIromAddress(u32, FlashSize),
+    #[error("The region of {1:#x} bytes at {0:#x} is not aligned to flash sectors")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::unaligned_erase_region),
+        help("Flash is erased in sectors of 4096 bytes, both the offset and the size have to be multiples of that")
+    ))]
+    UnalignedEraseRegion(u32, u32),
+    #[error("The partition table has no otadata partition")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::missing_otadata_partition),
+        help("Add an otadata partition to the partition table or remove the `--update-otadata` option")
+    ))]
+    MissingOtaDataPartition,
+    #[error("`{0}` matches multiple serial ports: {1}")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::ambiguous_serial),
+            help("Specify the serial port by its name instead")
+        )
+    )]
+    AmbiguousSerialPort(String, String),
+    #[error("Multiple known serial ports were found: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::multiple_serial),
+        help("Specify the serial port to use, or select it interactively by leaving out `--no-interactive`")
+    ))]
+    MultipleSerialPorts(String),
+    #[error("Serial port {0} is already in use")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::serial_in_use),
+            help(
+                "Close any other program using the port, such as a serial monitor, and try again"
+            )
+        )
+    )]
+    SerialPortInUse(String),
+    #[error("The region of {1:#x} bytes at {0:#x} is outside of the {2} flash")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(code(espflash::flash_region_out_of_range))
+    )]
+    FlashRegionOutOfRange(u32, u32, FlashSize),
+    #[error("The {1} registers starting at {0:#x} run past the end of the address space")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(code(espflash::register_range_out_of_range))
+    )]
+    RegisterRangeOutOfRange(u32, u32),
+    #[error("Verifying flash is not supported on {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::verify_unsupported)))]
+    VerifyUnsupported(Chip),
+    #[error("Verification of the data written at {0:#x} failed")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::verify_failed),
+            help("The flash may be worn out or write protected, try flashing again")
+        )
+    )]
+    VerifyFailed(u32),
+    #[error("Invalid flasher stub: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::invalid_stub)))]
+    InvalidStub(String),
+    #[error("Invalid sector cache: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::invalid_sector_cache)))]
+    InvalidSectorCache(String),
+    #[error("No bootloader of ESP-IDF {0} is bundled for the {1}")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::bootloader_not_found),
+        help("The bundled releases are: {2}, pass the bootloader of other releases using the `--bootloader` option")
+    ))]
+    BootloaderNotFound(String, Chip, String),
+    #[error("The flasher stub did not start")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::stub_not_running),
+            help("Make sure the flasher stub matches the chip, or remove the `--use-stub` option")
+        )
+    )]
+    StubNotRunning,
+    #[error("No bundled bootloader supports this revision of the {0}")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::no_default_bootloader),
+            help("Pass a bootloader built with ESP-IDF using the `--bootloader` option")
+        )
+    )]
+    NoDefaultBootloader(Chip),
+    #[error("Failed to write to the log file {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::log_file)))]
+    LogFile(PathBuf, #[source] io::Error),
+    #[error("The device crashed: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::device_crashed)))]
+    DeviceCrashed(String),
+    #[error("No defmt data found in the ELF image")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::no_defmt_data),
+        help("The application has to use defmt and the `.defmt` section must be kept by the linker, to monitor without flashing pass the image with `--elf`")
+    ))]
+    NoDefmtData,
+    #[error("Can't use GPIO {0} to reset the device")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::gpio_unsupported),
+            help("GPIO pins can only replace the DTR and RTS lines on Linux, and the CBUS pins of FTDI adapters when espflash is built with the `ftdi` feature")
+        )
+    )]
+    GpioUnsupported(String),
+    #[error("Flashing failed on {0} of {1} devices")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::devices_failed)))]
+    DevicesFailed(usize, usize),
+    #[error("The segment at {0:#x} overlaps the previous one, which ends at {1:#x}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::overlapping_segments)))]
+    OverlappingSegments(u32, u32),
+    #[error("Invalid filesystem parameters: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::invalid_fs_config)))]
+    InvalidFsConfig(&'static str),
+    #[error("The file name `{0}` is too long, the filesystem allows at most {1} bytes")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::fs_name_too_long)))]
+    FsNameTooLong(String, usize),
+    #[error("The path `{0}` names more than one file or directory")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::fs_path_conflict)))]
+    FsPathConflict(String),
+    #[error("The files don't fit into the filesystem of {0:#x} bytes")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::fs_full),
+            help("Use a larger partition or leave some of the files out")
+        )
+    )]
+    FsFull(u32),
+    #[error("Invalid NVS definition: {0}")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::invalid_nvs),
+            help("Rows have the `key,type,encoding,value` format of nvs_partition_gen.py")
+        )
+    )]
+    InvalidNvs(String),
+    #[error("The values don't fit into the NVS partition of {0:#x} bytes")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::nvs_full),
+            help("Use a larger partition, one of its pages is always kept free")
+        )
+    )]
+    NvsFull(u32),
+    #[error("Invalid signing key: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::invalid_signing_key),
+        help("Pass an unencrypted RSA-3072, ECDSA P-256 or ECDSA P-192 private key in the PEM format, eg generated with `espsecure.py generate_signing_key`")
+    ))]
+    InvalidSigningKey(String),
+    #[error("Failed to sign the image: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::signing_failed)))]
+    SigningFailed(String),
+    #[error("Invalid application image: {0}")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::invalid_image),
+            help("Pass an image generated with `save-image` or by ESP-IDF, or an ELF file")
+        )
+    )]
+    InvalidImage(String),
+    #[error("Can't change the application description: {0}")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::invalid_app_descriptor),
+            help("Only ESP-IDF applications contain an application description")
+        )
+    )]
+    InvalidAppDescriptor(String),
+    #[error("Invalid flash layout: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::invalid_flash_layout),
+        help("Check `--bootloader-offset`, `--partition-table-offset` and `--app-offset` against the partition table")
+    ))]
+    InvalidFlashLayout(String),
+    #[error("Invalid Secure Boot signature: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::invalid_signature)))]
+    InvalidSignature(String),
+    #[error("Flash encryption is not supported by the {0}")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(code(espflash::encryption_not_supported))
+    )]
+    EncryptionNotSupported(Chip),
+    #[error("The ROM loader of the {0} can't write encrypted data")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::encryption_requires_stub),
+            help("Load the flasher stub with `--use-stub`")
+        )
+    )]
+    EncryptionRequiresStub(Chip),
+    #[error("Images in this format can't be signed")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::signing_not_supported),
+            help("Secure Boot v2 requires the `bootloader` image format")
+        )
+    )]
+    SigningNotSupported,
+    #[error("Debugging the {0} is not supported")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::debug_not_supported)))]
+    DebugNotSupported(Chip),
+    #[error("The coredump partition is empty")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::no_coredump),
+            help("Core dumps are only saved with `CONFIG_ESP_COREDUMP_ENABLE_TO_FLASH` enabled")
+        )
+    )]
+    NoCoredump,
+    #[error("The core dump uses the binary format, which is not supported")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::unsupported_coredump_format),
+            help("Enable `CONFIG_ESP_COREDUMP_DATA_FORMAT_ELF` to save core dumps as ELF files")
+        )
+    )]
+    UnsupportedCoredumpFormat,
+    #[error("Invalid core dump: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::invalid_coredump)))]
+    InvalidCoredump(String),
+    #[error("Invalid manifest: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::invalid_manifest),
+        help("List the binaries as `[[bin]]` tables with a `path` and either an `offset` or a `partition`")
+    ))]
+    InvalidManifest(String),
+    #[error("{0} `--then` actions were given for {1} `--expect` patterns")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::unmatched_trigger_action),
+        help("Every `--then` action belongs to the `--expect` pattern given in the same position")
+    ))]
+    UnmatchedTriggerAction(usize, usize),
+    #[error("The image requires chip revision {0} or newer, but the device is revision {1}")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::chip_revision_too_old),
+        help("Flash the image to a device with a newer chip, or build it for this revision and lower `--min-chip-rev`")
+    ))]
+    ChipRevisionTooOld(u32, u32),
+    #[error("Building the application with cargo failed")]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::build_failed)))]
+    BuildFailed,
+    #[error("No executable artifact found")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::no_artifact),
+        help("If you're trying to run an example you need to specify it using the `--example` argument\n\
+              or if you're in a cargo workspace, specify the binary package with `--package`.")
+    ))]
+    NoArtifact,
+    #[error("Multiple build artifacts found")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::multiple_artifacts),
+        help("Select the binary to flash with `--bin`, or in a cargo workspace the package of it with `--package`")
+    ))]
+    MultipleArtifacts,
+}
+
+/// Broad kind of an error, for scripts to react to failures
+///
+/// Every category has a process exit code, which is stable across releases.
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Serialize)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorCategory {
+    /// Any error which isn't covered by another category
+    Other = 1,
+    /// The serial port or the device can't be reached, or stopped responding
+    Connection = 2,
+    /// The image can't be built from the ELF file
+    Image = 3,
+    /// The flash contents don't match what was written
+    Verification = 4,
+    /// The device doesn't support the operation or reported an error
+    Device = 5,
+    /// The partition table is invalid or lacks a required partition
+    PartitionTable = 6,
+    /// An input file or option is invalid
+    Input = 7,
+    /// The application failed its test, crashed or left no core dump
+    Application = 8,
+    /// The operation didn't complete within `--timeout`
+    Timeout = 124,
+    /// The operation was canceled by the user
+    Canceled = 130,
+}
+
+impl ErrorCategory {
+    pub fn exit_code(self) -> i32 {
+        self as i32
+    }
+
+    /// Category of the first error in the chain of `report` which espflash
+    /// knows about
+    #[cfg(feature = "miette")]
+    pub fn of(report: &Report) -> Option<Self> {
+        report.chain().find_map(|error| {
+            if let Some(error) = error.downcast_ref::<Error>() {
+                Some(error.category())
+            } else if error.is::<ConnectionError>() {
+                Some(ErrorCategory::Connection)
+            } else if error.is::<RomError>() {
+                Some(ErrorCategory::Device)
+            } else if error.is::<PartitionTableError>() {
+                Some(ErrorCategory::PartitionTable)
+            } else {
+                None
+            }
+        })
+    }
+}
+
+impl Error {
+    pub fn category(&self) -> ErrorCategory {
+        use ErrorCategory::*;
+
+        match self {
+            Error::Connection(_)
+            | Error::Flashing(_)
+            | Error::UnrecognizedChip(_)
+            | Error::FlashConnect
+            | Error::NoSerial
+            | Error::AmbiguousSerialPort(..)
+            | Error::MultipleSerialPorts(_)
+            | Error::SerialPortInUse(_)
+            | Error::StubNotRunning
+            | Error::ReadFlashCorrupted => Connection,
+            Error::InvalidElf(_)
+            | Error::ElfNotRamLoadable
+            | Error::RamSegmentOverlapsStub(..)
+            | Error::UnsupportedImageFormat(_)
+            | Error::InvalidDirectBootBinary
+            | Error::InvalidMetadata(_)
+            | Error::AppTooLarge(..)
+            | Error::ImageTooLarge(..)
+            | Error::IromOverlap(..)
+            | Error::InvalidIromAddress(..)
+            | Error::OverlappingSegments(..)
+            | Error::InvalidImage(_)
+            | Error::InvalidAppDescriptor(_)
+            | Error::NoDefmtData
+            | Error::SigningNotSupported
+            | Error::SigningFailed(_)
+            | Error::BuildFailed
+            | Error::NoArtifact
+            | Error::MultipleArtifacts => Image,
+            Error::VerifyFailed(_) | Error::InvalidSignature(_) => Verification,
+            Error::RomError(_)
+            | Error::UnsupportedFlash(_)
+            | Error::ReadFlashUnsupported(_)
+            | Error::ChipMismatch(..)
+            | Error::ElfChipMismatch(..)
+            | Error::BoardChipMismatch(..)
+            | Error::IdfChipMismatch(..)
+            | Error::NoPartitionTable(_)
+            | Error::FlashRegionOutOfRange(..)
+            | Error::VerifyUnsupported(_)
+            | Error::EncryptionNotSupported(_)
+            | Error::EncryptionRequiresStub(_)
+            | Error::DebugNotSupported(_)
+            | Error::ChipRevisionTooOld(..) => Device,
+            Error::MalformedPartitionTable(_)
+            | Error::MissingAppPartition(_)
+            | Error::MissingPartition(_)
+            | Error::PartitionTooSmall(..)
+            | Error::MissingOtaDataPartition
+            | Error::InvalidFlashLayout(_) => PartitionTable,
+            Error::UnrecognizedChipName
+            | Error::UnknownImageFormat(_)
+            | Error::UnknownBoard(..)
+            | Error::InvalidIdfBuild(_)
+            | Error::InvalidCapture(_)
+            | Error::InvalidHex(..)
+            | Error::RegisterRangeOutOfRange(..)
+            | Error::UnalignedEraseRegion(..)
+            | Error::InvalidStub(_)
+            | Error::InvalidSectorCache(_)
+            | Error::BootloaderNotFound(..)
+            | Error::NoDefaultBootloader(_)
+            | Error::LogFile(..)
+            | Error::GpioUnsupported(_)
+            | Error::InvalidFsConfig(_)
+            | Error::FsNameTooLong(..)
+            | Error::FsPathConflict(_)
+            | Error::FsFull(_)
+            | Error::InvalidNvs(_)
+            | Error::NvsFull(_)
+            | Error::InvalidSigningKey(_)
+            | Error::UnsupportedCoredumpFormat
+            | Error::InvalidCoredump(_)
+            | Error::InvalidManifest(_)
+            | Error::UnmatchedTriggerAction(..) => Input,
+            Error::TestFailed(_) | Error::DeviceCrashed(_) | Error::NoCoredump => Application,
+            Error::OperationTimeout(_) => Timeout,
+            Error::Canceled => Canceled,
+            Error::DevicesFailed(..) => Other,
+        }
+    }
 }
 
-#[derive(Error, Debug, Diagnostic)]
+#[derive(Error, Debug)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
 #[non_exhaustive]
 pub enum ConnectionError {
     #[error("IO error while using serial port: {0}")]
-    #[diagnostic(code(espflash::serial_error))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::serial_error)))]
     Serial(#[source] serialport::Error),
     #[cfg(target_os = "linux")]
     #[error("IO error while using GPIO: {0}")]
-    #[diagnostic(code(espflash::gpio_error))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::gpio_error)))]
     Gpio(#[source] gpio_cdev::errors::Error),
+    #[cfg(feature = "ftdi")]
+    #[error("IO error while using the FTDI adapter: {0}")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::ftdi_error),
+        help("Ensure that the D2XX driver of FTDI is installed and that the CBUS pins are configured as `I/O` in the EEPROM of the adapter")
+    ))]
+    Ftdi(#[source] libftd2xx::FtStatus),
     #[error("Failed to connect to the device")]
-    #[diagnostic(
+    #[cfg_attr(feature = "miette", diagnostic(
         code(espflash::connection_failed),
         help("Ensure that the device is connected and the reset and boot pins are not being held down")
-    )]
+    ))]
     ConnectionFailed,
     #[error("Serial port not found")]
-    #[diagnostic(
-        code(espflash::connection_failed),
-        help("Ensure that the device is connected and your host recognizes the serial adapter")
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::connection_failed),
+            help(
+                "Ensure that the device is connected and your host recognizes the serial adapter"
+            )
+        )
     )]
     DeviceNotFound,
+    #[error("The device did not respond after switching to {0} baud")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(
+            code(espflash::baud_change_failed),
+            help("Try a lower baud rate, not all serial adapters support high speeds")
+        )
+    )]
+    BaudChangeFailed(u32),
     #[error("Timeout while running {0}command")]
-    #[diagnostic(code(espflash::timeout))]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::timeout),
+        help("If the device is slow to respond, eg while erasing a large flash chip, raise the timeouts with `--timeout-scale`")
+    ))]
     Timeout(TimedOutCommand),
     #[error("Received packet has invalid SLIP framing")]
-    #[diagnostic(
+    #[cfg_attr(feature = "miette", diagnostic(
         code(espflash::slip_framing),
         help("Try hard-resetting the device and try again, if the error persists your rom might be corrupted")
-    )]
+    ))]
     FramingError,
+    #[error("Received a response too short for the data and the status it claims to hold")]
+    #[cfg_attr(feature = "miette", diagnostic(
+        code(espflash::malformed_response),
+        help("Try hard-resetting the device and try again, if the error persists your rom might be corrupted")
+    ))]
+    MalformedResponse,
     #[error("Received packet to large for buffer")]
-    #[diagnostic(
+    #[cfg_attr(feature = "miette", diagnostic(
         code(espflash::oversized_packet),
         help("Try hard-resetting the device and try again, if the error persists your rom might be corrupted")
-    )]
+    ))]
     OverSizedPacket,
 }
 
@@ -173,6 +753,20 @@ impl From<gpio_cdev::errors::Error> for Error {
     }
 }
 
+#[cfg(feature = "ftdi")]
+impl From<libftd2xx::FtStatus> for ConnectionError {
+    fn from(err: libftd2xx::FtStatus) -> Self {
+        ConnectionError::Ftdi(err)
+    }
+}
+
+#[cfg(feature = "ftdi")]
+impl From<libftd2xx::FtStatus> for Error {
+    fn from(err: libftd2xx::FtStatus) -> Self {
+        Self::Connection(err.into())
+    }
+}
+
 impl From<io::Error> for ConnectionError {
     fn from(err: io::Error) -> Self {
         from_error_kind(err.kind(), err)
@@ -225,34 +819,35 @@ impl From<binread::Error> for Error {
     }
 }
 
-#[derive(Copy, Clone, Debug, Error, Diagnostic)]
+#[derive(Copy, Clone, Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
 #[allow(dead_code)]
 #[repr(u8)]
 #[non_exhaustive]
 pub enum RomErrorKind {
     #[error("Invalid message received")]
-    #[diagnostic(code(espflash::rom::invalid_message))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::rom::invalid_message)))]
     InvalidMessage = 0x05,
     #[error("Bootloader failed to execute command")]
-    #[diagnostic(code(espflash::rom::failed))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::rom::failed)))]
     FailedToAct = 0x06,
     #[error("Received message has invalid crc")]
-    #[diagnostic(code(espflash::rom::crc))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::rom::crc)))]
     InvalidCrc = 0x07,
     #[error("Bootloader failed to write to flash")]
-    #[diagnostic(code(espflash::rom::flash_write))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::rom::flash_write)))]
     FlashWriteError = 0x08,
     #[error("Bootloader failed to read from flash")]
-    #[diagnostic(code(espflash::rom::flash_read))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::rom::flash_read)))]
     FlashReadError = 0x09,
     #[error("Invalid length for flash read")]
-    #[diagnostic(code(espflash::rom::flash_read_length))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::rom::flash_read_length)))]
     FlashReadLengthError = 0x0a,
     #[error("Malformed compressed data received")]
-    #[diagnostic(code(espflash::rom::deflate))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::rom::deflate)))]
     DeflateError = 0x0b,
     #[error("Other")]
-    #[diagnostic(code(espflash::rom::other))]
+    #[cfg_attr(feature = "miette", diagnostic(code(espflash::rom::other)))]
     Other = 0xff,
 }
 
@@ -271,7 +866,8 @@ impl From<u8> for RomErrorKind {
     }
 }
 
-#[derive(Copy, Clone, Debug, Error, Diagnostic)]
+#[derive(Copy, Clone, Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
 #[allow(dead_code)]
 #[non_exhaustive]
 #[error("Error while running {command} command")]
@@ -315,40 +911,60 @@ impl<T> ResultExt for Result<T, Error> {
     }
 }
 
-#[derive(Debug, Error, Diagnostic)]
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
 pub enum PartitionTableError {
     #[error(transparent)]
-    #[diagnostic(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
     Csv(#[from] CSVError),
     #[error(transparent)]
-    #[diagnostic(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
     Overlapping(#[from] OverlappingPartitionsError),
     #[error(transparent)]
-    #[diagnostic(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
     Duplicate(#[from] DuplicatePartitionsError),
     #[error(transparent)]
-    #[diagnostic(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
     InvalidSubType(#[from] InvalidSubTypeError),
     #[error(transparent)]
-    #[diagnostic(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
     NoApp(#[from] NoAppError),
     #[error(transparent)]
-    #[diagnostic(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
     UnalignedPartitionError(#[from] UnalignedPartitionError),
+    #[error(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
+    ReservedOffset(#[from] ReservedOffsetError),
+    #[error(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
+    AppTooSmall(#[from] AppPartitionTooSmallError),
+    #[error(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
+    PartitionOverflow(#[from] PartitionOverflowError),
+    #[error(transparent)]
+    #[cfg_attr(feature = "miette", diagnostic(transparent))]
+    TooManyPartitions(#[from] TooManyPartitionsError),
+    #[error("Malformed binary partition table: {0}")]
+    #[cfg_attr(
+        feature = "miette",
+        diagnostic(code(espflash::partition_table::invalid_binary))
+    )]
+    InvalidBinary(String),
 }
 
-#[derive(Debug, Error, Diagnostic)]
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
 #[error("Malformed partition table")]
-#[diagnostic(
+#[cfg_attr(feature = "miette", diagnostic(
     code(espflash::partition_table::mallformed),
     help("{}See the espressif documentation for information on the partition table format:
 
 https://docs.espressif.com/projects/esp-idf/en/latest/esp32/api-guides/partition-tables.html#creating-custom-tables", self.help)
-)]
+))]
 pub struct CSVError {
-    #[source_code]
+    #[cfg_attr(feature = "miette", source_code)]
     source: String,
-    #[label("{}", self.hint)]
+    #[cfg_attr(feature = "miette", label("{}", self.hint))]
     err_span: SourceSpan,
     hint: String,
     #[source]
@@ -377,12 +993,13 @@ impl CSVError {
 
         // string matching is fragile but afaik there is no better way in this case
         // and if it does break the error is still not bad
-        if hint == "data did not match any variant of untagged enum SubType" {
+        if hint == UNKNOWN_SUB_TYPE {
             hint = "Unknown sub-type".into();
             help = format!(
                 "the following sub-types are supported:
     {} for data partitions
-    {} for app partitions\n\n",
+    {} for app partitions
+    or a number such as 0x40 for custom sub-types\n\n",
                 Type::Data.subtype_hint(),
                 Type::App.subtype_hint()
             )
@@ -402,21 +1019,38 @@ impl CSVError {
 
 /// since csv doesn't give us the position in the line the error occurs, we highlight the entire line
 ///
-/// line starts at 1
+/// line starts at 1, an unknown line (0) highlights the first line
+#[cfg_attr(not(feature = "miette"), allow(clippy::useless_conversion))]
 fn line_to_span(source: &str, line: usize) -> SourceSpan {
-    let line_length = source.lines().nth(line - 1).unwrap().len().into();
-    SourceSpan::new(SourceOffset::from_location(source, line, 2), line_length)
+    let line = line.max(1);
+    let line_length = source.lines().nth(line - 1).map_or(0, str::len);
+    let offset = source
+        .split('\n')
+        .take(line - 1)
+        .map(|line| line.len() + 1)
+        .sum::<usize>()
+        .min(source.len());
+    (offset, line_length).into()
 }
 
-#[derive(Debug, Error, Diagnostic)]
+/// Offset and length of the highlighted part of the source, without the
+/// diagnostics
+#[cfg(not(feature = "miette"))]
+type SourceSpan = (usize, usize);
+
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
 #[error("Overlapping partitions")]
-#[diagnostic(code(espflash::partition_table::overlapping))]
+#[cfg_attr(
+    feature = "miette",
+    diagnostic(code(espflash::partition_table::overlapping))
+)]
 pub struct OverlappingPartitionsError {
-    #[source_code]
+    #[cfg_attr(feature = "miette", source_code)]
     source_code: String,
-    #[label("This partition")]
+    #[cfg_attr(feature = "miette", label("This partition"))]
     partition1_span: SourceSpan,
-    #[label("overlaps with this partition")]
+    #[cfg_attr(feature = "miette", label("overlaps with this partition"))]
     partition2_span: SourceSpan,
 }
 
@@ -430,15 +1064,19 @@ impl OverlappingPartitionsError {
     }
 }
 
-#[derive(Debug, Error, Diagnostic)]
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
 #[error("Duplicate partitions")]
-#[diagnostic(code(espflash::partition_table::duplicate))]
+#[cfg_attr(
+    feature = "miette",
+    diagnostic(code(espflash::partition_table::duplicate))
+)]
 pub struct DuplicatePartitionsError {
-    #[source_code]
+    #[cfg_attr(feature = "miette", source_code)]
     source_code: String,
-    #[label("This partition")]
+    #[cfg_attr(feature = "miette", label("This partition"))]
     partition1_span: SourceSpan,
-    #[label("has the same {} as this partition", self.ty)]
+    #[cfg_attr(feature = "miette", label("has the same {} as this partition", self.ty))]
     partition2_span: SourceSpan,
     ty: &'static str,
 }
@@ -459,16 +1097,17 @@ impl DuplicatePartitionsError {
     }
 }
 
-#[derive(Debug, Error, Diagnostic)]
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
 #[error("Invalid subtype for type")]
-#[diagnostic(
+#[cfg_attr(feature = "miette", diagnostic(
     code(espflash::partition_table::invalid_type),
     help("'{}' supports the following subtypes: {}", self.ty, self.ty.subtype_hint())
-)]
+))]
 pub struct InvalidSubTypeError {
-    #[source_code]
+    #[cfg_attr(feature = "miette", source_code)]
     source_code: String,
-    #[label("'{}' is not a valid subtype for '{}'", self.sub_type, self.ty)]
+    #[cfg_attr(feature = "miette", label("'{}' is not a valid subtype for '{}'", self.sub_type, self.ty))]
     span: SourceSpan,
     ty: Type,
     sub_type: SubType,
@@ -485,14 +1124,18 @@ impl InvalidSubTypeError {
     }
 }
 
-#[derive(Debug, Error, Diagnostic)]
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
 #[error("No app partition was found")]
-#[diagnostic(
-    code(espflash::partition_table::no_app),
-    help("Partition table must contain a factory or ota app partition")
+#[cfg_attr(
+    feature = "miette",
+    diagnostic(
+        code(espflash::partition_table::no_app),
+        help("Partition table must contain a factory or ota app partition")
+    )
 )]
 pub struct NoAppError {
-    #[source_code]
+    #[cfg_attr(feature = "miette", source_code)]
     source_code: String,
 }
 
@@ -504,21 +1147,136 @@ impl NoAppError {
     }
 }
 
-#[derive(Debug, Error, Diagnostic)]
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
 #[error("Unaligned partition")]
-#[diagnostic(code(espflash::partition_table::unaligned))]
+#[cfg_attr(feature = "miette", diagnostic(
+    code(espflash::partition_table::unaligned),
+    help("Move the partition to {:#x}, or leave the offset empty to place it automatically", self.suggested)
+))]
 pub struct UnalignedPartitionError {
-    #[source_code]
+    #[cfg_attr(feature = "miette", source_code)]
     source_code: String,
-    #[label("App partition is not aligned to 64k (0x10000)")]
+    #[cfg_attr(feature = "miette", label("{} partition is not aligned to {:#x}", self.ty, self.alignment))]
     span: SourceSpan,
+    ty: Type,
+    alignment: u32,
+    suggested: u64,
 }
 
 impl UnalignedPartitionError {
-    pub fn new(source: &str, line: usize) -> Self {
+    pub fn new(source: &str, line: usize, ty: Type, offset: u32, alignment: u32) -> Self {
+        let alignment_mask = alignment as u64 - 1;
         UnalignedPartitionError {
             source_code: source.into(),
             span: line_to_span(source, line),
+            ty,
+            alignment,
+            suggested: (offset as u64 + alignment_mask) & !alignment_mask,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
+#[error("Partition overlaps the bootloader or partition table")]
+#[cfg_attr(feature = "miette", diagnostic(
+    code(espflash::partition_table::reserved_offset),
+    help("Partitions have to start at or after {:#x}, the end of the partition table", self.first_offset)
+))]
+pub struct ReservedOffsetError {
+    #[cfg_attr(feature = "miette", source_code)]
+    source_code: String,
+    #[cfg_attr(feature = "miette", label("Partition starts at {:#x}", self.offset))]
+    span: SourceSpan,
+    offset: u32,
+    first_offset: u32,
+}
+
+impl ReservedOffsetError {
+    pub fn new(source: &str, line: usize, offset: u32, first_offset: u32) -> Self {
+        ReservedOffsetError {
+            source_code: source.into(),
+            span: line_to_span(source, line),
+            offset,
+            first_offset,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
+#[error("App partition is too small")]
+#[cfg_attr(feature = "miette", diagnostic(
+    code(espflash::partition_table::app_too_small),
+    help("App partitions have to be at least {:#x} bytes large", self.min_size)
+))]
+pub struct AppPartitionTooSmallError {
+    #[cfg_attr(feature = "miette", source_code)]
+    source_code: String,
+    #[cfg_attr(feature = "miette", label("App partition is only {:#x} bytes large", self.size))]
+    span: SourceSpan,
+    size: u32,
+    min_size: u32,
+}
+
+impl AppPartitionTooSmallError {
+    pub fn new(source: &str, line: usize, size: u32, min_size: u32) -> Self {
+        AppPartitionTooSmallError {
+            source_code: source.into(),
+            span: line_to_span(source, line),
+            size,
+            min_size,
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
+#[error("Partition out of range")]
+#[cfg_attr(
+    feature = "miette",
+    diagnostic(code(espflash::partition_table::overflow))
+)]
+pub struct PartitionOverflowError {
+    #[cfg_attr(feature = "miette", source_code)]
+    source_code: String,
+    #[cfg_attr(
+        feature = "miette",
+        label("Partition extends past the end of the 32-bit address space")
+    )]
+    span: SourceSpan,
+}
+
+impl PartitionOverflowError {
+    pub fn new(source: &str, line: usize) -> Self {
+        PartitionOverflowError {
+            source_code: source.into(),
+            span: line_to_span(source, line),
+        }
+    }
+}
+
+#[derive(Debug, Error)]
+#[cfg_attr(feature = "miette", derive(Diagnostic))]
+#[error("Partition table contains {count} partitions, at most {max} are supported")]
+#[cfg_attr(
+    feature = "miette",
+    diagnostic(code(espflash::partition_table::too_many_partitions))
+)]
+pub struct TooManyPartitionsError {
+    #[cfg_attr(feature = "miette", source_code)]
+    source_code: String,
+    count: usize,
+    max: usize,
+}
+
+impl TooManyPartitionsError {
+    pub fn new(source: &str, count: usize, max: usize) -> Self {
+        TooManyPartitionsError {
+            source_code: source.into(),
+            count,
+            max,
         }
     }
 }
@@ -576,6 +1334,7 @@ impl Display for UnsupportedImageFormatError {
 
 impl std::error::Error for UnsupportedImageFormatError {}
 
+#[cfg(feature = "miette")]
 impl Diagnostic for UnsupportedImageFormatError {
     fn code<'a>(&'a self) -> Option<Box<dyn Display + 'a>> {
         Some(Box::new("espflash::unsupported_image_format"))
@@ -616,3 +1375,38 @@ impl UnsupportedImageFormatError {
             .join(", ")
     }
 }
+
+#[cfg(all(test, feature = "miette"))]
+mod tests {
+    use miette::{SourceOffset, WrapErr};
+
+    use super::*;
+
+    #[test]
+    fn categories_are_found_through_context() {
+        let report = Err::<(), _>(Error::Flashing(ConnectionError::ConnectionFailed))
+            .wrap_err("Failed to flash the device")
+            .unwrap_err();
+        assert_eq!(ErrorCategory::of(&report), Some(ErrorCategory::Connection));
+        assert_eq!(ErrorCategory::Connection.exit_code(), 2);
+
+        let report = Report::new(Error::VerifyFailed(0x10000));
+        assert_eq!(
+            ErrorCategory::of(&report),
+            Some(ErrorCategory::Verification)
+        );
+
+        assert_eq!(ErrorCategory::of(&miette::miette!("Unknown")), None);
+    }
+
+    #[test]
+    fn spans_cover_whole_lines() {
+        let source = "# Name, Type\r\nnvs, data\nfactory, app";
+        for line in 0..=4 {
+            let span = line_to_span(source, line);
+            let expected = SourceOffset::from_location(source, line.max(1), 2);
+            assert_eq!(span.offset(), expected.offset(), "line {}", line);
+        }
+        assert_eq!(line_to_span(source, 2).len(), "nvs, data".len());
+    }
+}
@@ -0,0 +1,185 @@
+//! Reading and writing Intel HEX files
+
+use std::fmt::Write;
+
+use crate::elf::RomSegment;
+#[cfg(feature = "cli")]
+use crate::error::Error;
+
+const DATA: u8 = 0x00;
+const END_OF_FILE: u8 = 0x01;
+#[cfg(feature = "cli")]
+const EXTENDED_SEGMENT_ADDRESS: u8 = 0x02;
+#[cfg(feature = "cli")]
+const START_SEGMENT_ADDRESS: u8 = 0x03;
+const EXTENDED_LINEAR_ADDRESS: u8 = 0x04;
+#[cfg(feature = "cli")]
+const START_LINEAR_ADDRESS: u8 = 0x05;
+
+/// Number of data bytes per record
+const RECORD_SIZE: usize = 16;
+
+/// Whether the data looks like an Intel HEX file rather than an ELF image
+#[cfg(feature = "cli")]
+pub fn is_hex(data: &[u8]) -> bool {
+    data.first() == Some(&b':')
+}
+
+fn push_record(hex: &mut String, kind: u8, addr: u16, data: &[u8]) {
+    let mut checksum = (data.len() as u8)
+        .wrapping_add((addr >> 8) as u8)
+        .wrapping_add(addr as u8)
+        .wrapping_add(kind);
+    write!(hex, ":{:02X}{:04X}{:02X}", data.len(), addr, kind).unwrap();
+    for byte in data {
+        checksum = checksum.wrapping_add(*byte);
+        write!(hex, "{:02X}", byte).unwrap();
+    }
+    writeln!(hex, "{:02X}", checksum.wrapping_neg()).unwrap();
+}
+
+/// Encode the segments as data records, switching the upper half of the
+/// address with extended linear address records when needed
+pub fn encode(segments: &[RomSegment]) -> Vec<u8> {
+    let mut hex = String::new();
+    let mut upper = None;
+    for segment in segments {
+        for (i, chunk) in segment.data.chunks(RECORD_SIZE).enumerate() {
+            let addr = segment.addr + (i * RECORD_SIZE) as u32;
+            // A record may not cross a 64k boundary
+            let split = (0x10000 - (addr & 0xFFFF) as usize).min(chunk.len());
+            for (addr, part) in [
+                (addr, &chunk[..split]),
+                (addr + split as u32, &chunk[split..]),
+            ] {
+                if part.is_empty() {
+                    continue;
+                }
+                if upper != Some(addr >> 16) {
+                    upper = Some(addr >> 16);
+                    push_record(
+                        &mut hex,
+                        EXTENDED_LINEAR_ADDRESS,
+                        0,
+                        &((addr >> 16) as u16).to_be_bytes(),
+                    );
+                }
+                push_record(&mut hex, DATA, addr as u16, part);
+            }
+        }
+    }
+    push_record(&mut hex, END_OF_FILE, 0, &[]);
+
+    hex.into_bytes()
+}
+
+#[cfg(feature = "cli")]
+fn parse_record(line: &str) -> Result<(u8, u16, Vec<u8>), &'static str> {
+    let digits = line.strip_prefix(':').ok_or("missing start code")?;
+    if !digits.is_ascii() {
+        return Err("invalid hex digit");
+    }
+    if digits.len() % 2 != 0 || digits.len() < 10 {
+        return Err("invalid record length");
+    }
+    let bytes = (0..digits.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(&digits[i..i + 2], 16))
+        .collect::<Result<Vec<_>, _>>()
+        .map_err(|_| "invalid hex digit")?;
+
+    let len = bytes[0] as usize;
+    if bytes.len() != len + 5 {
+        return Err("byte count doesn't match the record length");
+    }
+    if bytes.iter().fold(0u8, |sum, byte| sum.wrapping_add(*byte)) != 0 {
+        return Err("invalid checksum");
+    }
+
+    let addr = u16::from_be_bytes([bytes[1], bytes[2]]);
+    Ok((bytes[3], addr, bytes[4..4 + len].to_vec()))
+}
+
+/// Decode the data records, merging consecutive ones into a single segment,
+/// for flashing them with the CLI
+#[cfg(feature = "cli")]
+pub fn decode(data: &[u8]) -> Result<Vec<(u32, Vec<u8>)>, Error> {
+    let text = std::str::from_utf8(data).map_err(|_| Error::InvalidHex(1, "not a text file"))?;
+
+    let mut segments: Vec<(u32, Vec<u8>)> = Vec::new();
+    let mut base = 0u32;
+    for (i, line) in text.lines().enumerate() {
+        let line = line.trim();
+        if line.is_empty() {
+            continue;
+        }
+        let (kind, addr, data) = parse_record(line).map_err(|err| Error::InvalidHex(i + 1, err))?;
+
+        match kind {
+            DATA => {
+                let addr = base.wrapping_add(addr as u32);
+                match segments.last_mut() {
+                    Some((start, segment))
+                        if start.checked_add(segment.len() as u32) == Some(addr) =>
+                    {
+                        segment.extend_from_slice(&data)
+                    }
+                    _ => segments.push((addr, data)),
+                }
+            }
+            END_OF_FILE => break,
+            EXTENDED_SEGMENT_ADDRESS | EXTENDED_LINEAR_ADDRESS if data.len() == 2 => {
+                let value = u16::from_be_bytes([data[0], data[1]]) as u32;
+                base = if kind == EXTENDED_LINEAR_ADDRESS {
+                    value << 16
+                } else {
+                    value << 4
+                };
+            }
+            // The entry point is stored in the image itself
+            START_SEGMENT_ADDRESS | START_LINEAR_ADDRESS => {}
+            _ => return Err(Error::InvalidHex(i + 1, "unsupported record type")),
+        }
+    }
+
+    Ok(segments)
+}
+
+#[cfg(all(test, feature = "cli"))]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_survive_a_round_trip() {
+        let segments = [
+            RomSegment {
+                addr: 0x1000,
+                data: vec![1; 20].into(),
+            },
+            RomSegment {
+                addr: 0xFFF8,
+                data: (0..16).collect::<Vec<u8>>().into(),
+            },
+        ];
+        let hex = encode(&segments);
+        let text = std::str::from_utf8(&hex).unwrap();
+
+        assert!(text.starts_with(":020000040000FA\n:10100000"));
+        assert!(text.ends_with(":00000001FF\n"));
+        assert_eq!(
+            decode(&hex).unwrap(),
+            [
+                (0x1000, vec![1; 20]),
+                (0xFFF8, (0..16).collect::<Vec<u8>>())
+            ]
+        );
+        assert!(matches!(
+            decode(b":0100000001FF\n"),
+            Err(Error::InvalidHex(1, "invalid checksum"))
+        ));
+        assert!(matches!(
+            decode(":0100000é000\n".as_bytes()),
+            Err(Error::InvalidHex(1, "invalid hex digit"))
+        ));
+    }
+}
@@ -0,0 +1,60 @@
+//! File formats flash images can be saved in, for use by other tools
+//!
+//! Images are normally saved as raw binaries. The other formats keep the flash
+//! address of every segment, so a single file can hold all of them.
+
+use std::{path::Path, str::FromStr};
+
+use crate::{elf::RomSegment, Chip};
+
+pub mod ihex;
+mod uf2;
+
+/// Format of a saved image file
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum FileFormat {
+    /// The raw contents of the flash
+    Binary,
+    /// USB Flashing Format, for drag and drop flashing onto UF2 bootloaders
+    Uf2,
+    /// Intel HEX, as used by many programmers
+    Hex,
+}
+
+impl FileFormat {
+    /// Guess the format from the extension of `path`, defaulting to binary
+    pub fn from_path(path: &Path) -> Self {
+        match path.extension().and_then(|ext| ext.to_str()) {
+            Some(ext) if ext.eq_ignore_ascii_case("uf2") => FileFormat::Uf2,
+            Some(ext) if ext.eq_ignore_ascii_case("hex") => FileFormat::Hex,
+            _ => FileFormat::Binary,
+        }
+    }
+
+    /// Encode the segments in this format
+    ///
+    /// Binaries can only hold one segment, whose address is lost.
+    pub fn encode(self, chip: Chip, segments: &[RomSegment]) -> Vec<u8> {
+        match self {
+            FileFormat::Binary => segments
+                .iter()
+                .flat_map(|segment| segment.data.iter().copied())
+                .collect(),
+            FileFormat::Uf2 => uf2::encode(chip.uf2_family_id(), segments),
+            FileFormat::Hex => ihex::encode(segments),
+        }
+    }
+}
+
+impl FromStr for FileFormat {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "bin" => Ok(FileFormat::Binary),
+            "uf2" => Ok(FileFormat::Uf2),
+            "hex" => Ok(FileFormat::Hex),
+            _ => Err(format!("unknown file format `{}`", s)),
+        }
+    }
+}
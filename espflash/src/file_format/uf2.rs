@@ -0,0 +1,76 @@
+//! Writing UF2 files, see https://github.com/microsoft/uf2
+
+use crate::elf::RomSegment;
+
+const MAGIC_START0: u32 = 0x0A32_4655;
+const MAGIC_START1: u32 = 0x9E5D_5157;
+const MAGIC_END: u32 = 0x0AB1_6F30;
+/// The `family_id` field holds the family of the chip instead of the file size
+const FLAG_FAMILY_ID_PRESENT: u32 = 0x0000_2000;
+const BLOCK_SIZE: usize = 512;
+/// Amount of data in each block, the remaining space is padding
+const PAYLOAD_SIZE: usize = 256;
+
+/// Split the segments into UF2 blocks of up to 256 bytes each
+pub fn encode(family_id: u32, segments: &[RomSegment]) -> Vec<u8> {
+    let chunks = segments
+        .iter()
+        .flat_map(|segment| {
+            segment
+                .data
+                .chunks(PAYLOAD_SIZE)
+                .enumerate()
+                .map(move |(i, chunk)| (segment.addr + (i * PAYLOAD_SIZE) as u32, chunk))
+        })
+        .collect::<Vec<_>>();
+
+    let mut uf2 = Vec::with_capacity(chunks.len() * BLOCK_SIZE);
+    for (block_no, (addr, chunk)) in chunks.iter().enumerate() {
+        let header = [
+            MAGIC_START0,
+            MAGIC_START1,
+            FLAG_FAMILY_ID_PRESENT,
+            *addr,
+            // The last block of a segment may be shorter
+            chunk.len() as u32,
+            block_no as u32,
+            chunks.len() as u32,
+            family_id,
+        ];
+        let start = uf2.len();
+        for word in header {
+            uf2.extend_from_slice(&word.to_le_bytes());
+        }
+        uf2.extend_from_slice(chunk);
+        uf2.resize(start + BLOCK_SIZE - 4, 0);
+        uf2.extend_from_slice(&MAGIC_END.to_le_bytes());
+    }
+
+    uf2
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn segments_are_split_into_blocks() {
+        let segments = [RomSegment {
+            addr: 0x10000,
+            data: vec![0xAA; 300].into(),
+        }];
+        let uf2 = encode(0x1c5f21b0, &segments);
+
+        assert_eq!(uf2.len(), 2 * 512);
+        let second = &uf2[512..];
+        assert_eq!(second[..4], MAGIC_START0.to_le_bytes());
+        assert_eq!(second[12..16], 0x10100u32.to_le_bytes());
+        assert_eq!(second[16..20], 44u32.to_le_bytes());
+        assert_eq!(second[20..24], 1u32.to_le_bytes());
+        assert_eq!(second[24..28], 2u32.to_le_bytes());
+        assert_eq!(second[28..32], 0x1c5f21b0u32.to_le_bytes());
+        assert_eq!(second[32..32 + 44], [0xAA; 44]);
+        assert_eq!(second[32 + 44], 0);
+        assert_eq!(second[508..], MAGIC_END.to_le_bytes());
+    }
+}
@@ -1,118 +1,146 @@
 use crate::command::{Command, CommandType};
-use crate::connection::Connection;
-use crate::elf::{FirmwareImage, RomSegment};
+use crate::elf::RomSegment;
 use crate::error::Error;
-use crate::flash_target::FlashTarget;
+use crate::flash_target::{compress_blocks, Encryption, FlashTarget};
 use crate::flasher::{SpiAttachParams, FLASH_SECTOR_SIZE, FLASH_WRITE_SIZE};
+use crate::protocol::{BlockCommand, BlockWrite, Step, Steps};
 use crate::Chip;
-use flate2::write::{ZlibDecoder, ZlibEncoder};
-use flate2::Compression;
-use indicatif::{ProgressBar, ProgressStyle};
-use std::io::Write;
 
 pub struct Esp32Target {
     chip: Chip,
     spi_attach_params: SpiAttachParams,
+    use_compression: bool,
+    encryption: Option<Encryption>,
+    /// Whether the last segment was written compressed, the write is ended
+    /// with the matching command
+    compressed: bool,
 }
 
 impl Esp32Target {
-    pub fn new(chip: Chip, spi_attach_params: SpiAttachParams) -> Self {
+    pub fn new(
+        chip: Chip,
+        spi_attach_params: SpiAttachParams,
+        use_compression: bool,
+        encryption: Option<Encryption>,
+    ) -> Self {
         Esp32Target {
             chip,
             spi_attach_params,
+            use_compression,
+            encryption,
+            compressed: use_compression,
         }
     }
-}
 
-impl FlashTarget for Esp32Target {
-    fn begin(&mut self, connection: &mut Connection, _image: &FirmwareImage) -> Result<(), Error> {
-        connection.with_timeout(CommandType::SpiAttach.timeout(), |connection| {
-            connection.command(Command::SpiAttach {
-                spi_params: self.spi_attach_params,
-            })
-        })?;
-        Ok(())
-    }
-
-    fn write_segment(
+    fn write_uncompressed<'a>(
         &mut self,
-        connection: &mut Connection,
-        segment: RomSegment,
-    ) -> Result<(), Error> {
+        segment: RomSegment<'a>,
+        encrypted: bool,
+    ) -> BlockWrite<'a> {
+        self.compressed = false;
         let addr = segment.addr;
-        let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
-        encoder.write_all(&segment.data)?;
-        let compressed = encoder.finish()?;
-        let block_count = (compressed.len() + FLASH_WRITE_SIZE - 1) / FLASH_WRITE_SIZE;
-        let erase_count = (segment.data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
+        // The ROM only encrypts data when told so at the start of the write, the stub
+        // uses a dedicated command for every block instead
+        let stub = self
+            .encryption
+            .as_ref()
+            .map_or(false, |encryption| encryption.stub);
+        let rom_encrypted = encrypted && !stub;
+
+        // Encryption works on blocks of 32 bytes on the ESP32 and 16 bytes otherwise
+        let mut data = segment.data;
+        if encrypted {
+            let align = if self.chip == Chip::Esp32 { 32 } else { 16 };
+            let len = (data.len() + align - 1) / align * align;
+            data.to_mut().resize(len, 0xff);
+        }
+        let block_count = (data.len() + FLASH_WRITE_SIZE - 1) / FLASH_WRITE_SIZE;
+        let erase_count = (data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
 
         // round up to sector size
         let erase_size = (erase_count * FLASH_SECTOR_SIZE) as u32;
 
-        connection.with_timeout(
-            CommandType::FlashDeflateBegin.timeout_for_size(erase_size),
-            |connection| {
-                connection.command(Command::FlashDeflateBegin {
-                    size: erase_size,
-                    blocks: block_count as u32,
-                    block_size: FLASH_WRITE_SIZE as u32,
-                    offset: addr,
-                    supports_encryption: self.chip != Chip::Esp32,
-                })?;
-                Ok(())
-            },
-        )?;
+        let begin = Command::FlashBegin {
+            size: erase_size,
+            blocks: block_count as u32,
+            block_size: FLASH_WRITE_SIZE as u32,
+            offset: addr,
+            supports_encryption: self.chip != Chip::Esp32,
+            encrypted: rom_encrypted,
+        };
+        let command = if encrypted && stub {
+            BlockCommand::FlashEncrypt
+        } else {
+            BlockCommand::Flash
+        };
 
-        let chunks = compressed.chunks(FLASH_WRITE_SIZE);
+        BlockWrite::new(
+            begin,
+            CommandType::FlashBegin.timeout_for_size(erase_size),
+            command,
+            addr,
+            data,
+            FLASH_WRITE_SIZE,
+        )
+    }
+}
 
-        let (_, chunk_size) = chunks.size_hint();
-        let chunk_size = chunk_size.unwrap_or(0) as u64;
-        let pb_chunk = ProgressBar::new(chunk_size);
-        pb_chunk.set_style(
-            ProgressStyle::default_bar()
-                .template("[{elapsed_precise}] {bar:40.cyan/blue} {pos:>7}/{len:7} {msg}")
-                .progress_chars("#>-"),
-        );
+impl FlashTarget for Esp32Target {
+    fn begin(&mut self) -> Steps {
+        Steps::from(Some(Step::Command(
+            Command::SpiAttach {
+                spi_params: self.spi_attach_params,
+            },
+            CommandType::SpiAttach.timeout(),
+        )))
+    }
 
-        // decode the chunks to see how much data the device will have to save
-        let mut decoder = ZlibDecoder::new(Vec::new());
-        let mut decoded_size = 0;
+    fn write_segment<'a>(&mut self, segment: RomSegment<'a>) -> Result<BlockWrite<'a>, Error> {
+        // Encrypted data can't be written compressed
+        let encrypted = self
+            .encryption
+            .as_ref()
+            .map_or(false, |encryption| encryption.covers(segment.addr));
+        if !self.use_compression || encrypted {
+            return Ok(self.write_uncompressed(segment, encrypted));
+        }
 
-        for (i, block) in chunks.enumerate() {
-            decoder.write_all(block)?;
-            decoder.flush()?;
-            let size = decoder.get_ref().len() - decoded_size;
-            decoded_size = decoder.get_ref().len();
+        self.compressed = true;
+        let addr = segment.addr;
+        let blocks = compress_blocks(&segment.data)?;
+        let block_count = blocks.len();
+        let erase_count = (segment.data.len() + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
 
-            pb_chunk.set_message(format!("segment 0x{:X} writing chunks", addr));
-            connection.with_timeout(
-                CommandType::FlashDeflateData.timeout_for_size(size as u32),
-                |connection| {
-                    connection.command(Command::FlashDeflateData {
-                        sequence: i as u32,
-                        pad_to: 0,
-                        pad_byte: 0xff,
-                        data: block,
-                    })?;
-                    Ok(())
-                },
-            )?;
-            pb_chunk.inc(1);
-        }
+        // round up to sector size
+        let erase_size = (erase_count * FLASH_SECTOR_SIZE) as u32;
 
-        pb_chunk.finish_with_message(format!("segment 0x{:X}", addr));
+        let begin = Command::FlashDeflateBegin {
+            size: erase_size,
+            blocks: block_count as u32,
+            block_size: FLASH_WRITE_SIZE as u32,
+            offset: addr,
+            supports_encryption: self.chip != Chip::Esp32,
+        };
 
-        Ok(())
+        Ok(BlockWrite::deflate(
+            begin,
+            CommandType::FlashDeflateBegin.timeout_for_size(erase_size),
+            addr,
+            segment.data.len(),
+            blocks,
+        ))
     }
 
-    fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error> {
-        connection.with_timeout(CommandType::FlashDeflateEnd.timeout(), |connection| {
-            connection.write_command(Command::FlashDeflateEnd { reboot: false })
-        })?;
-        if reboot {
-            connection.reset()
+    fn finish(&mut self, reboot: bool) -> Steps {
+        let end = if self.compressed {
+            Command::FlashDeflateEnd { reboot: false }
         } else {
-            Ok(())
+            Command::FlashEnd { reboot: false }
+        };
+        let mut steps = Steps::from(Some(Step::Send(end)));
+        if reboot {
+            steps.push(Step::Reset);
         }
+        steps
     }
 }
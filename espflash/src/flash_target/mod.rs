@@ -2,23 +2,43 @@ mod esp32;
 mod esp8266;
 mod ram;
 
-use crate::connection::Connection;
-use crate::elf::{FirmwareImage, RomSegment};
+use crate::elf::RomSegment;
 use crate::error::Error;
+use crate::flasher::FLASH_WRITE_SIZE;
+use crate::protocol::{BlockWrite, Steps};
 
 use bytemuck::{Pod, Zeroable};
 pub use esp32::Esp32Target;
 pub use esp8266::Esp8266Target;
+use flate2::write::{ZlibDecoder, ZlibEncoder};
+use flate2::Compression;
 pub use ram::RamTarget;
+use std::io::Write;
+use std::ops::Range;
 
-pub trait FlashTarget {
-    fn begin(&mut self, connection: &mut Connection, image: &FirmwareImage) -> Result<(), Error>;
-    fn write_segment(
-        &mut self,
-        connection: &mut Connection,
-        segment: RomSegment,
-    ) -> Result<(), Error>;
-    fn finish(&mut self, connection: &mut Connection, reboot: bool) -> Result<(), Error>;
+/// The flash regions written through the flash encryption of the chip
+#[derive(Clone, Debug)]
+pub struct Encryption {
+    /// Whether the flasher stub is running, it encrypts the data itself instead
+    /// of the ROM
+    pub stub: bool,
+    pub regions: Vec<Range<u32>>,
+}
+
+impl Encryption {
+    pub fn covers(&self, addr: u32) -> bool {
+        self.regions.iter().any(|region| region.contains(&addr))
+    }
+}
+
+/// Builds the operations writing to the device, which the blocking and the
+/// async connections carry out
+pub trait FlashTarget: Send {
+    fn begin(&mut self) -> Steps;
+    fn write_segment<'a>(&mut self, segment: RomSegment<'a>) -> Result<BlockWrite<'a>, Error>;
+    /// End the write, and reset the device or run the code written to RAM if
+    /// `reboot` is set
+    fn finish(&mut self, reboot: bool) -> Steps;
 }
 
 #[derive(Zeroable, Pod, Copy, Clone, Debug)]
@@ -30,3 +50,22 @@ struct BeginParams {
     offset: u32,
     encrypted: u32,
 }
+
+/// Compress `data` for the deflate commands into blocks, each with the number
+/// of bytes of `data` the device has decompressed once it received the block
+pub(crate) fn compress_blocks(data: &[u8]) -> Result<Vec<(Vec<u8>, usize)>, Error> {
+    let mut encoder = ZlibEncoder::new(Vec::new(), Compression::best());
+    encoder.write_all(data)?;
+    let compressed = encoder.finish()?;
+
+    // decode the chunks to see how much data the device will have to save
+    let mut decoder = ZlibDecoder::new(Vec::new());
+    let mut blocks = Vec::new();
+    for block in compressed.chunks(FLASH_WRITE_SIZE) {
+        decoder.write_all(block)?;
+        decoder.flush()?;
+        blocks.push((block.to_vec(), decoder.get_ref().len()));
+    }
+
+    Ok(blocks)
+}
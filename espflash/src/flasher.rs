@@ -1,21 +1,34 @@
-use std::{borrow::Cow, thread::sleep};
+use std::{borrow::Cow, io::Write, iter::once, ops::Range, str::FromStr};
 
-use bytemuck::{Pod, Zeroable, __core::time::Duration};
-use serialport::{SerialPort, UsbPortInfo};
+use bytemuck::{__core::time::Duration, Pod, Zeroable};
+use log::{debug, info, warn};
+use serde::Serialize;
+use serialport::{SerialPort, SerialPortType};
+use sha2::{Digest, Sha256};
 use strum_macros::Display;
 
 use crate::{
     chip::Chip,
     command::{Command, CommandType},
-    connection::Connection,
-    connection::GpioLine,
-    elf::{FirmwareImage, RomSegment},
+    connection::{ConnectSettings, Connection, GpioLine},
+    efuse,
+    elf::{FirmwareImage, FlashLayout, FlashSettings, RomSegment},
     error::{ConnectionError, FlashDetectError, ResultExt, RomError, RomErrorKind},
+    flash_target::{Encryption, FlashTarget},
     image_format::ImageFormatId,
+    image_info::AppDescriptorSettings,
+    partition_table::{Flags, Type, PARTITION_TABLE_OFFSET},
+    protocol::{SpiCommand, Step, Steps},
+    reset::ResetAfterOperation,
+    sector_cache::SectorCache,
+    secure_boot::SigningKey,
+    stubs::FlashStub,
+    transport::Transport,
     Error, PartitionTable,
 };
 
-const DEFAULT_CONNECT_ATTEMPTS: usize = 7;
+/// Number of sync commands sent after each reset into the bootloader
+pub(crate) const SYNC_ATTEMPTS: usize = 5;
 const DEFAULT_TIMEOUT: Duration = Duration::from_secs(3);
 
 pub(crate) const FLASH_SECTOR_SIZE: usize = 0x1000;
@@ -24,7 +37,7 @@ const FLASH_BLOCK_SIZE: usize = 0x100;
 const FLASH_SECTORS_PER_BLOCK: usize = FLASH_SECTOR_SIZE / FLASH_BLOCK_SIZE;
 
 // register used for chip detect
-const CHIP_DETECT_MAGIC_REG_ADDR: u32 = 0x40001000;
+pub(crate) const CHIP_DETECT_MAGIC_REG_ADDR: u32 = 0x40001000;
 
 #[derive(Clone, Copy, Debug, Eq, PartialEq, Display)]
 #[allow(dead_code)]
@@ -51,7 +64,43 @@ pub enum FlashSize {
     FlashRetry = 0xFF, // used to hint that alternate detection should be tried
 }
 
+impl FromStr for FlashSize {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let size = match s.to_ascii_uppercase().as_str() {
+            "256KB" => FlashSize::Flash256Kb,
+            "512KB" => FlashSize::Flash512Kb,
+            "1MB" => FlashSize::Flash1Mb,
+            "2MB" => FlashSize::Flash2Mb,
+            "4MB" => FlashSize::Flash4Mb,
+            "8MB" => FlashSize::Flash8Mb,
+            "16MB" => FlashSize::Flash16Mb,
+            "32MB" => FlashSize::Flash32Mb,
+            "64MB" => FlashSize::Flash64Mb,
+            _ => {
+                return Err(Error::InvalidMetadata(format!(
+                    "unknown flash size `{}`",
+                    s
+                )))
+            }
+        };
+        Ok(size)
+    }
+}
+
+serde_plain::derive_deserialize_from_fromstr!(FlashSize, "a flash size like `4MB`");
+serde_plain::derive_serialize_from_display!(FlashSize);
+
 impl FlashSize {
+    /// The size in bytes
+    pub fn size(self) -> u32 {
+        match self {
+            FlashSize::FlashRetry => 0,
+            size => 1 << (size as u32),
+        }
+    }
+
     fn from(value: u8) -> Result<FlashSize, Error> {
         match value {
             0x12 => Ok(FlashSize::Flash256Kb),
@@ -115,7 +164,7 @@ impl SpiAttachParams {
 }
 
 /// List of spi params to try while detecting flash size
-const TRY_SPI_PARAMS: [SpiAttachParams; 2] =
+pub(crate) const TRY_SPI_PARAMS: [SpiAttachParams; 2] =
     [SpiAttachParams::default(), SpiAttachParams::esp32_pico_d4()];
 
 #[derive(Zeroable, Pod, Copy, Clone, Debug)]
@@ -144,47 +193,378 @@ struct EntryParams {
     entry: u32,
 }
 
+/// Progress update callbacks, used to report the progress of writing segments
+pub trait ProgressCallbacks {
+    /// Start writing a segment of `total` bytes to `addr`
+    fn init(&mut self, addr: u32, total: usize);
+    /// `current` bytes of the segment have been written
+    fn update(&mut self, current: usize);
+    /// The segment has been written
+    fn finish(&mut self);
+}
+
+/// Information about the connected board, as printed by
+/// [`Flasher::board_info`]
+#[derive(Clone, Debug, Serialize)]
+pub struct DeviceInfo {
+    pub chip: String,
+    pub revision: Option<u32>,
+    /// Crystal frequency in MHz
+    pub crystal_frequency: u32,
+    pub flash_size: String,
+    pub flash_manufacturer: u8,
+    pub flash_manufacturer_name: Option<&'static str>,
+    pub flash_device: u32,
+    pub features: Vec<String>,
+    pub mac_address: String,
+}
+
+/// Options for one of the binaries written by
+/// [`Flasher::write_bins_to_flash_with_options`], the settings of the flasher
+/// are used for the ones which aren't set
+#[derive(Copy, Clone, Debug, Default, PartialEq, Eq)]
+pub struct BinOptions {
+    /// Write the binary through the flash encryption
+    pub encrypt: Option<bool>,
+    /// Verify the binary after writing it
+    pub verify: Option<bool>,
+}
+
+/// A piece of data written to flash by the last flashing operation
+#[derive(Clone, Debug, Serialize)]
+pub struct WrittenSegment {
+    pub addr: u32,
+    pub size: u32,
+    /// MD5 digest of the data, as hexadecimal string
+    pub md5: String,
+    /// SHA-256 digest of the data, as hexadecimal string
+    pub sha256: String,
+}
+
+impl WrittenSegment {
+    pub(crate) fn new(addr: u32, data: &[u8]) -> Self {
+        WrittenSegment {
+            addr,
+            size: data.len() as u32,
+            md5: format!("{:x}", md5::compute(data)),
+            sha256: format!("{:x}", Sha256::digest(data)),
+        }
+    }
+}
+
+/// Settings for flashing ELF images, which don't depend on the device
+#[derive(Default)]
+pub struct ImageSettings {
+    /// App partition to write the application to, instead of the one selected
+    /// by the image or the default one
+    pub app_partition: Option<String>,
+    /// Rewrite the `otadata` partition to start the application
+    pub update_otadata: bool,
+    /// Partitions to erase before flashing
+    pub erase_parts: Vec<String>,
+    /// Data to write to these partitions after the image
+    pub partition_data: Vec<(String, Vec<u8>)>,
+    pub signing_key: Option<SigningKey>,
+    pub flash_settings: FlashSettings,
+    pub app_descriptor: AppDescriptorSettings,
+    pub flash_layout: FlashLayout,
+    /// ESP-IDF release of the bundled bootloader to use, see
+    /// [`Bootloader::find`](crate::bootloaders::Bootloader::find)
+    pub bootloader_release: Option<String>,
+}
+
+/// The partitions to erase and the data to write when flashing an image
+pub struct FlashPlan {
+    /// Name, offset and size of the partitions to erase before writing
+    pub erase: Vec<(String, u32, u32)>,
+    /// Data to write and its offset, the image followed by the data of other
+    /// partitions
+    pub segments: Vec<(u32, Vec<u8>)>,
+    /// The partition table which is flashed, if the chip uses one
+    pub partition_table: Option<PartitionTable>,
+}
+
+impl ImageSettings {
+    /// Build the flash contents of the ELF image in `elf_data` for `chip`,
+    /// without writing anything
+    ///
+    /// All regions are checked to fit into `flash_size`, which is also written
+    /// to the image headers if neither the image nor the flash settings give a
+    /// size.
+    #[allow(clippy::too_many_arguments)]
+    pub fn plan(
+        &self,
+        chip: Chip,
+        flash_size: FlashSize,
+        chip_revision: Option<u32>,
+        elf_data: &[u8],
+        bootloader: Option<Vec<u8>>,
+        partition_table: Option<PartitionTable>,
+        image_format: Option<ImageFormatId>,
+    ) -> Result<FlashPlan, Error> {
+        let mut image = FirmwareImage::from_data(elf_data)?;
+        if image.metadata.flash_size.is_none() {
+            image.flash_size = flash_size;
+        }
+        image.apply_flash_settings(self.flash_settings);
+        if image.min_chip_rev > 0 {
+            match chip_revision {
+                Some(revision) if revision < image.min_chip_rev as u32 => {
+                    return Err(Error::ChipRevisionTooOld(
+                        image.min_chip_rev as u32,
+                        revision,
+                    ));
+                }
+                Some(_) => {}
+                None => warn!(
+                    "The revision of the {} is unknown, it can't be checked against the minimum revision {}",
+                    chip, image.min_chip_rev
+                ),
+            }
+        }
+        image.app_descriptor = self.app_descriptor.clone();
+        image.flash_layout = self.flash_layout;
+        image.bootloader_release = self.bootloader_release.clone();
+        if let Some(name) = &self.app_partition {
+            image.metadata.app_partition = Some(name.clone());
+        }
+
+        let ota_data = match (&partition_table, self.update_otadata) {
+            (Some(table), true) => {
+                // Same fallback as used when picking the partition to write the app to
+                let app = match &image.metadata.app_partition {
+                    Some(name) => name.as_str(),
+                    None => table
+                        .find_app(None)
+                        .map(|partition| partition.name())
+                        .unwrap_or("factory"),
+                };
+                Some(table.ota_data(app)?)
+            }
+            (None, true) => return Err(Error::MissingOtaDataPartition),
+            (_, false) => None,
+        };
+
+        let table = match &partition_table {
+            Some(table) => Some(table.clone()),
+            None => chip.default_partition_table(self.flash_layout),
+        };
+        let find_partition = |name: &str| {
+            table
+                .as_ref()
+                .ok_or(Error::NoPartitionTable(chip))?
+                .find(name)
+                .ok_or_else(|| Error::MissingPartition(name.into()))
+        };
+
+        let mut erase = Vec::new();
+        for name in &self.erase_parts {
+            let partition = find_partition(name)?;
+            check_flash_region(partition.offset(), partition.size(), flash_size)?;
+            erase.push((name.clone(), partition.offset(), partition.size()));
+        }
+
+        // Written after the image segments
+        let mut extra_segments: Vec<_> = ota_data.into_iter().collect();
+        for (name, data) in &self.partition_data {
+            let partition = find_partition(name)?;
+            if data.len() > partition.size() as usize {
+                return Err(Error::PartitionTooSmall(
+                    name.clone(),
+                    data.len(),
+                    partition.size(),
+                ));
+            }
+            extra_segments.push((partition.offset(), data.clone()));
+        }
+
+        let mut flash_image = chip.get_flash_image(
+            &image,
+            bootloader,
+            partition_table,
+            image_format,
+            chip_revision,
+        )?;
+        if let Some(key) = &self.signing_key {
+            flash_image.sign(key)?;
+        }
+
+        // Check the placement of all segments before writing anything, so the flash
+        // isn't left half written
+        let segments: Vec<_> = flash_image
+            .flash_segments()
+            .map(|segment| (segment.addr, segment.data.into_owned()))
+            .chain(extra_segments)
+            .collect();
+        for (addr, data) in &segments {
+            check_flash_region(*addr, data.len() as u32, flash_size)?;
+        }
+
+        Ok(FlashPlan {
+            erase,
+            segments,
+            partition_table: table,
+        })
+    }
+}
+
 pub struct Flasher {
     connection: Connection,
     chip: Chip,
     flash_size: FlashSize,
+    flash_id: u32,
+    /// Silicon revision of the chip, if it can be detected
+    chip_revision: Option<u32>,
+    /// Frequency of the crystal in MHz
+    crystal_frequency: u32,
     spi_params: SpiAttachParams,
+    verify: bool,
+    encrypt: bool,
+    use_compression: bool,
+    stub: bool,
+    /// Memory taken up by the flasher stub while it's running
+    stub_memory: Vec<Range<u32>>,
+    image_settings: ImageSettings,
+    written: Vec<WrittenSegment>,
+    sector_cache: Option<SectorCache>,
+    skip_identical: bool,
+    /// Base MAC address, once read
+    mac_address: Option<String>,
+    /// Features of the chip, once read
+    chip_features: Option<Vec<String>>,
 }
 
 impl Flasher {
     pub fn connect(
         serial: Box<dyn SerialPort>,
-        port_info: UsbPortInfo,
+        port_type: SerialPortType,
+        speed: Option<u32>,
+        gpio_dtr: Option<GpioLine>,
+        gpio_rts: Option<GpioLine>,
+        settings: ConnectSettings,
+    ) -> Result<Self, Error> {
+        Self::connect_transport(
+            Box::new(serial),
+            port_type,
+            speed,
+            gpio_dtr,
+            gpio_rts,
+            settings,
+        )
+    }
+
+    /// Connect to the bootloader through another transport than a serial port
+    pub fn connect_transport(
+        transport: Box<dyn Transport>,
+        port_type: SerialPortType,
         speed: Option<u32>,
         gpio_dtr: Option<GpioLine>,
         gpio_rts: Option<GpioLine>,
+        settings: ConnectSettings,
     ) -> Result<Self, Error> {
+        let mut connection = Connection::new(transport, port_type, gpio_dtr, gpio_rts); // default baud is always 115200
+        connection.set_reset_behavior(settings.before, settings.after);
+        connection.set_timeout_scale(settings.timeout_scale);
         let mut flasher = Flasher {
-            connection: Connection::new(serial, port_info, gpio_dtr, gpio_rts), // default baud is always 115200
+            connection,
             chip: Chip::Esp8266, // dummy, set properly later
             flash_size: FlashSize::Flash4Mb,
+            flash_id: 0,
+            chip_revision: None,
+            crystal_frequency: 40,
             spi_params: SpiAttachParams::default(), // may be set when trying to attach to flash
+            verify: false,
+            encrypt: false,
+            use_compression: true,
+            stub: false,
+            stub_memory: Vec::new(),
+            image_settings: ImageSettings::default(),
+            written: Vec::new(),
+            sector_cache: None,
+            skip_identical: true,
+            mac_address: None,
+            chip_features: None,
         };
-        flasher.start_connection()?;
+        if !flasher.connection.can_reset() {
+            warn!(
+                "The device can not be reset through this port, make sure it is in download mode"
+            );
+        }
+        flasher.start_connection(settings.attempts)?;
         flasher.connection.set_timeout(DEFAULT_TIMEOUT)?;
         flasher.chip_detect()?;
+        flasher
+            .connection
+            .set_short_status(flasher.chip == Chip::Esp8266);
+        flasher.chip_revision = flasher.chip.chip_revision(&mut flasher.connection)?;
+        // Detected from the baud rate the bootloader measured, before changing it
+        flasher.crystal_frequency = flasher.chip.crystal_freq(&mut flasher.connection)?;
+        if flasher.chip == Chip::Esp8266 && flasher.crystal_frequency != 26 {
+            warn!(
+                "The ESP8266 has a {}MHz crystal, applications built for the usual 26MHz crystal print at a different baud rate",
+                flasher.crystal_frequency
+            );
+        }
         flasher.spi_autodetect()?;
 
-        if let Some(b) = speed {
-            match flasher.chip {
-                Chip::Esp8266 => (), // Not available
-                _ => {
-                    if b > 115_200 {
-                        println!("WARN setting baud rate higher than 115200 can cause issues.");
-                        flasher.change_baud(b)?;
-                    }
-                }
-            }
-        }
+        flasher.switch_speed(speed, settings.attempts)?;
 
         Ok(flasher)
     }
 
+    /// Reset the device and connect to it again, eg once it was reset into the
+    /// application after flashing
+    ///
+    /// Unlike [`Flasher::connect`] this doesn't detect the chip and the flash
+    /// again, so it has to be the same device. The flasher stub has to be
+    /// loaded again if it's used.
+    pub fn reconnect(&mut self, speed: Option<u32>, attempts: usize) -> Result<(), Error> {
+        self.resync(attempts)?;
+        self.switch_speed(speed, attempts)
+    }
+
+    /// Reset and sync with the device again at the default baud rate, and attach
+    /// the flash with the parameters detected before
+    fn resync(&mut self, attempts: usize) -> Result<(), Error> {
+        self.connection.set_baud(115_200)?;
+        self.stub = false;
+        self.connection.set_short_status(self.chip == Chip::Esp8266);
+        self.start_connection(attempts)?;
+        self.enable_flash(self.spi_params)
+    }
+
+    /// Switch to the baud rate `speed` if it's faster than the default one
+    fn switch_speed(&mut self, speed: Option<u32>, attempts: usize) -> Result<(), Error> {
+        let speed = match speed {
+            Some(speed) if speed > 115_200 => speed,
+            _ => return Ok(()),
+        };
+        // Not available
+        if self.chip == Chip::Esp8266 {
+            return Ok(());
+        }
+
+        match self.change_baud(speed) {
+            Err(Error::Connection(ConnectionError::BaudChangeFailed(_))) => {
+                warn!(
+                    "Failed to switch to {} baud, continuing at {} baud",
+                    speed,
+                    self.connection.get_baud()?
+                );
+                Ok(())
+            }
+            // The device may be left at the new speed, which only a reset undoes
+            Err(Error::Connection(ConnectionError::ConnectionFailed)) => {
+                warn!(
+                    "Lost the connection switching to {} baud, reconnecting at 115200 baud",
+                    speed
+                );
+                self.resync(attempts)
+            }
+            result => result,
+        }
+    }
+
     fn spi_autodetect(&mut self) -> Result<(), Error> {
         // loop over all available spi params until we find one that successfully reads
         // the flash size
@@ -210,20 +590,10 @@ impl Flasher {
     }
 
     fn flash_detect(&mut self) -> Result<bool, Error> {
-        let flash_id = self.spi_command(CommandType::FlashDetect, &[], 24)?;
-        let size_id = flash_id >> 16;
-
-        self.flash_size = match FlashSize::from(size_id as u8) {
-            Ok(size) => size,
-            Err(_) => {
-                eprintln!(
-                    "Warning: could not detect flash size (FlashID=0x{:02X}, SizeID=0x{:02X}), defaulting to 4MB\n",
-                    flash_id,
-                    size_id
-                );
-                FlashSize::Flash4Mb
-            }
-        };
+        let command = SpiCommand::new(self.chip.spi_registers(), CommandType::FlashDetect, &[], 24);
+        let flash_id = self.connection.run(command, &mut None)?;
+        self.flash_id = flash_id;
+        self.flash_size = detected_flash_size(flash_id);
 
         Ok(self.flash_size != FlashSize::FlashRetry)
     }
@@ -262,22 +632,35 @@ impl Flasher {
         Ok(())
     }
 
-    fn start_connection(&mut self) -> Result<(), Error> {
+    /// Reset the device into the bootloader and sync with it, alternating
+    /// between the default and the extra reset delay on every attempt
+    ///
+    /// With zero `attempts` this keeps trying until the device responds.
+    fn start_connection(&mut self, attempts: usize) -> Result<(), Error> {
         let mut extra_delay = false;
-        for i in 0..DEFAULT_CONNECT_ATTEMPTS {
-            if self.connect_attempt(extra_delay).is_err() {
-                extra_delay = !extra_delay;
-
-                let delay_text = if extra_delay { "extra" } else { "default" };
-                println!("Unable to connect, retrying with {} delay...", delay_text);
-            } else {
-                // Print a blank line if more than one connection attempt was made to visually
-                // separate the status text and whatever comes next.
-                if i > 0 {
-                    println!();
-                }
+        let mut attempt = 1;
+        loop {
+            if self.connect_attempt(extra_delay).is_ok() {
                 return Ok(());
             }
+            if attempts != 0 && attempt >= attempts {
+                break;
+            }
+            attempt += 1;
+            extra_delay = !extra_delay;
+
+            let delay_text = if extra_delay { "extra" } else { "default" };
+            if attempts == 0 {
+                debug!(
+                    "Unable to connect, retrying with {} delay (attempt {})...",
+                    delay_text, attempt
+                );
+            } else {
+                debug!(
+                    "Unable to connect, retrying with {} delay (attempt {}/{})...",
+                    delay_text, attempt, attempts
+                );
+            }
         }
 
         Err(Error::Connection(ConnectionError::ConnectionFailed))
@@ -286,7 +669,7 @@ impl Flasher {
     fn connect_attempt(&mut self, extra_delay: bool) -> Result<(), Error> {
         self.connection.reset_to_flash(extra_delay)?;
 
-        for _ in 0..5 {
+        for _ in 0..SYNC_ATTEMPTS {
             self.connection.flush()?;
             if self.sync().is_ok() {
                 return Ok(());
@@ -297,178 +680,352 @@ impl Flasher {
     }
 
     fn enable_flash(&mut self, spi_params: SpiAttachParams) -> Result<(), Error> {
-        match self.chip {
-            Chip::Esp8266 => {
-                self.connection.command(Command::FlashBegin {
-                    supports_encryption: false,
-                    offset: 0,
-                    block_size: FLASH_WRITE_SIZE as u32,
-                    size: 0,
-                    blocks: 0,
-                })?;
-            }
-            _ => {
-                self.connection
-                    .with_timeout(CommandType::SpiAttach.timeout(), |connection| {
-                        connection.command(Command::SpiAttach { spi_params })
-                    })?;
-            }
-        }
-        Ok(())
+        let steps = self
+            .chip
+            .flash_target(spi_params, self.use_compression, None)
+            .begin();
+        self.connection.run(steps, &mut None)
     }
 
-    fn spi_command(
-        &mut self,
-        command: CommandType,
-        data: &[u8],
-        read_bits: u32,
-    ) -> Result<u32, Error> {
-        assert!(read_bits < 32);
-        assert!(data.len() < 64);
+    /// Read the 32 bit register at `addr`
+    pub fn read_reg(&mut self, addr: u32) -> Result<u32, Error> {
+        self.connection.read_reg(addr)
+    }
 
-        let spi_registers = self.chip.spi_registers();
+    /// Write `value` to the 32 bit register at `addr`, only changing the bits
+    /// set in `mask` if given
+    pub fn write_reg(&mut self, addr: u32, value: u32, mask: Option<u32>) -> Result<(), Error> {
+        self.connection.write_reg(addr, value, mask)
+    }
 
-        let old_spi_usr = self.connection.read_reg(spi_registers.usr())?;
-        let old_spi_usr2 = self.connection.read_reg(spi_registers.usr2())?;
+    /// The active serial connection being used by the flasher
+    pub fn connection(&mut self) -> &mut Connection {
+        &mut self.connection
+    }
 
-        let mut flags = 1 << 31;
-        if !data.is_empty() {
-            flags |= 1 << 27;
-        }
-        if read_bits > 0 {
-            flags |= 1 << 28;
-        }
+    /// The chip type that the flasher is connected to
+    pub fn chip(&self) -> Chip {
+        self.chip
+    }
 
-        self.connection
-            .write_reg(spi_registers.usr(), flags, None)?;
-        self.connection
-            .write_reg(spi_registers.usr2(), 7 << 28 | command as u32, None)?;
-
-        if let (Some(mosi_data_length), Some(miso_data_length)) =
-            (spi_registers.mosi_length(), spi_registers.miso_length())
-        {
-            if !data.is_empty() {
-                self.connection
-                    .write_reg(mosi_data_length, data.len() as u32 * 8 - 1, None)?;
-            }
-            if read_bits > 0 {
-                self.connection
-                    .write_reg(miso_data_length, read_bits - 1, None)?;
-            }
-        } else {
-            let mosi_mask = if data.is_empty() {
-                0
-            } else {
-                data.len() as u32 * 8 - 1
-            };
-            let miso_mask = if read_bits == 0 { 0 } else { read_bits - 1 };
-            self.connection.write_reg(
-                spi_registers.usr1(),
-                miso_mask << 8 | mosi_mask << 17,
-                None,
-            )?;
+    /// The silicon revision of the chip, detected when connecting
+    pub fn chip_revision(&self) -> Option<u32> {
+        self.chip_revision
+    }
+
+    /// The flash size of the board that the flasher is connected to
+    pub fn flash_size(&self) -> FlashSize {
+        self.flash_size
+    }
+
+    /// The JEDEC ID of the flash chip, as read from the flash
+    ///
+    /// The lowest byte is the manufacturer ID, followed by the memory type and
+    /// the capacity which make up the device ID.
+    pub fn flash_id(&self) -> u32 {
+        self.flash_id
+    }
+
+    /// Verify every segment after writing it to flash, before the device is reset
+    pub fn set_verify(&mut self, verify: bool) {
+        self.verify = verify;
+    }
+
+    /// Write the bootloader, the partition table, the app partitions and the
+    /// partitions marked as encrypted through the flash encryption of the chip,
+    /// for devices with flash encryption enabled
+    ///
+    /// The other partitions are written as plaintext. Encrypted data is read
+    /// back encrypted, so it can't be verified.
+    pub fn set_encrypt(&mut self, encrypt: bool) {
+        self.encrypt = encrypt;
+    }
+
+    /// Write the application to the app partition `name`, instead of the one
+    /// selected by the image or the default one
+    pub fn set_app_partition(&mut self, name: Option<String>) {
+        self.image_settings.app_partition = name;
+    }
+
+    /// Rewrite the `otadata` partition so the bootloader starts the app
+    /// partition the application is written to
+    pub fn set_update_otadata(&mut self, update_otadata: bool) {
+        self.image_settings.update_otadata = update_otadata;
+    }
+
+    /// Erase the partitions with these names before flashing
+    ///
+    /// The partitions are looked up in the partition table which is flashed.
+    pub fn set_erase_parts(&mut self, names: Vec<String>) {
+        self.image_settings.erase_parts = names;
+    }
+
+    /// Write data to the partitions with these names after the image, eg a
+    /// generated NVS partition
+    ///
+    /// The partitions are looked up in the partition table which is flashed.
+    pub fn set_partition_data(&mut self, data: Vec<(String, Vec<u8>)>) {
+        self.image_settings.partition_data = data;
+    }
+
+    /// Sign the bootloader and the application for Secure Boot v2 with this
+    /// key
+    pub fn set_signing_key(&mut self, key: Option<SigningKey>) {
+        self.image_settings.signing_key = key;
+    }
+
+    /// Write these flash settings to the image headers, instead of the ones of
+    /// the image
+    pub fn set_flash_settings(&mut self, settings: FlashSettings) {
+        self.image_settings.flash_settings = settings;
+    }
+
+    /// Replace these fields of the application description of ESP-IDF images
+    pub fn set_app_descriptor(&mut self, settings: AppDescriptorSettings) {
+        self.image_settings.app_descriptor = settings;
+    }
+
+    /// Replace all settings for flashing ELF images at once
+    pub fn set_image_settings(&mut self, settings: ImageSettings) {
+        self.image_settings = settings;
+    }
+
+    /// Use these offsets instead of the default flash layout of the chip
+    pub fn set_flash_layout(&mut self, layout: FlashLayout) {
+        self.image_settings.flash_layout = layout;
+    }
+
+    /// The flash layout set with [`Flasher::set_flash_layout`]
+    pub fn flash_layout(&self) -> FlashLayout {
+        self.image_settings.flash_layout
+    }
+
+    /// Offset of the partition table, as given in the flash layout
+    pub fn partition_table_offset(&self) -> u32 {
+        self.image_settings
+            .flash_layout
+            .partition_table_offset
+            .unwrap_or(PARTITION_TABLE_OFFSET)
+    }
+
+    /// Only write the flash sectors which differ from the ones in `cache`,
+    /// which is updated with the sectors written
+    pub fn set_sector_cache(&mut self, cache: Option<SectorCache>) {
+        self.sector_cache = cache;
+    }
+
+    /// The sector cache in use, holding the sectors written so far
+    pub fn sector_cache(&self) -> Option<&SectorCache> {
+        self.sector_cache.as_ref()
+    }
+
+    /// Check the MD5 digest of the flash before writing each segment, and
+    /// skip the ones which the flash already contains
+    ///
+    /// Enabled by default, except on the ESP8266 which can't calculate them.
+    pub fn set_skip_identical(&mut self, skip_identical: bool) {
+        self.skip_identical = skip_identical;
+    }
+
+    /// Compress the data written to flash, enabled by default
+    ///
+    /// Compression is not supported by the ESP8266 ROM, which always writes the
+    /// data uncompressed.
+    pub fn set_compression(&mut self, use_compression: bool) {
+        self.use_compression = use_compression;
+    }
+
+    /// Check that the flash at `addr` contains `data`
+    ///
+    /// The flash isn't read back, instead the device calculates the MD5 digest of
+    /// the region which is compared with the digest of `data`.
+    pub fn verify_segment(&mut self, addr: u32, data: &[u8]) -> Result<(), Error> {
+        if self.chip == Chip::Esp8266 {
+            return Err(Error::VerifyUnsupported(self.chip));
         }
 
-        if data.is_empty() {
-            self.connection.write_reg(spi_registers.w0(), 0, None)?;
+        if self.flash_digest(addr, data.len() as u32)?[..] == md5::compute(data).0[..] {
+            Ok(())
         } else {
-            for (i, bytes) in data.chunks(4).enumerate() {
-                let mut data_bytes = [0; 4];
-                data_bytes[0..bytes.len()].copy_from_slice(bytes);
-                let data = u32::from_le_bytes(data_bytes);
-                self.connection
-                    .write_reg(spi_registers.w0() + i as u32, data, None)?;
-            }
+            Err(Error::VerifyFailed(addr))
         }
+    }
 
-        self.connection
-            .write_reg(spi_registers.cmd(), 1 << 18, None)?;
-
-        let mut i = 0;
-        loop {
-            sleep(Duration::from_millis(1));
-            if self.connection.read_reg(spi_registers.usr())? & (1 << 18) == 0 {
-                break;
-            }
-            i += 1;
-            if i > 10 {
-                return Err(Error::Connection(ConnectionError::Timeout(command.into())));
-            }
-        }
+    /// MD5 digest of `size` bytes of flash at `addr`, calculated by the device
+    fn flash_digest(&mut self, addr: u32, size: u32) -> Result<Vec<u8>, Error> {
+        let response = self
+            .connection
+            .with_timeout(CommandType::FlashMd5.timeout_for_size(size), |connection| {
+                connection.command_with_data(Command::FlashMd5 { offset: addr, size })
+            })?;
 
-        let result = self.connection.read_reg(spi_registers.w0())?;
-        self.connection
-            .write_reg(spi_registers.usr(), old_spi_usr, None)?;
-        self.connection
-            .write_reg(spi_registers.usr2(), old_spi_usr2, None)?;
+        // The ROM responds with the digest as hex, the stub with the raw bytes
+        let digest = match response.len() {
+            16 => response,
+            32 => std::str::from_utf8(&response)
+                .ok()
+                .and_then(|hex| {
+                    (0..16)
+                        .map(|i| u8::from_str_radix(hex.get(i * 2..i * 2 + 2)?, 16).ok())
+                        .collect::<Option<Vec<u8>>>()
+                })
+                .ok_or(Error::Connection(ConnectionError::FramingError))?,
+            _ => return Err(Error::Connection(ConnectionError::FramingError)),
+        };
 
-        Ok(result)
+        Ok(digest)
     }
 
-    /// The active serial connection being used by the flasher
-    pub fn connection(&mut self) -> &mut Connection {
-        &mut self.connection
+    /// Read the base MAC address of the connected board from its efuses
+    ///
+    /// The address is formatted as colon separated hex bytes, eg
+    /// `7c:df:a1:40:02:a4`. It's only read from the device the first time, so
+    /// it's still known once the device was reset after flashing.
+    pub fn mac_address(&mut self) -> Result<String, Error> {
+        if let Some(mac) = &self.mac_address {
+            return Ok(mac.clone());
+        }
+        let chip = self.chip;
+        let mac = chip.mac_address(self.connection())?;
+        self.mac_address = Some(mac.clone());
+        Ok(mac)
     }
 
-    /// The chip type that the flasher is connected to
-    pub fn chip(&self) -> Chip {
-        self.chip
+    /// Read the contents of all efuse blocks of the connected chip
+    ///
+    /// The words of each block are returned in the order of
+    /// [`efuse::blocks`](crate::efuse::blocks).
+    pub fn read_efuses(&mut self) -> Result<Vec<Vec<u32>>, Error> {
+        efuse::blocks(self.chip)
+            .iter()
+            .map(|block| self.connection.read_regs(block.addr, block.words))
+            .collect()
     }
 
-    /// The flash size of the board that the flasher is connected to
-    pub fn flash_size(&self) -> FlashSize {
-        self.flash_size
+    /// Read any information we can about the connected board
+    ///
+    /// Like the MAC address, the information is only read from the device the
+    /// first time, so it can still be reported once the device was reset after
+    /// flashing.
+    pub fn device_info(&mut self) -> Result<DeviceInfo, Error> {
+        let chip = self.chip();
+
+        let revision = self.chip_revision;
+        let features = match &self.chip_features {
+            Some(features) => features.clone(),
+            None => {
+                let features: Vec<_> = chip
+                    .chip_features(self.connection())?
+                    .iter()
+                    .map(|feature| feature.to_string())
+                    .collect();
+                self.chip_features = Some(features.clone());
+                features
+            }
+        };
+        let crystal_frequency = self.crystal_frequency;
+        let mac_address = self.mac_address()?;
+
+        let manufacturer = (self.flash_id & 0xff) as u8;
+
+        Ok(DeviceInfo {
+            chip: chip.to_string(),
+            revision,
+            crystal_frequency,
+            flash_size: self.flash_size().to_string(),
+            flash_manufacturer: manufacturer,
+            flash_manufacturer_name: flash_manufacturer(manufacturer),
+            flash_device: ((self.flash_id >> 8) & 0xff) << 8 | (self.flash_id >> 16) & 0xff,
+            features,
+            mac_address,
+        })
     }
 
     /// Read and print any information we can about the connected board
     pub fn board_info(&mut self) -> Result<(), Error> {
-        let chip = self.chip();
-        let size = self.flash_size();
-
-        let maybe_revision = chip.chip_revision(self.connection())?;
-        let features = chip.chip_features(self.connection())?;
-        let freq = chip.crystal_freq(self.connection())?;
-        let mac = chip.mac_address(self.connection())?;
+        let info = self.device_info()?;
 
-        print!("Chip type:         {}", chip);
-        match maybe_revision {
-            Some(revision) => println!(" (revision {})", revision),
-            None => println!(),
+        match info.revision {
+            Some(revision) => info!("Chip type:         {} (revision {})", info.chip, revision),
+            None => info!("Chip type:         {}", info.chip),
         }
-        println!("Crystal frequency: {}MHz", freq);
-        println!("Flash size:        {}", size);
-        println!("Features:          {}", features.join(", "));
-        println!("MAC address:       {}", mac);
+        info!("Crystal frequency: {}MHz", info.crystal_frequency);
+        info!("Flash size:        {}", info.flash_size);
+        match info.flash_manufacturer_name {
+            Some(name) => info!(
+                "Flash ID:          manufacturer {:#04x} ({}), device {:#06x}",
+                info.flash_manufacturer, name, info.flash_device
+            ),
+            None => info!(
+                "Flash ID:          manufacturer {:#04x}, device {:#06x}",
+                info.flash_manufacturer, info.flash_device
+            ),
+        }
+        info!("Features:          {}", info.features.join(", "));
+        info!("MAC address:       {}", info.mac_address);
 
         Ok(())
     }
 
+    /// The data written to flash by the last flashing operation
+    pub fn written_segments(&self) -> &[WrittenSegment] {
+        &self.written
+    }
+
     /// Load an elf image to ram and execute it
     ///
     /// Note that this will not touch the flash on the device
-    pub fn load_elf_to_ram(&mut self, elf_data: &[u8]) -> Result<(), Error> {
-        let image = FirmwareImage::from_data(elf_data)?;
+    pub fn load_elf_to_ram(
+        &mut self,
+        elf_data: &[u8],
+        progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        self.load_elf_to_ram_with_entry(elf_data, None, progress)
+    }
 
-        let mut target = self.chip.ram_target();
-        target.begin(&mut self.connection, &image).flashing()?;
+    /// Load an elf image to ram and execute it from `entry`, or the entry point
+    /// of the image if not given
+    pub fn load_elf_to_ram_with_entry(
+        &mut self,
+        elf_data: &[u8],
+        entry: Option<u32>,
+        mut progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        let image = FirmwareImage::from_data(elf_data)?;
+        image.check_chip(self.chip)?;
 
         if image.rom_segments(self.chip).next().is_some() {
             return Err(Error::ElfNotRamLoadable);
         }
 
-        for segment in image.ram_segments(self.chip) {
-            target
-                .write_segment(
-                    &mut self.connection,
-                    RomSegment {
-                        addr: segment.addr,
-                        data: Cow::Borrowed(segment.data()),
-                    },
-                )
-                .flashing()?;
+        let segments = image.ram_load_segments(self.chip);
+        for segment in &segments {
+            let range = segment.addr..segment.addr + segment.size();
+            if self
+                .stub_memory
+                .iter()
+                .any(|stub| stub.start < range.end && range.start < stub.end)
+            {
+                return Err(Error::RamSegmentOverlapsStub(segment.addr, segment.size()));
+            }
+        }
+
+        let mut target = self
+            .chip
+            .ram_target(Some(entry.unwrap_or_else(|| image.entry())));
+        self.connection.run(target.begin(), &mut None).flashing()?;
+
+        for segment in &segments {
+            let write = target.write_segment(RomSegment {
+                addr: segment.addr,
+                data: Cow::Borrowed(segment.data()),
+            })?;
+            self.connection.run(write, &mut progress).flashing()?;
         }
 
-        target.finish(&mut self.connection, true).flashing()
+        self.connection
+            .run(target.finish(true), &mut None)
+            .flashing()
     }
 
     /// Load an elf image to flash and execute it
@@ -478,30 +1035,47 @@ impl Flasher {
         bootloader: Option<Vec<u8>>,
         partition_table: Option<PartitionTable>,
         image_format: Option<ImageFormatId>,
+        mut progress: Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
-        let mut image = FirmwareImage::from_data(elf_data)?;
-        image.flash_size = self.flash_size();
-
-        let mut target = self.chip.flash_target(self.spi_params);
-        target.begin(&mut self.connection, &image).flashing()?;
-
-        let flash_image = self.chip.get_flash_image(
-            &image,
+        let plan = self.image_settings.plan(
+            self.chip,
+            self.flash_size,
+            self.chip_revision,
+            elf_data,
             bootloader,
             partition_table,
             image_format,
-            self.chip.chip_revision(&mut self.connection)?,
         )?;
 
-        for segment in flash_image.flash_segments() {
-            target
-                .write_segment(&mut self.connection, segment)
-                .flashing()?;
+        for (name, offset, size) in &plan.erase {
+            info!("Erasing partition {}", name);
+            self.erase_region(*offset, *size)?;
         }
 
-        target.finish(&mut self.connection, true).flashing()?;
+        let encryption = self.encryption(plan.partition_table.as_ref())?;
+        let mut target =
+            self.chip
+                .flash_target(self.spi_params, self.use_compression, encryption.clone());
+        self.connection.run(target.begin(), &mut None).flashing()?;
+
+        self.written.clear();
+        for (addr, data) in &plan.segments {
+            self.written.push(WrittenSegment::new(*addr, data));
+            self.write_flash_segment(
+                &mut *target,
+                *addr,
+                data,
+                is_encrypted(&encryption, *addr),
+                &mut progress,
+            )?;
+            if self.verify && !is_encrypted(&encryption, *addr) {
+                self.verify_segment(*addr, data)?;
+            }
+        }
 
-        Ok(())
+        self.connection
+            .run(target.finish(true), &mut None)
+            .flashing()
     }
 
     /// Load an elf image to flash and execute it
@@ -510,26 +1084,471 @@ impl Flasher {
         elf_data: &[u8],
         bootloader: Option<Vec<u8>>,
         partition_table: Option<PartitionTable>,
+        progress: Option<&mut dyn ProgressCallbacks>,
     ) -> Result<(), Error> {
-        self.load_elf_to_flash_with_format(elf_data, bootloader, partition_table, None)
+        self.load_elf_to_flash_with_format(elf_data, bootloader, partition_table, None, progress)
     }
 
-    pub fn change_baud(&mut self, speed: u32) -> Result<(), Error> {
+    /// Write raw data to flash at `addr` and reset the device
+    pub fn write_bin_to_flash(
+        &mut self,
+        addr: u32,
+        data: &[u8],
+        progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        self.write_bins_to_flash(once((addr, data)), progress)
+    }
+
+    /// Write multiple pieces of raw data to flash and reset the device
+    ///
+    /// This doesn't parse the data in any way, so it can be used to flash
+    /// binaries created by other tools.
+    pub fn write_bins_to_flash<'a, I>(
+        &mut self,
+        bins: I,
+        progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (u32, &'a [u8])>,
+    {
+        self.write_bins_to_flash_with_options(
+            bins.into_iter()
+                .map(|(addr, data)| (addr, data, BinOptions::default())),
+            progress,
+        )
+    }
+
+    /// Write multiple pieces of raw data to flash like
+    /// [`Flasher::write_bins_to_flash`], with options for every one of them
+    /// overriding the ones of the flasher
+    pub fn write_bins_to_flash_with_options<'a, I>(
+        &mut self,
+        bins: I,
+        mut progress: Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error>
+    where
+        I: IntoIterator<Item = (u32, &'a [u8], BinOptions)>,
+    {
+        let bins: Vec<_> = bins.into_iter().collect();
+        for (addr, data, _) in &bins {
+            self.check_flash_region(*addr, data.len() as u32)?;
+        }
+
+        // Look up the partitions to encrypt in the partition table which is
+        // flashed, if any
+        let default_encryption = if self.encrypt {
+            let table = bins
+                .iter()
+                .find(|(addr, _, _)| *addr == self.partition_table_offset())
+                .and_then(|(_, data, _)| {
+                    PartitionTable::try_from_bytes_at(data, self.partition_table_offset()).ok()
+                })
+                .or_else(|| {
+                    self.chip
+                        .default_partition_table(self.image_settings.flash_layout)
+                });
+            self.encryption(table.as_ref())?
+        } else {
+            None
+        };
+        let encrypted: Vec<_> = bins
+            .iter()
+            .map(|(addr, _, options)| {
+                options
+                    .encrypt
+                    .unwrap_or_else(|| is_encrypted(&default_encryption, *addr))
+            })
+            .collect();
+        let encryption = if encrypted.contains(&true) {
+            self.check_encryption_support()?;
+            let regions = bins
+                .iter()
+                .zip(&encrypted)
+                .filter(|(_, &encrypted)| encrypted)
+                .map(|((addr, data, _), _)| *addr..*addr + data.len() as u32)
+                .collect();
+            Some(Encryption {
+                stub: self.stub,
+                regions,
+            })
+        } else {
+            None
+        };
+
+        let mut target =
+            self.chip
+                .flash_target(self.spi_params, self.use_compression, encryption.clone());
+        self.connection.run(target.begin(), &mut None).flashing()?;
+
+        self.written.clear();
+        for (addr, data, options) in bins {
+            self.written.push(WrittenSegment::new(addr, data));
+            self.write_flash_segment(
+                &mut *target,
+                addr,
+                data,
+                is_encrypted(&encryption, addr),
+                &mut progress,
+            )?;
+            if options.verify.unwrap_or(self.verify) && !is_encrypted(&encryption, addr) {
+                self.verify_segment(addr, data)?;
+            }
+        }
+
         self.connection
-            .with_timeout(CommandType::ChangeBaud.timeout(), |connection| {
-                connection.command(Command::ChangeBaud { speed })
+            .run(target.finish(true), &mut None)
+            .flashing()
+    }
+
+    /// Write `data` at `addr` through `target`, leaving out the sectors which
+    /// the sector cache knows to be unchanged
+    fn write_flash_segment(
+        &mut self,
+        target: &mut dyn FlashTarget,
+        addr: u32,
+        data: &[u8],
+        encrypted: bool,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<(), Error> {
+        // Without a cache every sector counts as changed
+        let empty = SectorCache::default();
+        let cache = match &self.sector_cache {
+            Some(cache) if !encrypted => cache,
+            _ => &empty,
+        };
+        let ranges = cache.changed(addr, data);
+        let skipped = data.len() - ranges.iter().map(|range| range.len()).sum::<usize>();
+        if skipped > 0 {
+            info!(
+                "Skipping {} of {} bytes at {:#x}, unchanged since the last flash",
+                skipped,
+                data.len(),
+                addr
+            );
+        }
+
+        // The flash of encrypted segments holds the encrypted data
+        let check_flash = self.skip_identical && !encrypted && self.chip != Chip::Esp8266;
+
+        for range in ranges {
+            let start = addr + range.start as u32;
+            let data = &data[range];
+            // Forgotten first, in case the write fails halfway
+            if let Some(cache) = &mut self.sector_cache {
+                cache.forget(start, data.len() as u32);
+            }
+            if check_flash
+                && self.flash_digest(start, data.len() as u32)?[..] == md5::compute(data).0[..]
+            {
+                info!(
+                    "Skipping {} bytes at {:#x}, the flash already contains them",
+                    data.len(),
+                    start
+                );
+                if let Some(cache) = &mut self.sector_cache {
+                    cache.insert(start, data);
+                }
+                continue;
+            }
+            let segment = RomSegment {
+                addr: start,
+                data: Cow::Borrowed(data),
+            };
+            let write = target.write_segment(segment)?;
+            self.connection.run(write, progress).flashing()?;
+            if let (Some(cache), false) = (&mut self.sector_cache, encrypted) {
+                cache.insert(start, data);
+            }
+        }
+
+        Ok(())
+    }
+
+    /// Erase the entire flash
+    ///
+    /// The ROM loader has no command to erase the whole chip, instead a flash
+    /// write covering the entire flash is started, which erases it.
+    pub fn erase_flash(&mut self) -> Result<(), Error> {
+        self.erase_region(0, self.flash_size.size())
+    }
+
+    /// Erase `size` bytes of flash starting at `offset`, both have to be aligned
+    /// to flash sectors
+    ///
+    /// The flasher stub erases the region with a dedicated command, with the ROM
+    /// loader a flash write covering the region is started instead, like
+    /// [`Flasher::erase_flash`] does.
+    pub fn erase_region(&mut self, offset: u32, size: u32) -> Result<(), Error> {
+        let chip = self.chip;
+        if offset % FLASH_SECTOR_SIZE as u32 != 0 || size % FLASH_SECTOR_SIZE as u32 != 0 {
+            return Err(Error::UnalignedEraseRegion(offset, size));
+        }
+        self.check_flash_region(offset, size)?;
+        if let Some(cache) = &mut self.sector_cache {
+            cache.forget(offset, size);
+        }
+
+        let step = erase_region_step(chip, self.stub, offset, size);
+        self.connection.run(Steps::from(Some(step)), &mut None)
+    }
+
+    /// The regions to write through the flash encryption, if enabled
+    ///
+    /// Besides the bootloader and the partition table, ESP-IDF encrypts the app
+    /// partitions and the partitions marked as encrypted in `table`.
+    fn encryption(&self, table: Option<&PartitionTable>) -> Result<Option<Encryption>, Error> {
+        if !self.encrypt {
+            return Ok(None);
+        }
+        self.check_encryption_support()?;
+
+        // The partition table is at most 0xC00 bytes long
+        let mut regions = Vec::new();
+        regions.push(0..self.partition_table_offset() + 0xc00);
+        if let Some(table) = table {
+            regions.extend(
+                table
+                    .partitions()
+                    .iter()
+                    .filter(|partition| {
+                        partition.ty() == Type::App || partition.flags().contains(Flags::ENCRYPTED)
+                    })
+                    .map(|partition| partition.offset()..partition.offset() + partition.size()),
+            );
+        }
+
+        Ok(Some(Encryption {
+            stub: self.stub,
+            regions,
+        }))
+    }
+
+    fn check_encryption_support(&self) -> Result<(), Error> {
+        match self.chip {
+            Chip::Esp8266 => Err(Error::EncryptionNotSupported(self.chip)),
+            Chip::Esp32 if !self.stub => Err(Error::EncryptionRequiresStub(self.chip)),
+            _ => Ok(()),
+        }
+    }
+
+    /// Check that the region fits into the detected flash
+    fn check_flash_region(&self, offset: u32, size: u32) -> Result<(), Error> {
+        check_flash_region(offset, size, self.flash_size)
+    }
+
+    /// Read `size` bytes of flash starting at `offset` and write them to `writer`
+    ///
+    /// The flasher stub streams the data a sector at a time, the ROM loader
+    /// only has the slow read command which transfers 64 bytes at a time.
+    pub fn read_flash<W: Write>(
+        &mut self,
+        offset: u32,
+        size: u32,
+        writer: &mut W,
+    ) -> Result<(), Error> {
+        const BLOCK_SIZE: u32 = 64;
+
+        let end = match offset.checked_add(size) {
+            Some(end) if end <= self.flash_size.size() => end,
+            _ => return Err(Error::FlashRegionOutOfRange(offset, size, self.flash_size)),
+        };
+        if self.stub {
+            return self.read_flash_stub(offset, size, writer);
+        }
+        if self.chip == Chip::Esp8266 {
+            return Err(Error::ReadFlashUnsupported(self.chip));
+        }
+
+        let mut address = offset;
+        while address < end {
+            let block_size = BLOCK_SIZE.min(end - address);
+            let block = self.connection.with_timeout(
+                CommandType::ReadFlashSlow.timeout(),
+                |connection| {
+                    connection.command_with_data(Command::ReadFlashSlow {
+                        offset: address,
+                        size: block_size,
+                    })
+                },
+            )?;
+            if block.len() < block_size as usize {
+                return Err(Error::Connection(ConnectionError::FramingError));
+            }
+            writer.write_all(&block[..block_size as usize])?;
+            address += block_size;
+        }
+
+        Ok(())
+    }
+
+    /// Read flash through the flasher stub, which sends the blocks on its own
+    /// as long as their receipt is acknowledged, followed by their MD5 digest
+    fn read_flash_stub<W: Write>(
+        &mut self,
+        offset: u32,
+        size: u32,
+        writer: &mut W,
+    ) -> Result<(), Error> {
+        const MAX_IN_FLIGHT: u32 = 64;
+
+        let timeout = CommandType::ReadFlash.timeout();
+        self.connection.with_timeout(timeout, |connection| {
+            connection.command(Command::ReadFlash {
+                offset,
+                size,
+                block_size: FLASH_SECTOR_SIZE as u32,
+                max_in_flight: MAX_IN_FLIGHT,
             })?;
-        self.connection.set_baud(speed)?;
-        std::thread::sleep(Duration::from_secs_f32(0.05));
-        self.connection.flush()?;
+
+            let mut digest = md5::Context::new();
+            let mut received = 0;
+            while received < size {
+                let block = connection.read_frame()?;
+                received += block.len() as u32;
+                // Only the last block may be shorter than a sector
+                if received < size && block.len() < FLASH_SECTOR_SIZE || received > size {
+                    return Err(Error::ReadFlashCorrupted);
+                }
+                digest.consume(&block);
+                writer.write_all(&block)?;
+                connection.write_frame(&received.to_le_bytes())?;
+            }
+
+            if connection.read_frame()?[..] != digest.compute().0[..] {
+                return Err(Error::ReadFlashCorrupted);
+            }
+            Ok(())
+        })
+    }
+
+    /// Switch to a different baud rate, see [`Connection::change_baud`]
+    pub fn change_baud(&mut self, speed: u32) -> Result<(), Error> {
+        // The ROM of the ESP32-C2 calculates the baud rate for a 40MHz crystal
+        let device_speed =
+            if self.chip == Chip::Esp32c2 && !self.stub && self.crystal_frequency == 26 {
+                (speed as u64 * 40 / 26) as u32
+            } else {
+                speed
+            };
+        self.connection
+            .change_baud(speed, device_speed, CHIP_DETECT_MAGIC_REG_ADDR, self.stub)
+    }
+
+    /// The frequency of the crystal of the chip in MHz, detected when connecting
+    pub fn crystal_frequency(&self) -> u32 {
+        self.crystal_frequency
+    }
+
+    /// Load the flasher stub into RAM and run it
+    ///
+    /// The stub replaces the ROM bootloader until the device is reset.
+    pub fn load_stub(&mut self, stub: &FlashStub) -> Result<(), Error> {
+        if self.stub {
+            return Ok(());
+        }
+
+        let (before, after) = self.connection.reset_behavior();
+        if after == ResetAfterOperation::SoftReset {
+            warn!(
+                "The flasher stub can't start the application without a reset, using a hard reset"
+            );
+            self.connection
+                .set_reset_behavior(before, ResetAfterOperation::HardReset);
+        }
+
+        info!("Loading the flasher stub");
+        let mut target = self.chip.ram_target(Some(stub.entry()));
+        self.connection.run(target.begin(), &mut None).flashing()?;
+        for segment in stub.segments() {
+            let write = target.write_segment(segment)?;
+            self.connection.run(write, &mut None).flashing()?;
+        }
+        self.connection
+            .run(target.finish(true), &mut None)
+            .flashing()?;
+
+        self.connection.wait_for_stub()?;
+        self.stub = true;
+        self.connection.set_short_status(true);
+        self.stub_memory = stub
+            .segments()
+            .map(|segment| segment.addr..segment.addr + segment.data.len() as u32)
+            .collect();
+
         Ok(())
     }
 
-    pub fn into_serial(self) -> Box<dyn SerialPort> {
-        self.connection.into_serial()
+    /// Whether the flasher stub is running
+    pub fn is_stub(&self) -> bool {
+        self.stub
+    }
+
+    pub fn into_transport(self) -> Box<dyn Transport> {
+        self.connection.into_transport()
+    }
+}
+
+/// The command erasing a region of flash, with the dedicated command of the
+/// flasher stub or by starting a flash write covering it with the ROM loader
+pub(crate) fn erase_region_step(chip: Chip, stub: bool, offset: u32, size: u32) -> Step<'static> {
+    if stub {
+        return Step::Command(
+            Command::EraseRegion { offset, size },
+            CommandType::EraseRegion.timeout_for_size(size),
+        );
+    }
+
+    let erase_size = match chip {
+        Chip::Esp8266 => get_erase_size(offset as usize, size as usize) as u32,
+        _ => size,
+    };
+    Step::Command(
+        Command::FlashBegin {
+            size: erase_size,
+            blocks: 0,
+            block_size: FLASH_WRITE_SIZE as u32,
+            offset,
+            supports_encryption: chip != Chip::Esp32 && chip != Chip::Esp8266,
+            encrypted: false,
+        },
+        CommandType::FlashBegin.timeout_for_size(size),
+    )
+}
+
+/// The size of the flash with the JEDEC ID `flash_id`, unknown sizes are
+/// assumed to be 4MB
+pub(crate) fn detected_flash_size(flash_id: u32) -> FlashSize {
+    let size_id = flash_id >> 16;
+    match FlashSize::from(size_id as u8) {
+        Ok(size) => size,
+        Err(_) => {
+            warn!(
+                "Could not detect flash size (FlashID=0x{:02X}, SizeID=0x{:02X}), defaulting to 4MB",
+                flash_id,
+                size_id
+            );
+            FlashSize::Flash4Mb
+        }
+    }
+}
+
+pub(crate) fn check_flash_region(
+    offset: u32,
+    size: u32,
+    flash_size: FlashSize,
+) -> Result<(), Error> {
+    match offset.checked_add(size) {
+        Some(end) if end <= flash_size.size() => Ok(()),
+        _ => Err(Error::FlashRegionOutOfRange(offset, size, flash_size)),
     }
 }
 
+fn is_encrypted(encryption: &Option<Encryption>, addr: u32) -> bool {
+    encryption
+        .as_ref()
+        .map_or(false, |encryption| encryption.covers(addr))
+}
+
 pub(crate) fn get_erase_size(offset: usize, size: usize) -> usize {
     let sector_count = (size + FLASH_SECTOR_SIZE - 1) / FLASH_SECTOR_SIZE;
     let start_sector = offset / FLASH_SECTOR_SIZE;
@@ -555,3 +1574,386 @@ pub fn checksum(data: &[u8], mut checksum: u8) -> u8 {
 
     checksum
 }
+
+/// The name of the flash manufacturer with the JEDEC manufacturer `id`, for the
+/// manufacturers commonly found on Espressif modules
+pub(crate) fn flash_manufacturer(id: u8) -> Option<&'static str> {
+    match id {
+        0x1c => Some("EON"),
+        0x20 => Some("XMC"),
+        0x5e => Some("Zbit"),
+        0x68 => Some("Boya"),
+        0x9d => Some("ISSI"),
+        0xa1 => Some("Fudan"),
+        0xc2 => Some("Macronix"),
+        0xc8 => Some("GigaDevice"),
+        0xef => Some("Winbond"),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::{
+        reset::{HardReset, ResetBeforeOperation, ResetLines, ResetStrategy},
+        transport::mock::{MockDevice, FLASH_ID},
+    };
+
+    fn connect_mock(device: &MockDevice, speed: Option<u32>) -> Result<Flasher, Error> {
+        let settings = ConnectSettings {
+            before: ResetBeforeOperation::NoReset,
+            after: ResetAfterOperation::NoReset,
+            ..ConnectSettings::default()
+        };
+        Flasher::connect_transport(
+            Box::new(device.clone()),
+            SerialPortType::Unknown,
+            speed,
+            None,
+            None,
+            settings,
+        )
+    }
+
+    #[test]
+    fn connects_to_the_rom() {
+        let device = MockDevice::new();
+        let mut flasher = connect_mock(&device, None).unwrap();
+        assert_eq!(flasher.chip(), Chip::Esp32c3);
+        assert_eq!(flasher.flash_id, FLASH_ID);
+        assert_eq!(flasher.flash_size, FlashSize::Flash4Mb);
+        assert_eq!(device.commands()[0], CommandType::Sync as u8);
+        assert_eq!(flasher.connection().get_baud().unwrap(), 115_200);
+
+        // The baud rate is only kept if the device responds at it
+        let flasher = connect_mock(&device, Some(921_600)).unwrap();
+        assert_eq!(flasher.into_transport().baud_rate().unwrap(), 921_600);
+        let device = MockDevice::new();
+        device.refuse_baud_change();
+        let flasher = connect_mock(&device, Some(921_600)).unwrap();
+        assert_eq!(flasher.into_transport().baud_rate().unwrap(), 115_200);
+    }
+
+    #[test]
+    fn reconnects_after_losing_the_device_to_a_baud_change() {
+        // The device follows the baud change, but the link doesn't work at that speed
+        let device = MockDevice::new();
+        device.limit_baud(460_800);
+        let settings = ConnectSettings {
+            after: ResetAfterOperation::NoReset,
+            ..ConnectSettings::default()
+        };
+        let mut flasher = Flasher::connect_transport(
+            Box::new(device.clone()),
+            SerialPortType::Unknown,
+            Some(921_600),
+            None,
+            None,
+            settings,
+        )
+        .unwrap();
+        assert_eq!(flasher.connection().get_baud().unwrap(), 115_200);
+        flasher.erase_region(0x9000, 0x1000).unwrap();
+    }
+
+    fn test_stub() -> FlashStub {
+        FlashStub::from_json(
+            r#"{"entry": 1077411840, "text": "SGVsbG8sIHN0dWIh", "text_start": 1077411840,
+                "data": "AAEC/w==", "data_start": 1070163968}"#,
+        )
+        .unwrap()
+    }
+
+    #[test]
+    fn loads_the_stub_and_resets() {
+        let device = MockDevice::new();
+        let mut flasher = connect_mock(&device, None).unwrap();
+        flasher.load_stub(&test_stub()).unwrap();
+        assert!(flasher.is_stub() && device.is_stub());
+
+        device.set_flash(0x9000, &[0; 0x1000]);
+        flasher.erase_region(0x9000, 0x1000).unwrap();
+        assert_eq!(device.flash(0x9000, 0x1000), [0xff; 0x1000]);
+
+        // The stub ends the responses with two status bytes
+        let data = vec![0x5a; 0x1800];
+        flasher.set_verify(true);
+        flasher.write_bin_to_flash(0x10000, &data, None).unwrap();
+        assert_eq!(device.flash(0x10000, data.len()), data);
+        device.set_flash(0x10000, &[0]);
+        assert!(matches!(
+            flasher.verify_segment(0x10000, &data),
+            Err(Error::VerifyFailed(0x10000))
+        ));
+
+        let mut transport = flasher.into_transport();
+        HardReset {
+            usb_serial_jtag: false,
+        }
+        .reset(&mut ResetLines::new(&mut *transport, &None, &None))
+        .unwrap();
+        assert!(device.is_running_app() && !device.is_stub());
+    }
+
+    #[test]
+    fn reads_flash_through_the_rom_and_the_stub() {
+        let device = MockDevice::new();
+        let data: Vec<u8> = (0..0x2100).map(|i| (i % 253) as u8).collect();
+        device.set_flash(0x20000, &data);
+        let mut flasher = connect_mock(&device, None).unwrap();
+
+        let mut read = Vec::new();
+        flasher.read_flash(0x20000, 100, &mut read).unwrap();
+        assert_eq!(read, data[..100]);
+        assert!(device
+            .commands()
+            .contains(&(CommandType::ReadFlashSlow as u8)));
+
+        flasher.load_stub(&test_stub()).unwrap();
+        let mut read = Vec::new();
+        flasher
+            .read_flash(0x20000, data.len() as u32, &mut read)
+            .unwrap();
+        assert_eq!(read, data);
+        assert!(device.commands().contains(&(CommandType::ReadFlash as u8)));
+    }
+
+    #[test]
+    fn erases_and_writes_flash() {
+        let device = MockDevice::new();
+        device.set_flash(0x9000, &[0; 0x2000]);
+        let mut flasher = connect_mock(&device, None).unwrap();
+        flasher.erase_region(0x9000, 0x1000).unwrap();
+        assert_eq!(device.flash(0x9000, 0x1000), [0xff; 0x1000]);
+        assert_eq!(device.flash(0xa000, 0x1000), [0; 0x1000]);
+
+        let data: Vec<u8> = (0..0x1800).map(|i| (i % 251) as u8).collect();
+        flasher.set_verify(true);
+        for compression in [true, false] {
+            flasher.set_compression(compression);
+            flasher.write_bin_to_flash(0x10000, &data, None).unwrap();
+            assert_eq!(device.flash(0x10000, data.len()), data);
+            let begin = if compression {
+                CommandType::FlashDeflateBegin
+            } else {
+                CommandType::FlashBegin
+            };
+            assert!(device.commands().contains(&(begin as u8)));
+        }
+
+        device.set_flash(0x10000, &[0xaa]);
+        assert!(matches!(
+            flasher.verify_segment(0x10000, &data),
+            Err(Error::VerifyFailed(0x10000))
+        ));
+    }
+
+    #[test]
+    fn skips_cached_sectors() {
+        let device = MockDevice::new();
+        let mut flasher = connect_mock(&device, None).unwrap();
+        flasher.set_sector_cache(Some(SectorCache::default()));
+        flasher.set_skip_identical(false);
+
+        let mut data = vec![0x5a; FLASH_SECTOR_SIZE * 8];
+        flasher.write_bin_to_flash(0x10000, &data, None).unwrap();
+        let begin = CommandType::FlashDeflateBegin as u8;
+        let writes =
+            |device: &MockDevice| device.commands().iter().filter(|&&op| op == begin).count();
+        assert_eq!(writes(&device), 1);
+        flasher.write_bin_to_flash(0x10000, &data, None).unwrap();
+        assert_eq!(writes(&device), 1);
+
+        // Only the changed sector is written, unless it was erased since
+        data[FLASH_SECTOR_SIZE * 3] = 0;
+        device.set_flash(0x10000 + FLASH_SECTOR_SIZE * 6, &[0]);
+        flasher
+            .erase_region(0x10000 + FLASH_SECTOR_SIZE as u32 * 6, 0x1000)
+            .unwrap();
+        flasher.write_bin_to_flash(0x10000, &data, None).unwrap();
+        assert_eq!(writes(&device), 3);
+        assert_eq!(device.flash(0x10000, data.len()), data);
+    }
+
+    #[test]
+    fn skips_segments_already_in_flash() {
+        let device = MockDevice::new();
+        let mut flasher = connect_mock(&device, None).unwrap();
+
+        let data = vec![0x5a; FLASH_SECTOR_SIZE * 2];
+        device.set_flash(0x10000, &data);
+        let begin = CommandType::FlashDeflateBegin as u8;
+        let writes =
+            |device: &MockDevice| device.commands().iter().filter(|&&op| op == begin).count();
+        flasher.write_bin_to_flash(0x10000, &data, None).unwrap();
+        assert_eq!(writes(&device), 0);
+
+        flasher.set_skip_identical(false);
+        flasher.write_bin_to_flash(0x10000, &data, None).unwrap();
+        assert_eq!(writes(&device), 1);
+    }
+
+    #[test]
+    fn keeps_the_mac_address_after_resetting() {
+        let device = MockDevice::new();
+        let settings = ConnectSettings {
+            before: ResetBeforeOperation::NoReset,
+            after: ResetAfterOperation::HardReset,
+            ..ConnectSettings::default()
+        };
+        let mut flasher = Flasher::connect_transport(
+            Box::new(device.clone()),
+            SerialPortType::Unknown,
+            None,
+            None,
+            None,
+            settings,
+        )
+        .unwrap();
+        let mac = flasher.mac_address().unwrap();
+
+        flasher
+            .write_bin_to_flash(0x10000, &[0x5a; 0x1000], None)
+            .unwrap();
+        assert!(device.is_running_app());
+        assert_eq!(flasher.mac_address().unwrap(), mac);
+    }
+
+    #[test]
+    fn reports_the_device_after_resetting() {
+        let device = MockDevice::new();
+        let settings = ConnectSettings {
+            before: ResetBeforeOperation::NoReset,
+            after: ResetAfterOperation::HardReset,
+            ..ConnectSettings::default()
+        };
+        let mut flasher = Flasher::connect_transport(
+            Box::new(device.clone()),
+            SerialPortType::Unknown,
+            None,
+            None,
+            None,
+            settings,
+        )
+        .unwrap();
+        flasher.board_info().unwrap();
+
+        flasher
+            .write_bin_to_flash(0x10000, &[0x5a; 0x1000], None)
+            .unwrap();
+        assert!(device.is_running_app());
+        let info = flasher.device_info().unwrap();
+        assert_eq!(info.chip, "ESP32-C3");
+    }
+
+    #[test]
+    fn caches_segments_already_in_flash_with_the_stub() {
+        let device = MockDevice::new();
+        let mut flasher = connect_mock(&device, None).unwrap();
+        flasher.load_stub(&test_stub()).unwrap();
+        flasher.set_sector_cache(Some(SectorCache::default()));
+
+        let data = vec![0x5a; FLASH_SECTOR_SIZE * 2];
+        device.set_flash(0x10000, &data);
+        let count = |device: &MockDevice, command: CommandType| {
+            let op = command as u8;
+            device.commands().iter().filter(|&&c| c == op).count()
+        };
+        flasher.write_bin_to_flash(0x10000, &data, None).unwrap();
+        assert_eq!(count(&device, CommandType::FlashDeflateBegin), 0);
+        assert_eq!(count(&device, CommandType::FlashMd5), 1);
+
+        // The cache now knows the flash contents, so the device isn't asked again
+        flasher.write_bin_to_flash(0x10000, &data, None).unwrap();
+        assert_eq!(count(&device, CommandType::FlashDeflateBegin), 0);
+        assert_eq!(count(&device, CommandType::FlashMd5), 1);
+    }
+
+    #[test]
+    fn plans_images() {
+        let elf_data = include_bytes!("../tests/data/esp32");
+        let settings = ImageSettings {
+            erase_parts: vec!["nvs".into()],
+            ..ImageSettings::default()
+        };
+        let plan = settings
+            .plan(
+                Chip::Esp32,
+                FlashSize::Flash4Mb,
+                None,
+                elf_data,
+                None,
+                None,
+                None,
+            )
+            .unwrap();
+        assert_eq!(plan.erase, [("nvs".to_string(), 0x9000, 0x6000)]);
+        let offsets: Vec<_> = plan.segments.iter().map(|(addr, _)| *addr).collect();
+        assert_eq!(offsets, [0x1000, 0x8000, 0x10000]);
+
+        // The factory partition of the default partition table needs 4MB
+        let settings = ImageSettings {
+            erase_parts: vec!["factory".into()],
+            ..ImageSettings::default()
+        };
+        assert!(matches!(
+            settings.plan(
+                Chip::Esp32,
+                FlashSize::Flash1Mb,
+                None,
+                elf_data,
+                None,
+                None,
+                None
+            ),
+            Err(Error::FlashRegionOutOfRange(..))
+        ));
+
+        let settings = ImageSettings {
+            flash_settings: FlashSettings {
+                min_chip_rev: Some(3),
+                ..FlashSettings::default()
+            },
+            ..ImageSettings::default()
+        };
+        let plan = |revision| {
+            settings.plan(
+                Chip::Esp32,
+                FlashSize::Flash4Mb,
+                Some(revision),
+                elf_data,
+                None,
+                None,
+                None,
+            )
+        };
+        assert!(matches!(plan(1), Err(Error::ChipRevisionTooOld(3, 1))));
+        // Written to the extended header of the application image
+        let app = &plan(3).unwrap().segments[2].1;
+        assert_eq!(app[14], 3);
+
+        // The bundled bootloader only finds the partition table at its default offset
+        let settings = ImageSettings {
+            flash_layout: FlashLayout {
+                partition_table_offset: Some(0x10000),
+                ..FlashLayout::default()
+            },
+            ..ImageSettings::default()
+        };
+        let plan = |bootloader| {
+            settings.plan(
+                Chip::Esp32,
+                FlashSize::Flash4Mb,
+                None,
+                elf_data,
+                bootloader,
+                None,
+                None,
+            )
+        };
+        assert!(matches!(plan(None), Err(Error::InvalidFlashLayout(_))));
+        let bootloader = include_bytes!("../bootloader/v4.4/esp32-bootloader.bin").to_vec();
+        assert!(plan(Some(bootloader)).is_ok());
+    }
+}
@@ -0,0 +1,521 @@
+//! LittleFS images, as mounted by the `esp_littlefs` component for ESP-IDF
+//!
+//! Directories are kept in metadata pairs of two blocks, each holding a log
+//! of commits made of tagged entries. The images contain a single commit in
+//! the first block of every pair, with a name and a struct tag for each entry
+//! of the directory, sorted by name, and the tail pointing to the next pair. All
+//! pairs are linked into one list starting at the root directory, whose first
+//! pair in blocks 0 and 1 also holds the superblock. Small files are stored
+//! inline in their entry, larger ones in CTZ skip-lists, where block `n` of a
+//! file starts with pointers to the blocks `n - 2^i` for each power of two
+//! dividing `n`. This is version 2.0 of the on-disk format, which all releases
+//! of LittleFS 2 can mount.
+
+use std::collections::{BTreeMap, VecDeque};
+
+use super::FsFile;
+use crate::error::Error;
+
+const DISK_VERSION: u32 = 0x0002_0000;
+const MAGIC: &[u8] = b"littlefs";
+/// Largest file size of LittleFS, as written to the superblock
+const FILE_MAX: u32 = 0x7FFF_FFFF;
+/// Largest attribute size of LittleFS, as written to the superblock
+const ATTR_MAX: u32 = 1022;
+/// The block pointer of the superblock pair, which is also the root
+const ROOT_PAIR: [u32; 2] = [0, 1];
+/// Revision count of the written metadata blocks
+const REVISION: u32 = 1;
+
+const TYPE_REG: u16 = 0x001;
+const TYPE_DIR: u16 = 0x002;
+const TYPE_SUPERBLOCK: u16 = 0x0FF;
+const TYPE_DIRSTRUCT: u16 = 0x200;
+const TYPE_INLINESTRUCT: u16 = 0x201;
+const TYPE_CTZSTRUCT: u16 = 0x202;
+const TYPE_CRC: u16 = 0x500;
+const TYPE_SOFTTAIL: u16 = 0x600;
+const TYPE_HARDTAIL: u16 = 0x601;
+/// Id of the tags which don't belong to an entry
+const ID_NONE: u16 = 0x3FF;
+/// Largest length of a tag, `0x3ff` marks deleted tags
+const TAG_LEN_MAX: usize = 0x3FE;
+const TAG_LEN: usize = 4;
+const TAIL_LEN: usize = TAG_LEN + 8;
+const CRC_LEN: usize = TAG_LEN + 4;
+
+/// Parameters of a LittleFS filesystem
+///
+/// The block size has to match the configuration of the firmware mounting
+/// it, the maximum name length may not exceed the one of the firmware. The
+/// defaults are the ones of `esp_littlefs`.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct LittleFsConfig {
+    pub block_size: usize,
+    /// Size of the flash writes of the firmware, commits are padded to it so
+    /// the firmware can append to them
+    pub prog_size: usize,
+    /// Size of the caches of the firmware, files up to this size, and at most
+    /// an eighth of a block, are stored inline in their directory
+    pub cache_size: usize,
+    /// Maximum length of file names
+    pub name_max: usize,
+}
+
+impl Default for LittleFsConfig {
+    fn default() -> Self {
+        LittleFsConfig {
+            block_size: 4096,
+            prog_size: 128,
+            cache_size: 512,
+            name_max: 64,
+        }
+    }
+}
+
+/// A directory, as built from the paths of the files
+#[derive(Default)]
+struct Dir<'a> {
+    entries: BTreeMap<&'a str, Node<'a>>,
+}
+
+enum Node<'a> {
+    File(&'a [u8]),
+    Dir(Dir<'a>),
+}
+
+/// An entry of a metadata pair: the tag types and data of its name and struct
+struct Entry {
+    name_type: u16,
+    name: Vec<u8>,
+    struct_type: u16,
+    data: Vec<u8>,
+}
+
+impl Entry {
+    fn len(&self) -> usize {
+        2 * TAG_LEN + self.name.len() + self.data.len()
+    }
+}
+
+/// A metadata pair and the entries written to its first block
+struct Pair {
+    blocks: [u32; 2],
+    entries: Vec<Entry>,
+    /// Whether the next pair continues the same directory
+    split: bool,
+}
+
+impl LittleFsConfig {
+    /// Build an image of `size` bytes holding `files`
+    ///
+    /// The size is rounded down to whole blocks and the unused blocks are left
+    /// erased, as if the firmware had formatted the filesystem and written the
+    /// files.
+    pub fn build(&self, files: &[FsFile], size: u32) -> Result<Vec<u8>, Error> {
+        let block_count = size as usize / self.block_size;
+        self.validate(block_count)?;
+
+        let mut root = Dir::default();
+        for file in files {
+            root.insert(file, self.name_max)?;
+        }
+
+        let mut image = vec![0xFF; block_count * self.block_size];
+        let mut next_block = ROOT_PAIR[1] + 1;
+        let mut alloc = || {
+            if next_block as usize == block_count {
+                return Err(Error::FsFull(size));
+            }
+            next_block += 1;
+            Ok(next_block - 1)
+        };
+
+        // Every pair is linked into the list in the order the directories are
+        // visited, starting with the root directory
+        let mut pairs = Vec::new();
+        let mut dirs = VecDeque::from(vec![(&root, ROOT_PAIR)]);
+        while let Some((dir, blocks)) = dirs.pop_front() {
+            let mut entries = Vec::new();
+            if blocks == ROOT_PAIR {
+                entries.push(self.superblock(block_count));
+            }
+            for (name, node) in &dir.entries {
+                let (name_type, struct_type, data) = match node {
+                    Node::File(data) if data.len() <= self.inline_max() => {
+                        (TYPE_REG, TYPE_INLINESTRUCT, data.to_vec())
+                    }
+                    Node::File(data) => {
+                        let head = self.write_ctz(&mut image, data, &mut alloc)?;
+                        let mut ctz = head.to_le_bytes().to_vec();
+                        ctz.extend_from_slice(&(data.len() as u32).to_le_bytes());
+                        (TYPE_REG, TYPE_CTZSTRUCT, ctz)
+                    }
+                    Node::Dir(child) => {
+                        let child_blocks = [alloc()?, alloc()?];
+                        dirs.push_back((child, child_blocks));
+                        (TYPE_DIR, TYPE_DIRSTRUCT, pair_bytes(child_blocks))
+                    }
+                };
+                entries.push(Entry {
+                    name_type,
+                    name: name.as_bytes().to_vec(),
+                    struct_type,
+                    data,
+                });
+            }
+
+            // Pairs are only filled up to half, like the firmware compacts them,
+            // which leaves room for appending to them
+            let mut pair = Pair {
+                blocks,
+                entries: Vec::new(),
+                split: false,
+            };
+            let mut len = 0;
+            for entry in entries {
+                if !pair.entries.is_empty()
+                    && (len + entry.len() > self.pair_capacity()
+                        || pair.entries.len() == ID_NONE as usize)
+                {
+                    let next = Pair {
+                        blocks: [alloc()?, alloc()?],
+                        entries: Vec::new(),
+                        split: false,
+                    };
+                    pair.split = true;
+                    pairs.push(pair);
+                    pair = next;
+                    len = 0;
+                }
+                len += entry.len();
+                pair.entries.push(entry);
+            }
+            pairs.push(pair);
+        }
+
+        for (i, pair) in pairs.iter().enumerate() {
+            let tail = pairs.get(i + 1).map(|next| {
+                let ty = if pair.split {
+                    TYPE_HARDTAIL
+                } else {
+                    TYPE_SOFTTAIL
+                };
+                (ty, next.blocks)
+            });
+            let block = &mut image[pair.blocks[0] as usize * self.block_size..][..self.block_size];
+            self.write_pair(block, pair, tail);
+        }
+
+        Ok(image)
+    }
+
+    fn validate(&self, block_count: usize) -> Result<(), Error> {
+        if self.prog_size == 0
+            || self.prog_size > TAG_LEN_MAX - CRC_LEN
+            || self.block_size % self.prog_size != 0
+        {
+            return Err(Error::InvalidFsConfig(
+                "the block size must be a multiple of the program size, which may be at most 1014 bytes",
+            ));
+        }
+        if self.name_max == 0 || self.name_max > 255 {
+            return Err(Error::InvalidFsConfig(
+                "the maximum name length must be between 1 and 255 bytes",
+            ));
+        }
+        // The largest entry has to fit into a pair filled up to half
+        if 2 * TAG_LEN + self.name_max + self.inline_max() > self.pair_capacity() {
+            return Err(Error::InvalidFsConfig(
+                "the blocks are too small for the names and the inline files",
+            ));
+        }
+        if block_count < 2 {
+            return Err(Error::InvalidFsConfig(
+                "the filesystem needs at least two blocks",
+            ));
+        }
+
+        Ok(())
+    }
+
+    /// Largest file stored inline, the firmware moves larger ones into blocks
+    fn inline_max(&self) -> usize {
+        TAG_LEN_MAX.min(self.cache_size).min(self.block_size / 8)
+    }
+
+    /// Space for the entries of a pair, the revision count, a tail and the
+    /// commit CRC are stored along with them
+    fn pair_capacity(&self) -> usize {
+        (self.block_size / 2).saturating_sub(4 + TAIL_LEN + CRC_LEN + self.prog_size)
+    }
+
+    fn superblock(&self, block_count: usize) -> Entry {
+        let fields = [
+            DISK_VERSION,
+            self.block_size as u32,
+            block_count as u32,
+            self.name_max as u32,
+            FILE_MAX,
+            ATTR_MAX,
+        ];
+
+        Entry {
+            name_type: TYPE_SUPERBLOCK,
+            name: MAGIC.to_vec(),
+            struct_type: TYPE_INLINESTRUCT,
+            data: fields
+                .iter()
+                .flat_map(|field| field.to_le_bytes())
+                .collect(),
+        }
+    }
+
+    /// Write `data` to a CTZ skip-list, returning the block of its head
+    fn write_ctz(
+        &self,
+        image: &mut [u8],
+        data: &[u8],
+        alloc: &mut impl FnMut() -> Result<u32, Error>,
+    ) -> Result<u32, Error> {
+        let mut blocks: Vec<u32> = Vec::new();
+        let mut rest = data;
+        while !rest.is_empty() {
+            let index = blocks.len();
+            let skips = if index == 0 {
+                0
+            } else {
+                index.trailing_zeros() as usize + 1
+            };
+            let block = alloc()?;
+            let area = &mut image[block as usize * self.block_size..][..self.block_size];
+
+            for (i, pointer) in area.chunks_mut(4).take(skips).enumerate() {
+                pointer.copy_from_slice(&blocks[index - (1 << i)].to_le_bytes());
+            }
+            let (chunk, remaining) = rest.split_at(rest.len().min(self.block_size - 4 * skips));
+            area[4 * skips..][..chunk.len()].copy_from_slice(chunk);
+
+            blocks.push(block);
+            rest = remaining;
+        }
+
+        Ok(*blocks.last().unwrap())
+    }
+
+    /// Write the commit holding the entries of `pair` to `block`
+    fn write_pair(&self, block: &mut [u8], pair: &Pair, tail: Option<(u16, [u32; 2])>) {
+        let mut commit = Commit {
+            data: REVISION.to_le_bytes().to_vec(),
+            ptag: 0xFFFF_FFFF,
+        };
+        for (id, entry) in pair.entries.iter().enumerate() {
+            commit.push(entry.name_type, id as u16, &entry.name);
+            commit.push(entry.struct_type, id as u16, &entry.data);
+        }
+        if let Some((ty, blocks)) = tail {
+            commit.push(ty, ID_NONE, &pair_bytes(blocks));
+        }
+
+        // The CRC tag pads the commit to the program size, the padding itself
+        // isn't written
+        let off = commit.data.len();
+        let end = align_up(off + CRC_LEN, self.prog_size);
+        commit.push_tag(TYPE_CRC, ID_NONE, end - off - TAG_LEN);
+        let crc = !crc32fast::hash(&commit.data);
+        commit.data.extend_from_slice(&crc.to_le_bytes());
+
+        block[..commit.data.len()].copy_from_slice(&commit.data);
+    }
+}
+
+impl<'a> Dir<'a> {
+    fn insert(&mut self, file: &'a FsFile, name_max: usize) -> Result<(), Error> {
+        let mut components = file.path.split('/').filter(|name| !name.is_empty());
+        let conflict = || Error::FsPathConflict(file.path.clone());
+
+        let mut name = components.next().ok_or_else(conflict)?;
+        let mut dir = self;
+        for next in components {
+            if name.len() > name_max {
+                return Err(Error::FsNameTooLong(name.into(), name_max));
+            }
+            let node = dir
+                .entries
+                .entry(name)
+                .or_insert_with(|| Node::Dir(Dir::default()));
+            dir = match node {
+                Node::Dir(dir) => dir,
+                Node::File(_) => return Err(conflict()),
+            };
+            name = next;
+        }
+
+        if name.len() > name_max {
+            return Err(Error::FsNameTooLong(name.into(), name_max));
+        }
+        if dir.entries.insert(name, Node::File(&file.data)).is_some() {
+            return Err(conflict());
+        }
+
+        Ok(())
+    }
+}
+
+/// A commit to a metadata block, the tags are stored big-endian and XORed
+/// with the previous one
+struct Commit {
+    data: Vec<u8>,
+    ptag: u32,
+}
+
+impl Commit {
+    fn push(&mut self, ty: u16, id: u16, data: &[u8]) {
+        self.push_tag(ty, id, data.len());
+        self.data.extend_from_slice(data);
+    }
+
+    fn push_tag(&mut self, ty: u16, id: u16, len: usize) {
+        let tag = (ty as u32) << 20 | (id as u32) << 10 | len as u32;
+        self.data
+            .extend_from_slice(&(tag ^ self.ptag).to_be_bytes());
+        self.ptag = tag;
+    }
+}
+
+fn pair_bytes(blocks: [u32; 2]) -> Vec<u8> {
+    blocks
+        .iter()
+        .flat_map(|block| block.to_le_bytes())
+        .collect()
+}
+
+fn align_up(value: usize, alignment: usize) -> usize {
+    (value + alignment - 1) / alignment * alignment
+}
+
+#[cfg(test)]
+mod tests {
+    use std::convert::TryInto;
+
+    use super::*;
+
+    /// Read the tags of the commit in `block`, checking its CRC
+    fn tags(block: &[u8]) -> Vec<(u16, u16, Vec<u8>)> {
+        let mut tags = Vec::new();
+        let mut ptag = 0xFFFF_FFFF;
+        let mut off = 4;
+        loop {
+            let tag = u32::from_be_bytes(block[off..off + 4].try_into().unwrap()) ^ ptag;
+            let (ty, id, len) = (
+                (tag >> 20) as u16,
+                (tag >> 10 & 0x3FF) as u16,
+                (tag & 0x3FF) as usize,
+            );
+            if ty == TYPE_CRC {
+                let crc = u32::from_le_bytes(block[off + 4..off + 8].try_into().unwrap());
+                assert_eq!(crc, !crc32fast::hash(&block[..off + 4]));
+                assert_eq!((off + 4 + len) % 128, 0);
+                return tags;
+            }
+            tags.push((ty, id, block[off + 4..][..len].to_vec()));
+            ptag = tag;
+            off += 4 + len;
+        }
+    }
+
+    #[test]
+    fn files_are_stored_inline_and_in_skip_lists() {
+        let large: Vec<u8> = (0..10000).map(|i| i as u8).collect();
+        let files = [
+            FsFile::new("/hello.txt", b"hello".to_vec()),
+            FsFile::new("/web/index.html", large.clone()),
+        ];
+        let image = LittleFsConfig::default().build(&files, 0x10000).unwrap();
+        assert_eq!(image.len(), 0x10000);
+
+        let root = tags(&image[..0x1000]);
+        assert_eq!(root[0], (TYPE_SUPERBLOCK, 0, MAGIC.to_vec()));
+        assert_eq!(root[1].0, TYPE_INLINESTRUCT);
+        assert_eq!(&root[1].2[8..12], &16u32.to_le_bytes());
+        assert_eq!(root[2], (TYPE_REG, 1, b"hello.txt".to_vec()));
+        assert_eq!(root[3], (TYPE_INLINESTRUCT, 1, b"hello".to_vec()));
+        assert_eq!(root[4], (TYPE_DIR, 2, b"web".to_vec()));
+        assert_eq!(root[5], (TYPE_DIRSTRUCT, 2, pair_bytes([2, 3])));
+        assert_eq!(root[6], (TYPE_SOFTTAIL, ID_NONE, pair_bytes([2, 3])));
+        // The second block of the pair is left erased
+        assert!(image[0x1000..0x2000].iter().all(|byte| *byte == 0xFF));
+
+        // The file takes three blocks, the second and the third one point
+        // back to the previous ones
+        let web = tags(&image[0x2000..0x3000]);
+        let mut ctz = 6u32.to_le_bytes().to_vec();
+        ctz.extend_from_slice(&10000u32.to_le_bytes());
+        assert_eq!(
+            web,
+            [
+                (TYPE_REG, 0, b"index.html".to_vec()),
+                (TYPE_CTZSTRUCT, 0, ctz)
+            ]
+        );
+        assert_eq!(image[0x4000..0x5000], large[..0x1000]);
+        assert_eq!(&image[0x5000..0x5004], &4u32.to_le_bytes());
+        assert_eq!(image[0x5004..0x6000], large[0x1000..0x1ffc]);
+        assert_eq!(&image[0x6000..0x6008], &[5, 0, 0, 0, 4, 0, 0, 0]);
+        assert_eq!(image[0x6008..0x6008 + 10000 - 0x1ffc], large[0x1ffc..]);
+    }
+
+    #[test]
+    fn large_directories_are_split() {
+        let files: Vec<_> = (0..100)
+            .map(|i| FsFile::new(format!("/file-{:03}.txt", i), vec![i as u8; 20]))
+            .collect();
+        let image = LittleFsConfig::default().build(&files, 0x10000).unwrap();
+
+        // The entries continue in the pairs following the hard tails
+        let mut names = Vec::new();
+        let mut block = 0;
+        loop {
+            let tags = tags(&image[block * 0x1000..][..0x1000]);
+            names.extend(
+                tags.iter()
+                    .filter(|tag| tag.0 == TYPE_REG)
+                    .map(|tag| String::from_utf8(tag.2.clone()).unwrap()),
+            );
+            match tags.last().unwrap() {
+                (TYPE_HARDTAIL, ID_NONE, next) => {
+                    block = u32::from_le_bytes(next[..4].try_into().unwrap()) as usize
+                }
+                _ => break,
+            }
+        }
+        assert_eq!(block, 4);
+        assert!(names.iter().eq(files.iter().map(|file| &file.path[1..])));
+    }
+
+    #[test]
+    fn invalid_contents_are_rejected() {
+        let config = LittleFsConfig::default();
+
+        let long_name = [FsFile::new(format!("/{}", "a".repeat(65)), Vec::new())];
+        assert!(matches!(
+            config.build(&long_name, 0x10000),
+            Err(Error::FsNameTooLong(_, 64))
+        ));
+
+        let conflict = [
+            FsFile::new("/data", Vec::new()),
+            FsFile::new("/data/log.txt", Vec::new()),
+        ];
+        assert!(matches!(
+            config.build(&conflict, 0x10000),
+            Err(Error::FsPathConflict(path)) if path == "/data/log.txt"
+        ));
+
+        let large_file = [FsFile::new("/large.bin", vec![0; 0x4000])];
+        assert!(matches!(
+            config.build(&large_file, 0x4000),
+            Err(Error::FsFull(0x4000))
+        ));
+    }
+}
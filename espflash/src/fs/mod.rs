@@ -0,0 +1,28 @@
+//! Building filesystem images for data partitions
+//!
+//! The images are built from files held in memory, so they can be flashed
+//! without running `mkspiffs`, `spiffsgen.py` or `mklittlefs` first. SPIFFS
+//! and LittleFS are supported, see [`SpiffsConfig`] and [`LittleFsConfig`].
+
+pub use littlefs::LittleFsConfig;
+pub use spiffs::SpiffsConfig;
+
+mod littlefs;
+mod spiffs;
+
+/// A file to put into a filesystem image
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FsFile {
+    /// Path of the file within the filesystem, eg `/web/index.html`
+    pub path: String,
+    pub data: Vec<u8>,
+}
+
+impl FsFile {
+    pub fn new(path: impl Into<String>, data: Vec<u8>) -> Self {
+        FsFile {
+            path: path.into(),
+            data,
+        }
+    }
+}
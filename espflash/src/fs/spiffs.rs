@@ -0,0 +1,248 @@
+//! SPIFFS images, as mounted by the `spiffs` component of ESP-IDF
+//!
+//! Every block starts with a lookup page holding the object id of each of its
+//! other pages. Files are objects made of data pages and index pages, which
+//! list the data pages in order. The structures use the layout ESP-IDF
+//! compiles SPIFFS with: 16 bit object ids and page indices, packed structs and
+//! a magic number in every block.
+
+use std::iter::once;
+
+use super::FsFile;
+use crate::error::Error;
+
+const OBJ_ID_LEN: usize = 2;
+const PAGE_IX_LEN: usize = 2;
+// Object id, span index and flags
+const PAGE_HEADER_LEN: usize = 5;
+// Index pages pad the page header to four bytes
+const INDEX_HEADER_LEN: usize = 8;
+const MAX_PAGES: usize = 1 << 16;
+
+const OBJ_ID_FREE: u16 = 0xFFFF;
+const OBJ_ID_INDEX_FLAG: u16 = 0x8000;
+
+// Flags are set by clearing their bits
+const FLAGS_DATA: u8 = 0xFC; // used, final
+const FLAGS_INDEX: u8 = 0xF8; // used, final, index
+const TYPE_FILE: u8 = 1;
+
+const MAGIC: u32 = 0x20140529;
+
+/// Parameters of a SPIFFS filesystem
+///
+/// These have to match the configuration of the firmware mounting it, the
+/// defaults are the ones of ESP-IDF.
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub struct SpiffsConfig {
+    pub page_size: usize,
+    pub block_size: usize,
+    /// Maximum length of file names, including the terminating null byte
+    pub obj_name_len: usize,
+    /// Length of the metadata stored with every file
+    pub meta_len: usize,
+}
+
+impl Default for SpiffsConfig {
+    fn default() -> Self {
+        SpiffsConfig {
+            page_size: 256,
+            block_size: 4096,
+            obj_name_len: 32,
+            meta_len: 4,
+        }
+    }
+}
+
+impl SpiffsConfig {
+    /// Build an image of `size` bytes holding `files`
+    ///
+    /// The size is rounded down to whole blocks and the unused pages are left
+    /// erased, as if the firmware had formatted the filesystem.
+    pub fn build(&self, files: &[FsFile], size: u32) -> Result<Vec<u8>, Error> {
+        let pages_per_block = self.block_size / self.page_size;
+        let lookup_pages = self.lookup_pages();
+        let block_count = size as usize / self.block_size;
+        self.validate(block_count)?;
+
+        let mut image = vec![0xFF; block_count * self.block_size];
+        let mut lookup = vec![OBJ_ID_FREE; block_count * pages_per_block];
+        let mut pages = (0..block_count).flat_map(move |block| {
+            let first = block * pages_per_block;
+            first + lookup_pages..first + pages_per_block
+        });
+
+        for (i, file) in files.iter().enumerate() {
+            if file.path.len() >= self.obj_name_len {
+                return Err(Error::FsNameTooLong(
+                    file.path.clone(),
+                    self.obj_name_len - 1,
+                ));
+            }
+            // Object id 0 is reserved, and the highest bit marks index pages
+            let obj_id = i + 1;
+            if obj_id >= OBJ_ID_INDEX_FLAG as usize {
+                return Err(Error::FsFull(size));
+            }
+            let obj_id = obj_id as u16;
+
+            let mut data_pages = Vec::new();
+            for (span, chunk) in file
+                .data
+                .chunks(self.page_size - PAGE_HEADER_LEN)
+                .enumerate()
+            {
+                let pix = pages.next().ok_or(Error::FsFull(size))?;
+                lookup[pix] = obj_id;
+                data_pages.push(pix as u16);
+
+                let page = self.page(&mut image, pix);
+                write_page_header(page, obj_id, span as u16, FLAGS_DATA);
+                page[PAGE_HEADER_LEN..][..chunk.len()].copy_from_slice(chunk);
+            }
+
+            // The header page lists the first data pages, any further index
+            // pages the remaining ones
+            let header_entries = (self.page_size - self.index_header_len()) / PAGE_IX_LEN;
+            let index_entries = (self.page_size - INDEX_HEADER_LEN) / PAGE_IX_LEN;
+            let (head, tail) = data_pages.split_at(data_pages.len().min(header_entries));
+
+            for (span, entries) in once(head).chain(tail.chunks(index_entries)).enumerate() {
+                let pix = pages.next().ok_or(Error::FsFull(size))?;
+                lookup[pix] = obj_id | OBJ_ID_INDEX_FLAG;
+
+                let page = self.page(&mut image, pix);
+                write_page_header(page, obj_id | OBJ_ID_INDEX_FLAG, span as u16, FLAGS_INDEX);
+                let offset = if span == 0 {
+                    page[INDEX_HEADER_LEN..][..4]
+                        .copy_from_slice(&(file.data.len() as u32).to_le_bytes());
+                    page[INDEX_HEADER_LEN + 4] = TYPE_FILE;
+                    let name = &mut page[INDEX_HEADER_LEN + 5..][..self.obj_name_len];
+                    name.fill(0);
+                    name[..file.path.len()].copy_from_slice(file.path.as_bytes());
+                    // The metadata is left erased
+                    self.index_header_len()
+                } else {
+                    INDEX_HEADER_LEN
+                };
+                for (entry, data_pix) in page[offset..].chunks_mut(PAGE_IX_LEN).zip(entries) {
+                    entry.copy_from_slice(&data_pix.to_le_bytes());
+                }
+            }
+        }
+
+        for block in 0..block_count {
+            let start = block * self.block_size;
+            let area = &mut image[start..][..lookup_pages * self.page_size];
+            let ids =
+                &lookup[block * pages_per_block + lookup_pages..][..pages_per_block - lookup_pages];
+            for (entry, obj_id) in area.chunks_mut(OBJ_ID_LEN).zip(ids) {
+                entry.copy_from_slice(&obj_id.to_le_bytes());
+            }
+
+            // The last two entries of the lookup pages hold the magic and the
+            // erase count, which is left unset
+            let magic = MAGIC ^ self.page_size as u32 ^ (block_count - block) as u32;
+            let offset = area.len() - 2 * OBJ_ID_LEN;
+            area[offset..][..OBJ_ID_LEN].copy_from_slice(&(magic as u16).to_le_bytes());
+        }
+
+        Ok(image)
+    }
+
+    fn validate(&self, block_count: usize) -> Result<(), Error> {
+        if !self.page_size.is_power_of_two() || !self.block_size.is_power_of_two() {
+            return Err(Error::InvalidFsConfig(
+                "the page and block sizes must be powers of two",
+            ));
+        }
+        if self.block_size < 2 * self.page_size {
+            return Err(Error::InvalidFsConfig(
+                "blocks must hold at least two pages",
+            ));
+        }
+        if self.obj_name_len < 2 || self.index_header_len() + PAGE_IX_LEN > self.page_size {
+            return Err(Error::InvalidFsConfig(
+                "the file names and metadata don't fit into a page",
+            ));
+        }
+        let pages_per_block = self.block_size / self.page_size;
+        let lookup_entries = pages_per_block - self.lookup_pages() + 2;
+        if lookup_entries * OBJ_ID_LEN > self.lookup_pages() * self.page_size {
+            return Err(Error::InvalidFsConfig(
+                "the lookup pages can't hold an entry for every page",
+            ));
+        }
+        if block_count * pages_per_block > MAX_PAGES {
+            return Err(Error::InvalidFsConfig(
+                "the filesystem has too many pages, use larger ones",
+            ));
+        }
+
+        Ok(())
+    }
+
+    fn lookup_pages(&self) -> usize {
+        (self.block_size / self.page_size * OBJ_ID_LEN / self.page_size).max(1)
+    }
+
+    /// Length of the header page of an object, up to its first data page index
+    fn index_header_len(&self) -> usize {
+        // Followed by the size, the type, the name and the metadata
+        INDEX_HEADER_LEN + 4 + 1 + self.obj_name_len + self.meta_len
+    }
+
+    fn page<'a>(&self, image: &'a mut [u8], pix: usize) -> &'a mut [u8] {
+        &mut image[pix * self.page_size..][..self.page_size]
+    }
+}
+
+fn write_page_header(page: &mut [u8], obj_id: u16, span_ix: u16, flags: u8) {
+    page[0..2].copy_from_slice(&obj_id.to_le_bytes());
+    page[2..4].copy_from_slice(&span_ix.to_le_bytes());
+    page[4] = flags;
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn files_are_laid_out_in_pages() {
+        let files = [FsFile::new("/hello.txt", b"hello".to_vec())];
+        let image = SpiffsConfig::default().build(&files, 0x2000).unwrap();
+        assert_eq!(image.len(), 0x2000);
+
+        // Lookup entries of the data and the index page, followed by the
+        // magic of both blocks
+        assert_eq!(&image[0..4], &[0x01, 0x00, 0x01, 0x80]);
+        assert_eq!(&image[252..256], &[0x2b, 0x04, 0xff, 0xff]);
+        assert_eq!(&image[0x1000 + 252..0x1000 + 254], &[0x28, 0x04]);
+
+        assert_eq!(&image[256..266], b"\x01\x00\x00\x00\xfchello");
+        assert_eq!(image[266], 0xff);
+
+        let index = &image[512..768];
+        assert_eq!(&index[..5], &[0x01, 0x80, 0x00, 0x00, 0xf8]);
+        assert_eq!(&index[8..13], &[5, 0, 0, 0, TYPE_FILE]);
+        assert_eq!(&index[13..24], b"/hello.txt\0");
+        assert_eq!(&index[49..53], &[0x01, 0x00, 0xff, 0xff]);
+    }
+
+    #[test]
+    fn oversized_contents_are_rejected() {
+        let config = SpiffsConfig::default();
+
+        let long_name = [FsFile::new(format!("/{}", "a".repeat(31)), Vec::new())];
+        assert!(matches!(
+            config.build(&long_name, 0x2000),
+            Err(Error::FsNameTooLong(_, 31))
+        ));
+
+        let large_file = [FsFile::new("/large.bin", vec![0; 0x2000])];
+        assert!(matches!(
+            config.build(&large_file, 0x2000),
+            Err(Error::FsFull(0x2000))
+        ));
+    }
+}
@@ -1,18 +1,21 @@
-use std::{borrow::Cow, io::Write, iter::once};
+use std::{borrow::Cow, convert::TryInto, io::Write, iter::once};
 
 use bytemuck::{bytes_of, Pod, Zeroable};
+use log::debug;
 use sha2::{Digest, Sha256};
 
 use crate::{
+    bootloaders::Bootloader,
     chip::Esp32Params,
     elf::{
         merge_adjacent_segments, update_checksum, CodeSegment, FirmwareImage, RomSegment,
         ESP_CHECKSUM_MAGIC,
     },
     error::{Error, FlashDetectError},
-    flasher::FlashSize,
+    flasher::{FlashSize, FLASH_SECTOR_SIZE},
     image_format::{EspCommonHeader, ImageFormat, SegmentHeader, ESP_MAGIC, WP_PIN_DISABLED},
-    partition_table::Type,
+    image_info::AppDescriptor,
+    secure_boot::{self, SigningKey},
     Chip, PartitionTable,
 };
 
@@ -31,21 +34,45 @@ impl<'a> Esp32BootloaderFormat<'a> {
         params: Esp32Params,
         partition_table: Option<PartitionTable>,
         bootloader: Option<Vec<u8>>,
+        chip_revision: Option<u32>,
     ) -> Result<Self, Error> {
+        let default_partition_addr = params.partition_addr;
+        let params = params.with_layout(image.flash_layout);
         let partition_table = partition_table.unwrap_or_else(|| params.default_partition_table());
-        let bootloader = if let Some(bytes) = bootloader {
-            Cow::Owned(bytes)
-        } else {
-            Cow::Borrowed(params.default_bootloader)
+        let mut bootloader = match bootloader {
+            Some(bytes) => Cow::Owned(bytes),
+            None => {
+                let bundled = match &image.bootloader_release {
+                    Some(release) => Bootloader::find(chip, release)?,
+                    None => Bootloader::select(chip, chip_revision)
+                        .ok_or(Error::NoDefaultBootloader(chip))?,
+                };
+                // The offset of the partition table is built into the bootloader
+                if params.partition_addr != default_partition_addr {
+                    return Err(Error::InvalidFlashLayout(format!(
+                        "the bundled bootloader reads the partition table at {:#x}, give a bootloader built for the offset {:#x} with `--bootloader`",
+                        default_partition_addr, params.partition_addr
+                    )));
+                }
+                debug!(
+                    "Using the bundled bootloader of ESP-IDF {}",
+                    bundled.release()
+                );
+                Cow::Borrowed(bundled.data())
+            }
         };
 
+        let flash_mode = image.flash_mode as u8;
+        let flash_config = encode_flash_size(image.flash_size)? + image.flash_frequency as u8;
+        patch_bootloader_header(&mut bootloader, flash_mode, flash_config);
+
         let mut data = Vec::new();
 
         let header = EspCommonHeader {
             magic: ESP_MAGIC,
             segment_count: 0,
-            flash_mode: image.flash_mode as u8,
-            flash_config: encode_flash_size(image.flash_size)? + image.flash_frequency as u8,
+            flash_mode,
+            flash_config,
             entry: image.entry,
         };
         data.write_all(bytes_of(&header))?;
@@ -56,7 +83,7 @@ impl<'a> Esp32BootloaderFormat<'a> {
             d_cs_drv: 0,
             gd_wp_drv: 0,
             chip_id: params.chip_id,
-            min_rev: 0,
+            min_rev: image.min_chip_rev,
             padding: [0; 8],
             append_digest: 1,
         };
@@ -64,7 +91,17 @@ impl<'a> Esp32BootloaderFormat<'a> {
 
         let mut checksum = ESP_CHECKSUM_MAGIC;
 
-        let flash_segments: Vec<_> = merge_adjacent_segments(image.rom_segments(chip).collect());
+        let mut flash_segments: Vec<_> =
+            merge_adjacent_segments(image.rom_segments(chip).collect());
+        if !image.app_descriptor.is_empty() {
+            let segment = flash_segments
+                .iter_mut()
+                .find(|segment| AppDescriptor::parse(segment.data()).is_some())
+                .ok_or_else(|| {
+                    Error::InvalidAppDescriptor("the image has no application description".into())
+                })?;
+            image.app_descriptor.apply(segment.data_mut())?;
+        }
         let mut ram_segments: Vec<_> = merge_adjacent_segments(image.ram_segments(chip).collect());
 
         let mut segment_count = 0;
@@ -126,13 +163,33 @@ impl<'a> Esp32BootloaderFormat<'a> {
         // provides a partition table via command-line then the validation step confirms
         // that at least one "app" partition is present. We prefer the "factory" partition,
         // and use any available "app" partitions if not present.
-        let factory_partition = partition_table
-            .find("factory")
-            .or_else(|| partition_table.find_by_type(Type::App))
-            .unwrap();
+        // The partition can also be selected in the image metadata.
+        let name = image.metadata.app_partition.as_deref();
+        let app_partition = partition_table
+            .find_app(name)
+            .ok_or_else(|| Error::MissingAppPartition(name.unwrap_or("factory").into()))?;
+
+        if let Some(offset) = image.flash_layout.app_offset {
+            if offset != app_partition.offset() {
+                return Err(Error::InvalidFlashLayout(format!(
+                    "the app offset {:#x} doesn't match the `{}` partition at {:#x}",
+                    offset,
+                    app_partition.name(),
+                    app_partition.offset()
+                )));
+            }
+        }
+        check_layout(&params, &bootloader, &partition_table)?;
+        if data.len() > app_partition.size() as usize {
+            return Err(Error::AppTooLarge(
+                app_partition.name().into(),
+                data.len(),
+                app_partition.size(),
+            ));
+        }
 
         let flash_segment = RomSegment {
-            addr: factory_partition.offset(),
+            addr: app_partition.offset(),
             data: Cow::Owned(data),
         };
 
@@ -169,6 +226,70 @@ impl<'a> ImageFormat<'a> for Esp32BootloaderFormat<'a> {
     {
         Box::new(once(self.flash_segment.borrow()))
     }
+
+    fn sign(&mut self, key: &SigningKey) -> Result<(), Error> {
+        // Bootloaders built with Secure Boot enabled are signed by ESP-IDF already
+        if secure_boot::is_signed(&self.bootloader) {
+            secure_boot::verify(&self.bootloader)?;
+        } else {
+            self.bootloader = Cow::Owned(key.sign(&self.bootloader)?);
+        }
+        self.flash_segment.data = Cow::Owned(key.sign(&self.flash_segment.data)?);
+
+        Ok(())
+    }
+}
+
+/// Check that the bootloader, the partition table and the partitions don't
+/// overlap each other
+fn check_layout(
+    params: &Esp32Params,
+    bootloader: &[u8],
+    partition_table: &PartitionTable,
+) -> Result<(), Error> {
+    if params.boot_addr % FLASH_SECTOR_SIZE as u32 != 0 {
+        return Err(Error::InvalidFlashLayout(format!(
+            "the bootloader offset {:#x} is not aligned to flash sectors",
+            params.boot_addr
+        )));
+    }
+    if params.partition_addr % FLASH_SECTOR_SIZE as u32 != 0 {
+        return Err(Error::InvalidFlashLayout(format!(
+            "the partition table offset {:#x} is not aligned to flash sectors",
+            params.partition_addr
+        )));
+    }
+    if params.app_addr % IROM_ALIGN != 0 {
+        return Err(Error::InvalidFlashLayout(format!(
+            "the app offset {:#x} is not aligned to 64 KiB",
+            params.app_addr
+        )));
+    }
+
+    let bootloader_end = params.boot_addr as u64 + bootloader.len() as u64;
+    if bootloader_end > params.partition_addr as u64 {
+        return Err(Error::InvalidFlashLayout(format!(
+            "the bootloader at {:#x} ends at {:#x}, after the partition table at {:#x}",
+            params.boot_addr, bootloader_end, params.partition_addr
+        )));
+    }
+
+    // The partition table takes up a whole sector
+    let table_end = params
+        .partition_addr
+        .saturating_add(FLASH_SECTOR_SIZE as u32);
+    for partition in partition_table.partitions() {
+        if partition.offset() < table_end {
+            return Err(Error::InvalidFlashLayout(format!(
+                "the `{}` partition at {:#x} overlaps the bootloader or the partition table, which end at {:#x}",
+                partition.name(),
+                partition.offset(),
+                table_end
+            )));
+        }
+    }
+
+    Ok(())
 }
 
 fn encode_flash_size(size: FlashSize) -> Result<u8, FlashDetectError> {
@@ -182,6 +303,53 @@ fn encode_flash_size(size: FlashSize) -> Result<u8, FlashDetectError> {
     }
 }
 
+/// Write the flash settings to the header of the bootloader, which configures
+/// the flash with them before loading the application
+///
+/// Like esptool.py this also updates the SHA256 digest appended to the
+/// bootloader, as it covers the header. Data which isn't an image is left
+/// untouched, as are signed bootloaders, changing them would invalidate their
+/// signature.
+fn patch_bootloader_header(bootloader: &mut Cow<[u8]>, flash_mode: u8, flash_config: u8) {
+    if bootloader.len() < IMAGE_HEADER_LEN || bootloader[0] != ESP_MAGIC {
+        return;
+    }
+    if secure_boot::is_signed(bootloader) {
+        return;
+    }
+    if bootloader[2] == flash_mode && bootloader[3] == flash_config {
+        return;
+    }
+
+    let data = bootloader.to_mut();
+    data[2] = flash_mode;
+    data[3] = flash_config;
+
+    // The last byte of the extended header is the `append_digest` flag
+    if data[IMAGE_HEADER_LEN - 1] == 1 {
+        if let Some(end) = image_len(data).filter(|end| end + 32 <= data.len()) {
+            let digest = Sha256::digest(&data[..end]);
+            data[end..end + 32].copy_from_slice(&digest);
+        }
+    }
+}
+
+/// Length of the image in `data` up to and including the checksum, excluding
+/// the appended digest
+fn image_len(data: &[u8]) -> Option<usize> {
+    let mut offset = IMAGE_HEADER_LEN;
+    for _ in 0..data[1] {
+        let length = data.get(offset + 4..offset + 8)?.try_into().ok()?;
+        offset =
+            offset.checked_add(SEG_HEADER_LEN as usize + u32::from_le_bytes(length) as usize)?;
+    }
+
+    // The checksum is the last byte of the padding to 16 bytes
+    Some(offset + 16 - offset % 16)
+}
+
+/// Length of the common and the extended header
+const IMAGE_HEADER_LEN: usize = 24;
 const IROM_ALIGN: u32 = 65536;
 const SEG_HEADER_LEN: u32 = 8;
 
@@ -250,3 +418,89 @@ struct ExtendedHeader {
     padding: [u8; 8],
     append_digest: u8,
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::elf::FlashLayout;
+
+    #[test]
+    fn patches_bootloader_header() {
+        let original: &[u8] = include_bytes!("../../bootloader/v4.4/esp32-bootloader.bin");
+        let end = image_len(original).unwrap();
+        assert_eq!(
+            Sha256::digest(&original[..end])[..],
+            original[end..end + 32]
+        );
+
+        let mut bootloader = Cow::Borrowed(original);
+        patch_bootloader_header(&mut bootloader, original[2], original[3]);
+        assert!(matches!(bootloader, Cow::Borrowed(_)));
+
+        patch_bootloader_header(&mut bootloader, 0, 0x4f);
+        assert_eq!(&bootloader[..4], &[ESP_MAGIC, original[1], 0, 0x4f]);
+        assert_eq!(bootloader[4..end], original[4..end]);
+        assert_eq!(
+            Sha256::digest(&bootloader[..end])[..],
+            bootloader[end..end + 32]
+        );
+    }
+
+    #[test]
+    fn checks_the_flash_layout() {
+        let layout = FlashLayout {
+            bootloader_offset: Some(0x3000),
+            partition_table_offset: None,
+            app_offset: Some(0x20000),
+        };
+        let table = Chip::Esp32.default_partition_table(layout).unwrap();
+        let factory = table.find("factory").unwrap();
+        assert_eq!((factory.offset(), factory.size()), (0x20000, 0x3e0000));
+
+        let params = Esp32Params {
+            boot_addr: 0x1000,
+            partition_addr: 0x8000,
+            nvs_addr: 0x9000,
+            nvs_size: 0x6000,
+            phy_init_data_addr: 0xf000,
+            phy_init_data_size: 0x1000,
+            app_addr: 0x10000,
+            app_size: 0x3f0000,
+            chip_id: 0,
+        };
+        let bootloader = [0; 0x6000];
+        assert!(check_layout(&params, &bootloader, &table).is_ok());
+
+        let moved = params.with_layout(layout);
+        assert!(matches!(
+            check_layout(&moved, &bootloader, &table),
+            Err(Error::InvalidFlashLayout(_))
+        ));
+
+        let unaligned = params.with_layout(FlashLayout {
+            app_offset: Some(0x18000),
+            ..FlashLayout::default()
+        });
+        assert!(check_layout(&unaligned, &bootloader, &table).is_err());
+
+        let overlapping = PartitionTable::basic(0x8000, 0x6000, 0xf000, 0x1000, 0x10000, 0x1000);
+        assert!(check_layout(&params, &bootloader, &overlapping).is_err());
+
+        let moved = params.with_layout(FlashLayout {
+            partition_table_offset: Some(0x10000),
+            ..FlashLayout::default()
+        });
+        let table = moved.default_partition_table();
+        let offsets: Vec<_> = table
+            .partitions()
+            .iter()
+            .map(|partition| (partition.offset(), partition.size()))
+            .collect();
+        assert_eq!(
+            offsets,
+            [(0x11000, 0x6000), (0x17000, 0x1000), (0x20000, 0x3e0000)]
+        );
+        assert!(check_layout(&moved, &bootloader, &table).is_ok());
+        assert!(check_layout(&params, &bootloader, &table).is_ok());
+    }
+}
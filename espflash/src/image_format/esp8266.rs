@@ -11,6 +11,18 @@ use crate::{
 };
 
 const IROM_MAP_START: u32 = 0x40200000;
+/// The cache maps this much of the flash, starting at a multiple of it
+const CACHE_MAP_SIZE: u32 = 0x100000;
+const SECTOR_SIZE: u32 = 0x1000;
+
+const ESP_OTA_MAGIC: u8 = 0xEA;
+/// Segment count written by esptool.py into the headers of OTA images, the
+/// bootloader doesn't use it
+const OTA_SEGMENT_COUNT: u8 = 4;
+/// Offset of the irom in an OTA image, after its header and the segment header
+const OTA_IROM_OFFSET: u32 = 0x10;
+/// Address of `user1.bin`
+const OTA_USER1: u32 = 0x1000;
 
 /// Image format for flashing to esp8266 chips
 pub struct Esp8266Format<'a> {
@@ -22,48 +34,50 @@ impl<'a> Esp8266Format<'a> {
     pub fn new(image: &'a FirmwareImage) -> Result<Self, Error> {
         // irom goes into a separate plain bin
         let irom_data = merge_rom_segments(image.rom_segments(Chip::Esp8266));
+        let flash_config = encode_flash_size(image.flash_size)? + image.flash_frequency as u8;
+        let common_data = ram_image(image, ESP_MAGIC, flash_config)?;
 
-        let mut common_data = Vec::with_capacity(
-            image
-                .ram_segments(Chip::Esp8266)
-                .map(|segment| segment.size() as usize)
-                .sum(),
-        );
-        // common header
-        let header = EspCommonHeader {
-            magic: ESP_MAGIC,
-            segment_count: image.ram_segments(Chip::Esp8266).count() as u8,
-            flash_mode: image.flash_mode as u8,
-            flash_config: encode_flash_size(image.flash_size)? + image.flash_frequency as u8,
-            entry: image.entry,
-        };
-        common_data.write_all(bytes_of(&header))?;
-
-        let mut total_len = 8;
-
-        let mut checksum = ESP_CHECKSUM_MAGIC;
-
-        for segment in image.ram_segments(Chip::Esp8266) {
-            let data = segment.data();
-            let padding = 4 - data.len() % 4;
-            let segment_header = SegmentHeader {
-                addr: segment.addr,
-                length: (data.len() + padding) as u32,
+        // The applications of the SDK's over-the-air updates are linked for the
+        // slot they are flashed to, with the irom right after the headers of
+        // the image
+        let ota_irom = irom_data
+            .as_ref()
+            .filter(|irom| irom.addr % SECTOR_SIZE == OTA_IROM_OFFSET);
+        if let Some(irom) = ota_irom {
+            let (name, addr, size) = ota_slot(irom.addr, image.flash_size)?;
+            let header = EspCommonHeader {
+                magic: ESP_OTA_MAGIC,
+                segment_count: OTA_SEGMENT_COUNT,
+                flash_mode: image.flash_mode as u8,
+                flash_config,
+                entry: image.entry,
             };
-            total_len += size_of::<SegmentHeader>() as u32 + segment_header.length;
-            common_data.write_all(bytes_of(&segment_header))?;
-            common_data.write_all(data)?;
+            let data = ota_image(&header, &irom.data, &common_data);
 
-            let padding = &[0u8; 4][0..padding];
-            common_data.write_all(padding)?;
-            checksum = update_checksum(data, checksum);
-        }
+            if data.len() > size as usize {
+                return Err(Error::AppTooLarge(name.into(), data.len(), size));
+            }
 
-        let padding = 15 - (total_len % 16);
-        let padding = &[0u8; 16][0..padding as usize];
-        common_data.write_all(padding)?;
+            return Ok(Self {
+                irom_data: None,
+                flash_segment: RomSegment {
+                    addr,
+                    data: Cow::Owned(data),
+                },
+            });
+        }
 
-        common_data.write_all(&[checksum])?;
+        // The flash is mapped from its start, so the irom has to be placed
+        // after the RAM segments and within the flash of the module
+        if let Some(irom) = &irom_data {
+            if common_data.len() > irom.addr as usize {
+                return Err(Error::IromOverlap(common_data.len() as u32, irom.addr));
+            }
+            let end = irom.addr + irom.data.len() as u32;
+            if end > image.flash_size.size() {
+                return Err(Error::ImageTooLarge(end, image.flash_size));
+            }
+        }
 
         let flash_segment = RomSegment {
             addr: 0,
@@ -77,6 +91,108 @@ impl<'a> Esp8266Format<'a> {
     }
 }
 
+/// The header and the RAM segments of the image, which the bootloader loads
+/// into memory
+fn ram_image(image: &FirmwareImage, magic: u8, flash_config: u8) -> Result<Vec<u8>, Error> {
+    let mut common_data = Vec::with_capacity(
+        image
+            .ram_segments(Chip::Esp8266)
+            .map(|segment| segment.size() as usize)
+            .sum(),
+    );
+    // common header
+    let header = EspCommonHeader {
+        magic,
+        segment_count: image.ram_segments(Chip::Esp8266).count() as u8,
+        flash_mode: image.flash_mode as u8,
+        flash_config,
+        entry: image.entry,
+    };
+    common_data.write_all(bytes_of(&header))?;
+
+    let mut total_len = 8;
+
+    let mut checksum = ESP_CHECKSUM_MAGIC;
+
+    for segment in image.ram_segments(Chip::Esp8266) {
+        let data = segment.data();
+        let padding = 4 - data.len() % 4;
+        let segment_header = SegmentHeader {
+            addr: segment.addr,
+            length: (data.len() + padding) as u32,
+        };
+        total_len += size_of::<SegmentHeader>() as u32 + segment_header.length;
+        common_data.write_all(bytes_of(&segment_header))?;
+        common_data.write_all(data)?;
+
+        let padding = &[0u8; 4][0..padding];
+        common_data.write_all(padding)?;
+        checksum = update_checksum(data, checksum);
+    }
+
+    let padding = 15 - (total_len % 16);
+    let padding = &[0u8; 16][0..padding as usize];
+    common_data.write_all(padding)?;
+
+    common_data.write_all(&[checksum])?;
+
+    Ok(common_data)
+}
+
+/// The name, address and size of the slot of the over-the-air updates the irom
+/// at `irom_addr` is linked for
+///
+/// Modules with 512KB and 1MB of flash use the 256KB+256KB and 512KB+512KB
+/// maps of the SDK, larger ones the 1024KB+1024KB map. The cache maps 1MB of
+/// flash at a time, so `user2.bin` is linked for a different address than
+/// `user1.bin` on the smaller modules only. Images linked for both slots are
+/// flashed to the first one.
+fn ota_slot(irom_addr: u32, flash_size: FlashSize) -> Result<(&'static str, u32, u32), Error> {
+    let invalid = Error::InvalidIromAddress(IROM_MAP_START + irom_addr, flash_size);
+    let user2 = match flash_size {
+        FlashSize::Flash512Kb => 0x41000,
+        FlashSize::Flash1Mb => 0x81000,
+        FlashSize::Flash256Kb => return Err(invalid),
+        _ => 0x101000,
+    };
+    // Both halves of the map start with a sector, the bootloader's in front of
+    // `user1.bin`, and end with 16KB for the settings of the application
+    let size = user2 - OTA_USER1 - 0x5000;
+
+    [("user1", OTA_USER1), ("user2", user2)]
+        .iter()
+        .find(|(_, addr)| addr % CACHE_MAP_SIZE + OTA_IROM_OFFSET == irom_addr)
+        .map(|&(name, addr)| (name, addr, size))
+        .ok_or(invalid)
+}
+
+/// An image for the over-the-air updates, the irom segment followed by the
+/// plain image of the RAM segments and a CRC checked by the bootloader of the
+/// SDK
+fn ota_image(header: &EspCommonHeader, irom: &[u8], ram_image: &[u8]) -> Vec<u8> {
+    let mut irom = irom.to_vec();
+    irom.resize((irom.len() + 15) / 16 * 16, 0);
+
+    let mut data = Vec::with_capacity(OTA_IROM_OFFSET as usize + irom.len() + ram_image.len() + 4);
+    data.extend_from_slice(bytes_of(header));
+    data.extend_from_slice(bytes_of(&SegmentHeader {
+        addr: 0,
+        length: irom.len() as u32,
+    }));
+    data.extend_from_slice(&irom);
+    data.extend_from_slice(ram_image);
+
+    // The variant of the CRC used by the SDK, never 0 nor 0xFFFFFFFF
+    let crc = crc32fast::hash(&data);
+    let crc = if crc & 0x8000_0000 != 0 {
+        !crc
+    } else {
+        crc + 1
+    };
+    data.extend_from_slice(&crc.to_le_bytes());
+    data
+}
+
 impl<'a> ImageFormat<'a> for Esp8266Format<'a> {
     fn flash_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
     where
@@ -141,3 +257,60 @@ fn encode_flash_size(size: FlashSize) -> Result<u8, FlashDetectError> {
         _ => Err(FlashDetectError::from(size as u8)),
     }
 }
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn ota_slots_match_the_flash_size() {
+        let slot = |irom: u32, size| ota_slot(irom - IROM_MAP_START, size).ok();
+
+        assert_eq!(
+            slot(0x40201010, FlashSize::Flash512Kb),
+            Some(("user1", 0x1000, 0x3b000))
+        );
+        assert_eq!(
+            slot(0x40241010, FlashSize::Flash512Kb),
+            Some(("user2", 0x41000, 0x3b000))
+        );
+        assert_eq!(
+            slot(0x40281010, FlashSize::Flash1Mb),
+            Some(("user2", 0x81000, 0x7b000))
+        );
+        assert_eq!(
+            slot(0x40201010, FlashSize::Flash4Mb),
+            Some(("user1", 0x1000, 0xfb000))
+        );
+
+        // `user2.bin` of a 1MB module on a larger one
+        assert_eq!(slot(0x40281010, FlashSize::Flash4Mb), None);
+        assert_eq!(slot(0x40201010, FlashSize::Flash256Kb), None);
+    }
+
+    #[test]
+    fn ota_images_are_laid_out_like_esptool() {
+        let header = EspCommonHeader {
+            magic: ESP_OTA_MAGIC,
+            segment_count: OTA_SEGMENT_COUNT,
+            flash_mode: 0,
+            flash_config: 0x20,
+            entry: 0x40100004,
+        };
+        let ram_image = [0xE9; 32];
+        let data = ota_image(&header, &[1, 2, 3], &ram_image);
+
+        assert_eq!(data[..8], [0xEA, 4, 0, 0x20, 0x04, 0x00, 0x10, 0x40]);
+        // The irom is padded to 16 bytes and loaded from the flash at offset 0
+        assert_eq!(data[8..16], [0, 0, 0, 0, 16, 0, 0, 0]);
+        assert_eq!(data[16..19], [1, 2, 3]);
+        assert!(data[19..32].iter().all(|&byte| byte == 0));
+        assert_eq!(data[32..64], ram_image);
+
+        let crc = u32::from_le_bytes([data[64], data[65], data[66], data[67]]);
+        let expected = crc32fast::hash(&data[..64]);
+        assert_eq!(data.len(), 68);
+        assert!(crc == !expected || crc == expected + 1);
+        assert_eq!(crc & 0x8000_0000, 0);
+    }
+}
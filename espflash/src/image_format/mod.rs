@@ -8,8 +8,8 @@ pub use esp32bootloader::*;
 pub use esp32directboot::*;
 pub use esp8266::*;
 
-use crate::error::Error;
-use serde::Deserialize;
+use crate::{error::Error, secure_boot::SigningKey};
+use serde::{Deserialize, Serialize};
 use std::str::FromStr;
 use strum_macros::{Display, EnumVariantNames, IntoStaticStr};
 
@@ -45,10 +45,25 @@ pub trait ImageFormat<'a> {
     fn ota_segments<'b>(&'b self) -> Box<dyn Iterator<Item = RomSegment<'b>> + 'b>
     where
         'a: 'b;
+
+    /// Append Secure Boot v2 signatures made with `key` to the bootloader and
+    /// the application
+    fn sign(&mut self, _key: &SigningKey) -> Result<(), Error> {
+        Err(Error::SigningNotSupported)
+    }
 }
 
 #[derive(
-    Debug, Copy, Clone, Eq, PartialEq, Display, IntoStaticStr, EnumVariantNames, Deserialize,
+    Debug,
+    Copy,
+    Clone,
+    Eq,
+    PartialEq,
+    Display,
+    IntoStaticStr,
+    EnumVariantNames,
+    Deserialize,
+    Serialize,
 )]
 #[strum(serialize_all = "kebab-case")]
 #[serde(rename_all = "kebab-case")]
@@ -0,0 +1,385 @@
+//! Inspecting application images without flashing them
+//!
+//! Binary images are parsed and their checksum and appended SHA-256 digest
+//! are validated, for ELF files only the application description of ESP-IDF
+//! is read.
+
+use std::convert::TryInto;
+
+use sha2::{Digest, Sha256};
+use xmas_elf::sections::SectionData;
+
+use crate::{
+    elf::{update_checksum, ESP_CHECKSUM_MAGIC},
+    Chip, Error, FirmwareImage, FlashFrequency, FlashMode, FlashSize,
+};
+
+const ESP_MAGIC: u8 = 0xE9;
+/// Length of the common header, followed by the extended header on the
+/// ESP32 family
+const COMMON_HEADER_LEN: usize = 8;
+const EXTENDED_HEADER_LEN: usize = 16;
+const SEG_HEADER_LEN: usize = 8;
+const DIGEST_LEN: usize = 32;
+
+pub const APP_DESC_MAGIC: u32 = 0xABCD_5432;
+/// Sections holding the application description, with ESP-IDF 5 and 4
+const APP_DESC_SECTIONS: &[&str] = &[".flash.appdesc", ".rodata_desc"];
+
+/// The `esp_app_desc_t` ESP-IDF places at the start of the first segment of
+/// an application
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct AppDescriptor {
+    pub secure_version: u32,
+    pub version: String,
+    pub project_name: String,
+    pub time: String,
+    pub date: String,
+    pub idf_version: String,
+    /// SHA-256 digest of the ELF file the application was built from
+    pub elf_sha256: [u8; 32],
+}
+
+impl AppDescriptor {
+    pub const LEN: usize = 256;
+
+    /// Parse the descriptor at the start of `data`, if there is one
+    pub fn parse(data: &[u8]) -> Option<Self> {
+        let data = data.get(..Self::LEN)?;
+        if u32::from_le_bytes(data[0..4].try_into().unwrap()) != APP_DESC_MAGIC {
+            return None;
+        }
+
+        Some(AppDescriptor {
+            secure_version: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            version: c_string(&data[16..48]),
+            project_name: c_string(&data[48..80]),
+            time: c_string(&data[80..96]),
+            date: c_string(&data[96..112]),
+            idf_version: c_string(&data[112..144]),
+            elf_sha256: data[144..176].try_into().unwrap(),
+        })
+    }
+
+    /// Read the descriptor from the section ESP-IDF links it into
+    pub fn from_elf(image: &FirmwareImage) -> Option<Self> {
+        image.elf.section_iter().find_map(|header| {
+            let name = header.get_name(&image.elf).ok()?;
+            if !APP_DESC_SECTIONS.contains(&name) {
+                return None;
+            }
+            match header.get_data(&image.elf) {
+                Ok(SectionData::Undefined(data)) => Self::parse(data),
+                _ => None,
+            }
+        })
+    }
+}
+
+/// Fields of the application description to replace when building an image
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct AppDescriptorSettings {
+    pub version: Option<String>,
+    pub project_name: Option<String>,
+}
+
+impl AppDescriptorSettings {
+    pub fn is_empty(&self) -> bool {
+        self.version.is_none() && self.project_name.is_none()
+    }
+
+    /// Write the settings to the descriptor at the start of `data`
+    pub(crate) fn apply(&self, data: &mut [u8]) -> Result<(), Error> {
+        let fields = [
+            (&self.version, 16, "version"),
+            (&self.project_name, 48, "project name"),
+        ];
+        for (value, offset, name) in fields.iter() {
+            if let Some(value) = value {
+                // The strings are NUL terminated within their 32 bytes
+                if value.len() >= 32 {
+                    return Err(Error::InvalidAppDescriptor(format!(
+                        "the {} `{}` is longer than 31 bytes",
+                        name, value
+                    )));
+                }
+                let field = &mut data[*offset..*offset + 32];
+                field.fill(0);
+                field[..value.len()].copy_from_slice(value.as_bytes());
+            }
+        }
+
+        Ok(())
+    }
+}
+
+fn c_string(data: &[u8]) -> String {
+    let end = data
+        .iter()
+        .position(|&byte| byte == 0)
+        .unwrap_or(data.len());
+    String::from_utf8_lossy(&data[..end]).into_owned()
+}
+
+/// A segment of a binary image
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct ImageSegment {
+    /// Load address of the segment
+    pub addr: u32,
+    /// Offset of the segment data in the image
+    pub offset: usize,
+    pub len: u32,
+}
+
+/// A hash stored in the image and whether it matches the contents
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub struct Checked<T> {
+    pub value: T,
+    pub valid: bool,
+}
+
+/// The contents of a binary application or bootloader image
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ImageInfo {
+    /// The chip from the extended header, images without one are for the
+    /// ESP8266
+    pub chip: Chip,
+    pub flash_mode: Option<FlashMode>,
+    pub flash_size: Option<FlashSize>,
+    pub flash_frequency: Option<FlashFrequency>,
+    pub entry: u32,
+    pub segments: Vec<ImageSegment>,
+    pub checksum: Checked<u8>,
+    /// The SHA-256 digest appended to the image, if enabled in the header
+    pub digest: Option<Checked<[u8; 32]>>,
+    pub app_descriptor: Option<AppDescriptor>,
+    /// Length of the image including the digest
+    pub len: usize,
+}
+
+impl ImageInfo {
+    pub fn parse(data: &[u8]) -> Result<Self, Error> {
+        let invalid = |message: &str| Error::InvalidImage(message.into());
+
+        if data.len() < COMMON_HEADER_LEN || data[0] != ESP_MAGIC {
+            return Err(invalid("the image doesn't start with the magic byte 0xE9"));
+        }
+
+        // The ESP8266 has no extended header, so only use the chip id if the
+        // header looks like one
+        let extended = data
+            .get(COMMON_HEADER_LEN..COMMON_HEADER_LEN + EXTENDED_HEADER_LEN)
+            .and_then(|header| {
+                let chip = chip_from_id(u16::from_le_bytes([header[4], header[5]]))?;
+                match header[15] {
+                    digest @ 0..=1 => Some((chip, digest == 1)),
+                    _ => None,
+                }
+            });
+        let (chip, append_digest, header_len) = match extended {
+            Some((chip, append_digest)) => {
+                (chip, append_digest, COMMON_HEADER_LEN + EXTENDED_HEADER_LEN)
+            }
+            None => (Chip::Esp8266, false, COMMON_HEADER_LEN),
+        };
+
+        let mut segments = Vec::with_capacity(data[1] as usize);
+        let mut offset = header_len;
+        let mut checksum = ESP_CHECKSUM_MAGIC;
+        for _ in 0..data[1] {
+            let header = data
+                .get(offset..offset + SEG_HEADER_LEN)
+                .ok_or_else(|| invalid("a segment header is truncated"))?;
+            let addr = u32::from_le_bytes(header[0..4].try_into().unwrap());
+            let len = u32::from_le_bytes(header[4..8].try_into().unwrap());
+            offset += SEG_HEADER_LEN;
+
+            let contents = data
+                .get(offset..offset + len as usize)
+                .ok_or_else(|| invalid("a segment is truncated"))?;
+            checksum = update_checksum(contents, checksum);
+            segments.push(ImageSegment { addr, offset, len });
+            offset += len as usize;
+        }
+
+        // The checksum is the last byte of the padding to 16 bytes
+        let end = offset + 16 - offset % 16;
+        let stored = *data
+            .get(end - 1)
+            .ok_or_else(|| invalid("the checksum is missing"))?;
+
+        let digest = if append_digest {
+            let value: [u8; 32] = data
+                .get(end..end + DIGEST_LEN)
+                .ok_or_else(|| invalid("the appended digest is missing"))?
+                .try_into()
+                .unwrap();
+            Some(Checked {
+                value,
+                valid: Sha256::digest(&data[..end])[..] == value,
+            })
+        } else {
+            None
+        };
+
+        let app_descriptor = segments
+            .first()
+            .and_then(|segment| AppDescriptor::parse(&data[segment.offset..]));
+
+        Ok(ImageInfo {
+            chip,
+            flash_mode: flash_mode(data[2]),
+            flash_size: flash_size(data[3] >> 4, chip == Chip::Esp8266),
+            flash_frequency: flash_frequency(data[3] & 0xf),
+            entry: u32::from_le_bytes(data[4..8].try_into().unwrap()),
+            segments,
+            checksum: Checked {
+                value: stored,
+                valid: stored == checksum,
+            },
+            len: end + digest.map_or(0, |_| DIGEST_LEN),
+            digest,
+            app_descriptor,
+        })
+    }
+}
+
+/// The chip ids used by the extended header
+fn chip_from_id(id: u16) -> Option<Chip> {
+    match id {
+        0 => Some(Chip::Esp32),
+        2 => Some(Chip::Esp32s2),
+        5 => Some(Chip::Esp32c3),
+        9 => Some(Chip::Esp32s3),
+        12 => Some(Chip::Esp32c2),
+        16 => Some(Chip::Esp32h2),
+        _ => None,
+    }
+}
+
+fn flash_mode(value: u8) -> Option<FlashMode> {
+    match value {
+        0 => Some(FlashMode::Qio),
+        1 => Some(FlashMode::Qout),
+        2 => Some(FlashMode::Dio),
+        3 => Some(FlashMode::Dout),
+        _ => None,
+    }
+}
+
+fn flash_size(value: u8, esp8266: bool) -> Option<FlashSize> {
+    if esp8266 {
+        return match value {
+            0 => Some(FlashSize::Flash512Kb),
+            1 => Some(FlashSize::Flash256Kb),
+            2 => Some(FlashSize::Flash1Mb),
+            // 5 and 6 are the `2MB-c1` and `4MB-c1` layouts, with 1MB for the application
+            3 | 5 => Some(FlashSize::Flash2Mb),
+            4 | 6 => Some(FlashSize::Flash4Mb),
+            8 => Some(FlashSize::Flash8Mb),
+            9 => Some(FlashSize::Flash16Mb),
+            _ => None,
+        };
+    }
+    match value {
+        0 => Some(FlashSize::Flash1Mb),
+        1 => Some(FlashSize::Flash2Mb),
+        2 => Some(FlashSize::Flash4Mb),
+        3 => Some(FlashSize::Flash8Mb),
+        4 => Some(FlashSize::Flash16Mb),
+        5 => Some(FlashSize::Flash32Mb),
+        6 => Some(FlashSize::Flash64Mb),
+        _ => None,
+    }
+}
+
+fn flash_frequency(value: u8) -> Option<FlashFrequency> {
+    match value {
+        0 => Some(FlashFrequency::Flash40M),
+        1 => Some(FlashFrequency::Flash26M),
+        2 => Some(FlashFrequency::Flash20M),
+        0xf => Some(FlashFrequency::Flash80M),
+        _ => None,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn image() -> Vec<u8> {
+        let mut descriptor = vec![0; AppDescriptor::LEN];
+        descriptor[0..4].copy_from_slice(&APP_DESC_MAGIC.to_le_bytes());
+        descriptor[16..20].copy_from_slice(b"v1.2");
+        descriptor[48..53].copy_from_slice(b"hello");
+        descriptor[112..118].copy_from_slice(b"v5.0.1");
+
+        let mut data = vec![ESP_MAGIC, 1, 2, 0x20, 0x00, 0x00, 0x38, 0x40];
+        data.extend_from_slice(&[0xee, 0, 0, 0, 5, 0, 0, 0, 0, 0, 0, 0, 0, 0, 0, 1]);
+        data.extend_from_slice(&0x3c00_0020u32.to_le_bytes());
+        data.extend_from_slice(&(descriptor.len() as u32).to_le_bytes());
+        data.extend_from_slice(&descriptor);
+        data.resize(data.len() + 15, 0);
+        data.push(update_checksum(&descriptor, ESP_CHECKSUM_MAGIC));
+        let digest = Sha256::digest(&data);
+        data.extend_from_slice(&digest);
+        data
+    }
+
+    #[test]
+    fn parses_images() {
+        let data = image();
+        let info = ImageInfo::parse(&data).unwrap();
+
+        assert_eq!(info.chip, Chip::Esp32c3);
+        assert_eq!(info.flash_mode, Some(FlashMode::Dio));
+        assert_eq!(info.flash_size, Some(FlashSize::Flash4Mb));
+        assert_eq!(info.entry, 0x4038_0000);
+        assert_eq!(
+            info.segments,
+            vec![ImageSegment {
+                addr: 0x3c00_0020,
+                offset: 32,
+                len: 256
+            }]
+        );
+        assert!(info.checksum.valid);
+        assert!(info.digest.unwrap().valid);
+        assert_eq!(info.len, data.len());
+
+        let descriptor = info.app_descriptor.unwrap();
+        assert_eq!(descriptor.project_name, "hello");
+        assert_eq!(descriptor.version, "v1.2");
+        assert_eq!(descriptor.idf_version, "v5.0.1");
+
+        let mut corrupted = data;
+        corrupted[100] ^= 1;
+        let info = ImageInfo::parse(&corrupted).unwrap();
+        assert!(!info.checksum.valid);
+        assert!(!info.digest.unwrap().valid);
+
+        assert_eq!(flash_size(5, true), Some(FlashSize::Flash2Mb));
+        assert_eq!(flash_size(6, true), Some(FlashSize::Flash4Mb));
+        assert_eq!(flash_size(7, true), None);
+    }
+
+    #[test]
+    fn patches_app_descriptors() {
+        let mut data = image();
+        let settings = AppDescriptorSettings {
+            version: Some("v2.0.0-rc1".into()),
+            project_name: None,
+        };
+        settings.apply(&mut data[32..]).unwrap();
+
+        let descriptor = AppDescriptor::parse(&data[32..]).unwrap();
+        assert_eq!(descriptor.version, "v2.0.0-rc1");
+        assert_eq!(descriptor.project_name, "hello");
+
+        let settings = AppDescriptorSettings {
+            version: None,
+            project_name: Some("x".repeat(32)),
+        };
+        assert!(settings.apply(&mut data[32..]).is_err());
+    }
+}
@@ -1,21 +1,54 @@
+#[cfg(feature = "async")]
+pub use async_flasher::{AsyncFlasher, AsyncProgressCallbacks, AsyncTransport};
 pub use chip::Chip;
+#[cfg(feature = "cli")]
 pub use cli::config::Config;
-pub use elf::FirmwareImage;
-pub use error::Error;
-pub use flasher::Flasher;
+pub use connection::{ConnectSettings, DEFAULT_CONNECT_ATTEMPTS};
+pub use elf::{FirmwareImage, FlashFrequency, FlashLayout, FlashMode, FlashSettings};
+pub use error::{Error, ErrorCategory};
+pub use file_format::FileFormat;
+pub use flasher::{
+    BinOptions, DeviceInfo, FlashPlan, FlashSize, Flasher, ImageSettings, ProgressCallbacks,
+    WrittenSegment,
+};
 pub use image_format::ImageFormatId;
 pub use partition_table::PartitionTable;
+pub use reset::{ResetAfterOperation, ResetBeforeOperation};
+pub use sector_cache::SectorCache;
 
+#[cfg(feature = "async")]
+mod async_flasher;
+#[cfg(feature = "cli")]
+mod capture;
 mod chip;
 mod command;
 mod connection;
 mod elf;
 mod encoder;
 mod error;
+mod file_format;
 mod flash_target;
 mod flasher;
 mod image_format;
 mod partition_table;
+mod protocol;
+#[cfg(feature = "cli")]
+mod remote;
+mod reset;
+mod sector_cache;
 
+pub mod bootloaders;
+pub mod coredump;
+pub mod efuse;
+pub mod fs;
+pub mod image_info;
+pub mod metadata;
+pub mod nvs;
+pub mod secure_boot;
+pub mod size;
+pub mod stubs;
+pub mod transport;
+
+#[cfg(feature = "cli")]
 #[doc(hidden)]
 pub mod cli;
@@ -1,17 +1,59 @@
-use std::{fs, mem::swap, path::PathBuf, str::FromStr};
+use std::{
+    mem::swap,
+    path::{Path, PathBuf},
+    str::FromStr,
+    sync::Arc,
+    time::Instant,
+};
 
 use clap::{AppSettings, IntoApp, Parser};
+#[cfg(target_os = "linux")]
+use espflash::cli::udev::{install_udev_rules, UdevOpts};
 use espflash::{
     cli::{
-        board_info, connect, flash_elf_image, monitor::monitor, save_elf_as_image, ConnectOpts,
-        FlashOpts,
+        board_info,
+        build::{build, BuildOpts},
+        capture::{print_capture, replay_capture, PrintCaptureOpts, ReplayCaptureOpts},
+        connect,
+        coredump::{coredump, CoredumpOpts},
+        deadline::deadline,
+        debug::debug,
+        dry_run::dry_run,
+        efuse::{read_efuse, ReadEfuseOpts},
+        erase_flash, erase_region, exit_with_error, flash_elf_image,
+        flash_fs::{flash_fs, FlashFsOpts},
+        flash_monitor,
+        idf::{flash_idf, FlashIdfOpts},
+        image_chip,
+        image_info::{image_info, ImageInfoOpts},
+        input::read_input,
+        load_signing_key,
+        logging::initialize_logger,
+        manifest::flash_manifest,
+        merge_bin::{merge_bin, MergeBinOpts},
+        multi::{device_ports, flash_devices},
+        nvs::{nvs, NvsOpts},
+        partition_table::{partition_table, PartitionTableOpts},
+        print_flash_report,
+        progress::EspflashProgress,
+        read_flash::{read_flash, ReadFlashOpts},
+        register::{read_reg, write_reg, ReadRegOpts, WriteRegOpts},
+        save_elf_as_image, sector_cache, serial_monitor,
+        serve::{serve, ServeOpts},
+        size::{size, SizeOpts},
+        test_runner::{run_test, TestOpts},
+        watch::watch_and_flash,
+        write_bin::{write_bin, WriteBinOpts},
+        BoardInfoOpts, ConnectOpts, EraseRegionOpts, FlashConfigOpts, FlashOpts, LogOpts,
+        SerialMonitorOpts,
     },
-    Chip, Config, ImageFormatId,
+    Chip, Config, ErrorCategory, FileFormat, Flasher, ImageFormatId,
 };
-use miette::{IntoDiagnostic, Result, WrapErr};
+use log::warn;
+use miette::Result;
 
 #[derive(Parser)]
-#[clap(version, global_setting = AppSettings::PropagateVersion)]
+#[clap(version, about, global_setting = AppSettings::PropagateVersion)]
 struct Opts {
     /// Image format to flash
     #[clap(long)]
@@ -20,6 +62,26 @@ struct Opts {
     flash_opts: FlashOpts,
     #[clap(flatten)]
     connect_opts: ConnectOpts,
+    #[clap(flatten)]
+    log_opts: LogOpts,
+    #[clap(flatten)]
+    build_opts: BuildOpts,
+    /// Flash the image again whenever it changes, restarting the monitor if enabled
+    #[clap(long, conflicts_with = "debug")]
+    watch: bool,
+    /// Flash the image to all connected devices which are known dev boards at once
+    #[clap(long)]
+    all_ports: bool,
+    /// Chip to build the image for with `--dry-run`, defaults to the one given
+    /// in the image
+    #[clap(long, requires = "dry-run")]
+    chip: Option<Chip>,
+    /// Flash the binaries listed in this manifest file instead of an ELF image
+    #[clap(long, conflicts_with_all = &["image", "watch", "all-ports", "monitor", "ram", "debug", "dry-run"])]
+    manifest: Option<PathBuf>,
+    /// Board preset setting the chip, flash settings, reset behavior and monitor speed, eg `wemos-d1-mini`
+    #[clap(long, global = true)]
+    board: Option<String>,
     /// ELF image to flash
     image: Option<String>,
     #[clap(subcommand)]
@@ -29,9 +91,55 @@ struct Opts {
 #[derive(Parser)]
 pub enum SubCommand {
     /// Display information about the connected board and exit without flashing
-    BoardInfo(ConnectOpts),
+    BoardInfo(BoardInfoOpts),
+    /// Open the serial monitor without flashing, also works with Bluetooth serial ports
+    Monitor(SerialMonitorOpts),
     /// Save the image to disk instead of flashing to device
     SaveImage(SaveImageOpts),
+    /// Combine the bootloader, partition table, application and other binaries into one image
+    MergeBin(MergeBinOpts),
+    /// Erase the entire flash of the connected device
+    EraseFlash(ConnectOpts),
+    /// Erase a region of the flash of the connected device
+    EraseRegion(EraseRegionOpts),
+    /// Flash raw binaries at the given offsets, without any processing
+    WriteBin(WriteBinOpts),
+    /// Flash all binaries of an ESP-IDF build directory at their offsets
+    FlashIdf(FlashIdfOpts),
+    /// Build a SPIFFS image from a directory and flash it to a data partition
+    FlashFs(FlashFsOpts),
+    /// Read the contents of the flash and save them to a file
+    ReadFlash(ReadFlashOpts),
+    /// Dump the efuses of the connected chip and decode its security settings
+    ReadEfuse(ReadEfuseOpts),
+    /// Read 32 bit registers of the connected chip, eg to check the strapping pins
+    ReadReg(ReadRegOpts),
+    /// Write a 32 bit register of the connected chip
+    WriteReg(WriteRegOpts),
+    /// Read the core dump saved by a crashed application and print the backtraces of its tasks
+    Coredump(CoredumpOpts),
+    /// Flash a test image and wait for it to report whether the tests passed
+    Test(TestOpts),
+    /// Print the traffic recorded in a capture file
+    PrintCapture(PrintCaptureOpts),
+    /// Replay the commands of a capture file against a mock device, and show
+    /// the responses which differ
+    ReplayCapture(ReplayCaptureOpts),
+    /// Print the header, segments, application description and digests of an image
+    ImageInfo(ImageInfoOpts),
+    /// Validate a partition table and convert it between the CSV and binary formats
+    PartitionTable(PartitionTableOpts),
+    /// Report the memory used by an ELF image and the free space in its app partition
+    Size(SizeOpts),
+    /// Keep the serial port open and flash, reset or monitor the device on
+    /// requests sent to a local TCP port, using the flash options given before
+    /// the subcommand
+    Serve(ServeOpts),
+    /// Generate an NVS partition from a CSV or TOML file, and optionally flash it
+    NvsPartition(NvsOpts),
+    /// Print or install udev rules allowing access to common USB serial adapters
+    #[cfg(target_os = "linux")]
+    InstallUdevRules(UdevOpts),
 }
 
 #[derive(Parser)]
@@ -39,6 +147,14 @@ pub struct SaveImageOpts {
     /// Image format to flash
     #[clap(long)]
     format: Option<String>,
+    #[clap(flatten)]
+    flash_config_opts: FlashConfigOpts,
+    /// File format to save the image in, defaults to the one matching the file extension
+    #[clap(long, possible_values = &["bin", "uf2", "hex"])]
+    file_format: Option<FileFormat>,
+    /// Sign the image for Secure Boot v2 with this RSA-3072 or ECDSA private key
+    #[clap(long)]
+    signing_key: Option<PathBuf>,
     /// the chip to create an image for
     chip: Chip,
     /// ELF image to flash
@@ -47,15 +163,40 @@ pub struct SaveImageOpts {
     file: PathBuf,
 }
 
-fn main() -> Result<()> {
+fn main() {
     miette::set_panic_hook();
 
-    let mut opts = Opts::parse();
-    let config = Config::load()?;
+    let opts = Opts::parse();
+    initialize_logger(&opts.log_opts);
+
+    let output_format = opts.log_opts.output_format;
+    if let Err(report) = run(opts) {
+        let category = ErrorCategory::of(&report).unwrap_or(ErrorCategory::Other);
+        exit_with_error(report, category, output_format);
+    }
+}
+
+fn run(mut opts: Opts) -> Result<()> {
+    let mut config = Config::load()?;
+    if let Some(board) = &opts.board {
+        config.select_board(board)?;
+    }
+    opts.flash_opts.apply_config(&config);
+    if opts.format.is_none() {
+        opts.format = config.flash.format.map(|format| format.to_string());
+    }
 
-    // If neither the IMAGE nor SERIAL arguments have been provided, print the help
-    // message and exit.
-    if opts.image.is_none() && opts.connect_opts.serial.is_none() {
+    if opts.subcommand.is_none() && opts.build_opts.enabled() {
+        opts.image = Some(build(&opts.build_opts)?.display().to_string());
+    }
+
+    // If neither the IMAGE nor SERIAL arguments nor a subcommand have been provided,
+    // print the help message and exit.
+    if opts.subcommand.is_none()
+        && opts.image.is_none()
+        && opts.connect_opts.serial.is_none()
+        && opts.manifest.is_none()
+    {
         Opts::into_app().print_help().ok();
         return Ok(());
     }
@@ -64,16 +205,41 @@ fn main() -> Result<()> {
     // In the case that the serial port was not provided as a command-line argument,
     // we will either load the value specified in the configuration file or do port
     // auto-detection instead.
-    if opts.image.is_none() && opts.connect_opts.serial.is_some() {
+    if opts.image.is_none() && opts.connect_opts.serial.is_some() && opts.manifest.is_none() {
         swap(&mut opts.image, &mut opts.connect_opts.serial);
     }
 
-    if let Some(subcommand) = opts.subcommand {
+    let output_format = opts.log_opts.output_format;
+    if let Some(subcommand) = opts.subcommand.take() {
         use SubCommand::*;
 
         match subcommand {
-            BoardInfo(opts) => board_info(opts, config),
-            SaveImage(opts) => save_image(opts),
+            BoardInfo(opts) => board_info(opts, config, output_format),
+            Monitor(opts) => serial_monitor(opts, config),
+            SaveImage(opts) => save_image(opts, config),
+            MergeBin(opts) => merge_bin(opts, config),
+            EraseFlash(opts) => erase_flash(opts, config),
+            EraseRegion(opts) => erase_region(opts, config),
+            WriteBin(opts) => write_bin(opts, config, output_format),
+            FlashIdf(opts) => flash_idf(opts, config, output_format),
+            FlashFs(opts) => flash_fs(opts, config, output_format),
+            ReadFlash(opts) => read_flash(opts, config),
+            ReadEfuse(opts) => read_efuse(opts, config),
+            ReadReg(opts) => read_reg(opts, config),
+            WriteReg(opts) => write_reg(opts, config),
+            Coredump(opts) => coredump(opts, config),
+            Test(opts) => run_test(opts, config),
+            PrintCapture(opts) => print_capture(opts),
+            ReplayCapture(opts) => replay_capture(opts),
+            ImageInfo(opts) => image_info(opts),
+            PartitionTable(opts) => partition_table(opts, config),
+            Size(opts) => size(opts, config, output_format),
+            Serve(serve_opts) => serve(serve_opts, config, |flasher, elf_data| {
+                load_image(flasher, &opts, elf_data)
+            }),
+            NvsPartition(opts) => nvs(opts, config, output_format),
+            #[cfg(target_os = "linux")]
+            InstallUdevRules(opts) => install_udev_rules(opts),
         }
     } else {
         flash(opts, config)
@@ -81,10 +247,35 @@ fn main() -> Result<()> {
 }
 
 fn flash(opts: Opts, config: Config) -> Result<()> {
+    if opts.flash_opts.dry_run {
+        return flash_dry_run(opts, &config);
+    }
+
+    // Several serial ports can be given separated by commas
+    if let Some(ports) = device_ports(opts.connect_opts.serial.as_deref(), opts.all_ports, &config)?
+    {
+        return flash_multiple(opts, config, ports);
+    }
+
+    if opts.watch {
+        return watch(opts, config);
+    }
+
+    let deadline = deadline(opts.connect_opts.timeout);
     let mut flasher = connect(&opts.connect_opts, &config)?;
     flasher.board_info()?;
 
-    let elf = if let Some(elf) = opts.image {
+    if let Some(manifest) = &opts.manifest {
+        return flash_manifest(
+            &mut flasher,
+            manifest,
+            opts.flash_opts.encrypt,
+            opts.flash_opts.verify,
+            opts.log_opts.output_format,
+        );
+    }
+
+    let elf = if let Some(elf) = &opts.image {
         elf
     } else {
         Opts::into_app().print_help().ok();
@@ -92,12 +283,127 @@ fn flash(opts: Opts, config: Config) -> Result<()> {
     };
 
     // Read the ELF data from the build path and load it to the target.
-    let elf_data = fs::read(&elf).into_diagnostic()?;
+    let elf_data = read_input(Path::new(elf))?;
+    let start = Instant::now();
+    load_image(&mut flasher, &opts, &elf_data)?;
+    print_flash_report(&mut flasher, start.elapsed(), opts.log_opts.output_format)?;
+
+    // The deadline only applies to flashing, the monitor runs until the user exits it.
+    drop(deadline);
+
+    if opts.flash_opts.debug_opts.debug {
+        return debug(
+            flasher,
+            Path::new(elf),
+            &elf_data,
+            &opts.flash_opts.debug_opts,
+        );
+    }
+    if opts.flash_opts.monitor {
+        flash_monitor(
+            flasher,
+            &opts.connect_opts,
+            &config,
+            &opts.flash_opts.monitor_opts,
+            &elf_data,
+            |flasher, elf_data| load_image(flasher, &opts, elf_data),
+        )?;
+    }
+
+    Ok(())
+}
+
+fn flash_dry_run(opts: Opts, config: &Config) -> Result<()> {
+    let elf = if let Some(elf) = &opts.image {
+        elf
+    } else {
+        Opts::into_app().print_help().ok();
+        return Ok(());
+    };
+
+    let elf_data = read_input(Path::new(elf))?;
+    let chip = image_chip(opts.chip.or_else(|| config.board_chip()), &elf_data)?;
+    let image_format = opts
+        .format
+        .as_deref()
+        .map(ImageFormatId::from_str)
+        .transpose()?;
+
+    dry_run(
+        chip,
+        &elf_data,
+        &opts.flash_opts,
+        opts.flash_opts.bootloader(chip)?.as_deref(),
+        opts.flash_opts.partition_table.as_deref(),
+        image_format,
+        opts.log_opts.output_format,
+    )
+}
+
+fn flash_multiple(opts: Opts, config: Config, ports: Vec<String>) -> Result<()> {
+    let elf = if let Some(elf) = &opts.image {
+        elf
+    } else {
+        Opts::into_app().print_help().ok();
+        return Ok(());
+    };
+    if opts.watch || opts.flash_opts.monitor || opts.flash_opts.debug_opts.debug {
+        warn!("Watching, monitoring and debugging are not available when flashing several devices");
+    }
+
+    let elf_data = read_input(Path::new(elf))?;
+    let connect_opts = opts.connect_opts.clone();
+    let deadline = deadline(connect_opts.timeout);
+    let opts = Arc::new(opts);
+    flash_devices(
+        ports,
+        &connect_opts,
+        &config,
+        elf_data,
+        move |flasher, elf_data| load_image(flasher, &opts, elf_data),
+    )?;
+    drop(deadline);
+
+    Ok(())
+}
+
+fn watch(opts: Opts, config: Config) -> Result<()> {
+    let elf = if let Some(elf) = &opts.image {
+        PathBuf::from(elf)
+    } else {
+        Opts::into_app().print_help().ok();
+        return Ok(());
+    };
+
+    watch_and_flash(
+        &opts.connect_opts,
+        &config,
+        &elf,
+        opts.flash_opts
+            .monitor
+            .then_some(&opts.flash_opts.monitor_opts),
+        |flasher, elf_data| load_image(flasher, &opts, elf_data),
+    )
+}
+
+fn load_image(flasher: &mut Flasher, opts: &Opts, elf_data: &[u8]) -> Result<()> {
+    flasher.set_verify(opts.flash_opts.verify);
+    flasher.set_compression(!opts.flash_opts.no_compress);
+    flasher.set_image_settings(opts.flash_opts.image_settings()?);
+    flasher.set_encrypt(opts.flash_opts.encrypt);
+    if opts.flash_opts.force {
+        sector_cache::clear(flasher);
+    }
+    flasher.set_skip_identical(!opts.flash_opts.no_skip && !opts.flash_opts.force);
 
     if opts.flash_opts.ram {
-        flasher.load_elf_to_ram(&elf_data)?;
+        flasher.load_elf_to_ram_with_entry(
+            elf_data,
+            opts.flash_opts.entry_point,
+            Some(&mut EspflashProgress::default()),
+        )?;
     } else {
-        let bootloader = opts.flash_opts.bootloader.as_deref();
+        let bootloader = opts.flash_opts.bootloader(flasher.chip())?;
         let partition_table = opts.flash_opts.partition_table.as_deref();
 
         let image_format = opts
@@ -106,43 +412,43 @@ fn flash(opts: Opts, config: Config) -> Result<()> {
             .map(ImageFormatId::from_str)
             .transpose()?;
 
+        opts.flash_opts
+            .provision_opts
+            .provision(flasher, partition_table)?;
+
         flash_elf_image(
-            &mut flasher,
-            &elf_data,
-            bootloader,
+            flasher,
+            elf_data,
+            bootloader.as_deref(),
             partition_table,
             image_format,
         )?;
+        opts.flash_opts.provision_opts.flashed(flasher)?;
     }
 
-    if opts.flash_opts.monitor {
-        #[cfg(target_os = "linux")]
-        {
-            let (dtr, rts) = espflash::cli::create_dtr_rts_gpios_from_args(
-                &opts.connect_opts.gpio_dtr,
-                &opts.connect_opts.gpio_rts,
-            )?;
-            monitor(flasher.into_serial(), dtr, rts).into_diagnostic()?;
-        }
-        #[cfg(not(target_os = "linux"))]
-        monitor(flasher.into_serial(), None, None).into_diagnostic()?;
-    }
-
-    Ok(())
+    sector_cache::save(flasher)
 }
 
-fn save_image(opts: SaveImageOpts) -> Result<()> {
-    let elf_data = fs::read(&opts.image)
-        .into_diagnostic()
-        .wrap_err_with(|| format!("Failed to open image {}", opts.image.display()))?;
+fn save_image(mut opts: SaveImageOpts, config: Config) -> Result<()> {
+    opts.flash_config_opts.apply_config(&config);
+    let elf_data = read_input(&opts.image)?;
 
     let image_format = opts
         .format
         .as_deref()
         .map(ImageFormatId::from_str)
-        .transpose()?;
+        .transpose()?
+        .or(config.flash.format);
 
-    save_elf_as_image(opts.chip, &elf_data, opts.file, image_format)?;
+    save_elf_as_image(
+        opts.chip,
+        &elf_data,
+        opts.file,
+        image_format,
+        &opts.flash_config_opts,
+        opts.file_format,
+        load_signing_key(opts.signing_key.as_deref())?.as_ref(),
+    )?;
 
     Ok(())
 }
@@ -0,0 +1,235 @@
+//! Flash settings embedded in the ELF image
+//!
+//! Applications can carry the settings they need to be flashed with in an ELF
+//! note section named `.note.espflash`, which is picked up when flashing the
+//! image so no command line arguments are needed. The note has the name
+//! `espflash` and type [`NOTE_TYPE`], its descriptor is a list of `key=value`
+//! lines:
+//!
+//! | Key             | Example    |
+//! | :-------------- | :--------- |
+//! | `chip`          | `esp32-c3` |
+//! | `flash_mode`    | `dio`      |
+//! | `flash_size`    | `4MB`      |
+//! | `flash_freq`    | `40M`      |
+//! | `app_partition` | `ota_0`    |
+//!
+//! Every key is optional. The note is most easily generated from a build
+//! script using [`FlashMetadata::write_rust_source`]:
+//!
+//! ```no_run
+//! // build.rs
+//! use espflash::{metadata::FlashMetadata, Chip, FlashSize};
+//!
+//! let metadata = FlashMetadata {
+//!     chip: Some(Chip::Esp32c3),
+//!     flash_size: Some(FlashSize::Flash4Mb),
+//!     ..FlashMetadata::default()
+//! };
+//! let out_dir = std::env::var("OUT_DIR").unwrap();
+//! metadata
+//!     .write_rust_source(format!("{}/espflash_metadata.rs", out_dir))
+//!     .unwrap();
+//! ```
+//!
+//! after which the note is included in the application with
+//! `include!(concat!(env!("OUT_DIR"), "/espflash_metadata.rs"));`. If the
+//! linker garbage collects sections, make sure the linker script keeps
+//! `.note.espflash`.
+
+use std::{convert::TryInto, fmt::Write as _, fs, io, path::Path, str::FromStr};
+
+use xmas_elf::{sections::ShType, ElfFile};
+
+use crate::{
+    elf::{FlashFrequency, FlashMode},
+    error::Error,
+    flasher::FlashSize,
+    Chip,
+};
+
+/// Name of the section containing the note
+pub const SECTION_NAME: &str = ".note.espflash";
+/// Type of the note containing the flash settings
+pub const NOTE_TYPE: u32 = 1;
+
+const NOTE_NAME: &[u8] = b"espflash\0";
+
+/// Flash settings requested by the application
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct FlashMetadata {
+    /// The chip the application is built for
+    pub chip: Option<Chip>,
+    pub flash_mode: Option<FlashMode>,
+    pub flash_size: Option<FlashSize>,
+    pub flash_frequency: Option<FlashFrequency>,
+    /// Name of the partition the application is written to
+    pub app_partition: Option<String>,
+}
+
+impl FlashMetadata {
+    /// Read the metadata from the note section of an ELF file, if present
+    pub fn from_elf(elf: &ElfFile) -> Result<Self, Error> {
+        let section = match elf.find_section_by_name(SECTION_NAME) {
+            Some(section) => section,
+            None => return Ok(Self::default()),
+        };
+
+        // Sections without contents in the file, or pointing past its end, can't
+        // hold the note
+        let data = match section.get_type() {
+            Ok(ShType::Null | ShType::NoBits) | Err(_) => None,
+            Ok(_) => {
+                let start = section.offset() as usize;
+                (section.size() as usize)
+                    .checked_add(start)
+                    .and_then(|end| elf.input.get(start..end))
+            }
+        };
+        let mut data = data.ok_or_else(|| Error::InvalidMetadata("invalid note section".into()))?;
+        while data.len() >= 12 {
+            let word =
+                |offset: usize| u32::from_le_bytes(data[offset..offset + 4].try_into().unwrap());
+            let name_size = word(0) as usize;
+            let desc_size = word(4) as usize;
+            let ty = word(8);
+
+            let name_end = 12 + align4(name_size);
+            let desc_end = name_end + align4(desc_size);
+            if desc_end > data.len() {
+                return Err(Error::InvalidMetadata("truncated note".into()));
+            }
+
+            if &data[12..12 + name_size] == NOTE_NAME && ty == NOTE_TYPE {
+                let desc = std::str::from_utf8(&data[name_end..name_end + desc_size])
+                    .map_err(|_| Error::InvalidMetadata("note is not valid UTF-8".into()))?;
+                return desc.parse();
+            }
+            data = &data[desc_end..];
+        }
+
+        Ok(Self::default())
+    }
+
+    /// Whether no settings are specified
+    pub fn is_empty(&self) -> bool {
+        *self == Self::default()
+    }
+
+    /// Encode the metadata as the contents of the note section
+    pub fn to_note(&self) -> Vec<u8> {
+        let desc = self.to_string();
+
+        let mut note = Vec::new();
+        note.extend_from_slice(&(NOTE_NAME.len() as u32).to_le_bytes());
+        note.extend_from_slice(&(desc.len() as u32).to_le_bytes());
+        note.extend_from_slice(&NOTE_TYPE.to_le_bytes());
+        note.extend_from_slice(NOTE_NAME);
+        note.resize(12 + align4(NOTE_NAME.len()), 0);
+        note.extend_from_slice(desc.as_bytes());
+        note.resize(note.len() + align4(desc.len()) - desc.len(), 0);
+
+        note
+    }
+
+    /// Write a Rust source file defining a static containing the note
+    ///
+    /// Meant to be called from a build script, the file is then included in the
+    /// application using `include!`.
+    pub fn write_rust_source<P: AsRef<Path>>(&self, path: P) -> io::Result<()> {
+        let note = self.to_note();
+
+        let mut source = String::new();
+        writeln!(source, "#[used]").unwrap();
+        writeln!(source, "#[link_section = \"{}\"]", SECTION_NAME).unwrap();
+        writeln!(
+            source,
+            "static ESPFLASH_METADATA: [u8; {}] = {:?};",
+            note.len(),
+            note
+        )
+        .unwrap();
+
+        fs::write(path, source)
+    }
+}
+
+impl std::fmt::Display for FlashMetadata {
+    fn fmt(&self, f: &mut std::fmt::Formatter<'_>) -> std::fmt::Result {
+        if let Some(chip) = self.chip {
+            writeln!(f, "chip={}", chip.to_string().to_ascii_lowercase())?;
+        }
+        if let Some(mode) = self.flash_mode {
+            writeln!(f, "flash_mode={}", mode)?;
+        }
+        if let Some(size) = self.flash_size {
+            writeln!(f, "flash_size={}", size)?;
+        }
+        if let Some(frequency) = self.flash_frequency {
+            writeln!(f, "flash_freq={}", frequency)?;
+        }
+        if let Some(partition) = &self.app_partition {
+            writeln!(f, "app_partition={}", partition)?;
+        }
+        Ok(())
+    }
+}
+
+impl FromStr for FlashMetadata {
+    type Err = Error;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        let mut metadata = FlashMetadata::default();
+
+        for line in s.lines().map(str::trim).filter(|line| !line.is_empty()) {
+            let (key, value) = match line.split_once('=') {
+                Some((key, value)) => (key.trim(), value.trim()),
+                None => return Err(Error::InvalidMetadata(format!("invalid entry `{}`", line))),
+            };
+            let invalid = || Error::InvalidMetadata(format!("invalid {} `{}`", key, value));
+
+            match key {
+                "chip" => metadata.chip = Some(value.parse().map_err(|_| invalid())?),
+                "flash_mode" => metadata.flash_mode = Some(value.parse().map_err(|_| invalid())?),
+                "flash_size" => metadata.flash_size = Some(value.parse().map_err(|_| invalid())?),
+                "flash_freq" => {
+                    metadata.flash_frequency = Some(value.parse().map_err(|_| invalid())?)
+                }
+                "app_partition" => metadata.app_partition = Some(value.to_string()),
+                _ => return Err(Error::InvalidMetadata(format!("unknown key `{}`", key))),
+            }
+        }
+
+        Ok(metadata)
+    }
+}
+
+fn align4(size: usize) -> usize {
+    (size + 3) & !3
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn metadata_round_trips_through_note() {
+        let metadata = FlashMetadata {
+            chip: Some(Chip::Esp32c3),
+            flash_mode: Some(FlashMode::Qio),
+            flash_size: Some(FlashSize::Flash8Mb),
+            flash_frequency: Some(FlashFrequency::Flash80M),
+            app_partition: Some("ota_0".into()),
+        };
+
+        let note = metadata.to_note();
+        assert_eq!(note.len() % 4, 0);
+
+        let desc_size = u32::from_le_bytes(note[4..8].try_into().unwrap()) as usize;
+        let desc = std::str::from_utf8(&note[24..24 + desc_size]).unwrap();
+        assert_eq!(desc.parse::<FlashMetadata>().unwrap(), metadata);
+
+        assert!("flash_size=3MB".parse::<FlashMetadata>().is_err());
+        assert!("baud=115200".parse::<FlashMetadata>().is_err());
+    }
+}
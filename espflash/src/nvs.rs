@@ -0,0 +1,582 @@
+//! NVS partitions, holding the key-value pairs read through the `nvs_flash`
+//! API of ESP-IDF
+//!
+//! The partitions are generated the same way as by `nvs_partition_gen.py`,
+//! whose CSV format is supported as well. The partition is made of 4 KiB
+//! pages, each holding 126 entries of 32 bytes. Strings and blobs continue
+//! into the entries following their header, blobs larger than a page are
+//! split into chunks.
+
+use std::{convert::TryFrom, fs, path::Path};
+
+use crc32fast::Hasher;
+
+use crate::error::Error;
+
+const PAGE_SIZE: usize = 4096;
+const HEADER_SIZE: usize = 32;
+const BITMAP_SIZE: usize = 32;
+const ENTRY_SIZE: usize = 32;
+const ENTRIES_PER_PAGE: usize = 126;
+const MAX_KEY_LEN: usize = 15;
+const MAX_NAMESPACES: usize = 254;
+// Everything but the header entry of a page can hold the data of a string
+const MAX_STRING_LEN: usize = (ENTRIES_PER_PAGE - 1) * ENTRY_SIZE;
+
+const STATE_ACTIVE: u32 = 0xFFFF_FFFE;
+const STATE_FULL: u32 = 0xFFFF_FFFC;
+// Version 2 supports blobs split into chunks
+const VERSION: u8 = 0xFE;
+const CHUNK_ANY: u8 = 0xFF;
+
+const TYPE_U8: u8 = 0x01;
+const TYPE_I8: u8 = 0x11;
+const TYPE_U16: u8 = 0x02;
+const TYPE_I16: u8 = 0x12;
+const TYPE_U32: u8 = 0x04;
+const TYPE_I32: u8 = 0x14;
+const TYPE_U64: u8 = 0x08;
+const TYPE_I64: u8 = 0x18;
+const TYPE_STRING: u8 = 0x21;
+const TYPE_BLOB_DATA: u8 = 0x42;
+const TYPE_BLOB_INDEX: u8 = 0x48;
+
+/// A value stored in NVS
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub enum NvsValue {
+    U8(u8),
+    I8(i8),
+    U16(u16),
+    I16(i16),
+    U32(u32),
+    I32(i32),
+    U64(u64),
+    I64(i64),
+    String(String),
+    Blob(Vec<u8>),
+}
+
+impl NvsValue {
+    /// Parse a value given in one of the encodings of `nvs_partition_gen.py`,
+    /// eg `u8`, `string` or `hex2bin`
+    pub fn parse(encoding: &str, value: &str) -> Result<Self, Error> {
+        let invalid =
+            || Error::InvalidNvs(format!("`{}` is not a valid {} value", value, encoding));
+
+        let value = match encoding {
+            "u8" => NvsValue::U8(parse_int(value).ok_or_else(invalid)?),
+            "i8" => NvsValue::I8(parse_int(value).ok_or_else(invalid)?),
+            "u16" => NvsValue::U16(parse_int(value).ok_or_else(invalid)?),
+            "i16" => NvsValue::I16(parse_int(value).ok_or_else(invalid)?),
+            "u32" => NvsValue::U32(parse_int(value).ok_or_else(invalid)?),
+            "i32" => NvsValue::I32(parse_int(value).ok_or_else(invalid)?),
+            "u64" => NvsValue::U64(parse_int(value).ok_or_else(invalid)?),
+            "i64" => NvsValue::I64(parse_int(value).ok_or_else(invalid)?),
+            "string" => NvsValue::String(value.into()),
+            "hex2bin" => NvsValue::Blob(decode_hex(value).ok_or_else(invalid)?),
+            "base64" => NvsValue::Blob(decode_base64(value).ok_or_else(invalid)?),
+            _ => {
+                return Err(Error::InvalidNvs(format!(
+                    "unknown encoding `{}`",
+                    encoding
+                )))
+            }
+        };
+
+        Ok(value)
+    }
+
+    /// Decode the contents of a file, which are used as is by the `binary`
+    /// encoding
+    pub fn from_file(encoding: &str, data: Vec<u8>) -> Result<Self, Error> {
+        match encoding {
+            "binary" => Ok(NvsValue::Blob(data)),
+            "string" | "hex2bin" | "base64" => {
+                let text = String::from_utf8(data)
+                    .map_err(|_| Error::InvalidNvs("the file is not valid UTF-8".into()))?;
+                Self::parse(encoding, &text)
+            }
+            _ => Err(Error::InvalidNvs(format!(
+                "the {} encoding can't be used for files",
+                encoding
+            ))),
+        }
+    }
+
+    fn primitive(&self) -> Option<(u8, Vec<u8>)> {
+        let primitive = match self {
+            NvsValue::U8(value) => (TYPE_U8, value.to_le_bytes().to_vec()),
+            NvsValue::I8(value) => (TYPE_I8, value.to_le_bytes().to_vec()),
+            NvsValue::U16(value) => (TYPE_U16, value.to_le_bytes().to_vec()),
+            NvsValue::I16(value) => (TYPE_I16, value.to_le_bytes().to_vec()),
+            NvsValue::U32(value) => (TYPE_U32, value.to_le_bytes().to_vec()),
+            NvsValue::I32(value) => (TYPE_I32, value.to_le_bytes().to_vec()),
+            NvsValue::U64(value) => (TYPE_U64, value.to_le_bytes().to_vec()),
+            NvsValue::I64(value) => (TYPE_I64, value.to_le_bytes().to_vec()),
+            NvsValue::String(_) | NvsValue::Blob(_) => return None,
+        };
+
+        Some(primitive)
+    }
+}
+
+/// A key and its value, in a namespace
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct NvsEntry {
+    pub namespace: String,
+    pub key: String,
+    pub value: NvsValue,
+}
+
+/// The contents of an NVS partition
+#[derive(Clone, Debug, Default, PartialEq, Eq)]
+pub struct NvsPartition {
+    entries: Vec<NvsEntry>,
+}
+
+impl NvsPartition {
+    pub fn new() -> Self {
+        Self::default()
+    }
+
+    /// Set the value of a key, replacing any previous value of it
+    pub fn set(&mut self, namespace: &str, key: &str, value: NvsValue) -> Result<(), Error> {
+        for name in &[namespace, key] {
+            if name.is_empty() || name.len() > MAX_KEY_LEN {
+                return Err(Error::InvalidNvs(format!(
+                    "`{}` is not between 1 and {} bytes long",
+                    name, MAX_KEY_LEN
+                )));
+            }
+        }
+
+        match self
+            .entries
+            .iter_mut()
+            .find(|entry| entry.namespace == namespace && entry.key == key)
+        {
+            Some(entry) => entry.value = value,
+            None => self.entries.push(NvsEntry {
+                namespace: namespace.into(),
+                key: key.into(),
+                value,
+            }),
+        }
+
+        Ok(())
+    }
+
+    pub fn entries(&self) -> &[NvsEntry] {
+        &self.entries
+    }
+
+    /// Parse the CSV format of `nvs_partition_gen.py`
+    ///
+    /// Every row holds a key, its type, encoding and value. The type is either
+    /// `namespace`, which starts a namespace, `data` or `file`, whose value is
+    /// the path of a file relative to `dir`.
+    pub fn try_from_csv(data: &[u8], dir: &Path) -> Result<Self, Error> {
+        Self::try_from_csv_with(data, dir, |value| value.to_string())
+    }
+
+    /// Parse the CSV format of `nvs_partition_gen.py`, passing the value of
+    /// every row through `fill` before it is decoded, eg to replace
+    /// placeholders
+    pub fn try_from_csv_with(
+        data: &[u8],
+        dir: &Path,
+        fill: impl Fn(&str) -> String,
+    ) -> Result<Self, Error> {
+        let mut reader = csv::ReaderBuilder::new()
+            .comment(Some(b'#'))
+            .has_headers(false)
+            .flexible(true)
+            .trim(csv::Trim::All)
+            .from_reader(data);
+
+        let mut partition = NvsPartition::new();
+        let mut namespace = None;
+
+        for record in reader.records() {
+            let record = record.map_err(|e| Error::InvalidNvs(e.to_string()))?;
+            let line = record.position().map_or(0, |position| position.line());
+            let field = |i| record.get(i).unwrap_or_default();
+            let text = fill(field(3));
+
+            let value = match field(1) {
+                // The header row
+                "type" if field(0) == "key" => continue,
+                "namespace" => {
+                    namespace = Some(field(0).to_string());
+                    continue;
+                }
+                "data" => NvsValue::parse(field(2), &text),
+                "file" => {
+                    let path = dir.join(&text);
+                    fs::read(&path)
+                        .map_err(|e| {
+                            Error::InvalidNvs(format!("failed to read {}: {}", path.display(), e))
+                        })
+                        .and_then(|data| NvsValue::from_file(field(2), data))
+                }
+                ty => Err(Error::InvalidNvs(format!("unknown type `{}`", ty))),
+            };
+
+            let result = match &namespace {
+                Some(namespace) => {
+                    value.and_then(|value| partition.set(namespace, field(0), value))
+                }
+                None => Err(Error::InvalidNvs(
+                    "the first row has to start a namespace".into(),
+                )),
+            };
+            result.map_err(|e| match e {
+                Error::InvalidNvs(message) => {
+                    Error::InvalidNvs(format!("line {}: {}", line, message))
+                }
+                e => e,
+            })?;
+        }
+
+        Ok(partition)
+    }
+
+    /// Generate a partition of `size` bytes
+    ///
+    /// The last page is left empty, NVS needs a free page to move entries to.
+    pub fn to_bytes(&self, size: u32) -> Result<Vec<u8>, Error> {
+        if size as usize % PAGE_SIZE != 0 || (size as usize) < 3 * PAGE_SIZE {
+            return Err(Error::InvalidNvs(format!(
+                "the partition size {:#x} must be a multiple of 4 KiB and at least 0x3000",
+                size
+            )));
+        }
+
+        let mut writer = PageWriter::new(size);
+        let mut namespaces: Vec<&str> = Vec::new();
+
+        for entry in &self.entries {
+            let index = match namespaces.iter().position(|ns| *ns == entry.namespace) {
+                Some(index) => index + 1,
+                None => {
+                    if namespaces.len() == MAX_NAMESPACES {
+                        return Err(Error::InvalidNvs(format!(
+                            "more than {} namespaces",
+                            MAX_NAMESPACES
+                        )));
+                    }
+                    namespaces.push(&entry.namespace);
+                    let index = namespaces.len();
+                    writer.write(
+                        0,
+                        TYPE_U8,
+                        CHUNK_ANY,
+                        &entry.namespace,
+                        primitive_field(&[index as u8]),
+                        &[],
+                    )?;
+                    index
+                }
+            };
+
+            writer.write_value(index as u8, &entry.key, &entry.value)?;
+        }
+
+        Ok(writer.image)
+    }
+}
+
+/// Fills the pages of a partition one entry after another
+struct PageWriter {
+    image: Vec<u8>,
+    page: usize,
+    entry: usize,
+}
+
+impl PageWriter {
+    fn new(size: u32) -> Self {
+        let mut writer = PageWriter {
+            image: vec![0xFF; size as usize],
+            page: 0,
+            entry: 0,
+        };
+        writer.write_page_header(STATE_ACTIVE);
+
+        writer
+    }
+
+    fn write_page_header(&mut self, state: u32) {
+        let header = &mut self.image[self.page * PAGE_SIZE..][..HEADER_SIZE];
+        header[0..4].copy_from_slice(&state.to_le_bytes());
+        header[4..8].copy_from_slice(&(self.page as u32).to_le_bytes());
+        header[8] = VERSION;
+        let crc = crc32(&header[4..28]);
+        header[28..32].copy_from_slice(&crc.to_le_bytes());
+    }
+
+    fn next_page(&mut self) -> Result<(), Error> {
+        if self.page + 2 >= self.image.len() / PAGE_SIZE {
+            return Err(Error::NvsFull(self.image.len() as u32));
+        }
+
+        self.write_page_header(STATE_FULL);
+        self.page += 1;
+        self.entry = 0;
+        self.write_page_header(STATE_ACTIVE);
+
+        Ok(())
+    }
+
+    fn free_entries(&self) -> usize {
+        ENTRIES_PER_PAGE - self.entry
+    }
+
+    fn write_value(&mut self, ns: u8, key: &str, value: &NvsValue) -> Result<(), Error> {
+        match value {
+            NvsValue::String(string) => {
+                let mut data = string.as_bytes().to_vec();
+                data.push(0);
+                if data.len() > MAX_STRING_LEN {
+                    return Err(Error::InvalidNvs(format!(
+                        "the string `{}` is longer than {} bytes",
+                        key, MAX_STRING_LEN
+                    )));
+                }
+                self.write(ns, TYPE_STRING, CHUNK_ANY, key, data_field(&data), &data)
+            }
+            NvsValue::Blob(data) => self.write_blob(ns, key, data),
+            _ => {
+                let (ty, data) = value.primitive().unwrap();
+                self.write(ns, ty, CHUNK_ANY, key, primitive_field(&data), &[])
+            }
+        }
+    }
+
+    /// Write a blob in chunks filling the remaining entries of each page,
+    /// followed by the index entry listing them
+    fn write_blob(&mut self, ns: u8, key: &str, data: &[u8]) -> Result<(), Error> {
+        let mut chunks = 0;
+        let mut rest = data;
+
+        loop {
+            if self.free_entries() < 2 {
+                self.next_page()?;
+            }
+            if chunks == CHUNK_ANY {
+                return Err(Error::InvalidNvs(format!(
+                    "the blob `{}` is too large",
+                    key
+                )));
+            }
+
+            let len = rest.len().min((self.free_entries() - 1) * ENTRY_SIZE);
+            let (chunk, tail) = rest.split_at(len);
+            self.write(ns, TYPE_BLOB_DATA, chunks, key, data_field(chunk), chunk)?;
+            chunks += 1;
+
+            rest = tail;
+            if rest.is_empty() {
+                break;
+            }
+        }
+
+        // Size, number of chunks and index of the first chunk
+        let mut field = [0xFF; 8];
+        field[0..4].copy_from_slice(&(data.len() as u32).to_le_bytes());
+        field[4] = chunks;
+        field[5] = 0;
+
+        self.write(ns, TYPE_BLOB_INDEX, CHUNK_ANY, key, field, &[])
+    }
+
+    /// Write an entry followed by `data`, on the next page if the current one
+    /// can't hold them
+    fn write(
+        &mut self,
+        ns: u8,
+        ty: u8,
+        chunk: u8,
+        key: &str,
+        field: [u8; 8],
+        data: &[u8],
+    ) -> Result<(), Error> {
+        let span = 1 + (data.len() + ENTRY_SIZE - 1) / ENTRY_SIZE;
+        if span > self.free_entries() {
+            self.next_page()?;
+        }
+
+        let mut entry = [0xFF; ENTRY_SIZE];
+        entry[0] = ns;
+        entry[1] = ty;
+        entry[2] = span as u8;
+        entry[3] = chunk;
+        let name = &mut entry[8..24];
+        name.fill(0);
+        name[..key.len()].copy_from_slice(key.as_bytes());
+        entry[24..32].copy_from_slice(&field);
+
+        // The checksum covers everything but itself
+        let mut hasher = Hasher::new_with_initial(0xFFFF_FFFF);
+        hasher.update(&entry[0..4]);
+        hasher.update(&entry[8..32]);
+        entry[4..8].copy_from_slice(&hasher.finalize().to_le_bytes());
+
+        let page = self.page * PAGE_SIZE;
+        let offset = page + HEADER_SIZE + BITMAP_SIZE + self.entry * ENTRY_SIZE;
+        self.image[offset..][..ENTRY_SIZE].copy_from_slice(&entry);
+        self.image[offset + ENTRY_SIZE..][..data.len()].copy_from_slice(data);
+
+        // Each entry has two state bits, clearing the lower one marks it written
+        for i in self.entry..self.entry + span {
+            self.image[page + HEADER_SIZE + i / 4] &= !(1 << (i % 4 * 2));
+        }
+        self.entry += span;
+
+        Ok(())
+    }
+}
+
+fn crc32(data: &[u8]) -> u32 {
+    let mut hasher = Hasher::new_with_initial(0xFFFF_FFFF);
+    hasher.update(data);
+    hasher.finalize()
+}
+
+fn primitive_field(data: &[u8]) -> [u8; 8] {
+    let mut field = [0xFF; 8];
+    field[..data.len()].copy_from_slice(data);
+    field
+}
+
+/// Size and checksum of the data of a string or blob chunk
+fn data_field(data: &[u8]) -> [u8; 8] {
+    let mut field = [0xFF; 8];
+    field[0..2].copy_from_slice(&(data.len() as u16).to_le_bytes());
+    field[4..8].copy_from_slice(&crc32(data).to_le_bytes());
+    field
+}
+
+/// Parse a decimal or hexadecimal integer
+fn parse_int<T: TryFrom<i128>>(value: &str) -> Option<T> {
+    let (negative, digits) = match value.strip_prefix('-') {
+        Some(digits) => (true, digits),
+        None => (false, value),
+    };
+    let magnitude = match digits
+        .strip_prefix("0x")
+        .or_else(|| digits.strip_prefix("0X"))
+    {
+        Some(hex) => i128::from_str_radix(hex, 16).ok()?,
+        None => digits.parse::<i128>().ok()?,
+    };
+
+    T::try_from(if negative { -magnitude } else { magnitude }).ok()
+}
+
+fn decode_hex(text: &str) -> Option<Vec<u8>> {
+    let text = text.trim();
+    if text.len() % 2 != 0 {
+        return None;
+    }
+
+    (0..text.len())
+        .step_by(2)
+        .map(|i| u8::from_str_radix(text.get(i..i + 2)?, 16).ok())
+        .collect()
+}
+
+fn decode_base64(text: &str) -> Option<Vec<u8>> {
+    let mut data = Vec::new();
+    let mut bits = 0u32;
+    let mut count = 0;
+
+    for c in text.bytes().filter(|c| !c.is_ascii_whitespace()) {
+        let value = match c {
+            b'A'..=b'Z' => c - b'A',
+            b'a'..=b'z' => c - b'a' + 26,
+            b'0'..=b'9' => c - b'0' + 52,
+            b'+' => 62,
+            b'/' => 63,
+            b'=' => break,
+            _ => return None,
+        };
+        bits = (bits << 6) | value as u32;
+        count += 6;
+        if count >= 8 {
+            count -= 8;
+            data.push((bits >> count) as u8);
+            bits &= (1 << count) - 1;
+        }
+    }
+
+    Some(data)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn entries_are_written_to_pages() {
+        let csv = "key,type,encoding,value\n\
+                   wifi,namespace,,\n\
+                   ssid,data,string,espflash\n\
+                   retries,data,u8,3\n\
+                   key,data,base64,3q2+7w==\n";
+        let partition = NvsPartition::try_from_csv(csv.as_bytes(), Path::new(".")).unwrap();
+        assert_eq!(
+            partition.entries()[2].value,
+            NvsValue::Blob(vec![0xde, 0xad, 0xbe, 0xef])
+        );
+
+        let image = partition.to_bytes(0x3000).unwrap();
+        assert_eq!(image.len(), 0x3000);
+
+        // Active page 0 of version 2
+        assert_eq!(&image[0..9], &[0xfe, 0xff, 0xff, 0xff, 0, 0, 0, 0, 0xfe]);
+        assert_eq!(image[28..32], crc32(&image[4..28]).to_le_bytes());
+
+        // Namespace, string spanning two entries, u8, blob chunk and index
+        assert_eq!(&image[32..35], &[0xaa, 0xea, 0xff]);
+
+        let entry = |i: usize| &image[64 + i * ENTRY_SIZE..][..ENTRY_SIZE];
+        assert_eq!(&entry(0)[..4], &[0, TYPE_U8, 1, CHUNK_ANY]);
+        assert_eq!(&entry(0)[8..13], b"wifi\0");
+        assert_eq!(entry(0)[24], 1);
+
+        assert_eq!(&entry(1)[..4], &[1, TYPE_STRING, 2, CHUNK_ANY]);
+        assert_eq!(&entry(1)[24..26], &[9, 0]);
+        assert_eq!(&entry(2)[..9], b"espflash\0");
+
+        assert_eq!(&entry(3)[..4], &[1, TYPE_U8, 1, CHUNK_ANY]);
+        assert_eq!(&entry(3)[24..26], &[3, 0xff]);
+
+        assert_eq!(&entry(4)[..4], &[1, TYPE_BLOB_DATA, 2, 0]);
+        assert_eq!(&entry(6)[..4], &[1, TYPE_BLOB_INDEX, 1, CHUNK_ANY]);
+        assert_eq!(&entry(6)[24..30], &[4, 0, 0, 0, 1, 0]);
+
+        let mut hasher = Hasher::new_with_initial(0xFFFF_FFFF);
+        hasher.update(&entry(3)[0..4]);
+        hasher.update(&entry(3)[8..32]);
+        assert_eq!(entry(3)[4..8], hasher.finalize().to_le_bytes());
+    }
+
+    #[test]
+    fn invalid_definitions_are_rejected() {
+        let mut partition = NvsPartition::new();
+        assert!(partition
+            .set("wifi", "a_key_that_is_too_long", NvsValue::U8(0))
+            .is_err());
+        assert!(NvsValue::parse("u8", "256").is_err());
+        assert!(NvsValue::parse("hex2bin", "abc").is_err());
+        assert!(NvsPartition::try_from_csv(b"ssid,data,string,espflash", Path::new(".")).is_err());
+
+        partition
+            .set("certs", "ca", NvsValue::Blob(vec![0; 0x3000]))
+            .unwrap();
+        assert!(matches!(
+            partition.to_bytes(0x3000),
+            Err(Error::NvsFull(0x3000))
+        ));
+    }
+}
@@ -6,30 +6,61 @@ use std::{
 };
 
 use md5::{Context, Digest};
-use regex::Regex;
 use serde::{Deserialize, Deserializer, Serialize};
 use strum::IntoEnumIterator;
 use strum_macros::EnumIter;
 
 use crate::error::{
-    CSVError, DuplicatePartitionsError, InvalidSubTypeError, NoAppError,
-    OverlappingPartitionsError, PartitionTableError, UnalignedPartitionError,
+    AppPartitionTooSmallError, CSVError, DuplicatePartitionsError, Error, InvalidSubTypeError,
+    NoAppError, OverlappingPartitionsError, PartitionOverflowError, PartitionTableError,
+    ReservedOffsetError, TooManyPartitionsError, UnalignedPartitionError,
 };
 
 const MAX_PARTITION_LENGTH: usize = 0xC00;
 const PARTITION_TABLE_SIZE: usize = 0x1000;
 const PARTITION_SIZE: usize = 32;
 const PARTITION_ALIGNMENT: u32 = 0x10000;
-
-#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
-#[repr(u8)]
-#[serde(rename_all = "lowercase")]
+const DATA_PARTITION_ALIGNMENT: u32 = 0x1000;
+/// Offset of the partition table unless it's moved, the bootloader and the
+/// partition table occupy the flash before its end
+pub(crate) const PARTITION_TABLE_OFFSET: u32 = 0x8000;
+const MIN_APP_PARTITION_SIZE: u32 = 0x10000;
+const MAX_PARTITIONS: usize = (MAX_PARTITION_LENGTH - PARTITION_SIZE) / PARTITION_SIZE;
+/// Magic starting every entry of binary partition tables
+pub(crate) const PARTITION_MAGIC: [u8; 2] = [0xAA, 0x50];
+const OTA_SELECT_ENTRY_SIZE: usize = 32;
+const MD5_MAGIC: [u8; 2] = [0xEB, 0xEB];
+pub(crate) const UNKNOWN_SUB_TYPE: &str = "unknown sub-type";
+
+/// Custom partition types have to be in this range, the others are reserved
+const CUSTOM_TYPES: std::ops::RangeInclusive<u8> = 0x40..=0xFE;
+
+#[derive(Copy, Clone, Debug, PartialEq)]
 pub enum Type {
-    App = 0x00,
-    Data = 0x01,
+    App,
+    Data,
+    /// Application specific partition type in the range 0x40 to 0xFE
+    Custom(u8),
 }
 
 impl Type {
+    fn from_u8(value: u8) -> Option<Self> {
+        match value {
+            0x00 => Some(Type::App),
+            0x01 => Some(Type::Data),
+            value if CUSTOM_TYPES.contains(&value) => Some(Type::Custom(value)),
+            _ => None,
+        }
+    }
+
+    pub fn as_u8(&self) -> u8 {
+        match self {
+            Type::App => 0x00,
+            Type::Data => 0x01,
+            Type::Custom(value) => *value,
+        }
+    }
+
     pub fn subtype_hint(&self) -> String {
         match self {
             Type::App => "'factory', 'ota_0' through 'ota_15', and 'test'".into(),
@@ -43,17 +74,40 @@ impl Type {
 
                 out
             }
+            Type::Custom(_) => "numeric sub-types such as 0x00".into(),
         }
     }
 }
 
 impl Display for Type {
     fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
-        write!(f, "{}", serde_plain::to_string(self).unwrap())
+        match self {
+            Type::App => write!(f, "app"),
+            Type::Data => write!(f, "data"),
+            Type::Custom(value) => write!(f, "{:#04x}", value),
+        }
+    }
+}
+
+impl<'de> Deserialize<'de> for Type {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let buf = String::deserialize(deserializer)?;
+        match buf.as_str() {
+            "app" => Ok(Type::App),
+            "data" => Ok(Type::Data),
+            _ => parse_u8(&buf).and_then(Type::from_u8).ok_or_else(|| {
+                Error::custom("unknown type, custom types have to be between 0x40 and 0xfe")
+            }),
+        }
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
+#[derive(Copy, Clone, Debug, Deserialize, EnumIter, Serialize, PartialEq)]
 #[repr(u8)]
 pub enum AppType {
     #[serde(rename = "factory")]
@@ -108,19 +162,22 @@ pub enum DataType {
     EspHttpd = 0x80,
     Fat = 0x81,
     Spiffs = 0x82,
+    LittleFs = 0x83,
 }
 
 impl DataType {
     fn is_multiple_allowed(self) -> bool {
-        matches!(self, Self::Fat | Self::Spiffs)
+        matches!(self, Self::Fat | Self::Spiffs | Self::LittleFs)
     }
 }
 
-#[derive(Debug, Deserialize, PartialEq, Copy, Clone)]
-#[serde(untagged)]
+#[derive(Debug, PartialEq, Copy, Clone)]
 pub enum SubType {
     App(AppType),
     Data(DataType),
+    /// A sub-type given as a number which isn't one of the known app or data
+    /// sub-types, as used by custom partition types
+    Custom(u8),
 }
 
 impl Display for SubType {
@@ -128,6 +185,7 @@ impl Display for SubType {
         let ser = match self {
             SubType::App(sub) => serde_plain::to_string(sub),
             SubType::Data(sub) => serde_plain::to_string(sub),
+            SubType::Custom(value) => return write!(f, "{:#04x}", value),
         }
         .unwrap();
 
@@ -135,11 +193,48 @@ impl Display for SubType {
     }
 }
 
+impl<'de> Deserialize<'de> for SubType {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let buf = String::deserialize(deserializer)?;
+        if let Ok(sub_type) = serde_plain::from_str(&buf) {
+            Ok(SubType::App(sub_type))
+        } else if let Ok(sub_type) = serde_plain::from_str(&buf) {
+            Ok(SubType::Data(sub_type))
+        } else {
+            parse_u8(&buf)
+                .map(SubType::Custom)
+                .ok_or_else(|| Error::custom(UNKNOWN_SUB_TYPE))
+        }
+    }
+}
+
 impl SubType {
-    fn as_u8(&self) -> u8 {
+    /// The sub-type `value` of the partition type `ty`, numbers which aren't a
+    /// known sub-type are kept as [`SubType::Custom`]
+    pub fn from_u8(ty: Type, value: u8) -> Self {
+        let known = match ty {
+            Type::App => AppType::iter()
+                .find(|sub| *sub as u8 == value)
+                .map(SubType::App),
+            Type::Data => DataType::iter()
+                .find(|sub| *sub as u8 == value)
+                .map(SubType::Data),
+            Type::Custom(_) => None,
+        };
+
+        known.unwrap_or(SubType::Custom(value))
+    }
+
+    pub fn as_u8(&self) -> u8 {
         match self {
             SubType::App(ty) => *ty as u8,
             SubType::Data(ty) => *ty as u8,
+            SubType::Custom(value) => *value,
         }
     }
 
@@ -147,23 +242,101 @@ impl SubType {
         match self {
             SubType::App(_) => false,
             SubType::Data(ty) => ty.is_multiple_allowed(),
+            SubType::Custom(_) => true,
         }
     }
 }
 
-#[derive(Copy, Clone, Debug, Deserialize, Serialize, PartialEq)]
-#[serde(rename_all = "lowercase")]
-pub enum Flags {
-    Encrypted = 0x1,
-}
+/// Partition flags, a set of the known flags and any unknown bits read from a
+/// binary partition table
+#[derive(Copy, Clone, Debug, Default, PartialEq)]
+pub struct Flags(u32);
 
 impl Flags {
-    pub fn as_u32(&self) -> u32 {
-        *self as u32
+    pub const ENCRYPTED: Flags = Flags(0x1);
+    pub const READONLY: Flags = Flags(0x2);
+
+    const NAMES: [(Flags, &'static str); 2] = [
+        (Flags::ENCRYPTED, "encrypted"),
+        (Flags::READONLY, "readonly"),
+    ];
+
+    pub fn from_bits(bits: u32) -> Self {
+        Flags(bits)
+    }
+
+    pub fn bits(&self) -> u32 {
+        self.0
+    }
+
+    pub fn is_empty(&self) -> bool {
+        self.0 == 0
+    }
+
+    pub fn contains(&self, other: Flags) -> bool {
+        self.0 & other.0 == other.0
+    }
+}
+
+impl std::ops::BitOr for Flags {
+    type Output = Flags;
+
+    fn bitor(self, other: Flags) -> Flags {
+        Flags(self.0 | other.0)
+    }
+}
+
+/// Flags are separated by colons like in `gen_esp32part.py`, unknown bits are
+/// written as a number
+impl Display for Flags {
+    fn fmt(&self, f: &mut Formatter) -> std::fmt::Result {
+        let mut names = Vec::new();
+        let mut unknown = self.0;
+        for (flag, name) in &Self::NAMES {
+            if self.contains(*flag) {
+                names.push(name.to_string());
+                unknown &= !flag.0;
+            }
+        }
+        if unknown != 0 {
+            names.push(format!("{:#x}", unknown));
+        }
+
+        write!(f, "{}", names.join(":"))
     }
 }
 
-#[derive(Debug)]
+impl<'de> Deserialize<'de> for Flags {
+    fn deserialize<D>(deserializer: D) -> Result<Self, D::Error>
+    where
+        D: Deserializer<'de>,
+    {
+        use serde::de::Error;
+
+        let buf = String::deserialize(deserializer)?;
+        buf.split(':')
+            .map(str::trim)
+            .filter(|flag| !flag.is_empty())
+            .try_fold(Flags::default(), |flags, flag| {
+                let known = Self::NAMES
+                    .iter()
+                    .find(|(_, name)| *name == flag)
+                    .map(|(flag, _)| *flag);
+                let flag = match known {
+                    Some(flag) => flag,
+                    None => flag
+                        .strip_prefix("0x")
+                        .and_then(|bits| u32::from_str_radix(bits, 16).ok())
+                        .map(Flags)
+                        .ok_or_else(|| Error::custom("unknown partition flag"))?,
+                };
+
+                Ok(flags | flag)
+            })
+    }
+}
+
+#[derive(Clone, Debug)]
 pub struct PartitionTable {
     partitions: Vec<Partition>,
 }
@@ -186,21 +359,21 @@ impl PartitionTable {
                     SubType::Data(DataType::Nvs),
                     nvs_offset,
                     nvs_size,
-                    None,
+                    Flags::default(),
                 ),
                 Partition::new(
                     String::from("phy_init"),
                     SubType::Data(DataType::Phy),
                     phy_init_data_offset,
                     phy_init_data_size,
-                    None,
+                    Flags::default(),
                 ),
                 Partition::new(
                     String::from("factory"),
                     SubType::App(AppType::Factory),
                     app_offset,
                     app_size,
-                    None,
+                    Flags::default(),
                 ),
             ],
         }
@@ -213,15 +386,27 @@ impl PartitionTable {
     where
         S: Into<String>,
     {
-        let data = data.into();
+        Self::try_from_str_at(data, PARTITION_TABLE_OFFSET)
+    }
+
+    /// Attempt to parse a partition table from the given string, for a table
+    /// placed at `table_offset` instead of the default offset
+    ///
+    /// Partitions without an offset then follow the table, and no partition
+    /// may start before its end.
+    pub fn try_from_str_at<S>(data: S, table_offset: u32) -> Result<Self, PartitionTableError>
+    where
+        S: Into<String>,
+    {
+        let data = data.into().trim().to_string();
         let mut reader = csv::ReaderBuilder::new()
             .comment(Some(b'#'))
             .has_headers(false)
             .trim(csv::Trim::All)
-            .from_reader(data.trim().as_bytes());
+            .from_reader(data.as_bytes());
 
-        // Default offset is 0x8000 in esp-idf, partition table size is 0x1000
-        let mut offset = 0x9000;
+        let first_offset = first_partition_offset(table_offset);
+        let mut offset = first_offset;
         let mut partitions = Vec::with_capacity(data.lines().count());
 
         for record in reader.records() {
@@ -240,7 +425,74 @@ impl PartitionTable {
         }
 
         let table = Self { partitions };
-        table.validate(&data)?;
+        table.validate(&data, first_offset)?;
+
+        Ok(table)
+    }
+
+    /// Attempt to parse a partition table from CSV data which may not be valid
+    /// UTF-8, invalid sequences are replaced before parsing
+    ///
+    /// Never panics, making it suitable for untrusted input.
+    pub fn try_from_csv(data: &[u8]) -> Result<Self, PartitionTableError> {
+        Self::try_from_str(String::from_utf8_lossy(data))
+    }
+
+    /// Attempt to parse a partition table in either the binary format, as
+    /// generated by `gen_esp32part.py`, or the CSV format, which is detected
+    /// from the data
+    pub fn try_from_bytes(data: &[u8]) -> Result<Self, PartitionTableError> {
+        Self::try_from_bytes_at(data, PARTITION_TABLE_OFFSET)
+    }
+
+    /// Attempt to parse a partition table in either format, for a table placed
+    /// at `table_offset`, see [`PartitionTable::try_from_str_at`]
+    pub fn try_from_bytes_at(data: &[u8], table_offset: u32) -> Result<Self, PartitionTableError> {
+        if data.starts_with(&PARTITION_MAGIC) {
+            Self::try_from_binary(data, table_offset)
+        } else {
+            Self::try_from_str_at(String::from_utf8_lossy(data), table_offset)
+        }
+    }
+
+    fn try_from_binary(data: &[u8], table_offset: u32) -> Result<Self, PartitionTableError> {
+        let invalid = |message: String| PartitionTableError::InvalidBinary(message);
+
+        let mut partitions = Vec::new();
+        let mut hasher = Context::new();
+
+        for (i, entry) in data.chunks(PARTITION_SIZE).enumerate() {
+            let offset = i * PARTITION_SIZE;
+            if entry.len() < PARTITION_SIZE {
+                return Err(invalid(format!("truncated entry at {:#x}", offset)));
+            }
+
+            match [entry[0], entry[1]] {
+                PARTITION_MAGIC => {
+                    if partitions.len() == MAX_PARTITIONS {
+                        return Err(invalid(format!("more than {} partitions", MAX_PARTITIONS)));
+                    }
+                    partitions.push(Partition::from_bytes(entry).map_err(invalid)?);
+                    hasher.consume(entry);
+                }
+                MD5_MAGIC => {
+                    if entry[16..] != hasher.compute().0 {
+                        return Err(invalid("checksum mismatch".into()));
+                    }
+                    break;
+                }
+                [0xFF, 0xFF] => break,
+                _ => return Err(invalid(format!("invalid entry at {:#x}", offset))),
+            }
+        }
+
+        // Validated like CSV tables, the errors point at the table converted to CSV,
+        // where the entries start on the third line
+        for (i, partition) in partitions.iter_mut().enumerate() {
+            partition.line = Some(i + 3);
+        }
+        let table = Self { partitions };
+        table.validate(&table.to_csv(), first_partition_offset(table_offset))?;
 
         Ok(table)
     }
@@ -270,13 +522,70 @@ impl PartitionTable {
         writer.write_all(&hash.0)?;
 
         let written = self.partitions.len() * PARTITION_SIZE + 32;
-        for _ in 0..(MAX_PARTITION_LENGTH - written) {
+        for _ in 0..MAX_PARTITION_LENGTH.saturating_sub(written) {
             writer.write_all(&[0xFF])?;
         }
 
         Ok(())
     }
 
+    /// Format the partition table as CSV, in the format accepted by
+    /// [`PartitionTable::try_from_str`]
+    pub fn to_csv(&self) -> String {
+        let mut csv =
+            String::from("# ESP-IDF Partition Table\n# Name, Type, SubType, Offset, Size, Flags\n");
+        for p in &self.partitions {
+            writeln!(
+                csv,
+                "{},{},{},{:#x},{},{}",
+                p.name,
+                p.ty,
+                p.sub_type,
+                p.offset,
+                format_size(p.size),
+                p.flags
+            )
+            .unwrap();
+        }
+
+        csv
+    }
+
+    /// Contents of the `otadata` partition which make the bootloader start the
+    /// app partition `app`, along with the offset of the `otadata` partition
+    ///
+    /// The whole partition is rewritten, so any OTA state is discarded. If `app`
+    /// isn't an OTA partition the bootloader falls back to the factory app.
+    pub fn ota_data(&self, app: &str) -> Result<(u32, Vec<u8>), Error> {
+        let otadata = self
+            .partitions
+            .iter()
+            .find(|p| p.sub_type == SubType::Data(DataType::Ota))
+            .ok_or(Error::MissingOtaDataPartition)?;
+        let app = self
+            .find(app)
+            .filter(|p| p.ty == Type::App)
+            .ok_or_else(|| Error::MissingAppPartition(app.into()))?;
+
+        let mut data = vec![0xFF; otadata.size as usize];
+
+        if let SubType::App(sub_type) = app.sub_type {
+            let slot = (sub_type as u8).wrapping_sub(AppType::Ota0 as u8);
+            if slot < 16 && data.len() >= OTA_SELECT_ENTRY_SIZE {
+                // The bootloader starts slot `(seq - 1) % ota partition count`
+                let seq = slot as u32 + 1;
+                data[..4].copy_from_slice(&seq.to_le_bytes());
+                data[28..32].copy_from_slice(&ota_seq_crc(seq).to_le_bytes());
+            }
+        }
+
+        Ok((otadata.offset, data))
+    }
+
+    pub fn partitions(&self) -> &[Partition] {
+        &self.partitions
+    }
+
     pub fn find(&self, name: &str) -> Option<&Partition> {
         self.partitions.iter().find(|&p| p.name == name)
     }
@@ -285,15 +594,43 @@ impl PartitionTable {
         self.partitions.iter().find(|&p| p.ty == ty)
     }
 
-    fn validate(&self, source: &str) -> Result<(), PartitionTableError> {
+    pub fn find_by_subtype(&self, ty: Type, sub_type: SubType) -> Option<&Partition> {
+        self.partitions
+            .iter()
+            .find(|&p| p.ty == ty && p.sub_type == sub_type)
+    }
+
+    /// The partition to write the application to, the app partition `name` if
+    /// given, otherwise the `factory` partition or the first app partition
+    pub fn find_app(&self, name: Option<&str>) -> Option<&Partition> {
+        match name {
+            Some(name) => self.find(name).filter(|p| p.ty == Type::App),
+            None => self
+                .find("factory")
+                .or_else(|| self.find_by_type(Type::App)),
+        }
+    }
+
+    fn validate(&self, source: &str, first_offset: u32) -> Result<(), PartitionTableError> {
+        if self.partitions.len() > MAX_PARTITIONS {
+            return Err(
+                TooManyPartitionsError::new(source, self.partitions.len(), MAX_PARTITIONS).into(),
+            );
+        }
+
         for partition in &self.partitions {
             if let Some(line) = &partition.line {
+                if partition.offset.checked_add(partition.size).is_none() {
+                    return Err(PartitionOverflowError::new(source, *line).into());
+                }
+
                 let expected_type = match partition.sub_type {
-                    SubType::App(_) => Type::App,
-                    SubType::Data(_) => Type::Data,
+                    SubType::App(_) => Some(Type::App),
+                    SubType::Data(_) => Some(Type::Data),
+                    SubType::Custom(_) => None,
                 };
 
-                if expected_type != partition.ty {
+                if expected_type.map_or(false, |ty| ty != partition.ty) {
                     return Err(InvalidSubTypeError::new(
                         source,
                         *line,
@@ -303,8 +640,36 @@ impl PartitionTable {
                     .into());
                 }
 
-                if partition.ty == Type::App && partition.offset.rem(PARTITION_ALIGNMENT) != 0 {
-                    return Err(UnalignedPartitionError::new(source, *line).into());
+                let alignment = DeserializedPartition::alignment(partition.ty);
+                if partition.offset.rem(alignment) != 0 {
+                    return Err(UnalignedPartitionError::new(
+                        source,
+                        *line,
+                        partition.ty,
+                        partition.offset,
+                        alignment,
+                    )
+                    .into());
+                }
+
+                if partition.offset < first_offset {
+                    return Err(ReservedOffsetError::new(
+                        source,
+                        *line,
+                        partition.offset,
+                        first_offset,
+                    )
+                    .into());
+                }
+
+                if partition.ty == Type::App && partition.size < MIN_APP_PARTITION_SIZE {
+                    return Err(AppPartitionTooSmallError::new(
+                        source,
+                        *line,
+                        partition.size,
+                        MIN_APP_PARTITION_SIZE,
+                    )
+                    .into());
                 }
             }
         }
@@ -361,14 +726,18 @@ pub struct DeserializedPartition {
 }
 
 impl DeserializedPartition {
-    fn align(offset: u32, ty: Type) -> u32 {
-        let pad = match ty {
+    fn alignment(ty: Type) -> u32 {
+        match ty {
             Type::App => PARTITION_ALIGNMENT,
-            Type::Data => 4,
-        };
+            Type::Data | Type::Custom(_) => DATA_PARTITION_ALIGNMENT,
+        }
+    }
+
+    fn align(offset: u32, ty: Type) -> u32 {
+        let pad = Self::alignment(ty);
 
         if offset % pad != 0 {
-            offset + pad - (offset % pad)
+            offset.saturating_add(pad - (offset % pad))
         } else {
             offset
         }
@@ -379,35 +748,46 @@ impl DeserializedPartition {
             self.offset = Some(Self::align(*offset, self.ty));
         }
 
-        *offset = self.offset.unwrap() + self.size;
+        // Overflowing partitions are rejected during validation
+        *offset = self.offset.unwrap().saturating_add(self.size);
     }
 }
 
-#[derive(Debug)]
+#[derive(Clone, Debug)]
 pub struct Partition {
     name: String,
     ty: Type,
     sub_type: SubType,
     offset: u32,
     size: u32,
-    flags: Option<Flags>,
+    flags: Flags,
     line: Option<usize>,
 }
 
 impl Partition {
-    pub fn new(
+    /// Create a partition of the type matching `sub_type`, partitions with a
+    /// [`SubType::Custom`] sub-type are data partitions, see
+    /// [`Partition::with_type`] for other types
+    pub fn new(name: String, sub_type: SubType, offset: u32, size: u32, flags: Flags) -> Self {
+        let ty = match sub_type {
+            SubType::App(_) => Type::App,
+            SubType::Data(_) | SubType::Custom(_) => Type::Data,
+        };
+
+        Self::with_type(name, ty, sub_type, offset, size, flags)
+    }
+
+    pub fn with_type(
         name: String,
+        ty: Type,
         sub_type: SubType,
         offset: u32,
         size: u32,
-        flags: Option<Flags>,
+        flags: Flags,
     ) -> Self {
         Partition {
             name,
-            ty: match sub_type {
-                SubType::App(_) => Type::App,
-                SubType::Data(_) => Type::Data,
-            },
+            ty,
             sub_type,
             offset,
             size,
@@ -420,8 +800,8 @@ impl Partition {
     where
         W: Write,
     {
-        writer.write_all(&[0xAA, 0x50])?;
-        writer.write_all(&[self.ty as u8, self.sub_type.as_u8()])?;
+        writer.write_all(&PARTITION_MAGIC)?;
+        writer.write_all(&[self.ty.as_u8(), self.sub_type.as_u8()])?;
         writer.write_all(&self.offset.to_le_bytes())?;
         writer.write_all(&self.size.to_le_bytes())?;
 
@@ -431,25 +811,70 @@ impl Partition {
         }
         writer.write_all(&name_bytes)?;
 
-        let flags = match &self.flags {
-            Some(f) => f.as_u32().to_le_bytes(),
-            None => 0u32.to_le_bytes(),
-        };
-        writer.write_all(&flags)?;
+        writer.write_all(&self.flags.bits().to_le_bytes())?;
 
         Ok(())
     }
 
+    fn from_bytes(entry: &[u8]) -> Result<Self, String> {
+        let word = |offset: usize| {
+            u32::from_le_bytes([
+                entry[offset],
+                entry[offset + 1],
+                entry[offset + 2],
+                entry[offset + 3],
+            ])
+        };
+
+        let name = &entry[12..28];
+        let name = &name[..name.iter().position(|&b| b == 0).unwrap_or(name.len())];
+        let name = String::from_utf8_lossy(name).into_owned();
+
+        let ty = Type::from_u8(entry[2])
+            .ok_or_else(|| format!("partition `{}` has invalid type {:#x}", name, entry[2]))?;
+        let sub_type = SubType::from_u8(ty, entry[3]);
+        let flags = Flags::from_bits(word(28));
+
+        Ok(Partition::with_type(
+            name,
+            ty,
+            sub_type,
+            word(4),
+            word(8),
+            flags,
+        ))
+    }
+
+    pub fn name(&self) -> &str {
+        &self.name
+    }
+
+    pub fn ty(&self) -> Type {
+        self.ty
+    }
+
+    pub fn sub_type(&self) -> SubType {
+        self.sub_type
+    }
+
     pub fn offset(&self) -> u32 {
         self.offset
     }
 
-    pub fn flags(&self) -> Option<Flags> {
+    pub fn size(&self) -> u32 {
+        self.size
+    }
+
+    pub fn flags(&self) -> Flags {
         self.flags
     }
 
+    fn end(&self) -> u64 {
+        self.offset as u64 + self.size as u64
+    }
+
     fn overlaps(&self, other: &Partition) -> bool {
-        max(self.offset, other.offset) < min(self.offset + self.size, other.offset + other.size)
+        max(self.offset as u64, other.offset as u64) < min(self.end(), other.end())
     }
 }
 
@@ -458,15 +883,55 @@ impl From<DeserializedPartition> for Partition {
         Partition {
             name: p.name,
             ty: p.ty,
-            sub_type: p.sub_type,
+            // Known sub-types may also be given as numbers
+            sub_type: match p.sub_type {
+                SubType::Custom(value) => SubType::from_u8(p.ty, value),
+                sub_type => sub_type,
+            },
             offset: p.offset.unwrap(),
             size: p.size,
-            flags: p.flags,
+            flags: p.flags.unwrap_or_default(),
             line: None,
         }
     }
 }
 
+/// The CRC stored in the `otadata` entries, the CRC32 of the sequence number as
+/// calculated by the ROM function `crc32_le(UINT32_MAX, ...)`
+fn ota_seq_crc(seq: u32) -> u32 {
+    let mut crc = 0u32;
+    for byte in seq.to_le_bytes() {
+        crc ^= byte as u32;
+        for _ in 0..8 {
+            crc = if crc & 1 != 0 {
+                (crc >> 1) ^ 0xEDB88320
+            } else {
+                crc >> 1
+            };
+        }
+    }
+    !crc
+}
+
+/// Format a size the way `gen_esp32part.py` does, using a `K` or `M` suffix if
+/// possible
+pub(crate) fn format_size(size: u32) -> String {
+    if size != 0 && size % (1024 * 1024) == 0 {
+        format!("{}M", size / (1024 * 1024))
+    } else if size != 0 && size % 1024 == 0 {
+        format!("{}K", size / 1024)
+    } else {
+        format!("{:#x}", size)
+    }
+}
+
+fn parse_u8(value: &str) -> Option<u8> {
+    match value.strip_prefix("0x") {
+        Some(hex) => u8::from_str_radix(hex, 16).ok(),
+        None => value.parse().ok(),
+    }
+}
+
 fn deserialize_partition_name<'de, D>(deserializer: D) -> Result<String, D::Error>
 where
     D: Deserializer<'de>,
@@ -491,36 +956,50 @@ where
     use serde::de::Error;
 
     let buf = String::deserialize(deserializer)?;
-    let re = Regex::new(r"(?i)^(\d+)([km]{1})$").unwrap();
 
     // NOTE: Partitions of type 'app' must be placed at offsets aligned to 0x10000
     //       (64K).
     if buf.trim().is_empty() {
         Ok(None)
-    } else if buf.starts_with("0x") {
-        // Hexadecimal format
-        let src = buf.trim_start_matches("0x");
-        let size = u32::from_str_radix(src, 16).unwrap();
-
-        Ok(Some(size))
-    } else if let Ok(size) = buf.parse::<u32>() {
-        // Decimal format
-        Ok(Some(size))
-    } else if let Some(captures) = re.captures(&buf) {
-        // Size multiplier format (1k, 2M, etc.)
-        let digits = captures.get(1).unwrap().as_str().parse::<u32>().unwrap();
-        let multiplier = match captures.get(2).unwrap().as_str() {
-            "k" | "K" => 1024,
-            "m" | "M" => 1024 * 1024,
-            _ => unreachable!(),
-        };
-
-        Ok(Some(digits * multiplier))
     } else {
-        Err(Error::custom("invalid partition size/offset format"))
+        parse_u32(buf.trim())
+            .map(Some)
+            .map_err(|err| Error::custom(format!("invalid partition size/offset: {}", err)))
     }
 }
 
+/// The first offset after the bootloader and the partition table at
+/// `table_offset`
+fn first_partition_offset(table_offset: u32) -> u32 {
+    table_offset.saturating_add(PARTITION_TABLE_SIZE as u32)
+}
+
+/// Parse a number given in decimal or in hexadecimal with a `0x` prefix,
+/// optionally followed by a `k` or `M` multiplier, like the offsets and sizes
+/// of partition tables
+pub fn parse_u32(input: &str) -> Result<u32, String> {
+    let (number, multiplier) = match input.strip_suffix(|c| c == 'k' || c == 'K') {
+        Some(number) => (number, 1024),
+        None => match input.strip_suffix(|c| c == 'm' || c == 'M') {
+            Some(number) => (number, 1024 * 1024),
+            None => (input, 1),
+        },
+    };
+
+    let value = match number
+        .strip_prefix("0x")
+        .or_else(|| number.strip_prefix("0X"))
+    {
+        Some(hex) => u32::from_str_radix(hex, 16),
+        None => number.parse::<u32>(),
+    }
+    .map_err(|_| format!("`{}` is not a valid number", input))?;
+
+    value
+        .checked_mul(multiplier)
+        .ok_or_else(|| format!("`{}` is too large", input))
+}
+
 fn deserialize_partition_offset<'de, D>(deserializer: D) -> Result<Option<u32>, D::Error>
 where
     D: Deserializer<'de>,
@@ -621,6 +1100,8 @@ phy_init, data, phy,     0xf000,  0x1000,
 factory,  app,  factory, 0x10000,  1M,
 a,        data,  spiffs, 0x110000, 1M,
 b,        data,  spiffs, 0x210000, 1M,
+c,        data,  littlefs, 0x310000, 1M,
+d,        data,  littlefs, 0x410000, 1M,
 ";
 
     const PTABLE_NO_FACTORY: &str = "
@@ -676,14 +1157,18 @@ phy_init, data, phy,     0xf000,  0x1000,
         let pt0 = pt0.unwrap();
         let nvs = pt0.find("nvs").unwrap();
         let fac = pt0.find("factory").unwrap();
-        assert_eq!(nvs.flags(), None);
-        assert_eq!(fac.flags(), Some(Flags::Encrypted));
+        assert_eq!(nvs.flags(), Flags::default());
+        assert_eq!(fac.flags(), Flags::ENCRYPTED);
 
         let pt1 = PartitionTable::try_from_str(PTABLE_1);
         assert!(pt1.is_ok());
 
         let pt_spiffs = PartitionTable::try_from_str(PTABLE_SPIFFS);
         assert!(pt_spiffs.is_ok());
+        assert_eq!(
+            pt_spiffs.unwrap().find("c").unwrap().sub_type(),
+            SubType::Data(DataType::LittleFs)
+        );
 
         PartitionTable::try_from_str(PTABLE_NO_FACTORY)
             .expect("Failed to parse partition table without factory partition");
@@ -692,6 +1177,177 @@ phy_init, data, phy,     0xf000,  0x1000,
             .expect_err("Failed to reject partition table without factory or ota partition");
     }
 
+    #[test]
+    fn malformed_input_is_rejected() {
+        for data in [
+            "factory, app, factory, 0xZZ, 1M",
+            "factory, app, factory, 0x10000, 99999999999k",
+            "factory, app, factory, 0x10000, 8192M",
+            "factory, app, factory, 0xfff00000, 0x200000",
+            "factory, app",
+        ] {
+            PartitionTable::try_from_str(data).expect_err(data);
+        }
+
+        PartitionTable::try_from_csv(b"factory, app, factory, 0x10000, 1M, \xff")
+            .expect_err("Failed to reject invalid flags");
+
+        let too_many = (0..100)
+            .map(|i| {
+                format!(
+                    "p{}, data, spiffs, {:#x}, 0x1000\n",
+                    i,
+                    0x10000 + i * 0x1000
+                )
+            })
+            .collect::<String>();
+        PartitionTable::try_from_str(too_many)
+            .expect_err("Failed to reject partition table with too many partitions");
+    }
+
+    #[test]
+    fn invalid_layouts_are_rejected() {
+        let error = |data: &str| PartitionTable::try_from_str(data).unwrap_err();
+
+        assert!(matches!(
+            error("nvs, data, nvs, 0x9800, 0x4000,\nfactory, app, factory, 0x10000, 1M,"),
+            PartitionTableError::UnalignedPartitionError(_)
+        ));
+        assert!(matches!(
+            error("factory, app, factory, 0x18000, 1M,"),
+            PartitionTableError::UnalignedPartitionError(_)
+        ));
+        assert!(matches!(
+            error("nvs, data, nvs, 0x7000, 0x4000,\nfactory, app, factory, 0x10000, 1M,"),
+            PartitionTableError::ReservedOffset(_)
+        ));
+        assert!(matches!(
+            error("factory, app, factory, 0x10000, 0x8000,"),
+            PartitionTableError::AppTooSmall(_)
+        ));
+        assert!(matches!(
+            error("nvs, data, nvs, 0x9000, 0x8000,\nfactory, app, factory, 0x10000, 1M,"),
+            PartitionTableError::Overlapping(_)
+        ));
+        assert!(matches!(
+            error("app, data, nvs, 0x9000, 0x4000,\napp, app, factory, 0x10000, 1M,"),
+            PartitionTableError::Duplicate(_)
+        ));
+    }
+
+    #[test]
+    fn offsets_follow_a_moved_table() {
+        let csv = "nvs, data, nvs, , 0x6000,\nphy_init, data, phy, , 0x1000,\nfactory, app, factory, , 1M,";
+        let table = PartitionTable::try_from_str_at(csv, 0xa000).unwrap();
+        let offsets: Vec<_> = table.partitions().iter().map(|p| p.offset()).collect();
+        assert_eq!(offsets, [0xb000, 0x11000, 0x20000]);
+
+        // The default table offset leaves the same partitions where ESP-IDF puts them
+        let table = PartitionTable::try_from_bytes(csv.as_bytes()).unwrap();
+        let offsets: Vec<_> = table.partitions().iter().map(|p| p.offset()).collect();
+        assert_eq!(offsets, [0x9000, 0xf000, 0x10000]);
+
+        // Partitions may not start inside the moved table
+        assert!(matches!(
+            PartitionTable::try_from_str_at(csv.replace("nvs, , ", "nvs, 0x9000, "), 0xa000),
+            Err(PartitionTableError::ReservedOffset(_))
+        ));
+        assert!(matches!(
+            PartitionTable::try_from_bytes_at(&table.to_bytes(), 0xa000),
+            Err(PartitionTableError::ReservedOffset(_))
+        ));
+    }
+
+    #[test]
+    fn binary_tables_are_parsed() {
+        let data = std::fs::read("./tests/data/partitions.bin").unwrap();
+        let table = PartitionTable::try_from_bytes(&data).unwrap();
+        assert_eq!(table.to_bytes(), data);
+
+        let pt0 = PartitionTable::try_from_str(PTABLE_0).unwrap();
+        let table = PartitionTable::try_from_bytes(&pt0.to_bytes()).unwrap();
+        assert_eq!(table.find("factory").unwrap().flags(), Flags::ENCRYPTED);
+        assert_eq!(table.to_bytes(), pt0.to_bytes());
+
+        let csv = PartitionTable::try_from_str(pt0.to_csv()).unwrap();
+        assert_eq!(csv.to_bytes(), pt0.to_bytes());
+
+        // CSV is still detected
+        PartitionTable::try_from_bytes(PTABLE_1.as_bytes()).unwrap();
+
+        let mut corrupted = data.clone();
+        corrupted[4] ^= 0x10;
+        PartitionTable::try_from_bytes(&corrupted).expect_err("Failed to reject bad checksum");
+        PartitionTable::try_from_bytes(&data[..40]).expect_err("Failed to reject truncated data");
+
+        // The same checks as for CSV tables apply
+        let overlapping = PartitionTable::basic(0x9000, 0x6000, 0xa000, 0x1000, 0x10000, 0x100000);
+        assert!(matches!(
+            PartitionTable::try_from_bytes(&overlapping.to_bytes()),
+            Err(PartitionTableError::Overlapping(_))
+        ));
+    }
+
+    #[test]
+    fn custom_types_are_preserved() {
+        let table = PartitionTable::try_from_str(
+            "factory, app, factory, 0x10000, 1M, encrypted:readonly
+storage, 0x40, 0x21, 0x110000, 64K,
+extra, data, 0x99, 0x120000, 64K, 0x80
+ota_1, app, 0x11, 0x130000, 1M,",
+        )
+        .unwrap();
+
+        let factory = table.find("factory").unwrap();
+        assert_eq!(factory.flags(), Flags::ENCRYPTED | Flags::READONLY);
+        let storage = table
+            .find_by_subtype(Type::Custom(0x40), SubType::Custom(0x21))
+            .unwrap();
+        assert_eq!(storage.name(), "storage");
+        assert_eq!(table.find("extra").unwrap().flags().bits(), 0x80);
+        assert_eq!(
+            table.find("ota_1").unwrap().sub_type(),
+            SubType::App(AppType::Ota1)
+        );
+
+        let binary = PartitionTable::try_from_bytes(&table.to_bytes()).unwrap();
+        assert_eq!(binary.to_bytes(), table.to_bytes());
+        assert_eq!(binary.find("storage").unwrap().ty(), Type::Custom(0x40));
+        let csv = PartitionTable::try_from_str(table.to_csv()).unwrap();
+        assert_eq!(csv.to_bytes(), table.to_bytes());
+
+        for data in [
+            "factory, app, factory, 0x10000, 1M,\nstorage, 0x20, 0x0, 0x110000, 64K,",
+            "factory, app, factory, 0x10000, 1M, hidden",
+            "factory, app, factory, 0x10000, 1M,\nstorage, 0x40, nvs, 0x110000, 64K,",
+        ] {
+            PartitionTable::try_from_str(data).expect_err(data);
+        }
+    }
+
+    #[test]
+    fn ota_data_selects_slot() {
+        let pt1 = PartitionTable::try_from_str(PTABLE_1).unwrap();
+
+        let (offset, data) = pt1.ota_data("ota_1").unwrap();
+        assert_eq!(offset, 0xd000);
+        assert_eq!(data.len(), 0x2000);
+        assert_eq!(data[..4], 2u32.to_le_bytes());
+        // Calculated with `binascii.crc32(struct.pack("<I", 2), 0xFFFFFFFF)`
+        assert_eq!(data[28..32], 0x55f63774u32.to_le_bytes());
+        assert!(data[32..].iter().all(|&b| b == 0xFF));
+
+        let (_, data) = pt1.ota_data("factory").unwrap();
+        assert!(data.iter().all(|&b| b == 0xFF));
+
+        pt1.ota_data("nvs")
+            .expect_err("Failed to reject data partition");
+        PartitionTable::try_from_str(PTABLE_0)
+            .unwrap()
+            .ota_data("factory")
+            .expect_err("Failed to reject table without otadata");
+    }
+
     #[test]
     fn blank_offsets_are_filled_in() {
         let pt2 = PartitionTable::try_from_str(PTABLE_2)
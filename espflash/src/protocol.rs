@@ -0,0 +1,663 @@
+//! The framing of the commands and the responses of the bootloader protocol,
+//! and the sequences of commands built from them, independent of how the bytes
+//! are transferred
+//!
+//! The blocking [`Connection`](crate::connection::Connection) and the
+//! `AsyncFlasher` both encode their commands and decode the responses with
+//! these, and carry out the same [`Operation`]s step by step.
+
+use std::{
+    borrow::Cow,
+    collections::VecDeque,
+    io::{Read, Write},
+    mem,
+    ops::Range,
+    time::Duration,
+};
+
+use binread::{io::Cursor, BinReaderExt};
+use slip_codec::{SlipDecoder, SlipError};
+
+use crate::{
+    chip::SpiRegisters,
+    command::{Command, CommandType},
+    connection::CommandResponse,
+    encoder::SlipEncoder,
+    error::{ConnectionError, Error, RomError, RomErrorKind},
+    flasher::{ProgressCallbacks, FLASH_WRITE_SIZE},
+};
+
+/// Length of the header every response starts with
+const RESPONSE_HEADER_LENGTH: usize = 8;
+
+/// The SLIP frame carrying `command`
+pub(crate) fn encode_command(command: Command) -> Result<Vec<u8>, Error> {
+    let mut frame = Vec::new();
+    let mut encoder = SlipEncoder::new(&mut frame)?;
+    command.write(&mut encoder)?;
+    encoder.finish()?;
+    Ok(frame)
+}
+
+/// The SLIP frame carrying `data` as is, without a command header
+pub(crate) fn encode_frame(data: &[u8]) -> Result<Vec<u8>, Error> {
+    let mut frame = Vec::new();
+    let mut encoder = SlipEncoder::new(&mut frame)?;
+    encoder.write_all(data)?;
+    encoder.finish()?;
+    Ok(frame)
+}
+
+/// Splits the received bytes into SLIP frames, which may arrive in pieces of
+/// any size
+#[derive(Default)]
+pub(crate) struct FrameDecoder {
+    decoder: SlipDecoder,
+    frame: Vec<u8>,
+}
+
+impl FrameDecoder {
+    /// Read the next frame from `source`
+    pub fn read(&mut self, source: &mut dyn Read) -> Result<Vec<u8>, Error> {
+        match self.decoder.decode(source, &mut self.frame) {
+            Ok(_) => Ok(mem::take(&mut self.frame)),
+            Err(err) => {
+                self.frame.clear();
+                Err(err.into())
+            }
+        }
+    }
+
+    /// Decode the next frame in `data`, advancing it past the consumed bytes
+    ///
+    /// Once `data` runs out in the middle of a frame `None` is returned, the
+    /// frame is continued with the data passed next.
+    #[cfg_attr(not(any(test, feature = "async")), allow(dead_code))]
+    pub fn decode(&mut self, data: &mut &[u8]) -> Result<Option<Vec<u8>>, Error> {
+        match self.decoder.decode(data, &mut self.frame) {
+            Ok(_) => Ok(Some(mem::take(&mut self.frame))),
+            Err(SlipError::EndOfStream) => Ok(None),
+            Err(err) => {
+                self.frame.clear();
+                Err(err.into())
+            }
+        }
+    }
+}
+
+/// The value returned in the response to a command of type `ty`
+///
+/// `None` is returned for frames which aren't a response to that command. A
+/// failed command is returned as [`Error::RomError`].
+pub(crate) fn parse_response(frame: &[u8], ty: CommandType) -> Result<Option<u32>, Error> {
+    // The value is followed by at least two status bytes
+    if frame.len() < RESPONSE_HEADER_LENGTH + 2 {
+        return Ok(None);
+    }
+    let response: CommandResponse = Cursor::new(frame).read_le()?;
+    if response.return_op != ty as u8 {
+        return Ok(None);
+    }
+
+    if response.status == 1 {
+        Err(Error::RomError(RomError::new(
+            ty,
+            RomErrorKind::from(response.error),
+        )))
+    } else {
+        Ok(Some(response.value))
+    }
+}
+
+/// The data returned in the response to a command of type `ty`, without the
+/// `status_length` status bytes ending it
+///
+/// Like with [`parse_response`], `None` is returned for frames which aren't a
+/// response to that command.
+pub(crate) fn parse_data_response(
+    frame: &[u8],
+    ty: CommandType,
+    status_length: usize,
+) -> Result<Option<Vec<u8>>, Error> {
+    if frame.len() < RESPONSE_HEADER_LENGTH + 2 {
+        return Ok(None);
+    }
+    let response: CommandResponse = Cursor::new(frame).read_le()?;
+    if response.return_op != ty as u8 {
+        return Ok(None);
+    }
+
+    // The status holds at least the failure flag and the error code
+    let data_end = RESPONSE_HEADER_LENGTH + response.return_length as usize;
+    let data = match frame.get(RESPONSE_HEADER_LENGTH..data_end) {
+        Some(data) if status_length >= 2 && data.len() >= status_length => data,
+        _ => return Err(Error::Connection(ConnectionError::MalformedResponse)),
+    };
+    let (data, status) = data.split_at(data.len() - status_length);
+
+    if status[0] == 1 {
+        Err(Error::RomError(RomError::new(
+            ty,
+            RomErrorKind::from(status[1]),
+        )))
+    } else {
+        Ok(Some(data.to_vec()))
+    }
+}
+
+/// What an [`Operation`] needs done next
+#[derive(Copy, Clone, Debug)]
+pub enum Step<'a> {
+    /// Send the command and wait at most the given time for its response
+    Command(Command<'a>, Duration),
+    /// Send the command without waiting for a response, like the ones which
+    /// end a write and may restart the device
+    Send(Command<'a>),
+    /// Wait before the next step
+    Sleep(Duration),
+    /// Reset the device the way the connection was set up to after flashing
+    Reset,
+}
+
+/// A sequence of commands, carried out step by step by the connections
+///
+/// The operations only decide what to send next, which lets the blocking and
+/// the async connections share them.
+pub(crate) trait Operation {
+    type Output;
+
+    /// The next step, or `None` once the operation is done
+    ///
+    /// `value` is the value returned in the response to the last
+    /// [`Step::Command`], and zero before the first step and after the other
+    /// steps.
+    fn step(
+        &mut self,
+        value: u32,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<Option<Step<'_>>, Error>;
+
+    fn output(self) -> Self::Output;
+}
+
+/// Steps which don't depend on the responses to the ones before
+#[derive(Default)]
+pub struct Steps(VecDeque<Step<'static>>);
+
+impl Steps {
+    pub fn push(&mut self, step: Step<'static>) {
+        self.0.push_back(step);
+    }
+}
+
+impl<I: IntoIterator<Item = Step<'static>>> From<I> for Steps {
+    fn from(steps: I) -> Self {
+        Steps(steps.into_iter().collect())
+    }
+}
+
+impl Operation for Steps {
+    type Output = ();
+
+    fn step(
+        &mut self,
+        _value: u32,
+        _progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<Option<Step<'_>>, Error> {
+        Ok(self.0.pop_front())
+    }
+
+    fn output(self) {}
+}
+
+/// The commands sending the blocks of a [`BlockWrite`]
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub enum BlockCommand {
+    Flash,
+    /// Written through the flash encryption by the flasher stub
+    FlashEncrypt,
+    FlashDeflate,
+    Mem,
+}
+
+impl BlockCommand {
+    fn command(self, sequence: u32, data: &[u8]) -> Command<'_> {
+        match self {
+            BlockCommand::Flash => Command::FlashData {
+                sequence,
+                pad_to: FLASH_WRITE_SIZE,
+                pad_byte: 0xff,
+                data,
+            },
+            BlockCommand::FlashEncrypt => Command::FlashEncryptData {
+                sequence,
+                pad_to: FLASH_WRITE_SIZE,
+                pad_byte: 0xff,
+                data,
+            },
+            BlockCommand::FlashDeflate => Command::FlashDeflateData {
+                sequence,
+                pad_to: 0,
+                pad_byte: 0xff,
+                data,
+            },
+            BlockCommand::Mem => Command::MemData {
+                sequence,
+                pad_to: 4,
+                pad_byte: 0,
+                data,
+            },
+        }
+    }
+}
+
+/// Writes data to flash or RAM block by block, after the command which starts
+/// the write
+///
+/// The progress is reported for the data as written to the device, which for
+/// compressed blocks is the data they decompress to.
+pub struct BlockWrite<'a> {
+    begin: Option<(Command<'static>, Duration)>,
+    command: BlockCommand,
+    addr: u32,
+    len: usize,
+    data: Cow<'a, [u8]>,
+    /// The range of every block in `data`, and the number of bytes written
+    /// once the block is
+    blocks: Vec<(Range<usize>, usize)>,
+    next: usize,
+}
+
+impl<'a> BlockWrite<'a> {
+    /// Write `data` to `addr` in blocks of `block_size` bytes
+    pub fn new(
+        begin: Command<'static>,
+        begin_timeout: Duration,
+        command: BlockCommand,
+        addr: u32,
+        data: Cow<'a, [u8]>,
+        block_size: usize,
+    ) -> Self {
+        let blocks = (0..data.len())
+            .step_by(block_size)
+            .map(|start| {
+                let end = data.len().min(start + block_size);
+                (start..end, end)
+            })
+            .collect();
+        BlockWrite {
+            begin: Some((begin, begin_timeout)),
+            command,
+            addr,
+            len: data.len(),
+            data,
+            blocks,
+            next: 0,
+        }
+    }
+
+    /// Write the `len` bytes at `addr` which `blocks` decompress to, see
+    /// [`compress_blocks`](crate::flash_target::compress_blocks)
+    pub fn deflate(
+        begin: Command<'static>,
+        begin_timeout: Duration,
+        addr: u32,
+        len: usize,
+        blocks: Vec<(Vec<u8>, usize)>,
+    ) -> Self {
+        let mut data = Vec::new();
+        let blocks = blocks
+            .into_iter()
+            .map(|(block, decoded)| {
+                let start = data.len();
+                data.extend_from_slice(&block);
+                (start..data.len(), decoded)
+            })
+            .collect();
+        BlockWrite {
+            begin: Some((begin, begin_timeout)),
+            command: BlockCommand::FlashDeflate,
+            addr,
+            len,
+            data: Cow::Owned(data),
+            blocks,
+            next: 0,
+        }
+    }
+}
+
+impl Operation for BlockWrite<'_> {
+    type Output = ();
+
+    fn step(
+        &mut self,
+        _value: u32,
+        progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<Option<Step<'_>>, Error> {
+        if let Some((begin, timeout)) = self.begin.take() {
+            return Ok(Some(Step::Command(begin, timeout)));
+        }
+
+        let written = match self.next {
+            0 => 0,
+            next => self.blocks[next - 1].1,
+        };
+        if let Some(cb) = progress.as_mut() {
+            if self.next == 0 {
+                cb.init(self.addr, self.len)
+            } else {
+                cb.update(written)
+            }
+        }
+
+        let (range, end) = match self.blocks.get(self.next) {
+            Some(block) => block.clone(),
+            None => {
+                if let Some(cb) = progress.as_mut() {
+                    cb.finish()
+                }
+                return Ok(None);
+            }
+        };
+        let sequence = self.next as u32;
+        self.next += 1;
+
+        let command = self.command.command(sequence, &self.data[range]);
+        let timeout = command.timeout_for_size((end - written) as u32);
+        Ok(Some(Step::Command(command, timeout)))
+    }
+
+    fn output(self) {}
+}
+
+/// Runs a command of the SPI flash through the registers of the SPI peripheral,
+/// returning the bits read back
+pub(crate) struct SpiCommand {
+    command: CommandType,
+    usr: u32,
+    usr2: u32,
+    w0: u32,
+    /// The register writes setting up and starting the command
+    setup: Steps,
+    stage: SpiStage,
+    old_usr: u32,
+    old_usr2: u32,
+    polls: u32,
+    result: u32,
+}
+
+#[derive(Copy, Clone, PartialEq, Eq)]
+enum SpiStage {
+    SaveUsr,
+    SaveUsr2,
+    Configure,
+    Setup,
+    Poll,
+    CheckDone,
+    ReadResult,
+    RestoreUsr,
+    RestoreUsr2,
+    Done,
+}
+
+impl SpiCommand {
+    pub fn new(registers: SpiRegisters, command: CommandType, data: &[u8], read_bits: u32) -> Self {
+        assert!(read_bits < 32);
+        assert!(data.len() < 64);
+
+        SpiCommand {
+            command,
+            usr: registers.usr(),
+            usr2: registers.usr2(),
+            w0: registers.w0(),
+            setup: setup_spi(&registers, command, data, read_bits),
+            stage: SpiStage::SaveUsr,
+            old_usr: 0,
+            old_usr2: 0,
+            polls: 0,
+            result: 0,
+        }
+    }
+}
+
+fn read_reg(address: u32) -> Step<'static> {
+    Step::Command(Command::ReadReg { address }, CommandType::ReadReg.timeout())
+}
+
+fn write_reg(address: u32, value: u32) -> Step<'static> {
+    Step::Command(
+        Command::WriteReg {
+            address,
+            value,
+            mask: None,
+        },
+        CommandType::WriteReg.timeout(),
+    )
+}
+
+fn setup_spi(registers: &SpiRegisters, command: CommandType, data: &[u8], read_bits: u32) -> Steps {
+    let mut steps = Steps::default();
+
+    let mut flags = 1 << 31;
+    if !data.is_empty() {
+        flags |= 1 << 27;
+    }
+    if read_bits > 0 {
+        flags |= 1 << 28;
+    }
+    steps.push(write_reg(registers.usr(), flags));
+    steps.push(write_reg(registers.usr2(), 7 << 28 | command as u32));
+
+    if let (Some(mosi_data_length), Some(miso_data_length)) =
+        (registers.mosi_length(), registers.miso_length())
+    {
+        if !data.is_empty() {
+            steps.push(write_reg(mosi_data_length, data.len() as u32 * 8 - 1));
+        }
+        if read_bits > 0 {
+            steps.push(write_reg(miso_data_length, read_bits - 1));
+        }
+    } else {
+        let mosi_mask = if data.is_empty() {
+            0
+        } else {
+            data.len() as u32 * 8 - 1
+        };
+        let miso_mask = if read_bits == 0 { 0 } else { read_bits - 1 };
+        steps.push(write_reg(
+            registers.usr1(),
+            miso_mask << 8 | mosi_mask << 17,
+        ));
+    }
+
+    if data.is_empty() {
+        steps.push(write_reg(registers.w0(), 0));
+    } else {
+        for (i, bytes) in data.chunks(4).enumerate() {
+            let mut data_bytes = [0; 4];
+            data_bytes[0..bytes.len()].copy_from_slice(bytes);
+            let data = u32::from_le_bytes(data_bytes);
+            steps.push(write_reg(registers.w0() + i as u32, data));
+        }
+    }
+
+    steps.push(write_reg(registers.cmd(), 1 << 18));
+    steps
+}
+
+impl Operation for SpiCommand {
+    type Output = u32;
+
+    fn step(
+        &mut self,
+        value: u32,
+        _progress: &mut Option<&mut dyn ProgressCallbacks>,
+    ) -> Result<Option<Step<'_>>, Error> {
+        loop {
+            let step = match self.stage {
+                SpiStage::SaveUsr => {
+                    self.stage = SpiStage::SaveUsr2;
+                    read_reg(self.usr)
+                }
+                SpiStage::SaveUsr2 => {
+                    self.old_usr = value;
+                    self.stage = SpiStage::Configure;
+                    read_reg(self.usr2)
+                }
+                SpiStage::Configure => {
+                    self.old_usr2 = value;
+                    self.stage = SpiStage::Setup;
+                    continue;
+                }
+                SpiStage::Setup => match self.setup.0.pop_front() {
+                    Some(step) => step,
+                    None => {
+                        self.stage = SpiStage::Poll;
+                        Step::Sleep(Duration::from_millis(1))
+                    }
+                },
+                SpiStage::Poll => {
+                    self.stage = SpiStage::CheckDone;
+                    read_reg(self.usr)
+                }
+                SpiStage::CheckDone => {
+                    if value & (1 << 18) == 0 {
+                        self.stage = SpiStage::ReadResult;
+                        continue;
+                    }
+                    self.polls += 1;
+                    if self.polls > 10 {
+                        return Err(Error::Connection(ConnectionError::Timeout(
+                            self.command.into(),
+                        )));
+                    }
+                    self.stage = SpiStage::Poll;
+                    Step::Sleep(Duration::from_millis(1))
+                }
+                SpiStage::ReadResult => {
+                    self.stage = SpiStage::RestoreUsr;
+                    read_reg(self.w0)
+                }
+                SpiStage::RestoreUsr => {
+                    self.result = value;
+                    self.stage = SpiStage::RestoreUsr2;
+                    write_reg(self.usr, self.old_usr)
+                }
+                SpiStage::RestoreUsr2 => {
+                    self.stage = SpiStage::Done;
+                    write_reg(self.usr2, self.old_usr2)
+                }
+                SpiStage::Done => return Ok(None),
+            };
+            return Ok(Some(step));
+        }
+    }
+
+    fn output(self) -> u32 {
+        self.result
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn response(op: CommandType, value: u32, data: &[u8]) -> Vec<u8> {
+        let mut frame = vec![1, op as u8];
+        frame.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        frame.extend_from_slice(&value.to_le_bytes());
+        frame.extend_from_slice(data);
+        frame
+    }
+
+    #[test]
+    fn frames_are_decoded_from_pieces() {
+        let frame = encode_command(Command::ReadReg {
+            address: 0x6000_00c0,
+        })
+        .unwrap();
+        assert_eq!(frame[0], 0xc0);
+        assert_eq!(frame[frame.len() - 1], 0xc0);
+        // The escaped END byte of the address
+        assert!(frame.windows(2).any(|bytes| bytes == [0xdb, 0xdc]));
+
+        let mut stream = frame.clone();
+        stream.extend_from_slice(&frame);
+        let mut decoder = FrameDecoder::default();
+        let mut frames = Vec::new();
+        for mut piece in stream.chunks(5) {
+            while let Some(frame) = decoder.decode(&mut piece).unwrap() {
+                frames.push(frame);
+            }
+            assert!(piece.is_empty());
+        }
+        assert_eq!(frames.len(), 2);
+        assert_eq!(frames[0], frames[1]);
+        assert_eq!(&frames[0][..2], [0, CommandType::ReadReg as u8]);
+        assert_eq!(&frames[0][8..12], 0x6000_00c0u32.to_le_bytes());
+    }
+
+    #[test]
+    fn responses_are_parsed() {
+        let ty = CommandType::ReadReg;
+        let frame = response(ty, 0x1234, &[0, 0, 0, 0]);
+        assert_eq!(parse_response(&frame, ty).unwrap(), Some(0x1234));
+        assert_eq!(parse_response(&frame, CommandType::Sync).unwrap(), None);
+        assert_eq!(parse_response(&frame[..6], ty).unwrap(), None);
+
+        let frame = response(ty, 0, &[1, 0x05, 0, 0]);
+        assert!(matches!(
+            parse_response(&frame, ty),
+            Err(Error::RomError(_))
+        ));
+
+        let ty = CommandType::FlashMd5;
+        let frame = response(ty, 0, &[0xaa, 0xbb, 0, 0]);
+        assert_eq!(
+            parse_data_response(&frame, ty, 2).unwrap(),
+            Some(vec![0xaa, 0xbb])
+        );
+        let frame = response(ty, 0, &[0xaa, 1, 0x07]);
+        assert!(matches!(
+            parse_data_response(&frame, ty, 2),
+            Err(Error::RomError(_))
+        ));
+        for (frame, status_length) in [(&frame[..10], 2), (&frame[..], 1), (&frame[..], 0)] {
+            assert!(matches!(
+                parse_data_response(frame, ty, status_length),
+                Err(Error::Connection(ConnectionError::MalformedResponse))
+            ));
+        }
+    }
+
+    #[test]
+    fn block_writes_step_through_the_blocks() {
+        let addr = 0x4037_c000;
+        let begin = Command::MemBegin {
+            size: 5,
+            blocks: 3,
+            block_size: 2,
+            offset: addr,
+            supports_encryption: false,
+        };
+        let data = [1, 2, 3, 4, 5];
+        let mut write = BlockWrite::new(
+            begin,
+            CommandType::MemBegin.timeout(),
+            BlockCommand::Mem,
+            addr,
+            Cow::Borrowed(&data),
+            2,
+        );
+
+        let mut sent = Vec::new();
+        while let Some(step) = write.step(0, &mut None).unwrap() {
+            match step {
+                Step::Command(command, _) => sent.push(command.command_type() as u8),
+                _ => panic!("unexpected step"),
+            }
+        }
+        let (begin, data) = (CommandType::MemBegin as u8, CommandType::MemData as u8);
+        assert_eq!(sent, [begin, data, data, data]);
+    }
+}
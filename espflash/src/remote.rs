@@ -0,0 +1,407 @@
+//! Serial ports on remote machines, reached over TCP
+//!
+//! Two kinds of endpoints are supported, given as URLs instead of a port name:
+//!
+//! - `tcp://host:port` is a raw socket, as provided by `ser2net` in raw mode or
+//!   esptool.py's `socket://` ports. Everything is forwarded as is, there are no
+//!   control lines so the device has to be put into download mode manually.
+//! - `rfc2217://host:port` is a telnet connection using the [RFC 2217] com port
+//!   control option, which also allows setting the baud rate and controlling
+//!   the DTR and RTS lines of the remote port.
+//!
+//! [RFC 2217]: https://datatracker.ietf.org/doc/html/rfc2217
+
+use std::{
+    io::{self, ErrorKind, Read, Write},
+    net::TcpStream,
+    time::Duration,
+};
+
+use serialport::{ClearBuffer, DataBits, FlowControl, Parity, SerialPort, StopBits};
+
+const IAC: u8 = 255;
+const WILL: u8 = 251;
+const WONT: u8 = 252;
+const DO: u8 = 253;
+const DONT: u8 = 254;
+const SB: u8 = 250;
+const SE: u8 = 240;
+
+const BINARY: u8 = 0;
+const SUPPRESS_GO_AHEAD: u8 = 3;
+const COM_PORT_OPTION: u8 = 44;
+
+const SET_BAUDRATE: u8 = 1;
+const SET_DATASIZE: u8 = 2;
+const SET_PARITY: u8 = 3;
+const SET_STOPSIZE: u8 = 4;
+const SET_CONTROL: u8 = 5;
+
+const CONTROL_DTR_ON: u8 = 8;
+const CONTROL_DTR_OFF: u8 = 9;
+const CONTROL_RTS_ON: u8 = 11;
+const CONTROL_RTS_OFF: u8 = 12;
+
+/// Whether `name` refers to a remote serial port instead of a local one
+pub fn is_remote(name: &str) -> bool {
+    name.starts_with("tcp://") || name.starts_with("rfc2217://")
+}
+
+/// Receiving state of the telnet protocol
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+enum Telnet {
+    Data,
+    Iac,
+    /// A WILL, WONT, DO or DONT command, waiting for its option
+    Negotiation(u8),
+    Subnegotiation,
+    SubnegotiationIac,
+}
+
+/// A serial port connected through TCP
+pub struct TcpSerial {
+    name: String,
+    stream: TcpStream,
+    /// Whether the telnet protocol of RFC 2217 is used
+    rfc2217: bool,
+    telnet: Telnet,
+    baud_rate: u32,
+    timeout: Duration,
+}
+
+impl TcpSerial {
+    /// Connect to the remote port given by `url`, see the [module](self)
+    /// documentation for the supported URLs
+    pub fn open(url: &str, baud_rate: u32) -> io::Result<Self> {
+        let (rfc2217, address) = if let Some(address) = url.strip_prefix("rfc2217://") {
+            (true, address)
+        } else if let Some(address) = url.strip_prefix("tcp://") {
+            (false, address)
+        } else {
+            return Err(io::Error::new(
+                ErrorKind::InvalidInput,
+                "expected a tcp:// or rfc2217:// URL",
+            ));
+        };
+
+        let stream = TcpStream::connect(address.trim_end_matches('/'))?;
+        stream.set_nodelay(true)?;
+
+        let mut serial = TcpSerial {
+            name: url.to_string(),
+            stream,
+            rfc2217,
+            telnet: Telnet::Data,
+            baud_rate,
+            timeout: Duration::from_secs(1),
+        };
+        serial.set_timeout(serial.timeout)?;
+
+        if rfc2217 {
+            serial.stream.write_all(&[
+                IAC,
+                WILL,
+                COM_PORT_OPTION,
+                IAC,
+                WILL,
+                BINARY,
+                IAC,
+                DO,
+                BINARY,
+            ])?;
+            serial.com_port_command(SET_BAUDRATE, &baud_rate.to_be_bytes())?;
+            serial.com_port_command(SET_DATASIZE, &[8])?;
+            serial.com_port_command(SET_PARITY, &[1])?;
+            serial.com_port_command(SET_STOPSIZE, &[1])?;
+        }
+
+        Ok(serial)
+    }
+
+    /// Send a command of the com port control option, the server's response is
+    /// discarded when reading
+    fn com_port_command(&mut self, command: u8, value: &[u8]) -> io::Result<()> {
+        let mut data = vec![IAC, SB, COM_PORT_OPTION, command];
+        escape(value, &mut data);
+        data.extend_from_slice(&[IAC, SE]);
+        self.stream.write_all(&data)
+    }
+
+    fn set_control(&mut self, control: u8) -> serialport::Result<()> {
+        if self.rfc2217 {
+            self.com_port_command(SET_CONTROL, &[control])?;
+        }
+        Ok(())
+    }
+
+    /// Remove the telnet commands from received data, answering the option
+    /// negotiations of the server
+    fn process_telnet(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut len = 0;
+        let mut responses = Vec::new();
+
+        for i in 0..buf.len() {
+            let byte = buf[i];
+            self.telnet = match (self.telnet, byte) {
+                (Telnet::Data, IAC) => Telnet::Iac,
+                (Telnet::Data, byte) | (Telnet::Iac, byte @ IAC) => {
+                    buf[len] = byte;
+                    len += 1;
+                    Telnet::Data
+                }
+                (Telnet::Iac, command @ WILL..=DONT) => Telnet::Negotiation(command),
+                (Telnet::Iac, SB) => Telnet::Subnegotiation,
+                (Telnet::Iac, _) => Telnet::Data,
+                (Telnet::Negotiation(command), option) => {
+                    let response = match (command, option) {
+                        // Acknowledgements of the options requested when connecting
+                        (DO, COM_PORT_OPTION) | (DO, BINARY) | (WILL, BINARY) => None,
+                        (WILL, SUPPRESS_GO_AHEAD) => Some(DO),
+                        (DO, SUPPRESS_GO_AHEAD) => Some(WILL),
+                        (WILL, _) => Some(DONT),
+                        (DO, _) => Some(WONT),
+                        _ => None,
+                    };
+                    if let Some(response) = response {
+                        responses.extend_from_slice(&[IAC, response, option]);
+                    }
+                    Telnet::Data
+                }
+                (Telnet::Subnegotiation, IAC) => Telnet::SubnegotiationIac,
+                (Telnet::Subnegotiation, _) => Telnet::Subnegotiation,
+                (Telnet::SubnegotiationIac, SE) => Telnet::Data,
+                (Telnet::SubnegotiationIac, _) => Telnet::Subnegotiation,
+            };
+        }
+
+        if !responses.is_empty() {
+            self.stream.write_all(&responses)?;
+        }
+        Ok(len)
+    }
+}
+
+/// Double every IAC byte, as required for data sent over telnet
+fn escape(data: &[u8], out: &mut Vec<u8>) {
+    for &byte in data {
+        out.push(byte);
+        if byte == IAC {
+            out.push(IAC);
+        }
+    }
+}
+
+fn unsupported(what: &str) -> serialport::Error {
+    serialport::Error::new(
+        serialport::ErrorKind::Unknown,
+        format!("{} is not supported by remote ports", what),
+    )
+}
+
+impl Read for TcpSerial {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        loop {
+            let count = match self.stream.read(buf) {
+                Ok(0) => return Err(ErrorKind::UnexpectedEof.into()),
+                Ok(count) => count,
+                // Depending on the platform an expired timeout is reported as `WouldBlock`
+                Err(e) if e.kind() == ErrorKind::WouldBlock => {
+                    return Err(ErrorKind::TimedOut.into())
+                }
+                Err(e) => return Err(e),
+            };
+            if !self.rfc2217 {
+                return Ok(count);
+            }
+
+            // Reads consisting only of telnet commands are not reported as empty reads,
+            // which would be taken for the end of the stream
+            let count = self.process_telnet(&mut buf[..count])?;
+            if count > 0 {
+                return Ok(count);
+            }
+        }
+    }
+}
+
+impl Write for TcpSerial {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        if self.rfc2217 {
+            let mut data = Vec::with_capacity(buf.len());
+            escape(buf, &mut data);
+            self.stream.write_all(&data)?;
+            Ok(buf.len())
+        } else {
+            self.stream.write(buf)
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.stream.flush()
+    }
+}
+
+impl SerialPort for TcpSerial {
+    fn name(&self) -> Option<String> {
+        Some(self.name.clone())
+    }
+
+    fn baud_rate(&self) -> serialport::Result<u32> {
+        Ok(self.baud_rate)
+    }
+
+    fn data_bits(&self) -> serialport::Result<DataBits> {
+        Ok(DataBits::Eight)
+    }
+
+    fn flow_control(&self) -> serialport::Result<FlowControl> {
+        Ok(FlowControl::None)
+    }
+
+    fn parity(&self) -> serialport::Result<Parity> {
+        Ok(Parity::None)
+    }
+
+    fn stop_bits(&self) -> serialport::Result<StopBits> {
+        Ok(StopBits::One)
+    }
+
+    fn timeout(&self) -> Duration {
+        self.timeout
+    }
+
+    fn set_baud_rate(&mut self, baud_rate: u32) -> serialport::Result<()> {
+        // The baud rate of a raw socket is configured on the remote end
+        if self.rfc2217 {
+            self.com_port_command(SET_BAUDRATE, &baud_rate.to_be_bytes())?;
+        }
+        self.baud_rate = baud_rate;
+        Ok(())
+    }
+
+    fn set_data_bits(&mut self, data_bits: DataBits) -> serialport::Result<()> {
+        match data_bits {
+            DataBits::Eight => Ok(()),
+            _ => Err(unsupported("Changing the data bits")),
+        }
+    }
+
+    fn set_flow_control(&mut self, flow_control: FlowControl) -> serialport::Result<()> {
+        match flow_control {
+            FlowControl::None => Ok(()),
+            _ => Err(unsupported("Flow control")),
+        }
+    }
+
+    fn set_parity(&mut self, parity: Parity) -> serialport::Result<()> {
+        match parity {
+            Parity::None => Ok(()),
+            _ => Err(unsupported("Parity")),
+        }
+    }
+
+    fn set_stop_bits(&mut self, stop_bits: StopBits) -> serialport::Result<()> {
+        match stop_bits {
+            StopBits::One => Ok(()),
+            _ => Err(unsupported("Changing the stop bits")),
+        }
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> serialport::Result<()> {
+        // A zero timeout isn't accepted by sockets
+        self.stream
+            .set_read_timeout(Some(timeout.max(Duration::from_millis(1))))?;
+        self.timeout = timeout;
+        Ok(())
+    }
+
+    fn write_request_to_send(&mut self, level: bool) -> serialport::Result<()> {
+        self.set_control(if level {
+            CONTROL_RTS_ON
+        } else {
+            CONTROL_RTS_OFF
+        })
+    }
+
+    fn write_data_terminal_ready(&mut self, level: bool) -> serialport::Result<()> {
+        self.set_control(if level {
+            CONTROL_DTR_ON
+        } else {
+            CONTROL_DTR_OFF
+        })
+    }
+
+    fn read_clear_to_send(&mut self) -> serialport::Result<bool> {
+        Err(unsupported("Reading CTS"))
+    }
+
+    fn read_data_set_ready(&mut self) -> serialport::Result<bool> {
+        Err(unsupported("Reading DSR"))
+    }
+
+    fn read_ring_indicator(&mut self) -> serialport::Result<bool> {
+        Err(unsupported("Reading RI"))
+    }
+
+    fn read_carrier_detect(&mut self) -> serialport::Result<bool> {
+        Err(unsupported("Reading CD"))
+    }
+
+    fn bytes_to_read(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn bytes_to_write(&self) -> serialport::Result<u32> {
+        Ok(0)
+    }
+
+    fn clear(&self, _buffer_to_clear: ClearBuffer) -> serialport::Result<()> {
+        Ok(())
+    }
+
+    fn try_clone(&self) -> serialport::Result<Box<dyn SerialPort>> {
+        Ok(Box::new(TcpSerial {
+            name: self.name.clone(),
+            stream: self.stream.try_clone()?,
+            rfc2217: self.rfc2217,
+            telnet: Telnet::Data,
+            baud_rate: self.baud_rate,
+            timeout: self.timeout,
+        }))
+    }
+
+    fn set_break(&self) -> serialport::Result<()> {
+        Err(unsupported("Sending a break"))
+    }
+
+    fn clear_break(&self) -> serialport::Result<()> {
+        Err(unsupported("Sending a break"))
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use std::net::TcpListener;
+
+    use super::*;
+
+    #[test]
+    fn telnet_commands_are_removed() {
+        let listener = TcpListener::bind("127.0.0.1:0").unwrap();
+        let url = format!("rfc2217://{}", listener.local_addr().unwrap());
+        let mut serial = TcpSerial::open(&url, 115_200).unwrap();
+        let (mut server, _) = listener.accept().unwrap();
+
+        // A negotiation, an escaped IAC and a subnegotiation split over two reads
+        server
+            .write_all(&[1, IAC, WILL, 99, 2, IAC, IAC, 3, IAC, SB, COM_PORT_OPTION])
+            .unwrap();
+        let mut buf = [0; 16];
+        assert_eq!(serial.read(&mut buf).unwrap(), 4);
+        assert_eq!(buf[..4], [1, 2, IAC, 3]);
+
+        server.write_all(&[101, 0, IAC, SE, 4]).unwrap();
+        assert_eq!(serial.read(&mut buf).unwrap(), 1);
+        assert_eq!(buf[0], 4);
+    }
+}
@@ -0,0 +1,411 @@
+//! Resetting the device through the serial control lines
+//!
+//! Most development boards connect the DTR and RTS lines of their USB serial
+//! adapter to the EN and GPIO0 pins of the chip through a pair of transistors,
+//! which lets the host reset the chip and select its boot mode. The chips with
+//! a built-in USB-JTAG-Serial peripheral emulate these lines, but need a
+//! different sequence. Each sequence is implemented as a [`ResetStrategy`].
+//!
+//! The built-in sequences are lists of [`ResetStep`]s, which both the blocking
+//! connection and the one of the `AsyncFlasher` run.
+
+use std::{str::FromStr, thread::sleep, time::Duration};
+
+use serde::{Deserialize, Serialize};
+use serialport::SerialPortType;
+
+use crate::{connection::GpioLine, error::Error, transport::Transport};
+
+const USB_SERIAL_JTAG_VID: u16 = 0x303a;
+const USB_SERIAL_JTAG_PID: u16 = 0x1001;
+
+/// Whether the port is the USB-JTAG-Serial peripheral built into the ESP32-C3,
+/// ESP32-S3 and later chips
+pub fn is_usb_serial_jtag(port_type: &SerialPortType) -> bool {
+    matches!(
+        port_type,
+        SerialPortType::UsbPort(info)
+            if info.vid == USB_SERIAL_JTAG_VID && info.pid == USB_SERIAL_JTAG_PID
+    )
+}
+
+/// The DTR and RTS lines of a serial port, or the GPIO pins replacing them
+pub struct ResetLines<'a> {
+    transport: &'a mut dyn Transport,
+    gpio_dtr: &'a Option<GpioLine>,
+    gpio_rts: &'a Option<GpioLine>,
+}
+
+impl<'a> ResetLines<'a> {
+    pub fn new(
+        transport: &'a mut dyn Transport,
+        gpio_dtr: &'a Option<GpioLine>,
+        gpio_rts: &'a Option<GpioLine>,
+    ) -> Self {
+        ResetLines {
+            transport,
+            gpio_dtr,
+            gpio_rts,
+        }
+    }
+
+    pub fn set_dtr(&mut self, state: bool) -> Result<(), Error> {
+        if let Some(dtr) = self.gpio_dtr {
+            dtr.set(state)?;
+        } else {
+            self.transport.set_dtr(state)?;
+        }
+        Ok(())
+    }
+
+    pub fn set_rts(&mut self, state: bool) -> Result<(), Error> {
+        if let Some(rts) = self.gpio_rts {
+            rts.set(state)?;
+        } else {
+            self.transport.set_rts(state)?;
+        }
+        Ok(())
+    }
+
+    /// Run the steps of a reset sequence, waiting in between by sleeping
+    pub(crate) fn run(&mut self, steps: &[ResetStep]) -> Result<(), Error> {
+        for step in steps {
+            match *step {
+                ResetStep::Dtr(level) => self.set_dtr(level)?,
+                ResetStep::Rts(level) => self.set_rts(level)?,
+                ResetStep::Wait(duration) => sleep(duration),
+            }
+        }
+        Ok(())
+    }
+}
+
+/// A step of a reset sequence
+#[derive(Copy, Clone, Debug, PartialEq, Eq)]
+pub(crate) enum ResetStep {
+    Dtr(bool),
+    Rts(bool),
+    Wait(Duration),
+}
+
+fn wait(millis: u64) -> ResetStep {
+    ResetStep::Wait(Duration::from_millis(millis))
+}
+
+/// A GPIO pin wired to the EN or GPIO0 pin of the chip, replacing the DTR or
+/// RTS line of the serial port
+///
+/// On Linux the pins of a GPIO character device can be used, on every platform
+/// the CBUS pins of an FTDI adapter with the `ftdi` feature.
+pub trait ResetPin {
+    fn set(&self, high: bool) -> Result<(), Error>;
+}
+
+#[cfg(target_os = "linux")]
+impl ResetPin for gpio_cdev::LineHandle {
+    fn set(&self, high: bool) -> Result<(), Error> {
+        self.set_value(high as u8)?;
+        Ok(())
+    }
+}
+
+/// The CBUS pins of an FTDI adapter, driven in the CBUS bit-bang mode
+///
+/// The pins have to be configured as `I/O` in the EEPROM of the adapter, eg
+/// with FT_PROG. All four pins are set at once, so the pins of one adapter are
+/// taken from the same `FtdiCbus`.
+#[cfg(feature = "ftdi")]
+#[derive(Clone)]
+pub struct FtdiCbus(std::sync::Arc<std::sync::Mutex<FtdiCbusState>>);
+
+#[cfg(feature = "ftdi")]
+struct FtdiCbusState {
+    device: libftd2xx::Ftdi,
+    /// Pins driven by espflash, the others are left as inputs
+    outputs: u8,
+    values: u8,
+}
+
+#[cfg(feature = "ftdi")]
+impl FtdiCbus {
+    /// Open the adapter with the given serial number, or the first one
+    pub fn open(serial_number: Option<&str>) -> Result<Self, Error> {
+        let device = match serial_number {
+            Some(serial_number) => libftd2xx::Ftdi::with_serial_number(serial_number)?,
+            None => libftd2xx::Ftdi::new()?,
+        };
+
+        Ok(FtdiCbus(std::sync::Arc::new(std::sync::Mutex::new(
+            FtdiCbusState {
+                device,
+                outputs: 0,
+                values: 0,
+            },
+        ))))
+    }
+
+    /// Drive the CBUS pin `pin`, 0 to 3, starting out low like the pins of a
+    /// GPIO character device
+    pub fn pin(&self, pin: u8) -> Result<FtdiCbusPin, Error> {
+        if pin > 3 {
+            return Err(Error::GpioUnsupported(format!("CBUS{}", pin)));
+        }
+
+        let pin = FtdiCbusPin {
+            cbus: self.clone(),
+            mask: 1 << pin,
+        };
+        self.0.lock().unwrap().outputs |= pin.mask;
+        pin.set(false)?;
+        Ok(pin)
+    }
+}
+
+/// A CBUS pin of an FTDI adapter, see [`FtdiCbus`]
+#[cfg(feature = "ftdi")]
+pub struct FtdiCbusPin {
+    cbus: FtdiCbus,
+    mask: u8,
+}
+
+#[cfg(feature = "ftdi")]
+impl ResetPin for FtdiCbusPin {
+    fn set(&self, high: bool) -> Result<(), Error> {
+        use libftd2xx::FtdiCommon;
+
+        let mut state = self.cbus.0.lock().unwrap();
+        if high {
+            state.values |= self.mask;
+        } else {
+            state.values &= !self.mask;
+        }
+        // The upper nibble selects the outputs, the lower one their levels
+        let mask = state.outputs << 4 | state.values;
+        state
+            .device
+            .set_bit_mode(mask, libftd2xx::BitMode::CbusBitbang)?;
+        Ok(())
+    }
+}
+
+/// A sequence of the control lines resetting the device
+pub trait ResetStrategy {
+    fn reset(&self, lines: &mut ResetLines) -> Result<(), Error>;
+}
+
+/// Reset into the serial bootloader using the auto-reset circuit of the
+/// nodemcu and most other development boards
+///
+/// Some boards need GPIO0 to be held low for longer, which `extra_delay` does.
+pub struct ClassicReset {
+    pub extra_delay: bool,
+}
+
+impl ClassicReset {
+    pub(crate) fn steps(&self) -> Vec<ResetStep> {
+        use ResetStep::*;
+
+        let millis = if self.extra_delay { 500 } else { 50 };
+        vec![
+            // Hold the chip in reset
+            Dtr(false),
+            Rts(true),
+            wait(100),
+            // Release the reset with GPIO0 low
+            Dtr(true),
+            Rts(false),
+            wait(millis),
+            Dtr(false),
+        ]
+    }
+}
+
+impl ResetStrategy for ClassicReset {
+    fn reset(&self, lines: &mut ResetLines) -> Result<(), Error> {
+        lines.run(&self.steps())
+    }
+}
+
+/// Reset into the serial bootloader through the USB-JTAG-Serial peripheral
+pub struct UsbJtagSerialReset;
+
+impl UsbJtagSerialReset {
+    pub(crate) fn steps(&self) -> Vec<ResetStep> {
+        use ResetStep::*;
+
+        vec![
+            Dtr(false),
+            Rts(false),
+            wait(100),
+            Dtr(true),
+            Rts(false),
+            wait(100),
+            // Going through DTR=0, RTS=1 directly from DTR=1, RTS=0 would reset
+            // the chip without selecting the bootloader
+            Rts(true),
+            Dtr(false),
+            Rts(true),
+            wait(100),
+            Dtr(false),
+            Rts(false),
+        ]
+    }
+}
+
+impl ResetStrategy for UsbJtagSerialReset {
+    fn reset(&self, lines: &mut ResetLines) -> Result<(), Error> {
+        lines.run(&self.steps())
+    }
+}
+
+/// Reset the device and let it boot the application
+///
+/// The USB-JTAG-Serial peripheral needs more time to come out of reset before
+/// it handles further changes of the lines.
+pub struct HardReset {
+    pub usb_serial_jtag: bool,
+}
+
+impl HardReset {
+    pub(crate) fn steps(&self) -> Vec<ResetStep> {
+        use ResetStep::*;
+
+        let millis = if self.usb_serial_jtag { 200 } else { 100 };
+        let mut steps = vec![
+            wait(millis),
+            Dtr(false),
+            Rts(true),
+            wait(millis),
+            Rts(false),
+        ];
+        if self.usb_serial_jtag {
+            steps.push(wait(millis));
+        }
+        steps
+    }
+}
+
+impl ResetStrategy for HardReset {
+    fn reset(&self, lines: &mut ResetLines) -> Result<(), Error> {
+        lines.run(&self.steps())
+    }
+}
+
+/// How the device is put into the serial bootloader before connecting
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResetBeforeOperation {
+    /// Use the USB-JTAG-Serial sequence for those ports, the classic one otherwise
+    #[default]
+    DefaultReset,
+    /// Always use the USB-JTAG-Serial sequence
+    UsbReset,
+    /// Don't reset, the device has to be in the bootloader already
+    NoReset,
+}
+
+impl ResetBeforeOperation {
+    /// The sequence resetting the device into the bootloader, `usb_serial_jtag`
+    /// tells whether it's connected through the USB-JTAG-Serial peripheral
+    pub(crate) fn steps(self, usb_serial_jtag: bool, extra_delay: bool) -> Vec<ResetStep> {
+        match self {
+            ResetBeforeOperation::DefaultReset if usb_serial_jtag => UsbJtagSerialReset.steps(),
+            ResetBeforeOperation::DefaultReset => ClassicReset { extra_delay }.steps(),
+            ResetBeforeOperation::UsbReset => UsbJtagSerialReset.steps(),
+            ResetBeforeOperation::NoReset => Vec::new(),
+        }
+    }
+}
+
+impl FromStr for ResetBeforeOperation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "default-reset" => Ok(ResetBeforeOperation::DefaultReset),
+            "usb-reset" => Ok(ResetBeforeOperation::UsbReset),
+            "no-reset" => Ok(ResetBeforeOperation::NoReset),
+            _ => Err(format!("unknown reset mode `{}`", s)),
+        }
+    }
+}
+
+/// What happens to the device once flashing has completed
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Deserialize, Serialize, Default)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResetAfterOperation {
+    /// Reset the device, booting the new application
+    #[default]
+    HardReset,
+    /// Let the ROM bootloader start the new application without resetting the
+    /// chip, leaving peripherals like the USB-JTAG-Serial connection intact
+    SoftReset,
+    /// Leave the device and the control lines as they are
+    NoReset,
+    /// Reset the device into the ROM bootloader again, so other tools can
+    /// connect to it without having to reset it themselves
+    StayInBootloader,
+}
+
+impl ResetAfterOperation {
+    /// The sequence run after flashing, `before` is the one resetting into the
+    /// bootloader again
+    ///
+    /// The default sequence is used to stay in the bootloader if `before` is
+    /// [`ResetBeforeOperation::NoReset`], which has none.
+    pub(crate) fn steps(
+        self,
+        before: ResetBeforeOperation,
+        usb_serial_jtag: bool,
+    ) -> Vec<ResetStep> {
+        match self {
+            ResetAfterOperation::HardReset => HardReset { usb_serial_jtag }.steps(),
+            // Ending the flash operation already made the ROM bootloader start
+            // the application
+            ResetAfterOperation::SoftReset | ResetAfterOperation::NoReset => Vec::new(),
+            ResetAfterOperation::StayInBootloader => {
+                let before = match before {
+                    ResetBeforeOperation::NoReset => ResetBeforeOperation::DefaultReset,
+                    before => before,
+                };
+                before.steps(usb_serial_jtag, false)
+            }
+        }
+    }
+}
+
+impl FromStr for ResetAfterOperation {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Self, Self::Err> {
+        match s {
+            "hard-reset" => Ok(ResetAfterOperation::HardReset),
+            "soft-reset" => Ok(ResetAfterOperation::SoftReset),
+            "no-reset" => Ok(ResetAfterOperation::NoReset),
+            "stay-in-bootloader" => Ok(ResetAfterOperation::StayInBootloader),
+            _ => Err(format!("unknown reset mode `{}`", s)),
+        }
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn staying_in_the_bootloader_always_resets_into_it() {
+        let after = ResetAfterOperation::StayInBootloader;
+        assert_eq!(
+            after.steps(ResetBeforeOperation::NoReset, false),
+            ClassicReset { extra_delay: false }.steps()
+        );
+        assert_eq!(
+            after.steps(ResetBeforeOperation::NoReset, true),
+            UsbJtagSerialReset.steps()
+        );
+        assert_eq!(
+            after.steps(ResetBeforeOperation::UsbReset, false),
+            UsbJtagSerialReset.steps()
+        );
+    }
+}
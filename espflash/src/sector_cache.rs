@@ -0,0 +1,128 @@
+//! Digests of the flash sectors written to a device, for only writing the
+//! sectors which changed since the previous flash
+//!
+//! The cache only knows about the writes made through the [`Flasher`] it's
+//! attached to, it has to be cleared if the flash was changed by other means.
+//!
+//! [`Flasher`]: crate::Flasher
+
+use std::{collections::BTreeMap, ops::Range};
+
+use serde::{Deserialize, Serialize};
+
+use crate::{error::Error, flasher::FLASH_SECTOR_SIZE};
+
+/// MD5 digests of the contents of flash sectors, by the address of the sector
+#[derive(Clone, Debug, Default, PartialEq, Eq, Serialize, Deserialize)]
+pub struct SectorCache {
+    sectors: BTreeMap<u32, String>,
+}
+
+impl SectorCache {
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        serde_json::from_str(json).map_err(|err| Error::InvalidSectorCache(err.to_string()))
+    }
+
+    pub fn to_json(&self) -> String {
+        serde_json::to_string(self).unwrap()
+    }
+
+    pub fn clear(&mut self) {
+        self.sectors.clear();
+    }
+
+    /// The sectors of `data` written at `addr`, with the range of `data` in
+    /// each of them and the digest of the sector after writing it
+    ///
+    /// The rest of the last sector is erased when writing, so it's included in
+    /// the digest as such.
+    fn sectors(addr: u32, data: &[u8]) -> impl Iterator<Item = (u32, Range<usize>, String)> + '_ {
+        data.chunks(FLASH_SECTOR_SIZE)
+            .enumerate()
+            .map(move |(index, chunk)| {
+                let start = index * FLASH_SECTOR_SIZE;
+                let mut context = md5::Context::new();
+                context.consume(chunk);
+                context.consume(&[0xff; FLASH_SECTOR_SIZE][chunk.len()..]);
+                let digest = format!("{:x}", context.compute());
+                (addr + start as u32, start..start + chunk.len(), digest)
+            })
+    }
+
+    /// The ranges of `data` which have to be written at `addr`, covering the
+    /// sectors whose contents differ from the cached ones
+    pub(crate) fn changed(&self, addr: u32, data: &[u8]) -> Vec<Range<usize>> {
+        // Writes starting within a sector erase the start of that sector as well
+        let aligned = addr % FLASH_SECTOR_SIZE as u32 == 0;
+
+        let mut ranges: Vec<Range<usize>> = Vec::new();
+        for (sector, range, digest) in Self::sectors(addr, data) {
+            if aligned && self.sectors.get(&sector) == Some(&digest) {
+                continue;
+            }
+            match ranges.last_mut() {
+                Some(last) if last.end == range.start => last.end = range.end,
+                _ => ranges.push(range),
+            }
+        }
+        ranges
+    }
+
+    /// Remember the contents of the sectors written with `data` at `addr`
+    pub(crate) fn insert(&mut self, addr: u32, data: &[u8]) {
+        if addr % FLASH_SECTOR_SIZE as u32 != 0 {
+            return;
+        }
+        for (sector, _, digest) in Self::sectors(addr, data) {
+            self.sectors.insert(sector, digest);
+        }
+    }
+
+    /// Forget the sectors overlapping the `size` bytes at `addr`, which are
+    /// being erased or written
+    pub(crate) fn forget(&mut self, addr: u32, size: u32) {
+        let first = addr - addr % FLASH_SECTOR_SIZE as u32;
+        let end = addr.saturating_add(size);
+        self.sectors
+            .retain(|&sector, _| sector < first || sector >= end);
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn only_changed_sectors_are_written() {
+        let mut cache = SectorCache::default();
+        let mut data = vec![0x5a; FLASH_SECTOR_SIZE * 4 + 100];
+        assert_eq!(
+            cache.changed(0x10000, &data).first(),
+            Some(&(0..data.len()))
+        );
+        cache.insert(0x10000, &data);
+        assert!(cache.changed(0x10000, &data).is_empty());
+
+        data[FLASH_SECTOR_SIZE + 1] = 0;
+        data[FLASH_SECTOR_SIZE * 4] = 0;
+        assert_eq!(
+            cache.changed(0x10000, &data),
+            [
+                FLASH_SECTOR_SIZE..FLASH_SECTOR_SIZE * 2,
+                FLASH_SECTOR_SIZE * 4..data.len()
+            ]
+        );
+
+        cache.forget(0x10000, 1);
+        cache.forget(0x10000 + FLASH_SECTOR_SIZE as u32 * 3, 1);
+        data = vec![0x5a; FLASH_SECTOR_SIZE * 4 + 100];
+        assert_eq!(
+            cache.changed(0x10000, &data),
+            [
+                0..FLASH_SECTOR_SIZE,
+                FLASH_SECTOR_SIZE * 3..FLASH_SECTOR_SIZE * 4
+            ]
+        );
+        assert_eq!(SectorCache::from_json(&cache.to_json()).unwrap(), cache);
+    }
+}
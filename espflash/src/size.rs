@@ -0,0 +1,111 @@
+//! Memory usage of ELF images, like `idf.py size`
+
+use serde::Serialize;
+use strum_macros::Display;
+use xmas_elf::sections::{ShType, SHF_ALLOC, SHF_EXECINSTR};
+
+use crate::{elf::FirmwareImage, Chip};
+
+/// Memory a section of an image is placed in
+#[derive(Copy, Clone, Debug, PartialEq, Eq, Display, Serialize)]
+pub enum Memory {
+    #[strum(serialize = "Flash code")]
+    FlashCode,
+    #[strum(serialize = "Flash data")]
+    FlashData,
+    #[strum(serialize = "IRAM")]
+    Iram,
+    #[strum(serialize = "DRAM")]
+    Dram,
+    /// RTC memory and memory mapped peripherals
+    #[strum(serialize = "Other")]
+    Other,
+}
+
+/// An allocated section of an image
+#[derive(Clone, Debug, Serialize)]
+pub struct SectionSize {
+    pub name: String,
+    pub addr: u32,
+    pub size: u32,
+    pub memory: Memory,
+    /// Zero initialized, the section takes up no space in the image
+    pub bss: bool,
+}
+
+/// The allocated sections of `image`, in the order of the ELF file
+pub fn section_sizes(image: &FirmwareImage, chip: Chip) -> Vec<SectionSize> {
+    let iram = chip.iram_range();
+    let dram = chip.dram_range();
+
+    image
+        .elf
+        .section_iter()
+        .filter(|header| {
+            header.flags() & SHF_ALLOC != 0 && header.size() > 0 && header.address() > 0
+        })
+        .filter_map(|header| {
+            let bss = match header.get_type() {
+                Ok(ShType::ProgBits) => false,
+                Ok(ShType::NoBits) => true,
+                _ => return None,
+            };
+            let addr = header.address() as u32;
+            let executable = header.flags() & SHF_EXECINSTR != 0;
+            // Chips with unified memory map the same RAM as IRAM and DRAM
+            let memory = if chip.addr_is_flash(addr) {
+                if executable {
+                    Memory::FlashCode
+                } else {
+                    Memory::FlashData
+                }
+            } else if iram.contains(&addr) && (executable || !dram.contains(&addr)) {
+                Memory::Iram
+            } else if dram.contains(&addr) {
+                Memory::Dram
+            } else {
+                Memory::Other
+            };
+
+            Some(SectionSize {
+                name: header.get_name(&image.elf).unwrap_or("").to_string(),
+                addr,
+                size: header.size() as u32,
+                memory,
+                bss,
+            })
+        })
+        .collect()
+}
+
+/// Total size of the `sections` placed in `memory`
+pub fn used(sections: &[SectionSize], memory: Memory) -> u32 {
+    sections
+        .iter()
+        .filter(|section| section.memory == memory)
+        .map(|section| section.size)
+        .sum()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn sections_are_classified() {
+        let image = FirmwareImage::from_data(include_bytes!("../tests/data/esp32")).unwrap();
+        let sections = section_sizes(&image, Chip::Esp32);
+
+        let memory = |name: &str| {
+            sections
+                .iter()
+                .find(|section| section.name == name)
+                .map(|section| section.memory)
+        };
+        assert_eq!(memory(".text"), Some(Memory::FlashCode));
+        assert_eq!(memory(".rodata"), Some(Memory::FlashData));
+        assert_eq!(memory(".rwtext"), Some(Memory::Iram));
+        assert_eq!(memory(".bss"), Some(Memory::Dram));
+        assert_eq!(used(&sections, Memory::Dram), 0x82 + 0x4 + 0x2);
+    }
+}
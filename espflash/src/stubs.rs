@@ -0,0 +1,187 @@
+//! Flasher stubs
+//!
+//! The flasher stub is a small program which is loaded into RAM and replaces
+//! the ROM bootloader for the rest of the session, it supports compression on
+//! all chips, writes faster and has additional commands such as reading flash.
+//!
+//! The stubs are the ones built by [esptool.py], which ships them as one JSON
+//! file per chip (see [`FlashStub::file_name`]). The file contains the entry
+//! point and the base64 encoded `text` and `data` segments with their load
+//! addresses. The stubs of all supported chips are bundled with espflash, see
+//! [`FlashStub::bundled`].
+//!
+//! [esptool.py]: https://github.com/espressif/esptool
+
+use std::borrow::Cow;
+
+use serde::Deserialize;
+
+use crate::{elf::RomSegment, error::Error, Chip};
+
+/// The packet sent by the stub once it's running
+pub const STUB_GREETING: &[u8] = b"OHAI";
+
+/// A flasher stub, ready to be loaded into RAM
+#[derive(Clone, Debug, PartialEq, Eq)]
+pub struct FlashStub {
+    entry: u32,
+    text: Vec<u8>,
+    text_start: u32,
+    data: Vec<u8>,
+    data_start: u32,
+}
+
+#[derive(Deserialize)]
+struct StubFile {
+    entry: u32,
+    text: String,
+    text_start: u32,
+    data: String,
+    data_start: u32,
+}
+
+impl FlashStub {
+    /// The stub for `chip` bundled with espflash
+    pub fn bundled(chip: Chip) -> Self {
+        let json = match chip {
+            Chip::Esp32 => include_str!("../stubs/stub_flasher_32.json"),
+            Chip::Esp32c2 => include_str!("../stubs/stub_flasher_32c2.json"),
+            Chip::Esp32c3 => include_str!("../stubs/stub_flasher_32c3.json"),
+            Chip::Esp32h2 => include_str!("../stubs/stub_flasher_32h2.json"),
+            Chip::Esp32s2 => include_str!("../stubs/stub_flasher_32s2.json"),
+            Chip::Esp32s3 => include_str!("../stubs/stub_flasher_32s3.json"),
+            Chip::Esp8266 => include_str!("../stubs/stub_flasher_8266.json"),
+        };
+
+        FlashStub::from_json(json).expect("the bundled stubs are valid")
+    }
+
+    /// Parse a stub in the JSON format used by esptool.py
+    pub fn from_json(json: &str) -> Result<Self, Error> {
+        let file: StubFile =
+            serde_json::from_str(json).map_err(|err| Error::InvalidStub(err.to_string()))?;
+
+        Ok(FlashStub {
+            entry: file.entry,
+            text: decode_base64(&file.text).map_err(Error::InvalidStub)?,
+            text_start: file.text_start,
+            data: decode_base64(&file.data).map_err(Error::InvalidStub)?,
+            data_start: file.data_start,
+        })
+    }
+
+    /// Name of the file containing the stub for `chip`, as used by esptool.py
+    pub fn file_name(chip: Chip) -> &'static str {
+        match chip {
+            Chip::Esp32 => "stub_flasher_32.json",
+            Chip::Esp32c2 => "stub_flasher_32c2.json",
+            Chip::Esp32c3 => "stub_flasher_32c3.json",
+            Chip::Esp32h2 => "stub_flasher_32h2.json",
+            Chip::Esp32s2 => "stub_flasher_32s2.json",
+            Chip::Esp32s3 => "stub_flasher_32s3.json",
+            Chip::Esp8266 => "stub_flasher_8266.json",
+        }
+    }
+
+    /// Entry point of the stub
+    pub fn entry(&self) -> u32 {
+        self.entry
+    }
+
+    /// The segments to load into RAM
+    pub(crate) fn segments(&self) -> impl Iterator<Item = RomSegment<'_>> {
+        let text = RomSegment {
+            addr: self.text_start,
+            data: Cow::Borrowed(&self.text),
+        };
+        let data = RomSegment {
+            addr: self.data_start,
+            data: Cow::Borrowed(&self.data),
+        };
+
+        IntoIterator::into_iter([text, data]).filter(|segment| !segment.data.is_empty())
+    }
+}
+
+pub(crate) fn decode_base64(encoded: &str) -> Result<Vec<u8>, String> {
+    fn value(c: u8) -> Option<u32> {
+        match c {
+            b'A'..=b'Z' => Some((c - b'A') as u32),
+            b'a'..=b'z' => Some((c - b'a') as u32 + 26),
+            b'0'..=b'9' => Some((c - b'0') as u32 + 52),
+            b'+' => Some(62),
+            b'/' => Some(63),
+            _ => None,
+        }
+    }
+
+    let encoded = encoded.trim_end_matches('=').as_bytes();
+    let mut decoded = Vec::with_capacity(encoded.len() * 3 / 4);
+
+    for chunk in encoded.chunks(4) {
+        let mut bits = 0;
+        for (i, &c) in chunk.iter().enumerate() {
+            let value = value(c).ok_or_else(|| format!("invalid base64 character `{}`", c))?;
+            bits |= value << (18 - 6 * i);
+        }
+
+        let bytes = bits.to_be_bytes();
+        match chunk.len() {
+            4 => decoded.extend_from_slice(&bytes[1..4]),
+            3 => decoded.extend_from_slice(&bytes[1..3]),
+            2 => decoded.push(bytes[1]),
+            _ => return Err("truncated base64 data".into()),
+        }
+    }
+
+    Ok(decoded)
+}
+
+#[cfg(test)]
+mod tests {
+    use strum::IntoEnumIterator;
+
+    use super::*;
+
+    #[test]
+    fn parses_esptool_stub() {
+        let json = r#"{
+            "entry": 1074521560,
+            "text": "SGVsbG8sIHN0dWIh",
+            "text_start": 1074520064,
+            "data": "AAEC/w==",
+            "data_start": 1073605544
+        }"#;
+
+        let stub = FlashStub::from_json(json).unwrap();
+        assert_eq!(stub.entry(), 1074521560);
+
+        let segments: Vec<_> = stub.segments().collect();
+        assert_eq!(segments.len(), 2);
+        assert_eq!(segments[0].addr, 1074520064);
+        assert_eq!(segments[0].data.as_ref(), b"Hello, stub!");
+        assert_eq!(segments[1].addr, 1073605544);
+        assert_eq!(segments[1].data.as_ref(), &[0x00, 0x01, 0x02, 0xff]);
+
+        assert!(decode_base64("AAE").is_ok());
+        assert!(decode_base64("A").is_err());
+        assert!(decode_base64("AA-=").is_err());
+    }
+
+    #[test]
+    fn bundles_a_stub_for_every_chip() {
+        for chip in Chip::iter() {
+            let stub = FlashStub::bundled(chip);
+            let segments: Vec<_> = stub.segments().collect();
+            assert!(!segments.is_empty(), "{}", chip);
+            assert!(
+                segments
+                    .iter()
+                    .any(|segment| (segment.addr..segment.addr + segment.data.len() as u32)
+                        .contains(&stub.entry())),
+                "the entry point of the {} stub isn't loaded",
+                chip
+            );
+        }
+    }
+}
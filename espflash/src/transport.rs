@@ -0,0 +1,70 @@
+//! The byte stream the flasher talks to the bootloader through
+//!
+//! Serial ports are the usual transport, others like a mock device for tests
+//! implement [`Transport`] to reuse the protocol code.
+
+use std::{
+    io::{Read, Write},
+    time::Duration,
+};
+
+use serialport::{ClearBuffer, SerialPort};
+
+use crate::error::Error;
+
+// Captures are replayed against the mock device by the CLI
+#[cfg(any(test, feature = "cli"))]
+#[cfg_attr(not(all(test, feature = "cli")), allow(dead_code))]
+pub(crate) mod mock;
+
+/// A connection to the device carrying the bootloader protocol, with the
+/// control lines used for resetting it
+pub trait Transport: Read + Write + Send {
+    /// How long reads wait for data before failing with a timeout
+    fn timeout(&self) -> Duration;
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error>;
+
+    fn baud_rate(&self) -> Result<u32, Error>;
+
+    fn set_baud_rate(&mut self, speed: u32) -> Result<(), Error>;
+
+    /// Drop the data received but not read yet
+    fn clear_input(&mut self) -> Result<(), Error>;
+
+    /// Set the DTR line, transports without one ignore it
+    fn set_dtr(&mut self, level: bool) -> Result<(), Error>;
+
+    /// Set the RTS line, transports without one ignore it
+    fn set_rts(&mut self, level: bool) -> Result<(), Error>;
+}
+
+impl Transport for Box<dyn SerialPort> {
+    fn timeout(&self) -> Duration {
+        SerialPort::timeout(&**self)
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        Ok(SerialPort::set_timeout(&mut **self, timeout)?)
+    }
+
+    fn baud_rate(&self) -> Result<u32, Error> {
+        Ok(SerialPort::baud_rate(&**self)?)
+    }
+
+    fn set_baud_rate(&mut self, speed: u32) -> Result<(), Error> {
+        Ok(SerialPort::set_baud_rate(&mut **self, speed)?)
+    }
+
+    fn clear_input(&mut self) -> Result<(), Error> {
+        Ok(self.clear(ClearBuffer::Input)?)
+    }
+
+    fn set_dtr(&mut self, level: bool) -> Result<(), Error> {
+        Ok(self.write_data_terminal_ready(level)?)
+    }
+
+    fn set_rts(&mut self, level: bool) -> Result<(), Error> {
+        Ok(self.write_request_to_send(level)?)
+    }
+}
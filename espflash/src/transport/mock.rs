@@ -0,0 +1,497 @@
+//! An in-process ESP32-C3 ROM loader, for testing the protocol code without
+//! hardware
+//!
+//! The device decodes the SLIP frames written to it and queues the responses
+//! for reading, reads time out right away once they're consumed. Registers read
+//! back what was written to them, except for the SPI command which runs the
+//! flash detection, and the flash commands operate on a 4MB flash kept in
+//! memory.
+//!
+//! Loading any program into RAM and running it starts the flasher stub, which
+//! responds like the esptool.py stub does. Releasing the reset through the RTS
+//! line restarts the device, into the ROM loader if DTR holds GPIO0 low and
+//! into the application otherwise, which ignores all commands and only keeps
+//! the data written to it.
+
+use std::{
+    collections::{HashMap, VecDeque},
+    convert::TryInto,
+    io::{self, Read, Write},
+    sync::{Arc, Mutex, MutexGuard},
+    time::Duration,
+};
+
+use flate2::write::ZlibDecoder;
+
+use super::Transport;
+use crate::{
+    chip::{ChipType, Esp32c3},
+    command::CommandType,
+    encoder::{END, ESC, ESC_END, ESC_ESC},
+    error::{Error, RomErrorKind},
+    flasher::{checksum, CHECKSUM_INIT, CHIP_DETECT_MAGIC_REG_ADDR},
+    stubs::STUB_GREETING,
+    Chip,
+};
+
+/// JEDEC ID of the flash, a 4MB chip
+pub const FLASH_ID: u32 = 0x0016_40ef;
+const FLASH_SIZE: usize = 0x40_0000;
+
+/// Responses the ROM sends to a single sync command
+const SYNC_RESPONSES: usize = 8;
+
+/// The mock device, clones share the same device which lets tests inspect it
+/// after handing a clone to the flasher
+#[derive(Clone)]
+pub struct MockDevice {
+    state: Arc<Mutex<DeviceState>>,
+}
+
+struct DeviceState {
+    registers: HashMap<u32, u32>,
+    flash: Vec<u8>,
+    /// Opcodes of the commands received, in order
+    commands: Vec<u8>,
+    /// Baud rate the device communicates at, frames sent at another one are
+    /// dropped
+    baud: u32,
+    host_baud: u32,
+    /// Whether the device switches its baud rate when asked to
+    follows_baud_change: bool,
+    /// Fastest baud rate the link carries frames at
+    max_baud: Option<u32>,
+    stub: bool,
+    running_app: bool,
+    dtr: bool,
+    rts: bool,
+    timeout: Duration,
+    write: Option<FlashWrite>,
+    frame: Vec<u8>,
+    escaped: bool,
+    output: VecDeque<u8>,
+    /// Data written while the application runs
+    input: Vec<u8>,
+}
+
+/// The flash write started by a begin command
+struct FlashWrite {
+    offset: usize,
+    /// Bytes written so far
+    position: usize,
+    sequence: u32,
+    /// Inflates the data of a compressed write
+    decoder: Option<ZlibDecoder<Vec<u8>>>,
+}
+
+impl MockDevice {
+    pub fn new() -> Self {
+        MockDevice {
+            state: Arc::new(Mutex::new(DeviceState {
+                registers: HashMap::new(),
+                flash: vec![0xff; FLASH_SIZE],
+                commands: Vec::new(),
+                baud: 115_200,
+                host_baud: 115_200,
+                follows_baud_change: true,
+                max_baud: None,
+                stub: false,
+                running_app: false,
+                dtr: false,
+                rts: false,
+                timeout: Duration::from_secs(3),
+                write: None,
+                frame: Vec::new(),
+                escaped: false,
+                output: VecDeque::new(),
+                input: Vec::new(),
+            })),
+        }
+    }
+
+    fn state(&self) -> MutexGuard<'_, DeviceState> {
+        self.state.lock().unwrap()
+    }
+
+    /// Keep the baud rate when asked to change it, like a device behind an
+    /// adapter which doesn't support the requested speed
+    pub fn refuse_baud_change(&self) {
+        self.state().follows_baud_change = false;
+    }
+
+    /// Drop all frames sent at a higher baud rate than `speed`, like a serial
+    /// adapter which doesn't support higher speeds but still switches to them
+    pub fn limit_baud(&self, speed: u32) {
+        self.state().max_baud = Some(speed);
+    }
+
+    /// Whether the flasher stub is running
+    pub fn is_stub(&self) -> bool {
+        self.state().stub
+    }
+
+    /// Whether the device was reset into the application
+    pub fn is_running_app(&self) -> bool {
+        self.state().running_app
+    }
+
+    /// Opcodes of the commands received so far
+    pub fn commands(&self) -> Vec<u8> {
+        self.state().commands.clone()
+    }
+
+    pub fn flash(&self, offset: usize, len: usize) -> Vec<u8> {
+        self.state().flash[offset..offset + len].to_vec()
+    }
+
+    /// Have the application print `text`, if it's running
+    pub fn print(&self, text: &[u8]) {
+        let mut state = self.state();
+        if state.running_app {
+            state.output.extend(text);
+        }
+    }
+
+    /// Data the application received so far
+    pub fn input(&self) -> Vec<u8> {
+        self.state().input.clone()
+    }
+
+    /// Put `data` into the flash without going through the protocol
+    pub fn set_flash(&self, offset: usize, data: &[u8]) {
+        self.state().flash[offset..offset + data.len()].copy_from_slice(data);
+    }
+}
+
+impl DeviceState {
+    fn receive(&mut self, byte: u8) {
+        match (byte, self.escaped) {
+            (END, _) => {
+                let frame = std::mem::take(&mut self.frame);
+                if !frame.is_empty() && self.link_works() && !self.running_app {
+                    self.handle_frame(&frame);
+                }
+            }
+            (ESC, false) => self.escaped = true,
+            (ESC_END, true) => {
+                self.frame.push(END);
+                self.escaped = false;
+            }
+            (ESC_ESC, true) => {
+                self.frame.push(ESC);
+                self.escaped = false;
+            }
+            (byte, _) => {
+                self.frame.push(byte);
+                self.escaped = false;
+            }
+        }
+    }
+
+    fn link_works(&self) -> bool {
+        self.baud == self.host_baud && self.max_baud.map_or(true, |max| self.baud <= max)
+    }
+
+    /// Restart the device after its reset is released
+    fn restart(&mut self) {
+        self.baud = 115_200;
+        self.stub = false;
+        self.running_app = !self.dtr;
+        self.write = None;
+        self.registers.clear();
+        self.output.clear();
+    }
+
+    fn handle_frame(&mut self, frame: &[u8]) {
+        if frame.len() < 8 || frame[0] != 0 {
+            return;
+        }
+        let op = frame[1];
+        let len = u16::from_le_bytes([frame[2], frame[3]]) as usize;
+        let check = u32::from_le_bytes([frame[4], frame[5], frame[6], frame[7]]);
+        let data = &frame[8..];
+        if data.len() != len {
+            return;
+        }
+        self.commands.push(op);
+
+        let result = self.execute(op, check, data);
+        if op == CommandType::ChangeBaud as u8 {
+            // The response is still sent at the previous speed
+            self.respond(op, result);
+            if self.follows_baud_change {
+                self.baud = word(data, 0);
+            }
+        } else if op == CommandType::Sync as u8 {
+            for _ in 0..SYNC_RESPONSES {
+                self.respond(op, Ok((0, Vec::new())));
+            }
+        } else if op == CommandType::MemEnd as u8 && result.is_ok() && word(data, 0) == 0 {
+            // Whatever was loaded is taken for the stub, which greets once started
+            self.respond(op, result);
+            self.stub = true;
+            self.send_frame(STUB_GREETING.to_vec());
+        } else if op == CommandType::ReadFlash as u8 && result.is_ok() {
+            // The stub streams the data after the response, followed by its digest
+            self.respond(op, result);
+            let (offset, size) = (word(data, 0) as usize, word(data, 4) as usize);
+            let region = self.flash[offset..offset + size].to_vec();
+            for block in region.chunks(word(data, 8) as usize) {
+                self.send_frame(block.to_vec());
+            }
+            self.send_frame(md5::compute(&region).0.to_vec());
+        } else {
+            self.respond(op, result);
+        }
+    }
+
+    fn execute(&mut self, op: u8, check: u32, data: &[u8]) -> Result<(u32, Vec<u8>), RomErrorKind> {
+        let spi_registers = Chip::Esp32c3.spi_registers();
+
+        match CommandType::from_u8(op) {
+            CommandType::Sync | CommandType::SpiAttach | CommandType::SpiSetParams => {
+                Ok((0, Vec::new()))
+            }
+            CommandType::ReadReg => {
+                let address = word(data, 0);
+                let value = match address {
+                    CHIP_DETECT_MAGIC_REG_ADDR => Esp32c3::CHIP_DETECT_MAGIC_VALUE,
+                    _ => self.registers.get(&address).copied().unwrap_or(0),
+                };
+                Ok((value, Vec::new()))
+            }
+            CommandType::WriteReg => {
+                let (address, value, mask) = (word(data, 0), word(data, 4), word(data, 8));
+                let old = self.registers.get(&address).copied().unwrap_or(0);
+                self.registers.insert(address, old & !mask | value & mask);
+
+                // Running the flash detection command makes the ID readable
+                let usr2 = self.registers.get(&spi_registers.usr2()).copied();
+                if address == spi_registers.cmd()
+                    && value & 1 << 18 != 0
+                    && usr2.map(|usr2| usr2 & 0xff) == Some(CommandType::FlashDetect as u32)
+                {
+                    self.registers.insert(spi_registers.w0(), FLASH_ID);
+                }
+                Ok((0, Vec::new()))
+            }
+            CommandType::ChangeBaud | CommandType::MemBegin | CommandType::MemEnd => {
+                Ok((0, Vec::new()))
+            }
+            CommandType::MemData => {
+                let size = word(data, 0) as usize;
+                let block = data
+                    .get(16..16 + size)
+                    .ok_or(RomErrorKind::InvalidMessage)?;
+                if check != checksum(block, CHECKSUM_INIT) as u32 {
+                    return Err(RomErrorKind::InvalidCrc);
+                }
+                Ok((0, Vec::new()))
+            }
+            CommandType::EraseRegion if self.stub => {
+                let (offset, size) = (word(data, 0) as usize, word(data, 4) as usize);
+                self.flash
+                    .get_mut(offset..offset + size)
+                    .ok_or(RomErrorKind::FlashWriteError)?
+                    .iter_mut()
+                    .for_each(|byte| *byte = 0xff);
+                Ok((0, Vec::new()))
+            }
+            CommandType::ReadFlashSlow => {
+                let (offset, size) = (word(data, 0) as usize, word(data, 4) as usize);
+                let region = self
+                    .flash
+                    .get(offset..offset + size)
+                    .ok_or(RomErrorKind::FlashReadError)?;
+                Ok((0, region.to_vec()))
+            }
+            CommandType::ReadFlash if self.stub => {
+                let (offset, size) = (word(data, 0) as usize, word(data, 4) as usize);
+                if offset + size > self.flash.len() || word(data, 8) == 0 {
+                    return Err(RomErrorKind::FlashReadError);
+                }
+                Ok((0, Vec::new()))
+            }
+            ty @ (CommandType::FlashBegin | CommandType::FlashDeflateBegin) => {
+                let (size, offset) = (word(data, 0) as usize, word(data, 12) as usize);
+                let erased = self
+                    .flash
+                    .get_mut(offset..offset + size)
+                    .ok_or(RomErrorKind::FlashWriteError)?;
+                erased.iter_mut().for_each(|byte| *byte = 0xff);
+
+                let compressed = matches!(ty, CommandType::FlashDeflateBegin);
+                self.write = Some(FlashWrite {
+                    offset,
+                    position: 0,
+                    sequence: 0,
+                    decoder: compressed.then(|| ZlibDecoder::new(Vec::new())),
+                });
+                Ok((0, Vec::new()))
+            }
+            CommandType::FlashData | CommandType::FlashDeflateData => {
+                let (size, sequence) = (word(data, 0) as usize, word(data, 4));
+                let block = data
+                    .get(16..16 + size)
+                    .ok_or(RomErrorKind::InvalidMessage)?;
+                if check != checksum(block, CHECKSUM_INIT) as u32 {
+                    return Err(RomErrorKind::InvalidCrc);
+                }
+
+                let write = self.write.as_mut().ok_or(RomErrorKind::FailedToAct)?;
+                if sequence != write.sequence {
+                    return Err(RomErrorKind::InvalidMessage);
+                }
+                write.sequence += 1;
+                let block = match &mut write.decoder {
+                    Some(decoder) => {
+                        decoder
+                            .write_all(block)
+                            .and_then(|_| decoder.flush())
+                            .map_err(|_| RomErrorKind::DeflateError)?;
+                        std::mem::take(decoder.get_mut())
+                    }
+                    None => block.to_vec(),
+                };
+
+                let start = write.offset + write.position;
+                write.position += block.len();
+                self.flash
+                    .get_mut(start..start + block.len())
+                    .ok_or(RomErrorKind::FlashWriteError)?
+                    .copy_from_slice(&block);
+                Ok((0, Vec::new()))
+            }
+            CommandType::FlashEnd | CommandType::FlashDeflateEnd => {
+                self.write = None;
+                Ok((0, Vec::new()))
+            }
+            CommandType::FlashMd5 => {
+                let (offset, size) = (word(data, 0) as usize, word(data, 4) as usize);
+                let region = self
+                    .flash
+                    .get(offset..offset + size)
+                    .ok_or(RomErrorKind::FlashReadError)?;
+                // The ROM responds with the digest as hex, the stub with the raw bytes
+                let digest = md5::compute(region);
+                if self.stub {
+                    Ok((0, digest.0.to_vec()))
+                } else {
+                    Ok((0, format!("{:x}", digest).into_bytes()))
+                }
+            }
+            _ => Err(RomErrorKind::InvalidMessage),
+        }
+    }
+
+    /// Queue the response to the command `op`, ending with the four status
+    /// bytes of the esp32 family ROMs, or the two of the stub
+    fn respond(&mut self, op: u8, result: Result<(u32, Vec<u8>), RomErrorKind>) {
+        let status_length = if self.stub { 2 } else { 4 };
+        let (value, data) = match result {
+            Ok((value, data)) => (value, [data, vec![0; status_length]].concat()),
+            Err(kind) => (
+                0,
+                [vec![1, kind as u8], vec![0; status_length - 2]].concat(),
+            ),
+        };
+
+        let mut response = vec![1, op];
+        response.extend_from_slice(&(data.len() as u16).to_le_bytes());
+        response.extend_from_slice(&value.to_le_bytes());
+        response.extend_from_slice(&data);
+        self.send_frame(response);
+    }
+
+    fn send_frame(&mut self, frame: Vec<u8>) {
+        if !self.link_works() {
+            return;
+        }
+
+        self.output.push_back(END);
+        for byte in frame {
+            match byte {
+                END => self.output.extend([ESC, ESC_END]),
+                ESC => self.output.extend([ESC, ESC_ESC]),
+                byte => self.output.push_back(byte),
+            }
+        }
+        self.output.push_back(END);
+    }
+}
+
+fn word(data: &[u8], offset: usize) -> u32 {
+    data.get(offset..offset + 4)
+        .map_or(0, |bytes| u32::from_le_bytes(bytes.try_into().unwrap()))
+}
+
+impl Read for MockDevice {
+    fn read(&mut self, buf: &mut [u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        if state.output.is_empty() {
+            return Err(io::ErrorKind::TimedOut.into());
+        }
+
+        let len = buf.len().min(state.output.len());
+        for (byte, received) in buf.iter_mut().zip(state.output.drain(..len)) {
+            *byte = received;
+        }
+        Ok(len)
+    }
+}
+
+impl Write for MockDevice {
+    fn write(&mut self, buf: &[u8]) -> io::Result<usize> {
+        let mut state = self.state();
+        if state.running_app {
+            state.input.extend_from_slice(buf);
+        }
+        for &byte in buf {
+            state.receive(byte);
+        }
+        Ok(buf.len())
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        Ok(())
+    }
+}
+
+impl Transport for MockDevice {
+    fn timeout(&self) -> Duration {
+        self.state().timeout
+    }
+
+    fn set_timeout(&mut self, timeout: Duration) -> Result<(), Error> {
+        self.state().timeout = timeout;
+        Ok(())
+    }
+
+    fn baud_rate(&self) -> Result<u32, Error> {
+        Ok(self.state().host_baud)
+    }
+
+    fn set_baud_rate(&mut self, speed: u32) -> Result<(), Error> {
+        self.state().host_baud = speed;
+        Ok(())
+    }
+
+    fn clear_input(&mut self) -> Result<(), Error> {
+        self.state().output.clear();
+        Ok(())
+    }
+
+    fn set_dtr(&mut self, level: bool) -> Result<(), Error> {
+        self.state().dtr = level;
+        Ok(())
+    }
+
+    fn set_rts(&mut self, level: bool) -> Result<(), Error> {
+        let mut state = self.state();
+        if state.rts && !level {
+            state.restart();
+        }
+        state.rts = level;
+        Ok(())
+    }
+}
@@ -0,0 +1,48 @@
+# Writes the keys and the signed images of the Secure Boot tests, with the
+# signature blocks laid out like `espsecure.py sign_data --version 2` does:
+#
+#     python3 sign.py .
+import hashlib, struct, zlib, sys
+from cryptography.hazmat.primitives import hashes, serialization
+from cryptography.hazmat.primitives.asymmetric import rsa, ec, padding, utils
+
+def le(x, n): return x.to_bytes(n, "little")
+
+def pad(image):
+    if len(image) % 4096: image += b"\xff" * (4096 - len(image) % 4096)
+    return image
+
+def rsa_block(key, digest):
+    pub = key.public_key().public_numbers()
+    rinv = (1 << (key.key_size * 2)) % pub.n
+    m = (-pow(pub.n, -1, 1 << 32)) & 0xFFFFFFFF
+    pubblock = struct.pack("<384sI384sI", le(pub.n, 384), pub.e, le(rinv, 384), m)
+    sig = key.sign(digest, padding.PSS(mgf=padding.MGF1(hashes.SHA256()), salt_length=32), utils.Prehashed(hashes.SHA256()))
+    return struct.pack("<BBxx32s", 0xE7, 2, digest) + pubblock + sig[::-1], pubblock
+
+def ec_block(key, digest):
+    pub = key.public_key().public_numbers()
+    n = key.curve.key_size // 8
+    cid = {24: 1, 32: 2}[n]
+    pubblock = struct.pack("B", cid) + (le(pub.x, n) + le(pub.y, n)).ljust(64, b"\0")
+    r, s = utils.decode_dss_signature(key.sign(digest, ec.ECDSA(utils.Prehashed(hashes.SHA256()))))
+    block = struct.pack("<BBxx32s", 0xE7, 3, digest) + pubblock + (le(r, n) + le(s, n)).ljust(64, b"\0")
+    return block.ljust(1196, b"\0"), pubblock
+
+def sign(key, image, f):
+    image = pad(image)
+    digest = hashlib.sha256(image).digest()
+    block, pubblock = f(key, digest)
+    block += struct.pack("<I", zlib.crc32(block) & 0xFFFFFFFF)
+    block = block.ljust(1216, b"\0")
+    print(hashlib.sha256(pubblock).hexdigest())
+    return image + block.ljust(4096, b"\xff")
+
+image = bytes((i * 7) & 0xFF for i in range(5000))
+out = sys.argv[1]
+k = rsa.generate_private_key(65537, 3072)
+open(out + "/rsa3072.pem", "wb").write(k.private_bytes(serialization.Encoding.PEM, serialization.PrivateFormat.TraditionalOpenSSL, serialization.NoEncryption()))
+open(out + "/rsa3072.bin", "wb").write(sign(k, image, rsa_block))
+k = ec.generate_private_key(ec.SECP192R1())
+open(out + "/p192.pem", "wb").write(k.private_bytes(serialization.Encoding.PEM, serialization.PrivateFormat.TraditionalOpenSSL, serialization.NoEncryption()))
+open(out + "/p192.bin", "wb").write(sign(k, image, ec_block))